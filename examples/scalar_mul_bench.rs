@@ -0,0 +1,37 @@
+// Rough timing of the scalar multiplication strategies.
+//
+//     cargo run --release --example scalar_mul_bench
+//
+// Add `--features alloc-stats` to also report heap usage per strategy.
+
+use num_bigint::BigInt;
+use prog_btc_book::math::ecc::scalar_mul::ScalarMulStrategy;
+use prog_btc_book::math::ecc::{s256_order, S256Point};
+use std::time::Instant;
+
+const ROUNDS: u32 = 20;
+
+fn main() {
+    let g = S256Point::generator();
+    let scalars: Vec<BigInt> = (1..=ROUNDS)
+        .map(|i| s256_order() / BigInt::from(i * 7 + 3) + BigInt::from(i))
+        .collect();
+
+    // Warm up the shared generator table so it is not counted below
+    g.scalar_mul_with(&scalars[0], ScalarMulStrategy::PrecomputedTable);
+
+    for strategy in ScalarMulStrategy::ALL.iter() {
+        let start = Instant::now();
+        for k in scalars.iter() {
+            g.scalar_mul_with(k, *strategy);
+        }
+        let elapsed = start.elapsed();
+        println!("{:>18}: {:?} per multiplication", strategy.name(), elapsed / ROUNDS);
+
+        #[cfg(feature = "alloc-stats")]
+        {
+            let (_, report) = prog_btc_book::alloc_stats::measure(|| g.scalar_mul_with(&scalars[0], *strategy));
+            println!("{:>18}  {}", "", report);
+        }
+    }
+}
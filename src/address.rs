@@ -0,0 +1,158 @@
+//! Addresses: base58check for P2PKH and P2SH (chapter 4), bech32 for
+//! segwit version 0 and bech32m for later versions.
+//!
+//! An address only holds what it pays to. The prefixes and human readable
+//! part come from the `NetworkParams` it is parsed or rendered for, so
+//! testnet3, testnet4 and signet addresses, which look the same, are told
+//! apart by the caller.
+
+use crate::encoding::base58::{decode_base58_checksum, encode_base58_checksum};
+use crate::encoding::bech32::{self, decode_segwit_address, encode_segwit_address};
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::{registered_networks, NetworkParams};
+use crate::script::opcodes::OpCode;
+use crate::script::{p2pkh_script, p2sh_script, p2tr_script, p2wpkh_script, p2wsh_script, Command, Script, ScriptType};
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    /// An x-only output key
+    P2tr([u8; 32]),
+    /// A witness version not defined yet, 2 to 16, or version 1 with a
+    /// program that is not 32 bytes
+    WitnessUnknown { version: u8, program: Vec<u8> },
+}
+
+impl Address {
+    /// Parses an address of `network`. One of another network is
+    /// `ErrorKind::UnknownNetwork`.
+    pub fn parse(s: &str, network: &NetworkParams) -> Result<Address> {
+        let segwit_prefix = format!("{}1", network.bech32_hrp);
+        if s.to_lowercase().starts_with(&segwit_prefix) {
+            let (version, program) = decode_segwit_address(&network.bech32_hrp, s)?;
+            return Ok(match (version, program.len()) {
+                (0, 20) => Address::P2wpkh(program[..].try_into().unwrap()),
+                (0, _) => Address::P2wsh(program[..].try_into().unwrap()),
+                (1, 32) => Address::P2tr(program[..].try_into().unwrap()),
+                _ => Address::WitnessUnknown { version, program },
+            });
+        }
+        if let Ok((hrp, _, _)) = bech32::decode(s) {
+            return Err(Error::new(ErrorKind::UnknownNetwork, format!("{} address on {}", hrp, network.name)));
+        }
+        let payload = decode_base58_checksum(s)?;
+        let (&prefix, hash) = payload.split_first().ok_or_else(|| Error::new(ErrorKind::InvalidEncoding, "empty address"))?;
+        let hash: [u8; 20] = hash
+            .try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidEncoding, format!("{} byte hash in {}", hash.len(), s)))?;
+        if prefix == network.p2pkh_prefix {
+            Ok(Address::P2pkh(hash))
+        } else if prefix == network.p2sh_prefix {
+            Ok(Address::P2sh(hash))
+        } else {
+            Err(Error::new(ErrorKind::UnknownNetwork, format!("{} is not a {} address", s, network.name)))
+        }
+    }
+
+    /// Parses an address of any registered network, returning the first
+    /// that accepts it: mainnet, or testnet3 for addresses shared by the
+    /// test networks.
+    pub fn parse_any(s: &str) -> Result<(Address, NetworkParams)> {
+        let mut error = None;
+        for network in registered_networks() {
+            match Address::parse(s, &network) {
+                Ok(address) => return Ok((address, network)),
+                Err(e) if e.kind() == ErrorKind::UnknownNetwork => {}
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        Err(error.unwrap_or_else(|| Error::new(ErrorKind::UnknownNetwork, format!("{} belongs to no known network", s))))
+    }
+
+    /// The address as written on `network`.
+    pub fn encode(&self, network: &NetworkParams) -> String {
+        let base58 = |prefix: u8, hash: &[u8]| encode_base58_checksum(&[&[prefix][..], hash].concat());
+        let hrp = &network.bech32_hrp;
+        match self {
+            Address::P2pkh(hash) => base58(network.p2pkh_prefix, hash),
+            Address::P2sh(hash) => base58(network.p2sh_prefix, hash),
+            Address::P2wpkh(hash) => encode_segwit_address(hrp, 0, hash),
+            Address::P2wsh(hash) => encode_segwit_address(hrp, 0, hash),
+            Address::P2tr(output_key) => encode_segwit_address(hrp, 1, output_key),
+            Address::WitnessUnknown { version, program } => encode_segwit_address(hrp, *version, program),
+        }
+    }
+
+    /// The address paid by `script_pubkey`, if it has one.
+    pub fn from_script(script_pubkey: &Script) -> Result<Address> {
+        match script_pubkey.classify() {
+            ScriptType::P2pkh(hash) => Ok(Address::P2pkh(hash)),
+            ScriptType::P2sh(hash) => Ok(Address::P2sh(hash)),
+            ScriptType::P2wpkh(hash) => Ok(Address::P2wpkh(hash)),
+            ScriptType::P2wsh(hash) => Ok(Address::P2wsh(hash)),
+            ScriptType::P2tr(output_key) => Ok(Address::P2tr(output_key)),
+            ScriptType::WitnessUnknown { version, program } => Ok(Address::WitnessUnknown { version, program }),
+            other => Err(Error::new(ErrorKind::UnsupportedScript, format!("{:?} outputs have no address", other))),
+        }
+    }
+
+    /// The script_pubkey paying to this address.
+    pub fn script_pubkey(&self) -> Script {
+        match self {
+            Address::P2pkh(hash) => p2pkh_script(hash),
+            Address::P2sh(hash) => p2sh_script(hash),
+            Address::P2wpkh(hash) => p2wpkh_script(hash),
+            Address::P2wsh(hash) => p2wsh_script(hash),
+            Address::P2tr(output_key) => p2tr_script(output_key),
+            Address::WitnessUnknown { version, program } => Script::new(vec![
+                Command::Op(OpCode::from_small_int(*version).unwrap().to_u8()),
+                Command::Data(program.clone()),
+            ]),
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[test]
+fn address_round_trips() {
+    let mainnet = NetworkParams::mainnet();
+    let testnet = NetworkParams::testnet3();
+    let regtest = NetworkParams::regtest();
+    let h160: [u8; 20] = decode_hex("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap()[..].try_into().unwrap();
+    let cases = [
+        (Address::P2pkh(h160), &mainnet, "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"),
+        (Address::P2pkh(h160), &testnet, "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r"),
+        (Address::P2sh(decode_hex("74d691da1574e6b3c192ecfb52cc8984ee7b6c56").unwrap()[..].try_into().unwrap()), &mainnet, "3CLoMMyuoDQTPRD3XYZtCvgvkadrAdvdXh"),
+        (Address::P2wpkh(h160), &mainnet, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+        (Address::P2wpkh(h160), &regtest, "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"),
+        (
+            Address::P2wsh(decode_hex("1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262").unwrap()[..].try_into().unwrap()),
+            &testnet,
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+        ),
+        (
+            Address::P2tr(decode_hex("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap()[..].try_into().unwrap()),
+            &mainnet,
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+        ),
+        (Address::WitnessUnknown { version: 16, program: vec![0x75, 0x1e] }, &mainnet, "bc1sw50qgdz25j"),
+    ];
+    for (address, network, s) in cases.iter() {
+        assert_eq!(address.encode(network), *s);
+        assert_eq!(Address::parse(s, network).unwrap(), *address);
+        assert_eq!(Address::from_script(&address.script_pubkey()).unwrap(), *address);
+    }
+    assert_eq!(Address::parse_any("mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r").unwrap(), (Address::P2pkh(h160), testnet.clone()));
+    assert_eq!(Address::parse_any("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080").unwrap().1, regtest);
+
+    assert_eq!(Address::parse("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", &testnet).unwrap_err().kind(), ErrorKind::UnknownNetwork);
+    assert_eq!(Address::parse("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", &testnet).unwrap_err().kind(), ErrorKind::UnknownNetwork);
+    assert_eq!(Address::parse_any("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMJ").unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert!(Address::from_script(&crate::script::op_return(b"data")).is_err());
+}
@@ -0,0 +1,151 @@
+//! Allocation counters for profiling the BigInt-heavy code paths.
+//!
+//! Enabling the `alloc-stats` feature installs `CountingAllocator` as the
+//! global allocator. It forwards to the system allocator and keeps running
+//! totals that can be read with `snapshot` or diffed around a closure with
+//! `measure`, e.g. around parsing a block or applying it to a UTXO set.
+//!
+//! The counters are process wide, so allocations made by other threads
+//! while a measurement is running are included.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn record_alloc(size: usize) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES_ALLOCATED.fetch_add(size, Ordering::Relaxed);
+        let current = CURRENT_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK_BYTES.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn record_dealloc(size: usize) {
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        CURRENT_BYTES.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            CountingAllocator::record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            CountingAllocator::record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CountingAllocator::record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            // A realloc counts as freeing the old block and allocating the new one
+            CountingAllocator::record_dealloc(layout.size());
+            CountingAllocator::record_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Point-in-time view of the allocation counters.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AllocStats {
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub bytes_allocated: usize,
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+}
+
+/// Counters accumulated while running a measured closure.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AllocReport {
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub bytes_allocated: usize,
+    /// Bytes still live at the end of the measurement (may be negative)
+    pub net_bytes: isize,
+    /// Highest live heap size above the starting point
+    pub peak_bytes: usize,
+}
+
+pub fn snapshot() -> AllocStats {
+    AllocStats {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        bytes_allocated: BYTES_ALLOCATED.load(Ordering::Relaxed),
+        current_bytes: CURRENT_BYTES.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Resets the peak to the current live heap size.
+pub fn reset_peak() {
+    PEAK_BYTES.store(CURRENT_BYTES.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Runs `f` and reports what it allocated.
+pub fn measure<T, F: FnOnce() -> T>(f: F) -> (T, AllocReport) {
+    reset_peak();
+    let before = snapshot();
+    let result = f();
+    let after = snapshot();
+    let report = AllocReport {
+        allocations: after.allocations - before.allocations,
+        deallocations: after.deallocations - before.deallocations,
+        bytes_allocated: after.bytes_allocated - before.bytes_allocated,
+        net_bytes: after.current_bytes as isize - before.current_bytes as isize,
+        peak_bytes: after.peak_bytes.saturating_sub(before.current_bytes),
+    };
+    (result, report)
+}
+
+impl fmt::Display for AllocReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} allocs, {} frees, {} bytes allocated, {} net, {} peak",
+            self.allocations, self.deallocations, self.bytes_allocated, self.net_bytes, self.peak_bytes)
+    }
+}
+
+#[test]
+fn measure_counts_allocations() {
+    let (v, report) = measure(|| vec![0u8; 4096]);
+    assert_eq!(v.len(), 4096);
+    assert!(report.allocations >= 1);
+    assert!(report.bytes_allocated >= 4096);
+    assert!(report.peak_bytes >= 4096);
+}
+
+#[cfg(feature = "ecc")]
+#[test]
+fn measure_scalar_mul() {
+    use crate::math::ecc::S256Point;
+    use num_bigint::BigInt;
+
+    let (_, report) = measure(|| S256Point::generator().scalar_mul(&BigInt::from(0xdeadbeefu64)));
+    assert!(report.allocations > 0);
+    assert!(report.deallocations > 0);
+}
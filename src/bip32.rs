@@ -0,0 +1,418 @@
+//! BIP32 hierarchical deterministic keys. An extended private key derives
+//! every child; an extended public key, a public key with the same chain
+//! code, derives the non-hardened children without the private key.
+//!
+//! Some wallets export keys with SLIP-0132 version bytes ("ypub", "zpub",
+//! "vpub" and so on) that say which script the key pays to. `parse` only
+//! takes the canonical versions; `parse_slip132` takes any of them and
+//! returns the canonical key with its `KeyScript`.
+
+use crate::encoding::base58::{decode_base58_checksum, encode_base58_checksum};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash160;
+use crate::math::ecc::{bytes_to_int, s256_order, PrivateKey, S256Point};
+use crate::params::Network;
+use hmac::{Hmac, Mac};
+use num_integer::Integer;
+use sha2::Sha512;
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// Child numbers from here on are hardened.
+pub const HARDENED: u32 = 1 << 31;
+/// Version bytes of mainnet extended public keys, "xpub".
+pub const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+/// Version bytes of test network extended public keys, "tpub".
+pub const TPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+/// Version bytes of mainnet extended private keys, "xprv".
+pub const XPRV_VERSION: [u8; 4] = [0x04, 0x88, 0xad, 0xe4];
+/// Version bytes of test network extended private keys, "tprv".
+pub const TPRV_VERSION: [u8; 4] = [0x04, 0x35, 0x83, 0x94];
+
+const SERIALIZED_LEN: usize = 78;
+
+/// The outputs a SLIP-0132 version says an extended key pays to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyScript {
+    /// xpub/tpub, which also stand for any script
+    P2pkh,
+    /// ypub/upub
+    P2shP2wpkh,
+    /// zpub/vpub
+    P2wpkh,
+    /// Ypub/Upub, multisig
+    P2shP2wsh,
+    /// Zpub/Vpub, multisig
+    P2wsh,
+}
+
+type Version = [u8; 4];
+
+// Public and private versions for mainnet, then for the test networks
+const SLIP132_VERSIONS: [(KeyScript, Version, Version, Version, Version); 5] = [
+    (KeyScript::P2pkh, XPUB_VERSION, XPRV_VERSION, TPUB_VERSION, TPRV_VERSION),
+    (KeyScript::P2shP2wpkh, [0x04, 0x9d, 0x7c, 0xb2], [0x04, 0x9d, 0x78, 0x78], [0x04, 0x4a, 0x52, 0x62], [0x04, 0x4a, 0x4e, 0x28]),
+    (KeyScript::P2wpkh, [0x04, 0xb2, 0x47, 0x46], [0x04, 0xb2, 0x43, 0x0c], [0x04, 0x5f, 0x1c, 0xf6], [0x04, 0x5f, 0x18, 0xbc]),
+    (KeyScript::P2shP2wsh, [0x02, 0x95, 0xb4, 0x3f], [0x02, 0x95, 0xb0, 0x05], [0x02, 0x42, 0x89, 0xef], [0x02, 0x42, 0x85, 0xb5]),
+    (KeyScript::P2wsh, [0x02, 0xaa, 0x7e, 0xd3], [0x02, 0xaa, 0x7a, 0x99], [0x02, 0x57, 0x54, 0x83], [0x02, 0x57, 0x50, 0x48]),
+];
+
+// The script and, whether mainnet or not, the canonical version for a
+// SLIP-0132 `version`
+fn slip132_canonical(version: [u8; 4], private: bool) -> Option<(KeyScript, [u8; 4])> {
+    SLIP132_VERSIONS.iter().find_map(|(script, xpub, xprv, tpub, tprv)| match private {
+        false if version == *xpub => Some((*script, XPUB_VERSION)),
+        false if version == *tpub => Some((*script, TPUB_VERSION)),
+        true if version == *xprv => Some((*script, XPRV_VERSION)),
+        true if version == *tprv => Some((*script, TPRV_VERSION)),
+        _ => None,
+    })
+}
+
+// The SLIP-0132 version for `script` on the network of canonical `version`
+fn slip132_version(version: [u8; 4], script: KeyScript) -> [u8; 4] {
+    let (_, xpub, xprv, tpub, tprv) = SLIP132_VERSIONS.iter().find(|entry| entry.0 == script).unwrap();
+    match version {
+        XPUB_VERSION => *xpub,
+        XPRV_VERSION => *xprv,
+        TPUB_VERSION => *tpub,
+        _ => *tprv,
+    }
+}
+
+// Swaps the version of base58check extended key `s` for its canonical
+// one, returning what the original said
+fn canonicalize(s: &str, private: bool) -> Result<(String, KeyScript)> {
+    let mut bytes = decode_base58_checksum(s)?;
+    if bytes.len() != SERIALIZED_LEN {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("extended key of {} bytes", bytes.len())));
+    }
+    let version = bytes[0..4].try_into().unwrap();
+    let (script, canonical) = slip132_canonical(version, private).ok_or_else(|| Error::new(ErrorKind::UnknownNetwork, format!("SLIP-0132 version {:02x?}", version)))?;
+    bytes[0..4].copy_from_slice(&canonical);
+    Ok((encode_base58_checksum(&bytes), script))
+}
+
+// The fields before the key data: version, depth, parent fingerprint,
+// child number and chain code
+type KeyHeader = ([u8; 4], u8, [u8; 4], u32, [u8; 32]);
+
+// Decodes base58check `s` into its header and 33 bytes of key data
+fn decode_key(s: &str) -> Result<(KeyHeader, [u8; 33])> {
+    let bytes = decode_base58_checksum(s)?;
+    if bytes.len() != SERIALIZED_LEN {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("extended key of {} bytes", bytes.len())));
+    }
+    let header = (
+        bytes[0..4].try_into().unwrap(),
+        bytes[4],
+        bytes[5..9].try_into().unwrap(),
+        u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+        bytes[13..45].try_into().unwrap(),
+    );
+    Ok((header, bytes[45..78].try_into().unwrap()))
+}
+
+fn encode_key(header: KeyHeader, key: &[u8]) -> [u8; SERIALIZED_LEN] {
+    let (version, depth, parent_fingerprint, child_number, chain_code) = header;
+    let mut bytes = [0u8; SERIALIZED_LEN];
+    bytes[0..4].copy_from_slice(&version);
+    bytes[4] = depth;
+    bytes[5..9].copy_from_slice(&parent_fingerprint);
+    bytes[9..13].copy_from_slice(&child_number.to_be_bytes());
+    bytes[13..45].copy_from_slice(&chain_code);
+    bytes[45..78].copy_from_slice(key);
+    bytes
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> [u8; 64] {
+    let mut mac = HmacSha512::new_from_slice(key).unwrap();
+    for part in data {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn child_depth(depth: u8) -> Result<u8> {
+    depth.checked_add(1).ok_or_else(|| Error::new(ErrorKind::OutOfRange, "derivation deeper than 255"))
+}
+
+/// An extended private key: a private key with a chain code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedPrivKey {
+    pub version: [u8; 4],
+    /// 0 for a master key
+    pub depth: u8,
+    /// First 4 bytes of the parent key's hash160
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub private_key: PrivateKey,
+}
+
+impl ExtendedPrivKey {
+    /// The master key of a 16 to 64 byte `seed`, "xprv" on mainnet and
+    /// "tprv" on the test networks.
+    pub fn new_master(seed: &[u8], network: Network) -> Result<ExtendedPrivKey> {
+        if seed.len() < 16 || seed.len() > 64 {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("seed of {} bytes", seed.len())));
+        }
+        let i = hmac_sha512(b"Bitcoin seed", &[seed]);
+        let secret = bytes_to_int(&i[..32]);
+        Ok(ExtendedPrivKey {
+            version: if network == Network::Mainnet { XPRV_VERSION } else { TPRV_VERSION },
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            chain_code: i[32..].try_into().unwrap(),
+            private_key: PrivateKey::new(&secret).map_err(|_| Error::new(ErrorKind::OutOfRange, "seed gives an invalid master key"))?,
+        })
+    }
+
+    /// Parses the base58check form, "xprv..." or "tprv...".
+    pub fn parse(s: &str) -> Result<ExtendedPrivKey> {
+        let ((version, depth, parent_fingerprint, child_number, chain_code), key) = decode_key(s)?;
+        if version != XPRV_VERSION && version != TPRV_VERSION {
+            return Err(Error::new(ErrorKind::UnknownNetwork, format!("extended private key version {:02x?}", version)));
+        }
+        if key[0] != 0 {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "extended private key data does not start with 0"));
+        }
+        let private_key = PrivateKey::new(&bytes_to_int(&key[1..]))?;
+        Ok(ExtendedPrivKey { version, depth, parent_fingerprint, child_number, chain_code, private_key })
+    }
+
+    /// Parses a key with any SLIP-0132 private version, "yprv", "zprv",
+    /// "vprv" and so on, into the canonical "xprv" or "tprv" key.
+    pub fn parse_slip132(s: &str) -> Result<(ExtendedPrivKey, KeyScript)> {
+        let (canonical, script) = canonicalize(s, true)?;
+        Ok((ExtendedPrivKey::parse(&canonical)?, script))
+    }
+
+    /// The base58check form with the SLIP-0132 version for `script`.
+    pub fn to_slip132(&self, script: KeyScript) -> String {
+        let mut bytes = self.serialize();
+        bytes[0..4].copy_from_slice(&slip132_version(self.version, script));
+        encode_base58_checksum(&bytes)
+    }
+
+    pub fn serialize(&self) -> [u8; SERIALIZED_LEN] {
+        let mut key = [0u8; 33];
+        key[1..].copy_from_slice(self.private_key.secret().as_bytes());
+        encode_key((self.version, self.depth, self.parent_fingerprint, self.child_number, self.chain_code), &key)
+    }
+
+    /// The first 4 bytes of the public key's hash160.
+    pub fn fingerprint(&self) -> [u8; 4] {
+        hash160(&self.private_key.public_key().sec(true))[..4].try_into().unwrap()
+    }
+
+    /// The extended public key with the same chain code, "xpub" or "tpub".
+    pub fn extended_pub_key(&self) -> ExtendedPubKey {
+        ExtendedPubKey {
+            version: if self.version == XPRV_VERSION { XPUB_VERSION } else { TPUB_VERSION },
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            public_key: self.private_key.public_key().clone(),
+        }
+    }
+
+    /// CKDpriv: the child key at `index`, hardened from `HARDENED` on. The
+    /// rare index whose child is not a valid key is `ErrorKind::OutOfRange`.
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedPrivKey> {
+        let secret = self.private_key.secret();
+        let i = if index >= HARDENED {
+            hmac_sha512(&self.chain_code, &[&[0], secret.as_bytes(), &index.to_be_bytes()])
+        } else {
+            hmac_sha512(&self.chain_code, &[&self.private_key.public_key().sec(true), &index.to_be_bytes()])
+        };
+        let tweak = bytes_to_int(&i[..32]);
+        if tweak >= *s256_order() {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("child {} has an invalid tweak", index)));
+        }
+        let child = (tweak + secret.to_bigint()).mod_floor(s256_order());
+        Ok(ExtendedPrivKey {
+            version: self.version,
+            depth: child_depth(self.depth)?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code: i[32..].try_into().unwrap(),
+            private_key: PrivateKey::new(&child).map_err(|_| Error::new(ErrorKind::OutOfRange, format!("child {} is zero", index)))?,
+        })
+    }
+
+    /// Derives each index of `path` in turn.
+    pub fn derive_path(&self, path: &[u32]) -> Result<ExtendedPrivKey> {
+        path.iter().try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+}
+
+impl fmt::Display for ExtendedPrivKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", encode_base58_checksum(&self.serialize()))
+    }
+}
+
+impl FromStr for ExtendedPrivKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ExtendedPrivKey> {
+        ExtendedPrivKey::parse(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExtendedPubKey {
+    pub version: [u8; 4],
+    /// 0 for a master key
+    pub depth: u8,
+    /// First 4 bytes of the parent key's hash160
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub public_key: S256Point,
+}
+
+impl ExtendedPubKey {
+    /// Parses the base58check form, "xpub..." or "tpub...".
+    pub fn parse(s: &str) -> Result<ExtendedPubKey> {
+        let ((version, depth, parent_fingerprint, child_number, chain_code), sec) = decode_key(s)?;
+        if version != XPUB_VERSION && version != TPUB_VERSION {
+            return Err(Error::new(ErrorKind::UnknownNetwork, format!("extended public key version {:02x?}", version)));
+        }
+        if sec[0] != 2 && sec[0] != 3 {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "extended public key is not a compressed key"));
+        }
+        Ok(ExtendedPubKey { version, depth, parent_fingerprint, child_number, chain_code, public_key: S256Point::parse_sec(&sec)? })
+    }
+
+    /// Parses a key with any SLIP-0132 public version, "ypub", "zpub",
+    /// "vpub" and so on, into the canonical "xpub" or "tpub" key.
+    pub fn parse_slip132(s: &str) -> Result<(ExtendedPubKey, KeyScript)> {
+        let (canonical, script) = canonicalize(s, false)?;
+        Ok((ExtendedPubKey::parse(&canonical)?, script))
+    }
+
+    /// The base58check form with the SLIP-0132 version for `script`.
+    pub fn to_slip132(&self, script: KeyScript) -> String {
+        let mut bytes = self.serialize();
+        bytes[0..4].copy_from_slice(&slip132_version(self.version, script));
+        encode_base58_checksum(&bytes)
+    }
+
+    pub fn serialize(&self) -> [u8; SERIALIZED_LEN] {
+        encode_key((self.version, self.depth, self.parent_fingerprint, self.child_number, self.chain_code), &self.public_key.sec(true))
+    }
+
+    /// The first 4 bytes of the key's hash160, identifying it to children.
+    pub fn fingerprint(&self) -> [u8; 4] {
+        hash160(&self.public_key.sec(true))[..4].try_into().unwrap()
+    }
+
+    /// CKDpub: the child key at `index`. Hardened children need the private
+    /// key and are `ErrorKind::OutOfRange`, as is the rare index whose
+    /// tweak is not a valid scalar.
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedPubKey> {
+        if index >= HARDENED {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("hardened child {}' of a public key", index - HARDENED)));
+        }
+        let i = hmac_sha512(&self.chain_code, &[&self.public_key.sec(true), &index.to_be_bytes()]);
+        let tweak = bytes_to_int(&i[..32]);
+        if tweak >= *s256_order() {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("child {} has an invalid tweak", index)));
+        }
+        let public_key = &S256Point::generator().scalar_mul(&tweak) + &self.public_key;
+        if public_key.is_infinity() {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("child {} is the point at infinity", index)));
+        }
+        Ok(ExtendedPubKey {
+            version: self.version,
+            depth: child_depth(self.depth)?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code: i[32..].try_into().unwrap(),
+            public_key,
+        })
+    }
+
+    /// Derives each index of `path` in turn.
+    pub fn derive_path(&self, path: &[u32]) -> Result<ExtendedPubKey> {
+        path.iter().try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+}
+
+impl fmt::Display for ExtendedPubKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", encode_base58_checksum(&self.serialize()))
+    }
+}
+
+impl FromStr for ExtendedPubKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ExtendedPubKey> {
+        ExtendedPubKey::parse(s)
+    }
+}
+
+#[test]
+fn derive_public_children() {
+    // BIP32 test vector 1, chains m/0H/1 and m/0H/1/2H/2/1000000000
+    let parent = ExtendedPubKey::parse("xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw").unwrap();
+    let child = parent.derive_child(1).unwrap();
+    assert_eq!(child.to_string(), "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ");
+    assert_eq!((child.depth, child.parent_fingerprint), (2, parent.fingerprint()));
+    assert!(child.derive_child(HARDENED + 2).is_err());
+
+    let m_0h_1_2h_2 = ExtendedPubKey::parse("xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV").unwrap();
+    assert_eq!(
+        m_0h_1_2h_2.derive_path(&[1_000_000_000]).unwrap().to_string(),
+        "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy"
+    );
+    assert_eq!(ExtendedPubKey::parse("xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwR").unwrap_err().kind(), ErrorKind::InvalidEncoding);
+}
+
+#[test]
+fn derive_private_children() {
+    // BIP32 test vector 1
+    let seed = crate::encoding::util::decode_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+    let master = ExtendedPrivKey::new_master(&seed, Network::Mainnet).unwrap();
+    assert_eq!(master.to_string(), "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi");
+    assert_eq!(master.extended_pub_key().to_string(), "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8");
+    let m_0h_1_2h = master.derive_path(&[HARDENED, 1, HARDENED + 2]).unwrap();
+    assert_eq!(m_0h_1_2h.to_string(), "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM");
+    assert_eq!(m_0h_1_2h.extended_pub_key().to_string(), "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5");
+    // Non-hardened children agree with CKDpub
+    assert_eq!(master.derive_path(&[HARDENED, 1]).unwrap().extended_pub_key(), master.derive_child(HARDENED).unwrap().extended_pub_key().derive_child(1).unwrap());
+    assert_eq!(ExtendedPrivKey::parse(&m_0h_1_2h.to_string()).unwrap(), m_0h_1_2h);
+
+    // Test vector 3: leading zeros in the private key are kept
+    let seed = crate::encoding::util::decode_hex("4b381541583be4423346c643850da4b320e46a87ae3d2a4e6da11eba819cd4acba45d239319ac14f863b8d5ab5a0d0c64d2e8a1e7d1457df2e5a3c51c73235be").unwrap();
+    let master = ExtendedPrivKey::new_master(&seed, Network::Mainnet).unwrap();
+    assert_eq!(master.derive_child(HARDENED).unwrap().to_string(), "xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L");
+    assert!(ExtendedPrivKey::new_master(&seed[..15], Network::Mainnet).is_err());
+    assert_eq!(ExtendedPrivKey::parse("xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8").unwrap_err().kind(), ErrorKind::UnknownNetwork);
+}
+
+#[test]
+fn slip132_versions() {
+    // BIP84's account key for "abandon ... about"
+    let zpub = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    let (xpub, script) = ExtendedPubKey::parse_slip132(zpub).unwrap();
+    assert_eq!((xpub.version, script), (XPUB_VERSION, KeyScript::P2wpkh));
+    assert_eq!(xpub.to_slip132(KeyScript::P2wpkh), zpub);
+    assert_eq!(ExtendedPubKey::parse(zpub).unwrap_err().kind(), ErrorKind::UnknownNetwork);
+    assert_eq!(ExtendedPubKey::parse_slip132(&xpub.to_string()).unwrap().1, KeyScript::P2pkh);
+
+    let master = ExtendedPrivKey::new_master(&[7; 32], Network::Testnet).unwrap();
+    let vprv = master.to_slip132(KeyScript::P2wsh);
+    assert!(vprv.starts_with("Vprv"));
+    assert_eq!(ExtendedPrivKey::parse_slip132(&vprv).unwrap(), (master.clone(), KeyScript::P2wsh));
+    assert!(master.extended_pub_key().to_slip132(KeyScript::P2wpkh).starts_with("vpub"));
+    assert!(ExtendedPubKey::parse_slip132(&vprv).is_err());
+}
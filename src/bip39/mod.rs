@@ -0,0 +1,165 @@
+//! BIP39 mnemonics: entropy written as words from the English list, with
+//! a checksum, and the PBKDF2 stretch that turns them into a BIP32 seed.
+//!
+//! Entropy must come from the caller's secure random source. Phrases and
+//! passphrases are used as given, so they must already be in NFKD form,
+//! which ASCII text always is.
+
+use crate::bip32::ExtendedPrivKey;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::sha256;
+use crate::params::Network;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use zeroize::Zeroize;
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// PBKDF2 iterations for the seed.
+pub const SEED_ROUNDS: u32 = 2048;
+
+static ENGLISH: OnceLock<Vec<&'static str>> = OnceLock::new();
+
+/// The 2048 English words, sorted.
+pub fn english_words() -> &'static [&'static str] {
+    ENGLISH.get_or_init(|| include_str!("english.txt").lines().collect())
+}
+
+/// A mnemonic, kept as its entropy, which is wiped when dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Mnemonic {
+    entropy: Vec<u8>,
+}
+
+impl Mnemonic {
+    /// The mnemonic for 16 to 32 bytes of entropy, a multiple of 4: 12 to
+    /// 24 words.
+    pub fn from_entropy(entropy: &[u8]) -> Result<Mnemonic> {
+        if entropy.len() < 16 || entropy.len() > 32 || !entropy.len().is_multiple_of(4) {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("{} bytes of entropy", entropy.len())));
+        }
+        Ok(Mnemonic { entropy: entropy.to_vec() })
+    }
+
+    /// Parses a phrase of 12, 15, 18, 21 or 24 words, checking the checksum.
+    pub fn parse(phrase: &str) -> Result<Mnemonic> {
+        let words: Vec<&str> = phrase.split_whitespace().collect();
+        if words.len() < 12 || words.len() > 24 || !words.len().is_multiple_of(3) {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("mnemonic of {} words", words.len())));
+        }
+        // Each word is 11 bits: the entropy, then a checksum bit for
+        // every 32 bits of it
+        let mut bits = Vec::with_capacity(words.len() * 11);
+        for word in words.iter() {
+            let index = english_words().binary_search(word).map_err(|_| Error::new(ErrorKind::InvalidEncoding, format!("{} is not a BIP39 word", word)))?;
+            bits.extend((0..11).rev().map(|bit| (index >> bit) & 1 == 1));
+        }
+        let entropy_bits = bits.len() * 32 / 33;
+        let mut entropy: Vec<u8> = bits[..entropy_bits].chunks(8).map(|byte| byte.iter().fold(0u8, |acc, bit| acc << 1 | *bit as u8)).collect();
+        let mnemonic = Mnemonic::from_entropy(&entropy)?;
+        entropy.zeroize();
+        if mnemonic.checksum_bits() != bits[entropy_bits..] {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "mnemonic checksum mismatch"));
+        }
+        Ok(mnemonic)
+    }
+
+    pub fn entropy(&self) -> &[u8] {
+        &self.entropy
+    }
+
+    // The first entropy length / 32 bits of its sha256
+    fn checksum_bits(&self) -> Vec<bool> {
+        let hash = sha256(&self.entropy);
+        (0..self.entropy.len() / 4).map(|i| hash[i / 8] >> (7 - i % 8) & 1 == 1).collect()
+    }
+
+    pub fn words(&self) -> Vec<&'static str> {
+        let mut bits: Vec<bool> = self.entropy.iter().flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1)).collect();
+        bits.extend(self.checksum_bits());
+        bits.chunks(11).map(|word| english_words()[word.iter().fold(0usize, |acc, bit| acc << 1 | *bit as usize)]).collect()
+    }
+
+    /// The 64 byte seed: PBKDF2-HMAC-SHA512 of the phrase, salted with
+    /// "mnemonic" and `passphrase`.
+    pub fn to_seed(&self, passphrase: &str) -> [u8; 64] {
+        let mut phrase = self.to_string();
+        let mac = HmacSha512::new_from_slice(phrase.as_bytes()).unwrap();
+        phrase.zeroize();
+        // One block of output, so one chain of 2048 HMACs
+        let mut round = mac.clone();
+        round.update(b"mnemonic");
+        round.update(passphrase.as_bytes());
+        round.update(&1u32.to_be_bytes());
+        let mut u: [u8; 64] = round.finalize().into_bytes().into();
+        let mut seed = u;
+        for _ in 1..SEED_ROUNDS {
+            let mut round = mac.clone();
+            round.update(&u);
+            u = round.finalize().into_bytes().into();
+            seed.iter_mut().zip(u.iter()).for_each(|(s, u)| *s ^= u);
+        }
+        u.zeroize();
+        seed
+    }
+
+    /// The BIP32 master key of the seed for `passphrase`.
+    pub fn to_master_key(&self, passphrase: &str, network: Network) -> Result<ExtendedPrivKey> {
+        let mut seed = self.to_seed(passphrase);
+        let master = ExtendedPrivKey::new_master(&seed, network);
+        seed.zeroize();
+        master
+    }
+}
+
+impl Drop for Mnemonic {
+    fn drop(&mut self) {
+        self.entropy.zeroize();
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.words().join(" "))
+    }
+}
+
+impl fmt::Debug for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mnemonic(<redacted>)")
+    }
+}
+
+impl FromStr for Mnemonic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Mnemonic> {
+        Mnemonic::parse(s)
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+
+#[test]
+fn mnemonic_vectors() {
+    // The reference implementation's vectors, all with passphrase "TREZOR"
+    assert_eq!(english_words().len(), 2048);
+    let mnemonic = Mnemonic::from_entropy(&[0; 16]).unwrap();
+    assert_eq!(mnemonic.to_string(), "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about");
+    assert_eq!(encode_hex(&mnemonic.to_seed("TREZOR")), "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04");
+    assert_eq!(mnemonic.to_master_key("TREZOR", Network::Mainnet).unwrap().to_string(), "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF");
+
+    let mnemonic = Mnemonic::parse("legal winner thank year wave sausage worth useful legal winner thank yellow").unwrap();
+    assert_eq!(mnemonic.entropy(), &[0x7f; 16][..]);
+    let zoo = Mnemonic::from_entropy(&decode_hex(&"ff".repeat(32)).unwrap()).unwrap();
+    assert_eq!(zoo.to_string(), format!("{}vote", "zoo ".repeat(23)));
+    assert_eq!(Mnemonic::parse(&zoo.to_string()).unwrap(), zoo);
+
+    assert_eq!(Mnemonic::parse(&"abandon ".repeat(12)).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert_eq!(Mnemonic::parse("abandon abandon abandon").unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert!(Mnemonic::from_entropy(&[0; 15]).is_err());
+}
@@ -0,0 +1,202 @@
+//! BIP158 compact block filters and BIP157 filter headers.
+//!
+//! A basic filter is a Golomb-coded set of every script a block's
+//! transactions create or spend. Each script hashes, with SipHash keyed by
+//! the block hash, into `[0, N * M)`; the sorted values are stored as
+//! deltas, each a unary quotient and a `P` bit remainder. A client fetches
+//! the filter and checks its own scripts against it locally.
+
+use super::Block;
+use crate::encoding::varint::{encode_varint, read_varint};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{hash256, siphash24};
+
+/// Golomb-Rice parameter of the basic filter.
+pub const BASIC_FILTER_P: u8 = 19;
+/// Inverse false positive rate of the basic filter.
+pub const BASIC_FILTER_M: u64 = 784_931;
+
+const OP_RETURN: u8 = 0x6a;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// Hash of the block the filter is for, in display order
+    pub block_hash: [u8; 32],
+    /// The serialized set: its size as a varint, then the bit stream
+    pub content: Vec<u8>,
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u8) {
+        for i in (0..bits).rev() {
+            if self.used.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= ((value >> i & 1) as u8) << (7 - self.used % 8);
+            self.used += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u8) -> Result<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.bytes.get(self.pos / 8).ok_or_else(|| Error::new(ErrorKind::InvalidEncoding, "block filter ends early"))?;
+            value = value << 1 | (byte >> (7 - self.pos % 8) & 1) as u64;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+}
+
+// The SipHash key: the first 16 bytes of the block hash in internal order
+fn sip_key(block_hash: &[u8; 32]) -> (u64, u64) {
+    let mut internal = *block_hash;
+    internal.reverse();
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&internal[..8]);
+    k1.copy_from_slice(&internal[8..16]);
+    (u64::from_le_bytes(k0), u64::from_le_bytes(k1))
+}
+
+// Each item's hash mapped onto [0, f), sorted
+fn hashed_set(block_hash: &[u8; 32], items: &[Vec<u8>], f: u64) -> Vec<u64> {
+    let (k0, k1) = sip_key(block_hash);
+    let mut values: Vec<u64> = items.iter().map(|item| ((siphash24(k0, k1, item) as u128 * f as u128) >> 64) as u64).collect();
+    values.sort_unstable();
+    values
+}
+
+impl BlockFilter {
+    /// The Golomb-coded set of `items`, duplicates removed.
+    pub fn from_items(block_hash: [u8; 32], items: &[Vec<u8>]) -> BlockFilter {
+        let mut items = items.to_vec();
+        items.sort();
+        items.dedup();
+        let mut content = vec![];
+        encode_varint(&mut content, items.len() as u64).unwrap();
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in hashed_set(&block_hash, &items, items.len() as u64 * BASIC_FILTER_M) {
+            let delta = value - last;
+            last = value;
+            for _ in 0..delta >> BASIC_FILTER_P {
+                writer.write(1, 1);
+            }
+            writer.write(0, 1);
+            writer.write(delta, BASIC_FILTER_P);
+        }
+        content.extend_from_slice(&writer.bytes);
+        BlockFilter { block_hash, content }
+    }
+
+    /// The basic filter of `block`: every output script but empty and
+    /// OP_RETURN ones, and the scripts its inputs spend. `spent_scripts`
+    /// are the latter, which only the spent transactions hold.
+    pub fn new_basic(block: &Block, spent_scripts: &[Vec<u8>]) -> BlockFilter {
+        let mut items: Vec<Vec<u8>> = block
+            .txs
+            .iter()
+            .flat_map(|tx| tx.tx_outs.iter())
+            .map(|tx_out| tx_out.script_pubkey.clone())
+            .filter(|script| !script.is_empty() && script[0] != OP_RETURN)
+            .collect();
+        items.extend(spent_scripts.iter().filter(|script| !script.is_empty()).cloned());
+        BlockFilter::from_items(block.header.hash(), &items)
+    }
+
+    fn decode(&self) -> Result<Vec<u64>> {
+        let mut reader = &self.content[..];
+        let n = read_varint(&mut reader)?;
+        let mut bits = BitReader { bytes: reader, pos: 0 };
+        let mut values = vec![];
+        let mut last = 0u64;
+        for _ in 0..n {
+            let mut quotient = 0;
+            while bits.read(1)? == 1 {
+                quotient += 1;
+            }
+            last += quotient << BASIC_FILTER_P | bits.read(BASIC_FILTER_P)?;
+            values.push(last);
+        }
+        Ok(values)
+    }
+
+    /// Whether any of `scripts` may be in the block. False positives happen
+    /// at a rate of about 1 in `BASIC_FILTER_M` per script.
+    pub fn match_any(&self, scripts: &[Vec<u8>]) -> Result<bool> {
+        let values = self.decode()?;
+        if values.is_empty() || scripts.is_empty() {
+            return Ok(false);
+        }
+        let queries = hashed_set(&self.block_hash, scripts, values.len() as u64 * BASIC_FILTER_M);
+        // Both lists are sorted, so walk them together
+        let (mut i, mut j) = (0, 0);
+        while i < values.len() && j < queries.len() {
+            match values[i].cmp(&queries[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// The double SHA256 of the content, in display order.
+    pub fn filter_hash(&self) -> [u8; 32] {
+        let mut hash = hash256(&self.content);
+        hash.reverse();
+        hash
+    }
+
+    /// The BIP157 header chaining this filter to the previous block's
+    /// filter header, both in display order. The genesis block's previous
+    /// header is all zeros.
+    pub fn filter_header(&self, prev_header: &[u8; 32]) -> [u8; 32] {
+        let mut preimage = hash256(&self.content).to_vec();
+        preimage.extend(prev_header.iter().rev());
+        let mut header = hash256(&preimage);
+        header.reverse();
+        header
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+#[cfg(test)]
+use crate::params::Network;
+
+#[test]
+fn basic_filters() {
+    // BIP158's first vector: the testnet genesis block
+    let genesis = decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d1aa4ae180101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000").unwrap();
+    let block = Block::parse(&mut &genesis[..], Network::Testnet).unwrap();
+    assert_eq!(block.header.id(), "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943");
+    let filter = BlockFilter::new_basic(&block, &[]);
+    assert_eq!(encode_hex(&filter.content), "019dfca8");
+    assert_eq!(encode_hex(&filter.filter_header(&[0; 32])), "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750");
+    assert!(filter.match_any(&[block.txs[0].tx_outs[0].script_pubkey.clone()]).unwrap());
+    assert!(!filter.match_any(&[vec![0x51]]).unwrap());
+
+    let items: Vec<Vec<u8>> = (0u32..100).map(|i| i.to_le_bytes().to_vec()).collect();
+    let filter = BlockFilter::from_items([7; 32], &items);
+    assert_eq!(filter.decode().unwrap().len(), 100);
+    assert!(items.iter().all(|item| filter.match_any(&[vec![0xff], item.clone()]).unwrap()));
+    assert!(!filter.match_any(&[b"not in the block".to_vec()]).unwrap());
+    assert!(!BlockFilter::from_items([7; 32], &[]).match_any(&items).unwrap());
+    assert!(BlockFilter { content: filter.content[..20].to_vec(), ..filter }.decode().is_err());
+}
@@ -0,0 +1,134 @@
+//! Merkle trees over transaction hashes (chapter 11).
+//!
+//! Hashes here are in internal byte order, the reverse of how txids are
+//! displayed. A level with an odd number of hashes pairs its last hash with
+//! itself.
+
+use crate::hash::hash256;
+
+/// The hash of two children.
+pub fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    hash256(&[&left[..], &right[..]].concat())
+}
+
+/// The level above `hashes`, half as long rounded up.
+pub fn merkle_parent_level(hashes: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    hashes.chunks(2).map(|pair| merkle_parent(&pair[0], pair.last().unwrap())).collect()
+}
+
+/// The root of the tree over `hashes`, all zeros when there are none.
+pub fn merkle_root(hashes: &[[u8; 32]]) -> [u8; 32] {
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = merkle_parent_level(&level);
+    }
+    level.first().copied().unwrap_or([0; 32])
+}
+
+/// Every level of a tree, so proofs can be read off it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// The leaves first, the root last
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+/// The path from a leaf to the root: the sibling at each level, bottom up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Position of the leaf, whose bits say which side each sibling is on
+    pub index: usize,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: &[[u8; 32]]) -> MerkleTree {
+        let mut levels = vec![leaves.to_vec()];
+        while levels[levels.len() - 1].len() > 1 {
+            let parent = merkle_parent_level(&levels[levels.len() - 1]);
+            levels.push(parent);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.levels[self.levels.len() - 1].first().copied().unwrap_or([0; 32])
+    }
+
+    pub fn leaves(&self) -> &[[u8; 32]] {
+        &self.levels[0]
+    }
+
+    /// The proof that `leaf` is in the tree, or `None` if it isn't.
+    pub fn proof(&self, leaf: &[u8; 32]) -> Option<MerkleProof> {
+        let index = self.leaves().iter().position(|hash| hash == leaf)?;
+        let siblings = self.levels[..self.levels.len() - 1]
+            .iter()
+            .enumerate()
+            .map(|(depth, level)| {
+                let sibling = (index >> depth) ^ 1;
+                // A lone last hash is its own sibling
+                level[sibling.min(level.len() - 1)]
+            })
+            .collect();
+        Some(MerkleProof { index, siblings })
+    }
+}
+
+impl MerkleProof {
+    /// Whether the proof leads from `leaf` to `root`.
+    pub fn verify(&self, leaf: &[u8; 32], root: &[u8; 32]) -> bool {
+        let mut hash = *leaf;
+        for (depth, sibling) in self.siblings.iter().enumerate() {
+            hash = if (self.index >> depth) & 1 == 0 { merkle_parent(&hash, sibling) } else { merkle_parent(sibling, &hash) };
+        }
+        self.index >> self.siblings.len() == 0 && hash == *root
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+
+#[test]
+fn merkle_proofs() {
+    let hash = |hex: &str| {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&decode_hex(hex).unwrap());
+        hash
+    };
+    let hashes: Vec<[u8; 32]> = [
+        "c117ea8ec828342f4dfb0ad6bd140e03a50720ece40169ee38bdc15d9eb64cf5",
+        "c131474164b412e3406696da1ee20ab0fc9bf41c8f05fa8ceea7a08d672d7cc5",
+        "f391da6ecfeed1814efae39e7fcb3838ae0b02c02ae7d0a5848a66947c0727b0",
+        "3d238a92a94532b946c90e19c49351c763696cff3db400485b813aecb8a13181",
+        "10092f2633be5f3ce349bf9ddbde36caa3dd10dfa0ec8106bce23acbff637dae",
+        "7d37b3d54fa6a64869084bfd2e831309118b9e833610e6228adacdbd1b4ba161",
+        "8118a77e542892fe15ae3fc771a4abfd2f5d5d5997544c3487ac36b5c85170fc",
+        "dff6879848c2c9b62fe652720b8df5272093acfaa45a43cdb3696fe2466a3877",
+        "b825c0745f46ac58f7d3759e6dc535a1fec7820377f24d4c2c6ad2cc55c0cb59",
+        "95513952a04bd8992721e9b7e2937f1c04ba31e0469fbe615a78197f68f52b7c",
+        "2e6d722e5e4dbdf2447ddecc9f7dabb8e299bae921c99ad5b0184cd9eb8e5908",
+        "b13a750047bc0bdceb2473e5fe488c2596d7a7124b4e716fdd29b046ef99bbf0",
+    ]
+    .iter()
+    .map(|hex| hash(hex))
+    .collect();
+    // Chapter 11's examples
+    assert_eq!(encode_hex(&merkle_parent(&hashes[0], &hashes[1])), "8b30c5ba100f6f2e5ad1e2a742e5020491240f8eb514fe97c713c31718ad7ecd");
+    assert_eq!(merkle_parent_level(&hashes[..11]).len(), 6);
+    let root = merkle_root(&hashes);
+    assert_eq!(encode_hex(&root), "acbcab8bcc1af95d8d563b77d24c3d19b18f1486383d75a5085c4e86c86beed6");
+
+    let tree = MerkleTree::new(&hashes[..11]);
+    assert_eq!(tree.root(), merkle_root(&hashes[..11]));
+    for leaf in hashes[..11].iter() {
+        let proof = tree.proof(leaf).unwrap();
+        assert_eq!(proof.siblings.len(), 4);
+        assert!(proof.verify(leaf, &tree.root()));
+        assert!(!proof.verify(leaf, &root));
+    }
+    let proof = tree.proof(&hashes[3]).unwrap();
+    assert!(!proof.verify(&hashes[2], &tree.root()));
+    assert!(!MerkleProof { index: proof.index + 16, ..proof }.verify(&hashes[3], &tree.root()));
+    assert!(tree.proof(&hashes[11]).is_none());
+    assert_eq!(MerkleTree::new(&hashes[..1]).proof(&hashes[0]).unwrap().siblings, Vec::<[u8; 32]>::new());
+}
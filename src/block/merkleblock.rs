@@ -0,0 +1,171 @@
+//! The BIP37 merkleblock message (chapter 11): a header plus the partial
+//! merkle tree proving which of its transactions matched a bloom filter.
+//!
+//! The tree is walked depth first. Each node takes a flag bit: a clear bit,
+//! or any leaf, takes the next hash as is; a set bit on an inner node means
+//! its children follow. Set leaves are the matches.
+
+use super::merkle::merkle_parent;
+use super::BlockHeader;
+use crate::encoding::util::{read_bytes, read_u32_le};
+use crate::encoding::varint::{encode_varint, read_varint_len};
+use crate::error::{Error, ErrorKind, Result};
+use std::io::Read;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    /// Transactions in the whole block
+    pub total: u32,
+    /// Hashes in the depth first order of the walk, internal byte order
+    pub hashes: Vec<[u8; 32]>,
+    /// Flag bits, least significant bit of each byte first
+    pub flags: Vec<u8>,
+}
+
+/// Flag bytes as bits, least significant first.
+pub fn bytes_to_bit_field(bytes: &[u8]) -> Vec<bool> {
+    bytes.iter().flat_map(|byte| (0..8).map(move |i| byte >> i & 1 == 1)).collect()
+}
+
+struct PartialTree<'a> {
+    total: usize,
+    flag_bits: &'a [bool],
+    hashes: &'a [[u8; 32]],
+    bits_used: usize,
+    hashes_used: usize,
+    matched: Vec<[u8; 32]>,
+}
+
+impl PartialTree<'_> {
+    // Nodes at `height` above the leaves
+    fn width(&self, height: u32) -> usize {
+        (self.total + (1 << height) - 1) >> height
+    }
+
+    fn next_hash(&mut self) -> Result<[u8; 32]> {
+        let hash = self.hashes.get(self.hashes_used).ok_or_else(|| Error::new(ErrorKind::InvalidEncoding, "merkleblock ran out of hashes"))?;
+        self.hashes_used += 1;
+        Ok(*hash)
+    }
+
+    fn walk(&mut self, height: u32, pos: usize) -> Result<[u8; 32]> {
+        let flag = *self.flag_bits.get(self.bits_used).ok_or_else(|| Error::new(ErrorKind::InvalidEncoding, "merkleblock ran out of flag bits"))?;
+        self.bits_used += 1;
+        if height == 0 || !flag {
+            let hash = self.next_hash()?;
+            if height == 0 && flag {
+                self.matched.push(hash);
+            }
+            return Ok(hash);
+        }
+        let left = self.walk(height - 1, pos * 2)?;
+        if pos * 2 + 1 >= self.width(height - 1) {
+            return Ok(merkle_parent(&left, &left));
+        }
+        let right = self.walk(height - 1, pos * 2 + 1)?;
+        // Identical siblings would let one tree stand for two transaction lists
+        if left == right {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "merkleblock has identical sibling hashes"));
+        }
+        Ok(merkle_parent(&left, &right))
+    }
+}
+
+/// Rebuilds the root of a tree of `total` leaves from a merkleblock's flag
+/// bits and hashes, returning it with the matched hashes. Every hash must be
+/// used, and every flag bit but the last byte's padding.
+pub fn populate_tree(total: usize, flag_bits: &[bool], hashes: &[[u8; 32]]) -> Result<([u8; 32], Vec<[u8; 32]>)> {
+    if total == 0 {
+        return Err(Error::new(ErrorKind::InvalidEncoding, "merkleblock without transactions"));
+    }
+    let mut tree = PartialTree { total, flag_bits, hashes, bits_used: 0, hashes_used: 0, matched: vec![] };
+    let mut height = 0;
+    while tree.width(height) > 1 {
+        height += 1;
+    }
+    let root = tree.walk(height, 0)?;
+    if tree.hashes_used != hashes.len() || tree.bits_used.div_ceil(8) != flag_bits.len().div_ceil(8) {
+        return Err(Error::new(ErrorKind::InvalidEncoding, "merkleblock has unused hashes or flag bits"));
+    }
+    Ok((root, tree.matched))
+}
+
+impl MerkleBlock {
+    pub fn parse<R: Read>(reader: &mut R) -> Result<MerkleBlock> {
+        let header = BlockHeader::parse(reader)?;
+        let total = read_u32_le(reader)?;
+        let num_hashes = read_varint_len(reader)?;
+        let hashes = (0..num_hashes)
+            .map(|_| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&read_bytes(reader, 32)?);
+                Ok(hash)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let num_flags = read_varint_len(reader)?;
+        let flags = read_bytes(reader, num_flags)?;
+        Ok(MerkleBlock { header, total, hashes, flags })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize().to_vec();
+        result.extend_from_slice(&self.total.to_le_bytes());
+        encode_varint(&mut result, self.hashes.len() as u64).unwrap();
+        for hash in self.hashes.iter() {
+            result.extend_from_slice(hash);
+        }
+        encode_varint(&mut result, self.flags.len() as u64).unwrap();
+        result.extend_from_slice(&self.flags);
+        result
+    }
+
+    /// The txids the tree proves are in the block, in display order.
+    /// Fails if the tree is malformed or its root isn't the header's.
+    pub fn matched_txids(&self) -> Result<Vec<[u8; 32]>> {
+        let (mut root, matched) = populate_tree(self.total as usize, &bytes_to_bit_field(&self.flags), &self.hashes)?;
+        root.reverse();
+        if root != self.header.merkle_root {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "merkleblock root does not match its header"));
+        }
+        Ok(matched
+            .into_iter()
+            .map(|mut txid| {
+                txid.reverse();
+                txid
+            })
+            .collect())
+    }
+
+    /// Whether the tree is well formed and leads to the header's root.
+    pub fn is_valid(&self) -> bool {
+        self.matched_txids().is_ok()
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+
+#[test]
+fn parse_merkleblock() {
+    // Chapter 11's example
+    let raw = decode_hex("00000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670bf0d00000aba412a0d1480e370173072c9562becffe87aa661c1e4a6dbc305d38ec5dc088a7cf92e6458aca7b32edae818f9c2c98c37e06bf72ae0ce80649a38655ee1e27d34d9421d940b16732f24b94023e9d572a7f9ab8023434a4feb532d2adfc8c2c2158785d1bd04eb99df2e86c54bc13e139862897217400def5d72c280222c4cbaee7261831e1550dbb8fa82853e9fe506fc5fda3f7b919d8fe74b6282f92763cef8e625f977af7c8619c32a369b832bc2d051ecd9c73c51e76370ceabd4f25097c256597fa898d404ed53425de608ac6bfe426f6e2bb457f1c554866eb69dcb8d6bf6f880e9a59b3cd053e6c7060eeacaacf4dac6697dac20e4bd3f38a2ea2543d1ab7953e3430790a9f81e1c67f5b58c825acf46bd02848384eebe9af917274cdfbb1a28a5d58a23a17977def0de10d644258d9c54f886d47d293a411cb6226103b55635").unwrap();
+    let block = MerkleBlock::parse(&mut &raw[..]).unwrap();
+    assert_eq!((block.total, block.hashes.len(), encode_hex(&block.flags)), (3519, 10, "b55635".to_string()));
+    assert_eq!(block.serialize(), raw);
+    let matched = block.matched_txids().unwrap();
+    assert_eq!(matched.len(), 1);
+    assert!(block.is_valid());
+
+    let mut bad = block.clone();
+    bad.hashes.swap(0, 1);
+    assert!(!bad.is_valid());
+    let mut bad = block.clone();
+    bad.flags.push(0);
+    assert!(!bad.is_valid());
+
+    // A single transaction block is its own root
+    let (root, matched) = populate_tree(1, &[true], &[[7; 32]]).unwrap();
+    assert_eq!((root, matched), ([7; 32], vec![[7; 32]]));
+    assert!(populate_tree(2, &bytes_to_bit_field(&[0b111]), &[[7; 32], [7; 32]]).is_err());
+}
@@ -0,0 +1,105 @@
+//! Grinding nonces, the loop a mining client drives.
+//!
+//! A `Miner` asks its work function for the header to mine at each extra
+//! nonce, the counter a miner rolls in the coinbase (stratum's
+//! extranonce2) to get a new merkle root once the header's 2^32 nonces
+//! are used up. `mine` tries a bounded number of hashes and returns, so a
+//! client can check for new jobs in between; the next call carries on
+//! where it stopped. A pool's share target is easier than the block's,
+//! so the target is the caller's: `BlockHeader::target` for blocks.
+
+use super::BlockHeader;
+use num_bigint::BigInt;
+
+// `target` as 32 big-endian bytes, comparable with `BlockHeader::hash`.
+// Anything past 256 bits is met by every hash
+fn target_bytes(target: &BigInt) -> [u8; 32] {
+    let (_, bytes) = target.to_bytes_be();
+    if bytes.len() > 32 {
+        return [0xff; 32];
+    }
+    let mut padded = [0u8; 32];
+    padded[32 - bytes.len()..].copy_from_slice(&bytes);
+    padded
+}
+
+/// Whether `header` hashes below `target`.
+pub fn meets_target(header: &BlockHeader, target: &BigInt) -> bool {
+    header.hash() < target_bytes(target)
+}
+
+pub struct Miner<W: FnMut(u64) -> BlockHeader> {
+    work: W,
+    extra_nonce: u64,
+    /// The header the next hash is of
+    header: BlockHeader,
+    /// Nonces left in the header, the current one included
+    left: u64,
+    /// Hashes tried so far
+    pub hashes: u64,
+}
+
+impl<W: FnMut(u64) -> BlockHeader> Miner<W> {
+    /// Starts on `work(0)`, from the nonce it holds.
+    pub fn new(mut work: W) -> Miner<W> {
+        let header = work(0);
+        let left = (1u64 << 32) - header.nonce as u64;
+        Miner { work, extra_nonce: 0, header, left, hashes: 0 }
+    }
+
+    /// The extra nonce of the header being mined.
+    pub fn extra_nonce(&self) -> u64 {
+        self.extra_nonce
+    }
+
+    /// Tries up to `max_hashes` nonces, returning the first header that
+    /// meets `target` and the extra nonce it was built with.
+    pub fn mine(&mut self, target: &BigInt, max_hashes: u64) -> Option<(u64, BlockHeader)> {
+        let target = target_bytes(target);
+        for _ in 0..max_hashes {
+            if self.left == 0 {
+                self.extra_nonce += 1;
+                self.header = (self.work)(self.extra_nonce);
+                self.left = (1u64 << 32) - self.header.nonce as u64;
+            }
+            let found = self.header.hash() < target;
+            let header = self.header.clone();
+            self.hashes += 1;
+            self.left -= 1;
+            self.header.nonce = self.header.nonce.wrapping_add(1);
+            if found {
+                return Some((self.extra_nonce, header));
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn miner() {
+    let template = |extra_nonce: u64, nonce: u32| BlockHeader {
+        version: 0x2000_0000,
+        prev_block: [1; 32],
+        merkle_root: [extra_nonce as u8; 32],
+        timestamp: 1_700_000_000,
+        bits: 0x207fffff,
+        nonce,
+    };
+    let mut miner = Miner::new(|extra_nonce| template(extra_nonce, 0));
+    let target = template(0, 0).target().unwrap();
+    let (extra_nonce, header) = miner.mine(&target, 1_000).unwrap();
+    assert!(header.check_pow() && meets_target(&header, &target));
+    assert_eq!((extra_nonce, miner.hashes), (0, header.nonce as u64 + 1));
+    // The next call carries on past the block found
+    let (_, next) = miner.mine(&target, 1_000).unwrap();
+    assert!(next.nonce > header.nonce && next.check_pow());
+    assert!(!meets_target(&header, &BigInt::from(0)));
+    assert!(meets_target(&header, &(BigInt::from(1) << 256)));
+
+    // Out of nonces, it rolls the extra nonce for a new merkle root
+    let mut miner = Miner::new(|extra_nonce| template(extra_nonce, u32::MAX - 1));
+    assert_eq!(miner.mine(&BigInt::from(0), 5), None);
+    assert_eq!((miner.extra_nonce(), miner.hashes), (2, 5));
+    let (extra_nonce, header) = miner.mine(&(BigInt::from(1) << 256), 1).unwrap();
+    assert_eq!((extra_nonce, header), (2, template(2, u32::MAX)));
+}
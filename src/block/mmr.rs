@@ -0,0 +1,212 @@
+//! A merkle mountain range over block headers, an experiment in proving a
+//! header is in the history of a chain tip without holding every header.
+//!
+//! Headers are appended in height order, their `BlockHeader::hash()`s the
+//! leaves. The range is a row of perfect merkle trees, the peaks, one for
+//! each set bit of the number of leaves and the largest first. Nodes are
+//! hashed with `merkle_parent` as in a block's tree, and the root bags the
+//! peaks from the right: `parent(p0, parent(p1, p2))`. Appending only
+//! merges the last peaks, so it takes O(log n) hashes.
+//!
+//! A light client keeps just the root for the tip's height. A proof is
+//! the leaf's path up to its peak plus the other peaks, checked by
+//! `MmrProof::verify`; it proves against that one root, so it has to be
+//! made again as the chain grows.
+
+use super::merkle::merkle_parent;
+use super::BlockHeader;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMmr {
+    /// The leaves first, then the nodes of each height above them. Only
+    /// nodes whose subtrees are complete are kept
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+/// The path from a header to its peak and the peaks it doesn't reach.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmrProof {
+    /// Height of the header, and the leaves in the range proven against
+    pub index: u64,
+    pub leaves: u64,
+    /// The sibling at each level up to the peak, bottom up
+    pub siblings: Vec<[u8; 32]>,
+    /// The other peaks, in order
+    pub peaks: Vec<[u8; 32]>,
+}
+
+// Bags `peaks` from the right into a root, all zeros for none
+fn bag(peaks: &[[u8; 32]]) -> [u8; 32] {
+    let mut peaks = peaks.iter().rev();
+    let last = peaks.next().copied().unwrap_or([0; 32]);
+    peaks.fold(last, |root, peak| merkle_parent(peak, &root))
+}
+
+// The peak holding leaf `index` of `leaves`: its position among the peaks
+// and its height
+fn peak_of(index: u64, leaves: u64) -> (usize, usize) {
+    let mut start = 0;
+    let mut position = 0;
+    for height in (0..64).rev() {
+        let size = 1u64 << height;
+        if leaves & size == 0 {
+            continue;
+        }
+        if index < start + size {
+            return (position, height);
+        }
+        start += size;
+        position += 1;
+    }
+    unreachable!("leaf {} is past the {} leaves", index, leaves)
+}
+
+impl HeaderMmr {
+    pub fn new() -> HeaderMmr {
+        HeaderMmr::default()
+    }
+
+    /// A range over `headers`, from height 0.
+    pub fn from_headers(headers: &[BlockHeader]) -> HeaderMmr {
+        let mut mmr = HeaderMmr::new();
+        for header in headers {
+            mmr.append(header);
+        }
+        mmr
+    }
+
+    /// How many headers the range holds.
+    pub fn len(&self) -> u64 {
+        self.levels.first().map_or(0, |leaves| leaves.len() as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends the header at the next height, merging every pair of equal
+    /// peaks it completes.
+    pub fn append(&mut self, header: &BlockHeader) {
+        let mut node = header.hash();
+        let mut height = 0;
+        loop {
+            if self.levels.len() == height {
+                self.levels.push(vec![]);
+            }
+            let level = &mut self.levels[height];
+            level.push(node);
+            if level.len() % 2 == 1 {
+                return;
+            }
+            node = merkle_parent(&level[level.len() - 2], &level[level.len() - 1]);
+            height += 1;
+        }
+    }
+
+    /// Drops the headers from height `leaves` on, as when a reorganisation
+    /// disconnects them.
+    pub fn truncate(&mut self, leaves: u64) {
+        for (height, level) in self.levels.iter_mut().enumerate() {
+            level.truncate((leaves >> height) as usize);
+        }
+        while self.levels.last().is_some_and(|level| level.is_empty()) {
+            self.levels.pop();
+        }
+    }
+
+    /// The roots of the perfect trees, largest first.
+    pub fn peaks(&self) -> Vec<[u8; 32]> {
+        let leaves = self.len();
+        (0..self.levels.len()).rev().filter(|height| leaves >> height & 1 == 1).map(|height| self.levels[height][(leaves >> height) as usize - 1]).collect()
+    }
+
+    /// The commitment to every header so far, all zeros when empty.
+    pub fn root(&self) -> [u8; 32] {
+        bag(&self.peaks())
+    }
+
+    /// The proof that the header at `index` is in the range, or `None` past
+    /// its end.
+    pub fn proof(&self, index: u64) -> Option<MmrProof> {
+        let leaves = self.len();
+        if index >= leaves {
+            return None;
+        }
+        let (position, height) = peak_of(index, leaves);
+        let siblings = (0..height).map(|level| self.levels[level][((index >> level) ^ 1) as usize]).collect();
+        let mut peaks = self.peaks();
+        peaks.remove(position);
+        Some(MmrProof { index, leaves, siblings, peaks })
+    }
+}
+
+impl MmrProof {
+    /// Whether `header` is at `index` in the range with `root`.
+    pub fn verify(&self, header: &BlockHeader, root: &[u8; 32]) -> bool {
+        if self.index >= self.leaves {
+            return false;
+        }
+        let (position, height) = peak_of(self.index, self.leaves);
+        if self.siblings.len() != height || self.peaks.len() + 1 != self.leaves.count_ones() as usize {
+            return false;
+        }
+        let mut node = header.hash();
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            node = if (self.index >> level) & 1 == 0 { merkle_parent(&node, sibling) } else { merkle_parent(sibling, &node) };
+        }
+        let mut peaks = self.peaks.clone();
+        peaks.insert(position, node);
+        bag(&peaks) == *root
+    }
+}
+
+#[cfg(test)]
+use super::merkle_root;
+
+#[test]
+fn header_mmr() {
+    let headers: Vec<BlockHeader> = (0..11)
+        .map(|nonce| BlockHeader { version: 1, prev_block: [0; 32], merkle_root: [0; 32], timestamp: 1_231_006_505, bits: 0x207fffff, nonce })
+        .collect();
+    let leaves: Vec<[u8; 32]> = headers.iter().map(BlockHeader::hash).collect();
+
+    let mut mmr = HeaderMmr::new();
+    assert_eq!((mmr.root(), mmr.proof(0)), ([0; 32], None));
+    mmr.append(&headers[0]);
+    assert_eq!(mmr.root(), leaves[0]);
+    mmr.append(&headers[1]);
+    mmr.append(&headers[2]);
+    assert_eq!(mmr.peaks(), vec![merkle_parent(&leaves[0], &leaves[1]), leaves[2]]);
+    assert_eq!(mmr.root(), merkle_parent(&merkle_parent(&leaves[0], &leaves[1]), &leaves[2]));
+    // A perfect range is a block's merkle tree
+    mmr.append(&headers[3]);
+    assert_eq!(mmr.root(), merkle_root(&leaves[..4]));
+
+    // Every header proves against the root, and nothing else does
+    for count in 1..=headers.len() {
+        let mmr = HeaderMmr::from_headers(&headers[..count]);
+        assert_eq!(mmr.len(), count as u64);
+        assert_eq!(mmr.peaks().len(), count.count_ones() as usize);
+        for (index, header) in headers[..count].iter().enumerate() {
+            let proof = mmr.proof(index as u64).unwrap();
+            assert!(proof.verify(header, &mmr.root()));
+            assert!(!MmrProof { index: proof.index ^ 1, ..proof.clone() }.verify(header, &mmr.root()));
+            if count > 1 {
+                assert!(!proof.verify(&headers[(index + 1) % count], &mmr.root()));
+            }
+        }
+        assert!(mmr.proof(count as u64).is_none());
+    }
+    let mmr = HeaderMmr::from_headers(&headers);
+    let proof = mmr.proof(9).unwrap();
+    assert!(!proof.verify(&headers[9], &HeaderMmr::from_headers(&headers[..10]).root()));
+    assert!(!MmrProof { leaves: 12, ..proof.clone() }.verify(&headers[9], &mmr.root()));
+    assert!(!MmrProof { siblings: proof.siblings[1..].to_vec(), ..proof }.verify(&headers[9], &mmr.root()));
+
+    // Truncating undoes appends
+    let mut shortened = mmr.clone();
+    shortened.truncate(6);
+    assert_eq!(shortened, HeaderMmr::from_headers(&headers[..6]));
+    shortened.truncate(0);
+    assert!(shortened.is_empty() && shortened.root() == [0; 32]);
+}
@@ -0,0 +1,261 @@
+//! Blocks and block headers (chapter 9).
+
+pub mod filter;
+pub mod merkle;
+pub mod merkleblock;
+pub mod mine;
+pub mod mmr;
+pub mod pow;
+pub mod template;
+
+pub use filter::BlockFilter;
+pub use merkle::{merkle_parent, merkle_parent_level, merkle_root, MerkleProof, MerkleTree};
+pub use merkleblock::MerkleBlock;
+pub use mine::{meets_target, Miner};
+pub use mmr::{HeaderMmr, MmrProof};
+pub use pow::{bits_to_target, calculate_new_bits, difficulty, epoch_new_bits, next_bits, target_to_bits};
+pub use template::{block_subsidy, BlockTemplate, MempoolEntry, MAX_BLOCK_SIGOP_COST, MAX_BLOCK_WEIGHT};
+
+use crate::encoding::util::{read_bytes, read_u32_le, to_reversed_hex};
+use crate::encoding::varint::{encode_varint, read_varint_len};
+use crate::error::Result;
+use crate::hash::hash256;
+use crate::params::Network;
+use crate::tx::Tx;
+use num_bigint::{BigInt, Sign};
+use std::io::Read;
+
+/// Serialized size of a block header.
+pub const HEADER_SIZE: usize = 80;
+
+/// Start of the coinbase output committing to the block's witnesses:
+/// OP_RETURN, a 36 byte push and the BIP141 tag.
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlockHeader {
+    pub version: u32,
+    /// Hash of the previous block, in display (big-endian) order
+    pub prev_block: [u8; 32],
+    /// Merkle root of the transactions, in display order
+    pub merkle_root: [u8; 32],
+    pub timestamp: u32,
+    /// The target in compact form
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+fn read_reversed<R: Read>(reader: &mut R) -> Result<[u8; 32]> {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&read_bytes(reader, 32)?);
+    hash.reverse();
+    Ok(hash)
+}
+
+impl BlockHeader {
+    pub fn parse<R: Read>(reader: &mut R) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            version: read_u32_le(reader)?,
+            prev_block: read_reversed(reader)?,
+            merkle_root: read_reversed(reader)?,
+            timestamp: read_u32_le(reader)?,
+            bits: read_u32_le(reader)?,
+            nonce: read_u32_le(reader)?,
+        })
+    }
+
+    pub fn serialize(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&self.prev_block);
+        bytes[4..36].reverse();
+        bytes[36..68].copy_from_slice(&self.merkle_root);
+        bytes[36..68].reverse();
+        bytes[68..72].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    /// The block hash in display order.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hash = hash256(&self.serialize());
+        hash.reverse();
+        hash
+    }
+
+    /// The block hash as hex, as shown by block explorers.
+    pub fn id(&self) -> String {
+        to_reversed_hex(&hash256(&self.serialize()))
+    }
+
+    /// BIP9 signalling: the top 3 bits of the version are 001.
+    pub fn bip9(&self) -> bool {
+        self.version >> 29 == 0b001
+    }
+
+    /// BIP91 (segwit via a lowered threshold) signals with bit 4.
+    pub fn bip91(&self) -> bool {
+        self.version >> 4 & 1 == 1
+    }
+
+    /// BIP141 (segwit) signals with bit 1.
+    pub fn bip141(&self) -> bool {
+        self.version >> 1 & 1 == 1
+    }
+
+    /// The target the block hash must be below.
+    pub fn target(&self) -> Result<BigInt> {
+        bits_to_target(self.bits)
+    }
+
+    /// How many times harder than difficulty 1 this block was to find.
+    pub fn difficulty(&self) -> Result<f64> {
+        difficulty(self.bits)
+    }
+
+    /// Hashes expected to find the block, 2^256 / (target + 1), which
+    /// adds up to a chain's total work.
+    pub fn work(&self) -> Result<BigInt> {
+        Ok((BigInt::from(1) << 256) / (self.target()? + 1))
+    }
+
+    /// Whether the hash, read as a little-endian number, is below the
+    /// target. Bits that do not encode a target fail.
+    pub fn check_pow(&self) -> bool {
+        let proof = BigInt::from_bytes_le(Sign::Plus, &hash256(&self.serialize()));
+        self.target().is_ok_and(|target| proof < target)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    /// The transactions, coinbase first
+    pub txs: Vec<Tx>,
+}
+
+impl Block {
+    pub fn parse<R: Read>(reader: &mut R, network: Network) -> Result<Block> {
+        let header = BlockHeader::parse(reader)?;
+        let num_txs = read_varint_len(reader)?;
+        let txs = (0..num_txs).map(|_| Tx::parse(reader, network)).collect::<Result<Vec<_>>>()?;
+        Ok(Block { header, txs })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize().to_vec();
+        encode_varint(&mut result, self.txs.len() as u64).unwrap();
+        for tx in self.txs.iter() {
+            result.extend_from_slice(&tx.serialize());
+        }
+        result
+    }
+
+    /// The merkle root of the txids, in display order like
+    /// `BlockHeader::merkle_root`.
+    pub fn merkle_root(&self) -> [u8; 32] {
+        let txids: Vec<[u8; 32]> = self.txs.iter().map(|tx| hash256(&tx.serialize_legacy())).collect();
+        let mut root = merkle_root(&txids);
+        root.reverse();
+        root
+    }
+
+    /// Whether the header commits to these transactions.
+    pub fn validate_merkle_root(&self) -> bool {
+        self.merkle_root() == self.header.merkle_root
+    }
+
+    /// The BIP141 commitment in the coinbase: the last output starting
+    /// with the commitment header.
+    pub fn witness_commitment(&self) -> Option<[u8; 32]> {
+        let coinbase = self.txs.first()?;
+        let tx_out = coinbase.tx_outs.iter().rev().find(|tx_out| tx_out.script_pubkey.starts_with(&WITNESS_COMMITMENT_HEADER))?;
+        let mut commitment = [0u8; 32];
+        commitment.copy_from_slice(&tx_out.script_pubkey[6..38]);
+        Some(commitment)
+    }
+
+    /// Whether the coinbase commits to the witnesses: the hash of the
+    /// wtxid merkle root, the coinbase's counting as zero, and the 32 byte
+    /// reserved value in the coinbase's witness. A block without a
+    /// commitment may not carry witness data.
+    pub fn validate_witness_commitment(&self) -> bool {
+        let commitment = match self.witness_commitment() {
+            Some(commitment) => commitment,
+            None => return !self.txs.iter().any(Tx::is_segwit),
+        };
+        let reserved = &self.txs[0].tx_ins[0].witness;
+        if reserved.len() != 1 || reserved[0].len() != 32 {
+            return false;
+        }
+        let wtxids: Vec<[u8; 32]> = std::iter::once([0; 32]).chain(self.txs[1..].iter().map(|tx| hash256(&tx.serialize()))).collect();
+        hash256(&[&merkle_root(&wtxids)[..], &reserved[0]].concat()) == commitment
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn, TxOut};
+
+#[test]
+fn parse_header() {
+    // Chapter 9's examples
+    let raw = decode_hex("020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d").unwrap();
+    let header = BlockHeader::parse(&mut &raw[..]).unwrap();
+    assert_eq!(header.version, 0x20000002);
+    assert_eq!(encode_hex(&header.prev_block), "000000000000000000fd0c220a0a8c3bc5a7b487e8c8de0dfa2373b12894c38e");
+    assert_eq!(encode_hex(&header.merkle_root), "be258bfd38db61f957315c3f9e9c5e15216857398d50402d5089a8e0fc50075b");
+    assert_eq!((header.timestamp, header.bits, header.nonce), (0x59a7771e, 0x18013ce9, 0x1dd7ffa4));
+    assert_eq!(header.serialize().to_vec(), raw);
+    assert_eq!(header.id(), "0000000000000000007e9e4c586439b0cdbe13b1370bdd9435d76a644d047523");
+    assert_eq!(encode_hex(&header.hash()), header.id());
+    assert_eq!(
+        format!("{:064x}", header.target().unwrap()),
+        "0000000000000000013ce9000000000000000000000000000000000000000000"
+    );
+    assert!(header.bip9() && header.bip141() && !header.bip91());
+    assert!(header.check_pow());
+    // Difficulty 1, the genesis block's chainwork in Bitcoin Core
+    assert_eq!(BlockHeader { bits: 0x1d00ffff, ..header.clone() }.work().unwrap(), BigInt::from(0x1_0001_0001u64));
+
+    let parse = |hex: &str| BlockHeader::parse(&mut &decode_hex(hex).unwrap()[..]).unwrap();
+    assert!(!parse("0400000039fa821848781f027a2e6dfabbf6bda920d9ae61b63400030000000000000000ecae536a304042e3154be0e3e9a8220e5568c3433a9ab49ac4cbb74f8df8e8b0cc2acf569fb9061806652c27").bip9());
+    assert!(parse("1200002028856ec5bca29cf76980d368b0a163a0bb81fc192951270100000000000000003288f32a2831833c31a25401c52093eb545d28157e200a64b21b3ae8f21c507401877b5935470118144dbfd1").bip91());
+    assert!(!parse("0000002066f09203c1cf5ef1531f24ed21b1915ae9abeb691f0d2e0100000000000000003de0976428ce56125351bae62c5b8b8c79d8297c702ea05d60feabb4ed188b59c36fa759e93c0118b74b2618").bip141());
+    assert!(parse("04000000fbedbbf0cfdaf278c094f187f2eb987c86a199da22bbb20400000000000000007b7697b29129648fa08b4bcd13c9d5e60abb973a1efac9c8d573c71c807c56c3d6213557faa80518c3737ec1").check_pow());
+    assert!(!parse("04000000fbedbbf0cfdaf278c094f187f2eb987c86a199da22bbb20400000000000000007b7697b29129648fa08b4bcd13c9d5e60abb973a1efac9c8d573c71c807c56c3d6213557faa80518c3737ec0").check_pow());
+    assert!(BlockHeader::parse(&mut &raw[..79]).is_err());
+}
+
+#[test]
+fn parse_block() {
+    let genesis = decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000").unwrap();
+    let block = Block::parse(&mut &genesis[..], Network::Mainnet).unwrap();
+    assert_eq!(block.header.id(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+    assert_eq!(block.serialize(), genesis);
+    assert!(block.validate_merkle_root());
+    assert!(block.witness_commitment().is_none() && block.validate_witness_commitment());
+
+    // A segwit block: coinbase, a legacy and a segwit spend
+    let mut coinbase = Tx::new_coinbase(1, &[], vec![TxOut::new(50, vec![0x51])], Network::Mainnet);
+    coinbase.tx_ins[0].witness = vec![vec![0; 32]];
+    let legacy = Tx::new(2, vec![TxIn::new([1; 32], 0)], vec![TxOut::new(10, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+    let mut segwit = Tx::new(2, vec![TxIn::new([2; 32], 0)], vec![TxOut::new(10, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+    segwit.tx_ins[0].witness = vec![vec![0xab; 72]];
+    let wtxids = vec![[0; 32], hash256(&legacy.serialize()), hash256(&segwit.serialize())];
+    let mut script_pubkey = WITNESS_COMMITMENT_HEADER.to_vec();
+    script_pubkey.extend_from_slice(&hash256(&[merkle_root(&wtxids), [0; 32]].concat()));
+    coinbase.tx_outs.push(TxOut::new(0, script_pubkey));
+    let mut block = Block { header: block.header, txs: vec![coinbase, legacy, segwit] };
+    assert!(!block.validate_merkle_root());
+    block.header.merkle_root = block.merkle_root();
+    assert!(block.validate_merkle_root() && block.validate_witness_commitment());
+    assert_eq!(Block::parse(&mut &block.serialize()[..], Network::Mainnet).unwrap(), block);
+
+    block.txs[2].tx_ins[0].witness[0][0] = 0;
+    assert!(block.validate_merkle_root() && !block.validate_witness_commitment());
+    block.txs.swap(1, 2);
+    assert!(!block.validate_merkle_root());
+}
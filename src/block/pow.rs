@@ -0,0 +1,139 @@
+//! Proof of work targets and their compact "bits" encoding.
+//!
+//! Bits hold a target as a base 256 exponent in the top byte and a three
+//! byte mantissa. The mantissa's top bit is a sign, so a target whose
+//! leading byte is 0x80 or more is written with an extra zero byte.
+
+use super::BlockHeader;
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::RetargetRules;
+use num_bigint::BigInt;
+use std::convert::TryFrom;
+
+/// The target of difficulty 1, the easiest mainnet target.
+pub const DIFFICULTY_1_BITS: u32 = 0x1d00_ffff;
+
+/// Seconds a retarget period of 2016 blocks is meant to take.
+pub const TWO_WEEKS: i64 = 60 * 60 * 24 * 14;
+
+const SIGN_BIT: u32 = 0x0080_0000;
+
+/// The target `bits` encodes. Negative targets and targets over 256 bits
+/// are `ErrorKind::OutOfRange`.
+pub fn bits_to_target(bits: u32) -> Result<BigInt> {
+    let exponent = bits >> 24;
+    let mantissa = bits & (SIGN_BIT - 1);
+    if mantissa != 0 && bits & SIGN_BIT != 0 {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("bits {:08x} encode a negative target", bits)));
+    }
+    let target = if exponent <= 3 {
+        BigInt::from(mantissa >> (8 * (3 - exponent)))
+    } else {
+        BigInt::from(mantissa) << (8 * (exponent - 3))
+    };
+    if target.bits() > 256 {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("bits {:08x} overflow 256 bits", bits)));
+    }
+    Ok(target)
+}
+
+/// The compact encoding of a non-negative `target`, dropping all but its
+/// top three bytes.
+pub fn target_to_bits(target: &BigInt) -> u32 {
+    let (_, bytes) = target.to_bytes_be();
+    let mut exponent = if target.bits() == 0 { 0 } else { bytes.len() as u32 };
+    let top = &bytes[..bytes.len().min(3)];
+    let mut mantissa = top.iter().fold(0u32, |acc, b| acc << 8 | *b as u32) << (8 * (3 - top.len() as u32));
+    // Keep the sign bit clear
+    if mantissa & SIGN_BIT != 0 {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+    exponent << 24 | mantissa
+}
+
+// Exact for targets, which have at most 24 significant bits
+fn to_f64(n: &BigInt) -> f64 {
+    let shift = n.bits().saturating_sub(64);
+    u64::try_from(n >> shift).unwrap() as f64 * 2f64.powi(shift as i32)
+}
+
+/// How many times harder than difficulty 1 a block at `bits` is to find.
+pub fn difficulty(bits: u32) -> Result<f64> {
+    let target = bits_to_target(bits)?;
+    if target.bits() == 0 {
+        return Err(Error::new(ErrorKind::OutOfRange, "zero target"));
+    }
+    Ok(to_f64(&bits_to_target(DIFFICULTY_1_BITS)?) / to_f64(&target))
+}
+
+/// The bits for the next period, given the previous period's bits and how
+/// long it took. The adjustment is at most a factor of 4 either way and the
+/// target never rises above difficulty 1.
+pub fn calculate_new_bits(prev_bits: u32, time_differential: i64) -> Result<u32> {
+    retarget_bits(prev_bits, time_differential, TWO_WEEKS, DIFFICULTY_1_BITS)
+}
+
+/// `calculate_new_bits` under a network's period length and easiest target.
+pub fn next_bits(rules: &RetargetRules, prev_bits: u32, time_differential: i64) -> Result<u32> {
+    retarget_bits(prev_bits, time_differential, rules.target_timespan as i64, rules.pow_limit_bits)
+}
+
+fn retarget_bits(prev_bits: u32, time_differential: i64, timespan: i64, pow_limit_bits: u32) -> Result<u32> {
+    let time_differential = time_differential.clamp(timespan / 4, timespan * 4);
+    let new_target = bits_to_target(prev_bits)? * time_differential / timespan;
+    Ok(target_to_bits(&new_target.min(bits_to_target(pow_limit_bits)?)))
+}
+
+/// The bits for the period after the one running from `first` to `last`,
+/// its first and last headers.
+pub fn epoch_new_bits(first: &BlockHeader, last: &BlockHeader) -> Result<u32> {
+    calculate_new_bits(last.bits, last.timestamp as i64 - first.timestamp as i64)
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[test]
+fn compact_targets() {
+    let target = |hex: &str| BigInt::parse_bytes(hex.as_bytes(), 16).unwrap();
+    // Bitcoin Core's SetCompact and GetCompact cases
+    let cases = [
+        (0x0000_0000, "0", 0x0000_0000),
+        (0x0112_3456, "12", 0x0112_0000),
+        (0x0200_8000, "80", 0x0200_8000),
+        (0x0512_3456, "1234560000", 0x0512_3456),
+        (0x0500_9234, "92340000", 0x0500_9234),
+        (0x2012_3456, "1234560000000000000000000000000000000000000000000000000000000000", 0x2012_3456),
+        (DIFFICULTY_1_BITS, "ffff0000000000000000000000000000000000000000000000000000", DIFFICULTY_1_BITS),
+    ];
+    for (bits, hex, compact) in cases.iter() {
+        assert_eq!(bits_to_target(*bits).unwrap(), target(hex), "{:08x}", bits);
+        assert_eq!(target_to_bits(&target(hex)), *compact, "{}", hex);
+    }
+    assert_eq!(bits_to_target(0x0180_3456).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(bits_to_target(0x0492_3456).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(bits_to_target(0xff12_3456).unwrap_err().kind(), ErrorKind::OutOfRange);
+    // A negative sign with a zero mantissa is just zero
+    assert_eq!(bits_to_target(0x0080_0000).unwrap(), BigInt::from(0));
+
+    assert_eq!(difficulty(DIFFICULTY_1_BITS).unwrap(), 1.0);
+    // Chapter 9's block 0000000000000000007e9e4c586439b0cdbe13b1370bdd9435d76a644d047523
+    assert_eq!(format!("{:.4}", difficulty(0x1801_3ce9).unwrap()), "888171856257.3206");
+    assert!(difficulty(0).is_err());
+}
+
+#[test]
+fn retarget() {
+    assert_eq!(calculate_new_bits(0x1801_d854, 302400).unwrap(), 0x1776_1500);
+    // Clamped to a factor of 4
+    assert_eq!(calculate_new_bits(0x1801_d854, TWO_WEEKS * 10).unwrap(), calculate_new_bits(0x1801_d854, TWO_WEEKS * 4).unwrap());
+    assert_eq!(calculate_new_bits(0x1801_d854, -1).unwrap(), calculate_new_bits(0x1801_d854, TWO_WEEKS / 4).unwrap());
+    assert_eq!(calculate_new_bits(DIFFICULTY_1_BITS, TWO_WEEKS * 2).unwrap(), DIFFICULTY_1_BITS);
+
+    // Chapter 9: the first and last blocks of the period ending at 473759
+    let parse = |hex: &str| BlockHeader::parse(&mut &decode_hex(hex).unwrap()[..]).unwrap();
+    let first = parse("000000203471101bbda3fe307664b3283a9ef0e97d9a38a7eacd8800000000000000000010c8aba8479bbaa5e0848152fd3c2289ca50e1c3e58c9a4faaafbdf5803c5448ddb845597e8b0118e43a81d3");
+    let last = parse("02000020f1472d9db4b563c35f97c428ac903f23b7fc055d1cfc26000000000000000000b3f449fcbe1bc4cfbcb8283a0d2c037f961a3fdf2b8bedc144973735eea707e1264258597e8b0118e5f00474");
+    assert_eq!(epoch_new_bits(&first, &last).unwrap(), 0x1801_8d30);
+}
@@ -0,0 +1,334 @@
+//! Block templates: the block a miner works on.
+//!
+//! `BlockTemplate::build` fills a block on top of the tip from a mempool
+//! snapshot the way Bitcoin Core's `BlockAssembler` does. It takes the
+//! transaction whose package, itself and its unconfirmed ancestors not
+//! yet in the block, pays the most per weight unit, as long as the package
+//! fits under the weight and signature operation limits. The coinbase pays
+//! the subsidy and the fees to the miner and commits to the witnesses
+//! (BIP141). `header` is the work function a `Miner` asks for each extra
+//! nonce, and `block` puts the block together once a header is found.
+//!
+//! The snapshot holds what a mempool would accept for the next block:
+//! final transactions whose inputs are confirmed or in the snapshot.
+//! Signature operations are counted the legacy way, over script_sigs and
+//! output scripts. P2SH and witness ones need the spent outputs, so
+//! whoever has them can raise an entry's `sigop_cost`.
+
+use super::{merkle_root, Block, BlockHeader, HEADER_SIZE, WITNESS_COMMITMENT_HEADER};
+use crate::encoding::varint::encode_varint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use crate::params::{Network, NetworkParams};
+use crate::tx::{Tx, TxOut};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// BIP141's limit on a block's weight.
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+/// BIP141's limit on a block's signature operations, a legacy one
+/// costing 4 and a witness one 1.
+pub const MAX_BLOCK_SIGOP_COST: usize = 80_000;
+const WITNESS_SCALE_FACTOR: usize = 4;
+const COIN: u64 = 100_000_000;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKSIGVERIFY: u8 = 0xad;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+// BIP9 version bits with nothing signalled
+const VERSION: u32 = 0x2000_0000;
+
+/// The new coins a block at `height` may pay itself: 50 bitcoin, halved
+/// every `subsidy_halving_interval` blocks.
+pub fn block_subsidy(height: u32, params: &NetworkParams) -> u64 {
+    let halvings = height / params.subsidy_halving_interval;
+    if halvings >= 64 {
+        return 0;
+    }
+    (50 * COIN) >> halvings
+}
+
+// Signature operations in `script` as Bitcoin Core's inaccurate count
+// has them: a CHECKMULTISIG is 20 whatever its keys. A push running off
+// the end stops the count
+fn legacy_sigops(script: &[u8]) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+    while i < script.len() {
+        let op = script[i];
+        i += 1;
+        let (len_size, len) = match op {
+            0x01..=0x4b => (0, op as usize),
+            OP_PUSHDATA1 | OP_PUSHDATA2 | OP_PUSHDATA4 => {
+                let len_size = 1 << (op - OP_PUSHDATA1);
+                match script.get(i..i + len_size) {
+                    Some(bytes) => (len_size, bytes.iter().rev().fold(0usize, |len, &b| len << 8 | b as usize)),
+                    None => break,
+                }
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                count += 1;
+                (0, 0)
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                count += 20;
+                (0, 0)
+            }
+            _ => (0, 0),
+        };
+        i += len_size + len;
+    }
+    count
+}
+
+fn legacy_sigop_cost(tx: &Tx) -> usize {
+    let script_sigs = tx.tx_ins.iter().map(|tx_in| legacy_sigops(&tx_in.script_sig));
+    let script_pubkeys = tx.tx_outs.iter().map(|tx_out| legacy_sigops(&tx_out.script_pubkey));
+    script_sigs.chain(script_pubkeys).sum::<usize>() * WITNESS_SCALE_FACTOR
+}
+
+/// A transaction in the mempool snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub tx: Tx,
+    /// What its inputs pay over its outputs
+    pub fee: u64,
+    /// Signature operations, scaled as in `MAX_BLOCK_SIGOP_COST`
+    pub sigop_cost: usize,
+}
+
+impl MempoolEntry {
+    /// An entry costing the legacy signature operations of `tx`.
+    pub fn new(tx: Tx, fee: u64) -> MempoolEntry {
+        let sigop_cost = legacy_sigop_cost(&tx);
+        MempoolEntry { tx, fee, sigop_cost }
+    }
+}
+
+// A transaction and the ancestors it would bring into the block
+struct Package {
+    index: usize,
+    members: Vec<usize>,
+    fee: u64,
+    weight: usize,
+    sigop_cost: usize,
+}
+
+impl Package {
+    // Fee rate against `other`'s, ties going to the earlier entry
+    fn cmp_rate(&self, other: &Package) -> Ordering {
+        let rate = self.fee as u128 * other.weight as u128;
+        rate.cmp(&(other.fee as u128 * self.weight as u128)).then(other.index.cmp(&self.index))
+    }
+}
+
+// The indices of every unconfirmed ancestor of each entry
+fn ancestors(pool: &[MempoolEntry], hashes: &[[u8; 32]]) -> Vec<BTreeSet<usize>> {
+    let by_hash: HashMap<[u8; 32], usize> = hashes.iter().enumerate().map(|(index, hash)| (*hash, index)).collect();
+    let parents: Vec<BTreeSet<usize>> =
+        pool.iter().map(|entry| entry.tx.tx_ins.iter().filter_map(|tx_in| by_hash.get(&tx_in.prev_tx).copied()).collect()).collect();
+    (0..pool.len())
+        .map(|index| {
+            let mut found = BTreeSet::new();
+            let mut todo: Vec<usize> = parents[index].iter().copied().collect();
+            while let Some(parent) = todo.pop() {
+                if found.insert(parent) {
+                    todo.extend(parents[parent].iter().copied());
+                }
+            }
+            found
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTemplate {
+    pub height: u32,
+    /// The header for extra nonce 0, its nonce 0
+    pub header: BlockHeader,
+    /// The coinbase for extra nonce 0
+    pub coinbase: Tx,
+    /// The transactions after the coinbase, parents before children
+    pub txs: Vec<Tx>,
+    /// What the transactions pay the miner
+    pub fees: u64,
+    /// Weight of the whole block
+    pub weight: usize,
+    /// Signature operation cost of the whole block
+    pub sigop_cost: usize,
+    /// Hashes of `txs` in internal order, for the merkle root
+    txids: Vec<[u8; 32]>,
+}
+
+impl BlockTemplate {
+    /// A block on top of `tip`, its height and header, paying
+    /// `script_pubkey`. The header takes the tip's bits, right except at a
+    /// retarget or for a minimum difficulty block, where the caller sets
+    /// `header.bits` from what header sync expects.
+    pub fn build(pool: &[MempoolEntry], tip: (u32, BlockHeader), script_pubkey: Vec<u8>, network: Network) -> Result<BlockTemplate> {
+        let (tip_height, tip) = tip;
+        let height = tip_height.checked_add(1).ok_or_else(|| Error::new(ErrorKind::OutOfRange, "no height after the tip"))?;
+        let subsidy = block_subsidy(height, &network.params());
+
+        // The coinbase's size doesn't change with its amount or commitment,
+        // so its weight is known up front
+        let mut coinbase = Tx::new_coinbase(height, &[8, 0, 0, 0, 0, 0, 0, 0, 0], vec![TxOut::new(subsidy, script_pubkey)], network);
+        coinbase.tx_ins[0].witness = vec![vec![0; 32]];
+        coinbase.tx_outs.push(TxOut::new(0, [&WITNESS_COMMITMENT_HEADER[..], &[0; 32]].concat()));
+        // Room for a 3 byte transaction count, up to 65535 of them
+        let mut weight = (HEADER_SIZE + 3) * WITNESS_SCALE_FACTOR + coinbase.weight();
+        let mut sigop_cost = legacy_sigop_cost(&coinbase);
+
+        let hashes: Vec<[u8; 32]> = pool.iter().map(|entry| entry.tx.hash()).collect();
+        let weights: Vec<usize> = pool.iter().map(|entry| entry.tx.weight()).collect();
+        let ancestors = ancestors(pool, &hashes);
+        let mut in_block = vec![false; pool.len()];
+        let mut skipped = vec![false; pool.len()];
+        let mut selected = vec![];
+        loop {
+            let best = (0..pool.len())
+                .filter(|index| !in_block[*index] && !skipped[*index])
+                .map(|index| {
+                    let mut members: Vec<usize> = ancestors[index].iter().copied().chain(std::iter::once(index)).filter(|member| !in_block[*member]).collect();
+                    // An ancestor has fewer ancestors than its descendants
+                    members.sort_by_key(|member| ancestors[*member].len());
+                    let fee = members.iter().map(|member| pool[*member].fee).sum();
+                    let weight = members.iter().map(|member| weights[*member]).sum();
+                    let sigop_cost = members.iter().map(|member| pool[*member].sigop_cost).sum();
+                    Package { index, members, fee, weight, sigop_cost }
+                })
+                .max_by(Package::cmp_rate);
+            let package = match best {
+                Some(package) => package,
+                None => break,
+            };
+            if weight + package.weight >= MAX_BLOCK_WEIGHT || sigop_cost + package.sigop_cost >= MAX_BLOCK_SIGOP_COST {
+                skipped[package.index] = true;
+                continue;
+            }
+            weight += package.weight;
+            sigop_cost += package.sigop_cost;
+            for member in package.members {
+                in_block[member] = true;
+                selected.push(member);
+            }
+        }
+
+        let fees = selected.iter().map(|index| pool[*index].fee).sum::<u64>();
+        let txs: Vec<Tx> = selected.iter().map(|index| pool[*index].tx.clone()).collect();
+        let txids = selected.iter().map(|index| hash256(&pool[*index].tx.serialize_legacy())).collect();
+        coinbase.tx_outs[0].amount = subsidy + fees;
+        // The coinbase's wtxid counts as zero, and its witness is the
+        // reserved value
+        let wtxids: Vec<[u8; 32]> = std::iter::once([0; 32]).chain(txs.iter().map(|tx| hash256(&tx.serialize()))).collect();
+        coinbase.tx_outs[1].script_pubkey[6..].copy_from_slice(&hash256(&[merkle_root(&wtxids), [0; 32]].concat()));
+
+        let mut count = vec![];
+        encode_varint(&mut count, txs.len() as u64 + 1)?;
+        weight -= (3 - count.len()) * WITNESS_SCALE_FACTOR;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as u32);
+        let header = BlockHeader { version: VERSION, prev_block: tip.hash(), merkle_root: [0; 32], timestamp: now.max(tip.timestamp + 1), bits: tip.bits, nonce: 0 };
+        let mut template = BlockTemplate { height, header, coinbase, txs, fees, weight, sigop_cost, txids };
+        template.header = template.header(0);
+        Ok(template)
+    }
+
+    /// The coinbase for `extra_nonce`, which ends its script_sig.
+    pub fn coinbase(&self, extra_nonce: u64) -> Tx {
+        let mut coinbase = self.coinbase.clone();
+        let script_sig = &mut coinbase.tx_ins[0].script_sig;
+        let len = script_sig.len();
+        script_sig[len - 8..].copy_from_slice(&extra_nonce.to_le_bytes());
+        coinbase
+    }
+
+    /// The header to mine for `extra_nonce`, its nonce `header.nonce`.
+    pub fn header(&self, extra_nonce: u64) -> BlockHeader {
+        let coinbase = hash256(&self.coinbase(extra_nonce).serialize_legacy());
+        let txids: Vec<[u8; 32]> = std::iter::once(coinbase).chain(self.txids.iter().copied()).collect();
+        let mut root = merkle_root(&txids);
+        root.reverse();
+        BlockHeader { merkle_root: root, ..self.header.clone() }
+    }
+
+    /// The block `header`, mined from `header(extra_nonce)`, heads.
+    pub fn block(&self, extra_nonce: u64, header: BlockHeader) -> Block {
+        let txs = std::iter::once(self.coinbase(extra_nonce)).chain(self.txs.iter().cloned()).collect();
+        Block { header, txs }
+    }
+}
+
+#[cfg(test)]
+use super::Miner;
+#[cfg(test)]
+use crate::tx::{p2pkh_script, p2wpkh_script, LockTime, TxIn};
+
+#[test]
+fn subsidy_and_sigops() {
+    let mainnet = NetworkParams::mainnet();
+    assert_eq!(block_subsidy(0, &mainnet), 50 * COIN);
+    assert_eq!(block_subsidy(209_999, &mainnet), 50 * COIN);
+    assert_eq!(block_subsidy(420_000, &mainnet), 1_250_000_000);
+    assert_eq!(block_subsidy(64 * 210_000, &mainnet), 0);
+    assert_eq!(block_subsidy(150, &NetworkParams::regtest()), 25 * COIN);
+
+    assert_eq!(legacy_sigops(&p2pkh_script(&[1; 20])), 1);
+    // Pushed bytes aren't opcodes, and a push past the end stops the count
+    assert_eq!(legacy_sigops(&[0x02, OP_CHECKSIG, OP_CHECKSIG, OP_CHECKMULTISIG, OP_PUSHDATA2, 0xff, 0xff, OP_CHECKSIG]), 20);
+    assert_eq!(legacy_sigops(&[OP_CHECKSIGVERIFY, OP_PUSHDATA2, 0x01]), 1);
+    let tx = Tx::new(1, vec![TxIn::new([1; 32], 0)], vec![TxOut::new(1, vec![OP_CHECKMULTISIGVERIFY, OP_CHECKSIG])], LockTime::ZERO, Network::Regtest);
+    assert_eq!(MempoolEntry::new(tx, 0).sigop_cost, 84);
+}
+
+#[test]
+fn build_and_mine_template() {
+    let tx = |prev_tx: [u8; 32], script_pubkey: Vec<u8>| {
+        Tx::new(2, vec![TxIn::new(prev_tx, 0)], vec![TxOut::new(10_000, script_pubkey)], LockTime::ZERO, Network::Regtest)
+    };
+    let parent = tx([1; 32], p2wpkh_script(&[1; 20]));
+    let child = tx(parent.hash(), p2wpkh_script(&[2; 20]));
+    let mut segwit = tx([2; 32], p2wpkh_script(&[3; 20]));
+    segwit.tx_ins[0].witness = vec![vec![0x30; 72], vec![2; 33]];
+    let sigops = tx([3; 32], vec![OP_CHECKSIG; 20_001]);
+    let heavy = tx([4; 32], vec![0x6a; 1_000_000]);
+    // The child pays for its parent; the others pay the most but don't fit
+    let pool = vec![
+        MempoolEntry::new(child.clone(), 100_000),
+        MempoolEntry::new(sigops, 10_000_000),
+        MempoolEntry::new(segwit.clone(), 20_000),
+        MempoolEntry::new(heavy, 100_000_000),
+        MempoolEntry::new(parent.clone(), 100),
+    ];
+
+    let tip = BlockHeader { version: 1, prev_block: [7; 32], merkle_root: [8; 32], timestamp: 1_700_000_000, bits: 0x207fffff, nonce: 0 };
+    let payout = p2pkh_script(&[9; 20]);
+    let template = BlockTemplate::build(&pool, (299, tip.clone()), payout.clone(), Network::Regtest).unwrap();
+    assert_eq!(template.txs, vec![parent, child, segwit]);
+    // The only signature operation is the payout's OP_CHECKSIG
+    assert_eq!((template.height, template.fees, template.sigop_cost), (300, 120_100, 4));
+    assert_eq!(template.coinbase.coinbase_height().unwrap(), Some(300));
+    assert_eq!(template.coinbase.tx_outs[0], TxOut::new(1_250_000_000 + 120_100, payout));
+    assert_eq!((template.header.prev_block, template.header.bits), (tip.hash(), tip.bits));
+    assert!(template.header.timestamp > tip.timestamp);
+    assert_eq!(template.header(0), template.header);
+    assert_ne!(template.header(1).merkle_root, template.header.merkle_root);
+
+    let mut miner = Miner::new(|extra_nonce| template.header(extra_nonce));
+    let (extra_nonce, header) = miner.mine(&template.header.target().unwrap(), 1_000).unwrap();
+    let block = template.block(extra_nonce, header);
+    assert!(block.header.check_pow() && block.validate_merkle_root() && block.validate_witness_commitment());
+    assert_eq!(block.txs[0].tx_ins[0].witness, vec![vec![0; 32]]);
+    let weight = block.txs.iter().map(Tx::weight).sum::<usize>() + (HEADER_SIZE + 1) * 4;
+    assert_eq!(template.weight, weight);
+    assert_eq!(Block::parse(&mut &block.serialize()[..], Network::Regtest).unwrap(), block);
+
+    // Nothing to mine but the subsidy
+    let template = BlockTemplate::build(&[], (0, tip.clone()), vec![0x51], Network::Regtest).unwrap();
+    assert_eq!((template.txs.len(), template.coinbase.tx_outs[0].amount), (0, 50 * COIN));
+    assert!(template.block(0, template.header.clone()).validate_witness_commitment());
+    assert_eq!(BlockTemplate::build(&[], (u32::MAX, tip), vec![0x51], Network::Regtest).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
@@ -0,0 +1,118 @@
+//! BIP37 bloom filters (chapter 12), which let an SPV client ask peers for
+//! only the transactions it cares about without naming them exactly.
+
+use crate::encoding::varint::encode_varint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::murmur3;
+
+/// Largest filter peers accept, in bytes.
+pub const MAX_FILTER_SIZE: usize = 36_000;
+/// Most hash functions peers accept.
+pub const MAX_HASH_FUNCS: u32 = 50;
+/// Seed multiplier for the nth hash function.
+const BIP37_CONSTANT: u32 = 0xfba4_c795;
+
+/// filterload flags: whether the peer adds matched outpoints to the filter.
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    /// One entry per bit of the filter
+    pub bit_field: Vec<bool>,
+    pub function_count: u32,
+    pub tweak: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter of `size` bytes.
+    pub fn new(size: usize, function_count: u32, tweak: u32) -> Result<BloomFilter> {
+        if size == 0 || size > MAX_FILTER_SIZE || function_count > MAX_HASH_FUNCS {
+            return Err(Error::new(
+                ErrorKind::OutOfRange,
+                format!("bloom filter of {} bytes and {} functions", size, function_count),
+            ));
+        }
+        Ok(BloomFilter { bit_field: vec![false; size * 8], function_count, tweak })
+    }
+
+    /// A filter sized per BIP37 for `elements` items at the given false
+    /// positive rate, capped at the protocol limits.
+    pub fn with_false_positive_rate(elements: usize, fp_rate: f64, tweak: u32) -> Result<BloomFilter> {
+        if elements == 0 || !(fp_rate > 0.0 && fp_rate < 1.0) {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("{} elements at rate {}", elements, fp_rate)));
+        }
+        let ln2 = std::f64::consts::LN_2;
+        let bits = -(elements as f64) * fp_rate.ln() / (ln2 * ln2);
+        let size = ((bits / 8.0) as usize).clamp(1, MAX_FILTER_SIZE);
+        let function_count = ((size * 8) as f64 / elements as f64 * ln2) as u32;
+        BloomFilter::new(size, function_count.clamp(1, MAX_HASH_FUNCS), tweak)
+    }
+
+    /// Filter size in bytes.
+    pub fn size(&self) -> usize {
+        self.bit_field.len() / 8
+    }
+
+    fn bit_indexes<'a>(&'a self, item: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        (0..self.function_count).map(move |i| {
+            let seed = i.wrapping_mul(BIP37_CONSTANT).wrapping_add(self.tweak);
+            murmur3(item, seed) as usize % self.bit_field.len()
+        })
+    }
+
+    /// Sets the item's bit for each hash function.
+    pub fn add(&mut self, item: &[u8]) {
+        let indexes: Vec<usize> = self.bit_indexes(item).collect();
+        for index in indexes {
+            self.bit_field[index] = true;
+        }
+    }
+
+    /// Whether the item may have been added. False positives are the point.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_indexes(item).all(|index| self.bit_field[index])
+    }
+
+    /// The bit field as bytes, least significant bit first.
+    pub fn filter_bytes(&self) -> Vec<u8> {
+        self.bit_field
+            .chunks(8)
+            .map(|bits| bits.iter().enumerate().fold(0u8, |byte, (i, bit)| byte | (*bit as u8) << i))
+            .collect()
+    }
+
+    /// The payload of a filterload message.
+    pub fn filterload(&self, flag: u8) -> Vec<u8> {
+        let mut payload = vec![];
+        encode_varint(&mut payload, self.size() as u64).unwrap();
+        payload.extend_from_slice(&self.filter_bytes());
+        payload.extend_from_slice(&self.function_count.to_le_bytes());
+        payload.extend_from_slice(&self.tweak.to_le_bytes());
+        payload.push(flag);
+        payload
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::encode_hex;
+
+#[test]
+fn bloom_filter() {
+    // Chapter 12's examples
+    let mut filter = BloomFilter::new(10, 5, 99).unwrap();
+    filter.add(b"Hello World");
+    assert_eq!(encode_hex(&filter.filter_bytes()), "0000000a080000000140");
+    filter.add(b"Goodbye!");
+    assert_eq!(encode_hex(&filter.filter_bytes()), "4000600a080000010940");
+    assert_eq!(encode_hex(&filter.filterload(BLOOM_UPDATE_ALL)), "0a4000600a080000010940050000006300000001");
+    assert!(filter.contains(b"Hello World") && filter.contains(b"Goodbye!"));
+    assert!(!filter.contains(b"Hello"));
+
+    let filter = BloomFilter::with_false_positive_rate(3, 0.01, 0).unwrap();
+    assert_eq!((filter.size(), filter.function_count), (3, 5));
+    assert_eq!(BloomFilter::with_false_positive_rate(1_000_000, 0.0001, 0).unwrap().size(), MAX_FILTER_SIZE);
+    assert!(BloomFilter::new(MAX_FILTER_SIZE + 1, 5, 0).is_err());
+    assert!(BloomFilter::with_false_positive_rate(10, 1.0, 0).is_err());
+}
@@ -0,0 +1,565 @@
+//! Output script descriptors (BIP380 onwards): a text description of the
+//! outputs a wallet pays to, like `wpkh([d34db33f/84h/0h/0h]xpub.../0/*)`.
+//!
+//! Supported are `pk`, `pkh`, `wpkh`, `sh`, `wsh`, `multi`, `sortedmulti`
+//! and `tr` with a tree of `pk` leaves. Keys are hex or extended public
+//! keys followed by an unhardened path; a path ending in `/*` makes the
+//! descriptor ranged, deriving a different script for each index. Inside
+//! `wsh()` any miniscript expression may be used.
+//!
+//! `Descriptor::from_slip132` turns a "ypub" or "zpub" another wallet
+//! exported into the descriptors its version bytes stand for.
+
+use crate::address::Address;
+use crate::bip32::{ExtendedPubKey, KeyScript, HARDENED};
+use crate::encoding::util::{decode_hex, encode_hex};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{hash160, sha256};
+use crate::math::ecc::{S256Point, XOnlyPoint};
+use crate::miniscript::{Base, Miniscript};
+use crate::params::NetworkParams;
+use crate::script::opcodes::OpCode;
+use crate::script::{m_of_n, p2pkh_script, p2sh_script, p2tr_script, p2wpkh_script, p2wsh_script, Command, Script};
+use crate::tx::psbt::KeySource;
+use crate::tx::taproot::TapTree;
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u64; 5] = [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd];
+
+fn invalid<S: Into<String>>(msg: S) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, msg)
+}
+
+fn polymod(symbols: &[u64]) -> u64 {
+    let mut chk = 1u64;
+    for value in symbols {
+        let top = chk >> 35;
+        chk = ((chk & 0x7_ffff_ffff) << 5) ^ value;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+/// The 8 character checksum written after a `#`.
+pub fn descriptor_checksum(desc: &str) -> Result<String> {
+    let mut symbols = Vec::new();
+    let mut groups = Vec::new();
+    for c in desc.chars() {
+        let position = INPUT_CHARSET.find(c).ok_or_else(|| invalid(format!("{:?} in a descriptor", c)))? as u64;
+        // The low 5 bits of each character, then its group for every 3
+        symbols.push(position & 31);
+        groups.push(position >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.as_slice() {
+        [a] => symbols.push(*a),
+        [a, b] => symbols.push(a * 3 + b),
+        _ => {}
+    }
+    symbols.extend_from_slice(&[0; 8]);
+    let checksum = polymod(&symbols) ^ 1;
+    Ok((0..8).map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char).collect())
+}
+
+/// A key inside a descriptor, with where it came from if known.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DescriptorKey {
+    /// A 33 or 65 byte SEC key, or a 32 byte x-only key inside `tr()`
+    Single { origin: Option<KeySource>, key: Vec<u8> },
+    /// An extended key and the path below it
+    Extended { origin: Option<KeySource>, xpub: Box<ExtendedPubKey>, path: Vec<u32>, wildcard: bool },
+}
+
+// A path step like 44, 44' or 44h
+fn parse_index(step: &str) -> Result<u32> {
+    let (number, hardened) = match step.strip_suffix(|c| c == '\'' || c == 'h' || c == 'H') {
+        Some(number) => (number, HARDENED),
+        None => (step, 0),
+    };
+    match number.parse::<u32>() {
+        Ok(index) if index < HARDENED => Ok(index + hardened),
+        _ => Err(invalid(format!("derivation step {:?}", step))),
+    }
+}
+
+fn write_path(f: &mut fmt::Formatter, path: &[u32]) -> fmt::Result {
+    for index in path {
+        if *index >= HARDENED {
+            write!(f, "/{}'", index - HARDENED)?;
+        } else {
+            write!(f, "/{}", index)?;
+        }
+    }
+    Ok(())
+}
+
+impl DescriptorKey {
+    pub(crate) fn parse(s: &str, allow_xonly: bool, allow_uncompressed: bool) -> Result<DescriptorKey> {
+        let (origin, key) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (origin, key) = rest.split_once(']').ok_or_else(|| invalid(format!("unclosed key origin in {}", s)))?;
+                let mut steps = origin.split('/');
+                let fingerprint = decode_hex(steps.next().unwrap())
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| invalid(format!("fingerprint in {}", s)))?;
+                let path = steps.map(parse_index).collect::<Result<Vec<u32>>>()?;
+                (Some(KeySource { fingerprint, path }), key)
+            }
+            None => (None, s),
+        };
+        if let Ok(bytes) = decode_hex(key) {
+            match bytes.len() {
+                32 if allow_xonly => XOnlyPoint::parse(&bytes).map(|_| ())?,
+                33 => S256Point::parse_sec(&bytes).map(|_| ())?,
+                65 if allow_uncompressed => S256Point::parse_sec(&bytes).map(|_| ())?,
+                _ => return Err(invalid(format!("key {} is not allowed here", key))),
+            }
+            return Ok(DescriptorKey::Single { origin, key: bytes });
+        }
+        let mut steps = key.split('/');
+        let xpub = Box::new(ExtendedPubKey::parse(steps.next().unwrap())?);
+        let mut path = Vec::new();
+        let mut wildcard = false;
+        for step in steps {
+            if wildcard {
+                return Err(invalid(format!("wildcard before the end of {}", key)));
+            }
+            if step == "*" {
+                wildcard = true;
+                continue;
+            }
+            let index = parse_index(step)?;
+            if index >= HARDENED {
+                return Err(invalid(format!("hardened step {} needs the private key", step)));
+            }
+            path.push(index);
+        }
+        Ok(DescriptorKey::Extended { origin, xpub, path, wildcard })
+    }
+
+    /// Whether the key ends in `/*`.
+    pub fn is_ranged(&self) -> bool {
+        matches!(self, DescriptorKey::Extended { wildcard: true, .. })
+    }
+
+    fn is_uncompressed(&self) -> bool {
+        matches!(self, DescriptorKey::Single { key, .. } if key.len() == 65)
+    }
+
+    /// The key at `index`, which only matters for ranged keys.
+    pub fn derive(&self, index: u32) -> Result<S256Point> {
+        match self {
+            DescriptorKey::Single { key, .. } if key.len() == 32 => Ok(XOnlyPoint::parse(key)?.to_point()),
+            DescriptorKey::Single { key, .. } => S256Point::parse_sec(key),
+            DescriptorKey::Extended { xpub, path, wildcard, .. } => {
+                let mut xpub = xpub.derive_path(path)?;
+                if *wildcard {
+                    xpub = xpub.derive_child(index)?;
+                }
+                Ok(xpub.public_key)
+            }
+        }
+    }
+
+    // What a key hashes to in pk() and pkh()
+    fn sec(&self, index: u32) -> Result<Vec<u8>> {
+        Ok(self.derive(index)?.sec(!self.is_uncompressed()))
+    }
+}
+
+impl fmt::Display for DescriptorKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let origin = match self {
+            DescriptorKey::Single { origin, .. } | DescriptorKey::Extended { origin, .. } => origin,
+        };
+        if let Some(origin) = origin {
+            write!(f, "[{}", encode_hex(&origin.fingerprint))?;
+            write_path(f, &origin.path)?;
+            write!(f, "]")?;
+        }
+        match self {
+            DescriptorKey::Single { key, .. } => write!(f, "{}", encode_hex(key)),
+            DescriptorKey::Extended { xpub, path, wildcard, .. } => {
+                write!(f, "{}", xpub)?;
+                write_path(f, path)?;
+                if *wildcard {
+                    write!(f, "/*")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The scripts of a `tr()` descriptor.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ScriptTree {
+    /// `pk(KEY)`, a single key tapscript
+    Leaf(DescriptorKey),
+    Branch(Box<ScriptTree>, Box<ScriptTree>),
+}
+
+impl ScriptTree {
+    fn parse(s: &str) -> Result<ScriptTree> {
+        if let Some(inner) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            return match split_args(inner).as_slice() {
+                [left, right] => Ok(ScriptTree::Branch(Box::new(ScriptTree::parse(left)?), Box::new(ScriptTree::parse(right)?))),
+                _ => Err(invalid(format!("tree branch {} does not have two children", s))),
+            };
+        }
+        match call(s)? {
+            ("pk", key) => Ok(ScriptTree::Leaf(DescriptorKey::parse(key, true, false)?)),
+            (name, _) => Err(invalid(format!("{}() leaves are not supported", name))),
+        }
+    }
+
+    /// The tree at `index`, with `<x-only key> OP_CHECKSIG` leaves.
+    pub fn tap_tree(&self, index: u32) -> Result<TapTree> {
+        match self {
+            ScriptTree::Leaf(key) => {
+                let xonly = XOnlyPoint::from_point(&key.derive(index)?);
+                let script = Script::new(vec![Command::Data(xonly.serialize().to_vec()), Command::Op(OpCode::CheckSig.to_u8())]);
+                Ok(TapTree::leaf(script.raw_serialize()))
+            }
+            ScriptTree::Branch(left, right) => Ok(TapTree::branch(left.tap_tree(index)?, right.tap_tree(index)?)),
+        }
+    }
+
+    fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            ScriptTree::Leaf(key) => vec![key],
+            ScriptTree::Branch(left, right) => [left.keys(), right.keys()].concat(),
+        }
+    }
+}
+
+impl fmt::Display for ScriptTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptTree::Leaf(key) => write!(f, "pk({})", key),
+            ScriptTree::Branch(left, right) => write!(f, "{{{},{}}}", left, right),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Descriptor {
+    /// `<sec> OP_CHECKSIG`
+    Pk(DescriptorKey),
+    Pkh(DescriptorKey),
+    Wpkh(DescriptorKey),
+    Sh(Box<Descriptor>),
+    Wsh(Box<Descriptor>),
+    /// `multi` or, with the keys sorted by their SEC, `sortedmulti`.
+    /// Compressed keys only
+    Multi { m: u8, keys: Vec<DescriptorKey>, sorted: bool },
+    Tr { internal_key: DescriptorKey, tree: Option<ScriptTree> },
+    /// Any other miniscript inside `wsh()`
+    Miniscript(Miniscript),
+}
+
+// Where a descriptor is nested, which decides what it may contain
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    Top,
+    Sh,
+    Wsh,
+}
+
+/// Splits `name(args)`.
+pub(crate) fn call(s: &str) -> Result<(&str, &str)> {
+    match (s.find('('), s.strip_suffix(')')) {
+        (Some(open), Some(inner)) => Ok((&s[..open], &inner[open + 1..])),
+        _ => Err(invalid(format!("{:?} is not a descriptor function", s))),
+    }
+}
+
+/// Splits on the commas not nested in brackets.
+pub(crate) fn split_args(s: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(&s[start..]);
+    args
+}
+
+impl Descriptor {
+    /// Parses a descriptor, checking the checksum if one follows a `#`.
+    pub fn parse(s: &str) -> Result<Descriptor> {
+        let desc = match s.split_once('#') {
+            Some((desc, checksum)) => {
+                let expected = descriptor_checksum(desc)?;
+                if checksum != expected {
+                    return Err(invalid(format!("checksum {} of {}, expected {}", checksum, desc, expected)));
+                }
+                desc
+            }
+            None => s,
+        };
+        Descriptor::parse_in(desc, Context::Top)
+    }
+
+    fn parse_in(s: &str, context: Context) -> Result<Descriptor> {
+        if context == Context::Wsh && !matches!(s.split('(').next(), Some("pk" | "pkh" | "multi" | "sortedmulti")) {
+            let ms = Miniscript::parse(s)?;
+            if ms.ty()?.base != Base::B {
+                return Err(invalid(format!("wsh({}) does not leave a boolean", ms)));
+            }
+            return Ok(Descriptor::Miniscript(ms));
+        }
+        let (name, args) = call(s)?;
+        let allowed = match name {
+            "pk" | "pkh" | "multi" | "sortedmulti" => true,
+            "wpkh" => context != Context::Wsh,
+            "wsh" => context != Context::Wsh,
+            "sh" | "tr" => context == Context::Top,
+            _ => return Err(invalid(format!("unknown descriptor function {}()", name))),
+        };
+        if !allowed {
+            return Err(invalid(format!("{}() cannot be nested here", name)));
+        }
+        let segwit = context == Context::Wsh;
+        let args = split_args(args);
+        let single = || match args.as_slice() {
+            [arg] => Ok(*arg),
+            _ => Err(invalid(format!("{}() takes one argument", name))),
+        };
+        Ok(match name {
+            "pk" => Descriptor::Pk(DescriptorKey::parse(single()?, false, !segwit)?),
+            "pkh" => Descriptor::Pkh(DescriptorKey::parse(single()?, false, !segwit)?),
+            "wpkh" => Descriptor::Wpkh(DescriptorKey::parse(single()?, false, false)?),
+            "sh" => Descriptor::Sh(Box::new(Descriptor::parse_in(single()?, Context::Sh)?)),
+            "wsh" => Descriptor::Wsh(Box::new(Descriptor::parse_in(single()?, Context::Wsh)?)),
+            "tr" => match args.as_slice() {
+                [key] => Descriptor::Tr { internal_key: DescriptorKey::parse(key, true, false)?, tree: None },
+                [key, tree] => Descriptor::Tr { internal_key: DescriptorKey::parse(key, true, false)?, tree: Some(ScriptTree::parse(tree)?) },
+                _ => return Err(invalid("tr() takes a key and an optional tree")),
+            },
+            _ => {
+                let (threshold, keys) = args.split_first().unwrap();
+                let m = threshold.parse::<u8>().map_err(|_| invalid(format!("threshold {:?}", threshold)))?;
+                let keys = keys.iter().map(|key| DescriptorKey::parse(key, false, false)).collect::<Result<Vec<_>>>()?;
+                if m == 0 || m as usize > keys.len() || keys.len() > 16 {
+                    return Err(Error::new(ErrorKind::OutOfRange, format!("{} of {} multisig", m, keys.len())));
+                }
+                Descriptor::Multi { m, keys, sorted: name == "sortedmulti" }
+            }
+        })
+    }
+
+    fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            Descriptor::Pk(key) | Descriptor::Pkh(key) | Descriptor::Wpkh(key) => vec![key],
+            Descriptor::Sh(inner) | Descriptor::Wsh(inner) => inner.keys(),
+            Descriptor::Multi { keys, .. } => keys.iter().collect(),
+            Descriptor::Tr { internal_key, tree } => {
+                let mut keys = vec![internal_key];
+                keys.extend(tree.iter().flat_map(|tree| tree.keys()));
+                keys
+            }
+            Descriptor::Miniscript(ms) => ms.keys(),
+        }
+    }
+
+    /// Whether a key ends in `/*`, so each index derives a new script.
+    pub fn is_ranged(&self) -> bool {
+        self.keys().iter().any(|key| key.is_ranged())
+    }
+
+    /// The script_pubkey at `index`. For `sh()` and `wsh()` the script
+    /// inside is the redeem or witness script.
+    pub fn script_pubkey(&self, index: u32) -> Result<Script> {
+        Ok(match self {
+            Descriptor::Pk(key) => Script::new(vec![Command::Data(key.sec(index)?), Command::Op(OpCode::CheckSig.to_u8())]),
+            Descriptor::Pkh(key) => p2pkh_script(&hash160(&key.sec(index)?)),
+            Descriptor::Wpkh(key) => p2wpkh_script(&hash160(&key.sec(index)?)),
+            Descriptor::Sh(inner) => p2sh_script(&hash160(&inner.script_pubkey(index)?.raw_serialize())),
+            Descriptor::Wsh(inner) => p2wsh_script(&sha256(&inner.script_pubkey(index)?.raw_serialize())),
+            Descriptor::Multi { m, keys, sorted } => {
+                let mut points = keys.iter().map(|key| key.derive(index)).collect::<Result<Vec<_>>>()?;
+                if *sorted {
+                    points.sort_by_key(|point| point.sec(true));
+                }
+                m_of_n(*m, &points)?
+            }
+            Descriptor::Tr { internal_key, tree } => {
+                let internal_key = XOnlyPoint::from_point(&internal_key.derive(index)?);
+                let merkle_root = match tree {
+                    Some(tree) => Some(tree.tap_tree(index)?.merkle_root()),
+                    None => None,
+                };
+                p2tr_script(&internal_key.tweak_add(merkle_root.as_ref())?.0.serialize())
+            }
+            Descriptor::Miniscript(ms) => ms.to_script(index)?,
+        })
+    }
+
+    /// The address at `index`. Bare `pk()` and `multi()` have none.
+    pub fn address(&self, index: u32, network: &NetworkParams) -> Result<String> {
+        Ok(Address::from_script(&self.script_pubkey(index)?)?.encode(network))
+    }
+
+    /// The receive (`/0/*`) and change (`/1/*`) descriptors for an account
+    /// key with a SLIP-0132 version: `pkh()` for an xpub, `sh(wpkh())` for
+    /// a ypub and `wpkh()` for a zpub, or their test network versions. The
+    /// multisig versions need the cosigners' keys too, and are
+    /// `ErrorKind::UnsupportedScript`.
+    pub fn from_slip132(key: &str, origin: Option<KeySource>) -> Result<(Descriptor, Descriptor)> {
+        let (xpub, script) = ExtendedPubKey::parse_slip132(key)?;
+        let chain = |index: u32| {
+            let key = DescriptorKey::Extended { origin: origin.clone(), xpub: Box::new(xpub.clone()), path: vec![index], wildcard: true };
+            match script {
+                KeyScript::P2pkh => Ok(Descriptor::Pkh(key)),
+                KeyScript::P2shP2wpkh => Ok(Descriptor::Sh(Box::new(Descriptor::Wpkh(key)))),
+                KeyScript::P2wpkh => Ok(Descriptor::Wpkh(key)),
+                KeyScript::P2shP2wsh | KeyScript::P2wsh => Err(Error::new(ErrorKind::UnsupportedScript, format!("{:?} keys need the other multisig keys", script))),
+            }
+        };
+        Ok((chain(0)?, chain(1)?))
+    }
+
+    /// The descriptor followed by `#` and its checksum.
+    pub fn to_string_with_checksum(&self) -> String {
+        let desc = self.to_string();
+        let checksum = descriptor_checksum(&desc).unwrap();
+        format!("{}#{}", desc, checksum)
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Descriptor::Pk(key) => write!(f, "pk({})", key),
+            Descriptor::Pkh(key) => write!(f, "pkh({})", key),
+            Descriptor::Wpkh(key) => write!(f, "wpkh({})", key),
+            Descriptor::Sh(inner) => write!(f, "sh({})", inner),
+            Descriptor::Wsh(inner) => write!(f, "wsh({})", inner),
+            Descriptor::Multi { m, keys, sorted } => {
+                write!(f, "{}({}", if *sorted { "sortedmulti" } else { "multi" }, m)?;
+                for key in keys {
+                    write!(f, ",{}", key)?;
+                }
+                write!(f, ")")
+            }
+            Descriptor::Tr { internal_key, tree: None } => write!(f, "tr({})", internal_key),
+            Descriptor::Tr { internal_key, tree: Some(tree) } => write!(f, "tr({},{})", internal_key, tree),
+            Descriptor::Miniscript(ms) => write!(f, "{}", ms),
+        }
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Descriptor> {
+        Descriptor::parse(s)
+    }
+}
+
+#[test]
+fn parse_and_derive() {
+    assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+    let mainnet = NetworkParams::mainnet();
+
+    // BIP86 account m/86'/0'/0', first receive and change addresses
+    let xpub = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+    let receive: Descriptor = format!("tr([73c5da0a/86h/0h/0h]{}/0/*)", xpub).parse().unwrap();
+    assert!(receive.is_ranged());
+    assert_eq!(receive.address(0, &mainnet).unwrap(), "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr");
+    assert_eq!(receive.address(1, &mainnet).unwrap(), "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh");
+    let change = Descriptor::parse(&format!("tr({}/1/*)", xpub)).unwrap();
+    assert_eq!(change.address(0, &mainnet).unwrap(), "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7");
+    let displayed = receive.to_string_with_checksum();
+    assert!(displayed.starts_with("tr([73c5da0a/86'/0'/0']xpub6BgB"));
+    assert_eq!(Descriptor::parse(&displayed).unwrap(), receive);
+    assert!(Descriptor::parse(&format!("{}x", displayed)).is_err());
+
+    let g = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    assert_eq!(Descriptor::parse(&format!("wpkh({})", g)).unwrap().address(0, &mainnet).unwrap(), "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+    assert_eq!(Descriptor::parse(&format!("pkh({})", g)).unwrap().address(0, &mainnet).unwrap(), "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH");
+    assert!(!Descriptor::parse(&format!("pkh({})", g)).unwrap().is_ranged());
+
+    // Nested scripts hash what is inside them, keys sorted in sortedmulti
+    let key = |i: u32| ExtendedPubKey::parse(xpub).unwrap().derive_path(&[0, i]).unwrap().public_key;
+    let nested = Descriptor::parse(&format!("sh(wsh(sortedmulti(1,{}/0/*,{})))", xpub, g)).unwrap();
+    let mut points = vec![key(3), S256Point::generator()];
+    points.sort_by_key(|point| point.sec(true));
+    let witness_script = m_of_n(1, &points).unwrap();
+    let redeem_script = p2wsh_script(&sha256(&witness_script.raw_serialize()));
+    assert_eq!(nested.script_pubkey(3).unwrap(), p2sh_script(&hash160(&redeem_script.raw_serialize())));
+
+    let policy = format!("and_v(v:pk({}/0/*),older(144))", xpub);
+    let wsh = Descriptor::parse(&format!("wsh({})", policy)).unwrap();
+    assert!(wsh.is_ranged() && wsh.to_string() == format!("wsh({})", policy));
+    let witness_script = Miniscript::parse(&policy).unwrap().to_script(7).unwrap();
+    assert_eq!(wsh.script_pubkey(7).unwrap(), p2wsh_script(&sha256(&witness_script.raw_serialize())));
+    assert!(Descriptor::parse(&format!("wsh(v:pk({}))", g)).is_err());
+
+    let tree = Descriptor::parse(&format!("tr({},{{pk({}/0/*),pk({})}})", &g[2..], xpub, g)).unwrap();
+    let leaf = |point: &S256Point| {
+        let xonly = XOnlyPoint::from_point(point).serialize().to_vec();
+        TapTree::leaf(Script::new(vec![Command::Data(xonly), Command::Op(OpCode::CheckSig.to_u8())]).raw_serialize())
+    };
+    let internal_key = XOnlyPoint::from_point(&S256Point::generator());
+    let (output_key, _) = TapTree::branch(leaf(&key(5)), leaf(&S256Point::generator())).output_key(&internal_key).unwrap();
+    assert_eq!(tree.script_pubkey(5).unwrap(), p2tr_script(&output_key.serialize()));
+
+    for bad in [
+        format!("wsh(wpkh({}))", g),
+        format!("sh(sh(pkh({})))", g),
+        format!("wpkh({}/0h/*)", xpub),
+        format!("wpkh({}/*/0)", xpub),
+        format!("wpkh(04{})", "11".repeat(64)),
+        format!("multi(3,{},{})", g, g),
+        format!("pkh({})", &g[2..]),
+        "addr(1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH)".to_string(),
+    ]
+    .iter()
+    {
+        assert!(Descriptor::parse(bad).is_err(), "{}", bad);
+    }
+    assert_eq!(Descriptor::parse(&format!("pk({})", g)).unwrap().address(0, &mainnet).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+}
+
+#[test]
+fn slip132_import() {
+    // BIP84 and BIP49 account keys for "abandon ... about"
+    let master = crate::bip39::Mnemonic::parse("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about").unwrap().to_master_key("", crate::params::Network::Mainnet).unwrap();
+    let account = master.derive_path(&[84 + HARDENED, HARDENED, HARDENED]).unwrap().extended_pub_key();
+    let zpub = account.to_slip132(KeyScript::P2wpkh);
+    assert_eq!(zpub, "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs");
+    let origin = KeySource { fingerprint: master.fingerprint(), path: vec![84 + HARDENED, HARDENED, HARDENED] };
+    let (receive, change) = Descriptor::from_slip132(&zpub, Some(origin)).unwrap();
+    let mainnet = NetworkParams::mainnet();
+    assert_eq!(receive.address(0, &mainnet).unwrap(), "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu");
+    assert_eq!(change.address(0, &mainnet).unwrap(), "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el");
+    assert!(receive.to_string().starts_with("wpkh([73c5da0a/84'/0'/0']xpub6CatWdiZ"));
+
+    let account = master.derive_path(&[49 + HARDENED, HARDENED, HARDENED]).unwrap().extended_pub_key();
+    let (receive, _) = Descriptor::from_slip132(&account.to_slip132(KeyScript::P2shP2wpkh), None).unwrap();
+    assert_eq!(receive.address(0, &mainnet).unwrap(), "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf");
+    assert_eq!(Descriptor::from_slip132(&account.to_slip132(KeyScript::P2wsh), None).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+}
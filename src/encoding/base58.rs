@@ -0,0 +1,81 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use num_bigint::{BigInt, Sign};
+use num_integer::Integer;
+use std::convert::TryFrom;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+pub fn encode_base58(bytes: &[u8]) -> String {
+    // Leading zero bytes would vanish in the integer, they become '1's
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    let mut num = BigInt::from_bytes_be(Sign::Plus, bytes);
+    let base = BigInt::from(58);
+    let mut result = Vec::new();
+    while num > BigInt::from(0) {
+        let (quotient, rem) = num.div_mod_floor(&base);
+        result.push(BASE58_ALPHABET[usize::try_from(rem).unwrap()]);
+        num = quotient;
+    }
+    result.extend(std::iter::repeat_n(b'1', zeros));
+    result.reverse();
+    String::from_utf8(result).unwrap()
+}
+
+/// Base58 with the first 4 bytes of hash256(bytes) appended.
+pub fn encode_base58_checksum(bytes: &[u8]) -> String {
+    let mut data = bytes.to_vec();
+    data.extend_from_slice(&hash256(bytes)[..4]);
+    encode_base58(&data)
+}
+
+pub fn decode_base58(s: &str) -> Result<Vec<u8>> {
+    let zeros = s.bytes().take_while(|c| *c == b'1').count();
+    let mut num = BigInt::from(0);
+    for c in s.bytes() {
+        let digit = BASE58_ALPHABET.iter().position(|a| *a == c)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidEncoding, format!("invalid base58 character {:?}", c as char)))?;
+        num = num * 58 + digit;
+    }
+    let mut bytes = vec![0u8; zeros];
+    if num > BigInt::from(0) {
+        bytes.extend(num.to_bytes_be().1);
+    }
+    Ok(bytes)
+}
+
+/// Decodes base58 and checks and strips the 4 byte checksum.
+pub fn decode_base58_checksum(s: &str) -> Result<Vec<u8>> {
+    let mut bytes = decode_base58(s)?;
+    if bytes.len() < 4 {
+        return Err(Error::new(ErrorKind::InvalidEncoding, "base58 string too short for a checksum"));
+    }
+    let checksum = bytes.split_off(bytes.len() - 4);
+    if hash256(&bytes)[..4] != checksum[..] {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("bad base58 checksum for {}", s)));
+    }
+    Ok(bytes)
+}
+
+#[test]
+fn base58_encode() {
+    let bytes = BigInt::parse_bytes(b"7c076ff316692a3d7eb3c3bb0f8b1488cf72e1afcd929e29307032997a838a3d", 16).unwrap().to_bytes_be().1;
+    assert_eq!(encode_base58(&bytes), "9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6");
+    assert_eq!(encode_base58(&[0, 0, 1]), "112");
+    assert_eq!(decode_base58("112").unwrap(), vec![0, 0, 1]);
+    assert_eq!(decode_base58("9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6").unwrap(), bytes);
+    assert!(decode_base58("0OIl").is_err());
+}
+
+#[test]
+fn base58_checksum_round_trip() {
+    let payload = [0x6f, 1, 2, 3, 4, 5];
+    let encoded = encode_base58_checksum(&payload);
+    assert_eq!(decode_base58_checksum(&encoded).unwrap(), payload.to_vec());
+
+    let mut corrupted = encoded.into_bytes();
+    let last = corrupted.len() - 1;
+    corrupted[last] = if corrupted[last] == b'2' { b'3' } else { b'2' };
+    let err = decode_base58_checksum(&String::from_utf8(corrupted).unwrap()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidEncoding);
+}
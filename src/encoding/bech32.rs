@@ -0,0 +1,287 @@
+//! Bech32 (BIP173) and bech32m (BIP350).
+//!
+//! A string is a human readable part, the separator '1', then 5-bit
+//! values written with a 32 character alphabet, the last six of them a
+//! BCH checksum over the whole. The two variants differ only in the
+//! constant the checksum is xored with.
+//!
+//! Strings are at most 90 characters, printable ASCII, and all lowercase
+//! or all uppercase. Mixing case would let a string be copied in two
+//! forms that both check out to the eye but not to every decoder.
+
+use crate::error::{Error, ErrorKind, Result};
+
+const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+const CHECKSUM_LEN: usize = 6;
+/// Longest string BIP173 allows.
+pub const MAX_LEN: usize = 90;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Variant {
+    /// BIP173, used by witness version 0
+    Bech32,
+    /// BIP350, used by witness versions 1 and up
+    Bech32m,
+}
+
+impl Variant {
+    fn constant(self) -> u32 {
+        match self {
+            Variant::Bech32 => 1,
+            Variant::Bech32m => 0x2bc8_30a3,
+        }
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk: u32 = 1;
+    for &value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ff_ffff) << 5 ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+/// The high bits of each character, a zero, then the low bits.
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut result: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    result.push(0);
+    result.extend(hrp.bytes().map(|c| c & 31));
+    result
+}
+
+fn checksum(hrp: &str, data: &[u8], variant: Variant) -> [u8; CHECKSUM_LEN] {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let polymod = polymod(&values) ^ variant.constant();
+    let mut result = [0u8; CHECKSUM_LEN];
+    for (i, value) in result.iter_mut().enumerate() {
+        *value = ((polymod >> (5 * (5 - i))) & 31) as u8;
+    }
+    result
+}
+
+/// Encodes 5-bit `data` under `hrp`, which should be lowercase.
+pub fn encode(hrp: &str, data: &[u8], variant: Variant) -> String {
+    let mut result = format!("{}1", hrp);
+    for &value in data.iter().chain(checksum(hrp, data, variant).iter()) {
+        result.push(CHARSET[value as usize] as char);
+    }
+    result
+}
+
+/// The lowercase hrp, the 5-bit data without the checksum, and which
+/// variant's checksum matched.
+pub fn decode(s: &str) -> Result<(String, Vec<u8>, Variant)> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidEncoding, msg);
+    if s.len() > MAX_LEN {
+        return Err(invalid(format!("{} characters, more than {}", s.len(), MAX_LEN)));
+    }
+    // Each hrp character must be in this range too
+    if let Some(c) = s.chars().find(|c| !(33..=126).contains(&(*c as u32))) {
+        return Err(invalid(format!("character {:?} in {:?}", c, s)));
+    }
+    let lower = s.to_lowercase();
+    if s != lower && s != s.to_uppercase() {
+        return Err(invalid(format!("mixed case in {}", s)));
+    }
+    let sep = lower.rfind('1').ok_or_else(|| invalid(format!("no separator in {}", s)))?;
+    let (hrp, rest) = (&lower[..sep], &lower[sep + 1..]);
+    if hrp.is_empty() {
+        return Err(invalid(format!("empty human readable part in {}", s)));
+    }
+    if rest.len() < CHECKSUM_LEN {
+        return Err(invalid(format!("{} is too short for a checksum", s)));
+    }
+    let data = rest
+        .bytes()
+        .map(|c| CHARSET.iter().position(|&a| a == c).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| invalid(format!("invalid bech32 character in {}", s)))?;
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    let variant = match polymod(&values) {
+        c if c == Variant::Bech32.constant() => Variant::Bech32,
+        c if c == Variant::Bech32m.constant() => Variant::Bech32m,
+        _ => return Err(invalid(format!("bad bech32 checksum for {}", s))),
+    };
+    Ok((hrp.to_string(), data[..data.len() - CHECKSUM_LEN].to_vec(), variant))
+}
+
+/// Regroups `data` from `from`-bit to `to`-bit values. Without `pad`,
+/// leftover bits must be fewer than `from` and all zero.
+pub fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut result = Vec::new();
+    let max = (1 << to) - 1;
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} does not fit in {} bits", value, from)));
+        }
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return Err(Error::new(ErrorKind::InvalidEncoding, "non-zero padding"));
+    }
+    Ok(result)
+}
+
+/// A segwit address: version 0 with bech32, later versions with bech32m.
+pub fn encode_segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let variant = if version == 0 { Variant::Bech32 } else { Variant::Bech32m };
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5, true).unwrap());
+    encode(hrp, &data, variant)
+}
+
+/// The witness version and program of a segwit address for `hrp`.
+pub fn decode_segwit_address(hrp: &str, address: &str) -> Result<(u8, Vec<u8>)> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidEncoding, msg);
+    let (found, data, variant) = decode(address)?;
+    if found != hrp {
+        return Err(Error::new(ErrorKind::UnknownNetwork, format!("expected {} address, got {}", hrp, found)));
+    }
+    let (&version, program) = data.split_first().ok_or_else(|| invalid(format!("{} has no witness version", address)))?;
+    let program = convert_bits(program, 5, 8, false)?;
+    if version > 16 {
+        return Err(invalid(format!("witness version {}", version)));
+    }
+    if !(2..=40).contains(&program.len()) || (version == 0 && program.len() != 20 && program.len() != 32) {
+        return Err(invalid(format!("{} byte version {} witness program", program.len(), version)));
+    }
+    let expected = if version == 0 { Variant::Bech32 } else { Variant::Bech32m };
+    if variant != expected {
+        return Err(invalid(format!("witness version {} with a {:?} checksum", version, variant)));
+    }
+    Ok((version, program))
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[test]
+fn segwit_addresses() {
+    let program = decode_hex("751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+    assert_eq!(encode_segwit_address("bc", 0, &program), "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+    assert_eq!(decode_segwit_address("bc", "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4").unwrap(), (0, program));
+    let output_key = decode_hex("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+    assert_eq!(encode_segwit_address("bc", 1, &output_key), "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0");
+    // Version 1 with a bech32 checksum
+    assert!(decode_segwit_address("bc", "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd").is_err());
+    assert_eq!(decode_segwit_address("tb", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap_err().kind(), ErrorKind::UnknownNetwork);
+    assert!(decode_segwit_address("bc", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5").is_err());
+}
+
+#[test]
+fn bip173_and_bip350_vectors() {
+    let valid = [
+        ("A12UEL5L", Variant::Bech32),
+        ("a12uel5l", Variant::Bech32),
+        ("an83characterlonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1tt5tgs", Variant::Bech32),
+        ("abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw", Variant::Bech32),
+        ("11qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqc8247j", Variant::Bech32),
+        ("split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w", Variant::Bech32),
+        ("?1ezyfcl", Variant::Bech32),
+        ("A1LQFN3A", Variant::Bech32m),
+        ("a1lqfn3a", Variant::Bech32m),
+        ("an83characterlonghumanreadablepartthatcontainsthetheexcludedcharactersbioandnumber11sg7hg6", Variant::Bech32m),
+        ("abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx", Variant::Bech32m),
+        ("11llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllludsr8", Variant::Bech32m),
+        ("split1checkupstagehandshakeupstreamerranterredcaperredlc445v", Variant::Bech32m),
+        ("?1v759aa", Variant::Bech32m),
+    ];
+    for &(s, variant) in valid.iter() {
+        let (hrp, data, found) = decode(s).unwrap();
+        assert_eq!(found, variant, "{}", s);
+        assert_eq!(encode(&hrp, &data, variant), s.to_lowercase());
+        // Flipping any one character breaks the checksum
+        let mut flipped = s.to_lowercase().into_bytes();
+        let last = flipped.len() - 1;
+        flipped[last] = if flipped[last] == b'q' { b'p' } else { b'q' };
+        assert!(decode(std::str::from_utf8(&flipped).unwrap()).is_err(), "{}", s);
+    }
+
+    let invalid = [
+        "\u{20}1nwldj5",
+        "\u{7f}1axkwrx",
+        "\u{80}1eym55h",
+        "an84characterslonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1569pvx",
+        "pzry9x0s0muk",
+        "1pzry9x0s0muk",
+        "x1b4n0q5v",
+        "li1dgmt3",
+        "de1lg7wt\u{ff}",
+        "A1G7SGD8",
+        "10a06t8",
+        "1qzzfhee",
+        "qyrz8wqd2c9m",
+        "y1b0jsk6g",
+        "lt1igcx5c0",
+        "in1muywd",
+        "mm1crxm3i",
+        "au1s5cgom",
+        "M1VUXWEZ",
+        "16plkw9",
+        "1p2gdwpf",
+    ];
+    for s in invalid.iter() {
+        assert_eq!(decode(s).unwrap_err().kind(), ErrorKind::InvalidEncoding, "{:?}", s);
+    }
+
+    let valid_addresses = [
+        ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7", "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"),
+        ("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y", "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ("BC1SW50QGDZ25J", "6002751e"),
+        ("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", "5210751e76e8199196d454941c45d1b3a323"),
+        ("tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy", "0020000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"),
+        ("tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c", "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"),
+        ("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0", "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"),
+    ];
+    for &(address, script_pubkey) in valid_addresses.iter() {
+        let hrp = address[..2].to_lowercase();
+        let (version, program) = decode_segwit_address(&hrp, address).unwrap();
+        let version_op = if version == 0 { 0 } else { 0x50 + version };
+        assert_eq!([&[version_op, program.len() as u8][..], &program].concat(), decode_hex(script_pubkey).unwrap());
+        assert_eq!(encode_segwit_address(&hrp, version, &program), address.to_lowercase());
+    }
+
+    let invalid_addresses = [
+        "tc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq5zuyut",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+        "tb1z0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqglt7rf",
+        "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL",
+        "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+        "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq24jc47",
+        "bc1p38j9r5y49hruaue7wxjce0updqjuyyx0kh56v8s25huc6995vvpql3jow4",
+        "BC130XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ7ZWS8R",
+        "bc1pw5dgrnzv",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v8n0nx0muaewav253zgeav",
+        "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+        "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq47Zagq",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v07qwwzcrf",
+        "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vpggkg4j",
+        "bc1gmk9yu",
+    ];
+    for address in invalid_addresses.iter() {
+        let hrp = if address.to_lowercase().starts_with("tb") { "tb" } else { "bc" };
+        assert!(decode_segwit_address(hrp, address).is_err(), "{}", address);
+    }
+}
@@ -0,0 +1,98 @@
+//! Endianness and hex helpers shared by the parsers.
+//!
+//! Bitcoin serializes integers little-endian, and txids and block hashes
+//! are displayed with their bytes reversed.
+
+use crate::error::{Error, ErrorKind, Result};
+use num_bigint::{BigInt, Sign};
+use std::io::Read;
+
+pub fn u32_to_le(num: u32) -> [u8; 4] {
+    num.to_le_bytes()
+}
+
+pub fn u64_to_le(num: u64) -> [u8; 8] {
+    num.to_le_bytes()
+}
+
+pub fn read_u32_le<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64_le<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads exactly `len` bytes.
+pub fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn le_bytes_to_int(bytes: &[u8]) -> BigInt {
+    BigInt::from_bytes_le(Sign::Plus, bytes)
+}
+
+/// Little-endian encoding of a non-negative `num`, zero padded to `len`.
+pub fn int_to_le_bytes(num: &BigInt, len: usize) -> Result<Vec<u8>> {
+    let (sign, mut bytes) = num.to_bytes_le();
+    if sign == Sign::Minus || bytes.len() > len {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("{} does not fit in {} bytes", num, len)));
+    }
+    bytes.resize(len, 0);
+    Ok(bytes)
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("bad hex string of length {}", hex.len())));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidEncoding, format!("bad hex digits {:?}", &hex[i..i + 2])))
+        })
+        .collect()
+}
+
+/// Hex of the bytes in reverse order, the way txids and block hashes are shown.
+pub fn to_reversed_hex(bytes: &[u8]) -> String {
+    bytes.iter().rev().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a displayed txid or block hash back into serialization order.
+pub fn from_reversed_hex(hex: &str) -> Result<Vec<u8>> {
+    let mut bytes = decode_hex(hex)?;
+    bytes.reverse();
+    Ok(bytes)
+}
+
+#[test]
+fn little_endian_helpers() {
+    assert_eq!(u32_to_le(1), [1, 0, 0, 0]);
+    assert_eq!(u64_to_le(10011545), [0x99, 0xc3, 0x98, 0, 0, 0, 0, 0]);
+    assert_eq!(le_bytes_to_int(&decode_hex("99c3980000000000").unwrap()), BigInt::from(10011545));
+    assert_eq!(int_to_le_bytes(&BigInt::from(32454049), 8).unwrap(), decode_hex("a135ef0100000000").unwrap());
+    assert!(int_to_le_bytes(&BigInt::from(0x10000), 2).is_err());
+}
+
+#[test]
+fn hex_helpers() {
+    let genesis = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    let bytes = from_reversed_hex(genesis).unwrap();
+    assert_eq!(&bytes[..2], &[0x6f, 0xe2]);
+    assert_eq!(to_reversed_hex(&bytes), genesis);
+    assert_eq!(encode_hex(&decode_hex("00ff10").unwrap()), "00ff10");
+    assert!(decode_hex("abc").is_err());
+    assert!(decode_hex("zz").is_err());
+}
@@ -0,0 +1,94 @@
+//! CompactSize integers: one byte below 0xfd, otherwise a 0xfd/0xfe/0xff
+//! marker followed by a little-endian u16/u32/u64.
+
+use crate::error::{Error, ErrorKind, Result};
+use std::io::{Read, Write};
+
+/// Largest length a message may announce, as in Bitcoin Core.
+pub const MAX_SIZE: u64 = 0x0200_0000;
+
+/// Reads a CompactSize, rejecting encodings that are longer than needed.
+pub fn read_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut marker = [0u8; 1];
+    reader.read_exact(&mut marker)?;
+    let (value, min) = match marker[0] {
+        0xfd => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf)?;
+            (u16::from_le_bytes(buf) as u64, 0xfd)
+        }
+        0xfe => {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf)?;
+            (u32::from_le_bytes(buf) as u64, 0x1_0000)
+        }
+        0xff => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            (u64::from_le_bytes(buf), 0x1_0000_0000)
+        }
+        byte => return Ok(byte as u64),
+    };
+    if value < min {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("non-canonical varint 0x{:02x} {}", marker[0], value)));
+    }
+    Ok(value)
+}
+
+/// Reads a CompactSize used as a length or count, which must fit in
+/// memory and not exceed `MAX_SIZE`.
+pub fn read_varint_len<R: Read>(reader: &mut R) -> Result<usize> {
+    let value = read_varint(reader)?;
+    if value > MAX_SIZE {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("varint length {} over {}", value, MAX_SIZE)));
+    }
+    Ok(value as usize)
+}
+
+/// Writes `value` in its shortest form and returns the number of bytes.
+pub fn encode_varint<W: Write>(writer: &mut W, value: u64) -> Result<usize> {
+    if value < 0xfd {
+        writer.write_all(&[value as u8])?;
+        Ok(1)
+    } else if value <= 0xffff {
+        writer.write_all(&[0xfd])?;
+        writer.write_all(&(value as u16).to_le_bytes())?;
+        Ok(3)
+    } else if value <= 0xffff_ffff {
+        writer.write_all(&[0xfe])?;
+        writer.write_all(&(value as u32).to_le_bytes())?;
+        Ok(5)
+    } else {
+        writer.write_all(&[0xff])?;
+        writer.write_all(&value.to_le_bytes())?;
+        Ok(9)
+    }
+}
+
+#[test]
+fn varint_round_trip() {
+    let cases: [(u64, &[u8]); 6] = [
+        (0, &[0x00]),
+        (0xfc, &[0xfc]),
+        (0xfd, &[0xfd, 0xfd, 0x00]),
+        (0xffff, &[0xfd, 0xff, 0xff]),
+        (0x1_0000, &[0xfe, 0x00, 0x00, 0x01, 0x00]),
+        (0x1_0000_0000, &[0xff, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]),
+    ];
+    for (value, bytes) in cases.iter() {
+        let mut encoded = Vec::new();
+        assert_eq!(encode_varint(&mut encoded, *value).unwrap(), bytes.len());
+        assert_eq!(&encoded[..], *bytes);
+        assert_eq!(read_varint(&mut &bytes[..]).unwrap(), *value);
+    }
+}
+
+#[test]
+fn varint_errors() {
+    let non_canonical: [&[u8]; 3] = [&[0xfd, 0xfc, 0x00], &[0xfe, 0xff, 0xff, 0x00, 0x00], &[0xff, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]];
+    for bytes in non_canonical.iter() {
+        assert_eq!(read_varint(&mut &bytes[..]).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    }
+    assert_eq!(read_varint(&mut &[0xfd, 0x01][..]).unwrap_err().kind(), ErrorKind::Io);
+    assert_eq!(read_varint_len(&mut &[0xfe, 0x01, 0x00, 0x00, 0x02][..]).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
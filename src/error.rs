@@ -0,0 +1,116 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Regular(ErrorKind),
+    /// An error with extra context, e.g. the offending point
+    Custom(ErrorKind, String),
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(kind: ErrorKind, msg: S) -> Error {
+        Error::Custom(kind, msg.into())
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Regular(kind) => kind,
+            Error::Custom(kind, _) => kind,
+            Error::Io(_) => ErrorKind::Io,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Regular(ref kind) => write!(f, "{}", kind),
+            Error::Custom(ref kind, ref msg) => write!(f, "{}: {}", kind, msg),
+            Error::Io(ref err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::Regular(kind)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ErrorKind {
+    OutOfRange,
+    NotOnCurve,
+    FieldMismatch,
+    InvalidEncoding,
+    InvalidSignature,
+    UnknownNetwork,
+    Fetch,
+    UnsupportedScript,
+    Io,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &str {
+        match *self {
+            ErrorKind::OutOfRange  =>
+                "Parameters out of range",
+            ErrorKind::NotOnCurve =>
+                "Point is not on the curve",
+            ErrorKind::FieldMismatch =>
+                "Field elements belong to different fields",
+            ErrorKind::InvalidEncoding =>
+                "Invalid encoding",
+            ErrorKind::InvalidSignature =>
+                "Invalid signature",
+            ErrorKind::UnknownNetwork =>
+                "Unknown or conflicting network",
+            ErrorKind::Fetch =>
+                "Could not fetch transaction",
+            ErrorKind::UnsupportedScript =>
+                "Unsupported script type",
+            ErrorKind::Io =>
+                "I/O error",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[test]
+fn error_kind_and_source() {
+    use std::error::Error as _;
+
+    let err = Error::new(ErrorKind::NotOnCurve, "(1, 2)");
+    assert_eq!(err.kind(), ErrorKind::NotOnCurve);
+    assert_eq!(err.to_string(), "Point is not on the curve: (1, 2)");
+    assert!(err.source().is_none());
+
+    let err: Error = io::Error::new(io::ErrorKind::UnexpectedEof, "short read").into();
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert!(err.source().is_some());
+}
@@ -0,0 +1,274 @@
+//! A dead man's switch: coins the owner can spend at any time, and the
+//! recipient after a block height unless the owner refreshes the switch
+//! first.
+//!
+//! The coins sit in a P2WSH output of
+//!
+//! `OP_IF <owner> OP_ELSE <lock height> OP_CHECKLOCKTIMEVERIFY OP_DROP
+//! <recipient> OP_ENDIF OP_CHECKSIG`
+//!
+//! A refresh is the owner spending them to the same switch a period later.
+//! Witness transactions have ids before they are signed, so a whole chain
+//! of refreshes can be signed at once and the key put away; each one can
+//! only confirm in the period before the deadline it pushes back, so the
+//! deadline is never more than two periods off. `Refreshes` holds the
+//! signed chain and says when the next one is due. Whoever holds it
+//! broadcasts that one only while the owner checks in; once they stop, the
+//! lock height passes and `claim` lets the recipient take the coins.
+
+use crate::address::Address;
+use crate::error::{Error, ErrorKind, Result};
+use crate::math::ecc::S256Point;
+use crate::params::Network;
+use crate::script::opcodes::OpCode;
+use crate::script::timelock::witness;
+use crate::script::{Command, Script};
+use crate::tx::builder::{dust_threshold, Utxo};
+use crate::tx::psbt::Psbt;
+use crate::tx::{LockTime, Sequence, Tx, TxIn, TxOut};
+use std::collections::VecDeque;
+
+fn op(op: OpCode) -> Command {
+    Command::Op(op.to_u8())
+}
+
+fn key(pubkey: &S256Point) -> Command {
+    Command::Data(pubkey.sec(true))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadMansSwitch {
+    /// Spends at any time, refreshes included
+    pub owner_key: S256Point,
+    /// Spends once the lock height has passed
+    pub recipient_key: S256Point,
+    /// After this block the recipient can take the coins
+    pub lock_height: LockTime,
+    /// Blocks each refresh pushes the lock height back
+    pub period: u32,
+    pub network: Network,
+}
+
+impl DeadMansSwitch {
+    /// A switch locked until after block `lock_height`, which must leave
+    /// room for the period before it.
+    pub fn new(owner_key: S256Point, recipient_key: S256Point, lock_height: u32, period: u32, network: Network) -> Result<DeadMansSwitch> {
+        if period == 0 || period > lock_height {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("period of {} blocks before height {}", period, lock_height)));
+        }
+        let lock_height = LockTime::from_height(lock_height)?;
+        Ok(DeadMansSwitch { owner_key, recipient_key, lock_height, period, network })
+    }
+
+    /// The witness script of coins in the switch.
+    pub fn witness_script(&self) -> Script {
+        Script::new(vec![
+            op(OpCode::If),
+            key(&self.owner_key),
+            op(OpCode::Else),
+            Command::num(self.lock_height.to_consensus_u32() as i64),
+            op(OpCode::CheckLockTimeVerify),
+            op(OpCode::Drop),
+            key(&self.recipient_key),
+            op(OpCode::EndIf),
+            op(OpCode::CheckSig),
+        ])
+    }
+
+    /// Where to fund the switch.
+    pub fn address(&self) -> Result<String> {
+        Ok(Address::from_script(&self.witness_script().p2wsh_script_pubkey())?.encode(&self.network.params()))
+    }
+
+    /// The switch a period later.
+    pub fn next(&self) -> Result<DeadMansSwitch> {
+        let lock_height = self.lock_height.to_consensus_u32().checked_add(self.period).ok_or_else(|| Error::new(ErrorKind::OutOfRange, "lock height overflows"))?;
+        Ok(DeadMansSwitch { lock_height: LockTime::from_height(lock_height)?, ..self.clone() })
+    }
+
+    /// `count` refreshes of `coin`, each spending the one before and paying
+    /// `fee`, for the owner key. The last switch is returned with them.
+    pub fn refreshes(&self, coin: &Utxo, count: usize, fee: u64) -> Result<(Vec<Psbt>, DeadMansSwitch)> {
+        let mut switch = self.clone();
+        let mut coin = coin.clone();
+        let mut psbts = vec![];
+        for _ in 0..count {
+            let next = switch.next()?;
+            let lock_time = LockTime::from_height(switch.lock_height.to_consensus_u32() - switch.period)?;
+            let psbt = switch.spend(&coin, next.witness_script().p2wsh_script_pubkey().raw_serialize(), fee, lock_time)?;
+            coin = Utxo::new(psbt.unsigned_tx.hash(), 0, psbt.unsigned_tx.tx_outs[0].clone());
+            psbts.push(psbt);
+            switch = next;
+        }
+        Ok((psbts, switch))
+    }
+
+    /// The owner sending `coin` to `script_pubkey`, at any time.
+    pub fn withdraw(&self, coin: &Utxo, script_pubkey: Vec<u8>, fee: u64) -> Result<Psbt> {
+        self.spend(coin, script_pubkey, fee, LockTime::ZERO)
+    }
+
+    /// The recipient sending `coin` to `script_pubkey`, which confirms
+    /// after the lock height.
+    pub fn claim(&self, coin: &Utxo, script_pubkey: Vec<u8>, fee: u64) -> Result<Psbt> {
+        self.spend(coin, script_pubkey, fee, self.lock_height)
+    }
+
+    // One input to one output, with what a signer needs
+    fn spend(&self, coin: &Utxo, script_pubkey: Vec<u8>, fee: u64, lock_time: LockTime) -> Result<Psbt> {
+        let witness_script = self.witness_script();
+        if coin.output.script_pubkey != witness_script.p2wsh_script_pubkey().raw_serialize() {
+            return Err(Error::new(ErrorKind::UnsupportedScript, "coin is not in the switch"));
+        }
+        let amount = coin.output.amount.checked_sub(fee).filter(|amount| *amount >= dust_threshold(&script_pubkey));
+        let amount = amount.ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("{} sat coin leaves dust after the {} sat fee", coin.output.amount, fee))
+        })?;
+        let mut tx_in = TxIn::new(coin.prev_tx, coin.prev_index);
+        // A final sequence would turn the locktime off
+        tx_in.sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+        let tx = Tx::new(2, vec![tx_in], vec![TxOut::new(amount, script_pubkey)], lock_time, self.network);
+        let mut psbt = Psbt::new(tx)?;
+        psbt.inputs[0].witness_utxo = Some(coin.output.clone());
+        psbt.inputs[0].witness_script = Some(witness_script.raw_serialize());
+        Ok(psbt)
+    }
+
+    /// The signed transaction of a PSBT from this switch: the owner's
+    /// branch if the owner key signed, the recipient's otherwise.
+    pub fn finalize(&self, mut psbt: Psbt) -> Result<Tx> {
+        let input = &mut psbt.inputs[0];
+        let witness_script = input.witness_script.take().ok_or_else(|| Error::new(ErrorKind::InvalidSignature, "switch input has no witness script"))?;
+        let sigs = &input.partial_sigs;
+        let stack = match (sigs.get(&self.owner_key.sec(true)), sigs.get(&self.recipient_key.sec(true))) {
+            (Some(sig), _) => vec![sig.clone(), vec![1]],
+            (None, Some(sig)) => vec![sig.clone(), vec![]],
+            (None, None) => return Err(Error::new(ErrorKind::InvalidSignature, "switch input is not signed")),
+        };
+        input.final_script_witness = Some(witness(stack, &Script::from_bytes(&witness_script)?));
+        input.partial_sigs.clear();
+        psbt.extract()
+    }
+}
+
+/// Signed refreshes waiting to be broadcast, the next first.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Refreshes {
+    txs: VecDeque<Tx>,
+}
+
+impl Refreshes {
+    pub fn new(txs: Vec<Tx>) -> Refreshes {
+        Refreshes { txs: txs.into() }
+    }
+
+    /// The next refresh, if a block at `height` can hold it.
+    pub fn due(&self, height: u32) -> Option<&Tx> {
+        self.txs.front().filter(|tx| tx.locktime.is_satisfied_by(height, 0))
+    }
+
+    /// Drops the next refresh once `txid` (display order), which it is,
+    /// has confirmed. Returns whether it was.
+    pub fn confirmed(&mut self, txid: &[u8; 32]) -> bool {
+        if self.txs.front().is_some_and(|tx| tx.hash() == *txid) {
+            self.txs.pop_front();
+            return true;
+        }
+        false
+    }
+
+    /// Refreshes left, how many periods the switch can still be put off.
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+}
+
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use crate::script::interpreter::{verify_script, verify_script_in, SpendContext, VerifyFlags};
+#[cfg(test)]
+use crate::script::p2wpkh_script;
+#[cfg(test)]
+use crate::tx::SighashType;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn dead_mans_switch() {
+    let keys: Vec<PrivateKey> = (1..=3).map(|n| PrivateKey::new(&BigInt::from(n * 6151)).unwrap()).collect();
+    let (owner, recipient, stranger) = (&keys[0], &keys[1], &keys[2]);
+    let switch = DeadMansSwitch::new(owner.public_key().clone(), recipient.public_key().clone(), 1_000, 100, Network::Regtest).unwrap();
+    assert!(switch.address().unwrap().starts_with("bcrt1q"));
+    assert!(DeadMansSwitch::new(owner.public_key().clone(), recipient.public_key().clone(), 50, 100, Network::Regtest).is_err());
+
+    // Whether the input's witness spends `coin` out of `switch`, with and
+    // without the locktime checked
+    let verify = |tx: &Tx, coin: &Utxo, switch: &DeadMansSwitch| {
+        let witness_script = switch.witness_script();
+        let z = tx.bip143_sig_hash(0, &witness_script.raw_serialize(), coin.output.amount, SighashType::All).unwrap();
+        let script_pubkey = Script::from_bytes(&coin.output.script_pubkey).unwrap();
+        let witness = &tx.tx_ins[0].witness;
+        let context = SpendContext::new(tx, 0).unwrap();
+        let checked = verify_script_in(&Script::default(), &script_pubkey, witness, &z, VerifyFlags::STANDARD, &context).unwrap().valid;
+        let unchecked = VerifyFlags::STANDARD.without(VerifyFlags::CHECKLOCKTIMEVERIFY);
+        (checked, verify_script(&Script::default(), &script_pubkey, witness, &z, unchecked).unwrap().valid)
+    };
+    let coin = Utxo::new([4; 32], 0, TxOut::new(500_000, switch.witness_script().p2wsh_script_pubkey().raw_serialize()));
+    let destination = p2wpkh_script(&[9; 20]).raw_serialize();
+
+    // The owner spends whenever
+    let mut psbt = switch.withdraw(&coin, destination.clone(), 1_000).unwrap();
+    assert_eq!(psbt.sign(owner).unwrap(), 1);
+    let withdrawal = switch.finalize(psbt).unwrap();
+    assert_eq!(withdrawal.locktime, LockTime::ZERO);
+    assert_eq!(verify(&withdrawal, &coin, &switch), (true, true));
+
+    // The recipient only from the lock height
+    let mut psbt = switch.claim(&coin, destination.clone(), 1_000).unwrap();
+    assert_eq!(psbt.clone().sign(stranger).unwrap(), 0);
+    assert_eq!(psbt.sign(recipient).unwrap(), 1);
+    let claim = switch.finalize(psbt).unwrap();
+    assert_eq!(verify(&claim, &coin, &switch), (true, true));
+    assert!(!claim.is_final_at(1_000, 0) && claim.is_final_at(1_001, 0));
+    // A locktime before the lock height fails OP_CHECKLOCKTIMEVERIFY
+    let mut psbt = switch.claim(&coin, destination.clone(), 1_000).unwrap();
+    psbt.unsigned_tx.locktime = LockTime::from_height(999).unwrap();
+    psbt.sign(recipient).unwrap();
+    assert_eq!(verify(&switch.finalize(psbt).unwrap(), &coin, &switch), (false, true));
+    assert!(switch.finalize(switch.claim(&coin, destination.clone(), 1_000).unwrap()).is_err());
+    let elsewhere = Utxo::new([5; 32], 0, TxOut::new(500_000, destination.clone()));
+    assert_eq!(switch.withdraw(&elsewhere, destination.clone(), 1_000).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+
+    // Three refreshes, signed at once and passed around as base64
+    let (psbts, last) = switch.refreshes(&coin, 3, 1_000).unwrap();
+    assert_eq!(last.lock_height, LockTime::from_height(1_300).unwrap());
+    let txs: Vec<Tx> = psbts
+        .into_iter()
+        .map(|mut psbt| {
+            assert_eq!(psbt.sign(owner).unwrap(), 1);
+            switch.finalize(Psbt::from_base64(&psbt.to_base64()).unwrap()).unwrap()
+        })
+        .collect();
+    let (mut spent, mut current) = (coin, switch.clone());
+    for tx in txs.iter() {
+        assert_eq!(verify(tx, &spent, &current), (true, true));
+        current = current.next().unwrap();
+        assert_eq!(tx.tx_outs[0].script_pubkey, current.witness_script().p2wsh_script_pubkey().raw_serialize());
+        spent = Utxo::new(tx.hash(), 0, tx.tx_outs[0].clone());
+    }
+    assert_eq!(spent.output.amount, 497_000);
+
+    let mut refreshes = Refreshes::new(txs.clone());
+    assert_eq!(refreshes.due(900), None);
+    assert_eq!(refreshes.due(901), Some(&txs[0]));
+    assert!(!refreshes.confirmed(&txs[1].hash()));
+    assert!(refreshes.confirmed(&txs[0].hash()));
+    // The next one waits for its own period
+    assert_eq!((refreshes.due(1_000), refreshes.due(1_001)), (None, Some(&txs[1])));
+    assert_eq!(refreshes.len(), 2);
+}
@@ -0,0 +1,225 @@
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+#[cfg(feature = "network")]
+use std::convert::TryInto;
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Double SHA256, used for txids, block hashes and checksums.
+pub fn hash256(data: &[u8]) -> [u8; 32] {
+    sha256(&sha256(data))
+}
+
+/// RIPEMD160(SHA256(data)), used for addresses.
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(sha256(data)).into()
+}
+
+/// SHA1, only for OP_SHA1.
+#[cfg(feature = "script")]
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    sha1::Sha1::digest(data).into()
+}
+
+/// 32-bit MurmurHash3, which BIP37 bloom filters use.
+#[cfg(feature = "network")]
+pub fn murmur3(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in chunks.by_ref() {
+        let k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0u32, |k, byte| k << 8 | *byte as u32);
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ h >> 16
+}
+
+/// SHA3-256 (FIPS 202), which Tor v3 onion addresses checksum with.
+#[cfg(feature = "network")]
+pub fn sha3_256(data: &[u8]) -> [u8; 32] {
+    const RATE: usize = 136;
+    const ROUND_CONSTANTS: [u64; 24] = [
+        0x0000_0000_0000_0001, 0x0000_0000_0000_8082, 0x8000_0000_0000_808a, 0x8000_0000_8000_8000,
+        0x0000_0000_0000_808b, 0x0000_0000_8000_0001, 0x8000_0000_8000_8081, 0x8000_0000_0000_8009,
+        0x0000_0000_0000_008a, 0x0000_0000_0000_0088, 0x0000_0000_8000_8009, 0x0000_0000_8000_000a,
+        0x0000_0000_8000_808b, 0x8000_0000_0000_008b, 0x8000_0000_0000_8089, 0x8000_0000_0000_8003,
+        0x8000_0000_0000_8002, 0x8000_0000_0000_0080, 0x0000_0000_0000_800a, 0x8000_0000_8000_000a,
+        0x8000_0000_8000_8081, 0x8000_0000_0000_8080, 0x0000_0000_8000_0001, 0x8000_0000_8000_8008,
+    ];
+    const ROTATIONS: [u32; 25] = [0, 1, 62, 28, 27, 36, 44, 6, 55, 20, 3, 10, 43, 25, 39, 41, 45, 15, 21, 8, 18, 2, 61, 56, 14];
+    fn keccak_f(a: &mut [u64; 25]) {
+        for rc in ROUND_CONSTANTS.iter() {
+            // theta
+            let c: Vec<u64> = (0..5).map(|x| a[x] ^ a[x + 5] ^ a[x + 10] ^ a[x + 15] ^ a[x + 20]).collect();
+            for x in 0..5 {
+                let d = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+                for y in 0..5 {
+                    a[x + 5 * y] ^= d;
+                }
+            }
+            // rho and pi
+            let mut b = [0u64; 25];
+            for x in 0..5 {
+                for y in 0..5 {
+                    b[y + 5 * ((2 * x + 3 * y) % 5)] = a[x + 5 * y].rotate_left(ROTATIONS[x + 5 * y]);
+                }
+            }
+            // chi, then iota
+            for x in 0..5 {
+                for y in 0..5 {
+                    a[x + 5 * y] = b[x + 5 * y] ^ (!b[(x + 1) % 5 + 5 * y] & b[(x + 2) % 5 + 5 * y]);
+                }
+            }
+            a[0] ^= rc;
+        }
+    }
+    let mut padded = data.to_vec();
+    padded.push(0x06);
+    padded.resize(padded.len().div_ceil(RATE) * RATE, 0);
+    let last = padded.len() - 1;
+    padded[last] |= 0x80;
+    let mut state = [0u64; 25];
+    for block in padded.chunks(RATE) {
+        for (lane, word) in state.iter_mut().zip(block.chunks(8)) {
+            *lane ^= u64::from_le_bytes(word.try_into().unwrap());
+        }
+        keccak_f(&mut state);
+    }
+    let mut hash = [0u8; 32];
+    for (chunk, lane) in hash.chunks_mut(8).zip(state.iter()) {
+        chunk.copy_from_slice(&lane.to_le_bytes());
+    }
+    hash
+}
+
+/// SipHash-2-4 keyed with `k0` and `k1`, which BIP158 filters use.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [k0 ^ 0x736f_6d65_7073_6575, k1 ^ 0x646f_7261_6e64_6f6d, k0 ^ 0x6c79_6765_6e65_7261, k1 ^ 0x7465_6462_7974_6573];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    let mut compress = |m: u64| {
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    };
+    let mut chunks = data.chunks_exact(8);
+    for chunk in chunks.by_ref() {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        compress(u64::from_le_bytes(word));
+    }
+    // The last word holds the leftover bytes and the length's low byte
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// BIP340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || data).
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = sha256(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[test]
+fn tagged_hash_matches_definition() {
+    let tag_hash = sha256(b"BIP0340/challenge");
+    let mut preimage = tag_hash.to_vec();
+    preimage.extend_from_slice(&tag_hash);
+    preimage.extend_from_slice(b"data");
+    assert_eq!(tagged_hash("BIP0340/challenge", b"data"), sha256(&preimage));
+    assert_ne!(tagged_hash("TapLeaf", b"data"), tagged_hash("TapBranch", b"data"));
+}
+
+#[test]
+fn sha256_empty() {
+    let expected = [
+        0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+        0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+    ];
+    assert_eq!(sha256(b""), expected);
+}
+
+#[test]
+fn hash160_of_sec() {
+    // hash160 of the compressed SEC of 1 * G
+    let sec = [
+        0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b, 0x07,
+        0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98,
+    ];
+    let expected = [
+        0x75, 0x1e, 0x76, 0xe8, 0x19, 0x91, 0x96, 0xd4, 0x54, 0x94,
+        0x1c, 0x45, 0xd1, 0xb3, 0xa3, 0x23, 0xf1, 0x43, 0x3b, 0xd6,
+    ];
+    assert_eq!(hash160(&sec), expected);
+}
+
+#[test]
+fn siphash_vectors() {
+    // The SipHash paper's key 00..0f
+    let (k0, k1) = (0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908);
+    assert_eq!(siphash24(k0, k1, b""), 0x726f_db47_dd0e_0e31);
+    assert_eq!(siphash24(k0, k1, &[0]), 0x74f8_39c5_93dc_67fd);
+    assert_eq!(siphash24(k0, k1, &(0..15).collect::<Vec<u8>>()), 0xa129_ca61_49be_45e5);
+}
+
+#[cfg(all(test, feature = "network"))]
+use crate::encoding::util::encode_hex;
+
+#[cfg(feature = "network")]
+#[test]
+fn sha3_vectors() {
+    // FIPS 202's examples; 200 bytes span two blocks
+    assert_eq!(encode_hex(&sha3_256(b"")), "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a");
+    assert_eq!(encode_hex(&sha3_256(b"abc")), "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532");
+    assert_eq!(encode_hex(&sha3_256(&[0xa3; 200])), "79f38adec5c20307a98ef76e8324afbfd46cfd81b22e3973c65fa1bd9de31787");
+    // A full block, so the padding takes one of its own
+    assert_eq!(encode_hex(&sha3_256(&[0xa3; 136])[..4]), "0adf6bfb");
+}
+
+#[cfg(feature = "network")]
+#[test]
+fn murmur3_vectors() {
+    // From Bitcoin Core's hash tests
+    assert_eq!(murmur3(b"", 0), 0);
+    assert_eq!(murmur3(b"", 0xfba4_c795), 0x6a39_6f08);
+    assert_eq!(murmur3(b"", 0xffff_ffff), 0x81f1_6f39);
+    assert_eq!(murmur3(&[0x00], 0), 0x514e_28b7);
+    assert_eq!(murmur3(&[0x00, 0x11], 0), 0x16c6_b7ab);
+    assert_eq!(murmur3(&[0x00, 0x11, 0x22], 0), 0x8eb5_1c3d);
+    assert_eq!(murmur3(&[0x00, 0x11, 0x22, 0x33], 0), 0xb447_1bf8);
+}
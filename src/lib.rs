@@ -0,0 +1,41 @@
+#[cfg(feature = "script")]
+pub mod address;
+#[cfg(feature = "ecc")]
+pub mod bip32;
+#[cfg(feature = "ecc")]
+pub mod bip39;
+#[cfg(feature = "tx")]
+pub mod block;
+#[cfg(feature = "network")]
+pub mod bloom;
+#[cfg(feature = "script")]
+pub mod descriptor;
+pub mod encoding;
+#[cfg(feature = "script")]
+pub mod escrow;
+pub mod error;
+pub mod hash;
+pub mod math;
+#[cfg(feature = "ecc")]
+pub mod message;
+#[cfg(feature = "script")]
+pub mod migrate;
+#[cfg(feature = "script")]
+pub mod miniscript;
+#[cfg(feature = "network")]
+pub mod network;
+pub mod params;
+#[cfg(feature = "script")]
+pub mod script;
+#[cfg(feature = "tx")]
+pub mod tx;
+#[cfg(feature = "script")]
+pub mod vault;
+#[cfg(feature = "wallet")]
+pub mod wallet;
+
+#[cfg(feature = "alloc-stats")]
+pub mod alloc_stats;
+
+#[cfg(all(feature = "nonce-injection", not(debug_assertions)))]
+compile_error!("the nonce-injection feature reproduces test vectors and must not be used in release builds");
@@ -1,8 +1,45 @@
-mod math;
+use prog_btc_book::math::FieldElement;
+use std::env;
+use std::process;
 
-use math::{FieldElement};
+const USAGE: &str = "usage: prog_btc_book backup <wallet file> <backup file> [--seed]
+       prog_btc_book restore <backup file> <wallet file> [--seed]
+       prog_btc_book reserves prove <utxo file> <descriptor> <message>
+       prog_btc_book reserves verify <utxo file> <message> [--btc] [--rate <currency>=<price>]
+       prog_btc_book inspect [<input>]
+
+backup --seed reads the wallet's mnemonic from stdin to back up with it.
+restore writes a new wallet file; --seed prints the backed up mnemonic.
+reserves prove prints an unsigned proof of reserves over the unspent
+coins paying the descriptor; reserves verify reads a signed one from
+stdin and prints the satoshis it proves, in bitcoin with --btc and also
+at a price per bitcoin with --rate, say --rate USD=65000.00.
+inspect decodes a transaction or block header in hex, a PSBT in base64,
+an address, a descriptor, a WIF key or an extended public key, read from
+stdin if not given; raw transaction, header and PSBT bytes work too.";
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        #[cfg(feature = "wallet")]
+        Some("backup") | Some("restore") => wallet_cli::run(&args),
+        #[cfg(feature = "wallet")]
+        Some("reserves") => wallet_cli::reserves(&args),
+        #[cfg(feature = "script")]
+        Some("inspect") => inspect::run(&args),
+        Some(_) => Err(USAGE.to_string()),
+        None => {
+            demo();
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn demo() {
     let field_el = FieldElement::new(2, 11);
 
     match field_el {
@@ -11,3 +48,274 @@ fn main() {
     }
 }
 
+#[cfg(feature = "wallet")]
+mod wallet_cli {
+    use super::USAGE;
+    use prog_btc_book::bip39::Mnemonic;
+    use prog_btc_book::descriptor::Descriptor;
+    use prog_btc_book::params::Network;
+    use prog_btc_book::tx::psbt::Psbt;
+    use prog_btc_book::tx::{Amount, FiatRate, FixedRates, FormatOptions, Unit};
+    use prog_btc_book::wallet::reserves::{build_proof, verify_proof};
+    use prog_btc_book::wallet::{Backup, FileUtxoStore, UtxoStore};
+    use std::fs::OpenOptions;
+    use std::io::{self, Read, Write};
+
+    /// Scripts of a ranged descriptor searched for coins to prove.
+    const PROOF_LOOKAHEAD: u32 = 1000;
+
+    pub fn run(args: &[String]) -> Result<(), String> {
+        let seed = args.iter().any(|arg| arg == "--seed");
+        let paths: Vec<&String> = args[1..].iter().filter(|arg| *arg != "--seed").collect();
+        let (from, to) = match paths.as_slice() {
+            [from, to] => (from, to),
+            _ => return Err(USAGE.to_string()),
+        };
+        if args[0] == "backup" {
+            let mnemonic = if seed { Some(read_mnemonic()?) } else { None };
+            let backup = Backup::from_wallet_file(from, mnemonic).map_err(|err| format!("{}: {}", from, err))?;
+            backup.save(to).map_err(|err| format!("{}: {}", to, err))?;
+            println!("Backed up {} to {}", from, to);
+        } else {
+            let backup = Backup::load(from).map_err(|err| format!("{}: {}", from, err))?;
+            if seed && backup.mnemonic.is_none() {
+                return Err(format!("{} has no seed", from));
+            }
+            // Never over an existing wallet
+            let mut file = OpenOptions::new().write(true).create_new(true).open(to).map_err(|err| format!("{}: {}", to, err))?;
+            file.write_all(backup.wallet_file().as_bytes()).and_then(|_| file.sync_all()).map_err(|err| format!("{}: {}", to, err))?;
+            println!("Restored {} from {}", to, from);
+            if let (Some(mnemonic), true) = (&backup.mnemonic, seed) {
+                println!("{}", mnemonic);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reserves(args: &[String]) -> Result<(), String> {
+        match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["reserves", "prove", path, descriptor, message] => {
+                let store = FileUtxoStore::open(path).map_err(|err| format!("{}: {}", path, err))?;
+                let descriptor = Descriptor::parse(descriptor).map_err(|err| format!("descriptor: {}", err))?;
+                let count = if descriptor.is_ranged() { PROOF_LOOKAHEAD } else { 1 };
+                let mut utxos = vec![];
+                for index in 0..count {
+                    let script_pubkey = descriptor.script_pubkey(index).map_err(|err| format!("descriptor: {}", err))?;
+                    utxos.extend(store.utxos_for_script(&script_pubkey.raw_serialize()).into_iter().map(|coin| coin.utxo));
+                }
+                // A proof is never broadcast, so the network doesn't matter
+                let proof = build_proof(message, &utxos, Network::Mainnet).map_err(|err| err.to_string())?;
+                println!("{}", proof.to_base64());
+            }
+            ["reserves", "verify", path, message, options @ ..] => {
+                let store = FileUtxoStore::open(path).map_err(|err| format!("{}: {}", path, err))?;
+                let mut encoded = String::new();
+                io::stdin().read_to_string(&mut encoded).map_err(|err| err.to_string())?;
+                let proof = Psbt::from_base64(encoded.trim()).map_err(|err| format!("proof: {}", err))?;
+                let total = verify_proof(&proof, message, &store).map_err(|err| err.to_string())?;
+                println!("{}", format_amount(total, options)?);
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+        Ok(())
+    }
+
+    // `sats` as the --btc and --rate options ask, plain satoshis without
+    // them
+    fn format_amount(sats: u64, options: &[&str]) -> Result<String, String> {
+        let (mut unit, mut rates, mut currency) = (Unit::Sat, FixedRates::new(), None);
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match *option {
+                "--btc" => unit = Unit::Btc,
+                "--rate" => {
+                    let rate = FiatRate::parse(options.next().ok_or_else(|| USAGE.to_string())?).map_err(|err| format!("rate: {}", err))?;
+                    currency = Some(rate.currency.clone());
+                    rates.insert(rate);
+                }
+                _ => return Err(USAGE.to_string()),
+            }
+        }
+        let mut format = FormatOptions::new(unit);
+        match currency {
+            None if unit == Unit::Sat => return Ok(sats.to_string()),
+            None => {}
+            Some(ref currency) => format = format.fiat(&rates, currency),
+        }
+        Amount(sats).format(&format).map_err(|err| err.to_string())
+    }
+
+    fn read_mnemonic() -> Result<Mnemonic, String> {
+        let mut phrase = String::new();
+        io::stdin().read_line(&mut phrase).map_err(|err| err.to_string())?;
+        Mnemonic::parse(phrase.trim()).map_err(|err| format!("mnemonic: {}", err))
+    }
+}
+
+#[cfg(feature = "script")]
+mod inspect {
+    use super::USAGE;
+    use prog_btc_book::address::Address;
+    use prog_btc_book::bip32::ExtendedPubKey;
+    use prog_btc_book::block::{BlockHeader, HEADER_SIZE};
+    use prog_btc_book::descriptor::Descriptor;
+    use prog_btc_book::encoding::util::{decode_hex, encode_hex};
+    use prog_btc_book::math::ecc::PrivateKey;
+    use prog_btc_book::params::{Network, NetworkParams};
+    use prog_btc_book::script::Script;
+    use prog_btc_book::tx::psbt::Psbt;
+    use prog_btc_book::tx::Tx;
+    use std::io::{self, Read};
+
+    /// What an input decodes to, its type first.
+    type Fields = Vec<(String, String)>;
+
+    const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+    fn field<V: ToString>(name: &str, value: V) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    pub fn run(args: &[String]) -> Result<(), String> {
+        let input = match args {
+            [_, input] => input.as_bytes().to_vec(),
+            [_] => {
+                let mut input = vec![];
+                io::stdin().read_to_end(&mut input).map_err(|err| err.to_string())?;
+                input
+            }
+            _ => return Err(USAGE.to_string()),
+        };
+        for (name, value) in detect(&input)? {
+            println!("{}: {}", name, value);
+        }
+        Ok(())
+    }
+
+    // Pasted text is tried as each format in turn; anything else, or text
+    // none of them take, as raw bytes
+    fn detect(input: &[u8]) -> Result<Fields, String> {
+        let text = std::str::from_utf8(input).map(str::trim).unwrap_or_default();
+        if text.contains('(') {
+            return Descriptor::parse(text).map_err(|err| format!("descriptor: {}", err)).and_then(|desc| descriptor(&desc));
+        }
+        if text.starts_with("cHNidP8") {
+            return Psbt::from_base64(text).map(|psbt| inspect_psbt(&psbt)).map_err(|err| format!("PSBT: {}", err));
+        }
+        if let Some(fields) = decode_hex(text).ok().filter(|bytes| !bytes.is_empty()).and_then(|bytes| binary(&bytes).ok()) {
+            return Ok(fields);
+        }
+        if let Ok((key, script)) = ExtendedPubKey::parse_slip132(text) {
+            return Ok(vec![
+                field("type", "extended public key"),
+                field("script", format!("{:?}", script)),
+                field("depth", key.depth),
+                field("fingerprint", encode_hex(&key.fingerprint())),
+                field("parent fingerprint", encode_hex(&key.parent_fingerprint)),
+                field("child number", key.child_number),
+                field("public key", encode_hex(&key.public_key.sec(true))),
+            ]);
+        }
+        if let Ok((address, network)) = Address::parse_any(text) {
+            return Ok(vec![
+                field("type", "address"),
+                field("network", &network.name),
+                field("script_pubkey", encode_hex(&address.script_pubkey().raw_serialize())),
+            ]);
+        }
+        if let Ok((key, compressed, network)) = PrivateKey::parse_wif(text) {
+            return Ok(vec![
+                field("type", "WIF private key"),
+                field("network", &network.name),
+                field("compressed", compressed),
+                field("public key", encode_hex(&key.public_key().sec(compressed))),
+            ]);
+        }
+        binary(input).map_err(|_| "not a transaction, PSBT, address, descriptor, WIF key, extended public key or block header".to_string())
+    }
+
+    // A PSBT, a transaction, or a block header, which is exactly 80 bytes
+    // and not a transaction
+    fn binary(bytes: &[u8]) -> Result<Fields, String> {
+        if bytes.starts_with(PSBT_MAGIC) {
+            return Psbt::parse(&mut &bytes[..]).map(|psbt| inspect_psbt(&psbt)).map_err(|err| format!("PSBT: {}", err));
+        }
+        let mut reader = bytes;
+        match Tx::parse(&mut reader, Network::Mainnet) {
+            Ok(tx) if reader.is_empty() => return Ok(inspect_tx(&tx)),
+            _ if bytes.len() == HEADER_SIZE => {}
+            Ok(_) => return Err(format!("{} bytes after the transaction", reader.len())),
+            Err(err) => return Err(format!("transaction: {}", err)),
+        }
+        let header = BlockHeader::parse(&mut &bytes[..]).map_err(|err| format!("block header: {}", err))?;
+        Ok(vec![
+            field("type", "block header"),
+            field("hash", header.id()),
+            field("version", format!("{:#010x}", header.version)),
+            field("prev_block", encode_hex(&header.prev_block)),
+            field("merkle_root", encode_hex(&header.merkle_root)),
+            field("timestamp", header.timestamp),
+            field("bits", format!("{:08x}", header.bits)),
+            field("nonce", header.nonce),
+            field("proof of work", header.check_pow()),
+        ])
+    }
+
+    // The address a script_pubkey pays, as on mainnet since transactions
+    // don't say their network, or its hex
+    fn destination(script_pubkey: &[u8]) -> String {
+        Script::from_bytes(script_pubkey)
+            .and_then(|script| Address::from_script(&script))
+            .map_or_else(|_| encode_hex(script_pubkey), |address| address.encode(&NetworkParams::mainnet()))
+    }
+
+    fn inspect_tx(tx: &Tx) -> Fields {
+        let mut fields = vec![
+            field("type", "transaction"),
+            field("txid", tx.id()),
+            field("wtxid", tx.wtxid()),
+            field("version", tx.version),
+            field("locktime", tx.locktime),
+            field("vsize", tx.vsize()),
+            field("weight", tx.weight()),
+        ];
+        for (index, tx_in) in tx.tx_ins.iter().enumerate() {
+            fields.push(field(&format!("input {}", index), format!("{}:{}", encode_hex(&tx_in.prev_tx), tx_in.prev_index)));
+        }
+        for (index, tx_out) in tx.tx_outs.iter().enumerate() {
+            fields.push(field(&format!("output {}", index), format!("{} {}", tx_out.amount, destination(&tx_out.script_pubkey))));
+        }
+        fields
+    }
+
+    fn inspect_psbt(psbt: &Psbt) -> Fields {
+        let spent: Option<Vec<u64>> = (0..psbt.inputs.len()).map(|index| psbt.spent_output(index).ok().flatten().map(|tx_out| tx_out.amount)).collect();
+        let outputs: u64 = psbt.unsigned_tx.tx_outs.iter().map(|tx_out| tx_out.amount).sum();
+        let fee = spent.and_then(|amounts| amounts.iter().sum::<u64>().checked_sub(outputs));
+        let mut fields = vec![
+            field("type", "PSBT"),
+            field("txid", psbt.unsigned_tx.id()),
+            field("fee", fee.map_or_else(|| "unknown".to_string(), |fee| fee.to_string())),
+            field("finalized", psbt.inputs.iter().all(|input| input.is_finalized())),
+        ];
+        for (index, (tx_in, input)) in psbt.unsigned_tx.tx_ins.iter().zip(psbt.inputs.iter()).enumerate() {
+            let sigs = input.partial_sigs.len() + input.tap_key_sig.iter().count();
+            fields.push(field(&format!("input {}", index), format!("{}:{} with {} signatures", encode_hex(&tx_in.prev_tx), tx_in.prev_index, sigs)));
+        }
+        for (index, tx_out) in psbt.unsigned_tx.tx_outs.iter().enumerate() {
+            fields.push(field(&format!("output {}", index), format!("{} {}", tx_out.amount, destination(&tx_out.script_pubkey))));
+        }
+        fields
+    }
+
+    fn descriptor(desc: &Descriptor) -> Result<Fields, String> {
+        let script_pubkey = desc.script_pubkey(0).map_err(|err| format!("descriptor: {}", err))?;
+        Ok(vec![
+            field("type", "descriptor"),
+            field("descriptor", desc.to_string_with_checksum()),
+            field("ranged", desc.is_ranged()),
+            field("script_pubkey 0", encode_hex(&script_pubkey.raw_serialize())),
+            field("address 0", destination(&script_pubkey.raw_serialize())),
+        ])
+    }
+}
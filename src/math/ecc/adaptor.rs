@@ -0,0 +1,113 @@
+//! Schnorr adaptor signatures.
+//!
+//! An adaptor signature is a BIP340 signature that is missing the discrete
+//! log `t` of an adaptor point `T = tG`. Anyone can check that it becomes a
+//! valid signature once `t` is added, and whoever sees both the adaptor and
+//! the completed signature learns `t`. This is the building block of
+//! scriptless atomic swaps.
+
+use super::private_key::PrivateKey;
+use super::s256::{s256_order, S256Point};
+use super::scalar_mul::{BaseKind, ScalarKind, ScalarMulContext};
+use super::schnorr::{challenge, even_y_secret, nonce, SchnorrSignature};
+use super::secret::SecretScalar;
+use super::xonly::XOnlyPoint;
+use crate::error::{Error, ErrorKind, Result};
+use num_bigint::BigInt;
+use num_integer::Integer;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdaptorSignature {
+    /// x coordinate of the final nonce point R
+    pub r: BigInt,
+    pub s: BigInt,
+    /// R was negated to get an even y, so `t` has to be subtracted
+    /// instead of added when completing
+    pub negated: bool,
+}
+
+impl AdaptorSignature {
+    /// Signs `msg` so that the result only verifies as a BIP340 signature
+    /// after it is completed with the discrete log of `adaptor`.
+    pub fn sign(key: &PrivateKey, msg: &[u8], adaptor: &S256Point, aux_rand: &[u8; 32]) -> Result<AdaptorSignature> {
+        let n = s256_order();
+        let d = even_y_secret(key)?;
+        let pubkey = key.xonly_public_key();
+        // T goes into the nonce so it never matches a plain signature's nonce
+        let k = nonce(&d, aux_rand, &[&adaptor.sec(true), &pubkey.serialize(), msg])?;
+
+        let big_r = &k.public_point() + adaptor;
+        if big_r.is_infinity() {
+            return Err(Error::new(ErrorKind::InvalidSignature, "nonce point cancels the adaptor point"));
+        }
+        let negated = !big_r.has_even_y();
+        let k = if negated { SecretScalar::new(&(n - k.to_bigint()))? } else { k };
+        let r = big_r.x().num.clone();
+        let e = challenge(&r, &pubkey, msg);
+        let s = (k.to_bigint() + e * d.to_bigint()).mod_floor(n);
+        Ok(AdaptorSignature { r, s, negated })
+    }
+
+    /// Checks that completing with the discrete log of `adaptor` will give a
+    /// valid signature for `msg` under `pubkey`.
+    pub fn verify(&self, pubkey: &XOnlyPoint, msg: &[u8], adaptor: &S256Point) -> bool {
+        if self.s >= *s256_order() {
+            return false;
+        }
+        let big_r = match XOnlyPoint::lift_x(&self.r) {
+            Ok(point) => point.to_point(),
+            Err(_) => return false,
+        };
+        let e = challenge(&self.r, pubkey, msg);
+        let fixed = ScalarMulContext { base: BaseKind::Fixed, scalar: ScalarKind::Public };
+        let s_g = S256Point::generator().scalar_mul_ctx(&self.s, &fixed);
+        // sG - eP is R - T, or R + T when R was negated
+        let partial = &s_g + &pubkey.as_point().scalar_mul(&e).negate();
+        let adaptor = if self.negated { adaptor.negate() } else { adaptor.clone() };
+        &partial + &adaptor == big_r
+    }
+
+    /// Adds the adaptor secret, giving an ordinary BIP340 signature.
+    pub fn complete(&self, adaptor_secret: &SecretScalar) -> SchnorrSignature {
+        let n = s256_order();
+        let t = adaptor_secret.to_bigint();
+        let s = if self.negated { &self.s - t } else { &self.s + t };
+        SchnorrSignature { r: self.r.clone(), s: s.mod_floor(n) }
+    }
+
+    /// Recovers the adaptor secret from the completed signature `sig`.
+    pub fn extract_secret(&self, sig: &SchnorrSignature, adaptor: &S256Point) -> Result<SecretScalar> {
+        if sig.r != self.r {
+            return Err(Error::new(ErrorKind::InvalidSignature, "signature does not complete this adaptor signature"));
+        }
+        let t = if self.negated { &self.s - &sig.s } else { &sig.s - &self.s };
+        let secret = SecretScalar::new(&t.mod_floor(s256_order()))?;
+        if secret.public_point() != *adaptor {
+            return Err(Error::new(ErrorKind::InvalidSignature, "extracted secret does not match the adaptor point"));
+        }
+        Ok(secret)
+    }
+}
+
+#[test]
+fn adaptor_sign_complete_extract() {
+    let key = PrivateKey::new(&BigInt::from(0xc0ffee)).unwrap();
+    let pubkey = key.xonly_public_key();
+    let mut seen = (false, false);
+    for i in 1..9u64 {
+        let t = SecretScalar::new(&(BigInt::from(i) << 200)).unwrap();
+        let adaptor = t.public_point();
+        let msg = [i as u8; 32];
+        let pre = AdaptorSignature::sign(&key, &msg, &adaptor, &[0; 32]).unwrap();
+        if pre.negated { seen.1 = true } else { seen.0 = true }
+
+        assert!(pre.verify(&pubkey, &msg, &adaptor));
+        assert!(!pre.verify(&pubkey, &msg, &S256Point::generator()));
+        assert!(!SchnorrSignature { r: pre.r.clone(), s: pre.s.clone() }.verify(&pubkey, &msg));
+
+        let sig = pre.complete(&t);
+        assert!(sig.verify(&pubkey, &msg));
+        assert_eq!(pre.extract_secret(&sig, &adaptor).unwrap(), t);
+    }
+    assert_eq!(seen, (true, true));
+}
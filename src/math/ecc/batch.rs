@@ -0,0 +1,23 @@
+//! Shared pieces of batch signature verification.
+
+use super::s256::bytes_to_int;
+use crate::hash::sha256;
+use num_bigint::BigInt;
+
+/// Coefficients `a_0 = 1, a_1, ...` for the random linear combination of a
+/// batch. They come from a hash of every input in the batch, as BIP340
+/// suggests, so a forger can't pick signatures that cancel out. 128 bits
+/// per coefficient is enough and keeps the multiplication chain short.
+pub(crate) fn batch_coefficients(inputs: &[u8], count: usize) -> Vec<BigInt> {
+    let seed = sha256(inputs);
+    let mut coefficients = Vec::with_capacity(count);
+    if count > 0 {
+        coefficients.push(BigInt::from(1));
+    }
+    for i in 1..count {
+        let mut data = seed.to_vec();
+        data.extend_from_slice(&(i as u32).to_le_bytes());
+        coefficients.push(bytes_to_int(&sha256(&data)[..16]));
+    }
+    coefficients
+}
@@ -0,0 +1,182 @@
+//! DER encodings shared with OpenSSL and other ecosystems: ECDSA
+//! signatures, and public keys as X.509 SubjectPublicKeyInfo (DER or PEM).
+
+use super::s256::{bytes_to_int, S256Point};
+use super::signature::Signature;
+use crate::error::{Error, ErrorKind, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use num_bigint::BigInt;
+
+// AlgorithmIdentifier { id-ecPublicKey, secp256k1 }
+const SPKI_ALGORITHM: &[u8] = &[
+    0x30, 0x10,
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01,
+    0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x0a,
+];
+
+const PEM_BEGIN: &str = "-----BEGIN PUBLIC KEY-----";
+const PEM_END: &str = "-----END PUBLIC KEY-----";
+
+fn der_error<S: Into<String>>(msg: S) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, msg)
+}
+
+// Everything here is short, so lengths always fit the one byte form
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag, contents.len() as u8];
+    result.extend_from_slice(contents);
+    result
+}
+
+/// Splits off one short-form TLV with the given tag, returning its
+/// contents and what follows it.
+fn read_tlv(tag: u8, bytes: &[u8]) -> Result<(&[u8], &[u8])> {
+    match bytes {
+        [t, len, rest @ ..] if *t == tag && *len < 0x80 && rest.len() >= *len as usize => {
+            Ok(rest.split_at(*len as usize))
+        }
+        _ => Err(der_error(format!("expected DER tag 0x{:02x}", tag))),
+    }
+}
+
+fn der_integer(num: &BigInt) -> Vec<u8> {
+    let (_, mut bytes) = num.to_bytes_be();
+    // A set high bit would make the integer negative
+    if bytes[0] & 0x80 != 0 {
+        bytes.insert(0, 0);
+    }
+    tlv(0x02, &bytes)
+}
+
+fn parse_der_integer(bytes: &[u8]) -> Result<(BigInt, &[u8])> {
+    let (contents, rest) = read_tlv(0x02, bytes)?;
+    match contents {
+        [] => Err(der_error("empty DER integer")),
+        [b, ..] if b & 0x80 != 0 => Err(der_error("negative DER integer")),
+        [0, b, ..] if b & 0x80 == 0 => Err(der_error("DER integer has an extra leading zero")),
+        _ => Ok((bytes_to_int(contents), rest)),
+    }
+}
+
+impl Signature {
+    /// DER encoding, as in OpenSSL and script_sigs.
+    pub fn der(&self) -> Vec<u8> {
+        let mut contents = der_integer(&self.r);
+        contents.extend(der_integer(&self.s));
+        tlv(0x30, &contents)
+    }
+
+    /// Parses a strictly (BIP66) encoded DER signature.
+    pub fn parse_der(der: &[u8]) -> Result<Signature> {
+        let (contents, rest) = read_tlv(0x30, der)?;
+        if !rest.is_empty() {
+            return Err(der_error("trailing bytes after DER signature"));
+        }
+        let (r, contents) = parse_der_integer(contents)?;
+        let (s, contents) = parse_der_integer(contents)?;
+        if !contents.is_empty() {
+            return Err(der_error("trailing bytes inside DER signature"));
+        }
+        Ok(Signature::new(r, s))
+    }
+}
+
+impl S256Point {
+    /// The key as a DER SubjectPublicKeyInfo, what `openssl ec -pubout
+    /// -outform DER` writes.
+    pub fn to_spki_der(&self, compressed: bool) -> Vec<u8> {
+        let mut bit_string = vec![0];
+        bit_string.extend(self.sec(compressed));
+        let mut contents = SPKI_ALGORITHM.to_vec();
+        contents.extend(tlv(0x03, &bit_string));
+        tlv(0x30, &contents)
+    }
+
+    pub fn from_spki_der(der: &[u8]) -> Result<S256Point> {
+        let (contents, rest) = read_tlv(0x30, der)?;
+        if !rest.is_empty() || !contents.starts_with(SPKI_ALGORITHM) {
+            return Err(der_error("not a secp256k1 SubjectPublicKeyInfo"));
+        }
+        let (bit_string, rest) = read_tlv(0x03, &contents[SPKI_ALGORITHM.len()..])?;
+        match bit_string {
+            [0, sec @ ..] if rest.is_empty() => S256Point::parse_sec(sec),
+            _ => Err(der_error("bad public key bit string")),
+        }
+    }
+
+    /// PEM armored SubjectPublicKeyInfo ("BEGIN PUBLIC KEY").
+    pub fn to_pem(&self, compressed: bool) -> String {
+        let encoded = BASE64.encode(self.to_spki_der(compressed));
+        let mut pem = format!("{}\n", PEM_BEGIN);
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str(PEM_END);
+        pem.push('\n');
+        pem
+    }
+
+    pub fn from_pem(pem: &str) -> Result<S256Point> {
+        let start = pem.find(PEM_BEGIN).ok_or_else(|| der_error("missing PEM header"))? + PEM_BEGIN.len();
+        let end = pem.find(PEM_END).ok_or_else(|| der_error("missing PEM footer"))?;
+        if end < start {
+            return Err(der_error("PEM footer before header"));
+        }
+        let body: String = pem[start..end].split_whitespace().collect();
+        let der = BASE64.decode(body).map_err(|e| der_error(format!("bad PEM base64: {}", e)))?;
+        S256Point::from_spki_der(&der)
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[test]
+fn signature_der() {
+    let sig = Signature::new(
+        super::s256::hex_int(b"37206a0610995c58074999cb9767b87af4c4978db68c06e8e6e81d282047a7c6"),
+        super::s256::hex_int(b"8ca63759c1157ebeaec0d03cecca119fc9a75bf8e6d0fa65c841c8e2738cdaec"),
+    );
+    let der = decode_hex("3045022037206a0610995c58074999cb9767b87af4c4978db68c06e8e6e81d282047a7c60221008ca63759c1157ebeaec0d03cecca119fc9a75bf8e6d0fa65c841c8e2738cdaec").unwrap();
+    assert_eq!(sig.der(), der);
+    assert_eq!(Signature::parse_der(&der).unwrap(), sig);
+
+    let mut trailing = der.clone();
+    trailing.push(0);
+    assert!(Signature::parse_der(&trailing).is_err());
+    // r padded with a zero it doesn't need
+    let padded = decode_hex("3046022100").unwrap().into_iter().chain(der[4..].iter().copied()).collect::<Vec<u8>>();
+    assert!(Signature::parse_der(&padded).is_err());
+}
+
+#[test]
+fn openssl_interop() {
+    // openssl ecparam -name secp256k1 -genkey; openssl ec -pubout [-conv_form compressed]
+    let uncompressed_pem = "-----BEGIN PUBLIC KEY-----
+MFYwEAYHKoZIzj0CAQYFK4EEAAoDQgAE2FIO5UQ5R0y1lanMnlXhtJrgTaJXSbPJ
+aVOXgPVrDFAExwUpd3qP6MpaYNn9PcOLnI8BOePfz6h4Yv/bfm9Viw==
+-----END PUBLIC KEY-----
+";
+    let compressed_pem = "-----BEGIN PUBLIC KEY-----
+MDYwEAYHKoZIzj0CAQYFK4EEAAoDIgAD2FIO5UQ5R0y1lanMnlXhtJrgTaJXSbPJ
+aVOXgPVrDFA=
+-----END PUBLIC KEY-----
+";
+    let secret = super::s256::hex_int(b"ca524d15ece177448ddf775b25a5fabeb156b71357108a3955d50a704e240558");
+    let key = super::private_key::PrivateKey::new(&secret).unwrap();
+    let point = S256Point::from_pem(uncompressed_pem).unwrap();
+    assert_eq!(&point, key.public_key());
+    assert_eq!(S256Point::from_pem(compressed_pem).unwrap(), point);
+    assert_eq!(point.to_pem(false), uncompressed_pem);
+    assert_eq!(point.to_pem(true), compressed_pem);
+
+    // openssl dgst -sha256 -sign, which does not normalize s
+    let der = decode_hex("3046022100c2d57966b95d073df3bb2211fca08a8bb6031adf2f40e9555230ea73f9e5ddf2022100af9860ce4f30e49898b1a5070c8ad88a6bd0ea58f5ee5ca2e01cc6ff8cddce68").unwrap();
+    let sig = Signature::parse_der(&der).unwrap();
+    let z = bytes_to_int(&crate::hash::sha256(b"Programming Bitcoin"));
+    assert!(point.verify(&z, &sig));
+    assert!(!point.verify_with(&z, &sig, true));
+    assert!(point.verify_with(&z, &sig.normalize_s(), true));
+}
@@ -0,0 +1,188 @@
+//! ElligatorSwift (the SwiftEC encoding as BIP324 uses it): a public key's
+//! x coordinate as 64 bytes that are indistinguishable from random.
+//!
+//! An encoding is two field elements u and t. Decoding maps them to an x
+//! on the curve; encoding picks u and one of eight inverse branches until
+//! one gives a t for the wanted x.
+
+use super::s256::{bytes_to_int, int_to_bytes32, s256_field, s256_prime, S256Point};
+use super::secret::SecretScalar;
+use super::xonly::sqrt;
+use crate::hash::tagged_hash;
+use crate::math::FieldElement;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use std::sync::OnceLock;
+
+/// Size of an encoded public key.
+pub const ELLSWIFT_SIZE: usize = 64;
+
+static MINUS_3_SQRT: OnceLock<FieldElement> = OnceLock::new();
+
+// sqrt(-3), the root (p + 1) / 4 exponentiation gives
+fn minus_3_sqrt() -> &'static FieldElement {
+    MINUS_3_SQRT.get_or_init(|| sqrt(&s256_field(-3)).unwrap())
+}
+
+fn neg(a: &FieldElement) -> FieldElement {
+    &s256_field(0) - a
+}
+
+fn is_zero(a: &FieldElement) -> bool {
+    a.num == BigInt::from(0)
+}
+
+// x^3 + 7
+fn curve_rhs(x: &FieldElement) -> FieldElement {
+    x.pow(3) + &s256_field(7)
+}
+
+fn is_valid_x(x: &FieldElement) -> bool {
+    sqrt(&curve_rhs(x)).is_some()
+}
+
+/// The x coordinate the encoding (u, t) decodes to.
+pub fn xswiftec(u: &FieldElement, t: &FieldElement) -> FieldElement {
+    let one = s256_field(1);
+    let u = if is_zero(u) { one.clone() } else { u.clone() };
+    let mut t = if is_zero(t) { one } else { t.clone() };
+    if is_zero(&(curve_rhs(&u) + &t.pow(2))) {
+        t = &t * &BigInt::from(2);
+    }
+    let big_x = (&curve_rhs(&u) - &t.pow(2)).div_field(&(&t * &BigInt::from(2)));
+    let big_y = (&big_x + &t).div_field(&(minus_3_sqrt() * &u));
+    let x_over_y = big_x.div_field(&big_y);
+    let half = |a: FieldElement| a.div(2);
+    let candidates = [
+        &u + &(&big_y.pow(2) * &BigInt::from(4)),
+        half(&neg(&x_over_y) - &u),
+        half(&x_over_y - &u),
+    ];
+    candidates.iter().find(|x| is_valid_x(x)).cloned().expect("one of the SwiftEC candidates is on the curve")
+}
+
+/// A t with `xswiftec(u, t) == x` from inverse branch `case` (0 to 7), if
+/// that branch has one.
+pub fn xswiftec_inv(x: &FieldElement, u: &FieldElement, case: u8) -> Option<FieldElement> {
+    let (v, s) = if case & 2 == 0 {
+        if is_valid_x(&(&neg(x) - u)) {
+            return None;
+        }
+        let denominator = &(&u.pow(2) + &(u * x)) + &x.pow(2);
+        (x.clone(), neg(&curve_rhs(u)).div_field(&denominator))
+    } else {
+        let s = x - u;
+        if is_zero(&s) {
+            return None;
+        }
+        let inner = &(&curve_rhs(u) * &BigInt::from(4)) + &(&(&s * &BigInt::from(3)) * &u.pow(2));
+        let r = sqrt(&neg(&(&s * &inner)))?;
+        if case & 1 == 1 && is_zero(&r) {
+            return None;
+        }
+        ((&r.div_field(&s) - u).div(2), s)
+    };
+    let w = sqrt(&s)?;
+    let one = s256_field(1);
+    let c = minus_3_sqrt();
+    let minus = (u * &(&one - c)).div(2) + &v;
+    let plus = (u * &(&one + c)).div(2) + &v;
+    Some(match case & 5 {
+        0 => neg(&(&w * &minus)),
+        1 => &w * &plus,
+        4 => &w * &minus,
+        _ => neg(&(&w * &plus)),
+    })
+}
+
+/// Decodes 64 bytes to a point. Every input decodes; the y coordinate
+/// has the parity of t.
+pub fn ellswift_decode(encoded: &[u8; ELLSWIFT_SIZE]) -> S256Point {
+    let u = s256_field(bytes_to_int(&encoded[..32]));
+    let t = s256_field(bytes_to_int(&encoded[32..]));
+    let x = xswiftec(&u, &t);
+    let y = sqrt(&curve_rhs(&x)).unwrap();
+    let y = if y.num.is_odd() == t.num.is_odd() { y } else { neg(&y) };
+    S256Point::new(x.num, y.num).unwrap()
+}
+
+/// Encodes `point`'s x coordinate, choosing among its encodings with
+/// `entropy`. The result is uniform over the encodings when `entropy` is
+/// random.
+pub fn ellswift_encode(point: &S256Point, entropy: &[u8; 32]) -> [u8; ELLSWIFT_SIZE] {
+    let x = point.x();
+    let mut preimage = entropy.to_vec();
+    preimage.extend_from_slice(&int_to_bytes32(&x.num));
+    for counter in 0u32.. {
+        let mut data = preimage.clone();
+        data.extend_from_slice(&counter.to_le_bytes());
+        let hash = tagged_hash("secp256k1_ellswift_encode", &data);
+        let u = bytes_to_int(&hash);
+        if u == BigInt::from(0) || &u >= s256_prime() {
+            continue;
+        }
+        let u = s256_field(u);
+        if let Some(t) = xswiftec_inv(x, &u, (counter % 8) as u8) {
+            let mut encoded = [0u8; ELLSWIFT_SIZE];
+            encoded[..32].copy_from_slice(&int_to_bytes32(&u.num));
+            encoded[32..].copy_from_slice(&int_to_bytes32(&t.num));
+            return encoded;
+        }
+    }
+    unreachable!()
+}
+
+/// The x coordinate of `secret` times the point `theirs` encodes.
+pub fn ellswift_ecdh_xonly(secret: &SecretScalar, theirs: &[u8; ELLSWIFT_SIZE]) -> [u8; 32] {
+    int_to_bytes32(&ellswift_decode(theirs).scalar_mul(&secret.to_bigint()).x().num)
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[cfg(test)]
+fn hex64(hex: &str) -> [u8; ELLSWIFT_SIZE] {
+    let mut bytes = [0u8; ELLSWIFT_SIZE];
+    bytes.copy_from_slice(&decode_hex(hex).unwrap());
+    bytes
+}
+
+#[test]
+fn ellswift_vectors() {
+    // From libsecp256k1's ellswift decoding tests
+    let cases = [
+        ("00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000", "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c", false),
+        ("000000000000000000000000000000000000000000000000000000000000000001d3475bf7655b0fb2d852921035b2ef607f49069b97454e6795251062741771", "b5da00b73cd6560520e7c364086e7cd23a34bf60d0e707be9fc34d4cd5fdfa2c", true),
+        ("00000000000000000000000000000000000000000000000000000000000000008421cc930e77c9f514b6915c3dbe2a94c6d8f690b5b739864ba6789fb8a55dd0", "9f59c40275f5085a006f05dae77eb98c6fd0db1ab4a72ac47eae90a4fc9e57e0", false),
+        ("0000000000000000000000000000000000000000000000000000000000000000bde70df51939b94c9c24979fa7dd04ebd9b3572da7802290438af2a681895441", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa9fffffd6b", true),
+    ];
+    for (encoded, x, odd_y) in cases.iter() {
+        let point = ellswift_decode(&hex64(encoded));
+        assert_eq!(int_to_bytes32(&point.x().num).to_vec(), decode_hex(x).unwrap());
+        assert_eq!(!point.has_even_y(), *odd_y);
+    }
+
+    // And one of its inverse tests, where only branches 2, 3, 6 and 7 work
+    let u = s256_field(bytes_to_int(&decode_hex("05ff6bdad900fc3261bc7fe34e2fb0f569f06e091ae437d3a52e9da0cbfb9590").unwrap()));
+    let x = s256_field(bytes_to_int(&decode_hex("80cdf63774ec7022c89a5a8558e373a279170285e0ab27412dbce510bdfe23fc").unwrap()));
+    assert_eq!(xswiftec_inv(&x, &u, 0), None);
+    let t = xswiftec_inv(&x, &u, 2).unwrap();
+    assert_eq!(int_to_bytes32(&t.num).to_vec(), decode_hex("45654798ece071ba79286d04f7f3eb1c3f1d17dd883610f2ad2efd82a287466b").unwrap());
+    let t = xswiftec_inv(&x, &u, 7).unwrap();
+    assert_eq!(int_to_bytes32(&t.num).to_vec(), decode_hex("f51557790948938ea7badbe7340afcc523a8b816164a2c4dcfc24695c9ad76d8").unwrap());
+
+    // Every branch that gives a t must decode back to x
+    let point = S256Point::generator().scalar_mul(&BigInt::from(12345));
+    let u = s256_field(777);
+    let mut found = 0;
+    for case in 0..8 {
+        if let Some(t) = xswiftec_inv(point.x(), &u, case) {
+            assert_eq!(&xswiftec(&u, &t), point.x());
+            found += 1;
+        }
+    }
+    assert!(found > 0);
+    let encoded = ellswift_encode(&point, &[7; 32]);
+    assert_eq!(ellswift_decode(&encoded).x(), point.x());
+    assert_ne!(ellswift_encode(&point, &[8; 32]), encoded);
+}
@@ -1,9 +1,60 @@
 use super::FieldElement;
+use crate::error::{Error, ErrorKind, Result};
 use num_bigint::BigInt;
+use std::fmt;
 use std::ops::Add;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct FieldPoint {
+mod s256;
+pub use s256::*;
+
+mod secret;
+pub use secret::*;
+
+mod private_key;
+pub use private_key::*;
+
+mod xonly;
+pub use xonly::*;
+
+mod batch;
+
+mod der;
+
+pub mod ellswift;
+
+mod rfc6979;
+
+mod signature;
+pub use signature::*;
+
+pub mod schnorr;
+pub use schnorr::SchnorrSignature;
+
+pub mod adaptor;
+pub use adaptor::AdaptorSignature;
+
+pub mod scalar_mul;
+
+pub mod threshold;
+
+pub mod trace;
+use trace::PointOp;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawFieldPoint"))]
+pub struct FieldPoint {
+    pub x:  FieldElement,
+    pub y:  FieldElement,
+    pub a:  FieldElement,
+    pub b:  FieldElement,
+    pub inf: bool,
+}
+
+// Deserialized points are checked against their curve like any other
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawFieldPoint {
     x:  FieldElement,
     y:  FieldElement,
     a:  FieldElement,
@@ -11,12 +62,28 @@ struct FieldPoint {
     inf: bool,
 }
 
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<RawFieldPoint> for FieldPoint {
+    type Error = Error;
+    fn try_from(raw: RawFieldPoint) -> Result<FieldPoint> {
+        if raw.inf {
+            FieldPoint::new_inf(&raw.a, &raw.b)
+        } else {
+            FieldPoint::new(&raw.x, &raw.y, &raw.a, &raw.b)
+        }
+    }
+}
+
 
 impl FieldPoint {
-    fn new(x: &FieldElement, y: &FieldElement, a: &FieldElement, b: &FieldElement) -> Result<FieldPoint, String> {
+    pub fn new(x: &FieldElement, y: &FieldElement, a: &FieldElement, b: &FieldElement) -> Result<FieldPoint> {
+        if x.prime != a.prime || y.prime != a.prime || b.prime != a.prime {
+            return Err(Error::new(ErrorKind::FieldMismatch,
+                format!("x: {}, y: {}, a: {}, b: {}", x, y, a, b)));
+        }
 
         if y.pow(2) != x.pow(3)  + &(a * x) + b {
-            Err(format!("{}, {} is not on curve (a: {}, b: {})", x, y, a, b))
+            Err(Error::new(ErrorKind::NotOnCurve, format!("{}, {} (a: {}, b: {})", x, y, a, b)))
         } else {
             Ok(FieldPoint {
                 x: x.clone(), 
@@ -29,7 +96,10 @@ impl FieldPoint {
     }
 
 
-    fn new_inf(a: &FieldElement, b: &FieldElement) -> Result<FieldPoint, String> {
+    pub fn new_inf(a: &FieldElement, b: &FieldElement) -> Result<FieldPoint> {
+        if a.prime != b.prime {
+            return Err(Error::new(ErrorKind::FieldMismatch, format!("a: {}, b: {}", a, b)));
+        }
         Ok(FieldPoint {
             x: FieldElement::new(0,1).unwrap(),
             y: FieldElement::new(0,1).unwrap(),
@@ -40,13 +110,23 @@ impl FieldPoint {
     }
 }
 
+impl fmt::Display for FieldPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.inf {
+            write!(f, "Point(infinity)")
+        } else {
+            write!(f, "Point({},{})_{}_{} FieldElement({})", self.x.num, self.y.num, self.a.num, self.b.num, self.a.prime)
+        }
+    }
+}
+
 impl Add<&FieldPoint> for FieldPoint {
     type Output = FieldPoint;
     fn add(self, other: &FieldPoint) -> FieldPoint {
         if self.a != other.a || self.b != other.b {
-            panic!(format!("cannot add 2 field points not on the same curve \
+            panic!("cannot add 2 field points not on the same curve \
 point 1: (a: {:?} b: {:?})\n\
-point2: ({:?}. {:?})", self.a, self.b, other.a, other.b));
+point2: ({:?}. {:?})", self.a, self.b, other.a, other.b);
         } 
 
         if self.inf {
@@ -54,23 +134,25 @@ point2: ({:?}. {:?})", self.a, self.b, other.a, other.b));
         }
 
         if other.inf {
-            return self.clone();
+            return self;
         }
 
         if  self.x != other.x {
+            trace::point_op(PointOp::Add, || format!("{} + {}", self, other));
             let slope = &(&other.y - &self.y).div_field(&(&other.x - &self.x));
             let x3 = &(slope.pow(2) - &self.x) - &other.x;
             let y3 = &(slope * &(self.x - &x3)) - &self.y;
-            return FieldPoint::new(&x3, &y3, &self.a, &self.b).unwrap();
-        } else if self.y.num == BigInt::from(0) { 
-            return FieldPoint::new_inf(&self.a, &self.b).unwrap();
+            FieldPoint::new(&x3, &y3, &self.a, &self.b).unwrap()
+        } else if self.y != other.y || self.y.num == BigInt::from(0) {
+            // P + (-P), including the vertical tangent at y = 0
+            FieldPoint::new_inf(&self.a, &self.b).unwrap()
         } else {
-        // Point 1 = Point 2
-            println!("same point: {:?}", self);
+            // Point 1 = Point 2
+            trace::point_op(PointOp::Double, || format!("2 * {}", self));
             let slope = &(&self.x.pow(2) * &BigInt::from(3) + &self.a).div_field(&(&self.y * &BigInt::from(2)));
             let x3 = &slope.pow(2) - &(&self.x * &BigInt::from(2));
             let y3 = &(slope * &(&self.x - &x3)) - &self.y;
-            return FieldPoint::new(&x3, &y3, &self.a, &self.b).unwrap();
+            FieldPoint::new(&x3, &y3, &self.a, &self.b).unwrap()
         }
     }
 }
@@ -83,7 +165,7 @@ fn point_new() {
     let b = FieldElement::new(4,7).unwrap();
     let point = FieldPoint::new(&x, &y, &a, &b);
     println!("{:?}", point);
-    assert!(point.is_err());
+    assert_eq!(point.unwrap_err().kind(), ErrorKind::NotOnCurve);
 
     let x = FieldElement::new(192, 223).unwrap();
     let y = FieldElement::new(105,223).unwrap();
@@ -107,3 +189,54 @@ fn point_add_point_to_inf() {
     let point3 = point.clone() + &point2; 
     assert_eq!(point, point3);
 }
+
+#[test]
+fn point_add_inverse_is_inf() {
+    let a = FieldElement::new(0, 223).unwrap();
+    let b = FieldElement::new(7, 223).unwrap();
+    let p = FieldPoint::new(&FieldElement::new(192, 223).unwrap(), &FieldElement::new(105, 223).unwrap(), &a, &b).unwrap();
+    let neg_p = FieldPoint::new(&FieldElement::new(192, 223).unwrap(), &FieldElement::new(223 - 105, 223).unwrap(), &a, &b).unwrap();
+    assert!((p + &neg_p).inf);
+}
+
+#[test]
+fn point_new_field_mismatch() {
+    let x = FieldElement::new(192, 223).unwrap();
+    let y = FieldElement::new(105, 227).unwrap();
+    let a = FieldElement::new(0, 223).unwrap();
+    let b = FieldElement::new(7, 223).unwrap();
+    assert_eq!(FieldPoint::new(&x, &y, &a, &b).unwrap_err().kind(), ErrorKind::FieldMismatch);
+    assert_eq!(FieldPoint::new_inf(&a, &y).unwrap_err().kind(), ErrorKind::FieldMismatch);
+}
+
+#[test]
+fn point_hash_and_ord() {
+    use std::collections::{BTreeSet, HashSet};
+
+    let a = FieldElement::new(0, 223).unwrap();
+    let b = FieldElement::new(7, 223).unwrap();
+    let p1 = FieldPoint::new(&FieldElement::new(192, 223).unwrap(), &FieldElement::new(105, 223).unwrap(), &a, &b).unwrap();
+    let p2 = FieldPoint::new(&FieldElement::new(17, 223).unwrap(), &FieldElement::new(56, 223).unwrap(), &a, &b).unwrap();
+
+    let set: HashSet<FieldPoint> = vec![p1.clone(), p2.clone(), p1.clone()].into_iter().collect();
+    assert_eq!(set.len(), 2);
+
+    let ordered: Vec<FieldPoint> = vec![p1.clone(), p2.clone()].into_iter().collect::<BTreeSet<_>>().into_iter().collect();
+    assert_eq!(ordered, vec![p2, p1]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn point_serde_round_trip() {
+    let g = S256Point::generator();
+    let json = serde_json::to_string(&g).unwrap();
+    assert_eq!(serde_json::from_str::<S256Point>(&json).unwrap(), g);
+
+    // Serializing does not validate, deserializing must
+    let off_curve = FieldPoint { y: g.x().clone(), ..g.as_field_point().clone() };
+    let json = serde_json::to_string(&off_curve).unwrap();
+    assert!(serde_json::from_str::<FieldPoint>(&json).is_err());
+
+    let bad_element = r#"{"num":[1,[9]],"prime":[1,[7]]}"#;
+    assert!(serde_json::from_str::<FieldElement>(bad_element).is_err());
+}
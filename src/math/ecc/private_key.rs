@@ -0,0 +1,125 @@
+use super::s256::{s256_order, S256Point};
+use super::secret::SecretScalar;
+use super::xonly::XOnlyPoint;
+use crate::encoding::base58::{decode_base58_checksum, encode_base58_checksum};
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::{registered_networks, NetworkParams};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use std::fmt;
+
+/// A secp256k1 private key together with its public point.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PrivateKey {
+    secret: SecretScalar,
+    point: S256Point,
+}
+
+impl PrivateKey {
+    pub fn new(secret: &BigInt) -> Result<PrivateKey> {
+        Ok(PrivateKey::from_secret(SecretScalar::new(secret)?))
+    }
+
+    pub fn from_secret(secret: SecretScalar) -> PrivateKey {
+        let point = secret.public_point();
+        PrivateKey { secret, point }
+    }
+
+    pub fn secret(&self) -> &SecretScalar {
+        &self.secret
+    }
+
+    pub fn public_key(&self) -> &S256Point {
+        &self.point
+    }
+
+    /// The BIP341 tweaked secret whose public key is
+    /// `XOnlyPoint::tweak_add` of this key's x-only public key.
+    pub fn tap_tweak(&self, merkle_root: Option<&[u8; 32]>) -> Result<PrivateKey> {
+        let n = s256_order();
+        let internal = XOnlyPoint::from_point(&self.point);
+        let t = internal.tap_tweak_hash(merkle_root)?;
+        let secret = if self.point.has_even_y() {
+            self.secret.to_bigint()
+        } else {
+            n - self.secret.to_bigint()
+        };
+        PrivateKey::new(&(secret + t).mod_floor(n))
+    }
+
+    /// Wallet import format: base58check of the network's prefix, the
+    /// secret and, if the public key is to be compressed, 0x01.
+    pub fn wif(&self, compressed: bool, network: &NetworkParams) -> String {
+        let mut payload = vec![network.wif_prefix];
+        payload.extend_from_slice(self.secret.as_bytes());
+        if compressed {
+            payload.push(1);
+        }
+        encode_base58_checksum(&payload)
+    }
+
+    /// Parses a WIF key, returning whether it is compressed and the first
+    /// registered network with its prefix: mainnet, or testnet3 for the
+    /// test networks.
+    pub fn parse_wif(s: &str) -> Result<(PrivateKey, bool, NetworkParams)> {
+        let payload = decode_base58_checksum(s)?;
+        let (compressed, secret) = match payload.len() {
+            33 => (false, &payload[1..]),
+            34 if payload[33] == 1 => (true, &payload[1..33]),
+            len => return Err(Error::new(ErrorKind::InvalidEncoding, format!("WIF key of {} bytes", len))),
+        };
+        let network = registered_networks().into_iter().find(|network| network.wif_prefix == payload[0]);
+        let network = network.ok_or_else(|| Error::new(ErrorKind::UnknownNetwork, format!("WIF prefix {:02x}", payload[0])))?;
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(secret);
+        Ok((PrivateKey::from_secret(SecretScalar::from_bytes(bytes)?), compressed, network))
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PrivateKey")
+            .field("secret", &self.secret)
+            .field("point", &self.point)
+            .finish()
+    }
+}
+
+#[test]
+fn private_key_public_point() {
+    let key = PrivateKey::new(&BigInt::from(7)).unwrap();
+    assert_eq!(key.public_key(), &(&S256Point::generator() * &BigInt::from(7)));
+    assert!(format!("{:?}", key).contains("SecretScalar(<redacted>)"));
+    assert!(PrivateKey::new(&BigInt::from(0)).is_err());
+}
+
+#[test]
+fn wif_round_trip() {
+    // Chapter 4's exercises
+    let cases = [
+        (BigInt::from(5003), true, NetworkParams::testnet3(), "cMahea7zqjxrtgAbB7LSGbcQUr1uX1ojuat9jZodMN8rFTv2sfUK"),
+        (BigInt::from(2021).pow(5), false, NetworkParams::testnet3(), "91avARGdfge8E4tZfYLoxeJ5sGBdNJQH4kvjpWAxgzczjbCwxic"),
+        (BigInt::from(0x54321deadbeefu64), true, NetworkParams::mainnet(), "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgiuQJv1h8Ytr2S53a"),
+    ];
+    for (secret, compressed, network, wif) in cases.iter() {
+        let key = PrivateKey::new(secret).unwrap();
+        assert_eq!(key.wif(*compressed, network), *wif);
+        assert_eq!(PrivateKey::parse_wif(wif).unwrap(), (key, *compressed, network.clone()));
+    }
+    // A base58check string of the wrong length, and an address
+    assert_eq!(PrivateKey::parse_wif("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn").unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    let unknown = encode_base58_checksum(&[&[0x42][..], &[1; 32]].concat());
+    assert_eq!(PrivateKey::parse_wif(&unknown).unwrap_err().kind(), ErrorKind::UnknownNetwork);
+}
+
+#[test]
+fn private_key_tap_tweak_matches_public_tweak() {
+    // 6 * G has an odd y, so the secret has to be negated before tweaking
+    for secret in [5, 6].iter() {
+        let key = PrivateKey::new(&BigInt::from(*secret)).unwrap();
+        let root = [7u8; 32];
+        let tweaked = key.tap_tweak(Some(&root)).unwrap();
+        let (output, _) = XOnlyPoint::from_point(key.public_key()).tweak_add(Some(&root)).unwrap();
+        assert_eq!(XOnlyPoint::from_point(tweaked.public_key()), output);
+    }
+}
@@ -0,0 +1,70 @@
+//! RFC6979 deterministic nonce generation with HMAC-SHA256.
+
+use super::s256::{int_to_bytes32, s256_order};
+use super::secret::SecretScalar;
+use hmac::{Hmac, Mac};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The nonce for signing `z` with `secret`. The intermediate K and V
+/// values are wiped before returning.
+pub(crate) fn deterministic_k(secret: &SecretScalar, z: &BigInt) -> SecretScalar {
+    let n = s256_order();
+    let mut k = [0u8; 32];
+    let mut v = [1u8; 32];
+    // bits2octets: z mod n
+    let z_bytes = int_to_bytes32(&z.mod_floor(n));
+    let secret_bytes = secret.as_bytes();
+
+    k = hmac(&k, &[&v, &[0x00], secret_bytes, &z_bytes]);
+    v = hmac(&k, &[&v]);
+    k = hmac(&k, &[&v, &[0x01], secret_bytes, &z_bytes]);
+    v = hmac(&k, &[&v]);
+    let nonce = loop {
+        v = hmac(&k, &[&v]);
+        if let Ok(candidate) = SecretScalar::from_bytes(v) {
+            break candidate;
+        }
+        k = hmac(&k, &[&v, &[0x00]]);
+        v = hmac(&k, &[&v]);
+    };
+    k.zeroize();
+    v.zeroize();
+    nonce
+}
+
+#[test]
+fn deterministic_k_satoshi_nakamoto() {
+    // Private key 1 signing sha256("Satoshi Nakamoto")
+    let secret = SecretScalar::new(&BigInt::from(1)).unwrap();
+    let z = super::s256::bytes_to_int(&crate::hash::sha256(b"Satoshi Nakamoto"));
+    let k = deterministic_k(&secret, &z);
+    assert_eq!(k.to_bigint(), super::s256::hex_int(b"8F8A276C19F4149656B280621E358CCE24F5F52542772691EE69063B74F15D15"));
+
+    let key = super::private_key::PrivateKey::from_secret(secret);
+    assert_eq!(key.sign_with_nonce(&z, &k), key.sign_recoverable(&z));
+}
+
+#[test]
+fn deterministic_k_reduces_z_mod_n() {
+    let secret = SecretScalar::new(&BigInt::from(1)).unwrap();
+    let n = s256_order();
+    let k = |z: &BigInt| deterministic_k(&secret, z).to_bigint();
+    assert_eq!(k(n), k(&BigInt::from(0)));
+    assert_eq!(k(&(n + 7)), k(&BigInt::from(7)));
+    // Wider than 32 bytes
+    let wide = (BigInt::from(1) << 256) + 7;
+    assert_eq!(k(&wide), k(&(&wide % n)));
+}
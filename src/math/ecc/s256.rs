@@ -0,0 +1,295 @@
+use super::FieldPoint;
+use super::scalar_mul::{self, BaseKind, ScalarKind, ScalarMulContext, ScalarMulStrategy};
+use super::xonly::sqrt;
+use crate::encoding::base58::encode_base58_checksum;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash160;
+use crate::math::FieldElement;
+use crate::params::Network;
+use num_bigint::{BigInt, Sign};
+use num_integer::Integer;
+use std::ops::{Add, Mul};
+use std::sync::OnceLock;
+
+// secp256k1 curve: y^2 = x^3 + 7 over F_p
+const S256_P: &[u8] = b"FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F";
+const S256_N: &[u8] = b"FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141";
+const S256_GX: &[u8] = b"79BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798";
+const S256_GY: &[u8] = b"483ADA7726A3C4655DA4FBFC0E1108A8FD17B448A68554199C47D08FFB10D4B8";
+
+static PRIME: OnceLock<BigInt> = OnceLock::new();
+static ORDER: OnceLock<BigInt> = OnceLock::new();
+static GENERATOR: OnceLock<S256Point> = OnceLock::new();
+
+pub(crate) fn hex_int(hex: &[u8]) -> BigInt {
+    BigInt::parse_bytes(hex, 16).unwrap()
+}
+
+/// The prime of the secp256k1 base field, 2^256 - 2^32 - 977.
+pub fn s256_prime() -> &'static BigInt {
+    PRIME.get_or_init(|| hex_int(S256_P))
+}
+
+/// The order of the secp256k1 generator point.
+pub fn s256_order() -> &'static BigInt {
+    ORDER.get_or_init(|| hex_int(S256_N))
+}
+
+/// Builds a field element of the secp256k1 base field, reducing `num` mod p.
+pub fn s256_field<T: Into<BigInt>>(num: T) -> FieldElement {
+    let prime = s256_prime();
+    FieldElement::new(num.into().mod_floor(prime), prime.clone()).unwrap()
+}
+
+/// Big-endian 32 byte encoding of a non-negative integer below 2^256.
+pub fn int_to_bytes32(num: &BigInt) -> [u8; 32] {
+    let (_, be) = num.to_bytes_be();
+    let mut bytes = [0u8; 32];
+    bytes[32 - be.len()..].copy_from_slice(&be);
+    bytes
+}
+
+pub fn bytes_to_int(bytes: &[u8]) -> BigInt {
+    BigInt::from_bytes_be(Sign::Plus, bytes)
+}
+
+/// A point on secp256k1.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "FieldPoint", into = "FieldPoint"))]
+pub struct S256Point {
+    point: FieldPoint,
+}
+
+impl From<S256Point> for FieldPoint {
+    fn from(point: S256Point) -> FieldPoint {
+        point.point
+    }
+}
+
+impl std::convert::TryFrom<FieldPoint> for S256Point {
+    type Error = Error;
+    fn try_from(point: FieldPoint) -> Result<S256Point> {
+        S256Point::from_field_point(point)
+    }
+}
+
+impl S256Point {
+    pub fn new<T: Into<BigInt>>(x: T, y: T) -> Result<S256Point> {
+        let point = FieldPoint::new(&s256_field(x), &s256_field(y), &s256_field(0), &s256_field(7))?;
+        Ok(S256Point { point })
+    }
+
+    pub fn infinity() -> S256Point {
+        S256Point {
+            point: FieldPoint::new_inf(&s256_field(0), &s256_field(7)).unwrap(),
+        }
+    }
+
+    pub fn generator() -> S256Point {
+        GENERATOR
+            .get_or_init(|| S256Point::new(hex_int(S256_GX), hex_int(S256_GY)).unwrap())
+            .clone()
+    }
+
+    /// Wraps a point that is already known to lie on secp256k1.
+    pub fn from_field_point(point: FieldPoint) -> Result<S256Point> {
+        if point.a != s256_field(0) || point.b != s256_field(7) {
+            return Err(Error::new(ErrorKind::NotOnCurve, format!("secp256k1 (a: {}, b: {})", point.a, point.b)));
+        }
+        Ok(S256Point { point })
+    }
+
+    pub fn x(&self) -> &FieldElement {
+        &self.point.x
+    }
+
+    pub fn y(&self) -> &FieldElement {
+        &self.point.y
+    }
+
+    pub fn is_infinity(&self) -> bool {
+        self.point.inf
+    }
+
+    pub fn as_field_point(&self) -> &FieldPoint {
+        &self.point
+    }
+
+    pub fn has_even_y(&self) -> bool {
+        !self.is_infinity() && self.point.y.num.is_even()
+    }
+
+    /// SEC serialization: 0x04 || x || y, or 0x02/0x03 || x when compressed.
+    pub fn sec(&self, compressed: bool) -> Vec<u8> {
+        let x = int_to_bytes32(&self.point.x.num);
+        if compressed {
+            let prefix = if self.has_even_y() { 0x02 } else { 0x03 };
+            let mut result = vec![prefix];
+            result.extend_from_slice(&x);
+            result
+        } else {
+            let mut result = vec![0x04];
+            result.extend_from_slice(&x);
+            result.extend_from_slice(&int_to_bytes32(&self.point.y.num));
+            result
+        }
+    }
+
+    pub fn parse_sec(sec_bin: &[u8]) -> Result<S256Point> {
+        match (sec_bin.first(), sec_bin.len()) {
+            (Some(0x04), 65) => {
+                let x = bytes_to_int(&sec_bin[1..33]);
+                let y = bytes_to_int(&sec_bin[33..65]);
+                if x >= *s256_prime() || y >= *s256_prime() {
+                    return Err(Error::new(ErrorKind::InvalidEncoding, "SEC coordinate out of range"));
+                }
+                S256Point::new(x, y)
+            }
+            (Some(prefix @ 0x02), 33) | (Some(prefix @ 0x03), 33) => {
+                let x = bytes_to_int(&sec_bin[1..]);
+                if x >= *s256_prime() {
+                    return Err(Error::new(ErrorKind::InvalidEncoding, "SEC x coordinate out of range"));
+                }
+                let x = s256_field(x);
+                let y = sqrt(&(&x.pow(3) + &s256_field(7)))
+                    .ok_or_else(|| Error::new(ErrorKind::NotOnCurve, format!("no point with x: {:x}", x.num)))?;
+                let want_even = *prefix == 0x02;
+                let y = if y.num.is_even() == want_even { y } else { &s256_field(0) - &y };
+                S256Point::new(x.num, y.num)
+            }
+            _ => Err(Error::new(ErrorKind::InvalidEncoding, format!("invalid SEC encoding of {} bytes", sec_bin.len()))),
+        }
+    }
+
+    pub fn hash160(&self, compressed: bool) -> [u8; 20] {
+        hash160(&self.sec(compressed))
+    }
+
+    /// The P2PKH address of this public key.
+    pub fn address(&self, compressed: bool, network: Network) -> String {
+        let mut payload = vec![network.p2pkh_prefix()];
+        payload.extend_from_slice(&self.hash160(compressed));
+        encode_base58_checksum(&payload)
+    }
+
+    pub fn negate(&self) -> S256Point {
+        if self.is_infinity() {
+            return self.clone();
+        }
+        S256Point {
+            point: FieldPoint { y: &s256_field(0) - &self.point.y, ..self.point.clone() },
+        }
+    }
+
+    /// Multiplies a variable base point by a public scalar, picking the
+    /// strategy through `ScalarMulStrategy::select`.
+    pub fn scalar_mul(&self, coefficient: &BigInt) -> S256Point {
+        self.scalar_mul_ctx(coefficient, &ScalarMulContext {
+            base: BaseKind::Variable,
+            scalar: ScalarKind::Public,
+        })
+    }
+
+    pub fn scalar_mul_ctx(&self, coefficient: &BigInt, ctx: &ScalarMulContext) -> S256Point {
+        self.scalar_mul_with(coefficient, ScalarMulStrategy::select(ctx))
+    }
+
+    /// Multiplies with an explicit strategy, mostly useful for benchmarking.
+    pub fn scalar_mul_with(&self, coefficient: &BigInt, strategy: ScalarMulStrategy) -> S256Point {
+        // The group has order n, so reduce the coefficient first
+        let coef = coefficient.mod_floor(s256_order());
+        let point = if strategy == ScalarMulStrategy::PrecomputedTable && *self == S256Point::generator() {
+            scalar_mul::generator_table().mul(&coef)
+        } else {
+            scalar_mul::multiply(&self.point, &coef, strategy)
+        };
+        S256Point { point }
+    }
+
+    /// `sum(k_i * P_i)` computed in one pass, see `scalar_mul::multi_multiply`.
+    pub fn multi_scalar_mul(terms: &[(&S256Point, &BigInt)]) -> S256Point {
+        let terms: Vec<(&FieldPoint, &BigInt)> = terms.iter().map(|(p, k)| (&p.point, *k)).collect();
+        match scalar_mul::multi_multiply(&terms) {
+            Ok(point) => S256Point { point },
+            // The empty sum
+            Err(_) => S256Point::infinity(),
+        }
+    }
+}
+
+impl Add<&S256Point> for &S256Point {
+    type Output = S256Point;
+    fn add(self, other: &S256Point) -> S256Point {
+        S256Point { point: self.point.clone() + &other.point }
+    }
+}
+
+impl Mul<&BigInt> for &S256Point {
+    type Output = S256Point;
+    fn mul(self, coefficient: &BigInt) -> S256Point {
+        self.scalar_mul(coefficient)
+    }
+}
+
+#[test]
+fn s256_order_times_generator_is_infinity() {
+    let g = S256Point::generator();
+    assert!(g.scalar_mul(s256_order()).is_infinity());
+    assert_eq!(g.scalar_mul(&(s256_order() + 1)), g);
+}
+
+#[test]
+fn s256_known_multiples() {
+    let g = S256Point::generator();
+    let cases = [
+        (BigInt::from(7),
+         "5cbdf0646e5db4eaa398f365f2ea7a0e3d419b7e0330e39ce92bddedcac4f9bc",
+         "6aebca40ba255960a3178d6d861a54dba813d0b813fde7b5a5082628087264da"),
+        (BigInt::from(1485),
+         "c982196a7466fbbbb0e27a940b6af926c1a74d5ad07128c82824a11b5398afda",
+         "7a91f9eae64438afb9ce6448a1c133db2d8fb9254e4546b6f001637d50901f55"),
+        (BigInt::from(1) << 128,
+         "8f68b9d2f63b5f339239c1ad981f162ee88c5678723ea3351b7b444c9ec4c0da",
+         "662a9f2dba063986de1d90c2b6be215dbbea2cfe95510bfdf23cbf79501fff82"),
+    ];
+    for (k, x, y) in cases.iter() {
+        let expected = S256Point::new(hex_int(x.as_bytes()), hex_int(y.as_bytes())).unwrap();
+        assert_eq!(&g * k, expected);
+    }
+}
+
+#[test]
+fn s256_sec() {
+    let g = S256Point::generator();
+    let uncompressed = "04ffe558e388852f0120e46af2d1b370f85854a8eb0841811ece0e3e03d282d57c315dc72890a4f10a1481c031b03b351b0dc79901ca18a00cf009dbdb157a1d10";
+    let point = &g * &BigInt::from(5000);
+    assert_eq!(bytes_to_int(&point.sec(false)), hex_int(uncompressed.as_bytes()));
+    assert_eq!(S256Point::parse_sec(&point.sec(false)).unwrap(), point);
+
+    let compressed = "0357a4f368868a8a6d572991e484e664810ff14c05c0fa023275251151fe0e53d1";
+    let point = &g * &BigInt::from(5001);
+    assert_eq!(bytes_to_int(&point.sec(true)), hex_int(compressed.as_bytes()));
+    assert_eq!(S256Point::parse_sec(&point.sec(true)).unwrap(), point);
+
+    let even = &g * &BigInt::from(2);
+    assert_eq!(S256Point::parse_sec(&even.sec(true)).unwrap(), even);
+    assert!(S256Point::parse_sec(&[0x05; 33]).is_err());
+
+    // (1, y) is on the curve; 1 + p still fits in 32 bytes but is no field element
+    let point = S256Point::new(BigInt::from(1), hex_int(b"4218F20AE6C646B363DB68605822FB14264CA8D2587FDD6FBC750D587E76A7EE")).unwrap();
+    let mut sec = point.sec(false);
+    sec[1..33].copy_from_slice(&int_to_bytes32(&(s256_prime() + 1)));
+    assert_eq!(S256Point::parse_sec(&sec).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    let mut sec = point.sec(false);
+    sec[33..65].copy_from_slice(&[0xff; 32]);
+    assert_eq!(S256Point::parse_sec(&sec).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+}
+
+#[test]
+fn s256_address() {
+    let g = S256Point::generator();
+    assert_eq!((&g * &BigInt::from(5002)).address(false, Network::Testnet), "mmTPbXQFxboEtNRkwfh6K51jvdtHLxGeMA");
+    assert_eq!((&g * &BigInt::from(2020).pow(5)).address(true, Network::Testnet), "mopVkxp8UhXqRYbCYJsbeE1h1fiF64jcoH");
+    assert_eq!((&g * &hex_int(b"12345deadbeef")).address(true, Network::Mainnet), "1F1Pn2y6pDb68E5nYJJeba4TLg2U7B6KF1");
+}
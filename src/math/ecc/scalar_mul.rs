@@ -0,0 +1,521 @@
+//! Scalar multiplication strategies.
+//!
+//! Every strategy works in Jacobian coordinates internally so that the only
+//! field inversion happens when converting the result back to a `FieldPoint`.
+//! `ScalarMulStrategy::select` picks a strategy from the calling context, and
+//! the enum can also be passed explicitly to compare the strategies against
+//! each other (see `examples/scalar_mul_bench.rs`).
+
+use super::s256::{hex_int, s256_field, s256_order, S256Point};
+use super::trace::{self, PointOp};
+use super::FieldPoint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::math::FieldElement;
+use num_bigint::{BigInt, Sign};
+use num_integer::Integer;
+use std::convert::TryFrom;
+use std::sync::OnceLock;
+
+/// Window width used for wNAF and GLV recoding.
+const WNAF_WIDTH: usize = 5;
+
+/// Bits per window in precomputed tables.
+const TABLE_WINDOW: usize = 4;
+
+// GLV endomorphism for secp256k1: lambda * (x, y) = (beta * x, y)
+const GLV_BETA: &[u8] = b"7AE96A2B657C07106E64479EAC3434E99CF0497512F58995C1396C28719501EE";
+const GLV_LAMBDA: &[u8] = b"5363AD4CC05C30E0A5261C028812645A122E22EA20816678DF02967C1B23BD72";
+// Short basis of the lattice {(x, y) : x + y * lambda = 0 mod n}
+const GLV_A1: &[u8] = b"3086D221A7D46BCDE86C90E49284EB15";
+const GLV_MINUS_B1: &[u8] = b"E4437ED6010E88286F547FA90ABFE4C3";
+const GLV_A2: &[u8] = b"114CA50F7A8E2F3F657C1108D9D44CFD8";
+
+static GENERATOR_TABLE: OnceLock<PrecomputedTable> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ScalarMulStrategy {
+    /// Right-to-left double-and-add
+    Naive,
+    /// Width-w non-adjacent form with a table of odd multiples
+    Wnaf,
+    /// secp256k1 endomorphism split into two half-length wNAF chains
+    Glv,
+    /// Per-window table of multiples of a fixed base; additions only
+    PrecomputedTable,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BaseKind {
+    /// The same base point is used over and over (e.g. the generator)
+    Fixed,
+    /// The base point changes from call to call (e.g. a public key)
+    Variable,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ScalarKind {
+    /// Private keys, nonces and anything else that must not leak
+    Secret,
+    /// Signature verification and other public data
+    Public,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ScalarMulContext {
+    pub base: BaseKind,
+    pub scalar: ScalarKind,
+}
+
+impl ScalarMulStrategy {
+    pub const ALL: [ScalarMulStrategy; 4] = [
+        ScalarMulStrategy::Naive,
+        ScalarMulStrategy::Wnaf,
+        ScalarMulStrategy::Glv,
+        ScalarMulStrategy::PrecomputedTable,
+    ];
+
+    /// Picks a strategy for the given context.
+    ///
+    /// Fixed bases amortise their table over many calls. For variable bases a
+    /// public scalar gets GLV, while a secret scalar stays on plain
+    /// double-and-add, which skips the digit recoding of wNAF/GLV. None of
+    /// this is constant time since `BigInt` arithmetic is not.
+    pub fn select(ctx: &ScalarMulContext) -> ScalarMulStrategy {
+        match (ctx.base, ctx.scalar) {
+            (BaseKind::Fixed, _) => ScalarMulStrategy::PrecomputedTable,
+            (BaseKind::Variable, ScalarKind::Public) => ScalarMulStrategy::Glv,
+            (BaseKind::Variable, ScalarKind::Secret) => ScalarMulStrategy::Naive,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ScalarMulStrategy::Naive => "naive",
+            ScalarMulStrategy::Wnaf => "wnaf",
+            ScalarMulStrategy::Glv => "glv",
+            ScalarMulStrategy::PrecomputedTable => "precomputed-table",
+        }
+    }
+}
+
+/// Computes `coefficient * point` with the given strategy.
+///
+/// GLV only applies to secp256k1 points; other curves fall back to wNAF.
+/// A precomputed table built here is thrown away afterwards, keep a
+/// `PrecomputedTable` around to reuse it.
+pub fn multiply(point: &FieldPoint, coefficient: &BigInt, strategy: ScalarMulStrategy) -> FieldPoint {
+    let curve = Jacobian::curve_of(point);
+    let result = match strategy {
+        ScalarMulStrategy::Naive => naive(&curve, point, coefficient),
+        ScalarMulStrategy::Wnaf => wnaf(&curve, point, coefficient),
+        ScalarMulStrategy::Glv => {
+            if point.a == s256_field(0) && point.b == s256_field(7) {
+                glv(&curve, point, coefficient)
+            } else {
+                wnaf(&curve, point, coefficient)
+            }
+        }
+        ScalarMulStrategy::PrecomputedTable => {
+            let bits = coefficient.bits() as usize;
+            return PrecomputedTable::new(point, bits).mul(coefficient);
+        }
+    };
+    curve.to_affine(&result)
+}
+
+/// The shared table for the secp256k1 generator.
+pub fn generator_table() -> &'static PrecomputedTable {
+    GENERATOR_TABLE.get_or_init(|| {
+        PrecomputedTable::new(S256Point::generator().as_field_point(), s256_order().bits() as usize)
+    })
+}
+
+/// Computes `sum(k_i * P_i)` for points on one curve with a single doubling
+/// chain (Straus' method over wNAF digits). secp256k1 terms are split with
+/// GLV first, which halves the length of the chain.
+///
+/// The curve is taken from the terms, so an empty sum has no point at
+/// infinity to return and is an `OutOfRange` error.
+pub fn multi_multiply(terms: &[(&FieldPoint, &BigInt)]) -> Result<FieldPoint> {
+    let first = match terms.first() {
+        Some((point, _)) => *point,
+        None => return Err(Error::new(ErrorKind::OutOfRange, "multi_multiply needs at least one term")),
+    };
+    let curve = Jacobian::curve_of(first);
+    let is_s256 = first.a == s256_field(0) && first.b == s256_field(7);
+    let beta = hex_int(GLV_BETA);
+
+    let mut split: Vec<(FieldPoint, BigInt)> = Vec::with_capacity(terms.len() * 2);
+    for (point, coefficient) in terms {
+        if is_s256 && !point.inf {
+            let (k1, k2) = glv_decompose(&coefficient.mod_floor(s256_order()));
+            let endo = FieldPoint { x: &point.x * &beta, ..(*point).clone() };
+            split.push(((*point).clone(), k1));
+            split.push((endo, k2));
+        } else {
+            split.push(((*point).clone(), (*coefficient).clone()));
+        }
+    }
+
+    let mut tables = Vec::with_capacity(split.len());
+    let mut digits = Vec::with_capacity(split.len());
+    for (point, coefficient) in split.iter() {
+        let (base, coef) = curve.signed(point, coefficient);
+        tables.push(curve.odd_multiples(&base, WNAF_WIDTH));
+        digits.push(wnaf_digits(&coef, WNAF_WIDTH));
+    }
+    let len = digits.iter().map(|d| d.len()).max().unwrap_or(0);
+    let mut result = curve.infinity();
+    for i in (0..len).rev() {
+        result = curve.double(&result);
+        for (table, digits) in tables.iter().zip(digits.iter()) {
+            result = curve.add_digit(&result, table, digits.get(i).copied().unwrap_or(0));
+        }
+    }
+    Ok(curve.to_affine(&result))
+}
+
+/// Multiples `j * 16^i * base` for every 4-bit window `i` and digit `j`.
+pub struct PrecomputedTable {
+    base: FieldPoint,
+    curve: Curve,
+    windows: Vec<Vec<Jacobian>>,
+}
+
+impl PrecomputedTable {
+    pub fn new(base: &FieldPoint, bits: usize) -> PrecomputedTable {
+        let curve = Jacobian::curve_of(base);
+        let num_windows = bits.div_ceil(TABLE_WINDOW);
+        let mut windows = Vec::with_capacity(num_windows);
+        let mut window_base = curve.lift(base);
+        for _ in 0..num_windows {
+            let mut row = vec![window_base.clone()];
+            for j in 1..(1 << TABLE_WINDOW) - 1 {
+                let next = curve.add(&row[j - 1], &window_base);
+                row.push(next);
+            }
+            window_base = curve.add(&row[row.len() - 1], &window_base);
+            windows.push(row);
+        }
+        PrecomputedTable { base: base.clone(), curve, windows }
+    }
+
+    pub fn mul(&self, coefficient: &BigInt) -> FieldPoint {
+        if coefficient.bits() as usize > self.windows.len() * TABLE_WINDOW {
+            return multiply(&self.base, coefficient, ScalarMulStrategy::Wnaf);
+        }
+        let (sign, bytes) = coefficient.to_bytes_le();
+        let mut result = self.curve.infinity();
+        for (i, row) in self.windows.iter().enumerate() {
+            let byte = bytes.get(i / 2).copied().unwrap_or(0);
+            let digit = ((byte >> (4 * (i % 2))) & 0xf) as usize;
+            if digit != 0 {
+                result = self.curve.add(&result, &row[digit - 1]);
+            }
+        }
+        if sign == Sign::Minus {
+            result = self.curve.neg(&result);
+        }
+        self.curve.to_affine(&result)
+    }
+}
+
+fn naive(curve: &Curve, point: &FieldPoint, coefficient: &BigInt) -> Jacobian {
+    let (mut current, mut coef) = curve.signed(point, coefficient);
+    let mut result = curve.infinity();
+    while coef > BigInt::from(0) {
+        if coef.is_odd() {
+            result = curve.add(&result, &current);
+        }
+        current = curve.double(&current);
+        coef >>= 1;
+    }
+    result
+}
+
+fn wnaf(curve: &Curve, point: &FieldPoint, coefficient: &BigInt) -> Jacobian {
+    let (base, coef) = curve.signed(point, coefficient);
+    let digits = wnaf_digits(&coef, WNAF_WIDTH);
+    let table = curve.odd_multiples(&base, WNAF_WIDTH);
+    let mut result = curve.infinity();
+    for digit in digits.iter().rev() {
+        result = curve.double(&result);
+        result = curve.add_digit(&result, &table, *digit);
+    }
+    result
+}
+
+fn glv(curve: &Curve, point: &FieldPoint, coefficient: &BigInt) -> Jacobian {
+    let (k1, k2) = glv_decompose(&coefficient.mod_floor(s256_order()));
+    let endo = FieldPoint {
+        x: &point.x * &hex_int(GLV_BETA),
+        ..point.clone()
+    };
+    let (base1, k1) = curve.signed(point, &k1);
+    let (base2, k2) = curve.signed(&endo, &k2);
+    let digits1 = wnaf_digits(&k1, WNAF_WIDTH);
+    let digits2 = wnaf_digits(&k2, WNAF_WIDTH);
+    let table1 = curve.odd_multiples(&base1, WNAF_WIDTH);
+    let table2 = curve.odd_multiples(&base2, WNAF_WIDTH);
+
+    // Shamir's trick: walk both digit strings with a single doubling chain
+    let mut result = curve.infinity();
+    for i in (0..digits1.len().max(digits2.len())).rev() {
+        result = curve.double(&result);
+        result = curve.add_digit(&result, &table1, digits1.get(i).copied().unwrap_or(0));
+        result = curve.add_digit(&result, &table2, digits2.get(i).copied().unwrap_or(0));
+    }
+    result
+}
+
+/// Splits `k` into `(k1, k2)` of about 128 bits each with
+/// `k = k1 + k2 * lambda (mod n)`.
+pub fn glv_decompose(k: &BigInt) -> (BigInt, BigInt) {
+    let n = s256_order();
+    let a1 = hex_int(GLV_A1);
+    let minus_b1 = hex_int(GLV_MINUS_B1);
+    let a2 = hex_int(GLV_A2);
+    let b2 = a1.clone();
+
+    // c = round(x / n) for non-negative x
+    let two_n = n * 2u32;
+    let round_div = |x: BigInt| (x * 2u32 + n).div_floor(&two_n);
+    let c1 = round_div(&b2 * k);
+    let c2 = round_div(&minus_b1 * k);
+    let k1 = k - &c1 * &a1 - &c2 * &a2;
+    let k2 = &c1 * &minus_b1 - &c2 * &b2;
+    (k1, k2)
+}
+
+/// The GLV lambda, exposed for tests and benchmarks.
+pub fn glv_lambda() -> BigInt {
+    hex_int(GLV_LAMBDA)
+}
+
+/// Recodes a non-negative scalar into width-w NAF digits, least significant first.
+fn wnaf_digits(coefficient: &BigInt, width: usize) -> Vec<i64> {
+    let modulus = BigInt::from(1i64 << width);
+    let half = 1i64 << (width - 1);
+    let mut k = coefficient.clone();
+    let mut digits = Vec::new();
+    while k > BigInt::from(0) {
+        if k.is_odd() {
+            let mut digit = i64::try_from(k.mod_floor(&modulus)).unwrap();
+            if digit >= half {
+                digit -= 1i64 << width;
+            }
+            k -= digit;
+            digits.push(digit);
+        } else {
+            digits.push(0);
+        }
+        k >>= 1;
+    }
+    digits
+}
+
+fn square(fe: &FieldElement) -> FieldElement {
+    fe * fe
+}
+
+/// A point (X, Y, Z) standing for (X/Z^2, Y/Z^3); Z = 0 is the point at infinity.
+#[derive(Debug, Clone)]
+struct Jacobian {
+    x: FieldElement,
+    y: FieldElement,
+    z: FieldElement,
+}
+
+/// Curve parameters needed for Jacobian arithmetic.
+struct Curve {
+    a: FieldElement,
+    b: FieldElement,
+    prime: BigInt,
+}
+
+impl Jacobian {
+    fn curve_of(point: &FieldPoint) -> Curve {
+        Curve {
+            a: point.a.clone(),
+            b: point.b.clone(),
+            prime: point.a.prime.clone(),
+        }
+    }
+}
+
+impl Curve {
+    fn element(&self, num: i64) -> FieldElement {
+        FieldElement::new(BigInt::from(num).mod_floor(&self.prime), self.prime.clone()).unwrap()
+    }
+
+    fn infinity(&self) -> Jacobian {
+        Jacobian { x: self.element(1), y: self.element(1), z: self.element(0) }
+    }
+
+    fn is_infinity(&self, p: &Jacobian) -> bool {
+        p.z.num == BigInt::from(0)
+    }
+
+    fn lift(&self, point: &FieldPoint) -> Jacobian {
+        if point.inf {
+            return self.infinity();
+        }
+        Jacobian { x: point.x.clone(), y: point.y.clone(), z: self.element(1) }
+    }
+
+    fn neg(&self, p: &Jacobian) -> Jacobian {
+        Jacobian { y: &self.element(0) - &p.y, ..p.clone() }
+    }
+
+    /// Lifts the point, negating it when the coefficient is negative so the
+    /// returned coefficient is always non-negative.
+    fn signed(&self, point: &FieldPoint, coefficient: &BigInt) -> (Jacobian, BigInt) {
+        let lifted = self.lift(point);
+        if *coefficient < BigInt::from(0) {
+            (self.neg(&lifted), -coefficient)
+        } else {
+            (lifted, coefficient.clone())
+        }
+    }
+
+    fn double(&self, p: &Jacobian) -> Jacobian {
+        trace::point_op(PointOp::Double, || format!("2 * {:?}", p));
+        if self.is_infinity(p) || p.y.num == BigInt::from(0) {
+            return self.infinity();
+        }
+        let xx = square(&p.x);
+        let yy = square(&p.y);
+        let zz = square(&p.z);
+        let s = &(&p.x * &yy) * &self.element(4);
+        let m = &(&xx * &self.element(3)) + &(&self.a * &square(&zz));
+        let x3 = square(&m) - &(&s * &self.element(2));
+        let y3 = &(&m * &(&s - &x3)) - &(&square(&yy) * &self.element(8));
+        let z3 = &(&p.y * &p.z) * &self.element(2);
+        Jacobian { x: x3, y: y3, z: z3 }
+    }
+
+    fn add(&self, p: &Jacobian, q: &Jacobian) -> Jacobian {
+        trace::point_op(PointOp::Add, || format!("{:?} + {:?}", p, q));
+        if self.is_infinity(p) {
+            return q.clone();
+        }
+        if self.is_infinity(q) {
+            return p.clone();
+        }
+        let z1z1 = square(&p.z);
+        let z2z2 = square(&q.z);
+        let u1 = &p.x * &z2z2;
+        let u2 = &q.x * &z1z1;
+        let s1 = &(&p.y * &q.z) * &z2z2;
+        let s2 = &(&q.y * &p.z) * &z1z1;
+        if u1 == u2 {
+            return if s1 == s2 { self.double(p) } else { self.infinity() };
+        }
+        let h = &u2 - &u1;
+        let r = &s2 - &s1;
+        let h2 = square(&h);
+        let h3 = &h * &h2;
+        let u1h2 = &u1 * &h2;
+        let x3 = &(square(&r) - &h3) - &(&u1h2 * &self.element(2));
+        let y3 = &(&r * &(&u1h2 - &x3)) - &(&s1 * &h3);
+        let z3 = &(&h * &p.z) * &q.z;
+        Jacobian { x: x3, y: y3, z: z3 }
+    }
+
+    /// [P, 3P, 5P, ..., (2^(w-1) - 1)P]
+    fn odd_multiples(&self, p: &Jacobian, width: usize) -> Vec<Jacobian> {
+        let twice = self.double(p);
+        let mut table = vec![p.clone()];
+        for i in 1..(1 << (width - 2)) {
+            let next = self.add(&table[i - 1], &twice);
+            table.push(next);
+        }
+        table
+    }
+
+    fn add_digit(&self, acc: &Jacobian, odd_multiples: &[Jacobian], digit: i64) -> Jacobian {
+        if digit > 0 {
+            self.add(acc, &odd_multiples[(digit as usize - 1) / 2])
+        } else if digit < 0 {
+            self.add(acc, &self.neg(&odd_multiples[(-digit as usize - 1) / 2]))
+        } else {
+            acc.clone()
+        }
+    }
+
+    fn to_affine(&self, p: &Jacobian) -> FieldPoint {
+        if self.is_infinity(p) {
+            return FieldPoint::new_inf(&self.a, &self.b).unwrap();
+        }
+        let z_inv = p.z.pow(-1);
+        let z_inv2 = square(&z_inv);
+        let x = &p.x * &z_inv2;
+        let y = &(&p.y * &z_inv2) * &z_inv;
+        FieldPoint::new(&x, &y, &self.a, &self.b).unwrap()
+    }
+}
+
+#[test]
+fn scalar_mul_strategies_agree_on_s256() {
+    let g = S256Point::generator();
+    let k = hex_int(b"A3D1C0FFEE0123456789ABCDEF00112233445566778899AABBCCDDEEFF001122");
+    let expected = multiply(g.as_field_point(), &k, ScalarMulStrategy::Naive);
+    for strategy in ScalarMulStrategy::ALL.iter() {
+        assert_eq!(g.scalar_mul_with(&k, *strategy).as_field_point(), &expected, "{}", strategy.name());
+    }
+}
+
+#[test]
+fn scalar_mul_small_curve() {
+    // Book exercise: (47, 71) on y^2 = x^3 + 7 over F_223 has order 21
+    let a = FieldElement::new(0, 223).unwrap();
+    let b = FieldElement::new(7, 223).unwrap();
+    let p = FieldPoint::new(&FieldElement::new(47, 223).unwrap(), &FieldElement::new(71, 223).unwrap(), &a, &b).unwrap();
+    for strategy in ScalarMulStrategy::ALL.iter() {
+        assert!(multiply(&p, &BigInt::from(21), *strategy).inf);
+        let twice = multiply(&p, &BigInt::from(2), *strategy);
+        assert_eq!(twice.x, FieldElement::new(36, 223).unwrap());
+        assert_eq!(twice.y, FieldElement::new(111, 223).unwrap());
+        assert_eq!(multiply(&p, &BigInt::from(-1), *strategy).y, FieldElement::new(223 - 71, 223).unwrap());
+    }
+}
+
+#[test]
+fn glv_decomposition_recombines() {
+    let n = s256_order();
+    let k = n - 12345;
+    let (k1, k2) = glv_decompose(&k);
+    assert!(k1.bits() <= 129 && k2.bits() <= 129);
+    assert_eq!((k1 + k2 * glv_lambda()).mod_floor(n), k);
+
+    // lambda acts on points as x -> beta * x
+    let g = S256Point::generator();
+    let lambda_g = g.scalar_mul_with(&glv_lambda(), ScalarMulStrategy::Naive);
+    assert_eq!(lambda_g.x(), &(g.x() * &hex_int(GLV_BETA)));
+    assert_eq!(lambda_g.y(), g.y());
+    assert_eq!(super::s256_prime(), &g.x().prime);
+}
+
+#[test]
+fn select_strategy_from_context() {
+    let ctx = |base, scalar| ScalarMulContext { base, scalar };
+    assert_eq!(ScalarMulStrategy::select(&ctx(BaseKind::Fixed, ScalarKind::Secret)), ScalarMulStrategy::PrecomputedTable);
+    assert_eq!(ScalarMulStrategy::select(&ctx(BaseKind::Variable, ScalarKind::Public)), ScalarMulStrategy::Glv);
+    assert_eq!(ScalarMulStrategy::select(&ctx(BaseKind::Variable, ScalarKind::Secret)), ScalarMulStrategy::Naive);
+}
+
+#[test]
+fn multi_multiply_matches_sum_of_products() {
+    let g = S256Point::generator();
+    let p = g.scalar_mul(&BigInt::from(777));
+    let k1 = hex_int(b"A3D1C0FFEE0123456789ABCDEF00112233445566778899AABBCCDDEEFF001122");
+    let k2 = BigInt::from(-5);
+    let expected = &g.scalar_mul(&k1) + &p.scalar_mul(&k2);
+    assert_eq!(&multi_multiply(&[(g.as_field_point(), &k1), (p.as_field_point(), &k2)]).unwrap(), expected.as_field_point());
+}
+
+#[test]
+fn multi_multiply_empty_sum() {
+    assert_eq!(multi_multiply(&[]).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert!(S256Point::multi_scalar_mul(&[]).is_infinity());
+}
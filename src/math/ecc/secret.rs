@@ -0,0 +1,105 @@
+use super::s256::{s256_order, S256Point};
+use super::scalar_mul::{BaseKind, ScalarKind, ScalarMulContext};
+use crate::error::{Error, ErrorKind, Result};
+use num_bigint::{BigInt, Sign};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// A secret scalar in [1, n) that wipes itself when dropped.
+///
+/// The value is kept as 32 big-endian bytes because a `BigInt` can't be
+/// wiped reliably. Anything obtained from `to_bigint` is an ordinary copy
+/// and should be kept as short-lived as possible.
+#[derive(Clone)]
+pub struct SecretScalar {
+    bytes: [u8; 32],
+}
+
+impl SecretScalar {
+    pub fn new(secret: &BigInt) -> Result<SecretScalar> {
+        if secret.sign() != Sign::Plus || secret >= s256_order() {
+            return Err(Error::Regular(ErrorKind::OutOfRange));
+        }
+        let (_, mut be) = secret.to_bytes_be();
+        let mut bytes = [0u8; 32];
+        bytes[32 - be.len()..].copy_from_slice(&be);
+        be.zeroize();
+        Ok(SecretScalar { bytes })
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<SecretScalar> {
+        SecretScalar::new(&BigInt::from_bytes_be(Sign::Plus, &bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    pub fn to_bigint(&self) -> BigInt {
+        BigInt::from_bytes_be(Sign::Plus, &self.bytes)
+    }
+
+    /// secret * G, treating the scalar as secret material.
+    pub fn public_point(&self) -> S256Point {
+        S256Point::generator().scalar_mul_ctx(&self.to_bigint(), &ScalarMulContext {
+            base: BaseKind::Fixed,
+            scalar: ScalarKind::Secret,
+        })
+    }
+
+    fn wipe(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl Drop for SecretScalar {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl PartialEq for SecretScalar {
+    // Compares every byte so the running time doesn't depend on where the
+    // first difference is
+    fn eq(&self, other: &SecretScalar) -> bool {
+        self.bytes.iter().zip(other.bytes.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl Eq for SecretScalar {}
+
+impl fmt::Debug for SecretScalar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretScalar(<redacted>)")
+    }
+}
+
+#[test]
+fn secret_scalar_range() {
+    assert!(SecretScalar::new(&BigInt::from(0)).is_err());
+    assert!(SecretScalar::new(&BigInt::from(-5)).is_err());
+    assert!(SecretScalar::new(s256_order()).is_err());
+    assert!(SecretScalar::new(&(s256_order() - 1)).is_ok());
+
+    let secret = SecretScalar::new(&BigInt::from(0x1234)).unwrap();
+    assert_eq!(&secret.as_bytes()[30..], &[0x12, 0x34]);
+    assert_eq!(secret.to_bigint(), BigInt::from(0x1234));
+    assert_eq!(SecretScalar::from_bytes(*secret.as_bytes()).unwrap(), secret);
+}
+
+#[test]
+fn secret_scalar_redacts_and_matches_public_point() {
+    let secret = SecretScalar::new(&BigInt::from(7)).unwrap();
+    assert_eq!(format!("{:?}", secret), "SecretScalar(<redacted>)");
+    assert_eq!(secret.public_point(), S256Point::generator().scalar_mul(&BigInt::from(7)));
+}
+
+#[test]
+fn secret_scalar_wiped_on_drop() {
+    // Reading the bytes after `drop` would be undefined, so check the routine
+    // `Drop` runs on a live value instead
+    let mut secret = SecretScalar::new(&BigInt::from(0xabcdef)).unwrap();
+    assert_ne!(secret.bytes, [0u8; 32]);
+    secret.wipe();
+    assert_eq!(secret.bytes, [0u8; 32]);
+}
@@ -0,0 +1,325 @@
+use super::batch::batch_coefficients;
+use super::private_key::PrivateKey;
+use super::rfc6979::deterministic_k;
+use super::s256::{s256_order, s256_prime, int_to_bytes32, S256Point};
+use super::scalar_mul::{BaseKind, ScalarKind, ScalarMulContext};
+use super::secret::SecretScalar;
+use crate::error::{Error, ErrorKind, Result};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use std::fmt;
+
+/// An ECDSA signature.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Signature {
+    pub r: BigInt,
+    pub s: BigInt,
+}
+
+impl Signature {
+    pub fn new(r: BigInt, s: BigInt) -> Signature {
+        Signature { r, s }
+    }
+
+    /// BIP62 low-S: s is at most n / 2.
+    pub fn is_low_s(&self) -> bool {
+        self.s <= s256_order() / 2
+    }
+
+    /// The low-S form of this signature. (r, n - s) is just as valid as
+    /// (r, s), so anybody can flip s and change the transaction id.
+    pub fn normalize_s(&self) -> Signature {
+        if self.is_low_s() {
+            self.clone()
+        } else {
+            Signature::new(self.r.clone(), s256_order() - &self.s)
+        }
+    }
+
+    /// Verifies every `(pubkey, z, sig)`. A plain ECDSA signature only
+    /// carries the x coordinate of R, so there is no single equation to
+    /// batch and this checks them one by one. Use
+    /// `RecoverableSignature::batch_verify` when recovery ids are at hand.
+    pub fn batch_verify(items: &[(&S256Point, &BigInt, &Signature)]) -> bool {
+        items.iter().all(|(point, z, sig)| point.verify(z, sig))
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature({:x},{:x})", self.r, self.s)
+    }
+}
+
+/// An ECDSA signature plus the recovery id needed to get the public key
+/// back from it: bit 0 is the parity of R's y, bit 1 is set when R's x
+/// coordinate was at least n.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RecoverableSignature {
+    pub signature: Signature,
+    pub recovery_id: u8,
+}
+
+fn inverse_mod_n(num: &BigInt) -> BigInt {
+    let n = s256_order();
+    num.modpow(&(n - 2), n)
+}
+
+impl S256Point {
+    /// Checks that `sig` signs the hash `z` for this public key.
+    pub fn verify(&self, z: &BigInt, sig: &Signature) -> bool {
+        self.verify_with(z, sig, false)
+    }
+
+    /// Like `verify`, but with `require_low_s` high-S signatures are
+    /// rejected as non-standard.
+    pub fn verify_with(&self, z: &BigInt, sig: &Signature, require_low_s: bool) -> bool {
+        let n = s256_order();
+        let zero = BigInt::from(0);
+        if sig.r <= zero || sig.r >= *n || sig.s <= zero || sig.s >= *n {
+            return false;
+        }
+        if require_low_s && !sig.is_low_s() {
+            return false;
+        }
+        let s_inv = inverse_mod_n(&sig.s);
+        let u = (z * &s_inv).mod_floor(n);
+        let v = (&sig.r * &s_inv).mod_floor(n);
+        let fixed = ScalarMulContext { base: BaseKind::Fixed, scalar: ScalarKind::Public };
+        let total = &S256Point::generator().scalar_mul_ctx(&u, &fixed) + &self.scalar_mul(&v);
+        !total.is_infinity() && total.x().num.mod_floor(n) == sig.r
+    }
+}
+
+impl PrivateKey {
+    /// Signs the hash `z` with an RFC6979 nonce. The result is always low-S
+    /// (BIP62) so it is standard in transactions.
+    pub fn sign(&self, z: &BigInt) -> Signature {
+        self.sign_recoverable(z).signature
+    }
+
+    pub fn sign_recoverable(&self, z: &BigInt) -> RecoverableSignature {
+        self.sign_with_k(z, &deterministic_k(self.secret(), z))
+    }
+
+    /// Signs with the nonce `k` instead of the RFC6979 one. Only for
+    /// reproducing known signatures; reusing a nonce reveals the key.
+    /// Needs the `nonce-injection` feature, which refuses release builds.
+    #[cfg(any(test, feature = "nonce-injection"))]
+    pub fn sign_with_nonce(&self, z: &BigInt, k: &SecretScalar) -> RecoverableSignature {
+        self.sign_with_k(z, k)
+    }
+
+    fn sign_with_k(&self, z: &BigInt, k: &SecretScalar) -> RecoverableSignature {
+        let n = s256_order();
+        let big_r = k.public_point();
+        let r = big_r.x().num.mod_floor(n);
+        let mut recovery_id = if big_r.has_even_y() { 0 } else { 1 };
+        if big_r.x().num >= *n {
+            recovery_id |= 2;
+        }
+        let k_inv = inverse_mod_n(&k.to_bigint());
+        let mut s = ((z + &r * self.secret().to_bigint()) * k_inv).mod_floor(n);
+        if s > n / 2 {
+            // Negating s corresponds to signing with -R
+            s = n - s;
+            recovery_id ^= 1;
+        }
+        RecoverableSignature { signature: Signature::new(r, s), recovery_id }
+    }
+}
+
+impl RecoverableSignature {
+    /// The nonce point R, rebuilt from r and the recovery id.
+    fn nonce_point(&self) -> Result<S256Point> {
+        let n = s256_order();
+        let Signature { ref r, ref s } = self.signature;
+        let zero = BigInt::from(0);
+        if self.recovery_id > 3 || *r <= zero || r >= n || *s <= zero || s >= n {
+            return Err(Error::new(ErrorKind::InvalidSignature, "signature values out of range"));
+        }
+        let x = r + n * BigInt::from(self.recovery_id >> 1);
+        if x >= *s256_prime() {
+            return Err(Error::new(ErrorKind::InvalidSignature, "R x coordinate out of range"));
+        }
+        let mut sec = vec![0x02 | (self.recovery_id & 1)];
+        sec.extend_from_slice(&int_to_bytes32(&x));
+        S256Point::parse_sec(&sec)
+    }
+
+    /// Batch verifies `(pubkey, z, sig)` triples. Knowing R makes each
+    /// check the equation `R = u G + v P`, so a random linear combination
+    /// of them takes one multi-scalar multiplication. A wrong recovery id
+    /// fails the batch even if the bare signature is valid.
+    pub fn batch_verify(items: &[(&S256Point, &BigInt, &RecoverableSignature)]) -> bool {
+        let n = s256_order();
+        let mut inputs = Vec::new();
+        let mut nonce_points = Vec::with_capacity(items.len());
+        for (point, z, sig) in items {
+            match sig.nonce_point() {
+                Ok(big_r) => nonce_points.push(big_r),
+                Err(_) => return false,
+            }
+            inputs.extend_from_slice(&point.sec(true));
+            inputs.extend_from_slice(&int_to_bytes32(&z.mod_floor(n)));
+            inputs.extend_from_slice(&sig.serialize_compact(true));
+        }
+        let coefficients = batch_coefficients(&inputs, items.len());
+
+        let mut points = Vec::with_capacity(2 * items.len() + 1);
+        let mut scalars = Vec::with_capacity(2 * items.len() + 1);
+        let mut u_sum = BigInt::from(0);
+        for (((point, z, sig), a), big_r) in items.iter().zip(coefficients.iter()).zip(nonce_points) {
+            let s_inv = inverse_mod_n(&sig.signature.s);
+            u_sum += a * ((*z * &s_inv).mod_floor(n));
+            points.push(big_r);
+            scalars.push((-a).mod_floor(n));
+            points.push((*point).clone());
+            scalars.push((a * &sig.signature.r * s_inv).mod_floor(n));
+        }
+        points.push(S256Point::generator());
+        scalars.push(u_sum.mod_floor(n));
+
+        let terms: Vec<(&S256Point, &BigInt)> = points.iter().zip(scalars.iter()).collect();
+        S256Point::multi_scalar_mul(&terms).is_infinity()
+    }
+
+    /// Recovers the public key that produced this signature over `z`.
+    pub fn recover(&self, z: &BigInt) -> Result<S256Point> {
+        let n = s256_order();
+        let Signature { ref r, ref s } = self.signature;
+        let big_r = self.nonce_point()?;
+
+        // Q = r^-1 (sR - zG)
+        let r_inv = inverse_mod_n(r);
+        let u1 = (-z * &r_inv).mod_floor(n);
+        let u2 = (s * &r_inv).mod_floor(n);
+        let fixed = ScalarMulContext { base: BaseKind::Fixed, scalar: ScalarKind::Public };
+        let q = &S256Point::generator().scalar_mul_ctx(&u1, &fixed) + &big_r.scalar_mul(&u2);
+        if q.is_infinity() {
+            return Err(Error::new(ErrorKind::InvalidSignature, "recovered the point at infinity"));
+        }
+        Ok(q)
+    }
+
+    /// The 65 byte compact form: header byte 27 + recovery id (+ 4 when the
+    /// key is meant to be used compressed), then r and s.
+    pub fn serialize_compact(&self, compressed: bool) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes[0] = 27 + self.recovery_id + if compressed { 4 } else { 0 };
+        bytes[1..33].copy_from_slice(&int_to_bytes32(&self.signature.r));
+        bytes[33..].copy_from_slice(&int_to_bytes32(&self.signature.s));
+        bytes
+    }
+
+    /// Parses the compact form, also returning whether the key is compressed.
+    pub fn parse_compact(bytes: &[u8]) -> Result<(RecoverableSignature, bool)> {
+        if bytes.len() != 65 || bytes[0] < 27 || bytes[0] > 34 {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "invalid compact signature"));
+        }
+        let header = bytes[0] - 27;
+        let signature = Signature::new(
+            super::s256::bytes_to_int(&bytes[1..33]),
+            super::s256::bytes_to_int(&bytes[33..]),
+        );
+        Ok((RecoverableSignature { signature, recovery_id: header & 3 }, header & 4 != 0))
+    }
+}
+
+#[test]
+fn sign_and_verify() {
+    let key = PrivateKey::new(&BigInt::from(12345)).unwrap();
+    let z = super::s256::bytes_to_int(&crate::hash::hash256(b"Programming Bitcoin!"));
+    let sig = key.sign(&z);
+    assert!(sig.s <= s256_order() / 2);
+    assert!(key.public_key().verify(&z, &sig));
+    assert!(!key.public_key().verify(&(z + 1), &sig));
+}
+
+#[test]
+fn verify_book_signatures() {
+    // Chapter 3 exercise: verify these signatures
+    let point = S256Point::new(
+        super::s256::hex_int(b"887387e452b8eacc4acfde10d9aaf7f6d9a0f975aabb10d006e4da568744d06c"),
+        super::s256::hex_int(b"61de6d95231cd89026e286df3b6ae4a894a3378e393e93a0f45b666329a0ae34"),
+    ).unwrap();
+    let z = super::s256::hex_int(b"ec208baa0fc1c19f708a9ca96fdeff3ac3f230bb4a7ba4aede4942ad003c0f60");
+    let sig = Signature::new(
+        super::s256::hex_int(b"ac8d1c87e51d0d441be8b3dd5b05c8795b48875dffe00b7ffcfac23010d3a395"),
+        super::s256::hex_int(b"68342ceff8935ededd102dd876ffd6ba72d6a427a3edb13d26eb0781cb423c4"),
+    );
+    assert!(point.verify(&z, &sig));
+}
+
+#[test]
+fn sign_recoverable_round_trip() {
+    for secret in [1u64, 6, 12345, 0xdeadbeef].iter() {
+        let key = PrivateKey::new(&BigInt::from(*secret)).unwrap();
+        let z = BigInt::from(secret * 31 + 7);
+        let sig = key.sign_recoverable(&z);
+        assert_eq!(sig.signature, key.sign(&z));
+        assert_eq!(&sig.recover(&z).unwrap(), key.public_key());
+
+        let (parsed, compressed) = RecoverableSignature::parse_compact(&sig.serialize_compact(true)).unwrap();
+        assert!(compressed);
+        assert_eq!(parsed, sig);
+    }
+}
+
+#[test]
+fn sign_deterministic_vector() {
+    let key = PrivateKey::new(&BigInt::from(1)).unwrap();
+    let z = super::s256::bytes_to_int(&crate::hash::sha256(b"Satoshi Nakamoto"));
+    let sig = key.sign_recoverable(&z);
+    assert_eq!(sig.signature.r, super::s256::hex_int(b"934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d8"));
+    assert_eq!(sig.signature.s, super::s256::hex_int(b"2442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5"));
+    assert_eq!(sig.recovery_id, 1);
+}
+
+#[test]
+fn batch_verify_recoverable() {
+    let keys: Vec<PrivateKey> = (1..6u64).map(|i| PrivateKey::new(&BigInt::from(i * 1_000_003)).unwrap()).collect();
+    let zs: Vec<BigInt> = (0..5u64).map(|i| BigInt::from(i + 42) << 200).collect();
+    let sigs: Vec<RecoverableSignature> = keys.iter().zip(zs.iter()).map(|(k, z)| k.sign_recoverable(z)).collect();
+    let items: Vec<_> = keys.iter().zip(zs.iter()).zip(sigs.iter()).map(|((k, z), s)| (k.public_key(), z, s)).collect();
+    assert!(RecoverableSignature::batch_verify(&items));
+
+    let plain: Vec<_> = items.iter().map(|(p, z, s)| (*p, *z, &s.signature)).collect();
+    assert!(Signature::batch_verify(&plain));
+
+    let wrong_z = BigInt::from(7);
+    let mut bad = items.clone();
+    bad[3].1 = &wrong_z;
+    assert!(!RecoverableSignature::batch_verify(&bad));
+}
+
+#[test]
+fn low_s_normalization() {
+    let key = PrivateKey::new(&BigInt::from(98765)).unwrap();
+    let z = BigInt::from(424242);
+    let sig = key.sign(&z);
+    let high = Signature::new(sig.r.clone(), s256_order() - &sig.s);
+    assert!(!high.is_low_s());
+    assert!(key.public_key().verify(&z, &high));
+    assert!(!key.public_key().verify_with(&z, &high, true));
+    assert_eq!(high.normalize_s(), sig);
+    assert_eq!(sig.normalize_s(), sig);
+    assert!(key.public_key().verify_with(&z, &sig, true));
+}
+
+#[test]
+fn sign_with_injected_nonce() {
+    // Chapter 3 example, which signs with k = 1234567890
+    let hash = |data: &[u8]| super::s256::bytes_to_int(&crate::hash::hash256(data));
+    let key = PrivateKey::new(&hash(b"my secret")).unwrap();
+    let z = hash(b"my message");
+    let sig = key.sign_with_nonce(&z, &SecretScalar::new(&BigInt::from(1234567890)).unwrap());
+    let book = Signature::new(
+        super::s256::hex_int(b"2b698a0f0a4041b77e63488ad48c23e8e8838dd1fb7520408b121697b782ef22"),
+        super::s256::hex_int(b"bb14e602ef9e3f872e25fad328466b34e6734b7a0fcd58b1eb635447ffae8cb9"),
+    );
+    // The book doesn't normalize s
+    assert_eq!(sig.signature, book.normalize_s());
+    assert_eq!(sig.recover(&z).unwrap(), *key.public_key());
+}
@@ -0,0 +1,331 @@
+//! FROST threshold Schnorr signatures.
+//!
+//! A group secret is split into `n` Shamir shares so that any `t` share
+//! holders can produce a BIP340 signature for the group key, in two rounds:
+//! every signer publishes a pair of nonce commitments, then every signer
+//! returns a signature share which the coordinator adds up.
+//!
+//! Key generation uses a trusted dealer. Scalars mod n are plain
+//! `FieldElement`s, so the polynomial and Lagrange arithmetic reads the
+//! same as the chapter 1 field code.
+
+use super::s256::{bytes_to_int, s256_order, S256Point};
+use super::schnorr::{challenge, SchnorrSignature};
+use super::secret::SecretScalar;
+use super::xonly::XOnlyPoint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::tagged_hash;
+use crate::math::FieldElement;
+use num_bigint::BigInt;
+use num_integer::Integer;
+
+fn scalar<T: Into<BigInt>>(num: T) -> FieldElement {
+    let n = s256_order();
+    FieldElement::new(num.into().mod_floor(n), n.clone()).unwrap()
+}
+
+fn hash_to_scalar(tag: &str, data: &[u8]) -> FieldElement {
+    scalar(bytes_to_int(&tagged_hash(tag, data)))
+}
+
+fn to_secret(fe: &FieldElement) -> Result<SecretScalar> {
+    SecretScalar::new(&fe.num)
+}
+
+/// One participant's share of the group secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyShare {
+    index: u32,
+    secret: SecretScalar,
+    threshold: usize,
+    group_key: XOnlyPoint,
+}
+
+impl KeyShare {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn group_key(&self) -> &XOnlyPoint {
+        &self.group_key
+    }
+
+    /// The public verification share, used to check this signer's
+    /// signature shares.
+    pub fn public_share(&self) -> S256Point {
+        self.secret.public_point()
+    }
+
+    /// Feldman check of the share against the dealer's polynomial
+    /// commitments: `s_i G = sum i^j C_j`.
+    pub fn verify(&self, commitments: &[S256Point]) -> bool {
+        let x = scalar(self.index);
+        let mut expected = S256Point::infinity();
+        let mut power = scalar(1);
+        for commitment in commitments {
+            expected = &expected + &commitment.scalar_mul(&power.num);
+            power = &power * &x;
+        }
+        commitments.len() == self.threshold && expected == self.public_share()
+    }
+}
+
+/// What a trusted dealer hands out: a share per participant plus the
+/// commitments to the sharing polynomial's coefficients.
+#[derive(Debug, Clone)]
+pub struct DealerOutput {
+    pub shares: Vec<KeyShare>,
+    pub commitments: Vec<S256Point>,
+    pub group_key: XOnlyPoint,
+}
+
+/// Splits `secret` into `participants` shares, any `threshold` of which can
+/// sign. The polynomial's other coefficients are derived from `seed`,
+/// which must be fresh randomness.
+///
+/// If the secret's point has an odd y the whole polynomial is negated, so
+/// the shares belong to the even-y group key that BIP340 verifies against.
+pub fn generate_with_dealer(secret: &SecretScalar, threshold: usize, participants: u32, seed: &[u8; 32]) -> Result<DealerOutput> {
+    if threshold == 0 || threshold > participants as usize {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("{}-of-{} sharing", threshold, participants)));
+    }
+    let mut coefficients = vec![scalar(secret.to_bigint())];
+    for j in 1..threshold {
+        let mut data = seed.to_vec();
+        data.extend_from_slice(secret.as_bytes());
+        data.extend_from_slice(&(j as u32).to_be_bytes());
+        coefficients.push(hash_to_scalar("FROST/coefficient", &data));
+    }
+    if !secret.public_point().has_even_y() {
+        coefficients = coefficients.iter().map(|a| &scalar(0) - a).collect();
+    }
+
+    let commitments: Vec<S256Point> = coefficients.iter().map(|a| S256Point::generator().scalar_mul(&a.num)).collect();
+    let group_key = XOnlyPoint::from_point(&commitments[0]);
+    let mut shares = Vec::with_capacity(participants as usize);
+    for index in 1..=participants {
+        // Horner's rule for f(index)
+        let x = scalar(index);
+        let value = coefficients.iter().rev().fold(scalar(0), |acc, a| &(&acc * &x) + a);
+        shares.push(KeyShare { index, secret: to_secret(&value)?, threshold, group_key: group_key.clone() });
+    }
+    Ok(DealerOutput { shares, commitments, group_key })
+}
+
+/// A signer's secret nonce pair for one signing session. It is consumed
+/// by `sign` so that it can't be used twice.
+#[derive(Debug)]
+pub struct SigningNonces {
+    index: u32,
+    hiding: SecretScalar,
+    binding: SecretScalar,
+}
+
+impl SigningNonces {
+    /// Derives the nonces from the share and `aux_rand`, which must be
+    /// fresh for every session. The other signers' commitments aren't
+    /// known yet, so unlike BIP340 nothing else can be mixed in.
+    pub fn new(share: &KeyShare, aux_rand: &[u8; 32]) -> Result<SigningNonces> {
+        let derive = |kind: u8| {
+            let mut data = aux_rand.to_vec();
+            data.extend_from_slice(share.secret.as_bytes());
+            data.push(kind);
+            to_secret(&hash_to_scalar("FROST/nonce", &data))
+        };
+        Ok(SigningNonces { index: share.index, hiding: derive(0)?, binding: derive(1)? })
+    }
+
+    pub fn commitment(&self) -> NonceCommitment {
+        NonceCommitment {
+            index: self.index,
+            hiding: self.hiding.public_point(),
+            binding: self.binding.public_point(),
+        }
+    }
+}
+
+/// The round one message of a signer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonceCommitment {
+    pub index: u32,
+    pub hiding: S256Point,
+    pub binding: S256Point,
+}
+
+/// The message and the commitments of everybody taking part in a session.
+#[derive(Debug, Clone)]
+pub struct SigningPackage {
+    message: Vec<u8>,
+    commitments: Vec<NonceCommitment>,
+}
+
+impl SigningPackage {
+    pub fn new(message: &[u8], mut commitments: Vec<NonceCommitment>) -> Result<SigningPackage> {
+        commitments.sort_by_key(|c| c.index);
+        if commitments.is_empty() || commitments[0].index == 0 || commitments.windows(2).any(|w| w[0].index == w[1].index) {
+            return Err(Error::new(ErrorKind::OutOfRange, "signers need distinct, non-zero indices"));
+        }
+        Ok(SigningPackage { message: message.to_vec(), commitments })
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    pub fn commitments(&self) -> &[NonceCommitment] {
+        &self.commitments
+    }
+
+    fn commitment(&self, index: u32) -> Result<&NonceCommitment> {
+        self.commitments.iter().find(|c| c.index == index)
+            .ok_or_else(|| Error::new(ErrorKind::OutOfRange, format!("signer {} is not part of this session", index)))
+    }
+
+    /// rho_i, binding signer i's nonces to this message and signer set.
+    fn binding_factor(&self, group_key: &XOnlyPoint, index: u32) -> FieldElement {
+        let mut data = group_key.serialize().to_vec();
+        data.extend_from_slice(&tagged_hash("FROST/message", &self.message));
+        for c in self.commitments.iter() {
+            data.extend_from_slice(&c.index.to_be_bytes());
+            data.extend_from_slice(&c.hiding.sec(true));
+            data.extend_from_slice(&c.binding.sec(true));
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+        hash_to_scalar("FROST/binding", &data)
+    }
+
+    /// The group nonce point R and whether it had to be negated to get
+    /// an even y.
+    fn group_commitment(&self, group_key: &XOnlyPoint) -> Result<(S256Point, bool)> {
+        let mut big_r = S256Point::infinity();
+        for c in self.commitments.iter() {
+            let rho = self.binding_factor(group_key, c.index);
+            big_r = &big_r + &(&c.hiding + &c.binding.scalar_mul(&rho.num));
+        }
+        if big_r.is_infinity() {
+            return Err(Error::new(ErrorKind::InvalidSignature, "group commitment is the point at infinity"));
+        }
+        let negated = !big_r.has_even_y();
+        Ok((if negated { big_r.negate() } else { big_r }, negated))
+    }
+
+    /// Lagrange coefficient of signer `index` for interpolating at zero.
+    fn lagrange(&self, index: u32) -> FieldElement {
+        let x_i = scalar(index);
+        self.commitments.iter().filter(|c| c.index != index).fold(scalar(1), |acc, c| {
+            let x_j = scalar(c.index);
+            &acc * &x_j.div_field(&(&x_j - &x_i))
+        })
+    }
+}
+
+/// The round two message of a signer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureShare {
+    pub index: u32,
+    pub z: BigInt,
+}
+
+/// Produces this signer's share: `z_i = d_i + rho_i e_i + lambda_i c s_i`,
+/// with the nonces negated when R was.
+pub fn sign(share: &KeyShare, nonces: SigningNonces, package: &SigningPackage) -> Result<SignatureShare> {
+    if nonces.index != share.index || package.commitment(share.index)? != &nonces.commitment() {
+        return Err(Error::new(ErrorKind::InvalidSignature, "nonces do not match the commitment in the package"));
+    }
+    let (big_r, negated) = package.group_commitment(&share.group_key)?;
+    let c = scalar(challenge(&big_r.x().num, &share.group_key, &package.message));
+    let rho = package.binding_factor(&share.group_key, share.index);
+    let lambda = package.lagrange(share.index);
+
+    let mut nonce = &scalar(nonces.hiding.to_bigint()) + &(&rho * &scalar(nonces.binding.to_bigint()));
+    if negated {
+        nonce = &scalar(0) - &nonce;
+    }
+    let z = &nonce + &(&(&lambda * &c) * &scalar(share.secret.to_bigint()));
+    Ok(SignatureShare { index: share.index, z: z.num })
+}
+
+/// Checks a single signature share against the signer's public share, so
+/// a coordinator can tell who sent a bad one.
+pub fn verify_share(package: &SigningPackage, sig_share: &SignatureShare, public_share: &S256Point, group_key: &XOnlyPoint) -> bool {
+    let (commitment, (big_r, negated)) = match (package.commitment(sig_share.index), package.group_commitment(group_key)) {
+        (Ok(commitment), Ok(r)) => (commitment, r),
+        _ => return false,
+    };
+    let c = scalar(challenge(&big_r.x().num, group_key, &package.message));
+    let rho = package.binding_factor(group_key, sig_share.index);
+    let lambda = package.lagrange(sig_share.index);
+
+    let mut nonce_point = &commitment.hiding + &commitment.binding.scalar_mul(&rho.num);
+    if negated {
+        nonce_point = nonce_point.negate();
+    }
+    let expected = &nonce_point + &public_share.scalar_mul(&(&lambda * &c).num);
+    sig_share.z < *s256_order() && S256Point::generator().scalar_mul(&sig_share.z) == expected
+}
+
+/// Adds up the signature shares of every signer in the package into a
+/// BIP340 signature for the group key.
+pub fn aggregate(package: &SigningPackage, shares: &[SignatureShare], group_key: &XOnlyPoint) -> Result<SchnorrSignature> {
+    let mut indices: Vec<u32> = shares.iter().map(|s| s.index).collect();
+    indices.sort_unstable();
+    if indices.iter().ne(package.commitments.iter().map(|c| &c.index)) {
+        return Err(Error::new(ErrorKind::InvalidSignature, "need exactly one share from every signer in the package"));
+    }
+    let (big_r, _) = package.group_commitment(group_key)?;
+    let s = shares.iter().fold(scalar(0), |acc, share| &acc + &scalar(share.z.clone()));
+    let sig = SchnorrSignature { r: big_r.x().num.clone(), s: s.num };
+    if !sig.verify(group_key, &package.message) {
+        return Err(Error::new(ErrorKind::InvalidSignature, "aggregated signature does not verify"));
+    }
+    Ok(sig)
+}
+
+#[cfg(test)]
+fn run_session(shares: &[&KeyShare], msg: &[u8]) -> (SigningPackage, Vec<SignatureShare>) {
+    let nonces: Vec<SigningNonces> = shares.iter().map(|s| SigningNonces::new(s, &[s.index() as u8; 32]).unwrap()).collect();
+    let package = SigningPackage::new(msg, nonces.iter().map(|n| n.commitment()).collect()).unwrap();
+    let sig_shares = shares.iter().zip(nonces).map(|(s, n)| sign(s, n, &package).unwrap()).collect();
+    (package, sig_shares)
+}
+
+#[test]
+fn frost_two_of_three() {
+    // 7 has an odd y, so this also covers the negated polynomial
+    for secret in [6u64, 7].iter() {
+        let secret = SecretScalar::new(&BigInt::from(*secret)).unwrap();
+        let dealer = generate_with_dealer(&secret, 2, 3, &[9; 32]).unwrap();
+        assert_eq!(dealer.group_key, XOnlyPoint::from_point(&secret.public_point()));
+        assert!(dealer.shares.iter().all(|s| s.verify(&dealer.commitments)));
+
+        let msg = b"frost";
+        for pair in [(0, 1), (0, 2), (1, 2)].iter() {
+            let signers = [&dealer.shares[pair.0], &dealer.shares[pair.1]];
+            let (package, sig_shares) = run_session(&signers, msg);
+            for (signer, sig_share) in signers.iter().zip(sig_shares.iter()) {
+                assert!(verify_share(&package, sig_share, &signer.public_share(), &dealer.group_key));
+            }
+            let sig = aggregate(&package, &sig_shares, &dealer.group_key).unwrap();
+            assert!(sig.verify(&dealer.group_key, msg));
+        }
+    }
+}
+
+#[test]
+fn frost_rejects_bad_shares() {
+    let secret = SecretScalar::new(&BigInt::from(123456789)).unwrap();
+    let dealer = generate_with_dealer(&secret, 3, 5, &[1; 32]).unwrap();
+    let signers = [&dealer.shares[0], &dealer.shares[2], &dealer.shares[4]];
+    let (package, mut sig_shares) = run_session(&signers, b"msg");
+    assert!(aggregate(&package, &sig_shares[..2], &dealer.group_key).is_err());
+
+    sig_shares[1].z += 1;
+    assert!(!verify_share(&package, &sig_shares[1], &signers[1].public_share(), &dealer.group_key));
+    assert!(aggregate(&package, &sig_shares, &dealer.group_key).is_err());
+
+    assert!(generate_with_dealer(&secret, 4, 3, &[1; 32]).is_err());
+}
@@ -0,0 +1,79 @@
+//! Opt-in tracing of point additions and doublings.
+//!
+//! With the `trace` feature every add/double is reported through the `log`
+//! facade at trace level (target `prog_btc_book::ecc`), and `record` can
+//! capture the sequence of operations a computation performs, which is handy
+//! for stepping through double-and-add by hand. Without the feature all of
+//! this compiles away.
+
+#[cfg(feature = "trace")]
+use std::cell::RefCell;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PointOp {
+    Add,
+    Double,
+}
+
+#[cfg(feature = "trace")]
+thread_local! {
+    static RECORDING: RefCell<Option<Vec<PointOp>>> = const { RefCell::new(None) };
+}
+
+/// Reports a point operation. `detail` is only evaluated when trace logging is on.
+#[inline]
+pub(crate) fn point_op<F: FnOnce() -> String>(op: PointOp, detail: F) {
+    #[cfg(feature = "trace")]
+    {
+        if log::log_enabled!(target: "prog_btc_book::ecc", log::Level::Trace) {
+            log::trace!(target: "prog_btc_book::ecc", "{:?}: {}", op, detail());
+        }
+        RECORDING.with(|recording| {
+            if let Some(ops) = recording.borrow_mut().as_mut() {
+                ops.push(op);
+            }
+        });
+    }
+    #[cfg(not(feature = "trace"))]
+    {
+        let _ = (op, detail);
+    }
+}
+
+/// Runs `f` and returns the point operations it performed on this thread.
+#[cfg(feature = "trace")]
+pub fn record<T, F: FnOnce() -> T>(f: F) -> (T, Vec<PointOp>) {
+    let outer = RECORDING.with(|recording| recording.borrow_mut().replace(Vec::new()));
+    let result = f();
+    let ops = RECORDING.with(|recording| {
+        let mut recording = recording.borrow_mut();
+        let ops = recording.take().unwrap_or_default();
+        // Nested recordings also show up in the enclosing one
+        *recording = outer.map(|mut outer_ops| {
+            outer_ops.extend_from_slice(&ops);
+            outer_ops
+        });
+        ops
+    });
+    (result, ops)
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn record_naive_double_and_add() {
+    use super::scalar_mul::{multiply, ScalarMulStrategy};
+    use super::FieldPoint;
+    use crate::math::FieldElement;
+    use num_bigint::BigInt;
+
+    let a = FieldElement::new(0, 223).unwrap();
+    let b = FieldElement::new(7, 223).unwrap();
+    let p = FieldPoint::new(&FieldElement::new(47, 223).unwrap(), &FieldElement::new(71, 223).unwrap(), &a, &b).unwrap();
+
+    // 5 = 0b101: add, double, double, add, double
+    let (_, ops) = record(|| multiply(&p, &BigInt::from(5), ScalarMulStrategy::Naive));
+    assert_eq!(ops, vec![PointOp::Add, PointOp::Double, PointOp::Double, PointOp::Add, PointOp::Double]);
+
+    let (_, ops) = record(|| p.clone() + &p);
+    assert_eq!(ops, vec![PointOp::Double]);
+}
@@ -0,0 +1,167 @@
+use super::s256::{bytes_to_int, int_to_bytes32, s256_field, s256_order, s256_prime, S256Point};
+use super::scalar_mul::{BaseKind, ScalarKind, ScalarMulContext};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::tagged_hash;
+use crate::math::FieldElement;
+use num_bigint::BigInt;
+
+/// Parity of the y coordinate dropped when going to an x-only point.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Parity {
+    Even,
+    Odd,
+}
+
+impl Parity {
+    pub fn of(point: &S256Point) -> Parity {
+        if point.has_even_y() { Parity::Even } else { Parity::Odd }
+    }
+
+    /// 0 for even, 1 for odd, as in the control block's leaf version byte.
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Parity::Even => 0,
+            Parity::Odd => 1,
+        }
+    }
+}
+
+/// A BIP340 x-only public key: the point with the given x coordinate and an
+/// even y coordinate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct XOnlyPoint {
+    point: S256Point,
+}
+
+impl XOnlyPoint {
+    /// Finds the point with x coordinate `x` and even y, if there is one.
+    pub fn lift_x(x: &BigInt) -> Result<XOnlyPoint> {
+        if x >= s256_prime() || *x < BigInt::from(0) {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("x: {:x}", x)));
+        }
+        let x = s256_field(x.clone());
+        let y_squared = &x.pow(3) + &s256_field(7);
+        let y = sqrt(&y_squared)
+            .ok_or_else(|| Error::new(ErrorKind::NotOnCurve, format!("no point with x: {:x}", x.num)))?;
+        let point = S256Point::new(x.num, y.num)?;
+        Ok(XOnlyPoint::from_point(&point))
+    }
+
+    /// Drops the parity of `point`, negating it if its y coordinate is odd.
+    pub fn from_point(point: &S256Point) -> XOnlyPoint {
+        if point.has_even_y() {
+            XOnlyPoint { point: point.clone() }
+        } else {
+            XOnlyPoint { point: point.negate() }
+        }
+    }
+
+    /// Like `from_point`, but also returns the parity that was dropped.
+    pub fn from_point_with_parity(point: &S256Point) -> (XOnlyPoint, Parity) {
+        (XOnlyPoint::from_point(point), Parity::of(point))
+    }
+
+    /// The BIP341 tweak t = hash_TapTweak(x || merkle_root) as a scalar. A key
+    /// with no script tree commits to an empty merkle root.
+    pub fn tap_tweak_hash(&self, merkle_root: Option<&[u8; 32]>) -> Result<BigInt> {
+        let mut data = self.serialize().to_vec();
+        if let Some(root) = merkle_root {
+            data.extend_from_slice(root);
+        }
+        let t = bytes_to_int(&tagged_hash("TapTweak", &data));
+        if t >= *s256_order() {
+            return Err(Error::new(ErrorKind::OutOfRange, "taproot tweak exceeds the curve order"));
+        }
+        Ok(t)
+    }
+
+    /// Computes the taproot output key Q = P + tG and its parity.
+    pub fn tweak_add(&self, merkle_root: Option<&[u8; 32]>) -> Result<(XOnlyPoint, Parity)> {
+        let t = self.tap_tweak_hash(merkle_root)?;
+        let fixed = ScalarMulContext { base: BaseKind::Fixed, scalar: ScalarKind::Public };
+        let q = &self.point + &S256Point::generator().scalar_mul_ctx(&t, &fixed);
+        if q.is_infinity() {
+            return Err(Error::new(ErrorKind::OutOfRange, "tweaked key is the point at infinity"));
+        }
+        Ok(XOnlyPoint::from_point_with_parity(&q))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<XOnlyPoint> {
+        if bytes.len() != 32 {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("x-only key of {} bytes", bytes.len())));
+        }
+        XOnlyPoint::lift_x(&bytes_to_int(bytes))
+    }
+
+    pub fn serialize(&self) -> [u8; 32] {
+        int_to_bytes32(&self.point.x().num)
+    }
+
+    pub fn x(&self) -> &BigInt {
+        &self.point.x().num
+    }
+
+    pub fn to_point(&self) -> S256Point {
+        self.point.clone()
+    }
+
+    pub fn as_point(&self) -> &S256Point {
+        &self.point
+    }
+}
+
+/// Square root in the secp256k1 field. Since p = 3 mod 4 a root, when it
+/// exists, is a^((p + 1) / 4).
+pub(crate) fn sqrt(a: &FieldElement) -> Option<FieldElement> {
+    let root = a.pow((s256_prime() + 1) / 4);
+    if &root.pow(2) == a {
+        Some(root)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn xonly_lift_x() {
+    let g = S256Point::generator();
+    let xonly = XOnlyPoint::lift_x(&g.x().num).unwrap();
+    assert_eq!(xonly.as_point(), &g);
+
+    // 6 * G has an odd y, so lifting its x gives -6G
+    let six_g = &g * &BigInt::from(6);
+    assert!(!six_g.has_even_y());
+    assert_eq!(XOnlyPoint::from_point(&six_g).to_point(), six_g.negate());
+    assert_eq!(XOnlyPoint::parse(&XOnlyPoint::from_point(&six_g).serialize()).unwrap().x(), &six_g.x().num);
+
+    // x = 5 has no point on the curve
+    assert_eq!(XOnlyPoint::lift_x(&BigInt::from(5)).unwrap_err().kind(), ErrorKind::NotOnCurve);
+    assert_eq!(XOnlyPoint::lift_x(s256_prime()).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
+
+#[test]
+fn xonly_tweak_add_vectors() {
+    let hex32 = |hex: &str| {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    };
+    // BIP341 wallet vectors and the first BIP86 receive key
+    let vectors = [
+        ("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d", None,
+         "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"),
+        ("187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+         Some("5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"),
+         "147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3"),
+        ("cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115", None,
+         "a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c"),
+    ];
+    for (internal, root, tweaked) in vectors.iter() {
+        let internal = XOnlyPoint::parse(&hex32(internal)).unwrap();
+        let root = root.map(hex32);
+        let (output, parity) = internal.tweak_add(root.as_ref()).unwrap();
+        assert_eq!(output.serialize(), hex32(tweaked));
+        assert_eq!(parity, Parity::Odd);
+    }
+}
@@ -1,15 +1,34 @@
-use std::error;
 use std::fmt;
 use std::ops;
 use num_bigint::BigInt;
 use num_integer::Integer;
+use crate::error::{Error, ErrorKind, Result};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Elements order by `num` first and `prime` second.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawFieldElement"))]
 pub struct FieldElement {
     pub num: BigInt,
     pub prime: BigInt
 }
 
+// Deserialized elements go through FieldElement::new so the range check still applies
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawFieldElement {
+    num: BigInt,
+    prime: BigInt
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<RawFieldElement> for FieldElement {
+    type Error = Error;
+    fn try_from(raw: RawFieldElement) -> Result<FieldElement> {
+        FieldElement::new(raw.num, raw.prime)
+    }
+}
+
 impl FieldElement {
     pub fn pow<T: Into<BigInt>>(&self, exp: T) -> FieldElement {
         let mut e: BigInt = exp.into().clone();
@@ -19,7 +38,7 @@ impl FieldElement {
         // We can use fermat's little theorem here.
         // x^-n = x^-n * 1 =  x^-n * x^(p-1) = x^(p-n-1) 
         if e < BigInt::from(0) {
-            e += &self.prime - &1;
+            e += &self.prime - 1;
         }
         FieldElement::new(self.num.modpow(&e, &self.prime), self.prime.clone()).unwrap()
     }
@@ -55,44 +74,6 @@ impl From<(i64, i64)> for FieldElement {
     }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
-
-#[derive(Debug)]
-pub enum Error {
-    Regular(ErrorKind)
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Error::Regular(ref err) => write!(f, "regular error occured {:?}", err)
-        }
-    }
-}
-
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Error::Regular(ref err) => err.as_str()
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub enum ErrorKind {
-    OutOfRange
-}
-
-impl ErrorKind {
-    fn as_str(&self) -> &str {
-        match *self {
-            ErrorKind::OutOfRange  => 
-                "Parameters out of range"
-        }
-    }
-}
-
-
 impl FieldElement {
     pub fn new<T: Into<BigInt> + Clone>(num: T, prime: T) -> Result<FieldElement>  {
         let n : BigInt = num.clone().into();
@@ -112,7 +93,7 @@ impl fmt::Display for FieldElement {
     }
 }
 
-/// Math operations
+// Math operations
 
 // &T + U
 // see rust/src/libcore/ops.rs forward_ref_binop and add_impl macros for a better but advanced way
@@ -189,12 +170,12 @@ impl<'a> ops::Mul<&'a FieldElement> for FieldElement {
     }
 }
 
-impl<'a> ops::Mul<&'a BigInt> for &FieldElement {
+impl ops::Mul<&BigInt> for &FieldElement {
     type Output = FieldElement;
     fn mul(self, other: &BigInt) -> FieldElement {
         let operand : BigInt  = other.clone();
         let fother = FieldElement::new(operand, self.prime.clone()).unwrap();
-        return self * &fother;
+        self * &fother
     }
 }
 // &T * &U
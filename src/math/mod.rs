@@ -1,13 +1,8 @@
-use std::error;
-use std::fmt;
-use std::ops;
-use num_bigint::BigInt;
-use num_integer::Integer;
-
 mod field_element;
 pub use field_element::*;
 
-mod ecc;
+#[cfg(feature = "ecc")]
+pub mod ecc;
 
 #[test]
 fn add_fieldelement() {
@@ -37,7 +32,7 @@ fn add_fieldelement_moved_ref() {
 fn add_fieldelement_panic() {
     let fe1 = FieldElement::new(3, 5).unwrap();
     let fe2 = FieldElement::new(3, 7).unwrap();
-    let fe3 = fe1 + &fe2;
+    let _fe3 = fe1 + &fe2;
     println!("fe2: {}", fe2);
 }
 
@@ -0,0 +1,71 @@
+//! Bitcoin Core's legacy signed messages (`signmessage`/`verifymessage`).
+
+use crate::encoding::base58::decode_base58_checksum;
+use crate::encoding::varint::encode_varint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use crate::math::ecc::{bytes_to_int, PrivateKey, RecoverableSignature};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+const MESSAGE_PREFIX: &[u8] = b"\x18Bitcoin Signed Message:\n";
+
+/// The double SHA256 digest that gets signed for `message`.
+pub fn signed_message_hash(message: &str) -> [u8; 32] {
+    let mut data = MESSAGE_PREFIX.to_vec();
+    encode_varint(&mut data, message.len() as u64).unwrap();
+    data.extend_from_slice(message.as_bytes());
+    hash256(&data)
+}
+
+/// Signs `message` and returns the base64 compact signature. `compressed`
+/// must match the form of the key's address.
+pub fn sign_message(key: &PrivateKey, message: &str, compressed: bool) -> String {
+    let z = bytes_to_int(&signed_message_hash(message));
+    BASE64.encode(key.sign_recoverable(&z).serialize_compact(compressed))
+}
+
+/// Checks a base64 signature over `message` against a P2PKH address.
+/// Malformed inputs are errors, a signature by some other key is `Ok(false)`.
+pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<bool> {
+    let payload = decode_base58_checksum(address)?;
+    if payload.len() != 21 || (payload[0] != 0x00 && payload[0] != 0x6f) {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} is not a P2PKH address", address)));
+    }
+    let sig_bytes = BASE64
+        .decode(signature)
+        .map_err(|e| Error::new(ErrorKind::InvalidEncoding, format!("signature is not base64: {}", e)))?;
+    let (sig, compressed) = RecoverableSignature::parse_compact(&sig_bytes)?;
+    let z = bytes_to_int(&signed_message_hash(message));
+    match sig.recover(&z) {
+        Ok(point) => Ok(point.hash160(compressed)[..] == payload[1..]),
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::params::Network;
+
+#[test]
+fn message_hash() {
+    let expected = "a6f87fe6d58a032c320ff8d1541656f0282c2c7bfcc69d61af4c8e8ed528e49c";
+    assert_eq!(crate::encoding::util::to_reversed_hex(&signed_message_hash("test")), expected);
+}
+
+#[test]
+fn sign_and_verify_message() {
+    let secret = bytes_to_int(&decode_hex("52e3860ec7cb3ebe0720c2905f48a98c97916a3d60782ab7c8f505d8238fe4c1").unwrap());
+    let key = PrivateKey::new(&secret).unwrap();
+    let message = "rust-bitcoin MessageSignature test";
+    let signature = sign_message(&key, message, true);
+    assert_eq!(signature, "IAM2qX24tYx/bdBTIgVLhD8QEAjrPlJpmjB4nZHdRYGIBa4DmVulAcwjPnWe6Q5iEwXH6F0pUCJP/ZeHPWS1h1o=");
+
+    let address = key.public_key().address(true, Network::Mainnet);
+    assert!(verify_message(&address, &signature, message).unwrap());
+    assert!(!verify_message(&address, &signature, "a different message from what was signed").unwrap());
+    // The uncompressed address belongs to a different hash160
+    assert!(!verify_message(&key.public_key().address(false, Network::Mainnet), &signature, message).unwrap());
+    assert!(verify_message(&address, "not base64!", message).is_err());
+}
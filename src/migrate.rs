@@ -0,0 +1,183 @@
+//! Moving a legacy wallet's coins to segwit and taproot outputs.
+//!
+//! `upgrade` maps a `pkh()` descriptor to the `wpkh()` and `tr()` ones
+//! over the same keys, and `sh(multi())` or `sh(sortedmulti())` to the
+//! `wsh()` one. There is no `multi_a()` here, so a multisig has no taproot
+//! version yet. `MigrationPlan::new` finds the legacy descriptor's coins
+//! among a wallet's, sizes their inputs from the script each one
+//! classifies as, and sweeps them with a `TxBuilder` to the first address
+//! of the newest witness version.
+
+use crate::descriptor::{Descriptor, DescriptorKey};
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::Network;
+use crate::script::{Script, ScriptType};
+use crate::tx::builder::{TxBuilder, Utxo};
+use crate::tx::Tx;
+use std::collections::HashMap;
+
+// A push of a DER signature with its sighash byte, at its longest
+const LEGACY_SIGNATURE_SIZE: usize = 1 + 73;
+
+fn push_size(len: usize) -> usize {
+    match len {
+        0..=75 => 1 + len,
+        76..=255 => 2 + len,
+        _ => 3 + len,
+    }
+}
+
+/// Weight units the script_sig of an m-of-n P2SH multisig input adds:
+/// the OP_0 `CHECKMULTISIG` pops, m signatures and the redeem script,
+/// with the length bytes past the one an empty script_sig has.
+fn p2sh_multisig_satisfaction_weight(m: usize, redeem_script_len: usize) -> usize {
+    let script_sig = 1 + m * LEGACY_SIGNATURE_SIZE + push_size(redeem_script_len);
+    let length = if script_sig < 0xfd { 1 } else { 3 };
+    (script_sig + length - 1) * 4
+}
+
+fn unsupported(legacy: &Descriptor) -> Error {
+    Error::new(ErrorKind::UnsupportedScript, format!("{} is not a P2PKH or P2SH multisig descriptor", legacy))
+}
+
+/// The descriptors paying the same keys as `legacy` with newer witness
+/// versions, oldest first. Segwit needs compressed keys.
+pub fn upgrade(legacy: &Descriptor) -> Result<Vec<Descriptor>> {
+    match legacy {
+        Descriptor::Pkh(key) => {
+            if matches!(key, DescriptorKey::Single { key, .. } if key.len() == 65) {
+                return Err(Error::new(ErrorKind::UnsupportedScript, format!("{} has an uncompressed key", legacy)));
+            }
+            Ok(vec![Descriptor::Wpkh(key.clone()), Descriptor::Tr { internal_key: key.clone(), tree: None }])
+        }
+        Descriptor::Sh(inner) if matches!(**inner, Descriptor::Multi { .. }) => Ok(vec![Descriptor::Wsh(inner.clone())]),
+        _ => Err(unsupported(legacy)),
+    }
+}
+
+/// Which of a wallet's coins move where, and what it costs.
+#[derive(Debug, Clone)]
+pub struct MigrationPlan {
+    /// What `upgrade` gives, to watch from now on
+    pub descriptors: Vec<Descriptor>,
+    /// The legacy coins, each with the index deriving its script
+    pub inputs: Vec<(u32, Utxo)>,
+    /// Where they go: index 0 of the last descriptor
+    pub address: String,
+    /// The unsigned sweep
+    pub tx: Tx,
+    pub fee: u64,
+}
+
+impl MigrationPlan {
+    /// Plans sweeping the coins in `utxos` that pay `legacy` at an index
+    /// below `lookahead` at `fee_rate` sat/vB. Other coins are left alone.
+    pub fn new(legacy: &Descriptor, utxos: &[Utxo], lookahead: u32, fee_rate: f64, network: Network) -> Result<MigrationPlan> {
+        let descriptors = upgrade(legacy)?;
+        let lookahead = if legacy.is_ranged() { lookahead } else { 1 };
+        let mut scripts = HashMap::new();
+        for index in 0..lookahead {
+            let script_pubkey = legacy.script_pubkey(index)?;
+            let satisfaction = match (script_pubkey.classify(), legacy) {
+                // The builder sizes P2PKH spends itself
+                (ScriptType::P2pkh(_), _) => None,
+                (ScriptType::P2sh(_), Descriptor::Sh(inner)) => match **inner {
+                    Descriptor::Multi { m, .. } => Some(p2sh_multisig_satisfaction_weight(m as usize, inner.script_pubkey(index)?.raw_serialize().len())),
+                    _ => return Err(unsupported(legacy)),
+                },
+                _ => return Err(unsupported(legacy)),
+            };
+            scripts.insert(script_pubkey.classify(), (index, satisfaction));
+        }
+
+        let mut inputs = vec![];
+        for utxo in utxos {
+            let script_type = Script::from_bytes(&utxo.output.script_pubkey).map_or(ScriptType::NonStandard, |script| script.classify());
+            if let Some((index, satisfaction)) = scripts.get(&script_type) {
+                let utxo = match satisfaction {
+                    Some(weight) => utxo.clone().with_satisfaction_weight(*weight),
+                    None => utxo.clone(),
+                };
+                inputs.push((*index, utxo));
+            }
+        }
+        if inputs.is_empty() {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("no coins pay {}", legacy)));
+        }
+
+        let destination = descriptors.last().unwrap();
+        let tx = TxBuilder::new()
+            .add_utxos(inputs.iter().map(|(_, utxo)| utxo.clone()))
+            .fee_rate(fee_rate)
+            .drain_to(destination.script_pubkey(0)?.raw_serialize())
+            .network(network)
+            .build()?;
+        let spent: u64 = inputs.iter().filter(|(_, utxo)| tx.tx_ins.iter().any(|tx_in| tx_in.prev_tx == utxo.prev_tx && tx_in.prev_index == utxo.prev_index)).map(|(_, utxo)| utxo.output.amount).sum();
+        let fee = spent - tx.tx_outs[0].amount;
+        let address = destination.address(0, &network.params())?;
+        Ok(MigrationPlan { descriptors, inputs, address, tx, fee })
+    }
+}
+
+#[cfg(test)]
+use crate::bip32::ExtendedPrivKey;
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use crate::tx::builder::estimate_weight;
+#[cfg(test)]
+use crate::tx::TxOut;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn migrate_p2pkh() {
+    let account = ExtendedPrivKey::new_master(&[3; 32], Network::Regtest).unwrap().extended_pub_key();
+    let legacy = Descriptor::parse(&format!("pkh({}/0/*)", account)).unwrap();
+    let descriptors = upgrade(&legacy).unwrap();
+    assert_eq!(descriptors.iter().map(|desc| desc.to_string()).collect::<Vec<_>>(), vec![format!("wpkh({}/0/*)", account), format!("tr({}/0/*)", account)]);
+    assert_eq!(descriptors[0].script_pubkey(4).unwrap().classify(), ScriptType::P2wpkh(match legacy.script_pubkey(4).unwrap().classify() {
+        ScriptType::P2pkh(h160) => h160,
+        other => panic!("{:?}", other),
+    }));
+
+    let coin = |index: u32, vout: u32, amount: u64| Utxo::new([7; 32], vout, TxOut::new(amount, legacy.script_pubkey(index).unwrap().raw_serialize()));
+    let foreign = Utxo::new([8; 32], 0, TxOut::new(90_000, descriptors[0].script_pubkey(0).unwrap().raw_serialize()));
+    let utxos = vec![coin(0, 0, 40_000), foreign, coin(3, 1, 25_000), coin(9, 2, 60_000)];
+    let plan = MigrationPlan::new(&legacy, &utxos, 5, 4.0, Network::Regtest).unwrap();
+    assert_eq!(plan.inputs.iter().map(|(index, utxo)| (*index, utxo.prev_index)).collect::<Vec<_>>(), vec![(0, 0), (3, 1)]);
+    assert_eq!(plan.address, descriptors[1].address(0, &Network::Regtest.params()).unwrap());
+    assert_eq!(plan.tx.tx_outs, vec![TxOut::new(65_000 - plan.fee, descriptors[1].script_pubkey(0).unwrap().raw_serialize())]);
+    let inputs: Vec<Utxo> = plan.inputs.iter().map(|(_, utxo)| utxo.clone()).collect();
+    let vsize = estimate_weight(&inputs, &plan.tx.tx_outs).unwrap().div_ceil(4) as u64;
+    assert!(plan.fee >= 4 * vsize && plan.fee < 4 * (vsize + 4));
+    // Index 9 is past the lookahead
+    assert_eq!(MigrationPlan::new(&legacy, &utxos, 10, 4.0, Network::Regtest).unwrap().inputs.len(), 3);
+
+    assert!(MigrationPlan::new(&legacy, &utxos[1..2], 5, 4.0, Network::Regtest).is_err());
+    let uncompressed = PrivateKey::new(&BigInt::from(5)).unwrap().public_key().sec(false);
+    let legacy = Descriptor::parse(&format!("pkh({})", crate::encoding::util::encode_hex(&uncompressed))).unwrap();
+    assert_eq!(upgrade(&legacy).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+    assert_eq!(upgrade(&descriptors[0]).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+}
+
+#[test]
+fn migrate_p2sh_multisig() {
+    let keys: Vec<String> = (1..=3).map(|i| crate::encoding::util::encode_hex(&PrivateKey::new(&BigInt::from(i * 1000)).unwrap().public_key().sec(true))).collect();
+    let legacy = Descriptor::parse(&format!("sh(sortedmulti(2,{}))", keys.join(","))).unwrap();
+    let descriptors = upgrade(&legacy).unwrap();
+    assert_eq!(descriptors.iter().map(|desc| desc.to_string()).collect::<Vec<_>>(), vec![format!("wsh(sortedmulti(2,{}))", keys.join(","))]);
+
+    let utxos = vec![Utxo::new([7; 32], 0, TxOut::new(500_000, legacy.script_pubkey(0).unwrap().raw_serialize()))];
+    let plan = MigrationPlan::new(&legacy, &utxos, 20, 10.0, Network::Regtest).unwrap();
+    // A 105 byte redeem script takes OP_PUSHDATA1, and the 256 byte
+    // script_sig a 3 byte length
+    assert_eq!(plan.inputs[0].1.satisfaction_weight().unwrap(), (1 + 2 * 74 + 2 + 105 + 2) * 4);
+    assert_eq!(plan.address, descriptors[0].address(0, &Network::Regtest.params()).unwrap());
+    let weight = estimate_weight(&[plan.inputs[0].1.clone()], &plan.tx.tx_outs).unwrap();
+    // A P2SH input is legacy, its empty witness counted in case others
+    // have one
+    assert_eq!(weight, (4 + 1 + 32 + 4 + 3 + 256 + 4 + 1 + 43 + 4) * 4 + 1);
+    assert!(plan.fee >= 10 * weight.div_ceil(4) as u64);
+    assert_eq!(plan.tx.tx_outs[0].amount, 500_000 - plan.fee);
+}
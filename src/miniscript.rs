@@ -0,0 +1,826 @@
+//! Miniscript for P2WSH: a structured subset of script that can be type
+//! checked, encoded, and satisfied without reasoning about opcodes.
+//!
+//! Expressions are written as in Bitcoin Core, like
+//! `or_d(pk(A),and_v(v:pkh(B),older(144)))`, with keys as in descriptors.
+//! Types cover the basic types and the z, o, n, d and u properties; the
+//! malleability properties and timelock mixing are not checked. There is
+//! no policy compiler, expressions are written by hand.
+
+use crate::descriptor::{call, split_args, DescriptorKey};
+use crate::encoding::util::{decode_hex, encode_hex};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash160;
+use crate::script::opcodes::OpCode;
+use crate::script::{Command, Script};
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+
+/// Keys in a `multi()`, the most CHECKMULTISIG takes.
+pub const MAX_MULTISIG_KEYS: usize = 20;
+// Above this a locktime or sequence is a flag, not a number
+const MAX_LOCK: u32 = 1 << 31;
+
+fn invalid<S: Into<String>>(msg: S) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, msg)
+}
+
+/// What an expression leaves on the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Base {
+    /// Consumes its inputs and pushes nonzero on success, zero otherwise
+    B,
+    /// Continues on success and aborts otherwise, pushing nothing
+    V,
+    /// Pushes a key for a signature check
+    K,
+    /// Like B, but takes its inputs from under the top element
+    W,
+}
+
+/// A base type and its properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Type {
+    pub base: Base,
+    /// Always consumes exactly 0 stack elements
+    pub z: bool,
+    /// Always consumes exactly 1 stack element
+    pub o: bool,
+    /// The top input is never zero when satisfied
+    pub n: bool,
+    /// Has a dissatisfaction that does not abort
+    pub d: bool,
+    /// Pushes exactly 1 on success
+    pub u: bool,
+}
+
+impl Type {
+    fn new(base: Base) -> Type {
+        Type { base, z: false, o: false, n: false, d: false, u: false }
+    }
+
+    fn is(&self, base: Base) -> bool {
+        self.base == base
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Miniscript {
+    /// `0`
+    False,
+    /// `1`
+    True,
+    /// `pk_k(K)`: `<K>`
+    PkK(DescriptorKey),
+    /// `pk_h(K)`: `DUP HASH160 <hash160(K)> EQUALVERIFY`
+    PkH(DescriptorKey),
+    /// `older(n)`: `<n> CHECKSEQUENCEVERIFY`
+    Older(u32),
+    /// `after(n)`: `<n> CHECKLOCKTIMEVERIFY`
+    After(u32),
+    Sha256([u8; 32]),
+    Hash256([u8; 32]),
+    Ripemd160([u8; 20]),
+    Hash160([u8; 20]),
+    /// `andor(X,Y,Z)`: `[X] NOTIF [Z] ELSE [Y] ENDIF`
+    AndOr(Box<Miniscript>, Box<Miniscript>, Box<Miniscript>),
+    /// `and_v(X,Y)`: `[X] [Y]`
+    AndV(Box<Miniscript>, Box<Miniscript>),
+    /// `and_b(X,Y)`: `[X] [Y] BOOLAND`
+    AndB(Box<Miniscript>, Box<Miniscript>),
+    /// `or_b(X,Z)`: `[X] [Z] BOOLOR`
+    OrB(Box<Miniscript>, Box<Miniscript>),
+    /// `or_c(X,Z)`: `[X] NOTIF [Z] ENDIF`
+    OrC(Box<Miniscript>, Box<Miniscript>),
+    /// `or_d(X,Z)`: `[X] IFDUP NOTIF [Z] ENDIF`
+    OrD(Box<Miniscript>, Box<Miniscript>),
+    /// `or_i(X,Z)`: `IF [X] ELSE [Z] ENDIF`
+    OrI(Box<Miniscript>, Box<Miniscript>),
+    /// `thresh(k,X1,...,Xn)`: `[X1] [X2] ADD ... [Xn] ADD <k> EQUAL`
+    Thresh(usize, Vec<Miniscript>),
+    /// `multi(k,K1,...,Kn)`: `<k> <K1> ... <Kn> <n> CHECKMULTISIG`
+    Multi(usize, Vec<DescriptorKey>),
+    /// `a:X`: `TOALTSTACK [X] FROMALTSTACK`
+    Alt(Box<Miniscript>),
+    /// `s:X`: `SWAP [X]`
+    Swap(Box<Miniscript>),
+    /// `c:X`: `[X] CHECKSIG`
+    Check(Box<Miniscript>),
+    /// `d:X`: `DUP IF [X] ENDIF`
+    DupIf(Box<Miniscript>),
+    /// `v:X`: `[X] VERIFY`, or the VERIFY form of its last opcode
+    Verify(Box<Miniscript>),
+    /// `j:X`: `SIZE 0NOTEQUAL IF [X] ENDIF`
+    NonZero(Box<Miniscript>),
+    /// `n:X`: `[X] 0NOTEQUAL`
+    ZeroNotEqual(Box<Miniscript>),
+}
+
+/// A placeholder in a witness template.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WitnessItem {
+    /// A DER signature with its sighash byte
+    Signature(DescriptorKey),
+    /// The compressed SEC of a key
+    PublicKey(DescriptorKey),
+    /// The 32 byte preimage of this hash
+    Preimage(Vec<u8>),
+    /// Any 32 bytes that are not a preimage
+    NonPreimage,
+    Empty,
+    One,
+}
+
+impl WitnessItem {
+    /// The most bytes the item can take, not counting its length prefix.
+    pub fn max_size(&self) -> usize {
+        match self {
+            WitnessItem::Signature(_) => 73,
+            WitnessItem::PublicKey(_) => 33,
+            WitnessItem::Preimage(_) | WitnessItem::NonPreimage => 32,
+            WitnessItem::Empty => 0,
+            WitnessItem::One => 1,
+        }
+    }
+}
+
+type Template = Vec<WitnessItem>;
+
+/// The serialized size of a witness stack, each item with its length byte.
+pub fn witness_size(template: &[WitnessItem]) -> usize {
+    template.iter().map(|item| 1 + item.max_size()).sum()
+}
+
+// Whether to build the cheapest witness or the worst case one
+#[derive(Clone, Copy, PartialEq)]
+enum Choice {
+    Smallest,
+    Largest,
+}
+
+fn pick(options: Vec<Option<Template>>, choice: Choice) -> Option<Template> {
+    let options = options.into_iter().flatten();
+    match choice {
+        Choice::Smallest => options.min_by_key(|t| witness_size(t)),
+        Choice::Largest => options.max_by_key(|t| witness_size(t)),
+    }
+}
+
+/// `first` pushed before `second`, if both exist.
+fn join(first: Option<Template>, second: Option<Template>) -> Option<Template> {
+    let mut first = first?;
+    first.extend(second?);
+    Some(first)
+}
+
+fn op(op: OpCode) -> Command {
+    Command::Op(op.to_u8())
+}
+
+fn parse_hash<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    decode_hex(hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid(format!("{:?} is not a {} byte hash", hex, N)))
+}
+
+fn parse_lock(s: &str) -> Result<u32> {
+    match s.parse::<u32>() {
+        Ok(n) if n > 0 && n < MAX_LOCK => Ok(n),
+        _ => Err(Error::new(ErrorKind::OutOfRange, format!("timelock {:?}", s))),
+    }
+}
+
+fn parse_threshold(s: &str) -> Result<usize> {
+    s.parse::<usize>().map_err(|_| invalid(format!("threshold {:?}", s)))
+}
+
+fn parse_key(s: &str) -> Result<DescriptorKey> {
+    DescriptorKey::parse(s, false, false)
+}
+
+impl Miniscript {
+    /// Parses and type checks an expression.
+    pub fn parse(s: &str) -> Result<Miniscript> {
+        let ms = Miniscript::parse_fragment(s)?;
+        ms.ty()?;
+        Ok(ms)
+    }
+
+    fn parse_fragment(s: &str) -> Result<Miniscript> {
+        let sub = |s: &str| Miniscript::parse_fragment(s).map(Box::new);
+        let paren = s.find('(').unwrap_or(s.len());
+        if let Some(colon) = s[..paren].find(':') {
+            let inner = Miniscript::parse_fragment(&s[colon + 1..])?;
+            // The wrapper nearest the colon applies first
+            return s[..colon].chars().rev().try_fold(inner, |ms, wrapper| {
+                let ms = Box::new(ms);
+                Ok(match wrapper {
+                    'a' => Miniscript::Alt(ms),
+                    's' => Miniscript::Swap(ms),
+                    'c' => Miniscript::Check(ms),
+                    'd' => Miniscript::DupIf(ms),
+                    'v' => Miniscript::Verify(ms),
+                    'j' => Miniscript::NonZero(ms),
+                    'n' => Miniscript::ZeroNotEqual(ms),
+                    't' => Miniscript::AndV(ms, Box::new(Miniscript::True)),
+                    'l' => Miniscript::OrI(Box::new(Miniscript::False), ms),
+                    'u' => Miniscript::OrI(ms, Box::new(Miniscript::False)),
+                    other => return Err(invalid(format!("unknown wrapper {}:", other))),
+                })
+            });
+        }
+        match s {
+            "0" => return Ok(Miniscript::False),
+            "1" => return Ok(Miniscript::True),
+            _ => {}
+        }
+        let (name, args) = call(s)?;
+        Ok(match (name, split_args(args).as_slice()) {
+            ("pk_k", [key]) => Miniscript::PkK(parse_key(key)?),
+            ("pk_h", [key]) => Miniscript::PkH(parse_key(key)?),
+            ("pk", [key]) => Miniscript::Check(Box::new(Miniscript::PkK(parse_key(key)?))),
+            ("pkh", [key]) => Miniscript::Check(Box::new(Miniscript::PkH(parse_key(key)?))),
+            ("older", [n]) => Miniscript::Older(parse_lock(n)?),
+            ("after", [n]) => Miniscript::After(parse_lock(n)?),
+            ("sha256", [h]) => Miniscript::Sha256(parse_hash(h)?),
+            ("hash256", [h]) => Miniscript::Hash256(parse_hash(h)?),
+            ("ripemd160", [h]) => Miniscript::Ripemd160(parse_hash(h)?),
+            ("hash160", [h]) => Miniscript::Hash160(parse_hash(h)?),
+            ("andor", [x, y, z]) => Miniscript::AndOr(sub(x)?, sub(y)?, sub(z)?),
+            ("and_n", [x, y]) => Miniscript::AndOr(sub(x)?, sub(y)?, Box::new(Miniscript::False)),
+            ("and_v", [x, y]) => Miniscript::AndV(sub(x)?, sub(y)?),
+            ("and_b", [x, y]) => Miniscript::AndB(sub(x)?, sub(y)?),
+            ("or_b", [x, z]) => Miniscript::OrB(sub(x)?, sub(z)?),
+            ("or_c", [x, z]) => Miniscript::OrC(sub(x)?, sub(z)?),
+            ("or_d", [x, z]) => Miniscript::OrD(sub(x)?, sub(z)?),
+            ("or_i", [x, z]) => Miniscript::OrI(sub(x)?, sub(z)?),
+            ("thresh", [k, subs @ ..]) => {
+                let subs = subs.iter().map(|s| Miniscript::parse_fragment(s)).collect::<Result<Vec<_>>>()?;
+                Miniscript::Thresh(parse_threshold(k)?, subs)
+            }
+            ("multi", [k, keys @ ..]) => {
+                let keys = keys.iter().map(|key| parse_key(key)).collect::<Result<Vec<_>>>()?;
+                Miniscript::Multi(parse_threshold(k)?, keys)
+            }
+            _ => return Err(invalid(format!("unknown miniscript fragment {}", s))),
+        })
+    }
+
+    /// The type of the expression, or `InvalidEncoding` if a fragment's
+    /// arguments have the wrong types.
+    pub fn ty(&self) -> Result<Type> {
+        use Base::*;
+        let wrong = || Err(invalid(format!("badly typed miniscript {}", self)));
+        Ok(match self {
+            Miniscript::False => Type { z: true, d: true, u: true, ..Type::new(B) },
+            Miniscript::True => Type { z: true, u: true, ..Type::new(B) },
+            Miniscript::PkK(_) => Type { o: true, n: true, d: true, u: true, ..Type::new(K) },
+            Miniscript::PkH(_) => Type { n: true, d: true, u: true, ..Type::new(K) },
+            Miniscript::Older(_) | Miniscript::After(_) => Type { z: true, ..Type::new(B) },
+            Miniscript::Sha256(_) | Miniscript::Hash256(_) | Miniscript::Ripemd160(_) | Miniscript::Hash160(_) => {
+                Type { o: true, n: true, d: true, u: true, ..Type::new(B) }
+            }
+            Miniscript::AndOr(x, y, z) => {
+                let (x, y, z) = (x.ty()?, y.ty()?, z.ty()?);
+                if !(x.is(B) && x.d && x.u) || y.base != z.base || y.is(W) {
+                    return wrong();
+                }
+                Type {
+                    z: x.z && y.z && z.z,
+                    o: (x.z && y.o && z.o) || (x.o && y.z && z.z),
+                    d: z.d,
+                    u: y.u && z.u,
+                    ..Type::new(y.base)
+                }
+            }
+            Miniscript::AndV(x, y) => {
+                let (x, y) = (x.ty()?, y.ty()?);
+                if !x.is(V) || y.is(W) {
+                    return wrong();
+                }
+                Type {
+                    z: x.z && y.z,
+                    o: (x.z && y.o) || (x.o && y.z),
+                    n: x.n || (x.z && y.n),
+                    u: y.u,
+                    ..Type::new(y.base)
+                }
+            }
+            Miniscript::AndB(x, y) => {
+                let (x, y) = (x.ty()?, y.ty()?);
+                if !x.is(B) || !y.is(W) {
+                    return wrong();
+                }
+                Type {
+                    z: x.z && y.z,
+                    o: (x.z && y.o) || (x.o && y.z),
+                    n: x.n || (x.z && y.n),
+                    d: x.d && y.d,
+                    u: true,
+                    ..Type::new(B)
+                }
+            }
+            Miniscript::OrB(x, z) => {
+                let (x, z) = (x.ty()?, z.ty()?);
+                if !(x.is(B) && x.d && z.is(W) && z.d) {
+                    return wrong();
+                }
+                Type { z: x.z && z.z, o: (x.z && z.o) || (x.o && z.z), d: true, u: true, ..Type::new(B) }
+            }
+            Miniscript::OrC(x, z) => {
+                let (x, z) = (x.ty()?, z.ty()?);
+                if !(x.is(B) && x.d && x.u && z.is(V)) {
+                    return wrong();
+                }
+                Type { z: x.z && z.z, o: x.o && z.z, ..Type::new(V) }
+            }
+            Miniscript::OrD(x, z) => {
+                let (x, z) = (x.ty()?, z.ty()?);
+                if !(x.is(B) && x.d && x.u && z.is(B)) {
+                    return wrong();
+                }
+                Type { z: x.z && z.z, o: x.o && z.z, d: z.d, u: z.u, ..Type::new(B) }
+            }
+            Miniscript::OrI(x, z) => {
+                let (x, z) = (x.ty()?, z.ty()?);
+                if x.base != z.base || x.is(W) {
+                    return wrong();
+                }
+                Type { o: x.z && z.z, d: x.d || z.d, u: x.u && z.u, ..Type::new(x.base) }
+            }
+            Miniscript::Thresh(k, subs) => {
+                let types = subs.iter().map(|sub| sub.ty()).collect::<Result<Vec<_>>>()?;
+                let first_ok = types.first().is_some_and(|t| t.is(B));
+                if *k == 0 || *k > subs.len() || !first_ok || types[1..].iter().any(|t| !t.is(W)) || types.iter().any(|t| !(t.d && t.u)) {
+                    return wrong();
+                }
+                let not_z: Vec<&Type> = types.iter().filter(|t| !t.z).collect();
+                Type { z: not_z.is_empty(), o: not_z.len() == 1 && not_z[0].o, d: true, u: true, ..Type::new(B) }
+            }
+            Miniscript::Multi(k, keys) => {
+                if *k == 0 || *k > keys.len() || keys.len() > MAX_MULTISIG_KEYS {
+                    return Err(Error::new(ErrorKind::OutOfRange, format!("{} of {} multisig", k, keys.len())));
+                }
+                Type { n: true, d: true, u: true, ..Type::new(B) }
+            }
+            Miniscript::Alt(x) => {
+                let x = x.ty()?;
+                if !x.is(B) {
+                    return wrong();
+                }
+                Type { d: x.d, u: x.u, ..Type::new(W) }
+            }
+            Miniscript::Swap(x) => {
+                let x = x.ty()?;
+                if !(x.is(B) && x.o) {
+                    return wrong();
+                }
+                Type { d: x.d, u: x.u, ..Type::new(W) }
+            }
+            Miniscript::Check(x) => {
+                let x = x.ty()?;
+                if !x.is(K) {
+                    return wrong();
+                }
+                Type { o: x.o, n: x.n, d: x.d, u: true, ..Type::new(B) }
+            }
+            Miniscript::DupIf(x) => {
+                // Not u: P2WSH does not require a minimal IF argument
+                let x = x.ty()?;
+                if !(x.is(V) && x.z) {
+                    return wrong();
+                }
+                Type { o: true, n: true, d: true, ..Type::new(B) }
+            }
+            Miniscript::Verify(x) => {
+                let x = x.ty()?;
+                if !x.is(B) {
+                    return wrong();
+                }
+                Type { z: x.z, o: x.o, n: x.n, ..Type::new(V) }
+            }
+            Miniscript::NonZero(x) => {
+                let x = x.ty()?;
+                if !(x.is(B) && x.n) {
+                    return wrong();
+                }
+                Type { o: x.o, n: true, d: true, u: x.u, ..Type::new(B) }
+            }
+            Miniscript::ZeroNotEqual(x) => {
+                let x = x.ty()?;
+                if !x.is(B) {
+                    return wrong();
+                }
+                Type { z: x.z, o: x.o, n: x.n, d: x.d, u: true, ..Type::new(B) }
+            }
+        })
+    }
+
+    /// Every key in the expression.
+    pub fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            Miniscript::PkK(key) | Miniscript::PkH(key) => vec![key],
+            Miniscript::Multi(_, keys) => keys.iter().collect(),
+            Miniscript::Thresh(_, subs) => subs.iter().flat_map(|sub| sub.keys()).collect(),
+            Miniscript::AndOr(x, y, z) => [x.keys(), y.keys(), z.keys()].concat(),
+            Miniscript::AndV(x, y)
+            | Miniscript::AndB(x, y)
+            | Miniscript::OrB(x, y)
+            | Miniscript::OrC(x, y)
+            | Miniscript::OrD(x, y)
+            | Miniscript::OrI(x, y) => [x.keys(), y.keys()].concat(),
+            Miniscript::Alt(x)
+            | Miniscript::Swap(x)
+            | Miniscript::Check(x)
+            | Miniscript::DupIf(x)
+            | Miniscript::Verify(x)
+            | Miniscript::NonZero(x)
+            | Miniscript::ZeroNotEqual(x) => x.keys(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The script with keys derived at `index`.
+    pub fn to_script(&self, index: u32) -> Result<Script> {
+        let mut cmds = Vec::new();
+        self.encode(index, &mut cmds)?;
+        Ok(Script::new(cmds))
+    }
+
+    fn encode(&self, index: u32, cmds: &mut Vec<Command>) -> Result<()> {
+        let hash_check = |cmds: &mut Vec<Command>, hash_op: OpCode, hash: &[u8]| {
+            cmds.extend(vec![op(OpCode::Size), Command::num(32), op(OpCode::EqualVerify), op(hash_op)]);
+            cmds.extend(vec![Command::Data(hash.to_vec()), op(OpCode::Equal)]);
+        };
+        match self {
+            Miniscript::False => cmds.push(op(OpCode::Zero)),
+            Miniscript::True => cmds.push(op(OpCode::One)),
+            Miniscript::PkK(key) => cmds.push(Command::Data(key.derive(index)?.sec(true))),
+            Miniscript::PkH(key) => {
+                let h160 = hash160(&key.derive(index)?.sec(true));
+                cmds.extend(vec![op(OpCode::Dup), op(OpCode::Hash160), Command::Data(h160.to_vec()), op(OpCode::EqualVerify)]);
+            }
+            Miniscript::Older(n) => cmds.extend(vec![Command::num(*n as i64), op(OpCode::CheckSequenceVerify)]),
+            Miniscript::After(n) => cmds.extend(vec![Command::num(*n as i64), op(OpCode::CheckLockTimeVerify)]),
+            Miniscript::Sha256(h) => hash_check(cmds, OpCode::Sha256, h),
+            Miniscript::Hash256(h) => hash_check(cmds, OpCode::Hash256, h),
+            Miniscript::Ripemd160(h) => hash_check(cmds, OpCode::Ripemd160, h),
+            Miniscript::Hash160(h) => hash_check(cmds, OpCode::Hash160, h),
+            Miniscript::AndOr(x, y, z) => {
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::NotIf));
+                z.encode(index, cmds)?;
+                cmds.push(op(OpCode::Else));
+                y.encode(index, cmds)?;
+                cmds.push(op(OpCode::EndIf));
+            }
+            Miniscript::AndV(x, y) => {
+                x.encode(index, cmds)?;
+                y.encode(index, cmds)?;
+            }
+            Miniscript::AndB(x, y) | Miniscript::OrB(x, y) => {
+                x.encode(index, cmds)?;
+                y.encode(index, cmds)?;
+                cmds.push(op(if let Miniscript::AndB(..) = self { OpCode::BoolAnd } else { OpCode::BoolOr }));
+            }
+            Miniscript::OrC(x, z) | Miniscript::OrD(x, z) => {
+                x.encode(index, cmds)?;
+                if let Miniscript::OrD(..) = self {
+                    cmds.push(op(OpCode::IfDup));
+                }
+                cmds.push(op(OpCode::NotIf));
+                z.encode(index, cmds)?;
+                cmds.push(op(OpCode::EndIf));
+            }
+            Miniscript::OrI(x, z) => {
+                cmds.push(op(OpCode::If));
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::Else));
+                z.encode(index, cmds)?;
+                cmds.push(op(OpCode::EndIf));
+            }
+            Miniscript::Thresh(k, subs) => {
+                for (i, sub) in subs.iter().enumerate() {
+                    sub.encode(index, cmds)?;
+                    if i > 0 {
+                        cmds.push(op(OpCode::Add));
+                    }
+                }
+                cmds.extend(vec![Command::num(*k as i64), op(OpCode::Equal)]);
+            }
+            Miniscript::Multi(k, keys) => {
+                cmds.push(Command::num(*k as i64));
+                for key in keys {
+                    cmds.push(Command::Data(key.derive(index)?.sec(true)));
+                }
+                cmds.extend(vec![Command::num(keys.len() as i64), op(OpCode::CheckMultiSig)]);
+            }
+            Miniscript::Alt(x) => {
+                cmds.push(op(OpCode::ToAltStack));
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::FromAltStack));
+            }
+            Miniscript::Swap(x) => {
+                cmds.push(op(OpCode::Swap));
+                x.encode(index, cmds)?;
+            }
+            Miniscript::Check(x) => {
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::CheckSig));
+            }
+            Miniscript::DupIf(x) => {
+                cmds.extend(vec![op(OpCode::Dup), op(OpCode::If)]);
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::EndIf));
+            }
+            Miniscript::Verify(x) => {
+                x.encode(index, cmds)?;
+                let verify = match cmds.last() {
+                    Some(Command::Op(byte)) => match OpCode::from_u8(*byte) {
+                        Some(OpCode::Equal) => Some(OpCode::EqualVerify),
+                        Some(OpCode::NumEqual) => Some(OpCode::NumEqualVerify),
+                        Some(OpCode::CheckSig) => Some(OpCode::CheckSigVerify),
+                        Some(OpCode::CheckMultiSig) => Some(OpCode::CheckMultiSigVerify),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match verify {
+                    Some(verify) => *cmds.last_mut().unwrap() = op(verify),
+                    None => cmds.push(op(OpCode::Verify)),
+                }
+            }
+            Miniscript::NonZero(x) => {
+                cmds.extend(vec![op(OpCode::Size), op(OpCode::ZeroNotEqual), op(OpCode::If)]);
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::EndIf));
+            }
+            Miniscript::ZeroNotEqual(x) => {
+                x.encode(index, cmds)?;
+                cmds.push(op(OpCode::ZeroNotEqual));
+            }
+        }
+        Ok(())
+    }
+
+    /// The smallest witness satisfying the script, bottom of the stack
+    /// first, without the witness script itself.
+    pub fn satisfaction(&self) -> Option<Vec<WitnessItem>> {
+        self.sat(Choice::Smallest)
+    }
+
+    /// The smallest witness making the script leave false, for expressions
+    /// that can be dissatisfied.
+    pub fn dissatisfaction(&self) -> Option<Vec<WitnessItem>> {
+        self.dsat(Choice::Smallest)
+    }
+
+    /// The largest satisfying witness in bytes, for estimating fees.
+    pub fn max_satisfaction_size(&self) -> Option<usize> {
+        self.sat(Choice::Largest).map(|t| witness_size(&t))
+    }
+
+    fn sat(&self, choice: Choice) -> Option<Template> {
+        use WitnessItem::*;
+        match self {
+            Miniscript::False => None,
+            Miniscript::True | Miniscript::Older(_) | Miniscript::After(_) => Some(vec![]),
+            Miniscript::PkK(key) => Some(vec![Signature(key.clone())]),
+            Miniscript::PkH(key) => Some(vec![Signature(key.clone()), PublicKey(key.clone())]),
+            Miniscript::Sha256(h) | Miniscript::Hash256(h) => Some(vec![Preimage(h.to_vec())]),
+            Miniscript::Ripemd160(h) | Miniscript::Hash160(h) => Some(vec![Preimage(h.to_vec())]),
+            Miniscript::AndOr(x, y, z) => {
+                pick(vec![join(y.sat(choice), x.sat(choice)), join(z.sat(choice), x.dsat(choice))], choice)
+            }
+            Miniscript::AndV(x, y) | Miniscript::AndB(x, y) => join(y.sat(choice), x.sat(choice)),
+            Miniscript::OrB(x, z) => {
+                pick(vec![join(z.dsat(choice), x.sat(choice)), join(z.sat(choice), x.dsat(choice))], choice)
+            }
+            Miniscript::OrC(x, z) | Miniscript::OrD(x, z) => pick(vec![x.sat(choice), join(z.sat(choice), x.dsat(choice))], choice),
+            Miniscript::OrI(x, z) => {
+                pick(vec![join(x.sat(choice), Some(vec![One])), join(z.sat(choice), Some(vec![Empty]))], choice)
+            }
+            Miniscript::Thresh(k, subs) => thresh_sat(*k, subs, choice),
+            Miniscript::Multi(k, keys) => {
+                let mut template = vec![Empty];
+                template.extend(keys[..*k].iter().map(|key| Signature(key.clone())));
+                Some(template)
+            }
+            Miniscript::DupIf(x) => join(x.sat(choice), Some(vec![One])),
+            Miniscript::Alt(x)
+            | Miniscript::Swap(x)
+            | Miniscript::Check(x)
+            | Miniscript::Verify(x)
+            | Miniscript::NonZero(x)
+            | Miniscript::ZeroNotEqual(x) => x.sat(choice),
+        }
+    }
+
+    fn dsat(&self, choice: Choice) -> Option<Template> {
+        use WitnessItem::*;
+        match self {
+            Miniscript::False => Some(vec![]),
+            Miniscript::True | Miniscript::Older(_) | Miniscript::After(_) => None,
+            Miniscript::PkK(_) => Some(vec![Empty]),
+            Miniscript::PkH(key) => Some(vec![Empty, PublicKey(key.clone())]),
+            Miniscript::Sha256(_) | Miniscript::Hash256(_) | Miniscript::Ripemd160(_) | Miniscript::Hash160(_) => {
+                Some(vec![NonPreimage])
+            }
+            Miniscript::AndOr(x, _, z) => join(z.dsat(choice), x.dsat(choice)),
+            Miniscript::AndB(x, y) => join(y.dsat(choice), x.dsat(choice)),
+            Miniscript::OrB(x, z) | Miniscript::OrD(x, z) => join(z.dsat(choice), x.dsat(choice)),
+            Miniscript::AndV(..) | Miniscript::OrC(..) | Miniscript::Verify(_) => None,
+            Miniscript::OrI(x, z) => {
+                pick(vec![join(x.dsat(choice), Some(vec![One])), join(z.dsat(choice), Some(vec![Empty]))], choice)
+            }
+            Miniscript::Thresh(_, subs) => subs.iter().rev().try_fold(Vec::new(), |template, sub| join(Some(template), sub.dsat(choice))),
+            Miniscript::Multi(k, _) => Some(vec![Empty; k + 1]),
+            Miniscript::DupIf(_) | Miniscript::NonZero(_) => Some(vec![Empty]),
+            Miniscript::Alt(x) | Miniscript::Swap(x) | Miniscript::Check(x) | Miniscript::ZeroNotEqual(x) => x.dsat(choice),
+        }
+    }
+
+    // The wrapper letter and what it wraps, with pk() and pkh() kept whole
+    fn wrapper(&self) -> Option<(char, &Miniscript)> {
+        match self {
+            Miniscript::Check(x) if matches!(**x, Miniscript::PkK(_) | Miniscript::PkH(_)) => None,
+            Miniscript::Alt(x) => Some(('a', x)),
+            Miniscript::Swap(x) => Some(('s', x)),
+            Miniscript::Check(x) => Some(('c', x)),
+            Miniscript::DupIf(x) => Some(('d', x)),
+            Miniscript::Verify(x) => Some(('v', x)),
+            Miniscript::NonZero(x) => Some(('j', x)),
+            Miniscript::ZeroNotEqual(x) => Some(('n', x)),
+            _ => None,
+        }
+    }
+}
+
+/// Satisfies `k` of `subs`, the cheapest (or costliest) ones over
+/// dissatisfying them.
+fn thresh_sat(k: usize, subs: &[Miniscript], choice: Choice) -> Option<Template> {
+    let sats: Vec<Option<Template>> = subs.iter().map(|sub| sub.sat(choice)).collect();
+    let dsats: Vec<Option<Template>> = subs.iter().map(|sub| sub.dsat(choice)).collect();
+    let mut order: Vec<usize> = (0..subs.len()).filter(|&i| sats[i].is_some()).collect();
+    // Those that cannot be dissatisfied have to be satisfied
+    order.sort_by_key(|&i| {
+        let extra = witness_size(sats[i].as_ref().unwrap()) as i64 - dsats[i].as_ref().map_or(0, |d| witness_size(d) as i64);
+        (dsats[i].is_some(), if choice == Choice::Smallest { extra } else { -extra })
+    });
+    if order.len() < k {
+        return None;
+    }
+    let satisfied = &order[..k];
+    (0..subs.len()).rev().try_fold(Vec::new(), |mut template, i| {
+        let witness = if satisfied.contains(&i) { sats[i].as_ref() } else { dsats[i].as_ref() };
+        template.extend(witness?.iter().cloned());
+        Some(template)
+    })
+}
+
+impl fmt::Display for Miniscript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((letter, mut inner)) = self.wrapper() {
+            write!(f, "{}", letter)?;
+            while let Some((letter, next)) = inner.wrapper() {
+                write!(f, "{}", letter)?;
+                inner = next;
+            }
+            return write!(f, ":{}", inner);
+        }
+        let list = |f: &mut fmt::Formatter, name: &str, k: &dyn fmt::Display, items: Vec<&dyn fmt::Display>| {
+            write!(f, "{}({}", name, k)?;
+            for item in items {
+                write!(f, ",{}", item)?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            Miniscript::False => write!(f, "0"),
+            Miniscript::True => write!(f, "1"),
+            Miniscript::PkK(key) => write!(f, "pk_k({})", key),
+            Miniscript::PkH(key) => write!(f, "pk_h({})", key),
+            Miniscript::Check(x) => match &**x {
+                Miniscript::PkK(key) => write!(f, "pk({})", key),
+                Miniscript::PkH(key) => write!(f, "pkh({})", key),
+                _ => unreachable!("other c: are wrappers"),
+            },
+            Miniscript::Older(n) => write!(f, "older({})", n),
+            Miniscript::After(n) => write!(f, "after({})", n),
+            Miniscript::Sha256(h) => write!(f, "sha256({})", encode_hex(h)),
+            Miniscript::Hash256(h) => write!(f, "hash256({})", encode_hex(h)),
+            Miniscript::Ripemd160(h) => write!(f, "ripemd160({})", encode_hex(h)),
+            Miniscript::Hash160(h) => write!(f, "hash160({})", encode_hex(h)),
+            Miniscript::AndOr(x, y, z) => write!(f, "andor({},{},{})", x, y, z),
+            Miniscript::AndV(x, y) => write!(f, "and_v({},{})", x, y),
+            Miniscript::AndB(x, y) => write!(f, "and_b({},{})", x, y),
+            Miniscript::OrB(x, z) => write!(f, "or_b({},{})", x, z),
+            Miniscript::OrC(x, z) => write!(f, "or_c({},{})", x, z),
+            Miniscript::OrD(x, z) => write!(f, "or_d({},{})", x, z),
+            Miniscript::OrI(x, z) => write!(f, "or_i({},{})", x, z),
+            Miniscript::Thresh(k, subs) => list(f, "thresh", k, subs.iter().map(|s| s as &dyn fmt::Display).collect()),
+            Miniscript::Multi(k, keys) => list(f, "multi", k, keys.iter().map(|key| key as &dyn fmt::Display).collect()),
+            _ => unreachable!("wrappers are written above"),
+        }
+    }
+}
+
+impl FromStr for Miniscript {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Miniscript> {
+        Miniscript::parse(s)
+    }
+}
+
+#[cfg(test)]
+use crate::hash::sha256;
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn encode_and_type_check() {
+    let key = |n: u32| PrivateKey::new(&BigInt::from(n)).unwrap().public_key().sec(true);
+    let (a, b) = (encode_hex(&key(1)), encode_hex(&key(2)));
+    let ms = Miniscript::parse(&format!("or_d(pk({}),and_v(v:pkh({}),older(12960)))", a, b)).unwrap();
+    assert_eq!(ms.to_string(), format!("or_d(pk({}),and_v(v:pkh({}),older(12960)))", a, b));
+    let h160 = encode_hex(&hash160(&key(2)));
+    assert_eq!(
+        ms.to_script(0).unwrap().to_string(),
+        format!("{} OP_CHECKSIG OP_IFDUP OP_NOTIF OP_DUP OP_HASH160 {} OP_EQUALVERIFY OP_CHECKSIGVERIFY a032 OP_CHECKSEQUENCEVERIFY OP_ENDIF", a, h160)
+    );
+    assert_eq!(ms.ty().unwrap(), Type::new(Base::B));
+    assert_eq!(ms.satisfaction().unwrap(), vec![WitnessItem::Signature(parse_key(&a).unwrap())]);
+    assert_eq!(ms.dissatisfaction(), None);
+    // A's signature, or an empty one for A then B's signature and key
+    assert_eq!(ms.max_satisfaction_size(), Some(1 + 74 + 34));
+
+    // Wrappers and sugar
+    let wrapped = Miniscript::parse(&format!("t:or_c(pk({}),v:sha256({}))", a, "00".repeat(32))).unwrap();
+    assert_eq!(wrapped.to_string(), format!("and_v(or_c(pk({}),v:sha256({})),1)", a, "00".repeat(32)));
+    assert_eq!(Miniscript::parse("l:after(100)").unwrap(), Miniscript::OrI(Box::new(Miniscript::False), Box::new(Miniscript::After(100))));
+    assert_eq!(Miniscript::parse(&format!("sc:pk_k({})", a)).unwrap().to_string(), format!("s:pk({})", a));
+
+    for bad in [
+        format!("and_v(pk({}),pk({}))", a, b),
+        format!("or_b(pk({}),pk({}))", a, b),
+        format!("thresh(3,pk({}),s:pk({}))", a, b),
+        format!("multi(0,{})", a),
+        "older(0)".to_string(),
+        "sha256(00)".to_string(),
+        format!("x:pk({})", a),
+    ]
+    .iter()
+    {
+        assert!(Miniscript::parse(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn satisfy_through_interpreter() {
+    use crate::script::interpreter::{verify_script, VerifyFlags};
+    use crate::script::p2wsh_script;
+    let keys: Vec<PrivateKey> = (1..=2).map(|n| PrivateKey::new(&BigInt::from(n)).unwrap()).collect();
+    let preimage = [7u8; 32];
+    let ms = Miniscript::parse(&format!(
+        "thresh(2,pk({}),s:pk({}),a:sha256({}))",
+        encode_hex(&keys[0].public_key().sec(true)),
+        encode_hex(&keys[1].public_key().sec(true)),
+        encode_hex(&sha256(&preimage))
+    ))
+    .unwrap();
+    let witness_script = ms.to_script(0).unwrap().raw_serialize();
+    let script_pubkey = p2wsh_script(&sha256(&witness_script));
+    let z = BigInt::from(0x5eed);
+
+    let fill = |template: Vec<WitnessItem>| -> Vec<Vec<u8>> {
+        let mut witness: Vec<Vec<u8>> = template
+            .iter()
+            .map(|item| match item {
+                WitnessItem::Signature(key) => {
+                    let key = keys.iter().find(|k| *k.public_key() == key.derive(0).unwrap()).unwrap();
+                    let mut sig = key.sign(&z).der();
+                    sig.push(1);
+                    sig
+                }
+                WitnessItem::PublicKey(key) => key.derive(0).unwrap().sec(true),
+                WitnessItem::Preimage(_) => preimage.to_vec(),
+                WitnessItem::NonPreimage => vec![0; 32],
+                WitnessItem::Empty => vec![],
+                WitnessItem::One => vec![1],
+            })
+            .collect();
+        witness.push(witness_script.clone());
+        witness
+    };
+    // The preimage is cheaper than a signature, so it is one of the two
+    let template = ms.satisfaction().unwrap();
+    assert_eq!(template[0], WitnessItem::Preimage(sha256(&preimage).to_vec()));
+    assert!(verify_script(&Script::default(), &script_pubkey, &fill(template), &z, VerifyFlags::STANDARD).unwrap().valid);
+    assert!(!verify_script(&Script::default(), &script_pubkey, &fill(ms.dissatisfaction().unwrap()), &z, VerifyFlags::STANDARD).unwrap().valid);
+    assert_eq!(ms.max_satisfaction_size(), Some(33 + 74 + 74));
+}
@@ -0,0 +1,206 @@
+//! The envelope codec, handshake and header sync on tokio, for embedding
+//! the P2P layer in an async application without a thread per peer.
+//!
+//! `AsyncNode` mirrors `SimpleNode`: it answers version messages and pings
+//! while it waits, and each wait is bounded by the node's timeout. It
+//! needs a runtime with the time driver enabled.
+
+use super::messages::{GetHeadersMessage, HeadersMessage, NetAddress, PingMessage, PongMessage, VerAckMessage, VersionMessage, MAX_HEADERS};
+use super::node::timed_out;
+use super::sync::{HeaderStore, HeaderSync};
+use super::{Message, NetworkEnvelope, MAX_PAYLOAD_SIZE};
+use crate::error::{Error, ErrorKind, Result};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+const HEADER_SIZE: usize = 24;
+
+impl NetworkEnvelope {
+    /// Reads an envelope as `parse` does, without blocking the thread.
+    pub async fn read_async<R: AsyncRead + Unpin>(reader: &mut R, magic: &[u8; 4]) -> Result<NetworkEnvelope> {
+        let mut raw = vec![0u8; HEADER_SIZE];
+        reader.read_exact(&mut raw).await?;
+        // Check the magic and length before waiting for a payload
+        if raw[..4] != magic[..] {
+            return NetworkEnvelope::parse(&mut &raw[..], magic);
+        }
+        let length = u32::from_le_bytes([raw[16], raw[17], raw[18], raw[19]]) as usize;
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("payload of {} bytes", length)));
+        }
+        raw.resize(HEADER_SIZE + length, 0);
+        reader.read_exact(&mut raw[HEADER_SIZE..]).await?;
+        NetworkEnvelope::parse(&mut &raw[..], magic)
+    }
+
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.serialize()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+// Bounds `future` by `timeout`
+async fn within<T, F: Future<Output = Result<T>>>(timeout: Duration, what: &str, future: F) -> Result<T> {
+    tokio::time::timeout(timeout, future).await.unwrap_or_else(|_| Err(timed_out(what)))
+}
+
+pub struct AsyncNode {
+    stream: TcpStream,
+    magic: [u8; 4],
+    pub timeout: Duration,
+    /// The peer's version message, once the handshake is done
+    pub peer_version: Option<VersionMessage>,
+}
+
+impl AsyncNode {
+    /// Connects to `addr` on the network with the given magic bytes. Does
+    /// not handshake.
+    pub async fn connect<A: ToSocketAddrs>(addr: A, magic: [u8; 4], timeout: Duration) -> Result<AsyncNode> {
+        let stream = within(timeout, "a connection", async { Ok(TcpStream::connect(addr).await?) }).await?;
+        Ok(AsyncNode::from_stream(stream, magic, timeout))
+    }
+
+    /// Wraps an open connection.
+    pub fn from_stream(stream: TcpStream, magic: [u8; 4], timeout: Duration) -> AsyncNode {
+        AsyncNode { stream, magic, timeout, peer_version: None }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    pub async fn send<M: Message>(&mut self, message: &M) -> Result<()> {
+        NetworkEnvelope::from_message(self.magic, message).write_async(&mut self.stream).await
+    }
+
+    /// The next message from the peer, whatever it is.
+    pub async fn read(&mut self) -> Result<NetworkEnvelope> {
+        let (timeout, magic) = (self.timeout, self.magic);
+        within(timeout, "a message", NetworkEnvelope::read_async(&mut self.stream, &magic)).await
+    }
+
+    // Answers the messages a peer expects a reply to
+    async fn respond(&mut self, envelope: &NetworkEnvelope) -> Result<()> {
+        if envelope.is::<VersionMessage>() {
+            self.peer_version = Some(envelope.decode()?);
+            self.send(&VerAckMessage).await?;
+        } else if envelope.is::<PingMessage>() {
+            let ping: PingMessage = envelope.decode()?;
+            self.send(&PongMessage { nonce: ping.nonce }).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads until an `M` arrives, answering version messages and pings on
+    /// the way and skipping everything else.
+    pub async fn wait_for<M: Message>(&mut self) -> Result<M> {
+        within(self.timeout, M::COMMAND, async {
+            loop {
+                let envelope = self.read().await?;
+                if envelope.is::<M>() {
+                    return envelope.decode();
+                }
+                self.respond(&envelope).await?;
+            }
+        })
+        .await
+    }
+
+    /// Exchanges version and verack messages with the peer.
+    pub async fn handshake(&mut self) -> Result<&VersionMessage> {
+        let peer = self.peer_addr()?;
+        self.send(&VersionMessage::new(NetAddress::from_socket_addr(0, peer), 0)).await?;
+        within(self.timeout, "the handshake", async {
+            let mut verack = false;
+            while !verack || self.peer_version.is_none() {
+                let envelope = self.read().await?;
+                if envelope.is::<VerAckMessage>() {
+                    verack = true;
+                } else {
+                    self.respond(&envelope).await?;
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(self.peer_version.as_ref().unwrap())
+    }
+}
+
+impl<S: HeaderStore> HeaderSync<S> {
+    /// `sync` over an `AsyncNode`.
+    pub async fn sync_async(&mut self, node: &mut AsyncNode) -> Result<usize> {
+        let mut added = 0;
+        loop {
+            node.send(&GetHeadersMessage::new(self.locator())).await?;
+            let headers = node.wait_for::<HeadersMessage>().await?.headers;
+            self.add_headers(&headers)?;
+            added += headers.len();
+            if headers.len() < MAX_HEADERS {
+                return Ok(added);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use super::node::{SimpleNode, DEFAULT_TIMEOUT};
+#[cfg(test)]
+use super::sync::MemoryHeaderStore;
+#[cfg(test)]
+use crate::block::BlockHeader;
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::params::NetworkParams;
+
+#[test]
+fn async_handshake_and_sync() {
+    let regtest = NetworkParams::regtest();
+    let magic = regtest.magic;
+    let genesis = BlockHeader::parse(&mut &decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let mut next = BlockHeader { prev_block: genesis.hash(), timestamp: genesis.timestamp + 600, ..genesis.clone() };
+    while !next.check_pow() {
+        next.nonce += 1;
+    }
+
+    // The peer is a blocking node on its own thread
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let served = next.clone();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        let version = node.wait_for::<VersionMessage>().unwrap();
+        node.send(&PingMessage { nonce: 3 }).unwrap();
+        node.send(&VersionMessage::new(version.sender, 1)).unwrap();
+        node.send(&VerAckMessage).unwrap();
+        assert_eq!(node.wait_for::<PongMessage>().unwrap().nonce, 3);
+        node.wait_for::<GetHeadersMessage>().unwrap();
+        node.send(&HeadersMessage { headers: vec![served] }).unwrap();
+        let _ = node.read();
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let mut node = AsyncNode::connect(addr, magic, DEFAULT_TIMEOUT).await.unwrap();
+        assert_eq!(node.handshake().await.unwrap().latest_block, 1);
+        let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis), regtest).unwrap();
+        assert_eq!(sync.sync_async(&mut node).await.unwrap(), 1);
+        assert_eq!(sync.store.tip(), (1, next));
+        node.timeout = Duration::from_millis(50);
+        assert_eq!(node.wait_for::<VerAckMessage>().await.unwrap_err().kind(), ErrorKind::Io);
+
+        // The codec on its own
+        let envelope = NetworkEnvelope::from_message(magic, &PingMessage { nonce: 9 });
+        let mut raw = vec![];
+        envelope.write_async(&mut raw).await.unwrap();
+        assert_eq!(NetworkEnvelope::read_async(&mut &raw[..], &magic).await.unwrap(), envelope);
+        let err = NetworkEnvelope::read_async(&mut &raw[..], &NetworkParams::mainnet().magic).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownNetwork);
+    });
+    peer.join().unwrap();
+}
@@ -0,0 +1,301 @@
+//! ChaCha20, Poly1305 and their AEAD (RFC 8439), plus the rekeying
+//! wrappers BIP324 encrypts packets with.
+
+use crate::error::{Error, ErrorKind, Result};
+use std::convert::TryInto;
+
+/// Size of a Poly1305 tag.
+pub const TAG_SIZE: usize = 16;
+/// Packets or length fields between rekeys.
+pub const REKEY_INTERVAL: u64 = 224;
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn le_words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0u32; N];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// One 64 byte block of keystream.
+pub fn chacha20_block(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(&le_words::<8>(key));
+    state[12] = counter;
+    state[13..].copy_from_slice(&le_words::<3>(nonce));
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut block = [0u8; 64];
+    for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+/// Encrypts or decrypts `data` in place, starting at block `counter`.
+pub fn chacha20_xor(key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, nonce, counter.wrapping_add(i as u32));
+        for (byte, k) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= k;
+        }
+    }
+}
+
+/// The Poly1305 tag of `message` under the one time `key`, with the
+/// accumulator in 44, 44 and 42 bit limbs.
+pub fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; TAG_SIZE] {
+    const MASK44: u64 = (1 << 44) - 1;
+    const MASK42: u64 = (1 << 42) - 1;
+    let word = |bytes: &[u8], i: usize| u64::from_le_bytes(bytes[8 * i..8 * i + 8].try_into().unwrap());
+    let (t0, t1) = (word(key, 0), word(key, 1));
+    let r0 = t0 & 0xffc_0fff_ffff;
+    let r1 = ((t0 >> 44) | (t1 << 20)) & 0xfff_ffc0_ffff;
+    let r2 = (t1 >> 24) & 0x00f_ffff_fc0f;
+    let (s1, s2) = (r1 * 20, r2 * 20);
+    let (mut h0, mut h1, mut h2) = (0u64, 0u64, 0u64);
+
+    for chunk in message.chunks(16) {
+        let mut block = [0u8; 16];
+        block[..chunk.len()].copy_from_slice(chunk);
+        // The 2^128 bit for a full block, a trailing 1 byte otherwise
+        let high_bit = if chunk.len() == 16 {
+            1 << 40
+        } else {
+            block[chunk.len()] = 1;
+            0
+        };
+        let (t0, t1) = (word(&block, 0), word(&block, 1));
+        h0 += t0 & MASK44;
+        h1 += ((t0 >> 44) | (t1 << 20)) & MASK44;
+        h2 += ((t1 >> 24) & MASK42) | high_bit;
+
+        let mul = |a: u64, b: u64| a as u128 * b as u128;
+        let d0 = mul(h0, r0) + mul(h1, s2) + mul(h2, s1);
+        let mut d1 = mul(h0, r1) + mul(h1, r0) + mul(h2, s2);
+        let mut d2 = mul(h0, r2) + mul(h1, r1) + mul(h2, r0);
+        d1 += d0 >> 44;
+        h0 = d0 as u64 & MASK44;
+        d2 += d1 >> 44;
+        h1 = d1 as u64 & MASK44;
+        h2 = d2 as u64 & MASK42;
+        h0 += (d2 >> 42) as u64 * 5;
+        h1 += h0 >> 44;
+        h0 &= MASK44;
+    }
+
+    // Fully carry h, then subtract p = 2^130 - 5 if h >= p
+    for _ in 0..2 {
+        h2 += h1 >> 44;
+        h1 &= MASK44;
+        h0 += (h2 >> 42) * 5;
+        h2 &= MASK42;
+        h1 += h0 >> 44;
+        h0 &= MASK44;
+    }
+    let mut g0 = h0 + 5;
+    let mut g1 = h1 + (g0 >> 44);
+    g0 &= MASK44;
+    let g2 = (h2 + (g1 >> 44)).wrapping_sub(1 << 42);
+    g1 &= MASK44;
+    let keep_g = (g2 >> 63).wrapping_sub(1);
+    h0 = (h0 & !keep_g) | (g0 & keep_g);
+    h1 = (h1 & !keep_g) | (g1 & keep_g);
+    h2 = (h2 & !keep_g) | (g2 & keep_g);
+
+    // Add s mod 2^128
+    let (t0, t1) = (word(key, 2), word(key, 3));
+    h0 += t0 & MASK44;
+    h1 += (((t0 >> 44) | (t1 << 20)) & MASK44) + (h0 >> 44);
+    h0 &= MASK44;
+    h2 += ((t1 >> 24) & MASK42) + (h1 >> 44);
+    h1 &= MASK44;
+    h2 &= MASK42;
+    let mut tag = [0u8; TAG_SIZE];
+    tag[..8].copy_from_slice(&(h0 | (h1 << 44)).to_le_bytes());
+    tag[8..].copy_from_slice(&((h1 >> 20) | (h2 << 24)).to_le_bytes());
+    tag
+}
+
+fn aead_tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+    let mut one_time_key = [0u8; 32];
+    one_time_key.copy_from_slice(&chacha20_block(key, nonce, 0)[..32]);
+    let pad = |len: usize| vec![0u8; (16 - len % 16) % 16];
+    let mut mac_data = aad.to_vec();
+    mac_data.extend(pad(aad.len()));
+    mac_data.extend_from_slice(ciphertext);
+    mac_data.extend(pad(ciphertext.len()));
+    mac_data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    mac_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly1305(&one_time_key, &mac_data)
+}
+
+/// ChaCha20-Poly1305: the ciphertext with the tag after it.
+pub fn aead_encrypt(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = plaintext.to_vec();
+    chacha20_xor(key, nonce, 1, &mut sealed);
+    let tag = aead_tag(key, nonce, aad, &sealed);
+    sealed.extend_from_slice(&tag);
+    sealed
+}
+
+/// Checks the tag on what `aead_encrypt` returned and decrypts it.
+pub fn aead_decrypt(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < TAG_SIZE {
+        return Err(Error::new(ErrorKind::InvalidEncoding, "ciphertext shorter than its tag"));
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+    let expected = aead_tag(key, nonce, aad, ciphertext);
+    // Compare without stopping at the first difference
+    if expected.iter().zip(tag.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+        return Err(Error::new(ErrorKind::InvalidSignature, "authentication tag mismatch"));
+    }
+    let mut plaintext = ciphertext.to_vec();
+    chacha20_xor(key, nonce, 1, &mut plaintext);
+    Ok(plaintext)
+}
+
+fn nonce(first: u32, second: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&first.to_le_bytes());
+    nonce[4..].copy_from_slice(&second.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20 as a stream over many chunks, taking a new key from its own
+/// keystream every `REKEY_INTERVAL` chunks. BIP324 encrypts packet
+/// lengths with it.
+pub struct FsChaCha20 {
+    key: [u8; 32],
+    chunks: u64,
+    block: u32,
+    keystream: Vec<u8>,
+}
+
+impl FsChaCha20 {
+    pub fn new(key: [u8; 32]) -> FsChaCha20 {
+        FsChaCha20 { key, chunks: 0, block: 0, keystream: vec![] }
+    }
+
+    fn keystream(&mut self, len: usize) -> Vec<u8> {
+        while self.keystream.len() < len {
+            let nonce = nonce(0, self.chunks / REKEY_INTERVAL);
+            self.keystream.extend_from_slice(&chacha20_block(&self.key, &nonce, self.block));
+            self.block += 1;
+        }
+        self.keystream.drain(..len).collect()
+    }
+
+    /// Encrypts or decrypts the next chunk.
+    pub fn crypt(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let result = chunk.iter().zip(self.keystream(chunk.len())).map(|(byte, k)| byte ^ k).collect();
+        self.chunks += 1;
+        if self.chunks.is_multiple_of(REKEY_INTERVAL) {
+            let key = self.keystream(32);
+            self.key.copy_from_slice(&key);
+            self.block = 0;
+            self.keystream.clear();
+        }
+        result
+    }
+}
+
+/// ChaCha20-Poly1305 with a nonce from a packet counter, taking a new key
+/// every `REKEY_INTERVAL` packets. BIP324 encrypts packet contents with it.
+pub struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packets: u64,
+}
+
+impl FsChaCha20Poly1305 {
+    pub fn new(key: [u8; 32]) -> FsChaCha20Poly1305 {
+        FsChaCha20Poly1305 { key, packets: 0 }
+    }
+
+    fn nonce(&self) -> [u8; 12] {
+        nonce((self.packets % REKEY_INTERVAL) as u32, self.packets / REKEY_INTERVAL)
+    }
+
+    fn advance(&mut self) {
+        self.packets += 1;
+        if self.packets.is_multiple_of(REKEY_INTERVAL) {
+            let rekey_nonce = nonce(u32::MAX, (self.packets - 1) / REKEY_INTERVAL);
+            let key = aead_encrypt(&self.key, &rekey_nonce, &[], &[0; 32]);
+            self.key.copy_from_slice(&key[..32]);
+        }
+    }
+
+    pub fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let sealed = aead_encrypt(&self.key, &self.nonce(), aad, plaintext);
+        self.advance();
+        sealed
+    }
+
+    /// Decrypts the next packet. A packet that fails authentication still
+    /// uses up its nonce.
+    pub fn decrypt(&mut self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let result = aead_decrypt(&self.key, &self.nonce(), aad, sealed);
+        self.advance();
+        result
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+
+#[test]
+fn chacha20_poly1305() {
+    // RFC 8439's AEAD example (section 2.8.2)
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&decode_hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").unwrap());
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&decode_hex("070000004041424344454647").unwrap());
+    let aad = decode_hex("50515253c0c1c2c3c4c5c6c7").unwrap();
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let sealed = aead_encrypt(&key, &nonce, &aad, plaintext);
+    assert_eq!(encode_hex(&sealed[sealed.len() - TAG_SIZE..]), "1ae10b594f09e26a7e902ecbd0600691");
+    assert_eq!(encode_hex(&sealed[..16]), "d31a8d34648e60db7b86afbc53ef7ec2");
+    assert_eq!(aead_decrypt(&key, &nonce, &aad, &sealed).unwrap(), plaintext.to_vec());
+    let mut tampered = sealed.clone();
+    tampered[0] ^= 1;
+    assert_eq!(aead_decrypt(&key, &nonce, &aad, &tampered).unwrap_err().kind(), ErrorKind::InvalidSignature);
+
+    // Poly1305 on its own (section 2.5.2)
+    key.copy_from_slice(&decode_hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").unwrap());
+    assert_eq!(encode_hex(&poly1305(&key, b"Cryptographic Forum Research Group")), "a8061dc1305136c6c22b8baf0c0127a9");
+
+    // Both rekeying ciphers stay in step with a copy across a rekey
+    let (mut sender, mut receiver) = (FsChaCha20Poly1305::new([3; 32]), FsChaCha20Poly1305::new([3; 32]));
+    let (mut length_out, mut length_in) = (FsChaCha20::new([4; 32]), FsChaCha20::new([4; 32]));
+    for i in 0..REKEY_INTERVAL + 2 {
+        let message = i.to_le_bytes();
+        let sealed = sender.encrypt(b"", &message);
+        assert_eq!(receiver.decrypt(b"", &sealed).unwrap(), message);
+        assert_eq!(length_in.crypt(&length_out.crypt(&message[..3])), message[..3]);
+    }
+    assert_ne!(sender.key, [3; 32]);
+    assert_ne!(length_out.key, [4; 32]);
+}
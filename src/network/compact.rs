@@ -0,0 +1,430 @@
+//! Compact block relay (BIP152, version 2).
+//!
+//! A compact block is the header, a 6 byte short ID per transaction and a
+//! few transactions in full, usually just the coinbase. The receiver finds
+//! the rest in its mempool by short ID and asks for whatever is missing
+//! with getblocktxn, so a block costs a few kilobytes instead of megabytes.
+
+use super::messages::{read_hash, write_hash, InvType, Inventory};
+use super::node::SimpleNode;
+use super::Message;
+use crate::block::{Block, BlockHeader};
+use crate::encoding::util::{encode_hex, read_bytes, read_u64_le};
+use crate::encoding::varint::{encode_varint, read_varint_len};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{sha256, siphash24};
+use crate::params::Network;
+use crate::tx::Tx;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::Read;
+
+/// The compact block version whose short IDs hash wtxids.
+pub const COMPACT_VERSION: u64 = 2;
+pub const SHORT_ID_SIZE: usize = 6;
+// Transaction indexes are 16 bit in Bitcoin Core
+const MAX_INDEX: usize = u16::MAX as usize;
+
+/// Tells the peer which compact block version we speak and whether to
+/// announce new blocks to us as compact blocks straight away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendCmpctMessage {
+    pub announce: bool,
+    pub version: u64,
+}
+
+impl Message for SendCmpctMessage {
+    const COMMAND: &'static str = "sendcmpct";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.announce as u8];
+        result.extend_from_slice(&self.version.to_le_bytes());
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<SendCmpctMessage> {
+        let announce = match read_bytes(reader, 1)?[0] {
+            0 => false,
+            1 => true,
+            flag => return Err(Error::new(ErrorKind::InvalidEncoding, format!("sendcmpct announce flag {}", flag))),
+        };
+        Ok(SendCmpctMessage { announce, version: read_u64_le(reader)? })
+    }
+}
+
+/// The 6 byte short ID of the transaction with witness hash `wtxid`
+/// (display order) under the block's SipHash keys.
+pub fn short_id(keys: (u64, u64), wtxid: &[u8; 32]) -> u64 {
+    let mut internal = *wtxid;
+    internal.reverse();
+    siphash24(keys.0, keys.1, &internal) & 0xffff_ffff_ffff
+}
+
+// Indexes go on the wire as the gap from the one before, less one
+fn write_indexes(result: &mut Vec<u8>, indexes: impl Iterator<Item = usize>) {
+    let mut next = 0;
+    for index in indexes {
+        encode_varint(result, (index - next) as u64).unwrap();
+        next = index + 1;
+    }
+}
+
+fn read_index<R: Read>(reader: &mut R, next: &mut usize) -> Result<usize> {
+    let index = read_varint_len(reader)?.checked_add(*next).filter(|index| *index <= MAX_INDEX);
+    let index = index.ok_or_else(|| Error::new(ErrorKind::OutOfRange, "transaction index over 65535"))?;
+    *next = index + 1;
+    Ok(index)
+}
+
+/// A transaction sent in full in a compact block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefilledTx {
+    /// Its position in the block
+    pub index: usize,
+    pub tx: Tx,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmpctBlockMessage {
+    pub header: BlockHeader,
+    /// Salts the short IDs, so they can't be ground to collide in advance
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    /// In block order
+    pub prefilled: Vec<PrefilledTx>,
+}
+
+impl CmpctBlockMessage {
+    /// `block` as a compact block with only the coinbase in full.
+    pub fn from_block(block: &Block, nonce: u64) -> CmpctBlockMessage {
+        let mut message = CmpctBlockMessage { header: block.header.clone(), nonce, short_ids: vec![], prefilled: vec![] };
+        let keys = message.keys();
+        for (index, tx) in block.txs.iter().enumerate() {
+            if index == 0 {
+                message.prefilled.push(PrefilledTx { index, tx: tx.clone() });
+            } else {
+                message.short_ids.push(short_id(keys, &tx.witness_hash()));
+            }
+        }
+        message
+    }
+
+    /// The SipHash keys: the first two little-endian words of the SHA256
+    /// of the header and nonce.
+    pub fn keys(&self) -> (u64, u64) {
+        let mut data = self.header.serialize().to_vec();
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        let hash = sha256(&data);
+        (u64::from_le_bytes(hash[..8].try_into().unwrap()), u64::from_le_bytes(hash[8..16].try_into().unwrap()))
+    }
+
+    /// How many transactions the block has.
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+}
+
+impl Message for CmpctBlockMessage {
+    const COMMAND: &'static str = "cmpctblock";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize().to_vec();
+        result.extend_from_slice(&self.nonce.to_le_bytes());
+        encode_varint(&mut result, self.short_ids.len() as u64).unwrap();
+        for id in self.short_ids.iter() {
+            result.extend_from_slice(&id.to_le_bytes()[..SHORT_ID_SIZE]);
+        }
+        encode_varint(&mut result, self.prefilled.len() as u64).unwrap();
+        let mut next = 0;
+        for prefilled in self.prefilled.iter() {
+            encode_varint(&mut result, (prefilled.index - next) as u64).unwrap();
+            next = prefilled.index + 1;
+            result.extend_from_slice(&prefilled.tx.serialize());
+        }
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<CmpctBlockMessage> {
+        let header = BlockHeader::parse(reader)?;
+        let nonce = read_u64_le(reader)?;
+        let short_ids = (0..read_varint_len(reader)?)
+            .map(|_| {
+                let mut id = [0u8; 8];
+                id[..SHORT_ID_SIZE].copy_from_slice(&read_bytes(reader, SHORT_ID_SIZE)?);
+                Ok(u64::from_le_bytes(id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut next = 0;
+        let prefilled = (0..read_varint_len(reader)?)
+            .map(|_| Ok(PrefilledTx { index: read_index(reader, &mut next)?, tx: Tx::parse(reader, Network::Mainnet)? }))
+            .collect::<Result<Vec<_>>>()?;
+        let message = CmpctBlockMessage { header, nonce, short_ids, prefilled };
+        if message.prefilled.last().is_some_and(|last| last.index >= message.tx_count()) {
+            return Err(Error::new(ErrorKind::OutOfRange, "prefilled transaction past the end of the block"));
+        }
+        Ok(message)
+    }
+}
+
+/// Asks for the transactions at `indexes` in a block we got as a compact
+/// block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetBlockTxnMessage {
+    /// Display order
+    pub block_hash: [u8; 32],
+    /// Ascending
+    pub indexes: Vec<usize>,
+}
+
+impl Message for GetBlockTxnMessage {
+    const COMMAND: &'static str = "getblocktxn";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        write_hash(&mut result, &self.block_hash);
+        encode_varint(&mut result, self.indexes.len() as u64).unwrap();
+        write_indexes(&mut result, self.indexes.iter().copied());
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<GetBlockTxnMessage> {
+        let block_hash = read_hash(reader)?;
+        let mut next = 0;
+        let indexes = (0..read_varint_len(reader)?).map(|_| read_index(reader, &mut next)).collect::<Result<Vec<_>>>()?;
+        Ok(GetBlockTxnMessage { block_hash, indexes })
+    }
+}
+
+/// Answers a getblocktxn with the transactions asked for, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTxnMessage {
+    pub block_hash: [u8; 32],
+    pub txs: Vec<Tx>,
+}
+
+impl BlockTxnMessage {
+    /// The answer to `request` from `block`.
+    pub fn answer(block: &Block, request: &GetBlockTxnMessage) -> Result<BlockTxnMessage> {
+        if block.header.hash() != request.block_hash {
+            return Err(Error::new(ErrorKind::Fetch, format!("getblocktxn for {} answered from another block", encode_hex(&request.block_hash))));
+        }
+        let txs = request
+            .indexes
+            .iter()
+            .map(|index| block.txs.get(*index).cloned().ok_or_else(|| Error::new(ErrorKind::OutOfRange, format!("transaction {} of {}", index, block.txs.len()))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(BlockTxnMessage { block_hash: request.block_hash, txs })
+    }
+}
+
+impl Message for BlockTxnMessage {
+    const COMMAND: &'static str = "blocktxn";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        write_hash(&mut result, &self.block_hash);
+        encode_varint(&mut result, self.txs.len() as u64).unwrap();
+        for tx in self.txs.iter() {
+            result.extend_from_slice(&tx.serialize());
+        }
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<BlockTxnMessage> {
+        let block_hash = read_hash(reader)?;
+        let txs = (0..read_varint_len(reader)?).map(|_| Tx::parse(reader, Network::Mainnet)).collect::<Result<Vec<_>>>()?;
+        Ok(BlockTxnMessage { block_hash, txs })
+    }
+}
+
+/// A compact block with the transactions found so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    txs: Vec<Option<Tx>>,
+}
+
+impl PartialBlock {
+    /// Places the prefilled transactions, then fills the other slots from
+    /// `mempool` by short ID. A short ID two mempool transactions share is
+    /// left missing. Fails if the compact block's own short IDs collide.
+    pub fn new<'a, I: IntoIterator<Item = &'a Tx>>(compact: &CmpctBlockMessage, mempool: I) -> Result<PartialBlock> {
+        let mut txs: Vec<Option<Tx>> = vec![None; compact.tx_count()];
+        for prefilled in compact.prefilled.iter() {
+            let slot = txs.get_mut(prefilled.index).ok_or_else(|| Error::new(ErrorKind::OutOfRange, format!("prefilled transaction {} of {}", prefilled.index, compact.tx_count())))?;
+            *slot = Some(prefilled.tx.clone());
+        }
+        // Slot of each short ID, in the order the empty slots come
+        let mut slots = HashMap::new();
+        let empty = txs.iter().enumerate().filter(|(_, tx)| tx.is_none()).map(|(index, _)| index);
+        for (id, index) in compact.short_ids.iter().zip(empty) {
+            if slots.insert(*id, index).is_some() {
+                return Err(Error::new(ErrorKind::InvalidEncoding, format!("short ID {:012x} appears twice", id)));
+            }
+        }
+        let keys = compact.keys();
+        let mut collided = vec![];
+        for tx in mempool {
+            if let Some(index) = slots.get(&short_id(keys, &tx.witness_hash())) {
+                match &txs[*index] {
+                    Some(found) if found != tx => collided.push(*index),
+                    _ => txs[*index] = Some(tx.clone()),
+                }
+            }
+        }
+        for index in collided {
+            txs[index] = None;
+        }
+        Ok(PartialBlock { header: compact.header.clone(), txs })
+    }
+
+    /// Indexes of the transactions still missing.
+    pub fn missing(&self) -> Vec<usize> {
+        self.txs.iter().enumerate().filter(|(_, tx)| tx.is_none()).map(|(index, _)| index).collect()
+    }
+
+    /// The getblocktxn for what's missing.
+    pub fn request(&self) -> GetBlockTxnMessage {
+        GetBlockTxnMessage { block_hash: self.header.hash(), indexes: self.missing() }
+    }
+
+    /// Fills the missing slots, in order, from the answer to `request`.
+    pub fn fill(&mut self, answer: &BlockTxnMessage) -> Result<()> {
+        let missing = self.missing();
+        if answer.block_hash != self.header.hash() || answer.txs.len() != missing.len() {
+            return Err(Error::new(ErrorKind::Fetch, format!("blocktxn with {} transactions for {} missing", answer.txs.len(), missing.len())));
+        }
+        for (index, tx) in missing.into_iter().zip(answer.txs.iter()) {
+            self.txs[index] = Some(tx.clone());
+        }
+        Ok(())
+    }
+
+    /// The whole block, once nothing is missing and the transactions match
+    /// the merkle root. A mismatch means a short ID picked the wrong
+    /// mempool transaction; ask for the full block then.
+    pub fn to_block(&self) -> Result<Block> {
+        let txs = self.txs.iter().cloned().collect::<Option<Vec<Tx>>>();
+        let txs = txs.ok_or_else(|| Error::new(ErrorKind::Fetch, format!("{} transactions still missing", self.missing().len())))?;
+        let block = Block { header: self.header.clone(), txs };
+        if !block.validate_merkle_root() {
+            return Err(Error::new(ErrorKind::Fetch, format!("reconstructed block {} has a bad merkle root", block.header.id())));
+        }
+        Ok(block)
+    }
+}
+
+impl SimpleNode {
+    /// Downloads the block with hash `hash` as a compact block, taking
+    /// what it can from `mempool` and asking the peer for the rest. Falls
+    /// back to the full block when reconstruction goes wrong.
+    pub fn get_compact_block<'a, I: IntoIterator<Item = &'a Tx>>(&mut self, hash: &[u8; 32], mempool: I) -> Result<Block> {
+        self.get_data(vec![Inventory::new(InvType::CompactBlock, *hash)])?;
+        let compact = self.wait_for::<CmpctBlockMessage>()?;
+        if compact.header.hash() != *hash {
+            return Err(Error::new(ErrorKind::Fetch, format!("got compact block {} instead of {}", compact.header.id(), encode_hex(hash))));
+        }
+        let mut partial = match PartialBlock::new(&compact, mempool) {
+            Ok(partial) => partial,
+            Err(_) => return self.get_block(hash),
+        };
+        if !partial.missing().is_empty() {
+            self.send(&partial.request())?;
+            partial.fill(&self.wait_for::<BlockTxnMessage>()?)?;
+        }
+        partial.to_block().or_else(|_| self.get_block(hash))
+    }
+
+    /// Tells the peer we speak compact blocks, and whether it should
+    /// announce blocks to us with them.
+    pub fn send_cmpct(&mut self, announce: bool) -> Result<()> {
+        self.send(&SendCmpctMessage { announce, version: COMPACT_VERSION })
+    }
+}
+
+#[cfg(test)]
+use super::messages::{BlockMessage, GetDataMessage};
+#[cfg(test)]
+use super::node::DEFAULT_TIMEOUT;
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn, TxOut};
+
+#[cfg(test)]
+fn test_block(count: u8) -> Block {
+    let txs: Vec<Tx> = (0..count)
+        .map(|i| {
+            let mut tx = Tx::new(2, vec![TxIn::new([i; 32], 0)], vec![TxOut::new(1000 + i as u64, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+            tx.tx_ins[0].witness = vec![vec![i]];
+            tx
+        })
+        .collect();
+    let header = BlockHeader { version: 1, prev_block: [0; 32], merkle_root: [0; 32], timestamp: 0, bits: 0x207f_ffff, nonce: 0 };
+    let mut block = Block { header, txs };
+    block.header.merkle_root = block.merkle_root();
+    block
+}
+
+#[test]
+fn compact_block_messages() {
+    let block = test_block(4);
+    let compact = CmpctBlockMessage::from_block(&block, 42);
+    assert_eq!((compact.short_ids.len(), compact.prefilled.len(), compact.tx_count()), (3, 1, 4));
+    assert!(compact.short_ids.iter().all(|id| *id < 1 << 48));
+    let raw = compact.serialize();
+    assert_eq!(raw.len(), 80 + 8 + 1 + 3 * SHORT_ID_SIZE + 1 + 1 + block.txs[0].serialize().len());
+    assert_eq!(CmpctBlockMessage::parse(&mut &raw[..]).unwrap(), compact);
+
+    // Indexes are differential: 1, 3, 4 go as 1, 1, 0
+    let request = GetBlockTxnMessage { block_hash: block.header.hash(), indexes: vec![1, 3, 4] };
+    assert_eq!(encode_hex(&request.serialize()[32..]), "03010100");
+    assert_eq!(GetBlockTxnMessage::parse(&mut &request.serialize()[..]).unwrap(), request);
+    let sendcmpct = SendCmpctMessage { announce: true, version: COMPACT_VERSION };
+    assert_eq!(encode_hex(&sendcmpct.serialize()), "010200000000000000");
+    assert_eq!(SendCmpctMessage::parse(&mut &sendcmpct.serialize()[..]).unwrap(), sendcmpct);
+
+    // With the middle transaction missing from the mempool
+    let mempool = [block.txs[1].clone(), block.txs[3].clone(), test_block(6).txs[5].clone()];
+    let mut partial = PartialBlock::new(&compact, mempool.iter()).unwrap();
+    assert_eq!(partial.missing(), vec![2]);
+    assert_eq!(partial.to_block().unwrap_err().kind(), ErrorKind::Fetch);
+    let answer = BlockTxnMessage::answer(&block, &partial.request()).unwrap();
+    assert_eq!(BlockTxnMessage::parse(&mut &answer.serialize()[..]).unwrap(), answer);
+    partial.fill(&answer).unwrap();
+    assert_eq!(partial.to_block().unwrap(), block);
+}
+
+#[test]
+fn compact_block_download() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let block = test_block(5);
+    let hash = block.header.hash();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let served = block.clone();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        assert!(node.wait_for::<SendCmpctMessage>().unwrap().announce);
+        assert_eq!(node.wait_for::<GetDataMessage>().unwrap().inventory[0].kind, InvType::CompactBlock);
+        node.send(&CmpctBlockMessage::from_block(&served, 7)).unwrap();
+        let request = node.wait_for::<GetBlockTxnMessage>().unwrap();
+        assert_eq!(request.indexes, vec![2, 4]);
+        node.send(&BlockTxnMessage::answer(&served, &request).unwrap()).unwrap();
+
+        // A wrong transaction under a right short ID can't be spotted
+        // until the merkle root, so the second download ends in a getdata
+        // for the whole block
+        node.wait_for::<GetDataMessage>().unwrap();
+        let mut compact = CmpctBlockMessage::from_block(&served, 7);
+        compact.prefilled[0].tx.locktime = LockTime::from_consensus(1);
+        node.send(&compact).unwrap();
+        assert_eq!(node.wait_for::<GetDataMessage>().unwrap().inventory[0].kind, InvType::WitnessBlock);
+        node.send(&BlockMessage(served)).unwrap();
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    node.send_cmpct(true).unwrap();
+    let mempool = vec![block.txs[1].clone(), block.txs[3].clone()];
+    assert_eq!(node.get_compact_block(&hash, &mempool).unwrap(), block);
+    assert_eq!(node.get_compact_block(&hash, &block.txs).unwrap(), block);
+    peer.join().unwrap();
+}
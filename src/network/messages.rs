@@ -0,0 +1,956 @@
+//! Typed payloads for the messages the crate speaks.
+
+use super::Message;
+use crate::block::merkleblock::bytes_to_bit_field;
+use crate::block::{Block, BlockHeader, MerkleBlock};
+use crate::bloom::BloomFilter;
+use crate::encoding::bech32::convert_bits;
+use crate::encoding::util::{read_bytes, read_u32_le, read_u64_le};
+use crate::encoding::varint::{encode_varint, read_varint, read_varint_len};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::sha3_256;
+use crate::params::Network;
+use crate::tx::Tx;
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The protocol version we advertise.
+pub const PROTOCOL_VERSION: u32 = 70015;
+/// Our user agent, as BIP14 describes.
+pub const USER_AGENT: &str = "/prog_btc_book:0.1/";
+/// Most headers a headers message may carry.
+pub const MAX_HEADERS: usize = 2000;
+/// Most entries an inv or getdata message may carry.
+pub const MAX_INV_SIZE: usize = 50_000;
+/// Most addresses an addr or addrv2 message may carry.
+pub const MAX_ADDR: usize = 1000;
+/// Service bit for full nodes serving the whole chain.
+pub const NODE_NETWORK: u64 = 1;
+/// Service bit for nodes serving witness data.
+pub const NODE_WITNESS: u64 = 1 << 3;
+
+/// A random 64 bit value, for nonces.
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    hasher.finish()
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// A hash in internal order on the wire, display order in memory
+pub(super) fn read_hash<R: Read>(reader: &mut R) -> Result<[u8; 32]> {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&read_bytes(reader, 32)?);
+    hash.reverse();
+    Ok(hash)
+}
+
+pub(super) fn write_hash(result: &mut Vec<u8>, hash: &[u8; 32]) {
+    result.extend(hash.iter().rev());
+}
+
+fn read_u16_be<R: Read>(reader: &mut R) -> Result<u16> {
+    let bytes = read_bytes(reader, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_bool<R: Read>(reader: &mut R) -> Result<bool> {
+    match read_bytes(reader, 1)?[0] {
+        0 => Ok(false),
+        1 => Ok(true),
+        byte => Err(Error::new(ErrorKind::InvalidEncoding, format!("bool {:#04x}", byte))),
+    }
+}
+
+/// A peer's address as the version message carries it: IPv6, or IPv4
+/// mapped into IPv6, with the port big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetAddress {
+    pub services: u64,
+    pub ip: [u8; 16],
+    pub port: u16,
+}
+
+impl NetAddress {
+    pub fn from_ipv4(services: u64, ip: [u8; 4], port: u16) -> NetAddress {
+        let mut mapped = [0u8; 16];
+        mapped[10..12].copy_from_slice(&[0xff, 0xff]);
+        mapped[12..].copy_from_slice(&ip);
+        NetAddress { services, ip: mapped, port }
+    }
+
+    pub fn from_socket_addr(services: u64, addr: SocketAddr) -> NetAddress {
+        match addr {
+            SocketAddr::V4(addr) => NetAddress::from_ipv4(services, addr.ip().octets(), addr.port()),
+            SocketAddr::V6(addr) => NetAddress { services, ip: addr.ip().octets(), port: addr.port() },
+        }
+    }
+
+    /// The address to connect to, IPv4 if it's mapped.
+    pub fn socket_addr(&self) -> SocketAddr {
+        let ip = Ipv6Addr::from(self.ip);
+        match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), self.port),
+            None => SocketAddr::new(IpAddr::V6(ip), self.port),
+        }
+    }
+
+    pub fn parse<R: Read>(reader: &mut R) -> Result<NetAddress> {
+        let services = read_u64_le(reader)?;
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&read_bytes(reader, 16)?);
+        let port = read_u16_be(reader)?;
+        Ok(NetAddress { services, ip, port })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.services.to_le_bytes().to_vec();
+        result.extend_from_slice(&self.ip);
+        result.extend_from_slice(&self.port.to_be_bytes());
+        result
+    }
+}
+
+/// The first message each side sends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: u32,
+    pub services: u64,
+    pub timestamp: u64,
+    pub receiver: NetAddress,
+    pub sender: NetAddress,
+    /// Lets a node notice it connected to itself
+    pub nonce: u64,
+    pub user_agent: String,
+    /// Height of the sender's best block
+    pub latest_block: u32,
+    /// Whether the peer should announce transactions before a filterload
+    pub relay: bool,
+}
+
+impl VersionMessage {
+    /// Our version message to a peer at `receiver`, with a fresh nonce.
+    pub fn new(receiver: NetAddress, latest_block: u32) -> VersionMessage {
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: 0,
+            timestamp: unix_time(),
+            receiver,
+            sender: NetAddress::from_ipv4(0, [0; 4], receiver.port),
+            nonce: random_u64(),
+            user_agent: USER_AGENT.to_string(),
+            latest_block,
+            relay: false,
+        }
+    }
+}
+
+impl Message for VersionMessage {
+    const COMMAND: &'static str = "version";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend_from_slice(&self.services.to_le_bytes());
+        result.extend_from_slice(&self.timestamp.to_le_bytes());
+        result.extend_from_slice(&self.receiver.serialize());
+        result.extend_from_slice(&self.sender.serialize());
+        result.extend_from_slice(&self.nonce.to_le_bytes());
+        write_var_str(&mut result, &self.user_agent);
+        result.extend_from_slice(&self.latest_block.to_le_bytes());
+        result.push(self.relay as u8);
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<VersionMessage> {
+        let version = read_u32_le(reader)?;
+        let services = read_u64_le(reader)?;
+        let timestamp = read_u64_le(reader)?;
+        let receiver = NetAddress::parse(reader)?;
+        let sender = NetAddress::parse(reader)?;
+        let nonce = read_u64_le(reader)?;
+        let user_agent = read_var_str(reader)?;
+        let latest_block = read_u32_le(reader)?;
+        // BIP37: absent before protocol version 70001
+        let mut byte = [0u8];
+        let relay = reader.read(&mut byte)? == 0 || read_bool(&mut &byte[..])?;
+        Ok(VersionMessage { version, services, timestamp, receiver, sender, nonce, user_agent, latest_block, relay })
+    }
+}
+
+/// Acknowledges the peer's version message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerAckMessage;
+
+impl Message for VerAckMessage {
+    const COMMAND: &'static str = "verack";
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn parse<R: Read>(_reader: &mut R) -> Result<VerAckMessage> {
+        Ok(VerAckMessage)
+    }
+}
+
+/// Asks for the headers after the first locator hash the peer knows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    /// Block hashes in display order, newest first
+    pub locator: Vec<[u8; 32]>,
+    /// Last header wanted, or zeros for as many as fit in one message
+    pub stop_hash: [u8; 32],
+}
+
+impl GetHeadersMessage {
+    pub fn new(locator: Vec<[u8; 32]>) -> GetHeadersMessage {
+        GetHeadersMessage { version: PROTOCOL_VERSION, locator, stop_hash: [0; 32] }
+    }
+}
+
+impl Message for GetHeadersMessage {
+    const COMMAND: &'static str = "getheaders";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        encode_varint(&mut result, self.locator.len() as u64).unwrap();
+        for hash in self.locator.iter() {
+            write_hash(&mut result, hash);
+        }
+        write_hash(&mut result, &self.stop_hash);
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<GetHeadersMessage> {
+        let version = read_u32_le(reader)?;
+        let len = read_varint_len(reader)?;
+        let locator = (0..len).map(|_| read_hash(reader)).collect::<Result<Vec<_>>>()?;
+        let stop_hash = read_hash(reader)?;
+        Ok(GetHeadersMessage { version, locator, stop_hash })
+    }
+}
+
+/// Up to `MAX_HEADERS` consecutive headers, each followed by a zero
+/// transaction count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadersMessage {
+    pub headers: Vec<BlockHeader>,
+}
+
+impl Message for HeadersMessage {
+    const COMMAND: &'static str = "headers";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        encode_varint(&mut result, self.headers.len() as u64).unwrap();
+        for header in self.headers.iter() {
+            result.extend_from_slice(&header.serialize());
+            result.push(0);
+        }
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<HeadersMessage> {
+        let len = read_varint_len(reader)?;
+        if len > MAX_HEADERS {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("{} headers in one message", len)));
+        }
+        let mut headers = Vec::with_capacity(len);
+        for _ in 0..len {
+            headers.push(BlockHeader::parse(reader)?);
+            if read_varint_len(reader)? != 0 {
+                return Err(Error::new(ErrorKind::InvalidEncoding, "header with transactions"));
+            }
+        }
+        Ok(HeadersMessage { headers })
+    }
+}
+
+/// What an inventory entry refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvType {
+    Error,
+    Tx,
+    Block,
+    /// A merkleblock followed by the matched transactions (BIP37)
+    FilteredBlock,
+    /// A compact block (BIP152)
+    CompactBlock,
+    /// Transactions and blocks with witness data (BIP144)
+    WitnessTx,
+    WitnessBlock,
+    WitnessFilteredBlock,
+    Unknown(u32),
+}
+
+const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+impl InvType {
+    pub fn from_u32(value: u32) -> InvType {
+        match value {
+            0 => InvType::Error,
+            1 => InvType::Tx,
+            2 => InvType::Block,
+            3 => InvType::FilteredBlock,
+            4 => InvType::CompactBlock,
+            v if v == MSG_WITNESS_FLAG | 1 => InvType::WitnessTx,
+            v if v == MSG_WITNESS_FLAG | 2 => InvType::WitnessBlock,
+            v if v == MSG_WITNESS_FLAG | 3 => InvType::WitnessFilteredBlock,
+            v => InvType::Unknown(v),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            InvType::Error => 0,
+            InvType::Tx => 1,
+            InvType::Block => 2,
+            InvType::FilteredBlock => 3,
+            InvType::CompactBlock => 4,
+            InvType::WitnessTx => MSG_WITNESS_FLAG | 1,
+            InvType::WitnessBlock => MSG_WITNESS_FLAG | 2,
+            InvType::WitnessFilteredBlock => MSG_WITNESS_FLAG | 3,
+            InvType::Unknown(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InvType,
+    /// Txid or block hash in display order
+    pub hash: [u8; 32],
+}
+
+impl Inventory {
+    pub fn new(kind: InvType, hash: [u8; 32]) -> Inventory {
+        Inventory { kind, hash }
+    }
+}
+
+fn serialize_inventory(inventory: &[Inventory]) -> Vec<u8> {
+    let mut result = vec![];
+    encode_varint(&mut result, inventory.len() as u64).unwrap();
+    for item in inventory.iter() {
+        result.extend_from_slice(&item.kind.to_u32().to_le_bytes());
+        write_hash(&mut result, &item.hash);
+    }
+    result
+}
+
+fn parse_inventory<R: Read>(reader: &mut R) -> Result<Vec<Inventory>> {
+    let len = read_varint_len(reader)?;
+    if len > MAX_INV_SIZE {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("{} inventory entries", len)));
+    }
+    (0..len).map(|_| Ok(Inventory::new(InvType::from_u32(read_u32_le(reader)?), read_hash(reader)?))).collect()
+}
+
+/// Announces transactions or blocks the sender has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvMessage {
+    pub inventory: Vec<Inventory>,
+}
+
+impl Message for InvMessage {
+    const COMMAND: &'static str = "inv";
+
+    fn serialize(&self) -> Vec<u8> {
+        serialize_inventory(&self.inventory)
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<InvMessage> {
+        Ok(InvMessage { inventory: parse_inventory(reader)? })
+    }
+}
+
+/// Asks for the objects in an inventory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetDataMessage {
+    pub inventory: Vec<Inventory>,
+}
+
+impl Message for GetDataMessage {
+    const COMMAND: &'static str = "getdata";
+
+    fn serialize(&self) -> Vec<u8> {
+        serialize_inventory(&self.inventory)
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<GetDataMessage> {
+        Ok(GetDataMessage { inventory: parse_inventory(reader)? })
+    }
+}
+
+/// Answers a getdata for objects the peer doesn't have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotFoundMessage {
+    pub inventory: Vec<Inventory>,
+}
+
+impl Message for NotFoundMessage {
+    const COMMAND: &'static str = "notfound";
+
+    fn serialize(&self) -> Vec<u8> {
+        serialize_inventory(&self.inventory)
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<NotFoundMessage> {
+        Ok(NotFoundMessage { inventory: parse_inventory(reader)? })
+    }
+}
+
+/// A transaction. Parsed ones are marked mainnet, the envelope doesn't say.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxMessage(pub Tx);
+
+impl Message for TxMessage {
+    const COMMAND: &'static str = "tx";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.0.serialize()
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<TxMessage> {
+        Ok(TxMessage(Tx::parse(reader, Network::Mainnet)?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockMessage(pub Block);
+
+impl Message for BlockMessage {
+    const COMMAND: &'static str = "block";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.0.serialize()
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<BlockMessage> {
+        Ok(BlockMessage(Block::parse(reader, Network::Mainnet)?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlockMessage(pub MerkleBlock);
+
+impl Message for MerkleBlockMessage {
+    const COMMAND: &'static str = "merkleblock";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.0.serialize()
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<MerkleBlockMessage> {
+        Ok(MerkleBlockMessage(MerkleBlock::parse(reader)?))
+    }
+}
+
+/// Sets the bloom filter a peer matches transactions against (BIP37).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterLoadMessage {
+    pub filter: BloomFilter,
+    /// One of the `bloom::BLOOM_UPDATE_*` flags
+    pub flag: u8,
+}
+
+impl Message for FilterLoadMessage {
+    const COMMAND: &'static str = "filterload";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.filter.filterload(self.flag)
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<FilterLoadMessage> {
+        let len = read_varint_len(reader)?;
+        let bytes = read_bytes(reader, len)?;
+        let function_count = read_u32_le(reader)?;
+        let tweak = read_u32_le(reader)?;
+        let flag = read_bytes(reader, 1)?[0];
+        let mut filter = BloomFilter::new(len, function_count, tweak)?;
+        filter.bit_field = bytes_to_bit_field(&bytes);
+        Ok(FilterLoadMessage { filter, flag })
+    }
+}
+
+/// Sent to check the peer is alive; it answers with a pong carrying the
+/// same nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingMessage {
+    pub nonce: u64,
+}
+
+impl Message for PingMessage {
+    const COMMAND: &'static str = "ping";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_le_bytes().to_vec()
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<PingMessage> {
+        Ok(PingMessage { nonce: read_u64_le(reader)? })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PongMessage {
+    pub nonce: u64,
+}
+
+impl Message for PongMessage {
+    const COMMAND: &'static str = "pong";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_le_bytes().to_vec()
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<PongMessage> {
+        Ok(PongMessage { nonce: read_u64_le(reader)? })
+    }
+}
+
+/// Asks the peer to announce the transactions in its mempool with inv
+/// messages (BIP35).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MempoolMessage;
+
+impl Message for MempoolMessage {
+    const COMMAND: &'static str = "mempool";
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn parse<R: Read>(_reader: &mut R) -> Result<MempoolMessage> {
+        Ok(MempoolMessage)
+    }
+}
+
+/// Asks the peer not to announce transactions paying less than
+/// `fee_rate` satoshis per 1000 vbytes (BIP133).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeFilterMessage {
+    pub fee_rate: u64,
+}
+
+impl Message for FeeFilterMessage {
+    const COMMAND: &'static str = "feefilter";
+
+    fn serialize(&self) -> Vec<u8> {
+        self.fee_rate.to_le_bytes().to_vec()
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<FeeFilterMessage> {
+        Ok(FeeFilterMessage { fee_rate: read_u64_le(reader)? })
+    }
+}
+
+/// Asks the peer to announce new blocks with headers messages instead of
+/// inv (BIP130).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendHeadersMessage;
+
+impl Message for SendHeadersMessage {
+    const COMMAND: &'static str = "sendheaders";
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn parse<R: Read>(_reader: &mut R) -> Result<SendHeadersMessage> {
+        Ok(SendHeadersMessage)
+    }
+}
+
+fn read_var_str<R: Read>(reader: &mut R) -> Result<String> {
+    let len = read_varint_len(reader)?;
+    String::from_utf8(read_bytes(reader, len)?).map_err(|_| Error::new(ErrorKind::InvalidEncoding, "string is not UTF-8"))
+}
+
+fn write_var_str(result: &mut Vec<u8>, s: &str) {
+    encode_varint(result, s.len() as u64).unwrap();
+    result.extend_from_slice(s.as_bytes());
+}
+
+/// Why a peer refused a message (BIP61). Newer nodes no longer send these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectMessage {
+    /// Command of the rejected message
+    pub message: String,
+    pub code: u8,
+    pub reason: String,
+    /// Hash of the rejected tx or block, in display order
+    pub hash: Option<[u8; 32]>,
+}
+
+impl Message for RejectMessage {
+    const COMMAND: &'static str = "reject";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        write_var_str(&mut result, &self.message);
+        result.push(self.code);
+        write_var_str(&mut result, &self.reason);
+        if let Some(hash) = self.hash {
+            write_hash(&mut result, &hash);
+        }
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<RejectMessage> {
+        let message = read_var_str(reader)?;
+        let code = read_bytes(reader, 1)?[0];
+        let reason = read_var_str(reader)?;
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        let hash = match rest.len() {
+            0 => None,
+            32 => Some(read_hash(&mut &rest[..])?),
+            len => return Err(Error::new(ErrorKind::InvalidEncoding, format!("reject with {} bytes of data", len))),
+        };
+        Ok(RejectMessage { message, code, reason, hash })
+    }
+}
+
+/// Asks the peer for addresses of other peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GetAddrMessage;
+
+impl Message for GetAddrMessage {
+    const COMMAND: &'static str = "getaddr";
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn parse<R: Read>(_reader: &mut R) -> Result<GetAddrMessage> {
+        Ok(GetAddrMessage)
+    }
+}
+
+/// Addresses of peers, each with when it was last seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrMessage {
+    pub addresses: Vec<(u32, NetAddress)>,
+}
+
+impl Message for AddrMessage {
+    const COMMAND: &'static str = "addr";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        encode_varint(&mut result, self.addresses.len() as u64).unwrap();
+        for (time, address) in self.addresses.iter() {
+            result.extend_from_slice(&time.to_le_bytes());
+            result.extend_from_slice(&address.serialize());
+        }
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<AddrMessage> {
+        let len = read_varint_len(reader)?;
+        if len > MAX_ADDR {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("{} addresses", len)));
+        }
+        let addresses = (0..len).map(|_| Ok((read_u32_le(reader)?, NetAddress::parse(reader)?))).collect::<Result<_>>()?;
+        Ok(AddrMessage { addresses })
+    }
+}
+
+/// Asks the peer to send addrv2 instead of addr (BIP155). Goes before
+/// the verack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendAddrV2Message;
+
+impl Message for SendAddrV2Message {
+    const COMMAND: &'static str = "sendaddrv2";
+
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn parse<R: Read>(_reader: &mut R) -> Result<SendAddrV2Message> {
+        Ok(SendAddrV2Message)
+    }
+}
+
+// Longest address BIP155 allows, of any network
+const MAX_ADDRV2_SIZE: usize = 512;
+// Onion names are lowercase RFC 4648 base32
+const ONION_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const ONION_VERSION: u8 = 3;
+
+fn onion_checksum(key: &[u8; 32]) -> [u8; 2] {
+    let mut preimage = b".onion checksum".to_vec();
+    preimage.extend_from_slice(key);
+    preimage.push(ONION_VERSION);
+    let hash = sha3_256(&preimage);
+    [hash[0], hash[1]]
+}
+
+/// An address on one of the networks BIP155 knows.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AddrV2 {
+    Ipv4([u8; 4]),
+    Ipv6([u8; 16]),
+    /// A Tor v3 onion service's public key
+    TorV3([u8; 32]),
+    /// The SHA256 of an I2P destination
+    I2p([u8; 32]),
+    Cjdns([u8; 16]),
+    /// A network id this crate doesn't know, Tor v2 included
+    Unknown(u8, Vec<u8>),
+}
+
+impl AddrV2 {
+    pub fn network_id(&self) -> u8 {
+        match self {
+            AddrV2::Ipv4(_) => 1,
+            AddrV2::Ipv6(_) => 2,
+            AddrV2::TorV3(_) => 4,
+            AddrV2::I2p(_) => 5,
+            AddrV2::Cjdns(_) => 6,
+            AddrV2::Unknown(id, _) => *id,
+        }
+    }
+
+    /// Parses a Tor v3 `.onion` host name, checking its checksum and
+    /// version.
+    pub fn from_onion(host: &str) -> Result<AddrV2> {
+        let invalid = || Error::new(ErrorKind::InvalidEncoding, format!("not a v3 onion address: {}", host));
+        let name = host.to_ascii_lowercase();
+        let name = name.strip_suffix(".onion").ok_or_else(invalid)?;
+        let values = name.bytes().map(|c| ONION_ALPHABET.iter().position(|a| *a == c).map(|v| v as u8)).collect::<Option<Vec<u8>>>().ok_or_else(invalid)?;
+        let bytes = convert_bits(&values, 5, 8, false).map_err(|_| invalid())?;
+        if bytes.len() != 35 || bytes[34] != ONION_VERSION {
+            return Err(invalid());
+        }
+        let key: [u8; 32] = bytes[..32].try_into().unwrap();
+        if bytes[32..34] != onion_checksum(&key) {
+            return Err(invalid());
+        }
+        Ok(AddrV2::TorV3(key))
+    }
+
+    /// The `.onion` host name of a Tor v3 address.
+    pub fn onion_host(&self) -> Option<String> {
+        match self {
+            AddrV2::TorV3(key) => {
+                let mut bytes = key.to_vec();
+                bytes.extend_from_slice(&onion_checksum(key));
+                bytes.push(ONION_VERSION);
+                let values = convert_bits(&bytes, 8, 5, true).unwrap();
+                Some(values.iter().map(|v| ONION_ALPHABET[*v as usize] as char).collect::<String>() + ".onion")
+            }
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            AddrV2::Ipv4(ip) => ip,
+            AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => ip,
+            AddrV2::TorV3(key) | AddrV2::I2p(key) => key,
+            AddrV2::Unknown(_, bytes) => bytes,
+        }
+    }
+
+    fn from_parts(network_id: u8, bytes: Vec<u8>) -> Result<AddrV2> {
+        let expected = match network_id {
+            1 => 4,
+            2 | 6 => 16,
+            4 | 5 => 32,
+            _ => return Ok(AddrV2::Unknown(network_id, bytes)),
+        };
+        if bytes.len() != expected {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} byte address on network {}", bytes.len(), network_id)));
+        }
+        Ok(match network_id {
+            1 => AddrV2::Ipv4([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            2 => AddrV2::Ipv6(bytes.try_into().unwrap()),
+            6 => AddrV2::Cjdns(bytes.try_into().unwrap()),
+            4 => AddrV2::TorV3(bytes.try_into().unwrap()),
+            _ => AddrV2::I2p(bytes.try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Entry {
+    /// When the address was last seen
+    pub time: u32,
+    pub services: u64,
+    pub address: AddrV2,
+    pub port: u16,
+}
+
+impl AddrV2Entry {
+    /// The address to connect to, if it's on IPv4 or IPv6.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match &self.address {
+            AddrV2::Ipv4(ip) => Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(*ip)), self.port)),
+            AddrV2::Ipv6(ip) => Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(*ip)), self.port)),
+            _ => None,
+        }
+    }
+
+    /// Where to connect through a proxy: `host:port` for IP and onion
+    /// addresses.
+    pub fn proxy_target(&self) -> Option<String> {
+        match self.address.onion_host() {
+            Some(host) => Some(format!("{}:{}", host, self.port)),
+            None => self.socket_addr().map(|addr| addr.to_string()),
+        }
+    }
+}
+
+/// Addresses of peers on any network (BIP155).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Message {
+    pub addresses: Vec<AddrV2Entry>,
+}
+
+impl Message for AddrV2Message {
+    const COMMAND: &'static str = "addrv2";
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        encode_varint(&mut result, self.addresses.len() as u64).unwrap();
+        for entry in self.addresses.iter() {
+            result.extend_from_slice(&entry.time.to_le_bytes());
+            encode_varint(&mut result, entry.services).unwrap();
+            result.push(entry.address.network_id());
+            encode_varint(&mut result, entry.address.bytes().len() as u64).unwrap();
+            result.extend_from_slice(entry.address.bytes());
+            result.extend_from_slice(&entry.port.to_be_bytes());
+        }
+        result
+    }
+
+    fn parse<R: Read>(reader: &mut R) -> Result<AddrV2Message> {
+        let len = read_varint_len(reader)?;
+        if len > MAX_ADDR {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("{} addresses", len)));
+        }
+        let mut addresses = vec![];
+        for _ in 0..len {
+            let time = read_u32_le(reader)?;
+            let services = read_varint(reader)?;
+            let network_id = read_bytes(reader, 1)?[0];
+            let addr_len = read_varint_len(reader)?;
+            if addr_len > MAX_ADDRV2_SIZE {
+                return Err(Error::new(ErrorKind::OutOfRange, format!("{} byte address", addr_len)));
+            }
+            let address = AddrV2::from_parts(network_id, read_bytes(reader, addr_len)?)?;
+            let port = read_u16_be(reader)?;
+            addresses.push(AddrV2Entry { time, services, address, port });
+        }
+        Ok(AddrV2Message { addresses })
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+
+#[test]
+fn version_messages() {
+    // Chapter 10's example
+    let mut version = VersionMessage::new(NetAddress::from_ipv4(0, [0; 4], 8333), 0);
+    version.timestamp = 0;
+    version.nonce = 0;
+    version.user_agent = "/programmingbitcoin:0.1/".to_string();
+    assert_eq!(encode_hex(&version.serialize()), "7f11010000000000000000000000000000000000000000000000000000000000000000000000ffff00000000208d000000000000000000000000000000000000ffff00000000208d0000000000000000182f70726f6772616d6d696e67626974636f696e3a302e312f0000000000");
+    assert_eq!(VersionMessage::parse(&mut &version.serialize()[..]).unwrap(), version);
+
+    // A Satoshi 0.9.3 node's version
+    let raw = decode_hex("721101000100000000000000bc8f5e5400000000010000000000000000000000000000000000ffffc61b6409208d010000000000000000000000000000000000ffffcb0071c0208d128035cbc97953f80f2f5361746f7368693a302e392e332fcf05050001").unwrap();
+    let peer = VersionMessage::parse(&mut &raw[..]).unwrap();
+    assert_eq!((peer.version, peer.user_agent.as_str(), peer.latest_block), (70002, "/Satoshi:0.9.3/", 329167));
+    assert_eq!(peer.receiver.ip[12..], [198, 27, 100, 9]);
+    assert_eq!(peer.serialize(), raw);
+    assert!(VersionMessage::parse(&mut &raw[..raw.len() - 2]).is_err());
+}
+
+#[test]
+fn header_messages() {
+    // Chapter 10's examples
+    let mut start = [0u8; 32];
+    start.copy_from_slice(&decode_hex("0000000000000000001237f46acddf58578a37e213d2a6edc4884a2fcad05ba3").unwrap());
+    let getheaders = GetHeadersMessage::new(vec![start]);
+    assert_eq!(encode_hex(&getheaders.serialize()), "7f11010001a35bd0ca2f4a88c4eda6d213e2378a5758dfcd6af437120000000000000000000000000000000000000000000000000000000000000000000000000000000000");
+    assert_eq!(GetHeadersMessage::parse(&mut &getheaders.serialize()[..]).unwrap(), getheaders);
+
+    let raw = decode_hex("0200000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670000000002030eb2540c41025690160a1014c577061596e32e426b712c7ca00000000000000768b89f07044e6130ead292a3f51951adbd2202df447d98789339937fd006bd44880835b67d8001ade09204600").unwrap();
+    let headers = HeadersMessage::parse(&mut &raw[..]).unwrap();
+    assert_eq!(headers.headers.len(), 2);
+    assert_eq!(headers.headers[1].prev_block, headers.headers[0].hash());
+    assert_eq!(headers.serialize(), raw);
+    let mut with_txs = raw.clone();
+    with_txs[81] = 1;
+    assert!(HeadersMessage::parse(&mut &with_txs[..]).is_err());
+}
+
+#[test]
+fn inventory_messages() {
+    // Chapter 12's example
+    let hash = |hex: &str| {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&decode_hex(hex).unwrap());
+        hash
+    };
+    let getdata = GetDataMessage {
+        inventory: vec![
+            Inventory::new(InvType::FilteredBlock, hash("00000000000000cac712b726e4326e596170574c01a16001692510c44025eb30")),
+            Inventory::new(InvType::FilteredBlock, hash("00000000000000beb88910c46f6b442312361c6693a7fb52065b583979844910")),
+        ],
+    };
+    assert_eq!(encode_hex(&getdata.serialize()), "020300000030eb2540c41025690160a1014c577061596e32e426b712c7ca00000000000000030000001049847939585b0652fba793661c361223446b6fc41089b8be00000000000000");
+    assert_eq!(GetDataMessage::parse(&mut &getdata.serialize()[..]).unwrap(), getdata);
+    assert_eq!(InvType::from_u32(0x4000_0002), InvType::WitnessBlock);
+    assert_eq!(InvType::from_u32(7).to_u32(), 7);
+
+    let mut filter = BloomFilter::new(10, 5, 99).unwrap();
+    filter.add(b"Hello World");
+    let filterload = FilterLoadMessage { filter, flag: crate::bloom::BLOOM_UPDATE_ALL };
+    assert_eq!(FilterLoadMessage::parse(&mut &filterload.serialize()[..]).unwrap(), filterload);
+
+    let feefilter = FeeFilterMessage { fee_rate: 1000 };
+    assert_eq!(encode_hex(&feefilter.serialize()), "e803000000000000");
+    assert_eq!(FeeFilterMessage::parse(&mut &feefilter.serialize()[..]).unwrap(), feefilter);
+    assert!(MempoolMessage.serialize().is_empty() && SendHeadersMessage.serialize().is_empty());
+}
+
+#[test]
+fn addr_messages() {
+    let addr = AddrMessage { addresses: vec![(1_700_000_000, NetAddress::from_ipv4(NODE_NETWORK, [10, 0, 0, 1], 8333))] };
+    let raw = addr.serialize();
+    assert_eq!(encode_hex(&raw), "0100f15365010000000000000000000000000000000000ffff0a000001208d");
+    assert_eq!(AddrMessage::parse(&mut &raw[..]).unwrap(), addr);
+    assert_eq!(addr.addresses[0].1.socket_addr(), "10.0.0.1:8333".parse().unwrap());
+    assert_eq!(NetAddress::from_socket_addr(0, "[2001:db8::1]:18333".parse().unwrap()).socket_addr(), "[2001:db8::1]:18333".parse().unwrap());
+
+    // BIP155's encoding: compact size services, network id, length prefixed address
+    let raw = decode_hex("02000000000901047f000001208d00000000fd000404202020202020202020202020202020202020202020202020202020202020202020d903").unwrap();
+    let addrv2 = AddrV2Message::parse(&mut &raw[..]).unwrap();
+    assert_eq!(addrv2.addresses[0], AddrV2Entry { time: 0, services: 9, address: AddrV2::Ipv4([127, 0, 0, 1]), port: 8333 });
+    assert_eq!(addrv2.addresses[0].socket_addr(), Some("127.0.0.1:8333".parse().unwrap()));
+    assert_eq!((addrv2.addresses[1].services, &addrv2.addresses[1].address), (0x400, &AddrV2::TorV3([0x20; 32])));
+    assert_eq!(addrv2.addresses[1].socket_addr(), None);
+    assert_eq!(addrv2.serialize(), raw);
+    assert_eq!(addrv2.addresses[1].proxy_target().unwrap(), "eaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqjlgqd.onion:55555");
+    let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    assert_eq!(AddrV2::from_onion(onion).unwrap().onion_host().unwrap(), onion);
+    assert!(AddrV2::from_onion(&onion.replace("duck", "dock")).is_err());
+    // An IPv4 address must be four bytes
+    let mut bad = raw.clone();
+    bad[7] = 2;
+    assert!(AddrV2Message::parse(&mut &bad[..]).is_err());
+}
@@ -0,0 +1,150 @@
+//! Misbehavior scoring, as in Bitcoin Core.
+//!
+//! Each protocol violation adds to a peer's score, and at `BAN_THRESHOLD`
+//! the node disconnects it; a `NodePool` then bans its address for
+//! `BAN_DURATION`. `MessageLimits` bound what the node reads, so a peer
+//! can't make it buffer huge payloads or keep it busy with a flood.
+
+use super::MAX_PAYLOAD_SIZE;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Score at which a peer is disconnected and banned.
+pub const BAN_THRESHOLD: u32 = 100;
+/// Seconds a ban lasts, as in Bitcoin Core.
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A header that extends our chain without the work its bits need,
+    /// with the wrong bits, or off a checkpoint
+    BadProofOfWork,
+    /// A payload over the node's limit
+    OversizedMessage,
+    /// An envelope with a bad checksum or command name
+    InvalidChecksum,
+    /// A block, filtered block or blocktxn we didn't ask for
+    UnsolicitedData,
+    /// More messages or bytes in a second than the node's limits
+    MessageFlood,
+}
+
+impl Violation {
+    /// What the violation adds to the peer's score.
+    pub fn score(&self) -> u32 {
+        match self {
+            Violation::BadProofOfWork | Violation::OversizedMessage => BAN_THRESHOLD,
+            Violation::MessageFlood => 50,
+            Violation::UnsolicitedData => 20,
+            Violation::InvalidChecksum => 10,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Violation::BadProofOfWork => "bad proof of work",
+            Violation::OversizedMessage => "oversized message",
+            Violation::InvalidChecksum => "invalid checksum",
+            Violation::UnsolicitedData => "unsolicited data",
+            Violation::MessageFlood => "message flood",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A peer's violations so far.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Misbehavior {
+    score: u32,
+    violations: Vec<Violation>,
+}
+
+impl Misbehavior {
+    /// Adds `violation`, returning whether the peer should now be banned.
+    pub fn record(&mut self, violation: Violation) -> bool {
+        self.score = self.score.saturating_add(violation.score());
+        self.violations.push(violation);
+        self.should_ban()
+    }
+
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn should_ban(&self) -> bool {
+        self.score >= BAN_THRESHOLD
+    }
+}
+
+/// The most a node reads from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Largest payload; a bigger one is an `OversizedMessage`
+    pub max_payload: usize,
+    /// Messages a second before it's a `MessageFlood`
+    pub max_messages: u32,
+    /// Payload bytes a second before it's a `MessageFlood`
+    pub max_bytes: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> MessageLimits {
+        MessageLimits { max_payload: MAX_PAYLOAD_SIZE, max_messages: 1000, max_bytes: 8 * MAX_PAYLOAD_SIZE }
+    }
+}
+
+/// Counts messages and bytes over one second windows.
+#[derive(Debug, Clone)]
+pub(super) struct RateCounter {
+    start: Instant,
+    messages: u32,
+    bytes: usize,
+    flooded: bool,
+}
+
+impl RateCounter {
+    pub(super) fn new() -> RateCounter {
+        RateCounter { start: Instant::now(), messages: 0, bytes: 0, flooded: false }
+    }
+
+    /// Counts a message with a `bytes` payload, returning true the first
+    /// time a window goes over `limits`.
+    pub(super) fn count(&mut self, limits: &MessageLimits, bytes: usize) -> bool {
+        if self.start.elapsed() >= Duration::from_secs(1) {
+            *self = RateCounter::new();
+        }
+        self.messages += 1;
+        self.bytes = self.bytes.saturating_add(bytes);
+        if self.flooded || (self.messages <= limits.max_messages && self.bytes <= limits.max_bytes) {
+            return false;
+        }
+        self.flooded = true;
+        true
+    }
+}
+
+#[test]
+fn misbehavior_scores() {
+    let mut misbehavior = Misbehavior::default();
+    for _ in 0..7 {
+        assert!(!misbehavior.record(Violation::InvalidChecksum));
+    }
+    assert!(!misbehavior.record(Violation::UnsolicitedData));
+    assert_eq!(misbehavior.score(), 90);
+    assert!(misbehavior.record(Violation::InvalidChecksum));
+    assert_eq!(misbehavior.violations().len(), 9);
+
+    let limits = MessageLimits { max_messages: 3, max_bytes: 100, ..MessageLimits::default() };
+    let mut counter = RateCounter::new();
+    assert!(!counter.count(&limits, 10));
+    assert!(!counter.count(&limits, 10));
+    assert!(counter.count(&limits, 90));
+    // Reported once a window
+    assert!(!counter.count(&limits, 0));
+}
@@ -0,0 +1,182 @@
+//! The peer-to-peer protocol (chapter 10).
+//!
+//! Every message travels in a `NetworkEnvelope`: the network's magic bytes,
+//! a 12 byte command name, the payload length and the first four bytes of
+//! the payload's hash256. Typed messages implement `Message` and go in and
+//! out of envelopes with `NetworkEnvelope::from_message` and `decode`.
+
+#[cfg(feature = "async")]
+pub mod async_node;
+pub mod chacha;
+pub mod compact;
+pub mod messages;
+pub mod misbehavior;
+pub mod node;
+pub mod peers;
+pub mod pool;
+pub mod proxy;
+pub mod sync;
+pub mod transport;
+
+#[cfg(feature = "async")]
+pub use async_node::AsyncNode;
+pub use compact::{BlockTxnMessage, CmpctBlockMessage, GetBlockTxnMessage, PartialBlock, SendCmpctMessage};
+pub use messages::{
+    AddrMessage, AddrV2, AddrV2Entry, AddrV2Message, BlockMessage, FeeFilterMessage, FilterLoadMessage, GetAddrMessage,
+    GetDataMessage, GetHeadersMessage, HeadersMessage, InvMessage, InvType, Inventory, MempoolMessage, MerkleBlockMessage,
+    NetAddress, NotFoundMessage, PingMessage, PongMessage, RejectMessage, SendAddrV2Message, SendHeadersMessage, TxMessage,
+    VerAckMessage, VersionMessage,
+};
+pub use misbehavior::{MessageLimits, Misbehavior, Violation};
+pub use node::SimpleNode;
+pub use peers::{dns_seed_addresses, AddrMan, PeerInfo};
+pub use pool::{NodePool, Peer, PeerState};
+pub use proxy::{local_tor_proxy, socks5_connect};
+pub use sync::{ChainEvent, HeaderStore, HeaderSync, MemoryHeaderStore};
+pub use transport::Transport;
+
+use crate::encoding::util::{encode_hex, read_bytes, read_u32_le};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use std::fmt;
+use std::io::{Read, Write};
+
+/// Largest payload accepted from a peer, as in Bitcoin Core.
+pub const MAX_PAYLOAD_SIZE: usize = 4_000_000;
+const COMMAND_SIZE: usize = 12;
+
+/// A message with a command name and a payload encoding.
+pub trait Message: Sized {
+    const COMMAND: &'static str;
+
+    fn serialize(&self) -> Vec<u8>;
+
+    fn parse<R: Read>(reader: &mut R) -> Result<Self>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkEnvelope {
+    pub magic: [u8; 4],
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
+impl NetworkEnvelope {
+    pub fn new<S: Into<String>>(magic: [u8; 4], command: S, payload: Vec<u8>) -> NetworkEnvelope {
+        NetworkEnvelope { magic, command: command.into(), payload }
+    }
+
+    pub fn from_message<M: Message>(magic: [u8; 4], message: &M) -> NetworkEnvelope {
+        NetworkEnvelope::new(magic, M::COMMAND, message.serialize())
+    }
+
+    /// Reads an envelope, which must carry `magic` and a matching checksum.
+    pub fn parse<R: Read>(reader: &mut R, magic: &[u8; 4]) -> Result<NetworkEnvelope> {
+        NetworkEnvelope::parse_limited(reader, magic, MAX_PAYLOAD_SIZE)
+    }
+
+    /// Reads an envelope as `parse` does, with a payload of at most
+    /// `max_payload` bytes. A bad command or checksum is only reported
+    /// once the whole envelope is read, so the next one can follow.
+    pub fn parse_limited<R: Read>(reader: &mut R, magic: &[u8; 4], max_payload: usize) -> Result<NetworkEnvelope> {
+        let mut found = [0u8; 4];
+        found.copy_from_slice(&read_bytes(reader, 4)?);
+        if found != *magic {
+            return Err(Error::new(
+                ErrorKind::UnknownNetwork,
+                format!("magic {} instead of {}", encode_hex(&found), encode_hex(magic)),
+            ));
+        }
+        let command = read_bytes(reader, COMMAND_SIZE)?;
+        let length = read_u32_le(reader)? as usize;
+        if length > max_payload {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("payload of {} bytes", length)));
+        }
+        let checksum = read_bytes(reader, 4)?;
+        let payload = read_bytes(reader, length)?;
+        let name_len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+        if !command[..name_len].iter().all(u8::is_ascii_graphic) || command[name_len..].iter().any(|b| *b != 0) {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("bad command {}", encode_hex(&command))));
+        }
+        if hash256(&payload)[..4] != checksum[..] {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "payload checksum mismatch"));
+        }
+        let command = String::from_utf8(command[..name_len].to_vec()).unwrap();
+        Ok(NetworkEnvelope { magic: found, command, payload })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.magic.to_vec();
+        let mut command = self.command.as_bytes().to_vec();
+        command.resize(COMMAND_SIZE, 0);
+        result.extend_from_slice(&command);
+        result.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        result.extend_from_slice(&hash256(&self.payload)[..4]);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.serialize())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Whether the envelope holds an `M`.
+    pub fn is<M: Message>(&self) -> bool {
+        self.command == M::COMMAND
+    }
+
+    /// The payload as an `M`, which must use all of it.
+    pub fn decode<M: Message>(&self) -> Result<M> {
+        if !self.is::<M>() {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("expected {}, got {}", M::COMMAND, self.command)));
+        }
+        let mut reader = &self.payload[..];
+        let message = M::parse(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} trailing bytes in {}", reader.len(), self.command)));
+        }
+        Ok(message)
+    }
+}
+
+impl fmt::Display for NetworkEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.command, encode_hex(&self.payload))
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::params::NetworkParams;
+
+#[test]
+fn parse_envelope() {
+    // Chapter 10's examples
+    let mainnet = NetworkParams::mainnet().magic;
+    let raw = decode_hex("f9beb4d976657261636b000000000000000000005df6e0e2").unwrap();
+    let envelope = NetworkEnvelope::parse(&mut &raw[..], &mainnet).unwrap();
+    assert_eq!((envelope.command.as_str(), envelope.payload.len()), ("verack", 0));
+    assert_eq!(envelope.serialize(), raw);
+    let raw = decode_hex("f9beb4d976657273696f6e0000000000650000005f1a69d2721101000100000000000000bc8f5e5400000000010000000000000000000000000000000000ffffc61b6409208d010000000000000000000000000000000000ffffcb0071c0208d128035cbc97953f80f2f5361746f7368693a302e392e332fcf05050001").unwrap();
+    let envelope = NetworkEnvelope::parse(&mut &raw[..], &mainnet).unwrap();
+    assert_eq!(envelope.command, "version");
+    assert_eq!(envelope.payload, raw[24..]);
+    assert_eq!(envelope.serialize(), raw);
+
+    let err = NetworkEnvelope::parse(&mut &raw[..], &NetworkParams::testnet3().magic).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnknownNetwork);
+    let mut corrupt = raw.clone();
+    corrupt[30] ^= 1;
+    assert_eq!(NetworkEnvelope::parse(&mut &corrupt[..], &mainnet).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert!(NetworkEnvelope::parse(&mut &raw[..100], &mainnet).is_err());
+    assert_eq!(NetworkEnvelope::parse_limited(&mut &raw[..], &mainnet, 100).unwrap_err().kind(), ErrorKind::OutOfRange);
+    // The bad envelope is read whole, so the next one parses
+    let mut stream = corrupt.clone();
+    stream.extend_from_slice(&raw);
+    let mut reader = &stream[..];
+    assert!(NetworkEnvelope::parse(&mut reader, &mainnet).is_err());
+    assert_eq!(NetworkEnvelope::parse(&mut reader, &mainnet).unwrap().command, "version");
+}
@@ -0,0 +1,749 @@
+//! A single blocking connection to a peer (chapter 10).
+//!
+//! The node answers pings while it reads. After the handshake it also
+//! pings the peer every `ping_interval`, and disconnects a peer that
+//! hasn't answered within `ping_timeout`. Being blocking, it only does
+//! this while the caller is reading.
+//!
+//! `connect_v2` and `accept` speak BIP324's encrypted transport with peers
+//! that support it and the book's plain envelopes with those that don't.
+//!
+//! Reads are bounded by the node's `MessageLimits`, and protocol
+//! violations add to the peer's misbehavior score; at the ban threshold
+//! the node disconnects.
+
+use super::compact::BlockTxnMessage;
+use super::messages::{
+    random_u64, BlockMessage, FeeFilterMessage, FilterLoadMessage, GetDataMessage, HeadersMessage, InvMessage, InvType, Inventory,
+    MempoolMessage, MerkleBlockMessage, NetAddress, PingMessage, PongMessage, RejectMessage, SendHeadersMessage, TxMessage, VerAckMessage,
+    VersionMessage,
+};
+use super::misbehavior::{MessageLimits, Misbehavior, RateCounter, Violation};
+use super::proxy::{socks5_connect, split_host_port};
+use super::transport::Transport;
+use super::{Message, NetworkEnvelope};
+use crate::block::{Block, BlockHeader, MerkleBlock};
+use crate::bloom::BloomFilter;
+use crate::encoding::util::encode_hex;
+use crate::error::{Error, ErrorKind, Result};
+use crate::tx::Tx;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// How long connecting, and waiting for any one message, may take.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to ping a peer, as in Bitcoin Core.
+pub const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+/// How long a peer may take to answer a ping, as in Bitcoin Core.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+pub struct SimpleNode {
+    stream: TcpStream,
+    magic: [u8; 4],
+    transport: Transport,
+    /// A message read before the transport was settled
+    queued: Option<NetworkEnvelope>,
+    timeout: Duration,
+    connected: bool,
+    /// Nonce and send time of our unanswered ping
+    pending_ping: Option<(u64, Instant)>,
+    last_ping: Instant,
+    last_seen: Instant,
+    latency: Option<Duration>,
+    /// Whether the peer sent sendheaders
+    peer_wants_headers: bool,
+    /// The peer's feefilter, in satoshis per 1000 vbytes
+    peer_fee_filter: u64,
+    limits: MessageLimits,
+    rate: RateCounter,
+    misbehavior: Misbehavior,
+    /// The peer's version message, once the handshake is done
+    pub peer_version: Option<VersionMessage>,
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+}
+
+pub(super) fn timed_out(what: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::TimedOut, format!("timed out waiting for {}", what)))
+}
+
+fn banned(violation: Violation) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted, format!("peer banned after {}", violation)))
+}
+
+fn wrong_object(wanted: &[u8; 32], got: &[u8; 32]) -> Error {
+    Error::new(ErrorKind::Fetch, format!("got {} instead of {}", encode_hex(got), encode_hex(wanted)))
+}
+
+impl SimpleNode {
+    /// Connects to the first address `addr` resolves to that accepts, on
+    /// the network with the given magic bytes. Does not handshake.
+    pub fn connect<A: ToSocketAddrs>(addr: A, magic: [u8; 4], timeout: Duration) -> Result<SimpleNode> {
+        let mut last_err = Error::new(ErrorKind::OutOfRange, "no addresses to connect to");
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return SimpleNode::from_stream(stream, magic, timeout),
+                Err(err) => last_err = err.into(),
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Connects to `target`, `host:port` with an IP address or a `.onion`
+    /// host, through the SOCKS5 proxy at `proxy` (Tor's, say). Does not
+    /// handshake.
+    pub fn connect_via_proxy<A: ToSocketAddrs>(proxy: A, target: &str, magic: [u8; 4], timeout: Duration) -> Result<SimpleNode> {
+        let (host, port) = split_host_port(target)?;
+        SimpleNode::from_stream(socks5_connect(proxy, &host, port, timeout)?, magic, timeout)
+    }
+
+    /// Connects as `connect` does and starts a v2 handshake, reconnecting
+    /// with v1 if the peer hangs up on it.
+    pub fn connect_v2<A: ToSocketAddrs>(addr: A, magic: [u8; 4], timeout: Duration) -> Result<SimpleNode> {
+        let mut node = SimpleNode::connect(addr, magic, timeout)?;
+        // Noted now, as a reset connection no longer has it
+        let peer = node.peer_addr()?;
+        match Transport::initiate(&mut node.stream, magic)? {
+            Some(transport) => node.transport = transport,
+            None => node = SimpleNode::connect(peer, magic, timeout)?,
+        }
+        Ok(node)
+    }
+
+    /// Wraps a connection a peer opened, answering its v2 handshake or
+    /// carrying on in v1 if it sends a v1 version message.
+    pub fn accept(stream: TcpStream, magic: [u8; 4], timeout: Duration) -> Result<SimpleNode> {
+        let mut node = SimpleNode::from_stream(stream, magic, timeout)?;
+        let (transport, queued) = Transport::respond(&mut node.stream, magic)?;
+        node.transport = transport;
+        node.queued = queued;
+        Ok(node)
+    }
+
+    /// Wraps an open connection, using v1.
+    pub fn from_stream(stream: TcpStream, magic: [u8; 4], timeout: Duration) -> Result<SimpleNode> {
+        let now = Instant::now();
+        let mut node = SimpleNode {
+            stream,
+            magic,
+            transport: Transport::V1,
+            queued: None,
+            timeout,
+            connected: true,
+            pending_ping: None,
+            last_ping: now,
+            last_seen: now,
+            latency: None,
+            peer_wants_headers: false,
+            peer_fee_filter: 0,
+            limits: MessageLimits::default(),
+            rate: RateCounter::new(),
+            misbehavior: Misbehavior::default(),
+            peer_version: None,
+            ping_interval: PING_INTERVAL,
+            ping_timeout: PING_TIMEOUT,
+        };
+        node.set_timeout(timeout)?;
+        Ok(node)
+    }
+
+    /// Changes how long reads, writes and `wait_for` may take.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Whether the connection uses BIP324's encrypted transport.
+    pub fn is_v2(&self) -> bool {
+        self.transport.is_v2()
+    }
+
+    /// The v2 session ID, the same on both ends of the connection.
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        match &self.transport {
+            Transport::V1 => None,
+            Transport::V2(cipher) => Some(cipher.session_id),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Closes the connection. Later sends and reads fail.
+    pub fn disconnect(&mut self) {
+        if self.connected {
+            self.connected = false;
+            // The peer may already have gone
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// When the peer last sent a message.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    /// Round trip time of the last answered ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Whether the peer wants new blocks announced with headers.
+    pub fn peer_wants_headers(&self) -> bool {
+        self.peer_wants_headers
+    }
+
+    /// The lowest fee rate, in satoshis per 1000 vbytes, of transactions
+    /// the peer wants announced. 0 until it sends a feefilter.
+    pub fn peer_fee_filter(&self) -> u64 {
+        self.peer_fee_filter
+    }
+
+    pub fn limits(&self) -> &MessageLimits {
+        &self.limits
+    }
+
+    /// Changes the bounds on what the node reads from the peer.
+    pub fn set_limits(&mut self, limits: MessageLimits) {
+        self.limits = limits;
+    }
+
+    /// The peer's violations so far.
+    pub fn misbehavior(&self) -> &Misbehavior {
+        &self.misbehavior
+    }
+
+    /// Adds `violation` to the peer's score, disconnecting it and failing
+    /// once the score reaches the ban threshold.
+    pub fn misbehaving(&mut self, violation: Violation) -> Result<()> {
+        if self.misbehavior.record(violation) {
+            self.disconnect();
+            return Err(banned(violation));
+        }
+        Ok(())
+    }
+
+    fn check_connected(&self) -> Result<()> {
+        if !self.connected {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::NotConnected, "disconnected from peer")));
+        }
+        Ok(())
+    }
+
+    fn send_ping(&mut self) -> Result<u64> {
+        let nonce = random_u64();
+        self.send(&PingMessage { nonce })?;
+        let now = Instant::now();
+        self.pending_ping = Some((nonce, now));
+        self.last_ping = now;
+        Ok(nonce)
+    }
+
+    /// Disconnects a peer that hasn't answered our ping in time, and pings
+    /// the peer if it's due.
+    pub fn keepalive(&mut self) -> Result<()> {
+        self.check_connected()?;
+        if let Some((_, sent)) = self.pending_ping {
+            if sent.elapsed() > self.ping_timeout {
+                self.disconnect();
+                return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "peer did not answer a ping")));
+            }
+        } else if self.peer_version.is_some() && self.last_ping.elapsed() >= self.ping_interval {
+            self.send_ping()?;
+        }
+        Ok(())
+    }
+
+    /// Pings the peer and waits for the pong, returning the round trip time.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let nonce = self.send_ping()?;
+        let deadline = Instant::now() + self.timeout;
+        while self.pending_ping.map(|(pending, _)| pending) == Some(nonce) {
+            let envelope = self.read()?;
+            self.respond(&envelope)?;
+            if Instant::now() > deadline {
+                return Err(timed_out("a pong"));
+            }
+        }
+        Ok(self.latency.unwrap())
+    }
+
+    /// Exchanges version and verack messages with the peer.
+    pub fn handshake(&mut self) -> Result<&VersionMessage> {
+        let peer = self.peer_addr()?;
+        self.send(&VersionMessage::new(NetAddress::from_socket_addr(0, peer), 0))?;
+        let deadline = Instant::now() + self.timeout;
+        let mut verack = false;
+        while !verack || self.peer_version.is_none() {
+            if Instant::now() > deadline {
+                return Err(timed_out("the handshake"));
+            }
+            let envelope = self.read()?;
+            if envelope.is::<VerAckMessage>() {
+                verack = true;
+            } else {
+                self.respond(&envelope)?;
+            }
+        }
+        Ok(self.peer_version.as_ref().unwrap())
+    }
+
+    pub fn send<M: Message>(&mut self, message: &M) -> Result<()> {
+        self.send_envelope(&NetworkEnvelope::from_message(self.magic, message))
+    }
+
+    fn send_envelope(&mut self, envelope: &NetworkEnvelope) -> Result<()> {
+        self.check_connected()?;
+        self.transport.write_envelope(&mut self.stream, envelope)
+    }
+
+    /// The next message from the peer, whatever it is. Pings the peer
+    /// first if it's due. Envelopes with a bad checksum or command are
+    /// scored and skipped; oversized ones and floods are scored too.
+    pub fn read(&mut self) -> Result<NetworkEnvelope> {
+        self.keepalive()?;
+        let envelope = match self.queued.take() {
+            Some(envelope) => envelope,
+            None => loop {
+                match self.transport.read_envelope(&mut self.stream, &self.magic, self.limits.max_payload) {
+                    Ok(envelope) => break envelope,
+                    // Read whole, so the next envelope follows
+                    Err(err) if err.kind() == ErrorKind::InvalidEncoding => self.misbehaving(Violation::InvalidChecksum)?,
+                    Err(err) if err.kind() == ErrorKind::OutOfRange => {
+                        self.misbehaving(Violation::OversizedMessage)?;
+                        return Err(err);
+                    }
+                    // A v2 packet that fails to authenticate
+                    Err(err) if err.kind() == ErrorKind::InvalidSignature => {
+                        self.misbehaving(Violation::InvalidChecksum)?;
+                        self.disconnect();
+                        return Err(err);
+                    }
+                    Err(err) => return Err(err),
+                }
+            },
+        };
+        self.last_seen = Instant::now();
+        if self.rate.count(&self.limits, envelope.payload.len()) {
+            self.misbehaving(Violation::MessageFlood)?;
+        }
+        Ok(envelope)
+    }
+
+    // Answers the messages a peer expects a reply to, notes pongs and the
+    // peer's preferences, and scores blocks nobody is waiting for
+    fn respond(&mut self, envelope: &NetworkEnvelope) -> Result<()> {
+        if envelope.is::<VersionMessage>() {
+            self.peer_version = Some(envelope.decode()?);
+            self.send(&VerAckMessage)?;
+        } else if envelope.is::<PingMessage>() {
+            let ping: PingMessage = envelope.decode()?;
+            self.send(&PongMessage { nonce: ping.nonce })?;
+        } else if envelope.is::<SendHeadersMessage>() {
+            self.peer_wants_headers = true;
+        } else if envelope.is::<FeeFilterMessage>() {
+            self.peer_fee_filter = envelope.decode::<FeeFilterMessage>()?.fee_rate;
+        } else if envelope.is::<PongMessage>() {
+            let pong: PongMessage = envelope.decode()?;
+            if let Some((nonce, sent)) = self.pending_ping {
+                if pong.nonce == nonce {
+                    self.latency = Some(sent.elapsed());
+                    self.pending_ping = None;
+                }
+            }
+        } else if [BlockMessage::COMMAND, MerkleBlockMessage::COMMAND, BlockTxnMessage::COMMAND].contains(&envelope.command.as_str()) {
+            self.misbehaving(Violation::UnsolicitedData)?;
+        }
+        Ok(())
+    }
+
+    /// Reads until an `M` arrives, answering version messages and pings on
+    /// the way and skipping everything else. Gives up after the node's
+    /// timeout.
+    pub fn wait_for<M: Message>(&mut self) -> Result<M> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let envelope = self.read()?;
+            if envelope.is::<M>() {
+                return envelope.decode();
+            }
+            self.respond(&envelope)?;
+            if Instant::now() > deadline {
+                return Err(timed_out(M::COMMAND));
+            }
+        }
+    }
+
+    /// Asks the peer for the objects in `inventory`.
+    pub fn get_data(&mut self, inventory: Vec<Inventory>) -> Result<()> {
+        self.send(&GetDataMessage { inventory })
+    }
+
+    /// Downloads the block with hash `hash` (display order), witnesses
+    /// included, and checks its merkle root.
+    pub fn get_block(&mut self, hash: &[u8; 32]) -> Result<Block> {
+        self.get_data(vec![Inventory::new(InvType::WitnessBlock, *hash)])?;
+        let block = self.wait_for::<BlockMessage>()?.0;
+        if block.header.hash() != *hash {
+            return Err(wrong_object(hash, &block.header.hash()));
+        }
+        if !block.validate_merkle_root() {
+            return Err(Error::new(ErrorKind::Fetch, format!("block {} has a bad merkle root", block.header.id())));
+        }
+        Ok(block)
+    }
+
+    /// Sets the bloom filter the peer matches against for filtered blocks
+    /// and transaction relay.
+    pub fn load_filter(&mut self, filter: &BloomFilter, flag: u8) -> Result<()> {
+        self.send(&FilterLoadMessage { filter: filter.clone(), flag })
+    }
+
+    /// Downloads the block with hash `hash` filtered by the loaded bloom
+    /// filter: the checked merkleblock and the matched transactions sent
+    /// after it. A peer skips transactions it already announced to us, so
+    /// use this before asking for the mempool.
+    pub fn get_filtered_block(&mut self, hash: &[u8; 32]) -> Result<(MerkleBlock, Vec<Tx>)> {
+        self.get_data(vec![Inventory::new(InvType::FilteredBlock, *hash)])?;
+        let merkle_block = self.wait_for::<MerkleBlockMessage>()?.0;
+        if merkle_block.header.hash() != *hash {
+            return Err(wrong_object(hash, &merkle_block.header.hash()));
+        }
+        let mut txs = vec![];
+        for txid in merkle_block.matched_txids()? {
+            let tx = self.wait_for::<TxMessage>()?.0;
+            if tx.hash() != txid {
+                return Err(wrong_object(&txid, &tx.hash()));
+            }
+            txs.push(tx);
+        }
+        Ok((merkle_block, txs))
+    }
+
+    /// Tells the peer we have `tx`, which it may then ask for.
+    pub fn announce_tx(&mut self, tx: &Tx) -> Result<()> {
+        self.send(&InvMessage { inventory: vec![Inventory::new(InvType::Tx, tx.hash())] })
+    }
+
+    /// Announces `tx`, which pays `fee`, unless its fee rate is under the
+    /// peer's fee filter. Returns whether it was announced.
+    pub fn relay_tx(&mut self, tx: &Tx, fee: u64) -> Result<bool> {
+        if (fee as u128) * 1000 < self.peer_fee_filter as u128 * tx.vsize() as u128 {
+            return Ok(false);
+        }
+        self.announce_tx(tx)?;
+        Ok(true)
+    }
+
+    /// Announces a new block the way the peer asked for: its header if
+    /// the peer sent sendheaders, an inv otherwise.
+    pub fn announce_block(&mut self, header: &BlockHeader) -> Result<()> {
+        if self.peer_wants_headers {
+            self.send(&HeadersMessage { headers: vec![header.clone()] })
+        } else {
+            self.send(&InvMessage { inventory: vec![Inventory::new(InvType::Block, header.hash())] })
+        }
+    }
+
+    /// Asks the peer to announce new blocks to us with headers.
+    pub fn send_headers(&mut self) -> Result<()> {
+        self.send(&SendHeadersMessage)
+    }
+
+    /// Asks the peer not to announce transactions paying under `fee_rate`
+    /// satoshis per 1000 vbytes.
+    pub fn set_fee_filter(&mut self, fee_rate: u64) -> Result<()> {
+        self.send(&FeeFilterMessage { fee_rate })
+    }
+
+    /// The txids (display order) of the transactions in the peer's
+    /// mempool. Pings after the request; the peer handles messages in
+    /// order, so the pong comes after its last inv.
+    pub fn get_mempool(&mut self) -> Result<Vec<[u8; 32]>> {
+        self.send(&MempoolMessage)?;
+        let nonce = random_u64();
+        self.send(&PingMessage { nonce })?;
+        let deadline = Instant::now() + self.timeout;
+        let mut txids = vec![];
+        loop {
+            let envelope = self.read()?;
+            if envelope.is::<InvMessage>() {
+                let inv: InvMessage = envelope.decode()?;
+                txids.extend(inv.inventory.iter().filter(|item| matches!(item.kind, InvType::Tx | InvType::WitnessTx)).map(|item| item.hash));
+            } else if envelope.is::<PongMessage>() && envelope.decode::<PongMessage>()?.nonce == nonce {
+                return Ok(txids);
+            } else {
+                self.respond(&envelope)?;
+            }
+            if Instant::now() > deadline {
+                return Err(timed_out("the pong after a mempool request"));
+            }
+        }
+    }
+
+    /// Hands `tx` to the peer: announces it, sends it when the peer asks
+    /// for it, then pings. The peer handles messages in order, so a pong
+    /// without a reject before it means the transaction was accepted. A
+    /// peer that never asks already has the transaction or won't take it,
+    /// and the broadcast times out.
+    pub fn broadcast_tx(&mut self, tx: &Tx) -> Result<()> {
+        let txid = tx.hash();
+        self.announce_tx(tx)?;
+        let deadline = Instant::now() + self.timeout;
+        let witness = loop {
+            if Instant::now() > deadline {
+                return Err(timed_out(&format!("a getdata for {}", tx.id())));
+            }
+            let request = self.wait_for::<GetDataMessage>()?;
+            if let Some(item) = request.inventory.iter().find(|item| item.hash == txid) {
+                break item.kind == InvType::WitnessTx;
+            }
+        };
+        let payload = if witness { tx.serialize() } else { tx.serialize_legacy() };
+        self.send_envelope(&NetworkEnvelope::new(self.magic, TxMessage::COMMAND, payload))?;
+        let nonce = random_u64();
+        self.send(&PingMessage { nonce })?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let envelope = self.read()?;
+            if envelope.is::<RejectMessage>() {
+                let reject: RejectMessage = envelope.decode()?;
+                if reject.hash == Some(txid) {
+                    return Err(Error::new(ErrorKind::Fetch, format!("{} rejected: {} ({:#04x})", tx.id(), reject.reason, reject.code)));
+                }
+            } else if envelope.is::<PongMessage>() && envelope.decode::<PongMessage>()?.nonce == nonce {
+                return Ok(());
+            } else {
+                self.respond(&envelope)?;
+            }
+            if Instant::now() > deadline {
+                return Err(timed_out("the pong after a broadcast"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::params::Network;
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn, TxOut};
+#[cfg(test)]
+use std::io::Write;
+#[cfg(test)]
+use std::net::TcpListener;
+
+#[test]
+fn handshake_with_peer() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut node = SimpleNode::from_stream(stream, magic, DEFAULT_TIMEOUT).unwrap();
+        let version = node.wait_for::<VersionMessage>().unwrap();
+        assert_eq!(version.receiver.port, addr.port());
+        let mut ours = VersionMessage::new(version.sender, 100);
+        ours.user_agent = "/peer:1.0/".to_string();
+        node.send(&ours).unwrap();
+        node.send(&VerAckMessage).unwrap();
+        node.wait_for::<VerAckMessage>().unwrap();
+        // Something the other side isn't waiting for, then what it is
+        node.stream.write_all(&NetworkEnvelope::new(magic, "sendheaders", vec![]).serialize()).unwrap();
+        node.send(&VerAckMessage).unwrap();
+        // Then silence until the other side hangs up
+        let _ = node.read();
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    let version = node.handshake().unwrap();
+    assert_eq!((version.user_agent.as_str(), version.latest_block), ("/peer:1.0/", 100));
+    node.wait_for::<VerAckMessage>().unwrap();
+    node.set_timeout(Duration::from_millis(100)).unwrap();
+    assert_eq!(node.wait_for::<VerAckMessage>().unwrap_err().kind(), ErrorKind::Io);
+    drop(node);
+    peer.join().unwrap();
+}
+
+#[test]
+fn request_blocks() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let txs: Vec<Tx> = (0..2u8)
+        .map(|i| Tx::new(1, vec![TxIn::new([i; 32], 0)], vec![TxOut::new(1000, vec![0x51])], LockTime::ZERO, Network::Mainnet))
+        .collect();
+    let header = crate::block::BlockHeader { version: 1, prev_block: [0; 32], merkle_root: [0; 32], timestamp: 0, bits: 0x207f_ffff, nonce: 0 };
+    let mut block = Block { header, txs: txs.clone() };
+    block.header.merkle_root = block.merkle_root();
+    let hash = block.header.hash();
+    // Only the second transaction matches: the root's flag, then the leaves'
+    let internal = |tx: &Tx| crate::hash::hash256(&tx.serialize_legacy());
+    let merkle_block = MerkleBlock { header: block.header.clone(), total: 2, hashes: vec![internal(&txs[0]), internal(&txs[1])], flags: vec![0b101] };
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let served = block.clone();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(node.wait_for::<GetDataMessage>().unwrap().inventory[0].kind, InvType::WitnessBlock);
+        node.send(&BlockMessage(served.clone())).unwrap();
+        node.wait_for::<FilterLoadMessage>().unwrap();
+        assert_eq!(node.wait_for::<GetDataMessage>().unwrap().inventory[0].kind, InvType::FilteredBlock);
+        node.send(&MerkleBlockMessage(merkle_block)).unwrap();
+        node.send(&TxMessage(served.txs[1].clone())).unwrap();
+        assert_eq!(node.wait_for::<InvMessage>().unwrap().inventory[0].hash, served.txs[0].hash());
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    assert_eq!(node.get_block(&hash).unwrap(), block);
+    node.load_filter(&BloomFilter::new(10, 5, 0).unwrap(), crate::bloom::BLOOM_UPDATE_NONE).unwrap();
+    let (merkle_block, matched) = node.get_filtered_block(&hash).unwrap();
+    assert_eq!((merkle_block.header, matched), (block.header, vec![txs[1].clone()]));
+    node.announce_tx(&txs[0]).unwrap();
+    peer.join().unwrap();
+}
+
+#[test]
+fn broadcast() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let mut tx = Tx::new(2, vec![TxIn::new([1; 32], 0)], vec![TxOut::new(1000, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+    tx.tx_ins[0].witness = vec![vec![0xab]];
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let txid = tx.hash();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        for reject in [false, true] {
+            let inv = node.wait_for::<InvMessage>().unwrap();
+            node.get_data(vec![Inventory::new(InvType::WitnessTx, inv.inventory[0].hash)]).unwrap();
+            assert!(node.wait_for::<TxMessage>().unwrap().0.is_segwit());
+            let ping = node.wait_for::<PingMessage>().unwrap();
+            if reject {
+                let reason = "insufficient fee".to_string();
+                node.send(&RejectMessage { message: "tx".to_string(), code: 0x42, reason, hash: Some(txid) }).unwrap();
+            }
+            node.send(&PongMessage { nonce: ping.nonce }).unwrap();
+        }
+        // Never asks for the third
+        node.wait_for::<InvMessage>().unwrap();
+        let _ = node.read();
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    node.broadcast_tx(&tx).unwrap();
+    let err = node.broadcast_tx(&tx).unwrap_err();
+    assert!(err.to_string().contains("insufficient fee"));
+    node.set_timeout(Duration::from_millis(100)).unwrap();
+    assert_eq!(node.broadcast_tx(&tx).unwrap_err().kind(), ErrorKind::Io);
+    drop(node);
+    peer.join().unwrap();
+}
+
+#[test]
+fn keepalive() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        node.send(&PingMessage { nonce: 7 }).unwrap();
+        node.send(&VerAckMessage).unwrap();
+        assert_eq!(node.wait_for::<PongMessage>().unwrap().nonce, 7);
+        // Answers the first ping from the other side, not the second
+        let ping = node.wait_for::<PingMessage>().unwrap();
+        node.send(&PongMessage { nonce: ping.nonce }).unwrap();
+        node.wait_for::<PingMessage>().unwrap();
+        let _ = node.read();
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    node.wait_for::<VerAckMessage>().unwrap();
+    assert!(node.ping().unwrap() < DEFAULT_TIMEOUT);
+    assert!(node.latency().is_some() && node.last_seen().elapsed() < DEFAULT_TIMEOUT);
+
+    node.set_timeout(Duration::from_millis(50)).unwrap();
+    node.peer_version = Some(VersionMessage::new(NetAddress::from_ipv4(0, [127, 0, 0, 1], addr.port()), 0));
+    node.ping_interval = Duration::ZERO;
+    node.ping_timeout = Duration::from_millis(100);
+    // The first read sends the due ping, later ones give up on the peer
+    let deadline = Instant::now() + DEFAULT_TIMEOUT;
+    while node.is_connected() && Instant::now() < deadline {
+        let _ = node.read();
+    }
+    assert!(!node.is_connected());
+    assert_eq!(node.send(&VerAckMessage).unwrap_err().kind(), ErrorKind::Io);
+    peer.join().unwrap();
+}
+
+#[test]
+fn peer_preferences() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let tx = Tx::new(1, vec![TxIn::new([1; 32], 0)], vec![TxOut::new(1000, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+    let header = crate::block::BlockHeader { version: 1, prev_block: [0; 32], merkle_root: [0; 32], timestamp: 0, bits: 0x207f_ffff, nonce: 0 };
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (txid, served) = (tx.hash(), header.clone());
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(node.wait_for::<InvMessage>().unwrap().inventory[0].kind, InvType::Block);
+        node.send_headers().unwrap();
+        node.set_fee_filter(10_000).unwrap();
+        node.send(&VerAckMessage).unwrap();
+        assert_eq!(node.wait_for::<HeadersMessage>().unwrap().headers, vec![served]);
+        // Only the second relay clears the filter
+        assert_eq!(node.wait_for::<InvMessage>().unwrap().inventory[0].hash, txid);
+        node.wait_for::<MempoolMessage>().unwrap();
+        node.send(&InvMessage { inventory: vec![Inventory::new(InvType::Tx, [7; 32]), Inventory::new(InvType::Block, [8; 32])] }).unwrap();
+        node.send(&InvMessage { inventory: vec![Inventory::new(InvType::WitnessTx, [9; 32])] }).unwrap();
+        let ping = node.wait_for::<PingMessage>().unwrap();
+        node.send(&PongMessage { nonce: ping.nonce }).unwrap();
+        let _ = node.read();
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    node.announce_block(&header).unwrap();
+    node.wait_for::<VerAckMessage>().unwrap();
+    assert!(node.peer_wants_headers());
+    assert_eq!(node.peer_fee_filter(), 10_000);
+    node.announce_block(&header).unwrap();
+    // 10 sat/vbyte is the filter
+    assert!(!node.relay_tx(&tx, tx.vsize() as u64 * 10 - 1).unwrap());
+    assert!(node.relay_tx(&tx, tx.vsize() as u64 * 10).unwrap());
+    assert_eq!(node.get_mempool().unwrap(), vec![[7; 32], [9; 32]]);
+    drop(node);
+    peer.join().unwrap();
+}
+
+#[test]
+fn misbehaving_peer() {
+    let magic = crate::params::NetworkParams::regtest().magic;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut corrupt = NetworkEnvelope::from_message(magic, &PingMessage { nonce: 1 }).serialize();
+        corrupt[20] ^= 1;
+        stream.write_all(&corrupt).unwrap();
+        stream.write_all(&NetworkEnvelope::from_message(magic, &VerAckMessage).serialize()).unwrap();
+        stream.write_all(&NetworkEnvelope::new(magic, "merkleblock", vec![]).serialize()).unwrap();
+        stream.write_all(&NetworkEnvelope::new(magic, "block", vec![0; 1000]).serialize()).unwrap();
+        let _ = std::io::Read::read(&mut stream, &mut [0]);
+    });
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    node.set_limits(MessageLimits { max_payload: 100, ..MessageLimits::default() });
+    // The corrupt ping is skipped
+    node.wait_for::<VerAckMessage>().unwrap();
+    assert_eq!(node.misbehavior().violations(), &[Violation::InvalidChecksum]);
+    let err = node.wait_for::<PongMessage>().unwrap_err();
+    assert!(err.to_string().contains("oversized message"));
+    assert_eq!(node.misbehavior().violations(), &[Violation::InvalidChecksum, Violation::UnsolicitedData, Violation::OversizedMessage]);
+    assert!(!node.is_connected());
+    peer.join().unwrap();
+}
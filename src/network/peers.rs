@@ -0,0 +1,265 @@
+//! Finding peers: DNS seeds to start from, then the addresses peers pass
+//! around in addr messages, kept in an address manager that remembers
+//! which peers worked and which are banned.
+
+use super::messages::{unix_time, AddrMessage, AddrV2Message};
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::NetworkParams;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+/// Seconds before a peer that was tried is offered again.
+pub const RETRY_DELAY: u64 = 10 * 60;
+/// Failed attempts in a row after which a peer is forgotten.
+pub const MAX_FAILURES: u32 = 10;
+
+/// The addresses the network's DNS seeds list, on its default port. A
+/// seed that doesn't resolve is skipped.
+pub fn dns_seed_addresses(params: &NetworkParams) -> Vec<SocketAddr> {
+    let mut addresses = vec![];
+    for seed in params.dns_seeds.iter() {
+        if let Ok(resolved) = (seed.as_str(), params.default_port).to_socket_addrs() {
+            addresses.extend(resolved);
+        }
+    }
+    addresses
+}
+
+/// What the address manager knows about a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub services: u64,
+    /// When the peer was last heard of, in Unix time
+    pub last_seen: u64,
+    /// When we last tried to connect, 0 if never
+    pub last_attempt: u64,
+    /// Failed attempts since the last success
+    pub failures: u32,
+    pub successes: u32,
+}
+
+impl PeerInfo {
+    // Peers that worked before come first, then recently seen ones; each
+    // failure halves the score
+    fn score(&self, now: u64) -> f64 {
+        let age_hours = now.saturating_sub(self.last_seen) as f64 / 3600.0;
+        let base = (1 + self.successes.min(10)) as f64 / (1.0 + age_hours / 24.0);
+        base / 2f64.powi(self.failures as i32)
+    }
+}
+
+/// Known peer addresses and how connecting to them went.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddrMan {
+    peers: HashMap<SocketAddr, PeerInfo>,
+    /// Banned IPs and when their bans end, in Unix time
+    banned: HashMap<IpAddr, u64>,
+}
+
+fn invalid_line(line: &str) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("bad peer entry {:?}", line))
+}
+
+impl AddrMan {
+    pub fn new() -> AddrMan {
+        AddrMan::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    /// Records that `addr` was seen at `time`, returning whether it's new.
+    /// Times in the future count as now.
+    pub fn add(&mut self, addr: SocketAddr, services: u64, time: u64) -> bool {
+        let time = time.min(unix_time());
+        match self.peers.get_mut(&addr) {
+            Some(info) => {
+                info.services |= services;
+                info.last_seen = info.last_seen.max(time);
+                false
+            }
+            None => {
+                self.peers.insert(addr, PeerInfo { services, last_seen: time, ..PeerInfo::default() });
+                true
+            }
+        }
+    }
+
+    /// Adds the network's DNS seed addresses, returning how many were new.
+    pub fn add_dns_seeds(&mut self, params: &NetworkParams) -> usize {
+        let now = unix_time();
+        dns_seed_addresses(params).into_iter().filter(|addr| self.add(*addr, 0, now)).count()
+    }
+
+    /// Adds the addresses in an addr message, returning how many were new.
+    pub fn add_addr(&mut self, message: &AddrMessage) -> usize {
+        message.addresses.iter().filter(|(time, address)| self.add(address.socket_addr(), address.services, *time as u64)).count()
+    }
+
+    /// Adds the IPv4 and IPv6 addresses in an addrv2 message, returning how
+    /// many were new. Others can't be connected to directly.
+    pub fn add_addrv2(&mut self, message: &AddrV2Message) -> usize {
+        let mut added = 0;
+        for entry in message.addresses.iter() {
+            if let Some(addr) = entry.socket_addr() {
+                added += self.add(addr, entry.services, entry.time as u64) as usize;
+            }
+        }
+        added
+    }
+
+    /// Notes a connection attempt to `addr`, so `select` passes it over
+    /// for a while.
+    pub fn mark_attempt(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.last_attempt = unix_time();
+        }
+    }
+
+    pub fn mark_success(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.last_seen = unix_time();
+            info.failures = 0;
+            info.successes += 1;
+        }
+    }
+
+    /// Notes a failed connection, forgetting the peer after `MAX_FAILURES`
+    /// in a row.
+    pub fn mark_failure(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.failures += 1;
+            if info.failures >= MAX_FAILURES {
+                self.peers.remove(addr);
+            }
+        }
+    }
+
+    /// Bans every peer at `ip` until `until`, in Unix time.
+    pub fn ban(&mut self, ip: IpAddr, until: u64) {
+        let until = self.banned.get(&ip).map_or(until, |current| until.max(*current));
+        self.banned.insert(ip, until);
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) {
+        self.banned.remove(ip);
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.banned.get(&addr.ip()).is_some_and(|until| *until > unix_time())
+    }
+
+    /// Up to `n` peers to try, best scored first, leaving out banned ones
+    /// and those tried in the last `RETRY_DELAY` seconds so repeated calls
+    /// rotate through the table.
+    pub fn select(&self, n: usize) -> Vec<SocketAddr> {
+        let now = unix_time();
+        let mut candidates: Vec<(f64, u64, SocketAddr)> = self
+            .peers
+            .iter()
+            .filter(|(addr, info)| info.last_attempt + RETRY_DELAY <= now && !self.is_banned(addr))
+            .map(|(addr, info)| (info.score(now), info.last_attempt, *addr))
+            .collect();
+        // Ties go to the peer tried longest ago
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        candidates.into_iter().take(n).map(|(_, _, addr)| addr).collect()
+    }
+
+    /// Writes the table to `path`, a line per peer, then a line per
+    /// unexpired ban.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut lines: Vec<String> = self
+            .peers
+            .iter()
+            .map(|(addr, info)| format!("{} {} {} {} {} {}\n", addr, info.services, info.last_seen, info.last_attempt, info.failures, info.successes))
+            .collect();
+        lines.sort();
+        let now = unix_time();
+        let mut bans: Vec<String> = self.banned.iter().filter(|(_, until)| **until > now).map(|(ip, until)| format!("ban {} {}\n", ip, until)).collect();
+        bans.sort();
+        lines.extend(bans);
+        fs::write(path, lines.concat())?;
+        Ok(())
+    }
+
+    /// Reads a table `save` wrote.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AddrMan> {
+        let mut peers = HashMap::new();
+        let mut banned = HashMap::new();
+        for line in fs::read_to_string(path)?.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() == 3 && fields[0] == "ban" {
+                let ip = fields[1].parse().map_err(|_| invalid_line(line))?;
+                banned.insert(ip, fields[2].parse().map_err(|_| invalid_line(line))?);
+                continue;
+            }
+            if fields.len() != 6 {
+                return Err(invalid_line(line));
+            }
+            let addr = fields[0].parse().map_err(|_| invalid_line(line))?;
+            let number = |i: usize| fields[i].parse::<u64>().map_err(|_| invalid_line(line));
+            let info = PeerInfo {
+                services: number(1)?,
+                last_seen: number(2)?,
+                last_attempt: number(3)?,
+                failures: number(4)? as u32,
+                successes: number(5)? as u32,
+            };
+            peers.insert(addr, info);
+        }
+        Ok(AddrMan { peers, banned })
+    }
+}
+
+#[cfg(test)]
+use super::messages::{NetAddress, NODE_NETWORK};
+
+#[test]
+fn address_manager() {
+    let now = unix_time();
+    let mut addrman = AddrMan::new();
+    let addr = AddrMessage {
+        addresses: (1..=4u8).map(|i| (now as u32 - i as u32 * 3600, NetAddress::from_ipv4(NODE_NETWORK, [10, 0, 0, i], 8333))).collect(),
+    };
+    assert_eq!(addrman.add_addr(&addr), 4);
+    assert_eq!(addrman.add_addr(&addr), 0);
+    let peer = |i: u8| SocketAddr::from(([10, 0, 0, i], 8333));
+    // Most recently seen first
+    assert_eq!(addrman.select(2), vec![peer(1), peer(2)]);
+
+    // A success puts a peer first, a failure last; tried peers sit out
+    addrman.mark_attempt(&peer(4));
+    addrman.mark_success(&peer(4));
+    addrman.mark_failure(&peer(1));
+    assert_eq!(addrman.select(4), vec![peer(2), peer(3), peer(1)]);
+    addrman.peers.get_mut(&peer(4)).unwrap().last_attempt = 0;
+    assert_eq!(addrman.select(1), vec![peer(4)]);
+    for _ in 1..MAX_FAILURES {
+        addrman.mark_failure(&peer(1));
+    }
+    assert_eq!((addrman.len(), addrman.get(&peer(1))), (3, None));
+    addrman.ban(peer(4).ip(), now + 60);
+    assert!(addrman.is_banned(&peer(4)));
+    assert!(!addrman.select(4).contains(&peer(4)));
+
+    let path = std::env::temp_dir().join(format!("prog_btc_book_peers_{}", std::process::id()));
+    addrman.save(&path).unwrap();
+    assert_eq!(AddrMan::load(&path).unwrap(), addrman);
+    addrman.unban(&peer(4).ip());
+    assert!(!addrman.is_banned(&peer(4)));
+    fs::write(&path, "10.0.0.1:8333 1 2\n").unwrap();
+    assert_eq!(AddrMan::load(&path).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    fs::remove_file(&path).unwrap();
+    assert!(dns_seed_addresses(&NetworkParams::regtest()).is_empty());
+}
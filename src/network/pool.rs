@@ -0,0 +1,278 @@
+//! Several peers at once, instead of the book's single connection.
+//!
+//! Each peer is a `SimpleNode` with a small state machine: it starts
+//! `Ready`, a timeout stalls it, and a second timeout or any other
+//! failure disconnects it. Connecting and broadcasting run on a thread per
+//! peer; headers come from the peer claiming the longest chain. A peer
+//! its node disconnected for misbehaving is banned when it's pruned.
+
+use super::messages::unix_time;
+use super::misbehavior::BAN_DURATION;
+use super::node::{SimpleNode, DEFAULT_TIMEOUT};
+use super::peers::AddrMan;
+use super::sync::{HeaderStore, HeaderSync};
+use super::VersionMessage;
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::NetworkParams;
+use crate::tx::Tx;
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerState {
+    /// Handshake done and answering
+    Ready,
+    /// Timed out once; used only when no ready peer will do
+    Stalled,
+    /// Failed, with why. `prune` removes these.
+    Disconnected(String),
+}
+
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub node: SimpleNode,
+    state: PeerState,
+}
+
+impl Peer {
+    pub fn state(&self) -> &PeerState {
+        &self.state
+    }
+
+    pub fn version(&self) -> Option<&VersionMessage> {
+        self.node.peer_version.as_ref()
+    }
+
+    fn height(&self) -> u32 {
+        self.version().map(|version| version.latest_block).unwrap_or(0)
+    }
+
+    fn is_usable(&self) -> bool {
+        !matches!(self.state, PeerState::Disconnected(_))
+    }
+
+    // Moves the state machine on after a request to the peer
+    fn record<T>(&mut self, result: &Result<T>) {
+        self.state = match (result, &self.state) {
+            (Ok(_), _) => PeerState::Ready,
+            // The peer answered, with a reject
+            (Err(err), state) if err.kind() == ErrorKind::Fetch => state.clone(),
+            (Err(Error::Io(err)), PeerState::Ready) if matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => PeerState::Stalled,
+            (Err(err), _) => {
+                self.node.disconnect();
+                PeerState::Disconnected(err.to_string())
+            }
+        };
+    }
+}
+
+/// Keeps up to a target number of peers connected, finding them through
+/// an address manager.
+pub struct NodePool {
+    params: NetworkParams,
+    target: usize,
+    timeout: Duration,
+    peers: Vec<Peer>,
+    pub addrman: AddrMan,
+}
+
+fn connect_peer(addr: SocketAddr, magic: [u8; 4], timeout: Duration) -> Result<SimpleNode> {
+    let mut node = SimpleNode::connect(addr, magic, timeout)?;
+    node.handshake()?;
+    Ok(node)
+}
+
+impl NodePool {
+    /// A pool aiming for `target` peers on the network `params` describes.
+    pub fn new(params: NetworkParams, target: usize) -> NodePool {
+        NodePool { params, target, timeout: DEFAULT_TIMEOUT, peers: vec![], addrman: AddrMan::new() }
+    }
+
+    /// Changes the timeout new connections get.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    /// How many peers aren't disconnected.
+    pub fn len(&self) -> usize {
+        self.peers.iter().filter(|peer| peer.is_usable()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Connects to and handshakes with `addrs` in parallel, skipping
+    /// banned ones and noting the outcomes in the address manager.
+    /// Returns how many were added.
+    pub fn connect(&mut self, addrs: &[SocketAddr]) -> usize {
+        let (magic, timeout) = (self.params.magic, self.timeout);
+        let addrman = &self.addrman;
+        let results: Vec<(SocketAddr, Result<SimpleNode>)> = thread::scope(|scope| {
+            let handles: Vec<_> = addrs.iter().filter(|addr| !addrman.is_banned(addr)).map(|addr| (*addr, scope.spawn(move || connect_peer(*addr, magic, timeout)))).collect();
+            handles.into_iter().map(|(addr, handle)| (addr, handle.join().unwrap())).collect()
+        });
+        let mut added = 0;
+        for (addr, result) in results {
+            self.addrman.mark_attempt(&addr);
+            match result {
+                Ok(node) => {
+                    self.addrman.mark_success(&addr);
+                    self.peers.push(Peer { addr, node, state: PeerState::Ready });
+                    added += 1;
+                }
+                Err(_) => self.addrman.mark_failure(&addr),
+            }
+        }
+        added
+    }
+
+    /// Drops disconnected peers and connects to new ones from the address
+    /// manager, seeding it from DNS when it's empty, until the pool has
+    /// its target. Returns how many were added.
+    pub fn fill(&mut self) -> usize {
+        self.prune();
+        if self.addrman.is_empty() {
+            self.addrman.add_dns_seeds(&self.params);
+        }
+        let missing = self.target.saturating_sub(self.peers.len());
+        let connected: Vec<SocketAddr> = self.peers.iter().map(|peer| peer.addr).collect();
+        let candidates: Vec<SocketAddr> = self
+            .addrman
+            .select(missing + connected.len())
+            .into_iter()
+            .filter(|addr| !connected.contains(addr))
+            .take(missing)
+            .collect();
+        self.connect(&candidates)
+    }
+
+    /// Forgets disconnected peers, banning those that misbehaved.
+    pub fn prune(&mut self) {
+        let until = unix_time() + BAN_DURATION;
+        for peer in self.peers.iter().filter(|peer| peer.node.misbehavior().should_ban()) {
+            self.addrman.ban(peer.addr.ip(), until);
+        }
+        self.peers.retain(|peer| peer.is_usable());
+    }
+
+    /// The ready peer with the highest best block, else the highest
+    /// stalled one.
+    fn best_peer(&mut self) -> Option<&mut Peer> {
+        self.peers.iter_mut().filter(|peer| peer.is_usable()).max_by_key(|peer| (peer.state == PeerState::Ready, peer.height()))
+    }
+
+    /// Sends `tx` to every peer at once, returning how many accepted it.
+    pub fn broadcast_tx(&mut self, tx: &Tx) -> usize {
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .peers
+                .iter_mut()
+                .filter(|peer| peer.is_usable())
+                .map(|peer| {
+                    scope.spawn(move || {
+                        let result = peer.node.broadcast_tx(tx);
+                        peer.record(&result);
+                        result.is_ok()
+                    })
+                })
+                .collect();
+            handles.into_iter().filter_map(|handle| handle.join().ok()).filter(|accepted| *accepted).count()
+        })
+    }
+
+    /// Syncs headers from the best peer, moving on to the next best when
+    /// one fails. Headers a failed peer sent are kept. Returns how many
+    /// were added.
+    pub fn sync_headers<S: HeaderStore>(&mut self, sync: &mut HeaderSync<S>) -> Result<usize> {
+        let start = sync.store.tip().0;
+        let mut last_err = Error::new(ErrorKind::Fetch, "no peers to sync headers from");
+        // Each peer can stall once, then fail
+        for _ in 0..2 * self.peers.len() {
+            let peer = match self.best_peer() {
+                Some(peer) => peer,
+                None => break,
+            };
+            let result = sync.sync(&mut peer.node);
+            peer.record(&result);
+            match result {
+                Ok(_) => return Ok(sync.store.tip().0.saturating_sub(start) as usize),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+use super::messages::{GetHeadersMessage, HeadersMessage, InvMessage, InvType, Inventory, PingMessage, PongMessage, TxMessage, VerAckMessage};
+#[cfg(test)]
+use super::sync::MemoryHeaderStore;
+#[cfg(test)]
+use crate::params::Network;
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn, TxOut};
+#[cfg(test)]
+use std::net::TcpListener;
+
+// Handshakes claiming `height`, takes one transaction, then serves
+// headers if asked
+#[cfg(test)]
+fn fake_peer(listener: TcpListener, magic: [u8; 4], height: u32) -> thread::JoinHandle<bool> {
+    thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        let version = node.wait_for::<VersionMessage>().unwrap();
+        node.send(&VersionMessage::new(version.sender, height)).unwrap();
+        node.send(&VerAckMessage).unwrap();
+        node.wait_for::<VerAckMessage>().unwrap();
+        let inv = node.wait_for::<InvMessage>().unwrap();
+        node.get_data(vec![Inventory::new(InvType::Tx, inv.inventory[0].hash)]).unwrap();
+        node.wait_for::<TxMessage>().unwrap();
+        let ping = node.wait_for::<PingMessage>().unwrap();
+        node.send(&PongMessage { nonce: ping.nonce }).unwrap();
+        match node.wait_for::<GetHeadersMessage>() {
+            Ok(_) => {
+                node.send(&HeadersMessage { headers: vec![] }).unwrap();
+                let _ = node.read();
+                true
+            }
+            Err(_) => false,
+        }
+    })
+}
+
+#[test]
+fn node_pool() {
+    let regtest = NetworkParams::regtest();
+    let mut pool = NodePool::new(regtest.clone(), 3);
+    let mut peers = vec![];
+    for height in [10, 20] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        pool.addrman.add(listener.local_addr().unwrap(), 0, 0);
+        peers.push(fake_peer(listener, regtest.magic, height));
+    }
+    // Nothing listens on the third
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    pool.addrman.add(closed, 0, 0);
+    assert_eq!(pool.fill(), 2);
+    assert_eq!(pool.len(), 2);
+    assert_eq!(pool.addrman.get(&closed).unwrap().failures, 1);
+    assert!(pool.peers().iter().all(|peer| *peer.state() == PeerState::Ready));
+
+    let tx = Tx::new(1, vec![TxIn::new([1; 32], 0)], vec![TxOut::new(1000, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+    assert_eq!(pool.broadcast_tx(&tx), 2);
+
+    let genesis = crate::block::BlockHeader::parse(&mut &crate::encoding::util::decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis), regtest).unwrap();
+    assert_eq!(pool.sync_headers(&mut sync).unwrap(), 0);
+    drop(pool);
+    // Only the peer with the longer chain was asked
+    let asked: Vec<bool> = peers.into_iter().map(|peer| peer.join().unwrap()).collect();
+    assert_eq!(asked, vec![false, true]);
+}
@@ -0,0 +1,175 @@
+//! Connecting through a SOCKS5 proxy (RFC 1928), such as the one Tor
+//! runs on port 9050.
+//!
+//! Host names go to the proxy unresolved, so `.onion` addresses work and
+//! DNS lookups don't leak around Tor.
+
+use crate::error::{Error, ErrorKind, Result};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Tor's default SOCKS port.
+pub const TOR_SOCKS_PORT: u16 = 9050;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+fn socks_error(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("SOCKS5 proxy: {}", msg))
+}
+
+// The reasons RFC 1928 gives for refusing a connection
+fn reply_reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// Splits `host:port`, with IPv6 hosts in brackets.
+pub fn split_host_port(target: &str) -> Result<(String, u16)> {
+    let invalid = || Error::new(ErrorKind::InvalidEncoding, format!("expected host:port, got {}", target));
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port.parse().map_err(|_| invalid())?))
+}
+
+/// Opens a connection to `host:port` through the SOCKS5 proxy at `proxy`.
+/// `host` may be an IP address or a name for the proxy to resolve.
+pub fn socks5_connect<A: ToSocketAddrs>(proxy: A, host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = Error::new(ErrorKind::OutOfRange, "no proxy addresses to connect to");
+    let mut stream = None;
+    for addr in proxy.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(err) => last_err = err.into(),
+        }
+    }
+    let mut stream = stream.ok_or(last_err)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [SOCKS_VERSION, NO_AUTHENTICATION] {
+        return Err(socks_error(format!("unsupported authentication {:02x?}", reply)));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(socks_error(format!("host name of {} bytes", host.len())));
+            }
+            request.extend_from_slice(&[ATYP_DOMAIN, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(socks_error(format!("version {} reply", reply[0])));
+    }
+    if reply[1] != 0 {
+        let err = io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5 proxy: {} ({})", reply_reason(reply[1]), reply[1]));
+        return Err(Error::Io(err));
+    }
+    // The address the proxy bound, which nothing here needs
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => return Err(socks_error(format!("address type {}", atyp))),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(stream)
+}
+
+/// The local Tor SOCKS proxy's address.
+pub fn local_tor_proxy() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], TOR_SOCKS_PORT))
+}
+
+#[cfg(test)]
+use super::{NetworkEnvelope, SimpleNode, VerAckMessage};
+#[cfg(test)]
+use std::net::TcpListener;
+
+// A proxy that accepts one connection, checks the request and answers
+// with `reply_code`
+#[cfg(test)]
+fn fake_proxy(expected_request: Vec<u8>, reply_code: u8) -> (SocketAddr, std::thread::JoinHandle<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let proxy = std::thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        client.write_all(&[5, 0]).unwrap();
+        let mut request = vec![0u8; expected_request.len()];
+        client.read_exact(&mut request).unwrap();
+        assert_eq!(request, expected_request);
+        client.write_all(&[5, reply_code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).unwrap();
+        client
+    });
+    (addr, proxy)
+}
+
+#[test]
+fn socks5() {
+    let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    let mut request = vec![5, 1, 0, ATYP_DOMAIN, onion.len() as u8];
+    request.extend_from_slice(onion.as_bytes());
+    request.extend_from_slice(&8333u16.to_be_bytes());
+    let (addr, proxy) = fake_proxy(request, 0);
+    let magic = crate::params::Network::Mainnet.magic();
+    let mut node = SimpleNode::connect_via_proxy(addr, &format!("{}:8333", onion), magic, Duration::from_secs(5)).unwrap();
+    node.send(&VerAckMessage).unwrap();
+    let mut far_end = proxy.join().unwrap();
+    assert!(NetworkEnvelope::parse(&mut far_end, &magic).unwrap().is::<VerAckMessage>());
+
+    let (addr, proxy) = fake_proxy(vec![5, 1, 0, ATYP_IPV4, 10, 0, 0, 1, 0x20, 0x8d], 4);
+    let err = socks5_connect(addr, "10.0.0.1", 8333, Duration::from_secs(5)).unwrap_err();
+    assert!(err.to_string().contains("host unreachable"));
+    proxy.join().unwrap();
+
+    assert_eq!(split_host_port("[::1]:18444").unwrap(), ("::1".to_string(), 18444));
+    assert_eq!(split_host_port("example.onion:8333").unwrap(), ("example.onion".to_string(), 8333));
+    assert!(split_host_port("example.onion").is_err());
+}
@@ -0,0 +1,490 @@
+//! Headers-first sync: download the header chain from a peer, checking
+//! each header's link to its parent, its proof of work and its bits
+//! before storing it.
+//!
+//! Sync starts from whatever the store holds, the genesis header or a
+//! trusted checkpoint. Checking a retarget needs the header a period back,
+//! so a checkpoint should sit on a period boundary.
+//!
+//! The network's checkpoints, and an assumevalid header the caller trusts,
+//! pin the chain: a header at their height must be theirs, and headers
+//! below the highest skip the retarget checks, which the pinned hash
+//! already vouches for.
+//!
+//! Headers that fork off the best chain within `MAX_REORG_DEPTH` blocks
+//! of the tip are kept as side branches. When a branch has more chainwork
+//! than the best chain above the fork, the store is rewound to the fork
+//! and the branch connected, and `ChainEvent`s record what changed so
+//! wallets can roll back. A header that links to one we have but fails the
+//! checks scores the peer as misbehaving.
+
+use super::messages::{GetHeadersMessage, HeadersMessage, MAX_HEADERS};
+use super::misbehavior::Violation;
+use super::node::SimpleNode;
+use crate::block::{next_bits, BlockHeader};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use crate::params::NetworkParams;
+use num_bigint::BigInt;
+use std::collections::HashMap;
+
+/// How far below the tip a fork can start; older side branches are dropped.
+pub const MAX_REORG_DEPTH: u32 = 288;
+
+/// A change to the best chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// The header at this height joined the best chain
+    Connected(u32, BlockHeader),
+    /// The header at this height left it in a reorganisation
+    Disconnected(u32, BlockHeader),
+}
+
+/// Where synced headers are kept.
+pub trait HeaderStore {
+    /// Height and header of the first stored header, the genesis block or
+    /// a checkpoint.
+    fn start(&self) -> (u32, BlockHeader);
+
+    /// Height and header of the last stored header.
+    fn tip(&self) -> (u32, BlockHeader);
+
+    /// The header at `height`, if stored.
+    fn get(&self, height: u32) -> Option<BlockHeader>;
+
+    /// Stores the header after the tip.
+    fn push(&mut self, header: BlockHeader) -> Result<()>;
+
+    /// Removes and returns the tip, which can't be the first header.
+    fn pop(&mut self) -> Result<BlockHeader>;
+}
+
+/// Headers in a `Vec`, from some starting height on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryHeaderStore {
+    start_height: u32,
+    headers: Vec<BlockHeader>,
+}
+
+impl MemoryHeaderStore {
+    pub fn new(start_height: u32, start: BlockHeader) -> MemoryHeaderStore {
+        MemoryHeaderStore { start_height, headers: vec![start] }
+    }
+}
+
+impl HeaderStore for MemoryHeaderStore {
+    fn start(&self) -> (u32, BlockHeader) {
+        (self.start_height, self.headers[0].clone())
+    }
+
+    fn tip(&self) -> (u32, BlockHeader) {
+        (self.start_height + self.headers.len() as u32 - 1, self.headers[self.headers.len() - 1].clone())
+    }
+
+    fn get(&self, height: u32) -> Option<BlockHeader> {
+        self.headers.get(height.checked_sub(self.start_height)? as usize).cloned()
+    }
+
+    fn push(&mut self, header: BlockHeader) -> Result<()> {
+        self.headers.push(header);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<BlockHeader> {
+        if self.headers.len() == 1 {
+            return Err(Error::new(ErrorKind::OutOfRange, "can't pop the first stored header"));
+        }
+        Ok(self.headers.pop().unwrap())
+    }
+}
+
+pub struct HeaderSync<S: HeaderStore> {
+    pub store: S,
+    params: NetworkParams,
+    assume_valid: Option<(u32, [u8; 32])>,
+    /// Headers off the best chain by hash, with their heights
+    side: HashMap<[u8; 32], (u32, BlockHeader)>,
+    events: Option<Vec<ChainEvent>>,
+}
+
+fn invalid(height: u32, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("header {}: {}", height, msg))
+}
+
+impl<S: HeaderStore> HeaderSync<S> {
+    /// Syncs on top of `store`. A store starting at height 0 must start
+    /// with the network's genesis block, and one starting at a checkpoint's
+    /// height with the checkpoint.
+    pub fn new(store: S, params: NetworkParams) -> Result<HeaderSync<S>> {
+        let (height, start) = store.start();
+        if height == 0 && hash256(&start.serialize()) != params.genesis_hash {
+            return Err(invalid(0, &format!("not the {} genesis block", params.name)));
+        }
+        let sync = HeaderSync { store, params, assume_valid: None, side: HashMap::new(), events: None };
+        if sync.checkpoint(height).is_some_and(|hash| hash != start.hash()) {
+            return Err(invalid(height, "does not match the checkpoint"));
+        }
+        Ok(sync)
+    }
+
+    pub fn params(&self) -> &NetworkParams {
+        &self.params
+    }
+
+    /// Trusts the chain up to the header at `height` with hash `hash`
+    /// (display order), as Bitcoin Core's `-assumevalid` does, and rejects
+    /// chains without it.
+    pub fn set_assume_valid(&mut self, height: u32, hash: [u8; 32]) {
+        self.assume_valid = Some((height, hash));
+    }
+
+    // The pinned hash at `height`, if any
+    fn checkpoint(&self, height: u32) -> Option<[u8; 32]> {
+        let pinned = self.params.checkpoints.iter().chain(self.assume_valid.iter());
+        pinned.filter(|(at, _)| *at == height).map(|(_, hash)| *hash).next()
+    }
+
+    /// Height of the highest checkpoint or assumevalid header, 0 if none.
+    pub fn last_checkpoint(&self) -> u32 {
+        self.params.checkpoints.iter().chain(self.assume_valid.iter()).map(|(height, _)| *height).max().unwrap_or(0)
+    }
+
+    /// Starts or stops recording `ChainEvent`s for `take_events`.
+    pub fn record_events(&mut self, record: bool) {
+        self.events = if record { Some(self.events.take().unwrap_or_default()) } else { None };
+    }
+
+    /// The events recorded since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<ChainEvent> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Height of the best chain header with `hash`, looking as far back as
+    // a fork can start
+    fn main_height(&self, hash: &[u8; 32]) -> Option<u32> {
+        let start = self.store.start().0;
+        let tip = self.store.tip().0;
+        (start.max(tip.saturating_sub(MAX_REORG_DEPTH))..=tip).rev().find(|height| self.store.get(*height).is_some_and(|header| header.hash() == *hash))
+    }
+
+    // The header at `target` on the branch through `header`, at `height`
+    fn ancestor(&self, mut height: u32, mut header: BlockHeader, target: u32) -> Option<BlockHeader> {
+        while height > target {
+            match self.side.get(&header.prev_block) {
+                Some((_, prev)) => header = prev.clone(),
+                None => return self.store.get(target),
+            }
+            height -= 1;
+        }
+        Some(header)
+    }
+
+    /// The bits a header at `height` on top of `prev` must have.
+    fn expected_bits(&self, height: u32, prev: &BlockHeader, header: &BlockHeader) -> Result<u32> {
+        let rules = &self.params.retarget;
+        let interval = rules.interval();
+        if rules.no_retargeting {
+            return Ok(prev.bits);
+        }
+        if height.is_multiple_of(interval) {
+            let first = self.ancestor(height - 1, prev.clone(), height - interval).ok_or_else(|| invalid(height, "start of the previous period not stored"))?;
+            // BIP94 retargets from the period's first block, which can't be
+            // a minimum difficulty one
+            let bits = if rules.enforce_bip94 { first.bits } else { prev.bits };
+            return next_bits(rules, bits, prev.timestamp as i64 - first.timestamp as i64);
+        }
+        if !rules.allow_min_difficulty_blocks {
+            return Ok(prev.bits);
+        }
+        if header.timestamp > prev.timestamp + 2 * rules.target_spacing {
+            return Ok(rules.pow_limit_bits);
+        }
+        // Otherwise the last bits in this period that weren't the minimum
+        let mut last = (height - 1, prev.clone());
+        while !last.0.is_multiple_of(interval) && last.1.bits == rules.pow_limit_bits {
+            let height = last.0 - 1;
+            match self.ancestor(last.0, last.1.clone(), height) {
+                Some(header) => last = (height, header),
+                None => break,
+            }
+        }
+        Ok(last.1.bits)
+    }
+
+    /// Checks that `header` can follow the tip.
+    pub fn validate(&self, header: &BlockHeader) -> Result<()> {
+        let (tip_height, tip) = self.store.tip();
+        let height = tip_height + 1;
+        if header.prev_block != tip.hash() {
+            return Err(invalid(height, "does not extend the tip"));
+        }
+        self.validate_at(height, &tip, header)
+    }
+
+    // Checks `header` at `height` on top of `prev`
+    fn validate_at(&self, height: u32, prev: &BlockHeader, header: &BlockHeader) -> Result<()> {
+        if self.checkpoint(height).is_some_and(|hash| hash != header.hash()) {
+            return Err(invalid(height, "does not match the checkpoint"));
+        }
+        if height > self.last_checkpoint() && header.bits != self.expected_bits(height, prev, header)? {
+            return Err(invalid(height, &format!("unexpected bits {:08x}", header.bits)));
+        }
+        if !header.check_pow() {
+            return Err(invalid(height, "insufficient proof of work"));
+        }
+        Ok(())
+    }
+
+    // Whether `hash` is a header on the best chain or a side branch
+    fn knows(&self, hash: &[u8; 32]) -> bool {
+        self.side.contains_key(hash) || self.main_height(hash).is_some()
+    }
+
+    fn connect(&mut self, height: u32, header: BlockHeader) -> Result<()> {
+        self.store.push(header.clone())?;
+        if let Some(events) = self.events.as_mut() {
+            events.push(ChainEvent::Connected(height, header));
+        }
+        Ok(())
+    }
+
+    /// Validates and stores `header`. One that extends the tip is
+    /// connected; one that forks off the best chain is kept on a side
+    /// branch, which becomes the best chain once it has more work.
+    /// Headers already known are ignored.
+    pub fn add_header(&mut self, header: &BlockHeader) -> Result<()> {
+        let (tip_height, tip) = self.store.tip();
+        if header.prev_block == tip.hash() {
+            self.validate_at(tip_height + 1, &tip, header)?;
+            self.connect(tip_height + 1, header.clone())?;
+            self.side.retain(|_, (height, _)| *height + MAX_REORG_DEPTH > tip_height);
+            return Ok(());
+        }
+        let hash = header.hash();
+        if self.knows(&hash) {
+            return Ok(());
+        }
+        // The branch back to the fork, newest first
+        let mut branch = vec![];
+        let mut prev_block = header.prev_block;
+        while let Some((_, prev)) = self.side.get(&prev_block) {
+            prev_block = prev.prev_block;
+            branch.push(prev.clone());
+        }
+        let fork = match self.main_height(&prev_block) {
+            Some(fork) => fork,
+            None => return Err(invalid(tip_height + 1, "does not connect to the chain")),
+        };
+        let height = fork + branch.len() as u32 + 1;
+        if fork < self.last_checkpoint() {
+            return Err(invalid(height, "forks below the last checkpoint"));
+        }
+        let prev = branch.first().cloned().unwrap_or_else(|| self.store.get(fork).unwrap());
+        self.validate_at(height, &prev, header)?;
+        self.side.insert(hash, (height, header.clone()));
+        branch.insert(0, header.clone());
+
+        // Switch only to a branch with strictly more work, as Core does
+        let mut branch_work = BigInt::from(0);
+        for header in branch.iter() {
+            branch_work += header.work()?;
+        }
+        let mut main_work = BigInt::from(0);
+        for height in fork + 1..=tip_height {
+            main_work += self.store.get(height).unwrap().work()?;
+        }
+        if branch_work <= main_work {
+            return Ok(());
+        }
+        for height in (fork + 1..=tip_height).rev() {
+            let old = self.store.pop()?;
+            if let Some(events) = self.events.as_mut() {
+                events.push(ChainEvent::Disconnected(height, old.clone()));
+            }
+            self.side.insert(old.hash(), (height, old));
+        }
+        for (i, header) in branch.into_iter().rev().enumerate() {
+            self.side.remove(&header.hash());
+            self.connect(fork + 1 + i as u32, header)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `headers` in order, stopping at the first bad one.
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<()> {
+        for header in headers {
+            self.add_header(header)?;
+        }
+        Ok(())
+    }
+
+    /// Hashes for getheaders: the last ten headers, then exponentially
+    /// sparser ones back to the first stored.
+    pub fn locator(&self) -> Vec<[u8; 32]> {
+        let (start_height, start) = self.store.start();
+        let (mut height, tip) = self.store.tip();
+        let mut locator = vec![tip.hash()];
+        let mut step = 1;
+        while height >= start_height + step {
+            height -= step;
+            match self.store.get(height) {
+                Some(header) => locator.push(header.hash()),
+                None => break,
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+        }
+        if height != start_height {
+            locator.push(start.hash());
+        }
+        locator
+    }
+
+    /// Downloads headers from `node` until it has no more, returning how
+    /// many were added.
+    pub fn sync(&mut self, node: &mut SimpleNode) -> Result<usize> {
+        let mut added = 0;
+        loop {
+            node.send(&GetHeadersMessage::new(self.locator()))?;
+            let headers = node.wait_for::<HeadersMessage>()?.headers;
+            for header in headers.iter() {
+                if let Err(err) = self.add_header(header) {
+                    // If it links to a header we have, its work was wrong
+                    if self.knows(&header.prev_block) {
+                        node.misbehaving(Violation::BadProofOfWork)?;
+                    }
+                    return Err(err);
+                }
+            }
+            added += headers.len();
+            if headers.len() < MAX_HEADERS {
+                return Ok(added);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use super::node::DEFAULT_TIMEOUT;
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::params::RetargetRules;
+
+#[cfg(test)]
+fn mine(prev: &BlockHeader, bits: u32, timestamp: u32) -> BlockHeader {
+    let mut header = BlockHeader { prev_block: prev.hash(), timestamp, bits, nonce: 0, ..prev.clone() };
+    while !header.check_pow() {
+        header.nonce += 1;
+    }
+    header
+}
+
+#[test]
+fn sync_headers() {
+    let regtest = NetworkParams::regtest();
+    let genesis = BlockHeader::parse(&mut &decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    assert!(HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), NetworkParams::mainnet()).is_err());
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), regtest.clone()).unwrap();
+    let mut first_chain = vec![genesis.clone()];
+    for i in 1..=30 {
+        let header = mine(&first_chain[i - 1], 0x207f_ffff, genesis.timestamp + i as u32 * 600);
+        first_chain.push(header);
+    }
+    sync.add_headers(&first_chain[1..20]).unwrap();
+    assert_eq!(sync.store.tip(), (19, first_chain[19].clone()));
+    // Not linked, then insufficient work
+    assert!(sync.add_headers(&first_chain[21..22]).is_err());
+    let mut bad = first_chain[20].clone();
+    while bad.check_pow() {
+        bad.nonce += 1;
+    }
+    assert!(sync.validate(&bad).is_err());
+    let locator = sync.locator();
+    assert_eq!((locator.len(), locator[0], locator[locator.len() - 1]), (13, first_chain[19].hash(), genesis.hash()));
+
+    // Periods of 4 blocks: blocks a second apart make the next one 4 times harder
+    let params = NetworkParams {
+        retarget: RetargetRules { target_timespan: 2400, no_retargeting: false, allow_min_difficulty_blocks: false, ..regtest.retarget },
+        ..regtest
+    };
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), params.clone()).unwrap();
+    let mut chain = vec![genesis.clone()];
+    for i in 1..4 {
+        chain.push(mine(&chain[i - 1], 0x207f_ffff, genesis.timestamp + i as u32));
+    }
+    sync.add_headers(&chain[1..]).unwrap();
+    let wrong_bits = mine(&chain[3], 0x207f_ffff, genesis.timestamp + 4);
+    assert!(sync.validate(&wrong_bits).is_err());
+    sync.add_headers(&[mine(&chain[3], 0x201f_ffff, genesis.timestamp + 4)]).unwrap();
+
+    // Below a checkpoint the bits aren't checked, and it must be on the chain
+    let pinned = mine(&wrong_bits, 0x207f_ffff, genesis.timestamp + 5);
+    let checkpointed = NetworkParams { checkpoints: vec![(5, pinned.hash())], ..params.clone() };
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), checkpointed).unwrap();
+    sync.add_headers(&chain[1..]).unwrap();
+    sync.add_headers(std::slice::from_ref(&wrong_bits)).unwrap();
+    assert!(sync.validate(&mine(&wrong_bits, 0x207f_ffff, genesis.timestamp + 6)).is_err());
+    sync.add_headers(std::slice::from_ref(&pinned)).unwrap();
+    assert!(HeaderSync::new(MemoryHeaderStore::new(5, chain[3].clone()), NetworkParams { checkpoints: vec![(5, pinned.hash())], ..params.clone() }).is_err());
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), params.clone()).unwrap();
+    sync.set_assume_valid(4, wrong_bits.hash());
+    sync.add_headers(&chain[1..]).unwrap();
+    sync.add_headers(&[wrong_bits]).unwrap();
+    assert_eq!(sync.last_checkpoint(), 4);
+
+    // A branch off block 1 takes over once it has more work, and back
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), NetworkParams::regtest()).unwrap();
+    sync.record_events(true);
+    sync.add_headers(&first_chain[1..4]).unwrap();
+    let mut branch = vec![first_chain[1].clone()];
+    for i in 1..4 {
+        branch.push(mine(&branch[i - 1], 0x207f_ffff, genesis.timestamp + i as u32 * 600 + 1));
+    }
+    sync.add_headers(&branch).unwrap();
+    assert_eq!(sync.take_events()[3..], [
+        ChainEvent::Disconnected(3, first_chain[3].clone()),
+        ChainEvent::Disconnected(2, first_chain[2].clone()),
+        ChainEvent::Connected(2, branch[1].clone()),
+        ChainEvent::Connected(3, branch[2].clone()),
+        ChainEvent::Connected(4, branch[3].clone()),
+    ]);
+    sync.add_headers(&first_chain[4..5]).unwrap();
+    assert_eq!(sync.store.tip(), (4, branch[3].clone()));
+    sync.add_headers(&first_chain[5..6]).unwrap();
+    assert_eq!(sync.store.tip(), (5, first_chain[5].clone()));
+    assert_eq!(sync.take_events().len(), 3 + 4);
+
+    // Over the wire, from a peer with the first chain
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let magic = NetworkParams::regtest().magic;
+    let peer_chain = first_chain.clone();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        let request = node.wait_for::<GetHeadersMessage>().unwrap();
+        let known = peer_chain.iter().position(|header| header.hash() == request.locator[0]).unwrap();
+        node.send(&HeadersMessage { headers: peer_chain[known + 1..].to_vec() }).unwrap();
+    });
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis), NetworkParams::regtest()).unwrap();
+    assert_eq!(sync.sync(&mut node).unwrap(), 30);
+    assert_eq!(sync.store.tip(), (30, first_chain[30].clone()));
+    peer.join().unwrap();
+
+    // A peer whose next header lacks work is banned
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        node.wait_for::<GetHeadersMessage>().unwrap();
+        node.send(&HeadersMessage { headers: vec![bad] }).unwrap();
+    });
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(19, first_chain[19].clone()), NetworkParams::regtest()).unwrap();
+    assert_eq!(sync.sync(&mut node).unwrap_err().kind(), ErrorKind::Io);
+    assert_eq!(node.misbehavior().violations(), &[Violation::BadProofOfWork]);
+    assert!(!node.is_connected());
+    peer.join().unwrap();
+}
@@ -0,0 +1,422 @@
+//! BIP324's v2 transport: an ElligatorSwift key exchange, then every
+//! message in a ChaCha20-Poly1305 packet with an encrypted length.
+//!
+//! Envelopes stay the unit callers see. On the wire v1 writes them as they
+//! are, while v2 sends the command (as a one byte short ID where there is
+//! one) and payload in a packet and rebuilds the envelope on receipt.
+
+use super::chacha::{FsChaCha20, FsChaCha20Poly1305, TAG_SIZE};
+use super::messages::random_u64;
+use super::{NetworkEnvelope, COMMAND_SIZE, MAX_PAYLOAD_SIZE};
+use crate::encoding::util::{encode_hex, read_bytes};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::tagged_hash;
+use crate::math::ecc::ellswift::{ellswift_ecdh_xonly, ellswift_encode, ELLSWIFT_SIZE};
+use crate::math::ecc::SecretScalar;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::{self, Read, Write};
+
+/// Most garbage either side may send after its key.
+pub const MAX_GARBAGE_LEN: usize = 4095;
+pub const GARBAGE_TERMINATOR_SIZE: usize = 16;
+const LENGTH_SIZE: usize = 3;
+const IGNORE_BIT: u8 = 0x80;
+
+/// Commands with a one byte ID, which is their index plus one.
+const SHORT_IDS: [&str; 28] = [
+    "addr", "block", "blocktxn", "cmpctblock", "feefilter", "filteradd", "filterclear", "filterload", "getblocks", "getblocktxn",
+    "getdata", "getheaders", "headers", "inv", "mempool", "merkleblock", "notfound", "ping", "pong", "sendcmpct", "tx", "getcfilters",
+    "cfilter", "getcfheaders", "cfheaders", "getcfcheckpt", "cfcheckpt", "addrv2",
+];
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn random_bytes32() -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for chunk in bytes.chunks_exact_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_le_bytes());
+    }
+    bytes
+}
+
+/// The secret both sides hash from their ECDH: BIP324's tagged hash of the
+/// two encodings, the initiator's first, and the shared x coordinate.
+pub fn v2_shared_secret(secret: &SecretScalar, ours: &[u8; ELLSWIFT_SIZE], theirs: &[u8; ELLSWIFT_SIZE], initiating: bool) -> [u8; 32] {
+    let (initiator, responder) = if initiating { (ours, theirs) } else { (theirs, ours) };
+    let mut data = initiator.to_vec();
+    data.extend_from_slice(responder);
+    data.extend_from_slice(&ellswift_ecdh_xonly(secret, theirs));
+    tagged_hash("bip324_ellswift_xonly_ecdh", &data)
+}
+
+/// One side's ciphers and garbage terminators for a v2 connection.
+pub struct V2Cipher {
+    /// The same on both sides; compare out of band to rule out a man in
+    /// the middle
+    pub session_id: [u8; 32],
+    send_length: FsChaCha20,
+    send_contents: FsChaCha20Poly1305,
+    recv_length: FsChaCha20,
+    recv_contents: FsChaCha20Poly1305,
+    send_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+    recv_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+}
+
+impl V2Cipher {
+    /// Derives the keys from the shared secret with HKDF-SHA256, salted
+    /// with the network's magic.
+    pub fn new(shared_secret: &[u8; 32], magic: [u8; 4], initiating: bool) -> V2Cipher {
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&magic);
+        let prk = hmac(&salt, shared_secret);
+        // Every output is a single HKDF-Expand block
+        let expand = |info: &str| hmac(&prk, &[info.as_bytes(), &[1]].concat());
+        let terminators = expand("garbage_terminators");
+        let mut first = [0u8; GARBAGE_TERMINATOR_SIZE];
+        let mut second = [0u8; GARBAGE_TERMINATOR_SIZE];
+        first.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_SIZE]);
+        second.copy_from_slice(&terminators[GARBAGE_TERMINATOR_SIZE..]);
+        let (send, recv) = if initiating { ("initiator", "responder") } else { ("responder", "initiator") };
+        V2Cipher {
+            session_id: expand("session_id"),
+            send_length: FsChaCha20::new(expand(&format!("{}_L", send))),
+            send_contents: FsChaCha20Poly1305::new(expand(&format!("{}_P", send))),
+            recv_length: FsChaCha20::new(expand(&format!("{}_L", recv))),
+            recv_contents: FsChaCha20Poly1305::new(expand(&format!("{}_P", recv))),
+            send_terminator: if initiating { first } else { second },
+            recv_terminator: if initiating { second } else { first },
+        }
+    }
+
+    /// The encrypted length, then the header byte and `contents` sealed
+    /// with `aad`. The peer skips packets with `ignore` set.
+    pub fn encrypt_packet(&mut self, contents: &[u8], aad: &[u8], ignore: bool) -> Vec<u8> {
+        let mut packet = self.send_length.crypt(&(contents.len() as u32).to_le_bytes()[..LENGTH_SIZE]);
+        let mut plaintext = vec![if ignore { IGNORE_BIT } else { 0 }];
+        plaintext.extend_from_slice(contents);
+        packet.extend(self.send_contents.encrypt(aad, &plaintext));
+        packet
+    }
+
+    /// Reads a packet, returning its contents and whether it's a decoy.
+    pub fn read_packet<R: Read>(&mut self, reader: &mut R, aad: &[u8]) -> Result<(Vec<u8>, bool)> {
+        self.read_packet_limited(reader, aad, MAX_PAYLOAD_SIZE)
+    }
+
+    /// Reads a packet as `read_packet` does, carrying a payload of at most
+    /// `max_payload` bytes.
+    pub fn read_packet_limited<R: Read>(&mut self, reader: &mut R, aad: &[u8], max_payload: usize) -> Result<(Vec<u8>, bool)> {
+        let mut length = [0u8; 4];
+        length[..LENGTH_SIZE].copy_from_slice(&self.recv_length.crypt(&read_bytes(reader, LENGTH_SIZE)?));
+        let length = u32::from_le_bytes(length) as usize;
+        if length > 1 + COMMAND_SIZE + max_payload {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("packet of {} bytes", length)));
+        }
+        let sealed = read_bytes(reader, 1 + length + TAG_SIZE)?;
+        let mut plaintext = self.recv_contents.decrypt(aad, &sealed)?;
+        let ignore = plaintext[0] & IGNORE_BIT != 0;
+        plaintext.remove(0);
+        Ok((plaintext, ignore))
+    }
+}
+
+/// An envelope's command and payload as v2 packet contents.
+pub fn encode_contents(envelope: &NetworkEnvelope) -> Vec<u8> {
+    let mut contents = match SHORT_IDS.iter().position(|command| *command == envelope.command) {
+        Some(index) => vec![index as u8 + 1],
+        None => {
+            let mut command = vec![0];
+            command.extend_from_slice(envelope.command.as_bytes());
+            command.resize(1 + COMMAND_SIZE, 0);
+            command
+        }
+    };
+    contents.extend_from_slice(&envelope.payload);
+    contents
+}
+
+/// The envelope packet contents carry, or `None` for a short ID this
+/// doesn't know, which peers are to ignore.
+pub fn decode_contents(contents: &[u8], magic: [u8; 4]) -> Result<Option<NetworkEnvelope>> {
+    match contents.first() {
+        None => Err(Error::new(ErrorKind::InvalidEncoding, "empty v2 message")),
+        Some(0) => {
+            if contents.len() < 1 + COMMAND_SIZE {
+                return Err(Error::new(ErrorKind::InvalidEncoding, "v2 message cut off in its command"));
+            }
+            let command = &contents[1..1 + COMMAND_SIZE];
+            let name_len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+            if !command[..name_len].iter().all(u8::is_ascii_graphic) || command[name_len..].iter().any(|b| *b != 0) {
+                return Err(Error::new(ErrorKind::InvalidEncoding, format!("bad command {}", encode_hex(command))));
+            }
+            let command = String::from_utf8(command[..name_len].to_vec()).unwrap();
+            Ok(Some(NetworkEnvelope::new(magic, command, contents[1 + COMMAND_SIZE..].to_vec())))
+        }
+        Some(id) => Ok(SHORT_IDS.get(*id as usize - 1).map(|command| NetworkEnvelope::new(magic, *command, contents[1..].to_vec()))),
+    }
+}
+
+// What a v1 peer's first message starts with
+fn v1_prefix(magic: [u8; 4]) -> Vec<u8> {
+    let mut prefix = magic.to_vec();
+    prefix.extend_from_slice(b"version");
+    prefix.resize(GARBAGE_TERMINATOR_SIZE, 0);
+    prefix
+}
+
+fn peer_hung_up(err: &Error) -> bool {
+    matches!(err, Error::Io(err) if matches!(err.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe))
+}
+
+/// How a connection frames envelopes.
+pub enum Transport {
+    /// Plain envelopes, as in the book
+    V1,
+    /// BIP324 packets
+    V2(Box<V2Cipher>),
+}
+
+impl Transport {
+    /// Starts a v2 handshake as the side that connected. `None` means the
+    /// peer hung up before sending its key, as a v1 peer does, and the
+    /// caller should reconnect with v1.
+    pub fn initiate<S: Read + Write>(stream: &mut S, magic: [u8; 4]) -> Result<Option<Transport>> {
+        let (secret, ours) = loop {
+            let (secret, ours) = new_key();
+            // A responder would take this for a v1 peer
+            if ours[..GARBAGE_TERMINATOR_SIZE] != v1_prefix(magic)[..] {
+                break (secret, ours);
+            }
+        };
+        let mut theirs = [0u8; ELLSWIFT_SIZE];
+        let exchange = send_key(stream, &ours).and_then(|garbage| {
+            stream.read_exact(&mut theirs)?;
+            Ok(garbage)
+        });
+        let garbage = match exchange {
+            Ok(garbage) => garbage,
+            Err(err) if peer_hung_up(&err) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let cipher = V2Cipher::new(&v2_shared_secret(&secret, &ours, &theirs, true), magic, true);
+        finish_handshake(stream, cipher, &garbage).map(Some)
+    }
+
+    /// Answers a handshake from the side that connected, which may be a
+    /// v1 peer. Its version message, already read to tell, comes back too.
+    pub fn respond<S: Read + Write>(stream: &mut S, magic: [u8; 4]) -> Result<(Transport, Option<NetworkEnvelope>)> {
+        let start = read_bytes(stream, GARBAGE_TERMINATOR_SIZE)?;
+        if start == v1_prefix(magic) {
+            let envelope = NetworkEnvelope::parse(&mut (&start[..]).chain(&mut *stream), &magic)?;
+            return Ok((Transport::V1, Some(envelope)));
+        }
+        let mut theirs = [0u8; ELLSWIFT_SIZE];
+        theirs[..GARBAGE_TERMINATOR_SIZE].copy_from_slice(&start);
+        stream.read_exact(&mut theirs[GARBAGE_TERMINATOR_SIZE..])?;
+        let (secret, ours) = new_key();
+        let garbage = send_key(stream, &ours)?;
+        let cipher = V2Cipher::new(&v2_shared_secret(&secret, &ours, &theirs, false), magic, false);
+        Ok((finish_handshake(stream, cipher, &garbage)?, None))
+    }
+
+    pub fn is_v2(&self) -> bool {
+        matches!(self, Transport::V2(_))
+    }
+
+    pub fn write_envelope<W: Write>(&mut self, writer: &mut W, envelope: &NetworkEnvelope) -> Result<()> {
+        match self {
+            Transport::V1 => envelope.write(writer),
+            Transport::V2(cipher) => {
+                writer.write_all(&cipher.encrypt_packet(&encode_contents(envelope), &[], false))?;
+                writer.flush()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// The next envelope, skipping v2 decoys and unknown short IDs. Its
+    /// payload may be at most `max_payload` bytes.
+    pub fn read_envelope<R: Read>(&mut self, reader: &mut R, magic: &[u8; 4], max_payload: usize) -> Result<NetworkEnvelope> {
+        match self {
+            Transport::V1 => NetworkEnvelope::parse_limited(reader, magic, max_payload),
+            Transport::V2(cipher) => loop {
+                let (contents, ignore) = cipher.read_packet_limited(reader, &[], max_payload)?;
+                if !ignore {
+                    if let Some(envelope) = decode_contents(&contents, *magic)? {
+                        return Ok(envelope);
+                    }
+                }
+            },
+        }
+    }
+}
+
+fn new_key() -> (SecretScalar, [u8; ELLSWIFT_SIZE]) {
+    let secret = loop {
+        if let Ok(secret) = SecretScalar::from_bytes(random_bytes32()) {
+            break secret;
+        }
+    };
+    let ours = ellswift_encode(&secret.public_point(), &random_bytes32());
+    (secret, ours)
+}
+
+// Sends our key and some random garbage, returning the garbage
+fn send_key<W: Write>(writer: &mut W, ours: &[u8; ELLSWIFT_SIZE]) -> Result<Vec<u8>> {
+    let garbage: Vec<u8> = (0..random_u64() as usize % (MAX_GARBAGE_LEN + 1)).map(|_| random_u64() as u8).collect();
+    writer.write_all(ours)?;
+    writer.write_all(&garbage)?;
+    writer.flush()?;
+    Ok(garbage)
+}
+
+// Sends our terminator and version packet, then skips the peer's garbage
+// and reads theirs
+fn finish_handshake<S: Read + Write>(stream: &mut S, mut cipher: V2Cipher, garbage: &[u8]) -> Result<Transport> {
+    let mut out = cipher.send_terminator.to_vec();
+    out.extend(cipher.encrypt_packet(&[], garbage, false));
+    stream.write_all(&out)?;
+    stream.flush()?;
+
+    let mut received = vec![];
+    while !received.ends_with(&cipher.recv_terminator) {
+        if received.len() == MAX_GARBAGE_LEN + GARBAGE_TERMINATOR_SIZE {
+            return Err(Error::new(ErrorKind::InvalidEncoding, "no garbage terminator from peer"));
+        }
+        received.extend(read_bytes(stream, 1)?);
+    }
+    received.truncate(received.len() - GARBAGE_TERMINATOR_SIZE);
+    // The garbage authenticates the first packet, decoy or not; the first
+    // packet that isn't a decoy is the version packet
+    let mut aad = received;
+    loop {
+        let (_, ignore) = cipher.read_packet(stream, &aad)?;
+        aad.clear();
+        if !ignore {
+            return Ok(Transport::V2(Box::new(cipher)));
+        }
+    }
+}
+
+#[cfg(test)]
+use super::messages::{PingMessage, PongMessage, VerAckMessage, VersionMessage};
+#[cfg(test)]
+use super::node::{SimpleNode, DEFAULT_TIMEOUT};
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::math::ecc::bytes_to_int;
+#[cfg(test)]
+use crate::params::NetworkParams;
+
+#[test]
+fn v2_packets() {
+    // BIP324's shared secret vectors, as libsecp256k1's ellswift tests have them
+    let vectors = [
+        (
+            "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+            "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+            "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+            true,
+            "c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592",
+        ),
+        (
+            "1f9c581b35231838f0f17cf0c979835baccb7f3abbbb96ffcc318ab71e6e126f",
+            "a1855e10e94e00baa23041d916e259f7044e491da6171269694763f018c7e63693d29575dcb464ac816baa1be353ba12e3876cba7628bd0bd8e755e721eb0140",
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f0000000000000000000000000000000000000000000000000000000000000000",
+            false,
+            "a0138f564f74d0ad70bc337dacc9d0bf1d2349364caf1188a1e6e8ddb3b7b184",
+        ),
+        (
+            "6c77432d1fda31e9f942f8af44607e10f3ad38a65f8a4bddae823e5eff90dc38",
+            "d2685070c1e6376e633e825296634fd461fa9e5bdf2109bcebd735e5a91f3e587c5cb782abb797fbf6bb5074fd1542a474f2a45b673763ec2db7fb99b737bbb9",
+            "56bd0c06f10352c3a1a9f4b4c92f6fa2b26df124b57878353c1fc691c51abea77c8817daeeb9fa546b77c8daf79d89b22b0e1b87574ece42371f00237aa9d83a",
+            false,
+            "1918b741ef5f9d1d7670b050c152b4a4ead2c31be9aecb0681c0cd4324150853",
+        ),
+    ];
+    let ellswift = |hex: &str| {
+        let mut bytes = [0u8; ELLSWIFT_SIZE];
+        bytes.copy_from_slice(&decode_hex(hex).unwrap());
+        bytes
+    };
+    for (secret, ours, theirs, initiating, expected) in vectors.iter() {
+        let secret = SecretScalar::new(&bytes_to_int(&decode_hex(secret).unwrap())).unwrap();
+        let shared = v2_shared_secret(&secret, &ellswift(ours), &ellswift(theirs), *initiating);
+        assert_eq!(encode_hex(&shared), *expected);
+    }
+
+    // Each side decrypts what the other encrypts
+    let magic = NetworkParams::regtest().magic;
+    let (mut initiator, mut responder) = (V2Cipher::new(&[9; 32], magic, true), V2Cipher::new(&[9; 32], magic, false));
+    assert_eq!((initiator.session_id, initiator.send_terminator), (responder.session_id, responder.recv_terminator));
+    let ping = NetworkEnvelope::new(magic, "ping", vec![1; 8]);
+    let sendheaders = NetworkEnvelope::new(magic, "sendheaders", vec![]);
+    let mut wire = initiator.encrypt_packet(b"", b"", true);
+    assert_eq!(wire.len(), LENGTH_SIZE + 1 + TAG_SIZE);
+    wire.extend(initiator.encrypt_packet(&encode_contents(&ping), b"", false));
+    wire.extend(initiator.encrypt_packet(&[200, 1, 2], b"", false));
+    wire.extend(initiator.encrypt_packet(&encode_contents(&sendheaders), b"", false));
+    assert_eq!(encode_contents(&ping)[0], 18);
+    let mut transport = Transport::V2(Box::new(responder));
+    let mut reader = &wire[..];
+    assert_eq!(transport.read_envelope(&mut reader, &magic, MAX_PAYLOAD_SIZE).unwrap(), ping);
+    assert_eq!(transport.read_envelope(&mut reader, &magic, MAX_PAYLOAD_SIZE).unwrap(), sendheaders);
+    assert!(reader.is_empty());
+
+    // A flipped bit fails authentication
+    responder = V2Cipher::new(&[9; 32], magic, false);
+    let mut wire = V2Cipher::new(&[9; 32], magic, true).encrypt_packet(b"\x12", b"", false);
+    wire[5] ^= 1;
+    assert_eq!(responder.read_packet(&mut &wire[..], b"").unwrap_err().kind(), ErrorKind::InvalidSignature);
+}
+
+#[test]
+fn v2_handshake() {
+    let magic = NetworkParams::regtest().magic;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = std::thread::spawn(move || {
+        // A v2 connection
+        let mut node = SimpleNode::accept(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        assert!(node.is_v2());
+        let ping = node.wait_for::<PingMessage>().unwrap();
+        node.send(&PongMessage { nonce: ping.nonce }).unwrap();
+        let session_id = node.session_id().unwrap();
+        node.wait_for::<VerAckMessage>().unwrap();
+
+        // A v1 peer drops the key exchange, then takes the v1 reconnection
+        let (mut stream, _) = listener.accept().unwrap();
+        assert!(NetworkEnvelope::parse(&mut stream, &magic).is_err());
+        drop(stream);
+        let mut node = SimpleNode::from_stream(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        node.wait_for::<VerAckMessage>().unwrap();
+
+        // A v1 peer connecting to a node that accepts both
+        let mut node = SimpleNode::accept(listener.accept().unwrap().0, magic, DEFAULT_TIMEOUT).unwrap();
+        assert!(!node.is_v2());
+        assert_eq!(node.wait_for::<VersionMessage>().unwrap().latest_block, 7);
+        session_id
+    });
+
+    let mut node = SimpleNode::connect_v2(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    assert!(node.is_v2());
+    assert!(node.ping().unwrap() < DEFAULT_TIMEOUT);
+    let session_id = node.session_id();
+    node.send(&VerAckMessage).unwrap();
+
+    let mut node = SimpleNode::connect_v2(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    assert_eq!((node.is_v2(), node.session_id()), (false, None));
+    node.send(&VerAckMessage).unwrap();
+
+    let mut node = SimpleNode::connect(addr, magic, DEFAULT_TIMEOUT).unwrap();
+    node.send(&VersionMessage::new(super::NetAddress::from_socket_addr(0, addr), 7)).unwrap();
+    assert_eq!(Some(peer.join().unwrap()), session_id);
+}
@@ -0,0 +1,370 @@
+//! Per-network chain parameters.
+//!
+//! The built-in networks are always registered. Custom parameter sets (a
+//! private signet, a new test network) can be added at runtime with
+//! `register_network` and are then found by name or magic like the rest.
+
+use crate::encoding::util::{decode_hex, from_reversed_hex};
+use crate::error::{Error, ErrorKind, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+static REGISTRY: OnceLock<RwLock<Vec<NetworkParams>>> = OnceLock::new();
+
+/// How the proof of work target is adjusted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetargetRules {
+    /// Easiest allowed target, in compact "bits" form
+    pub pow_limit_bits: u32,
+    /// Seconds each retarget period is supposed to take
+    pub target_timespan: u32,
+    /// Seconds between blocks
+    pub target_spacing: u32,
+    /// A block more than 20 minutes after its parent may use the minimum difficulty
+    pub allow_min_difficulty_blocks: bool,
+    /// The target never changes (regtest)
+    pub no_retargeting: bool,
+    /// BIP94: retarget from the first block of the period, not a minimum
+    /// difficulty block, and limit the timewarp attack
+    pub enforce_bip94: bool,
+}
+
+impl RetargetRules {
+    /// Blocks per retarget period, 2016 on every built-in network.
+    pub fn interval(&self) -> u32 {
+        self.target_timespan / self.target_spacing
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkParams {
+    pub name: String,
+    /// Message start bytes of the P2P protocol
+    pub magic: [u8; 4],
+    pub default_port: u16,
+    pub p2pkh_prefix: u8,
+    pub p2sh_prefix: u8,
+    pub wif_prefix: u8,
+    pub bech32_hrp: String,
+    /// Genesis block hash in internal (serialized) byte order
+    pub genesis_hash: [u8; 32],
+    pub retarget: RetargetRules,
+    /// Blocks between halvings of the coinbase subsidy
+    pub subsidy_halving_interval: u32,
+    /// Heights and block hashes (display order) the chain must have, from
+    /// Bitcoin Core. Header sync rejects forks that miss one and skips
+    /// retarget checks below the last.
+    pub checkpoints: Vec<(u32, [u8; 32])>,
+    /// Hosts whose DNS records list peers
+    pub dns_seeds: Vec<String>,
+}
+
+// Block hashes are displayed byte-reversed
+fn genesis(hex: &str) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&from_reversed_hex(hex).unwrap());
+    hash
+}
+
+fn checkpoints(list: &[(u32, &str)]) -> Vec<(u32, [u8; 32])> {
+    let mut result = vec![];
+    for (height, hex) in list {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&decode_hex(hex).unwrap());
+        result.push((*height, hash));
+    }
+    result
+}
+
+fn seeds(hosts: &[&str]) -> Vec<String> {
+    hosts.iter().map(|host| host.to_string()).collect()
+}
+
+fn retarget(pow_limit_bits: u32, allow_min_difficulty_blocks: bool) -> RetargetRules {
+    RetargetRules {
+        pow_limit_bits,
+        target_timespan: 14 * 24 * 60 * 60,
+        target_spacing: 10 * 60,
+        allow_min_difficulty_blocks,
+        no_retargeting: false,
+        enforce_bip94: false,
+    }
+}
+
+impl NetworkParams {
+    pub fn mainnet() -> NetworkParams {
+        NetworkParams {
+            name: "mainnet".to_string(),
+            magic: [0xf9, 0xbe, 0xb4, 0xd9],
+            default_port: 8333,
+            p2pkh_prefix: 0x00,
+            p2sh_prefix: 0x05,
+            wif_prefix: 0x80,
+            bech32_hrp: "bc".to_string(),
+            genesis_hash: genesis("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            retarget: retarget(0x1d00ffff, false),
+            subsidy_halving_interval: 210_000,
+            checkpoints: checkpoints(&[
+                (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+                (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+                (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+                (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+                (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+                (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+                (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+                (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+                (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+                (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+                (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+                (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+                (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+            ]),
+            dns_seeds: seeds(&[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+            ]),
+        }
+    }
+
+    pub fn testnet3() -> NetworkParams {
+        NetworkParams {
+            name: "testnet3".to_string(),
+            magic: [0x0b, 0x11, 0x09, 0x07],
+            default_port: 18333,
+            p2pkh_prefix: 0x6f,
+            p2sh_prefix: 0xc4,
+            wif_prefix: 0xef,
+            bech32_hrp: "tb".to_string(),
+            genesis_hash: genesis("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+            retarget: retarget(0x1d00ffff, true),
+            subsidy_halving_interval: 210_000,
+            checkpoints: checkpoints(&[(546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70")]),
+            dns_seeds: seeds(&[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+            ]),
+        }
+    }
+
+    pub fn testnet4() -> NetworkParams {
+        NetworkParams {
+            name: "testnet4".to_string(),
+            magic: [0x1c, 0x16, 0x3f, 0x28],
+            default_port: 48333,
+            genesis_hash: genesis("00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"),
+            retarget: RetargetRules { enforce_bip94: true, ..retarget(0x1d00ffff, true) },
+            checkpoints: vec![],
+            dns_seeds: seeds(&["seed.testnet4.bitcoin.sprovoost.nl", "seed.testnet4.wiz.biz"]),
+            ..NetworkParams::testnet3()
+        }
+    }
+
+    pub fn signet() -> NetworkParams {
+        NetworkParams {
+            name: "signet".to_string(),
+            magic: [0x0a, 0x03, 0xcf, 0x40],
+            default_port: 38333,
+            genesis_hash: genesis("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+            retarget: retarget(0x1e0377ae, false),
+            checkpoints: vec![],
+            dns_seeds: seeds(&["seed.signet.bitcoin.sprovoost.nl"]),
+            ..NetworkParams::testnet3()
+        }
+    }
+
+    pub fn regtest() -> NetworkParams {
+        NetworkParams {
+            name: "regtest".to_string(),
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            default_port: 18444,
+            bech32_hrp: "bcrt".to_string(),
+            genesis_hash: genesis("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+            retarget: RetargetRules { no_retargeting: true, ..retarget(0x207fffff, true) },
+            subsidy_halving_interval: 150,
+            checkpoints: vec![],
+            dns_seeds: vec![],
+            ..NetworkParams::testnet3()
+        }
+    }
+}
+
+/// The built-in networks, for code that only needs to tell them apart.
+/// `Testnet` is testnet3, the book's; testnet4 and custom networks are
+/// only available as `NetworkParams`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    pub fn params(&self) -> NetworkParams {
+        match self {
+            Network::Mainnet => NetworkParams::mainnet(),
+            Network::Testnet => NetworkParams::testnet3(),
+            Network::Signet => NetworkParams::signet(),
+            Network::Regtest => NetworkParams::regtest(),
+        }
+    }
+
+    /// The name its parameters are registered under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet3",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// The built-in network with parameters `params`, if any.
+    pub fn from_params(params: &NetworkParams) -> Option<Network> {
+        [Network::Mainnet, Network::Testnet, Network::Signet, Network::Regtest].iter().copied().find(|network| network.magic() == params.magic)
+    }
+
+    pub fn magic(&self) -> [u8; 4] {
+        self.params().magic
+    }
+
+    pub fn default_port(&self) -> u16 {
+        self.params().default_port
+    }
+
+    pub fn p2pkh_prefix(&self) -> u8 {
+        self.params().p2pkh_prefix
+    }
+
+    pub fn p2sh_prefix(&self) -> u8 {
+        self.params().p2sh_prefix
+    }
+
+    pub fn wif_prefix(&self) -> u8 {
+        self.params().wif_prefix
+    }
+
+    pub fn bech32_hrp(&self) -> String {
+        self.params().bech32_hrp
+    }
+
+    /// Genesis block hash in internal (serialized) byte order.
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        self.params().genesis_hash
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    /// Takes the registered names and Bitcoin Core's `-chain` names.
+    fn from_str(s: &str) -> Result<Network> {
+        match s {
+            "mainnet" | "main" | "bitcoin" => Ok(Network::Mainnet),
+            "testnet3" | "testnet" | "test" => Ok(Network::Testnet),
+            "signet" => Ok(Network::Signet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(Error::new(ErrorKind::UnknownNetwork, s.to_string())),
+        }
+    }
+}
+
+impl From<Network> for NetworkParams {
+    fn from(network: Network) -> NetworkParams {
+        network.params()
+    }
+}
+
+fn registry() -> &'static RwLock<Vec<NetworkParams>> {
+    REGISTRY.get_or_init(|| {
+        RwLock::new(vec![
+            NetworkParams::mainnet(),
+            NetworkParams::testnet3(),
+            NetworkParams::testnet4(),
+            NetworkParams::signet(),
+            NetworkParams::regtest(),
+        ])
+    })
+}
+
+/// Adds a parameter set. Names and magic bytes must be unique since that
+/// is how networks are looked up.
+pub fn register_network(params: NetworkParams) -> Result<()> {
+    let mut networks = registry().write().unwrap();
+    if let Some(existing) = networks.iter().find(|n| n.name == params.name || n.magic == params.magic) {
+        return Err(Error::new(ErrorKind::UnknownNetwork,
+            format!("{} conflicts with registered network {}", params.name, existing.name)));
+    }
+    networks.push(params);
+    Ok(())
+}
+
+pub fn network_params(name: &str) -> Result<NetworkParams> {
+    registry().read().unwrap().iter().find(|n| n.name == name).cloned()
+        .ok_or_else(|| Error::new(ErrorKind::UnknownNetwork, name.to_string()))
+}
+
+pub fn network_by_magic(magic: &[u8; 4]) -> Result<NetworkParams> {
+    registry().read().unwrap().iter().find(|n| n.magic == *magic).cloned()
+        .ok_or_else(|| Error::new(ErrorKind::UnknownNetwork, format!("magic {:02x?}", magic)))
+}
+
+/// Every registered network, built-in ones first.
+pub fn registered_networks() -> Vec<NetworkParams> {
+    registry().read().unwrap().clone()
+}
+
+#[test]
+fn builtin_networks() {
+    let testnet4 = network_by_magic(&[0x1c, 0x16, 0x3f, 0x28]).unwrap();
+    assert_eq!(testnet4, NetworkParams::testnet4());
+    assert_eq!(testnet4.p2pkh_prefix, 0x6f);
+    assert!(testnet4.retarget.enforce_bip94);
+    assert_eq!(testnet4.retarget.interval(), 2016);
+    assert_eq!(&network_params("mainnet").unwrap().genesis_hash[..4], &[0x6f, 0xe2, 0x8c, 0x0a]);
+    let checkpoints = NetworkParams::mainnet().checkpoints;
+    assert_eq!((checkpoints.len(), checkpoints[12].0, checkpoints[0].1[..4].to_vec()), (13, 295000, vec![0, 0, 0, 0]));
+    assert_eq!(network_params("nonet").unwrap_err().kind(), ErrorKind::UnknownNetwork);
+}
+
+#[test]
+fn network_enum() {
+    assert_eq!(Network::Testnet.params(), NetworkParams::testnet3());
+    assert_eq!((Network::Signet.magic(), Network::Regtest.default_port()), ([0x0a, 0x03, 0xcf, 0x40], 18444));
+    assert_eq!((Network::Testnet.p2pkh_prefix(), Network::Mainnet.wif_prefix(), Network::Regtest.bech32_hrp().as_str()), (0x6f, 0x80, "bcrt"));
+    assert_eq!("test".parse::<Network>().unwrap(), Network::Testnet);
+    assert_eq!(Network::Regtest.to_string().parse::<Network>().unwrap(), Network::Regtest);
+    assert_eq!("testnet4".parse::<Network>().unwrap_err().kind(), ErrorKind::UnknownNetwork);
+    assert_eq!(Network::from_params(&NetworkParams::signet()), Some(Network::Signet));
+    assert_eq!(Network::from_params(&NetworkParams::testnet4()), None);
+}
+
+#[test]
+fn register_custom_network() {
+    let custom = NetworkParams {
+        name: "mysignet".to_string(),
+        magic: [0x54, 0xd2, 0x6f, 0xbd],
+        default_port: 39333,
+        ..NetworkParams::signet()
+    };
+    register_network(custom.clone()).unwrap();
+    assert_eq!(network_params("mysignet").unwrap(), custom);
+    assert_eq!(network_by_magic(&custom.magic).unwrap(), custom);
+    assert!(register_network(custom).is_err());
+    assert!(register_network(NetworkParams { name: "other".to_string(), ..NetworkParams::regtest() }).is_err());
+}
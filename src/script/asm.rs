@@ -0,0 +1,84 @@
+//! Parsing scripts written as ASM, the format `Display` produces.
+//!
+//! Tokens are separated by whitespace. Opcodes are written by name, with or
+//! without the OP_ prefix, and OP_0 to OP_16 and OP_1NEGATE also as the
+//! numbers 0 to 16 and -1. Anything else is data in hex, optionally
+//! prefixed with 0x. Bitcoin Core's aliases OP_FALSE, OP_TRUE, OP_NOP2 and
+//! OP_NOP3 are understood too.
+//!
+//! A one byte element between 0x01 and 0x10 is displayed the same as the
+//! small number opcode, and parses back as that opcode.
+
+use super::opcodes::OpCode;
+use super::{Command, Script};
+use crate::encoding::util::decode_hex;
+use crate::error::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+fn opcode(token: &str) -> Option<OpCode> {
+    let alias = match token {
+        "OP_FALSE" => Some(OpCode::Zero),
+        "OP_TRUE" => Some(OpCode::One),
+        "OP_NOP2" => Some(OpCode::CheckLockTimeVerify),
+        "OP_NOP3" => Some(OpCode::CheckSequenceVerify),
+        "OP_1NEGATE" => Some(OpCode::OneNegate),
+        _ => None,
+    };
+    if alias.is_some() {
+        return alias;
+    }
+    // OP_0 to OP_16 are named by their number
+    let number = token.strip_prefix("OP_").filter(|n| n.parse::<u8>().is_ok()).unwrap_or(token);
+    let prefixed = format!("OP_{}", token);
+    (0..=255u8)
+        .filter_map(OpCode::from_u8)
+        .find(|op| op.name() == number || op.name() == prefixed)
+}
+
+impl Script {
+    /// Parses ASM like `OP_DUP OP_HASH160 <hex> OP_EQUALVERIFY OP_CHECKSIG`.
+    pub fn from_asm(asm: &str) -> Result<Script> {
+        let mut cmds = Vec::new();
+        for token in asm.split_whitespace() {
+            match opcode(token) {
+                Some(OpCode::PushData1) | Some(OpCode::PushData2) | Some(OpCode::PushData4) => {
+                    return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} is written as the data it pushes", token)));
+                }
+                Some(op) => cmds.push(Command::Op(op.to_u8())),
+                None => {
+                    let hex = token.strip_prefix("0x").unwrap_or(token);
+                    let data = decode_hex(hex)
+                        .map_err(|_| Error::new(ErrorKind::InvalidEncoding, format!("{:?} is neither an opcode nor hex", token)))?;
+                    cmds.push(Command::Data(data));
+                }
+            }
+        }
+        Ok(Script::new(cmds))
+    }
+}
+
+impl FromStr for Script {
+    type Err = Error;
+
+    fn from_str(asm: &str) -> Result<Script> {
+        Script::from_asm(asm)
+    }
+}
+
+#[test]
+fn parse_asm() {
+    let p2pkh = "OP_DUP OP_HASH160 bc3b654dca7e56b04dca18f2566cdaf02e8d9ada OP_EQUALVERIFY OP_CHECKSIG";
+    let script: Script = p2pkh.parse().unwrap();
+    assert_eq!(script.raw_serialize(), decode_hex("76a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac").unwrap());
+    assert_eq!(script.to_string(), p2pkh);
+    assert_eq!(Script::from_asm("DUP HASH160 0xbc3b654dca7e56b04dca18f2566cdaf02e8d9ada EQUALVERIFY CHECKSIG").unwrap(), script);
+
+    let numbers = Script::from_asm("OP_0 0 OP_FALSE -1 OP_1NEGATE 1 OP_TRUE OP_16 16").unwrap();
+    assert_eq!(numbers.raw_serialize(), vec![0x00, 0x00, 0x00, 0x4f, 0x4f, 0x51, 0x51, 0x60, 0x60]);
+    assert_eq!(Script::from_asm("OP_NOP2 OP_CHECKSEQUENCEVERIFY").unwrap().to_string(), "OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY");
+    assert_eq!(Script::from_asm("  ").unwrap(), Script::default());
+
+    assert!(Script::from_asm("OP_DUP OP_FOO").is_err());
+    assert!(Script::from_asm("abc").is_err());
+    assert!(Script::from_asm("OP_PUSHDATA1 00").is_err());
+}
@@ -0,0 +1,182 @@
+//! Running a script one command at a time, for following the book's
+//! exercises or finding where a spend fails.
+
+use super::interpreter::{Execution, VerifyFlags};
+use super::ops::{cast_to_bool, Stack};
+use super::{Command, Script};
+use crate::error::Result;
+use num_bigint::BigInt;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// Commands are left to run
+    Running,
+    /// Every command ran and a true value is on top
+    Succeeded,
+    /// A command failed, or the script ended without a true value on top
+    Failed,
+}
+
+/// The stacks right after a command ran.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Snapshot {
+    /// Index of the command in the script
+    pub position: usize,
+    pub command: Command,
+    /// Whether the command was in a branch being executed
+    pub executed: bool,
+    pub stack: Stack,
+    pub altstack: Stack,
+}
+
+type StepHook<'a> = Box<dyn FnMut(&Snapshot) + 'a>;
+
+/// Steps through a script like `Script::evaluate` runs it.
+pub struct ScriptDebugger<'a> {
+    execution: Execution<'a>,
+    script: &'a Script,
+    status: Status,
+    hook: Option<StepHook<'a>>,
+}
+
+impl<'a> ScriptDebugger<'a> {
+    pub fn new(script: &'a Script, z: &'a BigInt, flags: VerifyFlags) -> ScriptDebugger<'a> {
+        ScriptDebugger::with_stack(script, Stack::new(), z, flags)
+    }
+
+    /// Starts from `stack`, say what script_sig left for script_pubkey.
+    pub fn with_stack(script: &'a Script, stack: Stack, z: &'a BigInt, flags: VerifyFlags) -> ScriptDebugger<'a> {
+        ScriptDebugger { execution: Execution::new(script, stack, z, flags), script, status: Status::Running, hook: None }
+    }
+
+    /// Calls `hook` with a snapshot after every step.
+    pub fn on_step<F: FnMut(&Snapshot) + 'a>(&mut self, hook: F) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.execution.stack
+    }
+
+    pub fn altstack(&self) -> &Stack {
+        &self.execution.altstack
+    }
+
+    /// The commands not run yet.
+    pub fn remaining(&self) -> &'a [Command] {
+        &self.script.cmds[self.execution.position..]
+    }
+
+    /// Runs the next command. Malformed scripts are errors, as with
+    /// `Script::evaluate`; stepping a finished script does nothing.
+    pub fn step(&mut self) -> Result<Status> {
+        if self.status != Status::Running {
+            return Ok(self.status);
+        }
+        if self.execution.is_done() {
+            self.settle()?;
+            return Ok(self.status);
+        }
+        let position = self.execution.position;
+        let executed = self.execution.is_executing() || matches!(self.script.cmds[position], Command::Op(byte) if is_conditional(byte));
+        let ok = self.execution.step()?;
+        if !ok {
+            self.status = Status::Failed;
+        }
+        if let Some(hook) = self.hook.as_mut() {
+            hook(&Snapshot {
+                position,
+                command: self.script.cmds[position].clone(),
+                executed,
+                stack: self.execution.stack.clone(),
+                altstack: self.execution.altstack.clone(),
+            });
+        }
+        if ok && self.execution.is_done() {
+            self.settle()?;
+        }
+        Ok(self.status)
+    }
+
+    /// Steps to the end, succeeding exactly when `Script::evaluate` would.
+    pub fn run(&mut self) -> Result<bool> {
+        while self.step()? == Status::Running {}
+        Ok(self.status == Status::Succeeded)
+    }
+
+    // Decides the outcome once the last command has run
+    fn settle(&mut self) -> Result<()> {
+        self.execution.finish()?;
+        let ok = self.execution.stack.last().is_some_and(|top| cast_to_bool(top));
+        self.status = if ok { Status::Succeeded } else { Status::Failed };
+        Ok(())
+    }
+}
+
+/// IF, NOTIF, ELSE and ENDIF are looked at even in branches not taken.
+fn is_conditional(byte: u8) -> bool {
+    use super::opcodes::OpCode::*;
+    [If, NotIf, Else, EndIf].iter().any(|op| op.to_u8() == byte)
+}
+
+impl fmt::Debug for ScriptDebugger<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScriptDebugger")
+            .field("status", &self.status)
+            .field("stack", self.stack())
+            .field("altstack", self.altstack())
+            .field("remaining", &self.remaining().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+use super::opcodes::OpCode;
+
+#[test]
+fn step_through_script() {
+    use std::cell::RefCell;
+    let ops = |ops: &[OpCode]| Script::new(ops.iter().map(|op| Command::Op(op.to_u8())).collect());
+    let script = ops(&[OpCode::Two, OpCode::Dup, OpCode::ToAltStack, OpCode::Three, OpCode::Add, OpCode::Five, OpCode::Equal]);
+    let z = BigInt::from(0);
+    let snapshots = RefCell::new(Vec::new());
+    let mut debugger = ScriptDebugger::new(&script, &z, VerifyFlags::NONE);
+    debugger.on_step(|snapshot| snapshots.borrow_mut().push(snapshot.clone()));
+    assert_eq!(debugger.remaining().len(), 7);
+    assert_eq!(debugger.step().unwrap(), Status::Running);
+    assert_eq!(debugger.step().unwrap(), Status::Running);
+    assert_eq!(debugger.stack(), &vec![vec![2], vec![2]]);
+    debugger.step().unwrap();
+    assert_eq!((debugger.stack(), debugger.altstack()), (&vec![vec![2]], &vec![vec![2]]));
+    assert_eq!(debugger.remaining()[0], Command::Op(OpCode::Three.to_u8()));
+    assert!(debugger.run().unwrap());
+    assert_eq!(debugger.status(), Status::Succeeded);
+    drop(debugger);
+    let snapshots = snapshots.into_inner();
+    assert_eq!(snapshots.len(), 7);
+    assert_eq!(snapshots[4].stack, vec![vec![5]]);
+    assert_eq!(snapshots[6].position, 6);
+
+    // Stops at the failing command, skipped branches are marked
+    let failing = ops(&[OpCode::Zero, OpCode::If, OpCode::Return, OpCode::EndIf, OpCode::One, OpCode::Verify, OpCode::Return, OpCode::One]);
+    let skipped = RefCell::new(Vec::new());
+    let mut debugger = ScriptDebugger::new(&failing, &z, VerifyFlags::NONE);
+    debugger.on_step(|snapshot| {
+        if !snapshot.executed {
+            skipped.borrow_mut().push(snapshot.position)
+        }
+    });
+    assert!(!debugger.run().unwrap());
+    assert_eq!(debugger.remaining(), &failing.cmds[7..]);
+    drop(debugger);
+    assert_eq!(skipped.into_inner(), vec![2]);
+
+    let unbalanced = ops(&[OpCode::One, OpCode::If]);
+    assert!(ScriptDebugger::new(&unbalanced, &z, VerifyFlags::NONE).run().is_err());
+    assert_eq!(ScriptDebugger::new(&Script::default(), &z, VerifyFlags::NONE).step().unwrap(), Status::Failed);
+}
@@ -0,0 +1,839 @@
+//! Running scripts.
+//!
+//! Evaluation has three outcomes: the script succeeds (`Ok(true)`), the
+//! script is well formed but fails, say a VERIFY that does not hold or a
+//! stack too short for an opcode (`Ok(false)`), or the script is
+//! malformed and could never succeed whatever the stack: unbalanced
+//! conditionals, disabled opcodes, oversized scripts or elements, or too
+//! many opcodes (`ErrorKind::InvalidEncoding`).
+//!
+//! The size and count limits are consensus rules and always enforced.
+//! Standardness rules, which only decide what nodes relay, are turned on
+//! by `VerifyFlags`.
+//!
+//! Signatures are checked against the sighash `z` the caller computed for
+//! the input, whatever hash type byte they carry, as in the book. For
+//! segwit inputs that is the BIP143 digest of the script being run.
+//!
+//! OP_CHECKLOCKTIMEVERIFY and OP_CHECKSEQUENCEVERIFY compare against the
+//! spending transaction, which `verify_script_in` takes as a
+//! `SpendContext`. Without one they fail, as in Bitcoin Core, unless their
+//! flags are off and they are the NOP2 and NOP3 they replaced.
+//!
+//! Along with the verdict, runs report what they used in `ExecMetrics`,
+//! added up over every script `verify_script` runs for an input.
+
+use super::opcodes::OpCode;
+use super::ops::{cast_to_bool, decode_num, decode_num_sized, encode_num, handler, Handler, Stack};
+use super::{p2pkh_script, Command, Script, ScriptType};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::sha256;
+use crate::math::ecc::{S256Point, Signature};
+use crate::tx::{LockTime, Sequence, Tx};
+use num_bigint::BigInt;
+use std::convert::{TryFrom, TryInto};
+use std::ops::BitOr;
+
+/// Largest element that may be pushed.
+pub const MAX_ELEMENT_SIZE: usize = 520;
+/// Most opcodes above OP_16 a script may contain.
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+/// Most elements on the stack and altstack together.
+pub const MAX_STACK_SIZE: usize = 1000;
+/// Most public keys an OP_CHECKMULTISIG may check against.
+pub const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+/// Largest script that may be run, in bytes.
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+/// Most bytes a standard OP_RETURN output may push.
+pub const MAX_NULL_DATA_SIZE: usize = 80;
+
+/// Rules beyond the original consensus ones, after Bitcoin Core's
+/// `SCRIPT_VERIFY_*` flags. Combine them with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct VerifyFlags(u32);
+
+impl VerifyFlags {
+    pub const NONE: VerifyFlags = VerifyFlags(0);
+    /// BIP16: run the redeem script of P2SH outputs.
+    pub const P2SH: VerifyFlags = VerifyFlags(1 << 0);
+    /// BIP62: the stack holds exactly one element after a successful
+    /// legacy or P2SH spend.
+    pub const CLEANSTACK: VerifyFlags = VerifyFlags(1 << 8);
+    /// BIP65: OP_CHECKLOCKTIMEVERIFY checks the transaction's locktime.
+    pub const CHECKLOCKTIMEVERIFY: VerifyFlags = VerifyFlags(1 << 9);
+    /// BIP112: OP_CHECKSEQUENCEVERIFY checks the input's sequence.
+    pub const CHECKSEQUENCEVERIFY: VerifyFlags = VerifyFlags(1 << 10);
+    /// BIP141: run witness programs against the witness.
+    pub const WITNESS: VerifyFlags = VerifyFlags(1 << 11);
+    /// The argument of OP_IF and OP_NOTIF must be empty or exactly 0x01.
+    /// Like Bitcoin Core, `verify_script` only applies it to witness
+    /// scripts.
+    pub const MINIMALIF: VerifyFlags = VerifyFlags(1 << 13);
+    /// BIP147: the element OP_CHECKMULTISIG pops past the signatures must
+    /// be empty.
+    pub const NULLDUMMY: VerifyFlags = VerifyFlags(1 << 4);
+    /// Policy: OP_RETURN outputs push at most `MAX_NULL_DATA_SIZE` bytes,
+    /// see `check_output`.
+    pub const NULLDATA_SIZE: VerifyFlags = VerifyFlags(1 << 24);
+
+    /// What blocks are checked with.
+    pub const CONSENSUS: VerifyFlags =
+        VerifyFlags(Self::P2SH.0 | Self::NULLDUMMY.0 | Self::CHECKLOCKTIMEVERIFY.0 | Self::CHECKSEQUENCEVERIFY.0 | Self::WITNESS.0);
+    /// What transactions are relayed with.
+    pub const STANDARD: VerifyFlags =
+        VerifyFlags(Self::CONSENSUS.0 | Self::CLEANSTACK.0 | Self::MINIMALIF.0 | Self::NULLDATA_SIZE.0);
+
+    pub fn contains(self, other: VerifyFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn without(self, other: VerifyFlags) -> VerifyFlags {
+        VerifyFlags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for VerifyFlags {
+    type Output = VerifyFlags;
+
+    fn bitor(self, other: VerifyFlags) -> VerifyFlags {
+        VerifyFlags(self.0 | other.0)
+    }
+}
+
+/// What running scripts used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecMetrics {
+    /// Opcodes run in branches being executed, pushes of data aside
+    pub ops: usize,
+    /// Most elements on the stack and altstack together at any point
+    pub max_stack_depth: usize,
+    /// OP_RIPEMD160, OP_SHA1, OP_SHA256, OP_HASH160 and OP_HASH256 run
+    pub hash_ops: usize,
+}
+
+impl ExecMetrics {
+    fn add(&mut self, other: ExecMetrics) {
+        self.ops += other.ops;
+        self.max_stack_depth = self.max_stack_depth.max(other.max_stack_depth);
+        self.hash_ops += other.hash_ops;
+    }
+}
+
+/// Whether a script succeeded, and what it took to find out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub valid: bool,
+    pub metrics: ExecMetrics,
+}
+
+/// What the timelock opcodes check against: the spending transaction's
+/// version and locktime, and the sequence of the input being verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpendContext {
+    pub version: u32,
+    pub lock_time: LockTime,
+    pub sequence: Sequence,
+}
+
+impl SpendContext {
+    /// The context of input `input_index` of `tx`.
+    pub fn new(tx: &Tx, input_index: usize) -> Result<SpendContext> {
+        let tx_in = tx.tx_ins.get(input_index).ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("input {} of a transaction with {}", input_index, tx.tx_ins.len()))
+        })?;
+        Ok(SpendContext { version: tx.version, lock_time: tx.locktime, sequence: tx_in.sequence })
+    }
+}
+
+fn malformed(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, msg)
+}
+
+/// Whether an output may pay to `script_pubkey`. Consensus allows any
+/// script; `VerifyFlags::NULLDATA_SIZE` limits the data OP_RETURN outputs
+/// carry.
+pub fn check_output(script_pubkey: &Script, flags: VerifyFlags) -> bool {
+    if flags.contains(VerifyFlags::NULLDATA_SIZE) && script_pubkey.classify() == ScriptType::NullData {
+        let data: usize = script_pubkey.cmds.iter().map(|cmd| match cmd {
+            Command::Data(data) => data.len(),
+            Command::Op(_) => 0,
+        }).sum();
+        return data <= MAX_NULL_DATA_SIZE;
+    }
+    true
+}
+
+fn top_is_true(stack: &Stack) -> bool {
+    stack.last().is_some_and(|top| cast_to_bool(top))
+}
+
+/// Verifies an input: runs `script_sig`, then `script_pubkey` on the stack
+/// it leaves. With `VerifyFlags::P2SH` a P2SH `script_pubkey` also needs
+/// a push-only `script_sig` whose last element, the redeem script, runs on
+/// the elements below it. With `VerifyFlags::WITNESS` a v0 witness
+/// program, native or as the redeem script, is run against `witness`.
+/// `z` is the sighash for whichever script holds the signature checks.
+pub fn verify_script(script_sig: &Script, script_pubkey: &Script, witness: &[Vec<u8>], z: &BigInt, flags: VerifyFlags) -> Result<Verdict> {
+    let mut metrics = ExecMetrics::default();
+    let valid = verify(script_sig, script_pubkey, witness, z, flags, None, &mut metrics)?;
+    Ok(Verdict { valid, metrics })
+}
+
+/// `verify_script` for an input of the transaction in `context`, so that
+/// timelocks can be checked.
+pub fn verify_script_in(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    witness: &[Vec<u8>],
+    z: &BigInt,
+    flags: VerifyFlags,
+    context: &SpendContext,
+) -> Result<Verdict> {
+    let mut metrics = ExecMetrics::default();
+    let valid = verify(script_sig, script_pubkey, witness, z, flags, Some(context), &mut metrics)?;
+    Ok(Verdict { valid, metrics })
+}
+
+fn verify(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    witness: &[Vec<u8>],
+    z: &BigInt,
+    flags: VerifyFlags,
+    context: Option<&SpendContext>,
+    metrics: &mut ExecMetrics,
+) -> Result<bool> {
+    let legacy_flags = flags.without(VerifyFlags::MINIMALIF);
+    let mut stack = Stack::new();
+    if !script_sig.execute_into(&mut stack, z, legacy_flags, context, metrics)? {
+        return Ok(false);
+    }
+    let script_sig_stack = stack.clone();
+    if !script_pubkey.execute_into(&mut stack, z, legacy_flags, context, metrics)? || !top_is_true(&stack) {
+        return Ok(false);
+    }
+
+    let mut witnessed = false;
+    if let (true, Some((version, program))) = (flags.contains(VerifyFlags::WITNESS), script_pubkey.witness_program()) {
+        witnessed = true;
+        // Anything in script_sig could be changed without breaking the signatures
+        if !script_sig.cmds.is_empty() || !verify_witness_program(version, program, witness, z, flags, context, metrics)? {
+            return Ok(false);
+        }
+        stack.truncate(1);
+    }
+
+    if flags.contains(VerifyFlags::P2SH) && script_pubkey.is_p2sh() {
+        if !script_sig.is_push_only() {
+            return Ok(false);
+        }
+        // Not empty, or the hash would not have matched
+        stack = script_sig_stack;
+        let redeem = stack.pop().unwrap();
+        let redeem_script = Script::from_bytes(&redeem)?;
+        if !redeem_script.execute_into(&mut stack, z, legacy_flags, context, metrics)? || !top_is_true(&stack) {
+            return Ok(false);
+        }
+        if let (true, Some((version, program))) = (flags.contains(VerifyFlags::WITNESS), redeem_script.witness_program()) {
+            witnessed = true;
+            // P2SH-wrapped: the script_sig is the one push of the redeem script
+            if script_sig.cmds != [Command::Data(redeem.clone())]
+                || !verify_witness_program(version, program, witness, z, flags, context, metrics)?
+            {
+                return Ok(false);
+            }
+            stack.truncate(1);
+        }
+    }
+
+    if flags.contains(VerifyFlags::CLEANSTACK) && stack.len() != 1 {
+        return Ok(false);
+    }
+    // A witness nothing reads could be changed at will
+    if flags.contains(VerifyFlags::WITNESS) && !witnessed && !witness.is_empty() {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// P2WPKH runs the P2PKH script of the key hash and P2WSH the witness
+/// script committed to, each on the rest of the witness. Versions 2 to 16
+/// are left for future soft forks and pass.
+fn verify_witness_program(
+    version: u8,
+    program: &[u8],
+    witness: &[Vec<u8>],
+    z: &BigInt,
+    flags: VerifyFlags,
+    context: Option<&SpendContext>,
+    metrics: &mut ExecMetrics,
+) -> Result<bool> {
+    let (script, stack) = match (version, program.len()) {
+        (0, 20) => {
+            if witness.len() != 2 {
+                return Ok(false);
+            }
+            (p2pkh_script(program.try_into().unwrap()), witness.to_vec())
+        }
+        (0, 32) => {
+            let (witness_script, rest) = match witness.split_last() {
+                Some(split) => split,
+                None => return Ok(false),
+            };
+            if sha256(witness_script)[..] != program[..] {
+                return Ok(false);
+            }
+            (Script::from_bytes(witness_script)?, rest.to_vec())
+        }
+        (0, _) => return Ok(false),
+        (1, 32) => {
+            return Err(Error::new(ErrorKind::UnsupportedScript, "taproot spends are verified by Tx::verify_input"))
+        }
+        _ => return Ok(true),
+    };
+    if stack.iter().any(|item| item.len() > MAX_ELEMENT_SIZE) {
+        return Ok(false);
+    }
+    let mut stack = stack;
+    if !script.execute_into(&mut stack, z, flags, context, metrics)? {
+        return Ok(false);
+    }
+    // Witness scripts always leave a clean stack
+    Ok(stack.len() == 1 && cast_to_bool(&stack[0]))
+}
+
+impl Script {
+    /// Runs the script on an empty stack, usually script_sig +
+    /// script_pubkey, and succeeds if it leaves a true value on top.
+    pub fn evaluate(&self, z: &BigInt, flags: VerifyFlags) -> Result<Verdict> {
+        let mut stack = Stack::new();
+        let verdict = self.execute(&mut stack, z, flags)?;
+        Ok(Verdict { valid: verdict.valid && top_is_true(&stack), ..verdict })
+    }
+
+    /// Runs the commands on `stack`, failing as soon as one fails.
+    pub fn execute(&self, stack: &mut Stack, z: &BigInt, flags: VerifyFlags) -> Result<Verdict> {
+        self.execute_in(stack, z, flags, None)
+    }
+
+    fn execute_in(&self, stack: &mut Stack, z: &BigInt, flags: VerifyFlags, context: Option<&SpendContext>) -> Result<Verdict> {
+        let mut execution = Execution::new(self, std::mem::take(stack), z, flags);
+        execution.context = context.copied();
+        let mut ok = true;
+        while ok && !execution.is_done() {
+            ok = execution.step()?;
+        }
+        if ok {
+            execution.finish()?;
+        }
+        *stack = execution.stack;
+        Ok(Verdict { valid: ok, metrics: execution.metrics })
+    }
+
+    fn execute_into(&self, stack: &mut Stack, z: &BigInt, flags: VerifyFlags, context: Option<&SpendContext>, metrics: &mut ExecMetrics) -> Result<bool> {
+        let verdict = self.execute_in(stack, z, flags, context)?;
+        metrics.add(verdict.metrics);
+        Ok(verdict.valid)
+    }
+}
+
+/// A script part way through running, one command at a time.
+pub(super) struct Execution<'a> {
+    script: &'a Script,
+    z: &'a BigInt,
+    flags: VerifyFlags,
+    context: Option<SpendContext>,
+    /// Index of the next command
+    pub(super) position: usize,
+    pub(super) stack: Stack,
+    pub(super) altstack: Stack,
+    /// Whether each enclosing IF branch is being executed
+    conditions: Vec<bool>,
+    op_count: usize,
+    pub(super) metrics: ExecMetrics,
+}
+
+impl<'a> Execution<'a> {
+    pub(super) fn new(script: &'a Script, stack: Stack, z: &'a BigInt, flags: VerifyFlags) -> Execution<'a> {
+        let metrics = ExecMetrics { max_stack_depth: stack.len(), ..ExecMetrics::default() };
+        Execution { script, z, flags, context: None, position: 0, stack, altstack: Stack::new(), conditions: Vec::new(), op_count: 0, metrics }
+    }
+
+    pub(super) fn is_done(&self) -> bool {
+        self.position >= self.script.cmds.len()
+    }
+
+    /// Whether the next command is in a branch being executed.
+    pub(super) fn is_executing(&self) -> bool {
+        self.conditions.iter().all(|&taken| taken)
+    }
+
+    /// Fails if an OP_IF is left open once every command has run.
+    pub(super) fn finish(&self) -> Result<()> {
+        if !self.conditions.is_empty() {
+            return Err(malformed("OP_IF without OP_ENDIF".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Runs the next command, returning `false` if it fails the script.
+    pub(super) fn step(&mut self) -> Result<bool> {
+        if self.position == 0 {
+            let size = self.script.raw_serialize().len();
+            if size > MAX_SCRIPT_SIZE {
+                return Err(malformed(format!("{} byte script", size)));
+            }
+        }
+        let cmd = &self.script.cmds[self.position];
+        self.position += 1;
+        let ok = self.run(cmd)?;
+        let depth = self.stack.len() + self.altstack.len();
+        self.metrics.max_stack_depth = self.metrics.max_stack_depth.max(depth);
+        Ok(ok && depth <= MAX_STACK_SIZE)
+    }
+
+    fn run(&mut self, cmd: &Command) -> Result<bool> {
+        let executing = self.is_executing();
+        let stack = &mut self.stack;
+        let byte = match cmd {
+            Command::Data(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(malformed(format!("push of {} bytes", data.len())));
+                }
+                if executing {
+                    stack.push(data.clone());
+                }
+                return Ok(true);
+            }
+            Command::Op(byte) => *byte,
+        };
+        if byte > OpCode::Sixteen.to_u8() {
+            self.op_count += 1;
+            if self.op_count > MAX_OPS_PER_SCRIPT {
+                return Err(malformed(format!("more than {} opcodes", MAX_OPS_PER_SCRIPT)));
+            }
+        }
+        if executing {
+            self.metrics.ops += 1;
+        }
+        let op = match OpCode::from_u8(byte) {
+            Some(op) => op,
+            // Undefined opcodes only fail when executed
+            None => return Ok(!executing),
+        };
+        if op.is_disabled() {
+            return Err(malformed(format!("disabled opcode {}", op.name())));
+        }
+        let (z, flags, context) = (self.z, self.flags, self.context);
+        match op {
+            // Fail even in a branch not taken
+            OpCode::VerIf | OpCode::VerNotIf => return Ok(false),
+            OpCode::If | OpCode::NotIf => {
+                let mut taken = false;
+                if executing {
+                    taken = match stack.pop() {
+                        Some(top) if flags.contains(VerifyFlags::MINIMALIF) && !(top.is_empty() || top == [1u8]) => return Ok(false),
+                        Some(top) => cast_to_bool(&top),
+                        None => return Ok(false),
+                    };
+                    if op == OpCode::NotIf {
+                        taken = !taken;
+                    }
+                }
+                self.conditions.push(taken);
+            }
+            OpCode::Else => match self.conditions.last_mut() {
+                Some(taken) => *taken = !*taken,
+                None => return Err(malformed("OP_ELSE outside OP_IF".to_string())),
+            },
+            OpCode::EndIf => {
+                if self.conditions.pop().is_none() {
+                    return Err(malformed("OP_ENDIF outside OP_IF".to_string()));
+                }
+            }
+            _ if !executing => {}
+            op => {
+                if matches!(op, OpCode::Ripemd160 | OpCode::Sha1 | OpCode::Sha256 | OpCode::Hash160 | OpCode::Hash256) {
+                    self.metrics.hash_ops += 1;
+                }
+                return Ok(match handler(op) {
+                    Some(Handler::Stack(f)) => f(stack),
+                    Some(Handler::AltStack(f)) => f(stack, &mut self.altstack),
+                    None => match op {
+                        OpCode::CheckSig => op_checksig(stack, z),
+                        OpCode::CheckSigVerify => op_checksig(stack, z) && stack.pop().is_some_and(|top| cast_to_bool(&top)),
+                        OpCode::CheckMultiSig | OpCode::CheckMultiSigVerify => {
+                            // Every public key counts against the opcode limit
+                            let keys = stack.last().and_then(|top| decode_num(top)).unwrap_or(0);
+                            if (0..=MAX_PUBKEYS_PER_MULTISIG).contains(&keys) {
+                                self.op_count += keys as usize;
+                                if self.op_count > MAX_OPS_PER_SCRIPT {
+                                    return Err(malformed(format!("more than {} opcodes", MAX_OPS_PER_SCRIPT)));
+                                }
+                            }
+                            op_checkmultisig(stack, z, flags)
+                                && (op == OpCode::CheckMultiSig || stack.pop().is_some_and(|top| cast_to_bool(&top)))
+                        }
+                        OpCode::CheckLockTimeVerify if flags.contains(VerifyFlags::CHECKLOCKTIMEVERIFY) => {
+                            op_checklocktimeverify(stack, context.as_ref())
+                        }
+                        OpCode::CheckSequenceVerify if flags.contains(VerifyFlags::CHECKSEQUENCEVERIFY) => {
+                            op_checksequenceverify(stack, context.as_ref())
+                        }
+                        OpCode::CheckLockTimeVerify | OpCode::CheckSequenceVerify => true,
+                        // Only matters to legacy signature hashes
+                        OpCode::CodeSeparator => true,
+                        // OP_RESERVED and friends
+                        OpCode::Reserved | OpCode::Ver | OpCode::Reserved1 | OpCode::Reserved2 | OpCode::InvalidOpCode => false,
+                        _ => {
+                            return Err(Error::new(ErrorKind::UnsupportedScript,
+                                format!("{} is not supported by the interpreter", op.name())))
+                        }
+                    },
+                });
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The lock on top of the stack, left there: a script number of up to 5
+// bytes so that it reaches every u32, and not negative
+fn top_lock(stack: &Stack) -> Option<i64> {
+    stack.last().and_then(|top| decode_num_sized(top, 5)).filter(|lock| *lock >= 0)
+}
+
+fn op_checklocktimeverify(stack: &Stack, context: Option<&SpendContext>) -> bool {
+    let (lock, context) = match (top_lock(stack).and_then(|lock| u32::try_from(lock).ok()), context) {
+        (Some(lock), Some(context)) => (lock, context),
+        _ => return false,
+    };
+    // A final input would let the locktime be ignored
+    context.lock_time.satisfies(LockTime::from_consensus(lock)) && !context.sequence.is_final()
+}
+
+fn op_checksequenceverify(stack: &Stack, context: Option<&SpendContext>) -> bool {
+    match top_lock(stack) {
+        // The disable flag makes it a NOP, whatever the transaction
+        Some(lock) if lock & (1 << 31) != 0 => true,
+        Some(lock) => context.is_some_and(|context| context.sequence.satisfies(Sequence::from_consensus(lock as u32), context.version)),
+        None => false,
+    }
+}
+
+/// Whether `sig`, a DER signature followed by its hash type byte, signs
+/// `z` for the SEC public key `sec`. Anything that does not parse is
+/// simply a signature that does not verify.
+fn check_sig(sig: &[u8], sec: &[u8], z: &BigInt) -> bool {
+    let der = match sig.split_last() {
+        Some((_, der)) => der,
+        None => return false,
+    };
+    match (Signature::parse_der(der), S256Point::parse_sec(sec)) {
+        (Ok(sig), Ok(point)) => point.verify(z, &sig),
+        _ => false,
+    }
+}
+
+fn op_checksig(stack: &mut Stack, z: &BigInt) -> bool {
+    let (sec, sig) = match (stack.pop(), stack.pop()) {
+        (Some(sec), Some(sig)) => (sec, sig),
+        _ => return false,
+    };
+    stack.push(encode_num(check_sig(&sig, &sec, z) as i64));
+    true
+}
+
+/// m-of-n: signatures must match the public keys in order, so each key is
+/// tried once and skipped if it does not verify the next signature.
+fn op_checkmultisig(stack: &mut Stack, z: &BigInt, flags: VerifyFlags) -> bool {
+    let n = match stack.pop().and_then(|top| decode_num(&top)) {
+        Some(n) if (0..=MAX_PUBKEYS_PER_MULTISIG).contains(&n) => n as usize,
+        _ => return false,
+    };
+    if stack.len() < n {
+        return false;
+    }
+    let secs = stack.split_off(stack.len() - n);
+    let m = match stack.pop().and_then(|top| decode_num(&top)) {
+        Some(m) if (0..=n as i64).contains(&m) => m as usize,
+        _ => return false,
+    };
+    // One more than the signatures: the original off-by-one pops an extra
+    // element, which BIP147 requires to be empty
+    if stack.len() < m + 1 {
+        return false;
+    }
+    let sigs = stack.split_off(stack.len() - m);
+    let dummy = stack.pop().unwrap();
+    if flags.contains(VerifyFlags::NULLDUMMY) && !dummy.is_empty() {
+        return false;
+    }
+    let mut keys = secs.iter();
+    let ok = sigs.iter().all(|sig| keys.by_ref().any(|sec| check_sig(sig, sec, z)));
+    stack.push(encode_num(ok as i64));
+    true
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::params::Network;
+
+#[cfg(test)]
+fn ops(ops: &[OpCode]) -> Script {
+    Script::new(ops.iter().map(|&op| Command::Op(op.to_u8())).collect())
+}
+
+#[test]
+fn evaluate_scripts() {
+    use OpCode::*;
+    assert!(ops(&[Two, Three, Add, Five, Equal]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(!ops(&[Two, Three, Add, Six, Equal]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(!ops(&[]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(!ops(&[Zero]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+
+    // Chapter 6's exercise: 0x56 0x76 0x87 0x93 0x95 0x56 0x87 without OP_MUL
+    assert!(ops(&[Two, Dup, Dup, Add, Add, Six, Equal]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+
+    // sha256("") and hash160 of an empty element
+    let mut hashes = ops(&[Zero, Sha256]);
+    hashes.cmds.push(Command::Data(decode_hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855").unwrap()));
+    hashes.cmds.extend(ops(&[EqualVerify, Zero, Hash160]).cmds);
+    hashes.cmds.push(Command::Data(decode_hex("b472a266d0bd89c13706a4132ccfb16f7c3b9fcb").unwrap()));
+    hashes.cmds.push(Command::Op(Equal.to_u8()));
+    assert!(hashes.evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+
+    // Both branches, nested, with the altstack
+    assert!(ops(&[One, If, Two, Else, Three, EndIf, Two, Equal]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(ops(&[Zero, If, Two, Else, Three, EndIf, Three, Equal]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(ops(&[One, NotIf, Return, Else, Zero, If, Return, EndIf, Seven, EndIf, Seven, Equal]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(ops(&[Four, ToAltStack, One, FromAltStack, Four, EqualVerify]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+
+    // Undefined and reserved opcodes fail only when executed
+    let mut skipped = ops(&[Zero, If, Reserved]);
+    skipped.cmds.push(Command::Op(0xbb));
+    skipped.cmds.extend(ops(&[EndIf, One]).cmds);
+    assert!(skipped.evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(!ops(&[One, Reserved]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(!ops(&[Zero, If, VerIf, EndIf, One]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+
+    // Failing scripts against malformed ones
+    assert!(!ops(&[Dup]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert!(!ops(&[If, EndIf, One]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    assert_eq!(ops(&[One, If]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert_eq!(ops(&[Else, One]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert_eq!(ops(&[Zero, If, Cat, EndIf, One]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert!(Script::new(vec![Command::Data(vec![1; 521])]).evaluate(&BigInt::from(0), VerifyFlags::NONE).is_err());
+    assert!(ops(&vec![Nop; 202]).evaluate(&BigInt::from(0), VerifyFlags::NONE).is_err());
+    let mut large = Script::new(vec![Command::Data(vec![1; 500]); 20]);
+    assert!(large.evaluate(&BigInt::from(0), VerifyFlags::NONE).is_err());
+    large.cmds.truncate(19);
+    assert!(large.evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    let mut deep = Script::new(vec![Command::Data(vec![1]); MAX_STACK_SIZE]);
+    assert!(deep.evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+    deep.cmds.push(Command::Op(Dup.to_u8()));
+    assert!(!deep.evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap().valid);
+
+    // What a run used, branches not taken aside
+    let verdict = ops(&[Zero, Sha256, Dup, Hash160, Drop, Zero, If, Sha1, EndIf, One]).evaluate(&BigInt::from(0), VerifyFlags::NONE).unwrap();
+    assert_eq!(verdict, Verdict { valid: true, metrics: ExecMetrics { ops: 8, max_stack_depth: 2, hash_ops: 2 } });
+}
+
+#[cfg(test)]
+fn data(hex: &str) -> Command {
+    Command::Data(decode_hex(hex).unwrap())
+}
+
+#[test]
+fn check_signatures() {
+    // Chapter 6's pay-to-pubkey example
+    let z = BigInt::parse_bytes("7c076ff316692a3d7eb3c3bb0f8b1488cf72e1afcd929e29307032997a838a3d".as_bytes(), 16).unwrap();
+    let sec = data("04887387e452b8eacc4acfde10d9aaf7f6d9a0f975aabb10d006e4da568744d06c61de6d95231cd89026e286df3b6ae4a894a3378e393e93a0f45b666329a0ae34");
+    let sig = data("3045022000eff69ef2b1bd93a66ed5219add4fb51e11a840f404876325a1e8ffe0529a2c022100c7207fee197d27c618aea621406f6bf5ef6fca38681d82b2f06fddbdce6feab601");
+    let p2pk = Script::new(vec![sig.clone(), sec.clone(), Command::Op(OpCode::CheckSig.to_u8())]);
+    assert!(p2pk.evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+    assert!(!p2pk.evaluate(&(z.clone() + 1), VerifyFlags::NONE).unwrap().valid);
+    let mut verify = p2pk.clone();
+    verify.cmds[2] = Command::Op(OpCode::CheckSigVerify.to_u8());
+    assert!(!verify.evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+    verify.cmds.push(Command::Op(OpCode::One.to_u8()));
+    assert!(verify.evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+    // Garbage fails the check rather than the script
+    let garbage = Script::new(vec![data("3001"), sec, Command::Op(OpCode::CheckSig.to_u8()), Command::Op(OpCode::Not.to_u8())]);
+    assert!(garbage.evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+
+    // Chapter 8's 2-of-2 multisig
+    let z = BigInt::parse_bytes("e71bfa115715d6fd33796948126f40a8cdd39f187e4afb03896795189fe1423c".as_bytes(), 16).unwrap();
+    let sig1 = data("3045022100dc92655fe37036f47756db8102e0d7d5e28b3beb83a8fef4f5dc0559bddfb94e02205a36d4e4e6c7fcd16658c50783e00c341609977aed3ad00937bf4ee942a8993701");
+    let sig2 = data("3045022100da6bee3c93766232079a01639d07fa869598749729ae323eab8eef53577d611b02207bef15429dcadce2121ea07f233115c6f09034c0be68db99980b9a6c5e75402201");
+    let sec1 = data("022626e955ea6ea6d98850c994f9107b036b1334f18ca8830bfff1295d21cfdb70");
+    let sec2 = data("03b287eaf122eea69030a0e9feed096bed8045c8b98bec453e1ffac7fbdbd4bb71");
+    let multisig = |dummy: u8, sigs: &[&Command]| {
+        let mut cmds = vec![Command::Op(dummy)];
+        cmds.extend(sigs.iter().map(|&sig| sig.clone()));
+        cmds.push(Command::Op(OpCode::Two.to_u8()));
+        cmds.extend(vec![sec1.clone(), sec2.clone()]);
+        cmds.extend(vec![Command::Op(OpCode::Two.to_u8()), Command::Op(OpCode::CheckMultiSig.to_u8())]);
+        Script::new(cmds)
+    };
+    let zero = OpCode::Zero.to_u8();
+    assert!(multisig(zero, &[&sig1, &sig2]).evaluate(&z, VerifyFlags::NULLDUMMY).unwrap().valid);
+    // Out of order, or missing the extra element
+    assert!(!multisig(zero, &[&sig2, &sig1]).evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+    let mut short = multisig(zero, &[&sig1, &sig2]);
+    short.cmds.remove(0);
+    assert!(!short.evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+    // A non-empty dummy only fails under NULLDUMMY
+    let one = OpCode::One.to_u8();
+    assert!(multisig(one, &[&sig1, &sig2]).evaluate(&z, VerifyFlags::NONE).unwrap().valid);
+    assert!(!multisig(one, &[&sig1, &sig2]).evaluate(&z, VerifyFlags::NULLDUMMY).unwrap().valid);
+}
+
+#[test]
+fn verify_p2sh() {
+    use crate::tx::{SighashType, Tx};
+    // Chapter 8's 2-of-2 P2SH spend
+    let raw = decode_hex("0100000001868278ed6ddfb6c1ed3ad5f8181eb0c7a385aa0836f01d5e4789e6bd304d87221a000000db00483045022100dc92655fe37036f47756db8102e0d7d5e28b3beb83a8fef4f5dc0559bddfb94e02205a36d4e4e6c7fcd16658c50783e00c341609977aed3ad00937bf4ee942a8993701483045022100da6bee3c93766232079a01639d07fa869598749729ae323eab8eef53577d611b02207bef15429dcadce2121ea07f233115c6f09034c0be68db99980b9a6c5e75402201475221022626e955ea6ea6d98850c994f9107b036b1334f18ca8830bfff1295d21cfdb702103b287eaf122eea69030a0e9feed096bed8045c8b98bec453e1ffac7fbdbd4bb7152aeffffffff04d3b11400000000001976a914904a49878c0adfc3aa05de7afad2cc15f483a56a88ac7f400900000000001976a914418327e3f3dda4cf5b9089325a4b95abdfa0334088ac722c0c00000000001976a914ba35042cfe9fc66fd35ac2224eebdafd1028ad2788acdc4ace020000000017a91474d691da1574e6b3c192ecfb52cc8984ee7b6c568700000000").unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let script_sig = Script::from_bytes(&tx.tx_ins[0].script_sig).unwrap();
+    assert_eq!(script_sig.raw_serialize(), tx.tx_ins[0].script_sig);
+    let redeem = match script_sig.cmds.last() {
+        Some(Command::Data(redeem)) => redeem.clone(),
+        _ => panic!("expected the redeem script"),
+    };
+    let redeem_script = Script::from_bytes(&redeem).unwrap();
+    assert_eq!(redeem_script.p2sh_address(Network::Mainnet), "3CLoMMyuoDQTPRD3XYZtCvgvkadrAdvdXh");
+    let script_pubkey = redeem_script.p2sh_script_pubkey();
+    assert!(script_pubkey.is_p2sh() && script_sig.is_push_only());
+    let z = tx.legacy_sig_hash(0, &redeem, SighashType::All).unwrap();
+    assert_eq!(z, BigInt::parse_bytes("e71bfa115715d6fd33796948126f40a8cdd39f187e4afb03896795189fe1423c".as_bytes(), 16).unwrap());
+
+    let flags = VerifyFlags::P2SH | VerifyFlags::NULLDUMMY;
+    assert!(verify_script(&script_sig, &script_pubkey, &[], &z, flags).unwrap().valid);
+    assert!(!verify_script(&script_sig, &script_pubkey, &[], &(z.clone() + 1), flags).unwrap().valid);
+    // Before BIP16 only the hash is checked
+    let mut no_sigs = script_sig.clone();
+    no_sigs.cmds.drain(1..3);
+    assert!(!verify_script(&no_sigs, &script_pubkey, &[], &z, flags).unwrap().valid);
+    assert!(verify_script(&no_sigs, &script_pubkey, &[], &z, VerifyFlags::NONE).unwrap().valid);
+    // The script_sig may only push
+    let mut not_push_only = script_sig.clone();
+    not_push_only.cmds.insert(0, Command::Op(OpCode::Nop.to_u8()));
+    assert!(!verify_script(&not_push_only, &script_pubkey, &[], &z, flags).unwrap().valid);
+}
+
+#[test]
+fn verify_witness_v0() {
+    use crate::hash::hash160;
+    use crate::math::ecc::PrivateKey;
+    let key = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    let sec = key.public_key().sec(true);
+    let z = BigInt::from(0x1234_5678);
+    let mut sig = key.sign(&z).der();
+    sig.push(1);
+    let flags = VerifyFlags::P2SH | VerifyFlags::WITNESS | VerifyFlags::CLEANSTACK | VerifyFlags::MINIMALIF;
+    let empty = Script::default();
+
+    let p2wpkh = Script::new(vec![Command::Op(OpCode::Zero.to_u8()), Command::Data(hash160(&sec).to_vec())]);
+    assert_eq!(p2wpkh.witness_program(), Some((0, &hash160(&sec)[..])));
+    let witness = vec![sig.clone(), sec.clone()];
+    assert!(verify_script(&empty, &p2wpkh, &witness, &z, flags).unwrap().valid);
+    // OP_0 in the script_pubkey, then the four opcodes of P2PKH on the witness
+    let metrics = verify_script(&empty, &p2wpkh, &witness, &z, flags).unwrap().metrics;
+    assert_eq!(metrics, ExecMetrics { ops: 5, max_stack_depth: 4, hash_ops: 1 });
+    assert!(!verify_script(&empty, &p2wpkh, &witness, &(z.clone() + 1), flags).unwrap().valid);
+    assert!(!verify_script(&empty, &p2wpkh, &[sig.clone(), sec.clone(), Vec::new()], &z, flags).unwrap().valid);
+    let one = Script::new(vec![Command::Op(OpCode::One.to_u8())]);
+    assert!(!verify_script(&one, &p2wpkh, &witness, &z, flags).unwrap().valid);
+    // Without WITNESS the program is an anyone-can-spend push
+    assert!(verify_script(&empty, &p2wpkh, &[], &z, VerifyFlags::P2SH).unwrap().valid);
+
+    // Nested in P2SH, the script_sig pushes only the program
+    let nested = Script::new(vec![Command::Data(p2wpkh.raw_serialize())]);
+    assert!(verify_script(&nested, &p2wpkh.p2sh_script_pubkey(), &witness, &z, flags).unwrap().valid);
+    let padded = Script::new(vec![Command::Op(OpCode::Zero.to_u8()), Command::Data(p2wpkh.raw_serialize())]);
+    assert!(!verify_script(&padded, &p2wpkh.p2sh_script_pubkey(), &witness, &z, flags).unwrap().valid);
+
+    // P2WSH of <sec> OP_CHECKSIG behind an OP_IF
+    let witness_script = Script::new(vec![
+        Command::Op(OpCode::If.to_u8()),
+        Command::Data(sec.clone()),
+        Command::Op(OpCode::CheckSig.to_u8()),
+        Command::Op(OpCode::Else.to_u8()),
+        Command::Op(OpCode::Zero.to_u8()),
+        Command::Op(OpCode::EndIf.to_u8()),
+    ]).raw_serialize();
+    let p2wsh = Script::new(vec![Command::Op(OpCode::Zero.to_u8()), Command::Data(sha256(&witness_script).to_vec())]);
+    assert!(verify_script(&empty, &p2wsh, &[sig.clone(), vec![1], witness_script.clone()], &z, flags).unwrap().valid);
+    assert!(!verify_script(&empty, &p2wsh, &[sig.clone(), vec![], witness_script.clone()], &z, flags).unwrap().valid);
+    assert!(!verify_script(&empty, &p2wsh, &[sig.clone(), vec![1], witness_script[1..].to_vec()], &z, flags).unwrap().valid);
+    // MINIMALIF rejects any other true value
+    let non_minimal = [sig.clone(), vec![2], witness_script.clone()];
+    assert!(!verify_script(&empty, &p2wsh, &non_minimal, &z, flags).unwrap().valid);
+    assert!(verify_script(&empty, &p2wsh, &non_minimal, &z, flags.without(VerifyFlags::MINIMALIF)).unwrap().valid);
+    // An extra element is left behind
+    assert!(!verify_script(&empty, &p2wsh, &[vec![7], sig, vec![1], witness_script], &z, flags).unwrap().valid);
+
+    // Legacy scripts only need a clean stack under CLEANSTACK, and a
+    // witness only where a program reads it
+    let two = Script::new(vec![Command::Op(OpCode::One.to_u8()), Command::Op(OpCode::One.to_u8())]);
+    assert!(verify_script(&two, &one, &[], &z, VerifyFlags::P2SH).unwrap().valid);
+    assert!(!verify_script(&two, &one, &[], &z, flags).unwrap().valid);
+    assert!(!verify_script(&empty, &one, &[vec![1]], &z, flags).unwrap().valid);
+}
+
+#[test]
+fn standard_outputs() {
+    use super::op_return;
+    let flags = VerifyFlags::STANDARD;
+    assert!(flags.contains(VerifyFlags::CONSENSUS) && !VerifyFlags::CONSENSUS.contains(VerifyFlags::NULLDATA_SIZE));
+    assert!(check_output(&op_return(&[7; 80]), flags));
+    assert!(!check_output(&op_return(&[7; 81]), flags));
+    let mut split = op_return(&[7; 40]);
+    split.cmds.push(Command::Data(vec![7; 41]));
+    assert!(!check_output(&split, flags));
+    assert!(check_output(&split, VerifyFlags::CONSENSUS));
+    assert!(check_output(&super::p2wpkh_script(&[1; 20]), flags));
+}
+
+#[test]
+fn verify_timelocks() {
+    let z = BigInt::from(1);
+    let script = |lock: i64, op: OpCode| Script::new(vec![Command::num(lock), Command::Op(op.to_u8())]);
+    let at = |version: u32, lock_time: u32, sequence: u32| SpendContext {
+        version,
+        lock_time: LockTime::from_consensus(lock_time),
+        sequence: Sequence::from_consensus(sequence),
+    };
+    let run = |script: &Script, context: &SpendContext| {
+        verify_script_in(&Script::default(), script, &[], &z, VerifyFlags::CONSENSUS, context).unwrap().valid
+    };
+
+    let cltv = script(800_000, OpCode::CheckLockTimeVerify);
+    assert!(run(&cltv, &at(1, 800_000, 0xffff_fffe)));
+    assert!(!run(&cltv, &at(1, 799_999, 0xffff_fffe)));
+    assert!(!run(&cltv, &at(1, 1_700_000_000, 0xffff_fffe)));
+    // A final input ignores the locktime
+    assert!(!run(&cltv, &at(1, 800_000, 0xffff_ffff)));
+    // Five byte locks reach the times past 2^31
+    assert!(run(&script(0xffff_0000, OpCode::CheckLockTimeVerify), &at(1, 0xffff_ffff, 0)));
+    assert!(!run(&script(-1, OpCode::CheckLockTimeVerify), &at(1, 800_000, 0)));
+    assert!(!verify_script(&Script::default(), &cltv, &[], &z, VerifyFlags::CONSENSUS).unwrap().valid);
+    // Otherwise it is OP_NOP2
+    assert!(verify_script(&Script::default(), &cltv, &[], &z, VerifyFlags::P2SH).unwrap().valid);
+
+    let csv = script(144, OpCode::CheckSequenceVerify);
+    assert!(run(&csv, &at(2, 0, 144)));
+    assert!(!run(&csv, &at(2, 0, 143)));
+    assert!(!run(&csv, &at(1, 0, 144)));
+    assert!(!run(&csv, &at(2, 0, 0x0040_0090)));
+    assert!(!run(&csv, &at(2, 0, 0xffff_ffff)));
+    assert!(run(&script(1 << 31, OpCode::CheckSequenceVerify), &at(1, 0, 0xffff_ffff)));
+    let empty = Script::new(vec![Command::Op(OpCode::CheckSequenceVerify.to_u8())]);
+    assert!(!run(&empty, &at(2, 0, 144)));
+
+    let tx = Tx::new(2, vec![crate::tx::TxIn::new([1; 32], 0)], vec![], LockTime::ZERO, Network::Regtest);
+    assert_eq!(SpendContext::new(&tx, 0).unwrap(), at(2, 0, 0xffff_ffff));
+    assert!(SpendContext::new(&tx, 1).is_err());
+}
@@ -0,0 +1,256 @@
+//! Script (chapter 6).
+//!
+//! A script is a list of commands: opcodes, and elements of data that the
+//! opcodes 0x01-0x4e push onto the stack.
+
+mod asm;
+pub mod debugger;
+pub mod interpreter;
+pub mod opcodes;
+pub mod ops;
+pub mod templates;
+pub mod timelock;
+
+pub use templates::{m_of_n, op_return, p2pkh_script, p2sh_script, p2tr_script, p2wpkh_script, p2wsh_script, ScriptType};
+
+use crate::encoding::base58::encode_base58_checksum;
+use crate::encoding::util::encode_hex;
+use crate::encoding::varint::{encode_varint, read_varint_len};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{hash160, sha256};
+use crate::params::Network;
+use opcodes::{OpCode, MAX_DIRECT_PUSH};
+use std::fmt;
+use std::io::Read;
+use std::ops::Add;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// Any opcode that does not push data, OP_0 included
+    Op(u8),
+    /// An element pushed by a data push
+    Data(Vec<u8>),
+}
+
+impl Command {
+    /// The shortest push of `n`: OP_0 to OP_16, OP_1NEGATE, or its
+    /// minimal script number encoding.
+    pub fn num(n: i64) -> Command {
+        match n {
+            -1 => Command::Op(OpCode::OneNegate.to_u8()),
+            0..=16 => Command::Op(OpCode::from_small_int(n as u8).unwrap().to_u8()),
+            _ => Command::Data(ops::encode_num(n)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Script {
+    pub cmds: Vec<Command>,
+}
+
+impl Script {
+    pub fn new(cmds: Vec<Command>) -> Script {
+        Script { cmds }
+    }
+
+    /// Parses a script prefixed by its length, as in transactions.
+    pub fn parse<R: Read>(reader: &mut R) -> Result<Script> {
+        let len = read_varint_len(reader)?;
+        let mut raw = vec![0u8; len];
+        reader.read_exact(&mut raw)?;
+        Script::from_bytes(&raw)
+    }
+
+    /// Parses the script `raw`, without a length prefix.
+    pub fn from_bytes(raw: &[u8]) -> Result<Script> {
+        let mut cmds = Vec::new();
+        let mut rest = raw;
+        while let Some((&op, after)) = rest.split_first() {
+            rest = after;
+            let len = match OpCode::from_u8(op) {
+                None if (1..=MAX_DIRECT_PUSH).contains(&op) => op as usize,
+                Some(OpCode::PushData1) => take(&mut rest, 1)?[0] as usize,
+                Some(OpCode::PushData2) => {
+                    let bytes = take(&mut rest, 2)?;
+                    u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+                }
+                Some(OpCode::PushData4) => {
+                    let bytes = take(&mut rest, 4)?;
+                    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+                }
+                _ => {
+                    cmds.push(Command::Op(op));
+                    continue;
+                }
+            };
+            cmds.push(Command::Data(take(&mut rest, len)?.to_vec()));
+        }
+        Ok(Script { cmds })
+    }
+
+    /// The script without a length prefix. Data is pushed with the
+    /// shortest of a direct push and OP_PUSHDATA1/2/4 for its length.
+    pub fn raw_serialize(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        for cmd in self.cmds.iter() {
+            match cmd {
+                Command::Op(op) => result.push(*op),
+                Command::Data(data) => {
+                    let len = data.len();
+                    if len == 0 {
+                        result.push(OpCode::Zero.to_u8());
+                    } else if len <= MAX_DIRECT_PUSH as usize {
+                        result.push(len as u8);
+                    } else if len <= 0xff {
+                        result.extend_from_slice(&[OpCode::PushData1.to_u8(), len as u8]);
+                    } else if len <= 0xffff {
+                        result.push(OpCode::PushData2.to_u8());
+                        result.extend_from_slice(&(len as u16).to_le_bytes());
+                    } else {
+                        result.push(OpCode::PushData4.to_u8());
+                        result.extend_from_slice(&(len as u32).to_le_bytes());
+                    }
+                    result.extend_from_slice(data);
+                }
+            }
+        }
+        result
+    }
+
+    /// The script prefixed by its length.
+    pub fn serialize(&self) -> Vec<u8> {
+        let raw = self.raw_serialize();
+        let mut result = Vec::with_capacity(raw.len() + 9);
+        encode_varint(&mut result, raw.len() as u64).unwrap();
+        result.extend_from_slice(&raw);
+        result
+    }
+
+    /// Whether only data and small numbers are pushed, as BIP16 requires
+    /// of a script_sig spending P2SH.
+    pub fn is_push_only(&self) -> bool {
+        self.cmds.iter().all(|cmd| match cmd {
+            Command::Op(op) => *op <= OpCode::Sixteen.to_u8(),
+            Command::Data(_) => true,
+        })
+    }
+
+    /// Whether this is the BIP16 pattern OP_HASH160 <20 bytes> OP_EQUAL.
+    pub fn is_p2sh(&self) -> bool {
+        match self.cmds.as_slice() {
+            [Command::Op(hash), Command::Data(h160), Command::Op(equal)] => {
+                *hash == OpCode::Hash160.to_u8() && h160.len() == 20 && *equal == OpCode::Equal.to_u8()
+            }
+            _ => false,
+        }
+    }
+
+    /// The version and program of a BIP141 witness program: a version
+    /// opcode, OP_0 or OP_1 to OP_16, then a push of 2 to 40 bytes.
+    pub fn witness_program(&self) -> Option<(u8, &[u8])> {
+        match self.cmds.as_slice() {
+            [Command::Op(version), Command::Data(program)] if (2..=40).contains(&program.len()) => {
+                let version = OpCode::from_u8(*version)?.small_int()?;
+                if version < 0 {
+                    return None;
+                }
+                Some((version as u8, program))
+            }
+            _ => None,
+        }
+    }
+
+    /// The P2SH script_pubkey paying to this script as a redeem script.
+    pub fn p2sh_script_pubkey(&self) -> Script {
+        p2sh_script(&hash160(&self.raw_serialize()))
+    }
+
+    /// The P2WSH script_pubkey paying to this script as a witness script.
+    pub fn p2wsh_script_pubkey(&self) -> Script {
+        p2wsh_script(&sha256(&self.raw_serialize()))
+    }
+
+    /// The P2SH address of this script as a redeem script.
+    pub fn p2sh_address(&self, network: Network) -> String {
+        let mut payload = vec![network.p2sh_prefix()];
+        payload.extend_from_slice(&hash160(&self.raw_serialize()));
+        encode_base58_checksum(&payload)
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(Error::new(ErrorKind::InvalidEncoding,
+            format!("push of {} bytes with {} left in the script", len, bytes.len())));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+/// Concatenates the commands, as when script_sig and script_pubkey are
+/// evaluated together.
+impl Add for Script {
+    type Output = Script;
+
+    fn add(mut self, other: Script) -> Script {
+        self.cmds.extend(other.cmds);
+        self
+    }
+}
+
+/// Bitcoin Core style ASM: opcode names and data as hex.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, cmd) in self.cmds.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            match cmd {
+                Command::Op(op) => write!(f, "{}", opcodes::name(*op))?,
+                Command::Data(data) => write!(f, "{}", encode_hex(data))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[test]
+fn script_parse_and_serialize() {
+    // A P2PKH script_sig from chapter 6
+    let raw = decode_hex("6a47304402207899531a52d59a6de200179928ca900254a36b8dff8bb75f5f5d71b1cdc26125022008b422690b8461cb52c3cc30330b23d574351872b7c361e9aae3649071c1a7160121035d5c93d9ac96881f19ba1f686f15f009ded7c62efe85a872e6a19b43c15a2937").unwrap();
+    let script = Script::parse(&mut &raw[..]).unwrap();
+    assert_eq!(script.cmds.len(), 2);
+    assert_eq!(encode_hex(match &script.cmds[0] {
+        Command::Data(data) => data,
+        _ => panic!("expected data"),
+    }), "304402207899531a52d59a6de200179928ca900254a36b8dff8bb75f5f5d71b1cdc26125022008b422690b8461cb52c3cc30330b23d574351872b7c361e9aae3649071c1a71601");
+    assert_eq!(script.serialize(), raw);
+
+    let p2pkh = Script::from_bytes(&decode_hex("76a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac").unwrap()).unwrap();
+    assert_eq!(p2pkh.to_string(), "OP_DUP OP_HASH160 bc3b654dca7e56b04dca18f2566cdaf02e8d9ada OP_EQUALVERIFY OP_CHECKSIG");
+    let combined = script.clone() + p2pkh.clone();
+    assert_eq!(combined.cmds.len(), 7);
+    assert_eq!(combined.raw_serialize(), [script.raw_serialize(), p2pkh.raw_serialize()].concat());
+
+    // Each push uses the smallest opcode for its length
+    let pushes: [(usize, &[u8]); 6] =
+        [(0, &[0x00]), (75, &[0x4b]), (76, &[0x4c, 76]), (255, &[0x4c, 0xff]), (256, &[0x4d, 0x00, 0x01]), (0x1_0000, &[0x4e, 0, 0, 1, 0])];
+    for &(len, prefix) in pushes.iter() {
+        let raw = Script::new(vec![Command::Data(vec![7; len])]).raw_serialize();
+        assert_eq!(&raw[..prefix.len()], prefix, "push of {}", len);
+        assert_eq!(raw.len(), prefix.len() + len);
+        if len > 0 {
+            assert_eq!(Script::from_bytes(&raw).unwrap().cmds, vec![Command::Data(vec![7; len])]);
+        }
+    }
+    assert_eq!(Script::from_bytes(&[0x00, 0x4f, 0x51, 0xba, 0xfe]).unwrap().to_string(), "0 -1 1 OP_CHECKSIGADD OP_UNKNOWN");
+
+    assert_eq!(Script::from_bytes(&[0x02, 0x01]).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert!(Script::from_bytes(&[0x4c]).is_err());
+    assert!(Script::from_bytes(&[0x4d, 0x01]).is_err());
+}
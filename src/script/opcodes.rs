@@ -0,0 +1,219 @@
+//! Opcodes.
+//!
+//! Bytes 0x01-0x4b push that many bytes and are not opcodes of their own;
+//! neither are the undefined bytes 0xbb-0xfe, which fail when executed.
+
+/// Opcodes 0x01-0x4b push that many bytes
+pub const MAX_DIRECT_PUSH: u8 = 0x4b;
+
+macro_rules! opcodes {
+    ($($variant:ident = $byte:literal, $name:literal;)*) => {
+        /// Every defined opcode.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($variant = $byte,)*
+        }
+
+        impl OpCode {
+            /// The opcode `byte` stands for, if any.
+            pub fn from_u8(byte: u8) -> Option<OpCode> {
+                match byte {
+                    $($byte => Some(OpCode::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Bitcoin Core's name, with small integers written as numbers
+            /// like Core's ASM does.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Zero = 0x00, "0";
+    PushData1 = 0x4c, "OP_PUSHDATA1";
+    PushData2 = 0x4d, "OP_PUSHDATA2";
+    PushData4 = 0x4e, "OP_PUSHDATA4";
+    OneNegate = 0x4f, "-1";
+    Reserved = 0x50, "OP_RESERVED";
+    One = 0x51, "1";
+    Two = 0x52, "2";
+    Three = 0x53, "3";
+    Four = 0x54, "4";
+    Five = 0x55, "5";
+    Six = 0x56, "6";
+    Seven = 0x57, "7";
+    Eight = 0x58, "8";
+    Nine = 0x59, "9";
+    Ten = 0x5a, "10";
+    Eleven = 0x5b, "11";
+    Twelve = 0x5c, "12";
+    Thirteen = 0x5d, "13";
+    Fourteen = 0x5e, "14";
+    Fifteen = 0x5f, "15";
+    Sixteen = 0x60, "16";
+    Nop = 0x61, "OP_NOP";
+    Ver = 0x62, "OP_VER";
+    If = 0x63, "OP_IF";
+    NotIf = 0x64, "OP_NOTIF";
+    VerIf = 0x65, "OP_VERIF";
+    VerNotIf = 0x66, "OP_VERNOTIF";
+    Else = 0x67, "OP_ELSE";
+    EndIf = 0x68, "OP_ENDIF";
+    Verify = 0x69, "OP_VERIFY";
+    Return = 0x6a, "OP_RETURN";
+    ToAltStack = 0x6b, "OP_TOALTSTACK";
+    FromAltStack = 0x6c, "OP_FROMALTSTACK";
+    TwoDrop = 0x6d, "OP_2DROP";
+    TwoDup = 0x6e, "OP_2DUP";
+    ThreeDup = 0x6f, "OP_3DUP";
+    TwoOver = 0x70, "OP_2OVER";
+    TwoRot = 0x71, "OP_2ROT";
+    TwoSwap = 0x72, "OP_2SWAP";
+    IfDup = 0x73, "OP_IFDUP";
+    Depth = 0x74, "OP_DEPTH";
+    Drop = 0x75, "OP_DROP";
+    Dup = 0x76, "OP_DUP";
+    Nip = 0x77, "OP_NIP";
+    Over = 0x78, "OP_OVER";
+    Pick = 0x79, "OP_PICK";
+    Roll = 0x7a, "OP_ROLL";
+    Rot = 0x7b, "OP_ROT";
+    Swap = 0x7c, "OP_SWAP";
+    Tuck = 0x7d, "OP_TUCK";
+    Cat = 0x7e, "OP_CAT";
+    Substr = 0x7f, "OP_SUBSTR";
+    Left = 0x80, "OP_LEFT";
+    Right = 0x81, "OP_RIGHT";
+    Size = 0x82, "OP_SIZE";
+    Invert = 0x83, "OP_INVERT";
+    And = 0x84, "OP_AND";
+    Or = 0x85, "OP_OR";
+    Xor = 0x86, "OP_XOR";
+    Equal = 0x87, "OP_EQUAL";
+    EqualVerify = 0x88, "OP_EQUALVERIFY";
+    Reserved1 = 0x89, "OP_RESERVED1";
+    Reserved2 = 0x8a, "OP_RESERVED2";
+    OneAdd = 0x8b, "OP_1ADD";
+    OneSub = 0x8c, "OP_1SUB";
+    TwoMul = 0x8d, "OP_2MUL";
+    TwoDiv = 0x8e, "OP_2DIV";
+    Negate = 0x8f, "OP_NEGATE";
+    Abs = 0x90, "OP_ABS";
+    Not = 0x91, "OP_NOT";
+    ZeroNotEqual = 0x92, "OP_0NOTEQUAL";
+    Add = 0x93, "OP_ADD";
+    Sub = 0x94, "OP_SUB";
+    Mul = 0x95, "OP_MUL";
+    Div = 0x96, "OP_DIV";
+    Mod = 0x97, "OP_MOD";
+    LShift = 0x98, "OP_LSHIFT";
+    RShift = 0x99, "OP_RSHIFT";
+    BoolAnd = 0x9a, "OP_BOOLAND";
+    BoolOr = 0x9b, "OP_BOOLOR";
+    NumEqual = 0x9c, "OP_NUMEQUAL";
+    NumEqualVerify = 0x9d, "OP_NUMEQUALVERIFY";
+    NumNotEqual = 0x9e, "OP_NUMNOTEQUAL";
+    LessThan = 0x9f, "OP_LESSTHAN";
+    GreaterThan = 0xa0, "OP_GREATERTHAN";
+    LessThanOrEqual = 0xa1, "OP_LESSTHANOREQUAL";
+    GreaterThanOrEqual = 0xa2, "OP_GREATERTHANOREQUAL";
+    Min = 0xa3, "OP_MIN";
+    Max = 0xa4, "OP_MAX";
+    Within = 0xa5, "OP_WITHIN";
+    Ripemd160 = 0xa6, "OP_RIPEMD160";
+    Sha1 = 0xa7, "OP_SHA1";
+    Sha256 = 0xa8, "OP_SHA256";
+    Hash160 = 0xa9, "OP_HASH160";
+    Hash256 = 0xaa, "OP_HASH256";
+    CodeSeparator = 0xab, "OP_CODESEPARATOR";
+    CheckSig = 0xac, "OP_CHECKSIG";
+    CheckSigVerify = 0xad, "OP_CHECKSIGVERIFY";
+    CheckMultiSig = 0xae, "OP_CHECKMULTISIG";
+    CheckMultiSigVerify = 0xaf, "OP_CHECKMULTISIGVERIFY";
+    Nop1 = 0xb0, "OP_NOP1";
+    CheckLockTimeVerify = 0xb1, "OP_CHECKLOCKTIMEVERIFY";
+    CheckSequenceVerify = 0xb2, "OP_CHECKSEQUENCEVERIFY";
+    Nop4 = 0xb3, "OP_NOP4";
+    Nop5 = 0xb4, "OP_NOP5";
+    Nop6 = 0xb5, "OP_NOP6";
+    Nop7 = 0xb6, "OP_NOP7";
+    Nop8 = 0xb7, "OP_NOP8";
+    Nop9 = 0xb8, "OP_NOP9";
+    Nop10 = 0xb9, "OP_NOP10";
+    CheckSigAdd = 0xba, "OP_CHECKSIGADD";
+    InvalidOpCode = 0xff, "OP_INVALIDOPCODE";
+}
+
+impl OpCode {
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Opcodes that fail a script wherever they appear, even in a branch
+    /// not taken (CVE-2010-5137).
+    pub fn is_disabled(self) -> bool {
+        use OpCode::*;
+        matches!(
+            self,
+            Cat | Substr | Left | Right | Invert | And | Or | Xor | TwoMul | TwoDiv | Mul | Div | Mod | LShift | RShift
+        )
+    }
+
+    /// The number OP_0, OP_1NEGATE and OP_1 to OP_16 push.
+    pub fn small_int(self) -> Option<i64> {
+        match self {
+            OpCode::Zero => Some(0),
+            OpCode::OneNegate => Some(-1),
+            op if (OpCode::One..=OpCode::Sixteen).contains(&op) => Some((op as u8 - OpCode::One as u8 + 1) as i64),
+            _ => None,
+        }
+    }
+
+    /// OP_0 or OP_1 to OP_16 for `n` up to 16.
+    pub fn from_small_int(n: u8) -> Option<OpCode> {
+        match n {
+            0 => Some(OpCode::Zero),
+            1..=16 => OpCode::from_u8(OpCode::One as u8 + n - 1),
+            _ => None,
+        }
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(op: OpCode) -> u8 {
+        op as u8
+    }
+}
+
+/// Bitcoin Core's name for any byte that is not a direct push.
+pub fn name(byte: u8) -> &'static str {
+    OpCode::from_u8(byte).map_or("OP_UNKNOWN", OpCode::name)
+}
+
+#[test]
+fn opcode_bytes_and_names() {
+    let defined = (0..=255u8).filter_map(OpCode::from_u8).count();
+    // OP_0, 0x4c-0xba and OP_INVALIDOPCODE
+    assert_eq!(defined, 1 + (0xba - 0x4c + 1) + 1);
+    for byte in 0..=255u8 {
+        if let Some(op) = OpCode::from_u8(byte) {
+            assert_eq!(op.to_u8(), byte);
+        }
+    }
+    assert_eq!(OpCode::from_u8(0x76), Some(OpCode::Dup));
+    assert_eq!(OpCode::from_u8(0x14), None);
+    assert_eq!(OpCode::from_u8(0xbb), None);
+    assert_eq!(name(0xa9), "OP_HASH160");
+    assert_eq!(name(0xfe), "OP_UNKNOWN");
+    assert!(OpCode::Cat.is_disabled() && !OpCode::Equal.is_disabled());
+    assert_eq!(OpCode::Sixteen.small_int(), Some(16));
+    assert_eq!(OpCode::from_small_int(5), Some(OpCode::Five));
+    assert_eq!(OpCode::Dup.small_int(), None);
+}
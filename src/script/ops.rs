@@ -0,0 +1,536 @@
+//! What each opcode does to the stack.
+//!
+//! Handlers return `false` when the script fails: too few elements, a
+//! number that does not decode, or a VERIFY that does not hold. Opcodes
+//! that need more than the stacks, such as flow control and signature
+//! checks, have no handler here and are run by the interpreter itself.
+
+use super::opcodes::OpCode;
+use crate::hash::{hash160, hash256, sha1, sha256};
+use ripemd::{Digest, Ripemd160};
+
+pub type Stack = Vec<Vec<u8>>;
+
+/// Numbers popped for arithmetic are at most this many bytes.
+pub const MAX_NUM_SIZE: usize = 4;
+
+/// A handler and the stacks it works on.
+#[derive(Debug, Clone, Copy)]
+pub enum Handler {
+    Stack(fn(&mut Stack) -> bool),
+    /// The main stack then the altstack
+    AltStack(fn(&mut Stack, &mut Stack) -> bool),
+}
+
+/// The dispatch table: the handler for `op`, or `None` for opcodes the
+/// interpreter runs itself and for disabled ones.
+pub fn handler(op: OpCode) -> Option<Handler> {
+    use OpCode::*;
+    let stack_op: fn(&mut Stack) -> bool = match op {
+        Zero => op_0,
+        OneNegate => op_1negate,
+        One | Two | Three | Four | Five | Six | Seven | Eight | Nine | Ten | Eleven | Twelve | Thirteen | Fourteen
+        | Fifteen | Sixteen => return Some(Handler::Stack(SMALL_INTS[(op.to_u8() - One.to_u8()) as usize])),
+        Nop | Nop1 | Nop4 | Nop5 | Nop6 | Nop7 | Nop8 | Nop9 | Nop10 => op_nop,
+        Verify => op_verify,
+        Return => op_return,
+        ToAltStack => return Some(Handler::AltStack(op_toaltstack)),
+        FromAltStack => return Some(Handler::AltStack(op_fromaltstack)),
+        TwoDrop => op_2drop,
+        TwoDup => op_2dup,
+        ThreeDup => op_3dup,
+        TwoOver => op_2over,
+        TwoRot => op_2rot,
+        TwoSwap => op_2swap,
+        IfDup => op_ifdup,
+        Depth => op_depth,
+        Drop => op_drop,
+        Dup => op_dup,
+        Nip => op_nip,
+        Over => op_over,
+        Pick => op_pick,
+        Roll => op_roll,
+        Rot => op_rot,
+        Swap => op_swap,
+        Tuck => op_tuck,
+        Size => op_size,
+        Equal => op_equal,
+        EqualVerify => op_equalverify,
+        OneAdd => op_1add,
+        OneSub => op_1sub,
+        Negate => op_negate,
+        Abs => op_abs,
+        Not => op_not,
+        ZeroNotEqual => op_0notequal,
+        Add => op_add,
+        Sub => op_sub,
+        BoolAnd => op_booland,
+        BoolOr => op_boolor,
+        NumEqual => op_numequal,
+        NumEqualVerify => op_numequalverify,
+        NumNotEqual => op_numnotequal,
+        LessThan => op_lessthan,
+        GreaterThan => op_greaterthan,
+        LessThanOrEqual => op_lessthanorequal,
+        GreaterThanOrEqual => op_greaterthanorequal,
+        Min => op_min,
+        Max => op_max,
+        Within => op_within,
+        Ripemd160 => op_ripemd160,
+        Sha1 => op_sha1,
+        Sha256 => op_sha256,
+        Hash160 => op_hash160,
+        Hash256 => op_hash256,
+        _ => return None,
+    };
+    Some(Handler::Stack(stack_op))
+}
+
+/// Encodes `n` as a script number: little-endian magnitude, sign in the
+/// top bit of the last byte, zero as the empty element.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(&last) = result.last() {
+        if last & 0x80 != 0 {
+            result.push(if n < 0 { 0x80 } else { 0 });
+        } else if n < 0 {
+            *result.last_mut().unwrap() |= 0x80;
+        }
+    }
+    result
+}
+
+/// Decodes a script number of at most `MAX_NUM_SIZE` bytes.
+pub fn decode_num(element: &[u8]) -> Option<i64> {
+    decode_num_sized(element, MAX_NUM_SIZE)
+}
+
+/// Decodes a script number of at most `max_size` bytes, which the
+/// timelock opcodes allow to be 5.
+pub fn decode_num_sized(element: &[u8], max_size: usize) -> Option<i64> {
+    if element.len() > max_size {
+        return None;
+    }
+    let (&last, _) = match element.split_last() {
+        Some(split) => split,
+        None => return Some(0),
+    };
+    let magnitude = element.iter().rev().fold(0i64, |n, &b| n << 8 | b as i64) & !(0x80 << (8 * (element.len() - 1)));
+    Some(if last & 0x80 != 0 { -magnitude } else { magnitude })
+}
+
+/// False is any encoding of zero, negative zero included.
+pub fn cast_to_bool(element: &[u8]) -> bool {
+    match element.split_last() {
+        Some((&last, rest)) => rest.iter().any(|&b| b != 0) || (last != 0 && last != 0x80),
+        None => false,
+    }
+}
+
+fn pop_num(stack: &mut Stack) -> Option<i64> {
+    decode_num(&stack.pop()?)
+}
+
+fn push_bool(stack: &mut Stack, value: bool) {
+    stack.push(encode_num(value as i64));
+}
+
+const SMALL_INTS: [fn(&mut Stack) -> bool; 16] = [
+    |s| push_int(s, 1),
+    |s| push_int(s, 2),
+    |s| push_int(s, 3),
+    |s| push_int(s, 4),
+    |s| push_int(s, 5),
+    |s| push_int(s, 6),
+    |s| push_int(s, 7),
+    |s| push_int(s, 8),
+    |s| push_int(s, 9),
+    |s| push_int(s, 10),
+    |s| push_int(s, 11),
+    |s| push_int(s, 12),
+    |s| push_int(s, 13),
+    |s| push_int(s, 14),
+    |s| push_int(s, 15),
+    |s| push_int(s, 16),
+];
+
+fn push_int(stack: &mut Stack, n: i64) -> bool {
+    stack.push(encode_num(n));
+    true
+}
+
+fn op_0(stack: &mut Stack) -> bool {
+    push_int(stack, 0)
+}
+
+fn op_1negate(stack: &mut Stack) -> bool {
+    push_int(stack, -1)
+}
+
+fn op_nop(_stack: &mut Stack) -> bool {
+    true
+}
+
+fn op_verify(stack: &mut Stack) -> bool {
+    stack.pop().is_some_and(|top| cast_to_bool(&top))
+}
+
+fn op_return(_stack: &mut Stack) -> bool {
+    false
+}
+
+fn op_toaltstack(stack: &mut Stack, altstack: &mut Stack) -> bool {
+    match stack.pop() {
+        Some(top) => {
+            altstack.push(top);
+            true
+        }
+        None => false,
+    }
+}
+
+fn op_fromaltstack(stack: &mut Stack, altstack: &mut Stack) -> bool {
+    op_toaltstack(altstack, stack)
+}
+
+/// Copies the `n` elements ending `depth` from the top onto the top.
+fn copy_from(stack: &mut Stack, n: usize, depth: usize) -> bool {
+    if stack.len() < depth {
+        return false;
+    }
+    let start = stack.len() - depth;
+    let copied: Vec<Vec<u8>> = stack[start..start + n].to_vec();
+    stack.extend(copied);
+    true
+}
+
+/// Moves the `n` elements ending `depth` from the top onto the top.
+fn move_to_top(stack: &mut Stack, n: usize, depth: usize) -> bool {
+    if stack.len() < depth {
+        return false;
+    }
+    let start = stack.len() - depth;
+    let moved: Vec<Vec<u8>> = stack.drain(start..start + n).collect();
+    stack.extend(moved);
+    true
+}
+
+fn op_2drop(stack: &mut Stack) -> bool {
+    stack.len() >= 2 && {
+        stack.truncate(stack.len() - 2);
+        true
+    }
+}
+
+fn op_2dup(stack: &mut Stack) -> bool {
+    copy_from(stack, 2, 2)
+}
+
+fn op_3dup(stack: &mut Stack) -> bool {
+    copy_from(stack, 3, 3)
+}
+
+fn op_2over(stack: &mut Stack) -> bool {
+    copy_from(stack, 2, 4)
+}
+
+fn op_2rot(stack: &mut Stack) -> bool {
+    move_to_top(stack, 2, 6)
+}
+
+fn op_2swap(stack: &mut Stack) -> bool {
+    move_to_top(stack, 2, 4)
+}
+
+fn op_ifdup(stack: &mut Stack) -> bool {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => copy_from(stack, 1, 1),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+fn op_depth(stack: &mut Stack) -> bool {
+    push_int(stack, stack.len() as i64)
+}
+
+fn op_drop(stack: &mut Stack) -> bool {
+    stack.pop().is_some()
+}
+
+fn op_dup(stack: &mut Stack) -> bool {
+    copy_from(stack, 1, 1)
+}
+
+fn op_nip(stack: &mut Stack) -> bool {
+    stack.len() >= 2 && {
+        let at = stack.len() - 2;
+        stack.remove(at);
+        true
+    }
+}
+
+fn op_over(stack: &mut Stack) -> bool {
+    copy_from(stack, 1, 2)
+}
+
+/// The depth popped by OP_PICK and OP_ROLL, below the popped element.
+fn pop_depth(stack: &mut Stack) -> Option<usize> {
+    let n = pop_num(stack)?;
+    if n < 0 || n as usize >= stack.len() {
+        return None;
+    }
+    Some(n as usize + 1)
+}
+
+fn op_pick(stack: &mut Stack) -> bool {
+    match pop_depth(stack) {
+        Some(depth) => copy_from(stack, 1, depth),
+        None => false,
+    }
+}
+
+fn op_roll(stack: &mut Stack) -> bool {
+    match pop_depth(stack) {
+        Some(depth) => move_to_top(stack, 1, depth),
+        None => false,
+    }
+}
+
+fn op_rot(stack: &mut Stack) -> bool {
+    move_to_top(stack, 1, 3)
+}
+
+fn op_swap(stack: &mut Stack) -> bool {
+    move_to_top(stack, 1, 2)
+}
+
+fn op_tuck(stack: &mut Stack) -> bool {
+    stack.len() >= 2 && {
+        let top = stack[stack.len() - 1].clone();
+        let at = stack.len() - 2;
+        stack.insert(at, top);
+        true
+    }
+}
+
+fn op_size(stack: &mut Stack) -> bool {
+    match stack.last() {
+        Some(top) => push_int(stack, top.len() as i64),
+        None => false,
+    }
+}
+
+fn op_equal(stack: &mut Stack) -> bool {
+    match (stack.pop(), stack.pop()) {
+        (Some(a), Some(b)) => {
+            push_bool(stack, a == b);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn op_equalverify(stack: &mut Stack) -> bool {
+    op_equal(stack) && op_verify(stack)
+}
+
+fn unary(stack: &mut Stack, f: fn(i64) -> i64) -> bool {
+    match pop_num(stack) {
+        Some(n) => push_int(stack, f(n)),
+        None => false,
+    }
+}
+
+/// Pops b then a and pushes f(a, b).
+fn binary(stack: &mut Stack, f: fn(i64, i64) -> i64) -> bool {
+    match (pop_num(stack), pop_num(stack)) {
+        (Some(b), Some(a)) => push_int(stack, f(a, b)),
+        _ => false,
+    }
+}
+
+fn op_1add(stack: &mut Stack) -> bool {
+    unary(stack, |n| n + 1)
+}
+
+fn op_1sub(stack: &mut Stack) -> bool {
+    unary(stack, |n| n - 1)
+}
+
+fn op_negate(stack: &mut Stack) -> bool {
+    unary(stack, |n| -n)
+}
+
+fn op_abs(stack: &mut Stack) -> bool {
+    unary(stack, i64::abs)
+}
+
+fn op_not(stack: &mut Stack) -> bool {
+    unary(stack, |n| (n == 0) as i64)
+}
+
+fn op_0notequal(stack: &mut Stack) -> bool {
+    unary(stack, |n| (n != 0) as i64)
+}
+
+fn op_add(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| a + b)
+}
+
+fn op_sub(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| a - b)
+}
+
+fn op_booland(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a != 0 && b != 0) as i64)
+}
+
+fn op_boolor(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a != 0 || b != 0) as i64)
+}
+
+fn op_numequal(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a == b) as i64)
+}
+
+fn op_numequalverify(stack: &mut Stack) -> bool {
+    op_numequal(stack) && op_verify(stack)
+}
+
+fn op_numnotequal(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a != b) as i64)
+}
+
+fn op_lessthan(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a < b) as i64)
+}
+
+fn op_greaterthan(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a > b) as i64)
+}
+
+fn op_lessthanorequal(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a <= b) as i64)
+}
+
+fn op_greaterthanorequal(stack: &mut Stack) -> bool {
+    binary(stack, |a, b| (a >= b) as i64)
+}
+
+fn op_min(stack: &mut Stack) -> bool {
+    binary(stack, i64::min)
+}
+
+fn op_max(stack: &mut Stack) -> bool {
+    binary(stack, i64::max)
+}
+
+/// x min max: whether min <= x < max.
+fn op_within(stack: &mut Stack) -> bool {
+    match (pop_num(stack), pop_num(stack), pop_num(stack)) {
+        (Some(max), Some(min), Some(x)) => {
+            push_bool(stack, min <= x && x < max);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn hash_top(stack: &mut Stack, f: fn(&[u8]) -> Vec<u8>) -> bool {
+    match stack.pop() {
+        Some(top) => {
+            stack.push(f(&top));
+            true
+        }
+        None => false,
+    }
+}
+
+fn op_ripemd160(stack: &mut Stack) -> bool {
+    hash_top(stack, |data| Ripemd160::digest(data).to_vec())
+}
+
+fn op_sha1(stack: &mut Stack) -> bool {
+    hash_top(stack, |data| sha1(data).to_vec())
+}
+
+fn op_sha256(stack: &mut Stack) -> bool {
+    hash_top(stack, |data| sha256(data).to_vec())
+}
+
+fn op_hash160(stack: &mut Stack) -> bool {
+    hash_top(stack, |data| hash160(data).to_vec())
+}
+
+fn op_hash256(stack: &mut Stack) -> bool {
+    hash_top(stack, |data| hash256(data).to_vec())
+}
+
+#[cfg(test)]
+fn run(ops: &[OpCode], stack: &mut Stack) -> bool {
+    let mut altstack = Stack::new();
+    ops.iter().all(|&op| match handler(op) {
+        Some(Handler::Stack(f)) => f(stack),
+        Some(Handler::AltStack(f)) => f(stack, &mut altstack),
+        None => panic!("no handler for {:?}", op),
+    })
+}
+
+#[test]
+fn script_numbers() {
+    let cases: [(i64, &[u8]); 8] = [
+        (0, &[]),
+        (1, &[1]),
+        (-1, &[0x81]),
+        (127, &[0x7f]),
+        (128, &[0x80, 0]),
+        (-128, &[0x80, 0x80]),
+        (-255, &[0xff, 0x80]),
+        (0x7fff_ffff, &[0xff, 0xff, 0xff, 0x7f]),
+    ];
+    for &(n, bytes) in cases.iter() {
+        assert_eq!(encode_num(n), bytes, "{}", n);
+        assert_eq!(decode_num(bytes), Some(n));
+    }
+    assert_eq!(decode_num(&[0, 0, 0, 0, 1]), None);
+    assert!(!cast_to_bool(&[]) && !cast_to_bool(&[0, 0]) && !cast_to_bool(&[0, 0x80]));
+    assert!(cast_to_bool(&[0x80, 0]) && cast_to_bool(&[1]));
+}
+
+#[test]
+fn stack_handlers() {
+    use OpCode::*;
+    let mut stack = Stack::new();
+    assert!(run(&[Two, Three, Add, Five, NumEqualVerify, One], &mut stack));
+    assert_eq!(stack, vec![vec![1]]);
+
+    let mut stack: Stack = vec![vec![1], vec![2], vec![3]];
+    assert!(run(&[Rot], &mut stack));
+    assert_eq!(stack, vec![vec![2], vec![3], vec![1]]);
+    assert!(run(&[Two, Roll], &mut stack));
+    assert_eq!(stack, vec![vec![3], vec![1], vec![2]]);
+    assert!(run(&[Tuck, TwoSwap, Depth], &mut stack));
+    assert_eq!(stack, vec![vec![1], vec![2], vec![3], vec![2], vec![4]]);
+    assert!(run(&[ToAltStack, TwoDrop, TwoDrop, FromAltStack], &mut stack));
+    assert_eq!(stack, vec![vec![4]]);
+    assert!(!run(&[Pick], &mut stack));
+
+    let mut stack: Stack = vec![b"".to_vec()];
+    assert!(run(&[Sha1], &mut stack));
+    assert_eq!(crate::encoding::util::encode_hex(&stack[0]), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+
+    let mut stack = Stack::new();
+    assert!(run(&[Five, One, Ten, Within, Verify, Five, Six, Ten, Within], &mut stack));
+    assert_eq!(stack, vec![Vec::<u8>::new()]);
+
+    let mut stack = Stack::new();
+    assert!(!run(&[Dup], &mut stack));
+    assert!(!run(&[Zero, Verify], &mut stack));
+    assert!(handler(Cat).is_none() && handler(If).is_none() && handler(CheckSig).is_none());
+}
@@ -0,0 +1,196 @@
+//! Standard output scripts: building them and recognizing them.
+
+use super::opcodes::OpCode;
+use super::{Command, Script};
+use crate::error::{Error, ErrorKind, Result};
+use crate::math::ecc::S256Point;
+use std::convert::TryInto;
+
+/// The kind of a script_pubkey, with what it pays to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ScriptType {
+    /// `<sec> OP_CHECKSIG`
+    P2pk(Vec<u8>),
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    /// An x-only output key
+    P2tr([u8; 32]),
+    /// Bare `OP_m <sec>... OP_n OP_CHECKMULTISIG`
+    Multisig { m: u8, pubkeys: Vec<Vec<u8>> },
+    /// OP_RETURN followed by pushes, provably unspendable
+    NullData,
+    /// A witness program of a version or length not defined yet
+    WitnessUnknown { version: u8, program: Vec<u8> },
+    NonStandard,
+}
+
+fn op(op: OpCode) -> Command {
+    Command::Op(op.to_u8())
+}
+
+/// `OP_DUP OP_HASH160 <h160> OP_EQUALVERIFY OP_CHECKSIG`
+pub fn p2pkh_script(h160: &[u8; 20]) -> Script {
+    Script::new(vec![
+        op(OpCode::Dup),
+        op(OpCode::Hash160),
+        Command::Data(h160.to_vec()),
+        op(OpCode::EqualVerify),
+        op(OpCode::CheckSig),
+    ])
+}
+
+/// `OP_HASH160 <h160> OP_EQUAL`, paying to the hash160 of a redeem script.
+pub fn p2sh_script(h160: &[u8; 20]) -> Script {
+    Script::new(vec![op(OpCode::Hash160), Command::Data(h160.to_vec()), op(OpCode::Equal)])
+}
+
+/// `OP_0 <h160>`, paying to the hash160 of a compressed key.
+pub fn p2wpkh_script(h160: &[u8; 20]) -> Script {
+    Script::new(vec![op(OpCode::Zero), Command::Data(h160.to_vec())])
+}
+
+/// `OP_0 <sha256>`, paying to the sha256 of a witness script.
+pub fn p2wsh_script(sha256: &[u8; 32]) -> Script {
+    Script::new(vec![op(OpCode::Zero), Command::Data(sha256.to_vec())])
+}
+
+/// `OP_1 <output key>`, paying to an x-only output key.
+pub fn p2tr_script(output_key: &[u8; 32]) -> Script {
+    Script::new(vec![op(OpCode::One), Command::Data(output_key.to_vec())])
+}
+
+/// `OP_RETURN <data>`, an unspendable output carrying `data`.
+pub fn op_return(data: &[u8]) -> Script {
+    Script::new(vec![op(OpCode::Return), Command::Data(data.to_vec())])
+}
+
+/// `OP_m <sec>... OP_n OP_CHECKMULTISIG` with compressed keys, for up to
+/// 16 keys.
+pub fn m_of_n(m: u8, pubkeys: &[S256Point]) -> Result<Script> {
+    let n = pubkeys.len();
+    if m == 0 || m as usize > n || n > 16 {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("{} of {} multisig", m, n)));
+    }
+    let mut cmds = vec![op(OpCode::from_small_int(m).unwrap())];
+    cmds.extend(pubkeys.iter().map(|key| Command::Data(key.sec(true))));
+    cmds.push(op(OpCode::from_small_int(n as u8).unwrap()));
+    cmds.push(op(OpCode::CheckMultiSig));
+    Ok(Script::new(cmds))
+}
+
+/// The size and prefix of a compressed or uncompressed SEC key.
+fn is_sec(data: &[u8]) -> bool {
+    match data.first() {
+        Some(2) | Some(3) => data.len() == 33,
+        Some(4) => data.len() == 65,
+        _ => false,
+    }
+}
+
+/// The number pushed by an OP_1 to OP_16 command.
+fn small_int(cmd: &Command) -> Option<u8> {
+    match cmd {
+        Command::Op(byte) => match OpCode::from_u8(*byte)?.small_int()? {
+            n @ 1..=16 => Some(n as u8),
+            _ => None,
+        },
+        Command::Data(_) => None,
+    }
+}
+
+impl Script {
+    /// Which standard template this script_pubkey follows.
+    pub fn classify(&self) -> ScriptType {
+        if let Some((version, program)) = self.witness_program() {
+            return match (version, program.len()) {
+                (0, 20) => ScriptType::P2wpkh(program.try_into().unwrap()),
+                (0, 32) => ScriptType::P2wsh(program.try_into().unwrap()),
+                (1, 32) => ScriptType::P2tr(program.try_into().unwrap()),
+                // Undefined v0 lengths can never be spent
+                (0, _) => ScriptType::NonStandard,
+                _ => ScriptType::WitnessUnknown { version, program: program.to_vec() },
+            };
+        }
+        if self.is_p2sh() {
+            if let Command::Data(h160) = &self.cmds[1] {
+                return ScriptType::P2sh(h160.as_slice().try_into().unwrap());
+            }
+        }
+        let is = |cmd: &Command, expected: OpCode| *cmd == op(expected);
+        match self.cmds.as_slice() {
+            [dup, hash, Command::Data(h160), equal_verify, check_sig]
+                if h160.len() == 20
+                    && is(dup, OpCode::Dup)
+                    && is(hash, OpCode::Hash160)
+                    && is(equal_verify, OpCode::EqualVerify)
+                    && is(check_sig, OpCode::CheckSig) =>
+            {
+                ScriptType::P2pkh(h160.as_slice().try_into().unwrap())
+            }
+            [Command::Data(sec), check_sig] if is_sec(sec) && is(check_sig, OpCode::CheckSig) => ScriptType::P2pk(sec.clone()),
+            [first, rest @ ..] if is(first, OpCode::Return) && Script::new(rest.to_vec()).is_push_only() => ScriptType::NullData,
+            [first, keys @ .., last, check_multisig] if is(check_multisig, OpCode::CheckMultiSig) => {
+                let pubkeys: Vec<Vec<u8>> = keys
+                    .iter()
+                    .filter_map(|cmd| match cmd {
+                        Command::Data(sec) if is_sec(sec) => Some(sec.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                match (small_int(first), small_int(last)) {
+                    (Some(m), Some(n)) if pubkeys.len() == keys.len() && m <= n && n as usize == keys.len() => {
+                        ScriptType::Multisig { m, pubkeys }
+                    }
+                    _ => ScriptType::NonStandard,
+                }
+            }
+            _ => ScriptType::NonStandard,
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn build_and_classify() {
+    let h160 = [0x11; 20];
+    let h256 = [0x22; 32];
+    // Byte for byte what the transaction module builds
+    assert_eq!(p2pkh_script(&h160).raw_serialize(), crate::tx::p2pkh_script(&h160));
+    assert_eq!(p2wpkh_script(&h160).raw_serialize(), crate::tx::p2wpkh_script(&h160));
+    assert_eq!(p2wsh_script(&h256).raw_serialize(), crate::tx::p2wsh_script(&h256));
+    assert_eq!(p2tr_script(&h256).raw_serialize(), crate::tx::p2tr_script(&h256));
+
+    assert_eq!(p2pkh_script(&h160).classify(), ScriptType::P2pkh(h160));
+    assert_eq!(p2sh_script(&h160).classify(), ScriptType::P2sh(h160));
+    assert_eq!(p2wpkh_script(&h160).classify(), ScriptType::P2wpkh(h160));
+    assert_eq!(p2wsh_script(&h256).classify(), ScriptType::P2wsh(h256));
+    assert_eq!(p2tr_script(&h256).classify(), ScriptType::P2tr(h256));
+    assert_eq!(op_return(b"hello").classify(), ScriptType::NullData);
+    assert_eq!(Script::new(vec![op(OpCode::Return)]).classify(), ScriptType::NullData);
+    let v2 = Script::new(vec![op(OpCode::Two), Command::Data(vec![0; 2])]);
+    assert_eq!(v2.classify(), ScriptType::WitnessUnknown { version: 2, program: vec![0; 2] });
+    assert_eq!(Script::new(vec![op(OpCode::Zero), Command::Data(vec![0; 21])]).classify(), ScriptType::NonStandard);
+
+    // The 2-of-2 redeem script from chapter 8
+    let redeem = Script::from_bytes(&decode_hex("5221022626e955ea6ea6d98850c994f9107b036b1334f18ca8830bfff1295d21cfdb702103b287eaf122eea69030a0e9feed096bed8045c8b98bec453e1ffac7fbdbd4bb7152ae").unwrap()).unwrap();
+    match redeem.classify() {
+        ScriptType::Multisig { m: 2, pubkeys } => {
+            let keys: Vec<S256Point> = pubkeys.iter().map(|sec| S256Point::parse_sec(sec).unwrap()).collect();
+            assert_eq!(m_of_n(2, &keys).unwrap(), redeem);
+        }
+        other => panic!("expected 2-of-2 multisig, got {:?}", other),
+    }
+    let key = S256Point::generator().scalar_mul(&BigInt::from(5));
+    assert_eq!(Script::new(vec![Command::Data(key.sec(false)), op(OpCode::CheckSig)]).classify(), ScriptType::P2pk(key.sec(false)));
+    let keys = vec![key; 17];
+    assert!(m_of_n(0, &keys[..1]).is_err());
+    assert!(m_of_n(2, &keys[..1]).is_err());
+    assert!(m_of_n(1, &keys).is_err());
+    assert_eq!(Script::new(vec![op(OpCode::Dup)]).classify(), ScriptType::NonStandard);
+}
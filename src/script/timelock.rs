@@ -0,0 +1,157 @@
+//! Time-locked contracts using OP_CHECKLOCKTIMEVERIFY (BIP65) and
+//! OP_CHECKSEQUENCEVERIFY (BIP112).
+//!
+//! The builders return the redeem or witness script; pay to it with
+//! `p2sh_script_pubkey` or `p2wsh_script_pubkey`. The spend helpers return
+//! the stack the script expects, bottom first, which `witness` and
+//! `script_sig` finish for P2WSH and P2SH. Signatures are DER with the
+//! sighash byte. A spend through a timelock also needs the transaction's
+//! locktime, or the input's sequence, set to at least the lock.
+
+use super::opcodes::OpCode;
+use super::{Command, Script};
+use crate::math::ecc::S256Point;
+use crate::tx::{LockTime, Sequence};
+
+fn op(op: OpCode) -> Command {
+    Command::Op(op.to_u8())
+}
+
+fn key(pubkey: &S256Point) -> Command {
+    Command::Data(pubkey.sec(true))
+}
+
+fn push_lock_time(lock_time: LockTime) -> Command {
+    Command::num(lock_time.to_consensus_u32() as i64)
+}
+
+/// `<lock_time> OP_CHECKLOCKTIMEVERIFY OP_DROP <pubkey> OP_CHECKSIG`:
+/// coins `pubkey` can only spend once `lock_time` has passed.
+pub fn hodl_until(lock_time: LockTime, pubkey: &S256Point) -> Script {
+    Script::new(vec![push_lock_time(lock_time), op(OpCode::CheckLockTimeVerify), op(OpCode::Drop), key(pubkey), op(OpCode::CheckSig)])
+}
+
+/// Spends `hodl_until` with the key's signature.
+pub fn hodl_until_spend(sig: &[u8]) -> Vec<Vec<u8>> {
+    vec![sig.to_vec()]
+}
+
+/// A hashed timelock contract: `receiver` takes the coins by revealing the
+/// preimage of `payment_hash` (a SHA256), or `sender` takes them back once
+/// `timeout` has passed.
+///
+/// `OP_IF OP_SHA256 <payment_hash> OP_EQUALVERIFY <receiver> OP_ELSE
+/// <timeout> OP_CHECKLOCKTIMEVERIFY OP_DROP <sender> OP_ENDIF OP_CHECKSIG`
+pub fn htlc(payment_hash: &[u8; 32], receiver: &S256Point, sender: &S256Point, timeout: LockTime) -> Script {
+    Script::new(vec![
+        op(OpCode::If),
+        op(OpCode::Sha256),
+        Command::Data(payment_hash.to_vec()),
+        op(OpCode::EqualVerify),
+        key(receiver),
+        op(OpCode::Else),
+        push_lock_time(timeout),
+        op(OpCode::CheckLockTimeVerify),
+        op(OpCode::Drop),
+        key(sender),
+        op(OpCode::EndIf),
+        op(OpCode::CheckSig),
+    ])
+}
+
+/// The receiver's spend of `htlc`.
+pub fn htlc_claim(sig: &[u8], preimage: &[u8]) -> Vec<Vec<u8>> {
+    vec![sig.to_vec(), preimage.to_vec(), vec![1]]
+}
+
+/// The sender's spend of `htlc` after the timeout.
+pub fn htlc_refund(sig: &[u8]) -> Vec<Vec<u8>> {
+    vec![sig.to_vec(), vec![]]
+}
+
+/// A 2-of-2 between `keys` that `escape_key` alone can spend once the coin
+/// is `delay` old.
+///
+/// `OP_IF OP_2 <key 1> <key 2> OP_2 OP_CHECKMULTISIG OP_ELSE <delay>
+/// OP_CHECKSEQUENCEVERIFY OP_DROP <escape_key> OP_CHECKSIG OP_ENDIF`
+pub fn csv_escape(keys: &[S256Point; 2], escape_key: &S256Point, delay: Sequence) -> Script {
+    Script::new(vec![
+        op(OpCode::If),
+        op(OpCode::Two),
+        key(&keys[0]),
+        key(&keys[1]),
+        op(OpCode::Two),
+        op(OpCode::CheckMultiSig),
+        op(OpCode::Else),
+        Command::num(delay.to_consensus_u32() as i64),
+        op(OpCode::CheckSequenceVerify),
+        op(OpCode::Drop),
+        key(escape_key),
+        op(OpCode::CheckSig),
+        op(OpCode::EndIf),
+    ])
+}
+
+/// Spends `csv_escape` with both keys' signatures, in key order.
+pub fn csv_escape_cooperative(sigs: &[&[u8]; 2]) -> Vec<Vec<u8>> {
+    // OP_CHECKMULTISIG pops an extra element
+    vec![vec![], sigs[0].to_vec(), sigs[1].to_vec(), vec![1]]
+}
+
+/// Spends `csv_escape` with the escape key once the delay has passed.
+pub fn csv_escape_timeout(sig: &[u8]) -> Vec<Vec<u8>> {
+    vec![sig.to_vec(), vec![]]
+}
+
+/// The P2WSH witness: the stack followed by the witness script.
+pub fn witness(stack: Vec<Vec<u8>>, witness_script: &Script) -> Vec<Vec<u8>> {
+    let mut witness = stack;
+    witness.push(witness_script.raw_serialize());
+    witness
+}
+
+/// The P2SH script_sig: pushes of the stack and the redeem script.
+pub fn script_sig(stack: Vec<Vec<u8>>, redeem_script: &Script) -> Script {
+    let mut cmds: Vec<Command> = stack.into_iter().map(Command::Data).collect();
+    cmds.push(Command::Data(redeem_script.raw_serialize()));
+    Script::new(cmds)
+}
+
+#[cfg(test)]
+use super::interpreter::{verify_script, VerifyFlags};
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn timelock_contracts() {
+    let keys: Vec<PrivateKey> = (1..=3).map(|n| PrivateKey::new(&BigInt::from(n)).unwrap()).collect();
+    let z = BigInt::from(0xc0ffee);
+    let sign = |key: &PrivateKey| {
+        let mut sig = key.sign(&z).der();
+        sig.push(1);
+        sig
+    };
+    let hodl = hodl_until(LockTime::from_height(800_000).unwrap(), keys[0].public_key());
+    assert_eq!(hodl.raw_serialize()[..5], [0x03, 0x00, 0x35, 0x0c, 0xb1]);
+    assert_eq!(hodl_until_spend(&sign(&keys[0])).len(), 1);
+
+    // The hash branch runs without the transaction the timelock would need
+    let preimage = b"the secret";
+    let contract = htlc(&crate::hash::sha256(preimage), keys[0].public_key(), keys[1].public_key(), LockTime::from_height(100).unwrap());
+    let claim = htlc_claim(&sign(&keys[0]), preimage);
+    assert!(verify_script(&Script::default(), &contract.p2wsh_script_pubkey(), &witness(claim.clone(), &contract), &z, VerifyFlags::STANDARD).unwrap().valid);
+    let flags = VerifyFlags::STANDARD.without(VerifyFlags::WITNESS);
+    assert!(verify_script(&script_sig(claim, &contract), &contract.p2sh_script_pubkey(), &[], &z, flags).unwrap().valid);
+    let wrong = htlc_claim(&sign(&keys[0]), b"a guess");
+    assert!(!verify_script(&Script::default(), &contract.p2wsh_script_pubkey(), &witness(wrong, &contract), &z, VerifyFlags::STANDARD).unwrap().valid);
+    assert_eq!(htlc_refund(&sign(&keys[1]))[1], Vec::<u8>::new());
+
+    let escape = csv_escape(&[keys[0].public_key().clone(), keys[1].public_key().clone()], keys[2].public_key(), Sequence::from_height(144));
+    let both = csv_escape_cooperative(&[&sign(&keys[0]), &sign(&keys[1])]);
+    assert!(verify_script(&Script::default(), &escape.p2wsh_script_pubkey(), &witness(both, &escape), &z, VerifyFlags::STANDARD).unwrap().valid);
+    let swapped = csv_escape_cooperative(&[&sign(&keys[1]), &sign(&keys[0])]);
+    assert!(!verify_script(&Script::default(), &escape.p2wsh_script_pubkey(), &witness(swapped, &escape), &z, VerifyFlags::STANDARD).unwrap().valid);
+    assert_eq!(escape.cmds[7], Command::Data(vec![0x90, 0x00]));
+}
@@ -0,0 +1,163 @@
+//! Showing amounts, in bitcoin or satoshis and in a fiat currency.
+//!
+//! The crate fetches no prices: a `RateProvider` the caller implements
+//! says what a bitcoin is worth, from an exchange's API, a local price
+//! feed or, as with `FixedRates`, numbers the user typed in. Fiat values
+//! are worked out in the currency's minor unit, rounding half up.
+
+use crate::error::{Error, ErrorKind, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+const SATS_PER_BTC: u64 = 100_000_000;
+
+/// An amount of satoshis, displayed as bitcoin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Amount(pub u64);
+
+impl Amount {
+    pub fn from_sat(sats: u64) -> Amount {
+        Amount(sats)
+    }
+
+    pub fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    /// The amount in `options.unit`, followed by its value in the fiat
+    /// currency if one is asked for.
+    pub fn format(self, options: &FormatOptions) -> Result<String> {
+        let mut formatted = match options.unit {
+            Unit::Btc => self.to_string(),
+            Unit::Sat => format!("{} sat", self.0),
+        };
+        if let Some((provider, currency)) = options.fiat {
+            formatted.push_str(&format!(" ({})", provider.rate(currency)?.value(self)));
+        }
+        Ok(formatted)
+    }
+}
+
+/// With eight decimals, like bitcoind.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:08} BTC", self.0 / SATS_PER_BTC, self.0 % SATS_PER_BTC)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Btc,
+    Sat,
+}
+
+/// How `Amount::format` shows an amount.
+#[derive(Clone, Copy)]
+pub struct FormatOptions<'a> {
+    pub unit: Unit,
+    /// Also in this currency, at the provider's rate
+    pub fiat: Option<(&'a dyn RateProvider, &'a str)>,
+}
+
+impl<'a> FormatOptions<'a> {
+    pub fn new(unit: Unit) -> FormatOptions<'a> {
+        FormatOptions { unit, fiat: None }
+    }
+
+    pub fn fiat(mut self, provider: &'a dyn RateProvider, currency: &'a str) -> FormatOptions<'a> {
+        self.fiat = Some((provider, currency));
+        self
+    }
+}
+
+/// What one bitcoin is worth in a currency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FiatRate {
+    /// Its code, say "USD"
+    pub currency: String,
+    /// Digits after the decimal point of the currency's minor unit
+    pub decimals: u8,
+    /// The price of a bitcoin in the minor unit
+    pub per_btc: u64,
+}
+
+impl FiatRate {
+    /// Parses "USD=65432.10": the price of a bitcoin, with as many
+    /// decimals as it's written with.
+    pub fn parse(s: &str) -> Result<FiatRate> {
+        let invalid = || Error::new(ErrorKind::InvalidEncoding, format!("{} is not CURRENCY=PRICE", s));
+        let (currency, price) = s.split_once('=').filter(|(currency, _)| !currency.is_empty()).ok_or_else(invalid)?;
+        let (whole, fraction) = price.split_once('.').unwrap_or((price, ""));
+        let digits = format!("{}{}", whole, fraction);
+        if whole.is_empty() || fraction.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let per_btc = digits.parse().map_err(|_| Error::new(ErrorKind::OutOfRange, format!("price in {} too large", s)))?;
+        Ok(FiatRate { currency: currency.to_uppercase(), decimals: fraction.len() as u8, per_btc })
+    }
+
+    /// What `amount` is worth, say "65.43 USD".
+    pub fn value(&self, amount: Amount) -> String {
+        let sats = SATS_PER_BTC as u128;
+        let minor = (amount.0 as u128 * self.per_btc as u128 + sats / 2) / sats;
+        let scale = 10u128.pow(self.decimals as u32);
+        if self.decimals == 0 {
+            return format!("{} {}", minor, self.currency);
+        }
+        format!("{}.{:0width$} {}", minor / scale, minor % scale, self.currency, width = self.decimals as usize)
+    }
+}
+
+/// Where fiat rates come from.
+pub trait RateProvider {
+    /// What a bitcoin is worth in `currency`, or `ErrorKind::Fetch` if the
+    /// provider can't say.
+    fn rate(&self, currency: &str) -> Result<FiatRate>;
+}
+
+/// Rates set by hand, for offline use and tests.
+#[derive(Debug, Clone, Default)]
+pub struct FixedRates {
+    rates: HashMap<String, FiatRate>,
+}
+
+impl FixedRates {
+    pub fn new() -> FixedRates {
+        FixedRates::default()
+    }
+
+    pub fn insert(&mut self, rate: FiatRate) {
+        self.rates.insert(rate.currency.clone(), rate);
+    }
+}
+
+impl RateProvider for FixedRates {
+    fn rate(&self, currency: &str) -> Result<FiatRate> {
+        self.rates.get(&currency.to_uppercase()).cloned().ok_or_else(|| Error::new(ErrorKind::Fetch, format!("no {} rate", currency)))
+    }
+}
+
+#[test]
+fn amount_formatting() {
+    let amount = Amount::from_sat(123_456_789);
+    assert_eq!(amount.to_string(), "1.23456789 BTC");
+    assert_eq!(Amount(5_000).to_string(), "0.00005000 BTC");
+    assert_eq!(amount.format(&FormatOptions::new(Unit::Sat)).unwrap(), "123456789 sat");
+
+    let mut rates = FixedRates::new();
+    rates.insert(FiatRate::parse("usd=65432.10").unwrap());
+    rates.insert(FiatRate::parse("JPY=9876543").unwrap());
+    let options = FormatOptions::new(Unit::Btc).fiat(&rates, "USD");
+    assert_eq!(amount.format(&options).unwrap(), "1.23456789 BTC (80780.37 USD)");
+    assert_eq!(Amount(100_000).format(&FormatOptions::new(Unit::Sat).fiat(&rates, "jpy")).unwrap(), "100000 sat (9877 JPY)");
+    // Half a cent rounds up
+    let rate = FiatRate { currency: "USD".to_string(), decimals: 2, per_btc: 100 };
+    assert_eq!((rate.value(Amount(500_000)), rate.value(Amount(499_999))), ("0.01 USD".to_string(), "0.00 USD".to_string()));
+    assert_eq!(rate.value(Amount(u64::MAX)), "184467440737.10 USD");
+
+    assert_eq!(amount.format(&FormatOptions::new(Unit::Btc).fiat(&rates, "EUR")).unwrap_err().kind(), ErrorKind::Fetch);
+    for bad in ["USD", "=5", "USD=", "USD=.5", "USD=1.2.3", "USD=-5", "USD=1.123456789"].iter() {
+        assert_eq!(FiatRate::parse(bad).unwrap_err().kind(), ErrorKind::InvalidEncoding, "{}", bad);
+    }
+    assert_eq!(FiatRate::parse("USD=99999999999999999999").unwrap_err().kind(), ErrorKind::OutOfRange);
+}
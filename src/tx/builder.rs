@@ -0,0 +1,540 @@
+//! Building unsigned transactions from a wallet's coins.
+//!
+//! `TxBuilder` takes the spendable outputs, the payments to make, a fee
+//! rate and where change goes, then picks coins with a `CoinSelection`
+//! strategy, sizes the fee for the signed transaction and adds change when
+//! it is worth more than dust.
+//!
+//! In batch mode, for paying many recipients at once, payments to the same
+//! script merge into one output and the outputs are shuffled, so their
+//! order doesn't give away which one is change. `build_with_costs` reports
+//! what each recipient's payment added to the fee.
+//!
+//! `drain_to` sweeps every coin to one script, as when moving a wallet.
+
+use super::coin_selection::{fee_for_weight, CoinSelection, LargestFirst, SpendAll};
+use super::psbt::Psbt;
+use super::sign::{classify, Spend};
+use super::{LockTime, Sequence, Tx, TxIn, TxOut};
+use crate::encoding::varint::encode_varint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::sha256;
+use crate::params::Network;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+// Bitcoin Core's default dust relay fee, in sat/kvB
+const DUST_RELAY_FEE: u64 = 3_000;
+const OP_RETURN: u8 = 0x6a;
+
+// Weight units a signature adds to each input type. Signatures are taken at
+// their largest, 72 DER bytes plus the hash type byte.
+const P2PKH_SATISFACTION_WEIGHT: usize = 4 * (1 + 73 + 1 + 33);
+// Item count, then each item with its length
+const P2WPKH_SATISFACTION_WEIGHT: usize = 1 + 1 + 73 + 1 + 33;
+const P2TR_KEY_SATISFACTION_WEIGHT: usize = 1 + 1 + 64;
+// Outpoint, empty script_sig and sequence
+const INPUT_BASE_SIZE: usize = 32 + 4 + 1 + 4;
+
+/// An output the wallet can spend.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Utxo {
+    /// Id of the transaction holding the output, in display order
+    pub prev_tx: [u8; 32],
+    pub prev_index: u32,
+    pub output: TxOut,
+    /// Weight units signing adds, for outputs whose script_pubkey doesn't
+    /// say how they're spent, like P2SH
+    pub satisfaction: Option<usize>,
+}
+
+impl Utxo {
+    pub fn new(prev_tx: [u8; 32], prev_index: u32, output: TxOut) -> Utxo {
+        Utxo { prev_tx, prev_index, output, satisfaction: None }
+    }
+
+    /// The output with the weight units signing adds to its input: the
+    /// script_sig bytes, beyond the one counting them, times 4 plus the
+    /// witness bytes.
+    pub fn with_satisfaction_weight(mut self, weight: usize) -> Utxo {
+        self.satisfaction = Some(weight);
+        self
+    }
+
+    /// Weight units that signing adds to the input spending this output.
+    pub fn satisfaction_weight(&self) -> Result<usize> {
+        if let Some(weight) = self.satisfaction {
+            return Ok(weight);
+        }
+        match classify(&self.output.script_pubkey)? {
+            Spend::P2pkh(_) => Ok(P2PKH_SATISFACTION_WEIGHT),
+            Spend::P2wpkh(_) => Ok(P2WPKH_SATISFACTION_WEIGHT),
+            Spend::P2tr(_) => Ok(P2TR_KEY_SATISFACTION_WEIGHT),
+            Spend::P2wsh(_) => Err(Error::new(ErrorKind::UnsupportedScript, "cannot size a P2WSH spend without its script")),
+        }
+    }
+
+    /// Weight of the signed input spending this output. Legacy inputs
+    /// count the empty witness they get in a segwit transaction.
+    pub fn input_weight(&self) -> Result<usize> {
+        let legacy_witness = if self.is_segwit() { 0 } else { 1 };
+        Ok(INPUT_BASE_SIZE * 4 + self.satisfaction_weight()? + legacy_witness)
+    }
+
+    // P2SH counts as legacy
+    fn is_segwit(&self) -> bool {
+        is_witness_program(&self.output.script_pubkey)
+    }
+}
+
+/// The smallest amount an output paying to `script_pubkey` may carry
+/// under Bitcoin Core's default policy: anything less costs more to spend
+/// than it is worth. OP_RETURN outputs have no minimum.
+pub fn dust_threshold(script_pubkey: &[u8]) -> u64 {
+    if script_pubkey.first() == Some(&OP_RETURN) {
+        return 0;
+    }
+    let output_size = 8 + varint_size(script_pubkey.len()) + script_pubkey.len();
+    // The input that will spend it, with a 107 byte script_sig that segwit
+    // discounts to a quarter
+    let spend_size = if is_witness_program(script_pubkey) { INPUT_BASE_SIZE + 107 / 4 } else { INPUT_BASE_SIZE + 107 };
+    (output_size + spend_size) as u64 * DUST_RELAY_FEE / 1000
+}
+
+/// A version byte (OP_0 or OP_1 through OP_16) then a 2 to 40 byte push.
+fn is_witness_program(script: &[u8]) -> bool {
+    match script {
+        [version, len, program @ ..] => {
+            (*version == 0 || (0x51..=0x60).contains(version)) && (2..=40).contains(&program.len()) && *len as usize == program.len()
+        }
+        _ => false,
+    }
+}
+
+/// Estimated weight of a transaction spending `utxos` to `outputs` once
+/// every input is signed.
+pub fn estimate_weight(utxos: &[Utxo], outputs: &[TxOut]) -> Result<usize> {
+    let mut size = 4 + varint_size(utxos.len()) + varint_size(outputs.len()) + 4;
+    for output in outputs.iter() {
+        size += output_size(output);
+    }
+    let mut weight = size * 4;
+    for utxo in utxos.iter() {
+        weight += utxo.input_weight()?;
+    }
+    if utxos.iter().any(|utxo| utxo.is_segwit()) {
+        weight += 2;
+    }
+    Ok(weight)
+}
+
+fn output_size(output: &TxOut) -> usize {
+    8 + varint_size(output.script_pubkey.len()) + output.script_pubkey.len()
+}
+
+fn varint_size(n: usize) -> usize {
+    let mut buf = Vec::new();
+    encode_varint(&mut buf, n as u64).unwrap()
+}
+
+/// A recipient's share of a transaction's fee.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub script_pubkey: Vec<u8>,
+    /// Everything paid to the script
+    pub amount: u64,
+    /// Where the payment is in the transaction's outputs
+    pub output_index: usize,
+    /// The fee for the output's own weight, plus a share of the rest in
+    /// proportion to `amount`
+    pub fee: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TxBuilder {
+    utxos: Vec<Utxo>,
+    recipients: Vec<TxOut>,
+    /// Seed of the output shuffle, set in batch mode
+    batch: Option<[u8; 32]>,
+    /// Whether change is a sweep, which needs no payments
+    drain: bool,
+    fee_rate: f64,
+    change_script: Option<Vec<u8>>,
+    coin_selection: Arc<dyn CoinSelection + Send + Sync>,
+    version: u32,
+    locktime: LockTime,
+    sequence: Sequence,
+    network: Network,
+}
+
+impl Default for TxBuilder {
+    fn default() -> TxBuilder {
+        TxBuilder::new()
+    }
+}
+
+impl TxBuilder {
+    /// Version 2, locktime 0, final sequences, a 1 sat/vB fee rate and
+    /// largest first coin selection.
+    pub fn new() -> TxBuilder {
+        TxBuilder {
+            utxos: Vec::new(),
+            recipients: Vec::new(),
+            batch: None,
+            drain: false,
+            fee_rate: 1.0,
+            change_script: None,
+            coin_selection: Arc::new(LargestFirst),
+            version: 2,
+            locktime: LockTime::ZERO,
+            sequence: Sequence::MAX,
+            network: Network::Mainnet,
+        }
+    }
+
+    pub fn add_utxo(mut self, utxo: Utxo) -> TxBuilder {
+        self.utxos.push(utxo);
+        self
+    }
+
+    pub fn add_utxos<I: IntoIterator<Item = Utxo>>(mut self, utxos: I) -> TxBuilder {
+        self.utxos.extend(utxos);
+        self
+    }
+
+    pub fn add_recipient(mut self, script_pubkey: Vec<u8>, amount: u64) -> TxBuilder {
+        self.recipients.push(TxOut::new(amount, script_pubkey));
+        self
+    }
+
+    pub fn add_recipients<I: IntoIterator<Item = (Vec<u8>, u64)>>(mut self, payments: I) -> TxBuilder {
+        self.recipients.extend(payments.into_iter().map(|(script_pubkey, amount)| TxOut::new(amount, script_pubkey)));
+        self
+    }
+
+    /// Batch mode: payments to the same script become one output, the
+    /// outputs are shuffled with `seed`, which should be fresh random
+    /// bytes, and a payment whose output costs more in fees than it pays
+    /// is refused.
+    pub fn batch(mut self, seed: [u8; 32]) -> TxBuilder {
+        self.batch = Some(seed);
+        self
+    }
+
+    /// Fee rate in satoshis per vbyte.
+    pub fn fee_rate(mut self, sat_per_vbyte: f64) -> TxBuilder {
+        self.fee_rate = sat_per_vbyte;
+        self
+    }
+
+    pub fn change_script(mut self, script_pubkey: Vec<u8>) -> TxBuilder {
+        self.change_script = Some(script_pubkey);
+        self
+    }
+
+    /// Sweeps every coin worth spending, with `SpendAll`, and sends what
+    /// the payments and fee leave to `script_pubkey` as change. There need
+    /// be no payments, but then the change can't be dust.
+    pub fn drain_to(mut self, script_pubkey: Vec<u8>) -> TxBuilder {
+        self.drain = true;
+        self.coin_selection = Arc::new(SpendAll);
+        self.change_script(script_pubkey)
+    }
+
+    pub fn coin_selection<S: CoinSelection + Send + Sync + 'static>(mut self, strategy: S) -> TxBuilder {
+        self.coin_selection = Arc::new(strategy);
+        self
+    }
+
+    pub fn version(mut self, version: u32) -> TxBuilder {
+        self.version = version;
+        self
+    }
+
+    pub fn locktime(mut self, locktime: LockTime) -> TxBuilder {
+        self.locktime = locktime;
+        self
+    }
+
+    /// The sequence of every input.
+    pub fn sequence(mut self, sequence: Sequence) -> TxBuilder {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> TxBuilder {
+        self.network = network;
+        self
+    }
+
+    /// Weight of everything but the inputs: version, locktime, counts,
+    /// `outputs` and, if any coin is segwit, the marker and flag.
+    fn fixed_weight(&self, outputs: &[TxOut]) -> usize {
+        // Sizing no inputs cannot fail
+        let weight = estimate_weight(&[], outputs).unwrap();
+        let marker_and_flag = if self.utxos.iter().any(|utxo| utxo.is_segwit()) { 2 } else { 0 };
+        weight + marker_and_flag
+    }
+
+    /// The recipients, merged by script in batch mode.
+    fn payments(&self) -> Result<Vec<TxOut>> {
+        if self.batch.is_none() {
+            return Ok(self.recipients.clone());
+        }
+        let mut merged: Vec<TxOut> = Vec::new();
+        for recipient in self.recipients.iter() {
+            match merged.iter_mut().find(|payment| payment.script_pubkey == recipient.script_pubkey) {
+                Some(payment) => {
+                    payment.amount = payment.amount.checked_add(recipient.amount).ok_or_else(|| Error::new(ErrorKind::OutOfRange, "payments to one script overflow"))?;
+                }
+                None => merged.push(recipient.clone()),
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Selects coins paying for `payments` and the fee, and returns them
+    /// with the change output if there is one.
+    fn select(&self, payments: &[TxOut]) -> Result<(Vec<Utxo>, Option<TxOut>)> {
+        if payments.is_empty() && !self.drain {
+            return Err(Error::new(ErrorKind::OutOfRange, "no recipients"));
+        }
+        for recipient in payments.iter() {
+            let dust = dust_threshold(&recipient.script_pubkey);
+            if recipient.amount < dust {
+                return Err(Error::new(ErrorKind::OutOfRange,
+                    format!("payment of {} is below the dust threshold of {}", recipient.amount, dust)));
+            }
+        }
+        let total = payments.iter().try_fold(0u64, |sum, o| sum.checked_add(o.amount));
+        let target = total.and_then(|total| total.checked_add(fee_for_weight(self.fixed_weight(payments), self.fee_rate)));
+        let target = target.ok_or_else(|| Error::new(ErrorKind::OutOfRange, "payments overflow"))?;
+        let selection = self.coin_selection.select(&self.utxos, target, self.fee_rate)?;
+        let excess = selection.effective_value() - target;
+        let change_script = match self.change_script {
+            Some(ref script) => script,
+            // Without a change script any excess goes to the miner
+            None => return Ok((selection.utxos, None)),
+        };
+        let change = TxOut::new(0, change_script.clone());
+        let change_fee = fee_for_weight(output_size(&change) * 4, self.fee_rate);
+        if excess >= change_fee + dust_threshold(change_script) {
+            return Ok((selection.utxos, Some(TxOut::new(excess - change_fee, change.script_pubkey))));
+        }
+        if payments.is_empty() {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("sweeping {} leaves dust after the fee", excess)));
+        }
+        Ok((selection.utxos, None))
+    }
+
+    /// The unsigned transaction: selected inputs, then the recipients in
+    /// the order added, then change, or every output shuffled in batch
+    /// mode.
+    pub fn build(&self) -> Result<Tx> {
+        self.build_with_costs().map(|(tx, _)| tx)
+    }
+
+    /// The unsigned transaction and what each recipient adds to its fee,
+    /// in the order the recipients were first added. The fee covers the
+    /// inputs, the change and any excess too, so the shares add up to it;
+    /// a sweep without payments has none.
+    pub fn build_with_costs(&self) -> Result<(Tx, Vec<Allocation>)> {
+        let payments = self.payments()?;
+        let (selected, change) = self.select(&payments)?;
+        let tx_ins = selected
+            .iter()
+            .map(|utxo| {
+                let mut tx_in = TxIn::new(utxo.prev_tx, utxo.prev_index);
+                tx_in.sequence = self.sequence;
+                tx_in
+            })
+            .collect();
+        if self.batch.is_some() {
+            for payment in payments.iter() {
+                let cost = fee_for_weight(output_size(payment) * 4, self.fee_rate);
+                if cost > payment.amount {
+                    return Err(Error::new(ErrorKind::OutOfRange, format!("payment of {} costs {} in fees", payment.amount, cost)));
+                }
+            }
+        }
+        let mut tx_outs = payments.clone();
+        tx_outs.extend(change);
+        // Where each output goes
+        let mut order: Vec<usize> = (0..tx_outs.len()).collect();
+        if let Some(seed) = self.batch {
+            shuffle(&mut order, &seed);
+        }
+        let tx_outs = order.iter().map(|&i| tx_outs[i].clone()).collect();
+        let tx = Tx::new(self.version, tx_ins, tx_outs, self.locktime, self.network);
+
+        // Selected coins cover every output, so this can't underflow
+        let fee = selected.iter().map(|utxo| utxo.output.amount).sum::<u64>() - tx.tx_outs.iter().map(|o| o.amount).sum::<u64>();
+        let weight = estimate_weight(&selected, &tx.tx_outs)? as u128;
+        let own: Vec<u64> = payments.iter().map(|payment| (fee as u128 * (output_size(payment) * 4) as u128 / weight) as u64).collect();
+        let shared = fee - own.iter().sum::<u64>();
+        let total: u128 = payments.iter().map(|payment| payment.amount as u128).sum();
+        let mut allocations: Vec<Allocation> = payments
+            .iter()
+            .zip(own)
+            .enumerate()
+            .map(|(i, (payment, own))| Allocation {
+                script_pubkey: payment.script_pubkey.clone(),
+                amount: payment.amount,
+                output_index: order.iter().position(|&j| j == i).unwrap(),
+                fee: own + (shared as u128 * payment.amount as u128 / total.max(1)) as u64,
+            })
+            .collect();
+        // What rounding down left goes to the largest payment
+        let left = fee - allocations.iter().map(|allocation| allocation.fee).sum::<u64>();
+        if let Some(largest) = allocations.iter_mut().max_by_key(|allocation| allocation.amount) {
+            largest.fee += left;
+        }
+        Ok((tx, allocations))
+    }
+
+    /// The unsigned transaction as a PSBT, with each input's spent output
+    /// filled in as its witness UTXO.
+    pub fn build_psbt(&self) -> Result<Psbt> {
+        let tx = self.build()?;
+        let mut psbt = Psbt::new(tx)?;
+        for (input, tx_in) in psbt.inputs.iter_mut().zip(psbt.unsigned_tx.tx_ins.iter()) {
+            let utxo = self
+                .utxos
+                .iter()
+                .find(|utxo| utxo.prev_tx == tx_in.prev_tx && utxo.prev_index == tx_in.prev_index)
+                .unwrap();
+            input.witness_utxo = Some(utxo.output.clone());
+        }
+        Ok(psbt)
+    }
+}
+
+/// Fisher-Yates, drawing each index from sha256(seed || position).
+fn shuffle(items: &mut [usize], seed: &[u8; 32]) {
+    for i in (1..items.len()).rev() {
+        let draw = sha256(&[&seed[..], &(i as u32).to_le_bytes()].concat());
+        let j = u64::from_le_bytes(draw[..8].try_into().unwrap()) % (i as u64 + 1);
+        items.swap(i, j as usize);
+    }
+}
+
+#[cfg(test)]
+use super::coin_selection::Knapsack;
+#[cfg(test)]
+use super::{p2pkh_script, p2tr_script, p2wpkh_script, MapFetcher};
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn dust_thresholds() {
+    assert_eq!(dust_threshold(&p2pkh_script(&[0; 20])), 546);
+    assert_eq!(dust_threshold(&p2wpkh_script(&[0; 20])), 294);
+    assert_eq!(dust_threshold(&p2tr_script(&[0; 32])), 330);
+    assert_eq!(dust_threshold(&[OP_RETURN, 1, 0]), 0);
+}
+
+#[test]
+fn tx_builder_selects_and_signs() {
+    let key = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    let point = key.public_key();
+    let output_key = key.tap_tweak(None).unwrap().xonly_public_key().serialize();
+    let parent = Tx::new(
+        2,
+        vec![TxIn::new([7; 32], 0)],
+        vec![
+            TxOut::new(20_000, p2pkh_script(&point.hash160(true))),
+            TxOut::new(50_000, p2wpkh_script(&point.hash160(true))),
+            TxOut::new(30_000, p2tr_script(&output_key)),
+        ],
+        LockTime::ZERO,
+        Network::Mainnet,
+    );
+    let utxos = parent.tx_outs.iter().enumerate().map(|(i, out)| Utxo::new(parent.hash(), i as u32, out.clone()));
+    let fetcher = MapFetcher(vec![parent.clone()]);
+    let builder = TxBuilder::new()
+        .add_utxos(utxos)
+        .add_recipient(p2wpkh_script(&[1; 20]), 60_000)
+        .fee_rate(5.0)
+        .change_script(p2tr_script(&output_key))
+        .locktime(LockTime::from_height(800_000).unwrap())
+        .sequence(Sequence::ENABLE_LOCKTIME_NO_RBF);
+
+    // The two largest coins cover it, with change
+    let mut tx = builder.build().unwrap();
+    assert_eq!(tx.tx_ins.len(), 2);
+    assert_eq!((tx.tx_ins[0].prev_index, tx.tx_ins[1].prev_index), (1, 2));
+    assert_eq!((tx.version, tx.locktime, tx.tx_ins[0].sequence), (2, LockTime::from_height(800_000).unwrap(), Sequence::ENABLE_LOCKTIME_NO_RBF));
+    assert_eq!(tx.tx_outs.len(), 2);
+    for input_index in 0..tx.tx_ins.len() {
+        tx.sign_input(input_index, &key, super::SighashType::All, &fetcher).unwrap();
+    }
+    assert!(tx.verify(&fetcher).unwrap());
+    let fee = tx.fee(&fetcher).unwrap();
+    assert!(fee as f64 >= 5.0 * tx.vsize() as f64);
+    assert!((fee as f64) < 5.0 * (tx.vsize() + 2) as f64);
+
+    // Knapsack finds the smaller pair that still covers it
+    let tx = builder.clone().coin_selection(Knapsack::new(1)).build().unwrap();
+    assert_eq!((tx.tx_ins[0].prev_index, tx.tx_ins[1].prev_index), (1, 0));
+
+    // Change below dust is left to the fee
+    let tx = builder.clone().add_recipient(p2pkh_script(&[2; 20]), 37_900).build().unwrap();
+    assert_eq!(tx.tx_ins.len(), 3);
+    assert_eq!(tx.tx_outs.len(), 2);
+    let psbt = builder.build_psbt().unwrap();
+    assert_eq!(psbt.inputs[1].witness_utxo.as_ref().unwrap().amount, 30_000);
+
+    assert_eq!(builder.clone().add_recipient(p2wpkh_script(&[1; 20]), 40_000).build().unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(builder.clone().add_recipient(p2wpkh_script(&[1; 20]), 100).build().unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(TxBuilder::new().add_utxos(builder.utxos.clone()).build().unwrap_err().kind(), ErrorKind::OutOfRange);
+
+    // A sweep spends every coin to one output, leaving only the fee out
+    let sweep = TxBuilder::new().add_utxos(builder.utxos.clone()).fee_rate(5.0).drain_to(p2wpkh_script(&[1; 20]));
+    let tx = sweep.build().unwrap();
+    assert_eq!((tx.tx_ins.len(), tx.tx_outs.len()), (3, 1));
+    let fee = 100_000 - tx.tx_outs[0].amount;
+    let vsize = estimate_weight(&builder.utxos, &tx.tx_outs).unwrap().div_ceil(4) as u64;
+    assert!(fee >= 5 * vsize && fee < 5 * (vsize + 4));
+    let small = Utxo::new([7; 32], 0, TxOut::new(400, p2wpkh_script(&[5; 20])));
+    assert_eq!(TxBuilder::new().add_utxo(small).drain_to(p2wpkh_script(&[1; 20])).build().unwrap_err().kind(), ErrorKind::OutOfRange);
+}
+
+#[test]
+fn tx_builder_batches_payments() {
+    let (a, b, c, change) = (p2wpkh_script(&[1; 20]), p2pkh_script(&[2; 20]), p2tr_script(&[3; 32]), p2wpkh_script(&[4; 20]));
+    let utxos = vec![Utxo::new([7; 32], 0, TxOut::new(100_000, p2wpkh_script(&[5; 20]))), Utxo::new([7; 32], 1, TxOut::new(50_000, p2wpkh_script(&[5; 20])))];
+    let builder = TxBuilder::new()
+        .add_utxos(utxos.clone())
+        .add_recipients(vec![(a.clone(), 10_000), (b.clone(), 20_000), (a.clone(), 5_000), (c.clone(), 30_000)])
+        .fee_rate(2.0)
+        .change_script(change.clone())
+        .batch([9; 32]);
+
+    // Payments to `a` merge, and each allocation finds its output
+    let (tx, costs) = builder.build_with_costs().unwrap();
+    assert_eq!(tx.tx_outs.len(), 4);
+    assert_eq!(costs.iter().map(|cost| (&cost.script_pubkey, cost.amount)).collect::<Vec<_>>(), vec![(&a, 15_000), (&b, 20_000), (&c, 30_000)]);
+    for cost in costs.iter() {
+        assert_eq!(tx.tx_outs[cost.output_index], TxOut::new(cost.amount, cost.script_pubkey.clone()));
+    }
+    let inputs: u64 = tx.tx_ins.iter().map(|tx_in| utxos[tx_in.prev_index as usize].output.amount).sum();
+    let outputs: u64 = tx.tx_outs.iter().map(|o| o.amount).sum();
+    assert_eq!(costs.iter().map(|cost| cost.fee).sum::<u64>(), inputs - outputs);
+    // The legacy output is bigger and the taproot one pays more, so both cost more than `a`
+    assert!(costs[1].fee > costs[0].fee && costs[2].fee > costs[0].fee);
+
+    // The seed alone decides where change ends up
+    assert_eq!(builder.build().unwrap(), tx);
+    let positions: std::collections::HashSet<usize> =
+        (0..8u8).map(|seed| builder.clone().batch([seed; 32]).build().unwrap().tx_outs.iter().position(|o| o.script_pubkey == change).unwrap()).collect();
+    assert!(positions.len() > 1);
+
+    // Dust is checked once payments merge, and a payment costing more
+    // than it pays is refused
+    let small = TxBuilder::new().add_utxos(utxos).add_recipients(vec![(a.clone(), 200), (a.clone(), 200)]);
+    assert_eq!(small.build().unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(small.clone().batch([9; 32]).build().unwrap().tx_outs[0].amount, 400);
+    assert!(small.clone().fee_rate(20.0).batch([9; 32]).build().is_err());
+}
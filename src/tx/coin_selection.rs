@@ -0,0 +1,401 @@
+//! Choosing which coins fund a transaction.
+//!
+//! Every strategy works on effective values: a coin's amount minus the fee
+//! for the input that spends it at the target fee rate. Coins worth less
+//! than that are never selected. `target` is what the selected coins'
+//! effective values must add up to, the payments plus the fee for the rest
+//! of the transaction.
+
+use super::builder::Utxo;
+use crate::error::{Error, ErrorKind, Result};
+use std::fmt;
+
+/// Bitcoin Core's `MIN_CHANGE`, the change knapsack aims to leave
+pub const CENT: u64 = 1_000_000;
+const KNAPSACK_ITERATIONS: usize = 1000;
+const BNB_TOTAL_TRIES: usize = 100_000;
+
+/// The fee for `weight` weight units at `fee_rate` sat/vB. Like Bitcoin
+/// Core, vbytes are rounded up before the fee is, so fees summed over the
+/// parts of a transaction cover its whole vsize.
+pub fn fee_for_weight(weight: usize, fee_rate: f64) -> u64 {
+    (weight.div_ceil(4) as f64 * fee_rate).ceil() as u64
+}
+
+/// Coins picked by a strategy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    pub utxos: Vec<Utxo>,
+    /// Sum of the coins' amounts
+    pub total: u64,
+    /// Fee for the inputs spending them
+    pub input_fee: u64,
+}
+
+impl Selection {
+    fn new<'a, 'u: 'a, I: IntoIterator<Item = &'a Candidate<'u>>>(candidates: I) -> Selection {
+        let mut selection = Selection { utxos: Vec::new(), total: 0, input_fee: 0 };
+        for candidate in candidates {
+            selection.utxos.push(candidate.utxo.clone());
+            selection.total += candidate.utxo.output.amount;
+            selection.input_fee += candidate.fee;
+        }
+        selection
+    }
+
+    /// What the coins contribute once their inputs are paid for.
+    pub fn effective_value(&self) -> u64 {
+        self.total - self.input_fee
+    }
+
+    /// Bitcoin Core's waste metric: what spending these inputs now costs
+    /// over spending them at `long_term_fee_rate`, plus either the cost of
+    /// making and later spending change or, without change, the excess
+    /// over `target` given to the miner. Lower is better.
+    pub fn waste(&self, target: u64, long_term_fee_rate: f64, change_cost: Option<u64>) -> Result<i64> {
+        let mut long_term_fee = 0;
+        for utxo in self.utxos.iter() {
+            long_term_fee += fee_for_weight(utxo.input_weight()?, long_term_fee_rate);
+        }
+        let rest = match change_cost {
+            Some(cost) => cost as i64,
+            None => self.effective_value() as i64 - target as i64,
+        };
+        Ok(self.input_fee as i64 - long_term_fee as i64 + rest)
+    }
+}
+
+/// A coin selection strategy.
+pub trait CoinSelection: fmt::Debug {
+    /// Picks coins from `utxos` whose effective values at `fee_rate`
+    /// (sat/vB) add up to at least `target`.
+    fn select(&self, utxos: &[Utxo], target: u64, fee_rate: f64) -> Result<Selection>;
+}
+
+struct Candidate<'a> {
+    utxo: &'a Utxo,
+    fee: u64,
+    value: u64,
+}
+
+/// The coins worth spending at `fee_rate`, by effective value, largest
+/// first.
+fn candidates(utxos: &[Utxo], fee_rate: f64) -> Result<Vec<Candidate<'_>>> {
+    let mut candidates = Vec::new();
+    for utxo in utxos.iter() {
+        let fee = fee_for_weight(utxo.input_weight()?, fee_rate);
+        if utxo.output.amount > fee {
+            candidates.push(Candidate { utxo, fee, value: utxo.output.amount - fee });
+        }
+    }
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.value));
+    Ok(candidates)
+}
+
+fn insufficient_funds(available: u64, target: u64) -> Error {
+    Error::new(ErrorKind::OutOfRange,
+        format!("insufficient funds: {} available after input fees, {} needed", available, target))
+}
+
+/// Takes the largest coins until they reach the target. Simple and
+/// consolidates few coins, but rarely avoids change.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
+
+impl CoinSelection for LargestFirst {
+    fn select(&self, utxos: &[Utxo], target: u64, fee_rate: f64) -> Result<Selection> {
+        let candidates = candidates(utxos, fee_rate)?;
+        let mut value = 0;
+        for (i, candidate) in candidates.iter().enumerate() {
+            value += candidate.value;
+            if value >= target {
+                return Ok(Selection::new(&candidates[..=i]));
+            }
+        }
+        Err(insufficient_funds(value, target))
+    }
+}
+
+/// Takes every coin worth spending, to sweep or consolidate them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpendAll;
+
+impl CoinSelection for SpendAll {
+    fn select(&self, utxos: &[Utxo], target: u64, fee_rate: f64) -> Result<Selection> {
+        let candidates = candidates(utxos, fee_rate)?;
+        let selection = Selection::new(&candidates);
+        if selection.effective_value() < target {
+            return Err(insufficient_funds(selection.effective_value(), target));
+        }
+        Ok(selection)
+    }
+}
+
+/// Bitcoin Core's original knapsack solver: a coin matching the target
+/// exactly, else the best of a randomized search over the smaller coins
+/// and the smallest coin larger than target plus `min_change`. The search
+/// is seeded, so equal inputs give equal selections.
+#[derive(Debug, Clone, Copy)]
+pub struct Knapsack {
+    pub seed: u64,
+    pub min_change: u64,
+}
+
+impl Knapsack {
+    pub fn new(seed: u64) -> Knapsack {
+        Knapsack { seed, min_change: CENT }
+    }
+
+    /// Core's `ApproximateBestSubset`: the smallest sum of `values` found
+    /// that reaches `target`, and the coins making it up.
+    fn best_subset(&self, values: &[u64], target: u64, rng: &mut XorShift) -> (u64, Vec<bool>) {
+        let mut best = vec![true; values.len()];
+        let mut best_total: u64 = values.iter().sum();
+        for _ in 0..KNAPSACK_ITERATIONS {
+            if best_total == target {
+                break;
+            }
+            let mut included = vec![false; values.len()];
+            let mut total = 0;
+            let mut reached = false;
+            for pass in 0..2 {
+                if reached {
+                    break;
+                }
+                for (i, value) in values.iter().enumerate() {
+                    // The first pass picks coins at random, the second
+                    // tries every coin left out
+                    let take = if pass == 0 { rng.next() & 1 == 1 } else { !included[i] };
+                    if !take {
+                        continue;
+                    }
+                    total += value;
+                    included[i] = true;
+                    if total >= target {
+                        reached = true;
+                        if total < best_total {
+                            best_total = total;
+                            best = included.clone();
+                        }
+                        total -= value;
+                        included[i] = false;
+                    }
+                }
+            }
+        }
+        (best_total, best)
+    }
+}
+
+impl CoinSelection for Knapsack {
+    fn select(&self, utxos: &[Utxo], target: u64, fee_rate: f64) -> Result<Selection> {
+        let candidates = candidates(utxos, fee_rate)?;
+        if let Some(exact) = candidates.iter().position(|c| c.value == target) {
+            return Ok(Selection::new(&candidates[exact..=exact]));
+        }
+        // Candidates are sorted, so the smaller coins are a suffix and the
+        // smallest larger one is just before it
+        let split = candidates.iter().position(|c| c.value < target + self.min_change).unwrap_or(candidates.len());
+        let lowest_larger = split.checked_sub(1).map(|i| &candidates[i..=i]);
+        let smaller = &candidates[split..];
+        let smaller_total: u64 = smaller.iter().map(|c| c.value).sum();
+        if smaller_total == target {
+            return Ok(Selection::new(smaller));
+        }
+        if smaller_total < target {
+            return match lowest_larger {
+                Some(larger) => Ok(Selection::new(larger)),
+                None => Err(insufficient_funds(smaller_total, target)),
+            };
+        }
+
+        let values: Vec<u64> = smaller.iter().map(|c| c.value).collect();
+        let mut rng = XorShift::new(self.seed);
+        let (mut best_total, mut best) = self.best_subset(&values, target, &mut rng);
+        if best_total != target && smaller_total >= target + self.min_change {
+            // Aim to leave useful change instead of a sliver
+            let (total, subset) = self.best_subset(&values, target + self.min_change, &mut rng);
+            best_total = total;
+            best = subset;
+        }
+        if let Some(larger) = lowest_larger {
+            if best_total != target && (best_total < target + self.min_change || larger[0].value <= best_total) {
+                return Ok(Selection::new(larger));
+            }
+        }
+        Ok(Selection::new(smaller.iter().zip(best.iter()).filter(|(_, &take)| take).map(|(c, _)| c)))
+    }
+}
+
+/// Bitcoin Core's branch and bound search for a changeless selection:
+/// effective value between `target` and `target + cost_of_change`, with
+/// the lowest waste. Fails when there is none, so callers usually fall
+/// back to another strategy.
+#[derive(Debug, Clone, Copy)]
+pub struct BranchAndBound {
+    /// Fee for a change output plus the fee to spend it later
+    pub cost_of_change: u64,
+    pub long_term_fee_rate: f64,
+}
+
+impl CoinSelection for BranchAndBound {
+    fn select(&self, utxos: &[Utxo], target: u64, fee_rate: f64) -> Result<Selection> {
+        let candidates = candidates(utxos, fee_rate)?;
+        // Waste of each input, negative when fees are below the long term rate
+        let mut input_waste = Vec::new();
+        for c in candidates.iter() {
+            input_waste.push(c.fee as i64 - fee_for_weight(c.utxo.input_weight()?, self.long_term_fee_rate) as i64);
+        }
+        let mut available: u64 = candidates.iter().map(|c| c.value).sum();
+        if available < target {
+            return Err(insufficient_funds(available, target));
+        }
+        let fee_rate_high = fee_rate > self.long_term_fee_rate;
+
+        let mut selection: Vec<usize> = Vec::new();
+        let mut value = 0u64;
+        let mut waste = 0i64;
+        let mut best: Option<(Vec<usize>, i64)> = None;
+        let mut index = 0;
+        for _ in 0..BNB_TOTAL_TRIES {
+            let best_waste = best.as_ref().map_or(i64::MAX, |b| b.1);
+            let backtrack = if value + available < target
+                || value > target + self.cost_of_change
+                || (waste > best_waste && fee_rate_high)
+            {
+                true
+            } else if value >= target {
+                let total_waste = waste + (value - target) as i64;
+                if total_waste <= best_waste {
+                    best = Some((selection.clone(), total_waste));
+                }
+                true
+            } else {
+                false
+            };
+
+            if backtrack {
+                let last = match selection.last() {
+                    Some(&last) => last,
+                    None => break,
+                };
+                // Put back what was skipped after the last included coin,
+                // then explore leaving that coin out
+                while index > last + 1 {
+                    index -= 1;
+                    available += candidates[index].value;
+                }
+                index = last;
+                value -= candidates[last].value;
+                waste -= input_waste[last];
+                selection.pop();
+            } else {
+                let c = &candidates[index];
+                available -= c.value;
+                // Skip a coin equal to the previous one when that one was
+                // left out, it would only repeat the same branch
+                let previous_omitted = !selection.is_empty() && selection.last() != Some(&(index - 1));
+                if !previous_omitted
+                    || c.value != candidates[index - 1].value
+                    || c.fee != candidates[index - 1].fee
+                {
+                    selection.push(index);
+                    value += c.value;
+                    waste += input_waste[index];
+                }
+            }
+            index += 1;
+        }
+
+        match best {
+            Some((indices, _)) => Ok(Selection::new(indices.iter().map(|&i| &candidates[i]))),
+            None => Err(Error::new(ErrorKind::OutOfRange, format!("no changeless selection for {}", target))),
+        }
+    }
+}
+
+/// xorshift64, enough to shuffle knapsack passes reproducibly.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // Zero is a fixed point
+        XorShift(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+use super::{p2wpkh_script, TxOut};
+
+#[cfg(test)]
+fn coins(amounts: &[u64]) -> Vec<Utxo> {
+    amounts.iter().enumerate().map(|(i, &amount)| Utxo::new([1; 32], i as u32, TxOut::new(amount, p2wpkh_script(&[0; 20])))).collect()
+}
+
+#[cfg(test)]
+fn amounts(selection: &Selection) -> Vec<u64> {
+    let mut amounts: Vec<u64> = selection.utxos.iter().map(|u| u.output.amount).collect();
+    amounts.sort_unstable();
+    amounts
+}
+
+#[test]
+fn largest_first_selection() {
+    let utxos = coins(&[1_000, 50_000, 20_000, 30_000]);
+    let selection = LargestFirst.select(&utxos, 60_000, 0.0).unwrap();
+    assert_eq!(amounts(&selection), vec![30_000, 50_000]);
+    assert_eq!(selection.effective_value(), 80_000);
+
+    // At 10 sat/vB a P2WPKH input costs 690 sats, so the smallest coin is
+    // not worth spending
+    let p2wpkh_fee = fee_for_weight(utxos[0].input_weight().unwrap(), 10.0);
+    assert_eq!(p2wpkh_fee, 690);
+    let selection = LargestFirst.select(&utxos, 60_000, 10.0).unwrap();
+    assert_eq!((selection.input_fee, selection.effective_value()), (2 * p2wpkh_fee, 80_000 - 2 * p2wpkh_fee));
+    assert_eq!(LargestFirst.select(&utxos, 100_000, 10.0).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
+
+#[test]
+fn branch_and_bound_selection() {
+    let bnb = BranchAndBound { cost_of_change: 500, long_term_fee_rate: 0.0 };
+    let utxos = coins(&[1_000, 2_000, 3_000, 4_000, 7_000]);
+    // Exact sums, or within cost_of_change above the target
+    for &(target, want) in [(5_000, 5_000), (8_000, 8_000), (17_000, 17_000), (7_600, 8_000)].iter() {
+        let selection = bnb.select(&utxos, target, 0.0).unwrap();
+        assert_eq!(selection.total, want, "target {}", target);
+    }
+    assert_eq!(bnb.select(&utxos, 18_000, 0.0).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(bnb.select(&coins(&[4_000, 7_000]), 5_000, 0.0).unwrap_err().kind(), ErrorKind::OutOfRange);
+
+    // With fees above the long term rate fewer inputs waste less
+    let bnb = BranchAndBound { cost_of_change: 2_000, long_term_fee_rate: 1.0 };
+    let utxos = coins(&[10_690, 5_690, 5_690]);
+    let selection = bnb.select(&utxos, 10_000, 10.0).unwrap();
+    assert_eq!(amounts(&selection), vec![10_690]);
+    assert_eq!(selection.waste(10_000, 1.0, None).unwrap(), 690 - 69);
+    assert_eq!(selection.waste(10_000, 1.0, Some(300)).unwrap(), 690 - 69 + 300);
+}
+
+#[test]
+fn knapsack_selection() {
+    let knapsack = Knapsack { seed: 7, min_change: 0 };
+    let utxos = coins(&[1_000, 2_000, 5_000, 10_000, 20_000]);
+    assert_eq!(amounts(&knapsack.select(&utxos, 10_000, 0.0).unwrap()), vec![10_000]);
+    assert_eq!(amounts(&knapsack.select(&utxos, 17_000, 0.0).unwrap()), vec![2_000, 5_000, 10_000]);
+    // Nothing small adds up, so the smallest larger coin
+    assert_eq!(amounts(&knapsack.select(&coins(&[1_000, 2_000, 50_000]), 4_000, 0.0).unwrap()), vec![50_000]);
+    assert_eq!(knapsack.select(&utxos, 40_000, 0.0).unwrap_err().kind(), ErrorKind::OutOfRange);
+
+    // Same seed, same answer
+    let many = coins(&(1..=40).map(|i| i * 1_000 + i * i).collect::<Vec<u64>>());
+    let first = Knapsack::new(42).select(&many, 123_456, 1.0).unwrap();
+    assert_eq!(first, Knapsack::new(42).select(&many, 123_456, 1.0).unwrap());
+    assert!(first.effective_value() >= 123_456);
+}
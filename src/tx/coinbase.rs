@@ -0,0 +1,169 @@
+//! Coinbase transactions and context free transaction checks.
+//!
+//! A coinbase spends nothing: its single input points at the null outpoint
+//! and its script_sig starts with the block height (BIP34). It has no
+//! signatures to check, only the structural rules every transaction obeys.
+
+use super::sign::push;
+use super::{LockTime, Tx, TxIn, TxOut};
+use crate::encoding::util::encode_hex;
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::Network;
+use std::collections::HashSet;
+
+/// 21 million bitcoin, in satoshis.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+const NULL_INDEX: u32 = 0xffff_ffff;
+const MIN_COINBASE_SCRIPT_SIZE: usize = 2;
+const MAX_COINBASE_SCRIPT_SIZE: usize = 100;
+const OP_0: u8 = 0x00;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+
+impl TxIn {
+    /// Whether this input spends the null outpoint.
+    pub fn is_null(&self) -> bool {
+        self.prev_tx == [0; 32] && self.prev_index == NULL_INDEX
+    }
+}
+
+impl Tx {
+    /// A coinbase for the block at `height`, its script_sig the height
+    /// followed by `extra_nonce`.
+    pub fn new_coinbase(height: u32, extra_nonce: &[u8], tx_outs: Vec<TxOut>, network: Network) -> Tx {
+        let mut tx_in = TxIn::new([0; 32], NULL_INDEX);
+        tx_in.script_sig = height_script(height);
+        tx_in.script_sig.extend_from_slice(extra_nonce);
+        Tx::new(1, vec![tx_in], tx_outs, LockTime::ZERO, network)
+    }
+
+    /// A single input spending the null outpoint.
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_ins.len() == 1 && self.tx_ins[0].is_null()
+    }
+
+    /// The BIP34 block height at the start of a coinbase's script_sig, or
+    /// `None` for other transactions.
+    pub fn coinbase_height(&self) -> Result<Option<u32>> {
+        if !self.is_coinbase() {
+            return Ok(None);
+        }
+        let script_sig = &self.tx_ins[0].script_sig;
+        let invalid = || Error::new(ErrorKind::InvalidEncoding, "coinbase script_sig does not start with a height");
+        let height = match script_sig.first() {
+            Some(&OP_0) => 0,
+            Some(&op) if (OP_1..=OP_16).contains(&op) => (op - OP_1 + 1) as u32,
+            Some(&len) if (1..=5).contains(&len) && script_sig.len() > len as usize => {
+                let bytes = &script_sig[1..=len as usize];
+                // Script numbers are signed, heights are not
+                if bytes[bytes.len() - 1] & 0x80 != 0 {
+                    return Err(invalid());
+                }
+                let height = bytes.iter().rev().fold(0u64, |n, &b| n << 8 | b as u64);
+                if height > u32::MAX as u64 {
+                    return Err(invalid());
+                }
+                height as u32
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Some(height))
+    }
+
+    /// Bitcoin Core's `CheckTransaction`: rules that hold whatever the
+    /// chain. At least one input and output, amounts within `MAX_MONEY`, no
+    /// input spent twice, and null outpoints only in a coinbase, whose
+    /// script_sig is 2 to 100 bytes.
+    pub fn check(&self) -> Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidEncoding, msg));
+        if self.tx_ins.is_empty() || self.tx_outs.is_empty() {
+            return invalid(format!("{} inputs and {} outputs", self.tx_ins.len(), self.tx_outs.len()));
+        }
+        let mut total = 0u64;
+        for tx_out in self.tx_outs.iter() {
+            total = total.saturating_add(tx_out.amount);
+            if tx_out.amount > MAX_MONEY || total > MAX_MONEY {
+                return invalid(format!("output amounts above {}", MAX_MONEY));
+            }
+        }
+        let mut outpoints = HashSet::new();
+        for tx_in in self.tx_ins.iter() {
+            if !outpoints.insert((tx_in.prev_tx, tx_in.prev_index)) {
+                return invalid(format!("{}:{} spent twice", encode_hex(&tx_in.prev_tx), tx_in.prev_index));
+            }
+        }
+        if self.is_coinbase() {
+            let len = self.tx_ins[0].script_sig.len();
+            if !(MIN_COINBASE_SCRIPT_SIZE..=MAX_COINBASE_SCRIPT_SIZE).contains(&len) {
+                return invalid(format!("coinbase script_sig of {} bytes", len));
+            }
+        } else if self.tx_ins.iter().any(|tx_in| tx_in.is_null()) {
+            return invalid("null outpoint outside a coinbase".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The height as Bitcoin Core pushes it: OP_0 to OP_16 or a minimal
+/// little-endian script number.
+fn height_script(height: u32) -> Vec<u8> {
+    let mut script = Vec::new();
+    match height {
+        0 => script.push(OP_0),
+        1..=16 => script.push(OP_1 + height as u8 - 1),
+        _ => {
+            let mut bytes: Vec<u8> = height.to_le_bytes().to_vec();
+            while bytes.last() == Some(&0) {
+                bytes.pop();
+            }
+            // Keep the sign bit clear
+            if bytes.last().is_some_and(|b| b & 0x80 != 0) {
+                bytes.push(0);
+            }
+            push(&mut script, &bytes);
+        }
+    }
+    script
+}
+
+#[cfg(test)]
+use super::MapFetcher;
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[test]
+fn coinbase_height_and_checks() {
+    // The coinbase of block 465879, from chapter 9
+    let raw = decode_hex("01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff5e03d71b07254d696e656420627920416e74506f6f6c20626a31312f4542312f4144362f43205914293101fabe6d6d678e2c8c34afc36896e7d9402824ed38e856676ee94bfdb0c6c4bcd8b2e5666a0400000000000000c7270000a5e00e00ffffffff01faf20b58000000001976a914338c84849423992471bffb1a54a8d9b1d69dc28a88ac00000000").unwrap();
+    let coinbase = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    assert!(coinbase.is_coinbase());
+    assert_eq!(coinbase.coinbase_height().unwrap(), Some(465879));
+    coinbase.check().unwrap();
+    assert!(coinbase.verify(&MapFetcher(Vec::new())).unwrap());
+
+    for &height in [0, 1, 16, 17, 127, 128, 255, 256, 465879, 0x7fff_ffff, u32::MAX].iter() {
+        let tx = Tx::new_coinbase(height, &[0xab, 0xcd], vec![TxOut::new(50, vec![0x51])], Network::Mainnet);
+        assert_eq!(tx.coinbase_height().unwrap(), Some(height), "height {}", height);
+        tx.check().unwrap();
+    }
+    assert_eq!(height_script(128), vec![2, 0x80, 0x00]);
+
+    let mut spend = coinbase.clone();
+    spend.tx_ins[0].prev_index = 0;
+    assert_eq!(spend.coinbase_height().unwrap(), None);
+    spend.check().unwrap();
+    spend.tx_ins.push(spend.tx_ins[0].clone());
+    assert!(spend.check().is_err());
+    spend.tx_ins[1] = coinbase.tx_ins[0].clone();
+    assert!(spend.check().is_err());
+
+    let mut bad = coinbase.clone();
+    bad.tx_ins[0].script_sig = vec![0x51];
+    assert!(bad.check().is_err());
+    bad.tx_ins[0].script_sig = vec![0x6a, 0x51];
+    assert_eq!(bad.coinbase_height().unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    bad.tx_ins[0].script_sig = vec![0x01, 0x80];
+    assert!(bad.coinbase_height().is_err());
+    bad.tx_outs[0].amount = MAX_MONEY + 1;
+    assert!(bad.check().is_err());
+}
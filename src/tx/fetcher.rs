@@ -0,0 +1,220 @@
+//! Looking up previous transactions.
+//!
+//! Fees and input validation need the outputs being spent, which live in
+//! other transactions. `TxFetcher` abstracts where those come from: a
+//! block explorer (`HttpFetcher`, with the `http` feature), files on disk
+//! (`FileCache`), and an in-memory LRU (`CachedFetcher`) in front of
+//! either.
+
+use super::Tx;
+use crate::encoding::util::{decode_hex, encode_hex};
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::Network;
+use lru::LruCache;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub trait TxFetcher {
+    /// Fetches the transaction with id `txid` (display order).
+    fn fetch(&self, txid: &[u8; 32], network: Network) -> Result<Tx>;
+}
+
+impl<F: TxFetcher + ?Sized> TxFetcher for Box<F> {
+    fn fetch(&self, txid: &[u8; 32], network: Network) -> Result<Tx> {
+        (**self).fetch(txid, network)
+    }
+}
+
+/// Parses raw hex and checks that it really is the requested transaction.
+fn parse_checked(raw_hex: &str, txid: &[u8; 32], network: Network) -> Result<Tx> {
+    let raw = decode_hex(raw_hex.trim())?;
+    let tx = Tx::parse(&mut &raw[..], network)?;
+    if tx.hash() != *txid {
+        return Err(Error::new(ErrorKind::Fetch, format!("got {} instead of {}", tx.id(), encode_hex(txid))));
+    }
+    Ok(tx)
+}
+
+/// Fetches raw transactions from an Esplora style API such as
+/// mempool.space or blockstream.info.
+#[cfg(feature = "http")]
+pub struct HttpFetcher {
+    mainnet_url: String,
+    testnet_url: String,
+    signet_url: Option<String>,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "http")]
+impl HttpFetcher {
+    pub fn new<S: Into<String>>(mainnet_url: S, testnet_url: S) -> HttpFetcher {
+        HttpFetcher { mainnet_url: mainnet_url.into(), testnet_url: testnet_url.into(), signet_url: None, agent: ureq::Agent::new() }
+    }
+
+    /// Sends requests through a proxy such as `socks5://127.0.0.1:9050`,
+    /// which is Tor's.
+    pub fn with_proxy(mut self, proxy_url: &str) -> Result<HttpFetcher> {
+        let proxy = ureq::Proxy::new(proxy_url).map_err(|e| Error::new(ErrorKind::Fetch, format!("{}: {}", proxy_url, e)))?;
+        self.agent = ureq::AgentBuilder::new().proxy(proxy).build();
+        Ok(self)
+    }
+
+    /// Also fetches signet transactions, from `signet_url`.
+    pub fn with_signet_url<S: Into<String>>(mut self, signet_url: S) -> HttpFetcher {
+        self.signet_url = Some(signet_url.into());
+        self
+    }
+
+    pub fn mempool_space() -> HttpFetcher {
+        HttpFetcher::new("https://mempool.space/api", "https://mempool.space/testnet/api").with_signet_url("https://mempool.space/signet/api")
+    }
+
+    pub fn blockstream() -> HttpFetcher {
+        HttpFetcher::new("https://blockstream.info/api", "https://blockstream.info/testnet/api")
+    }
+}
+
+#[cfg(feature = "http")]
+impl TxFetcher for HttpFetcher {
+    fn fetch(&self, txid: &[u8; 32], network: Network) -> Result<Tx> {
+        let base = match network {
+            Network::Mainnet => &self.mainnet_url,
+            Network::Testnet => &self.testnet_url,
+            Network::Signet => self.signet_url.as_ref().ok_or_else(|| Error::new(ErrorKind::Fetch, "no signet explorer configured"))?,
+            Network::Regtest => return Err(Error::new(ErrorKind::Fetch, "regtest has no block explorer")),
+        };
+        let url = format!("{}/tx/{}/hex", base, encode_hex(txid));
+        let body = self.agent.get(&url)
+            .call()
+            .map_err(|e| Error::new(ErrorKind::Fetch, format!("{}: {}", url, e)))?
+            .into_string()?;
+        parse_checked(&body, txid, network)
+    }
+}
+
+/// Raw transactions stored as `<dir>/<txid>.hex`. Without an upstream
+/// fetcher it works fully offline; with one, misses are fetched and
+/// written to the directory for next time.
+pub struct FileCache {
+    dir: PathBuf,
+    upstream: Option<Box<dyn TxFetcher + Send + Sync>>,
+}
+
+impl FileCache {
+    pub fn offline<P: Into<PathBuf>>(dir: P) -> FileCache {
+        FileCache { dir: dir.into(), upstream: None }
+    }
+
+    pub fn with_upstream<P: Into<PathBuf>>(dir: P, upstream: Box<dyn TxFetcher + Send + Sync>) -> FileCache {
+        FileCache { dir: dir.into(), upstream: Some(upstream) }
+    }
+
+    fn path(&self, txid: &[u8; 32]) -> PathBuf {
+        self.dir.join(format!("{}.hex", encode_hex(txid)))
+    }
+
+    pub fn store(&self, tx: &Tx) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(&tx.hash()), encode_hex(&tx.serialize()))?;
+        Ok(())
+    }
+}
+
+impl TxFetcher for FileCache {
+    fn fetch(&self, txid: &[u8; 32], network: Network) -> Result<Tx> {
+        match fs::read_to_string(self.path(txid)) {
+            Ok(raw_hex) => parse_checked(&raw_hex, txid, network),
+            Err(_) => match self.upstream {
+                Some(ref upstream) => {
+                    let tx = upstream.fetch(txid, network)?;
+                    self.store(&tx)?;
+                    Ok(tx)
+                }
+                None => Err(Error::new(ErrorKind::Fetch, format!("{} is not in {}", encode_hex(txid), self.dir.display()))),
+            },
+        }
+    }
+}
+
+/// Keeps the most recently fetched transactions in memory.
+pub struct CachedFetcher<F: TxFetcher> {
+    inner: F,
+    cache: Mutex<LruCache<([u8; 32], Network), Tx>>,
+}
+
+impl<F: TxFetcher> CachedFetcher<F> {
+    pub fn new(inner: F, capacity: usize) -> CachedFetcher<F> {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        CachedFetcher { inner, cache: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+}
+
+impl<F: TxFetcher> TxFetcher for CachedFetcher<F> {
+    fn fetch(&self, txid: &[u8; 32], network: Network) -> Result<Tx> {
+        if let Some(tx) = self.cache.lock().unwrap().get(&(*txid, network)) {
+            return Ok(tx.clone());
+        }
+        let tx = self.inner.fetch(txid, network)?;
+        self.cache.lock().unwrap().put((*txid, network), tx.clone());
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+struct CountingFetcher {
+    tx: Tx,
+    calls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl TxFetcher for CountingFetcher {
+    fn fetch(&self, txid: &[u8; 32], _network: Network) -> Result<Tx> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if *txid == self.tx.hash() {
+            Ok(self.tx.clone())
+        } else {
+            Err(Error::new(ErrorKind::Fetch, "unknown transaction"))
+        }
+    }
+}
+
+#[cfg(test)]
+fn sample_tx() -> Tx {
+    let raw = decode_hex(super::CH5_TX).unwrap();
+    Tx::parse(&mut &raw[..], Network::Mainnet).unwrap()
+}
+
+#[test]
+fn cached_fetcher_hits_inner_once() {
+    let tx = sample_tx();
+    let fetcher = CachedFetcher::new(CountingFetcher { tx: tx.clone(), calls: Default::default() }, 4);
+    assert_eq!(fetcher.fetch(&tx.hash(), Network::Mainnet).unwrap(), tx);
+    assert_eq!(fetcher.fetch(&tx.hash(), Network::Mainnet).unwrap(), tx);
+    assert_eq!(fetcher.inner().calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert!(fetcher.fetch(&[0; 32], Network::Mainnet).is_err());
+}
+
+#[test]
+fn file_cache_offline_and_upstream() {
+    let dir = std::env::temp_dir().join(format!("prog_btc_book_fetcher_{}", std::process::id()));
+    let tx = sample_tx();
+
+    let offline = FileCache::offline(&dir);
+    assert_eq!(offline.fetch(&tx.hash(), Network::Mainnet).unwrap_err().kind(), ErrorKind::Fetch);
+
+    let upstream = CountingFetcher { tx: tx.clone(), calls: Default::default() };
+    let caching = FileCache::with_upstream(&dir, Box::new(upstream));
+    assert_eq!(caching.fetch(&tx.hash(), Network::Mainnet).unwrap(), tx);
+    // Written through, so the offline cache has it now
+    assert_eq!(offline.fetch(&tx.hash(), Network::Mainnet).unwrap(), tx);
+
+    fs::write(offline.path(&[1; 32]), encode_hex(&tx.serialize())).unwrap();
+    assert!(offline.fetch(&[1; 32], Network::Mainnet).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
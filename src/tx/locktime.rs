@@ -0,0 +1,270 @@
+//! Absolute and relative timelocks.
+//!
+//! A transaction's locktime keeps it out of blocks until a height or a
+//! median time past (BIP113) is reached, unless every input is final. An
+//! input's sequence can lock it relative to the block confirming the coin
+//! it spends (BIP68) and signals replaceability (BIP125).
+
+use super::Tx;
+use crate::error::{Error, ErrorKind, Result};
+use std::fmt;
+
+/// Locktimes below this are block heights, the rest Unix times.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// BIP68 sequence fields
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_VALUE_MASK: u32 = 0xffff;
+const SEQUENCE_GRANULARITY: u32 = 9;
+
+/// A transaction's `nLockTime`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct LockTime(u32);
+
+impl LockTime {
+    /// No lock.
+    pub const ZERO: LockTime = LockTime(0);
+
+    /// Any value, a height below `LOCKTIME_THRESHOLD` and a time above.
+    pub fn from_consensus(value: u32) -> LockTime {
+        LockTime(value)
+    }
+
+    pub fn from_height(height: u32) -> Result<LockTime> {
+        if height >= LOCKTIME_THRESHOLD {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("locktime height {} is a time", height)));
+        }
+        Ok(LockTime(height))
+    }
+
+    /// A Unix time, compared against median time past.
+    pub fn from_time(time: u32) -> Result<LockTime> {
+        if time < LOCKTIME_THRESHOLD {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("locktime time {} is a height", time)));
+        }
+        Ok(LockTime(time))
+    }
+
+    pub fn to_consensus_u32(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_block_height(self) -> bool {
+        self.0 < LOCKTIME_THRESHOLD
+    }
+
+    pub fn is_block_time(self) -> bool {
+        !self.is_block_height()
+    }
+
+    /// Whether a block at `height` with median time past `mtp` may hold a
+    /// transaction with this locktime, ignoring its sequences.
+    pub fn is_satisfied_by(self, height: u32, mtp: u32) -> bool {
+        self.0 < if self.is_block_height() { height } else { mtp }
+    }
+
+    /// BIP65: whether a transaction with this locktime meets the `lock`
+    /// an OP_CHECKLOCKTIMEVERIFY asks for, a height or time like it.
+    pub fn satisfies(self, lock: LockTime) -> bool {
+        self.is_block_height() == lock.is_block_height() && lock.0 <= self.0
+    }
+}
+
+impl fmt::Display for LockTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An input's `nSequence`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sequence(u32);
+
+/// A BIP68 relative lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RelativeLock {
+    Blocks(u16),
+    /// In units of 512 seconds
+    Time(u16),
+}
+
+impl Sequence {
+    /// Final: no relative lock, no replaceability, and the transaction's
+    /// locktime ignored if every input has it.
+    pub const MAX: Sequence = Sequence(0xffff_ffff);
+    /// Enables the locktime but no relative lock.
+    pub const ENABLE_LOCKTIME_NO_RBF: Sequence = Sequence(0xffff_fffe);
+    /// The highest sequence signalling replaceability.
+    pub const ENABLE_RBF_NO_LOCKTIME: Sequence = Sequence(0xffff_fffd);
+    pub const ZERO: Sequence = Sequence(0);
+
+    pub fn from_consensus(value: u32) -> Sequence {
+        Sequence(value)
+    }
+
+    /// Locks the input until the spent coin has `blocks` confirmations.
+    pub fn from_height(blocks: u16) -> Sequence {
+        Sequence(blocks as u32)
+    }
+
+    /// Locks the input for `intervals` times 512 seconds of median time
+    /// past after the spent coin's block.
+    pub fn from_512_second_intervals(intervals: u16) -> Sequence {
+        Sequence(SEQUENCE_TYPE_FLAG | intervals as u32)
+    }
+
+    /// A time lock of at least `seconds`.
+    pub fn from_seconds_ceil(seconds: u32) -> Result<Sequence> {
+        let intervals = seconds.div_ceil(1 << SEQUENCE_GRANULARITY);
+        if intervals > SEQUENCE_VALUE_MASK {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("relative lock of {} seconds", seconds)));
+        }
+        Ok(Sequence::from_512_second_intervals(intervals as u16))
+    }
+
+    pub fn to_consensus_u32(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_final(self) -> bool {
+        self == Sequence::MAX
+    }
+
+    /// BIP125: below `ENABLE_LOCKTIME_NO_RBF`.
+    pub fn signals_rbf(self) -> bool {
+        self.0 <= Sequence::ENABLE_RBF_NO_LOCKTIME.0
+    }
+
+    /// The relative lock, if the disable flag is clear. It only applies to
+    /// transactions of version 2 or more.
+    pub fn relative_lock(self) -> Option<RelativeLock> {
+        if self.0 & SEQUENCE_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = (self.0 & SEQUENCE_VALUE_MASK) as u16;
+        Some(if self.0 & SEQUENCE_TYPE_FLAG != 0 { RelativeLock::Time(value) } else { RelativeLock::Blocks(value) })
+    }
+
+    /// BIP112: whether an input with this sequence, in a transaction of
+    /// `version`, meets the relative `lock` an OP_CHECKSEQUENCEVERIFY asks
+    /// for. A `lock` with the disable flag set is always met.
+    pub fn satisfies(self, lock: Sequence, version: u32) -> bool {
+        let (own, lock) = match (self.relative_lock(), lock.relative_lock()) {
+            (_, None) => return true,
+            (Some(own), Some(lock)) if version >= 2 => (own, lock),
+            _ => return false,
+        };
+        match (own, lock) {
+            (RelativeLock::Blocks(own), RelativeLock::Blocks(lock)) | (RelativeLock::Time(own), RelativeLock::Time(lock)) => lock <= own,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Sequence {
+        Sequence::MAX
+    }
+}
+
+impl fmt::Display for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Tx {
+    /// Whether a block at `height` with median time past `mtp` may hold this
+    /// transaction as far as its locktime goes: no lock, a lock already
+    /// passed, or every input final.
+    pub fn is_final_at(&self, height: u32, mtp: u32) -> bool {
+        self.locktime == LockTime::ZERO
+            || self.locktime.is_satisfied_by(height, mtp)
+            || self.tx_ins.iter().all(|tx_in| tx_in.sequence.is_final())
+    }
+
+    /// Whether the BIP68 relative locks are met in a block at `height`
+    /// whose parent has median time past `mtp`. `spent_at` holds, for each
+    /// input, the height of the block confirming the coin it spends and the
+    /// median time past of that block's parent.
+    pub fn sequence_locks_satisfied(&self, spent_at: &[(u32, u32)], height: u32, mtp: u32) -> Result<bool> {
+        if spent_at.len() != self.tx_ins.len() {
+            return Err(Error::new(ErrorKind::OutOfRange,
+                format!("{} confirmations for {} inputs", spent_at.len(), self.tx_ins.len())));
+        }
+        if self.version < 2 {
+            return Ok(true);
+        }
+        for (tx_in, &(coin_height, coin_mtp)) in self.tx_ins.iter().zip(spent_at.iter()) {
+            let satisfied = match tx_in.sequence.relative_lock() {
+                None => true,
+                Some(RelativeLock::Blocks(blocks)) => coin_height as u64 + blocks as u64 <= height as u64,
+                Some(RelativeLock::Time(intervals)) => {
+                    coin_mtp as u64 + ((intervals as u64) << SEQUENCE_GRANULARITY) <= mtp as u64
+                }
+            };
+            if !satisfied {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+use super::{TxIn, TxOut};
+#[cfg(test)]
+use crate::params::Network;
+
+#[test]
+fn locktime_and_sequence() {
+    assert!(LockTime::from_height(800_000).unwrap().is_block_height());
+    assert!(LockTime::from_time(1_700_000_000).unwrap().is_block_time());
+    assert!(LockTime::from_height(LOCKTIME_THRESHOLD).is_err());
+    assert!(LockTime::from_time(800_000).is_err());
+
+    assert_eq!(Sequence::from_height(10).relative_lock(), Some(RelativeLock::Blocks(10)));
+    assert_eq!(Sequence::from_seconds_ceil(1025).unwrap().relative_lock(), Some(RelativeLock::Time(3)));
+    assert_eq!(Sequence::from_seconds_ceil(1025).unwrap().to_consensus_u32(), 0x0040_0003);
+    assert!(Sequence::from_seconds_ceil(512 * 65536).is_err());
+    assert_eq!(Sequence::MAX.relative_lock(), None);
+    assert_eq!(Sequence::ENABLE_RBF_NO_LOCKTIME.relative_lock(), None);
+    assert!(Sequence::ENABLE_RBF_NO_LOCKTIME.signals_rbf() && !Sequence::ENABLE_LOCKTIME_NO_RBF.signals_rbf());
+
+    let height = LockTime::from_height(800_000).unwrap();
+    assert!(height.satisfies(LockTime::from_height(800_000).unwrap()) && !height.satisfies(LockTime::from_height(800_001).unwrap()));
+    assert!(!LockTime::from_time(1_700_000_000).unwrap().satisfies(height));
+    assert!(Sequence::from_height(10).satisfies(Sequence::from_height(10), 2));
+    assert!(!Sequence::from_height(9).satisfies(Sequence::from_height(10), 2));
+    assert!(!Sequence::from_height(10).satisfies(Sequence::from_height(10), 1));
+    assert!(!Sequence::from_512_second_intervals(10).satisfies(Sequence::from_height(1), 2));
+    assert!(!Sequence::MAX.satisfies(Sequence::from_height(1), 2));
+    assert!(Sequence::MAX.satisfies(Sequence::MAX, 1));
+}
+
+#[test]
+fn tx_finality_and_sequence_locks() {
+    let mut tx = Tx::new(2, vec![TxIn::new([1; 32], 0)], vec![TxOut::new(1_000, vec![0x51])], LockTime::ZERO, Network::Mainnet);
+    assert!(tx.is_final_at(0, 0));
+    tx.locktime = LockTime::from_height(800_000).unwrap();
+    assert!(tx.is_final_at(800_001, 0));
+    assert!(tx.is_final_at(800_000, 0));
+    tx.tx_ins[0].sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+    assert!(!tx.is_final_at(800_000, 0));
+    assert!(tx.is_final_at(800_001, 0));
+    tx.locktime = LockTime::from_time(1_700_000_000).unwrap();
+    assert!(!tx.is_final_at(900_000, 1_700_000_000));
+    assert!(tx.is_final_at(0, 1_700_000_001));
+
+    tx.tx_ins[0].sequence = Sequence::from_height(10);
+    assert!(!tx.sequence_locks_satisfied(&[(100, 0)], 109, 0).unwrap());
+    assert!(tx.sequence_locks_satisfied(&[(100, 0)], 110, 0).unwrap());
+    tx.tx_ins[0].sequence = Sequence::from_512_second_intervals(2);
+    assert!(!tx.sequence_locks_satisfied(&[(100, 1_000_000)], 200, 1_001_023).unwrap());
+    assert!(tx.sequence_locks_satisfied(&[(100, 1_000_000)], 200, 1_001_024).unwrap());
+    assert!(tx.sequence_locks_satisfied(&[], 200, 0).is_err());
+    // BIP68 does not apply to version 1
+    tx.version = 1;
+    assert!(tx.sequence_locks_satisfied(&[(100, 1_000_000)], 101, 1_000_000).unwrap());
+}
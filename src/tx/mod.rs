@@ -0,0 +1,433 @@
+//! Transactions (chapter 5).
+
+pub mod amount;
+pub mod builder;
+pub mod coin_selection;
+pub mod coinbase;
+pub mod fetcher;
+pub mod locktime;
+pub mod psbt;
+pub mod rbf;
+pub mod sighash;
+mod sign;
+pub mod stream;
+pub mod taproot;
+
+pub use amount::{Amount, FiatRate, FixedRates, FormatOptions, RateProvider, Unit};
+pub use locktime::{LockTime, RelativeLock, Sequence};
+pub use sighash::{Bip143Hashes, Bip341Hashes, SighashType, TapSighashType};
+pub use sign::{p2pk_script, p2pkh_script, p2tr_script, p2wpkh_script, p2wsh_script};
+
+use crate::encoding::util::{encode_hex, read_bytes, read_u32_le, read_u64_le, to_reversed_hex};
+use crate::encoding::varint::{encode_varint, read_varint_len};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use crate::params::Network;
+use fetcher::TxFetcher;
+use std::fmt;
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tx {
+    pub version: u32,
+    pub tx_ins: Vec<TxIn>,
+    pub tx_outs: Vec<TxOut>,
+    pub locktime: LockTime,
+    pub network: Network,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TxIn {
+    /// Id of the transaction being spent, in display (big-endian) order
+    pub prev_tx: [u8; 32],
+    pub prev_index: u32,
+    pub script_sig: Vec<u8>,
+    pub sequence: Sequence,
+    /// Segwit witness stack, empty for legacy inputs
+    pub witness: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TxOut {
+    /// Amount in satoshis
+    pub amount: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+const SEGWIT_MARKER: u8 = 0x00;
+const SEGWIT_FLAG: u8 = 0x01;
+
+fn read_script<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_varint_len(reader)?;
+    read_bytes(reader, len)
+}
+
+fn write_script<W: Write>(writer: &mut W, script: &[u8]) -> Result<()> {
+    encode_varint(writer, script.len() as u64)?;
+    writer.write_all(script)?;
+    Ok(())
+}
+
+impl Tx {
+    pub fn new(version: u32, tx_ins: Vec<TxIn>, tx_outs: Vec<TxOut>, locktime: LockTime, network: Network) -> Tx {
+        Tx { version, tx_ins, tx_outs, locktime, network }
+    }
+
+    /// Parses both legacy and BIP144 segwit serializations.
+    pub fn parse<R: Read>(reader: &mut R, network: Network) -> Result<Tx> {
+        let version = read_u32_le(reader)?;
+        let mut num_inputs = read_varint_len(reader)?;
+        // A zero input count is the segwit marker, followed by the flag
+        let segwit = num_inputs == 0;
+        if segwit {
+            let flag = read_bytes(reader, 1)?[0];
+            if flag != SEGWIT_FLAG {
+                return Err(Error::new(ErrorKind::InvalidEncoding, format!("unknown segwit flag {:#04x}", flag)));
+            }
+            num_inputs = read_varint_len(reader)?;
+        }
+        let mut tx_ins = (0..num_inputs).map(|_| TxIn::parse(reader)).collect::<Result<Vec<_>>>()?;
+        let num_outputs = read_varint_len(reader)?;
+        let tx_outs = (0..num_outputs).map(|_| TxOut::parse(reader)).collect::<Result<Vec<_>>>()?;
+        if segwit {
+            for tx_in in tx_ins.iter_mut() {
+                let num_items = read_varint_len(reader)?;
+                tx_in.witness = (0..num_items).map(|_| read_script(reader)).collect::<Result<Vec<_>>>()?;
+            }
+            if tx_ins.iter().all(|tx_in| tx_in.witness.is_empty()) {
+                return Err(Error::new(ErrorKind::InvalidEncoding, "segwit marker without any witness data"));
+            }
+        }
+        let locktime = LockTime::from_consensus(read_u32_le(reader)?);
+        Ok(Tx { version, tx_ins, tx_outs, locktime, network })
+    }
+
+    /// Whether any input carries witness data.
+    pub fn is_segwit(&self) -> bool {
+        self.tx_ins.iter().any(|tx_in| !tx_in.witness.is_empty())
+    }
+
+    /// The full serialization, in the segwit format if `is_segwit`.
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(self.is_segwit())
+    }
+
+    /// The serialization without witness data, which the txid commits to.
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        self.serialize_with(false)
+    }
+
+    fn serialize_with(&self, witness: bool) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        if witness {
+            result.extend_from_slice(&[SEGWIT_MARKER, SEGWIT_FLAG]);
+        }
+        encode_varint(&mut result, self.tx_ins.len() as u64).unwrap();
+        for tx_in in self.tx_ins.iter() {
+            tx_in.write(&mut result).unwrap();
+        }
+        encode_varint(&mut result, self.tx_outs.len() as u64).unwrap();
+        for tx_out in self.tx_outs.iter() {
+            tx_out.write(&mut result).unwrap();
+        }
+        if witness {
+            for tx_in in self.tx_ins.iter() {
+                encode_varint(&mut result, tx_in.witness.len() as u64).unwrap();
+                for item in tx_in.witness.iter() {
+                    write_script(&mut result, item).unwrap();
+                }
+            }
+        }
+        result.extend_from_slice(&self.locktime.to_consensus_u32().to_le_bytes());
+        result
+    }
+
+    /// Size without witness data.
+    pub fn base_size(&self) -> usize {
+        self.serialize_legacy().len()
+    }
+
+    /// Size of the full serialization, witness data included.
+    pub fn total_size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// BIP141 weight: non-witness bytes count four times, witness bytes once.
+    pub fn weight(&self) -> usize {
+        self.base_size() * 3 + self.total_size()
+    }
+
+    /// Virtual size in vbytes, weight / 4 rounded up.
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
+
+    /// Inputs minus outputs, looking the spent outputs up with `fetcher`.
+    /// Amounts come from the wire, so sums that overflow are
+    /// `ErrorKind::OutOfRange`.
+    pub fn fee<F: TxFetcher + ?Sized>(&self, fetcher: &F) -> Result<u64> {
+        let overflow = |what: &str| Error::new(ErrorKind::OutOfRange, format!("{} of {} overflow", what, self.id()));
+        let mut input_sum = 0u64;
+        for tx_in in self.tx_ins.iter() {
+            input_sum = input_sum.checked_add(tx_in.value(fetcher, self.network)?).ok_or_else(|| overflow("inputs"))?;
+        }
+        let output_sum = self.tx_outs.iter().try_fold(0u64, |sum, o| sum.checked_add(o.amount)).ok_or_else(|| overflow("outputs"))?;
+        input_sum.checked_sub(output_sum).ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("outputs ({}) exceed inputs ({})", output_sum, input_sum))
+        })
+    }
+
+    /// The outputs spent by each input, in input order, as BIP341 signature
+    /// hashes need them.
+    pub fn spent_outputs<F: TxFetcher + ?Sized>(&self, fetcher: &F) -> Result<Vec<TxOut>> {
+        self.tx_ins.iter().map(|tx_in| tx_in.prev_output(fetcher, self.network)).collect()
+    }
+
+    /// Fee rate in satoshis per vbyte.
+    pub fn fee_rate<F: TxFetcher + ?Sized>(&self, fetcher: &F) -> Result<f64> {
+        Ok(self.fee(fetcher)? as f64 / self.vsize() as f64)
+    }
+
+    /// The transaction id in display order.
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hash = hash256(&self.serialize_legacy());
+        hash.reverse();
+        hash
+    }
+
+    /// The transaction id as hex, as shown by block explorers.
+    pub fn id(&self) -> String {
+        to_reversed_hex(&hash256(&self.serialize_legacy()))
+    }
+
+    /// The BIP141 witness transaction id in display order. Equal to
+    /// `hash` for transactions without witness data.
+    pub fn witness_hash(&self) -> [u8; 32] {
+        let mut hash = hash256(&self.serialize());
+        hash.reverse();
+        hash
+    }
+
+    /// The witness transaction id as hex.
+    pub fn wtxid(&self) -> String {
+        to_reversed_hex(&hash256(&self.serialize()))
+    }
+
+    fn input(&self, input_index: usize) -> Result<&TxIn> {
+        self.tx_ins.get(input_index).ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("input {} of {}", input_index, self.tx_ins.len()))
+        })
+    }
+}
+
+impl TxIn {
+    pub fn new(prev_tx: [u8; 32], prev_index: u32) -> TxIn {
+        TxIn { prev_tx, prev_index, script_sig: Vec::new(), sequence: Sequence::MAX, witness: Vec::new() }
+    }
+
+    pub fn parse<R: Read>(reader: &mut R) -> Result<TxIn> {
+        let mut prev_tx = [0u8; 32];
+        reader.read_exact(&mut prev_tx)?;
+        prev_tx.reverse();
+        let prev_index = read_u32_le(reader)?;
+        let script_sig = read_script(reader)?;
+        let sequence = Sequence::from_consensus(read_u32_le(reader)?);
+        Ok(TxIn { prev_tx, prev_index, script_sig, sequence, witness: Vec::new() })
+    }
+
+    /// The output this input spends.
+    pub fn prev_output<F: TxFetcher + ?Sized>(&self, fetcher: &F, network: Network) -> Result<TxOut> {
+        let prev = fetcher.fetch(&self.prev_tx, network)?;
+        prev.tx_outs.get(self.prev_index as usize).cloned().ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("{} has no output {}", prev.id(), self.prev_index))
+        })
+    }
+
+    pub fn value<F: TxFetcher + ?Sized>(&self, fetcher: &F, network: Network) -> Result<u64> {
+        Ok(self.prev_output(fetcher, network)?.amount)
+    }
+
+    pub fn script_pubkey<F: TxFetcher + ?Sized>(&self, fetcher: &F, network: Network) -> Result<Vec<u8>> {
+        Ok(self.prev_output(fetcher, network)?.script_pubkey)
+    }
+
+    /// Writes the input without its witness, which segwit serialization
+    /// puts after the outputs.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut prev_tx = self.prev_tx;
+        prev_tx.reverse();
+        writer.write_all(&prev_tx)?;
+        writer.write_all(&self.prev_index.to_le_bytes())?;
+        write_script(writer, &self.script_sig)?;
+        writer.write_all(&self.sequence.to_consensus_u32().to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl TxOut {
+    pub fn new(amount: u64, script_pubkey: Vec<u8>) -> TxOut {
+        TxOut { amount, script_pubkey }
+    }
+
+    pub fn parse<R: Read>(reader: &mut R) -> Result<TxOut> {
+        let amount = read_u64_le(reader)?;
+        let script_pubkey = read_script(reader)?;
+        Ok(TxOut { amount, script_pubkey })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.amount.to_le_bytes())?;
+        write_script(writer, &self.script_pubkey)
+    }
+}
+
+// BTC with eight decimals, like bitcoind's JSON
+fn format_btc(amount: u64) -> String {
+    format!("{}.{:08}", amount / 100_000_000, amount % 100_000_000)
+}
+
+/// Renders like `bitcoin-cli decoderawtransaction`.
+impl fmt::Display for Tx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{{")?;
+        writeln!(f, "  \"txid\": \"{}\",", self.id())?;
+        writeln!(f, "  \"hash\": \"{}\",", self.wtxid())?;
+        writeln!(f, "  \"version\": {},", self.version)?;
+        writeln!(f, "  \"size\": {},", self.total_size())?;
+        writeln!(f, "  \"vsize\": {},", self.vsize())?;
+        writeln!(f, "  \"weight\": {},", self.weight())?;
+        writeln!(f, "  \"locktime\": {},", self.locktime)?;
+        writeln!(f, "  \"vin\": [")?;
+        for (i, tx_in) in self.tx_ins.iter().enumerate() {
+            writeln!(f, "    {{")?;
+            writeln!(f, "      \"txid\": \"{}\",", encode_hex(&tx_in.prev_tx))?;
+            writeln!(f, "      \"vout\": {},", tx_in.prev_index)?;
+            writeln!(f, "      \"scriptSig\": {{")?;
+            writeln!(f, "        \"hex\": \"{}\"", encode_hex(&tx_in.script_sig))?;
+            writeln!(f, "      }},")?;
+            if !tx_in.witness.is_empty() {
+                let items: Vec<String> = tx_in.witness.iter().map(|item| format!("\"{}\"", encode_hex(item))).collect();
+                writeln!(f, "      \"txinwitness\": [{}],", items.join(", "))?;
+            }
+            writeln!(f, "      \"sequence\": {}", tx_in.sequence)?;
+            writeln!(f, "    }}{}", if i + 1 < self.tx_ins.len() { "," } else { "" })?;
+        }
+        writeln!(f, "  ],")?;
+        writeln!(f, "  \"vout\": [")?;
+        for (i, tx_out) in self.tx_outs.iter().enumerate() {
+            writeln!(f, "    {{")?;
+            writeln!(f, "      \"value\": {},", format_btc(tx_out.amount))?;
+            writeln!(f, "      \"n\": {},", i)?;
+            writeln!(f, "      \"scriptPubKey\": {{")?;
+            writeln!(f, "        \"hex\": \"{}\"", encode_hex(&tx_out.script_pubkey))?;
+            writeln!(f, "      }}")?;
+            writeln!(f, "    }}{}", if i + 1 < self.tx_outs.len() { "," } else { "" })?;
+        }
+        writeln!(f, "  ]")?;
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+
+#[cfg(test)]
+const CH5_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+#[test]
+fn tx_parse() {
+    let raw = decode_hex(CH5_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    assert_eq!(tx.version, 1);
+    assert_eq!(tx.tx_ins.len(), 1);
+    assert_eq!(encode_hex(&tx.tx_ins[0].prev_tx), "d1c789a9c60383bf715f3f6ad9d14b91fe55f3deb369fe5d9280cb1a01793f81");
+    assert_eq!(tx.tx_ins[0].prev_index, 0);
+    assert_eq!(tx.tx_ins[0].script_sig.len(), 0x6b);
+    assert_eq!(tx.tx_ins[0].sequence, Sequence::ENABLE_LOCKTIME_NO_RBF);
+    assert_eq!(tx.tx_outs.iter().map(|o| o.amount).collect::<Vec<_>>(), vec![32454049, 10011545]);
+    assert_eq!(encode_hex(&tx.tx_outs[1].script_pubkey), "76a9141c4bc762dd5423e332166702cb75f40df79fea1288ac");
+    assert_eq!(tx.locktime, LockTime::from_height(410393).unwrap());
+
+    assert_eq!(tx.serialize(), raw);
+    assert_eq!(tx.id(), "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03");
+    assert_eq!(encode_hex(&tx.hash()), tx.id());
+    assert!(tx.to_string().contains("\"value\": 0.32454049,"));
+    assert!(Tx::parse(&mut &raw[..100], Network::Mainnet).is_err());
+}
+
+#[test]
+fn tx_parse_segwit() {
+    // From rust-bitcoin's segwit_transaction test
+    let hex = "02000000000101595895ea20179de87052b4046dfe6fd515860505d6511a9004cf12a1f93cac7c0100000000ffffffff01deb807000000000017a9140f3444e271620c736808aa7b33e370bd87cb5a078702483045022100fb60dad8df4af2841adc0346638c16d0b8035f5e3f3753b88db122e70c79f9370220756e6633b17fd2710e626347d28d60b0a2d6cbb41de51740644b9fb3ba7751040121028fa937ca8cba2197a37c007176ed8941055d3bcb8627d085e94553e62f057dcc00000000";
+    let raw = decode_hex(hex).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    assert!(tx.is_segwit());
+    assert_eq!(tx.tx_ins[0].script_sig.len(), 0);
+    assert_eq!(tx.tx_ins[0].witness.len(), 2);
+    assert_eq!(tx.tx_ins[0].witness[1].len(), 33);
+    assert_eq!(tx.serialize(), raw);
+    assert_eq!(tx.id(), "f5864806e3565c34d1b41e716f72609d00b55ea5eac5b924c9719a842ef42206");
+    assert_eq!(tx.wtxid(), "80b7d8a82d5d5bf92905b06f2014dd699e03837ca172e3a59d51426ebbe3e7f5");
+    assert_eq!(encode_hex(&tx.witness_hash()), tx.wtxid());
+    assert_eq!(tx.total_size(), raw.len());
+    assert_eq!(tx.weight(), 442);
+    assert_eq!(tx.vsize(), 111);
+    assert!(tx.to_string().contains("\"weight\": 442,"));
+
+    // Without witnesses it goes back to the legacy format
+    let mut stripped = tx.clone();
+    stripped.tx_ins[0].witness.clear();
+    assert_eq!(stripped.serialize(), tx.serialize_legacy());
+    assert_eq!(stripped.wtxid(), tx.id());
+
+    // Marker and flag with no witness data, and an unknown flag
+    let mut empty_witness = tx.serialize_legacy();
+    empty_witness.splice(4..4, vec![0, 1]);
+    let at = empty_witness.len() - 4;
+    empty_witness.insert(at, 0);
+    assert_eq!(Tx::parse(&mut &empty_witness[..], Network::Mainnet).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    let mut bad_flag = raw.clone();
+    bad_flag[5] = 2;
+    assert_eq!(Tx::parse(&mut &bad_flag[..], Network::Mainnet).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+}
+
+#[cfg(test)]
+struct MapFetcher(Vec<Tx>);
+
+#[cfg(test)]
+impl TxFetcher for MapFetcher {
+    fn fetch(&self, txid: &[u8; 32], _network: Network) -> Result<Tx> {
+        self.0.iter().find(|tx| tx.hash() == *txid).cloned()
+            .ok_or_else(|| Error::new(ErrorKind::Fetch, encode_hex(txid)))
+    }
+}
+
+#[test]
+fn tx_fee_and_weight() {
+    let raw = decode_hex(CH5_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    assert_eq!(tx.weight(), 226 * 4);
+    assert_eq!(tx.vsize(), 226);
+
+    // A made up parent paying 0.5 BTC to the output being spent
+    let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(50_000_000, vec![])], LockTime::ZERO, Network::Mainnet);
+    let mut child = tx.clone();
+    child.tx_ins[0].prev_tx = parent.hash();
+    let fetcher = MapFetcher(vec![parent]);
+    assert_eq!(child.fee(&fetcher).unwrap(), 50_000_000 - 32454049 - 10011545);
+    assert!((child.fee_rate(&fetcher).unwrap() - 7534406.0 / 226.0).abs() < 1e-9);
+
+    child.tx_ins[0].prev_index = 1;
+    assert_eq!(child.fee(&fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(tx.fee(&fetcher).unwrap_err().kind(), ErrorKind::Fetch);
+
+    // Outputs and inputs whose sums don't fit in a u64
+    child.tx_ins[0].prev_index = 0;
+    child.tx_outs = vec![TxOut::new(u64::MAX, vec![]), TxOut::new(u64::MAX, vec![])];
+    assert_eq!(child.fee(&fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+    let rich = Tx::new(1, vec![TxIn::new([8; 32], 0)], vec![TxOut::new(u64::MAX, vec![])], LockTime::ZERO, Network::Mainnet);
+    let fetcher = MapFetcher(vec![rich.clone()]);
+    let spend = Tx::new(1, vec![TxIn::new(rich.hash(), 0), TxIn::new(rich.hash(), 0)], vec![], LockTime::ZERO, Network::Mainnet);
+    assert_eq!(spend.fee(&fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
+
@@ -0,0 +1,926 @@
+//! Partially signed transactions (BIP174).
+//!
+//! A PSBT carries an unsigned transaction plus, per input and output, the
+//! key-value maps that let independent parties fill in what they know: the
+//! creator builds it, updaters add the outputs being spent and scripts,
+//! signers add signatures, the finalizer turns those into script_sigs and
+//! witnesses, and the extractor produces the network transaction.
+//!
+//! Only version 0 PSBTs are supported. Fields this module does not know
+//! about, including BIP371 taproot script-path fields and proprietary ones,
+//! are kept as raw pairs and written back unchanged.
+
+use super::fetcher::TxFetcher;
+use super::sign::{classify, p2pkh_script, push, Spend};
+use super::{SighashType, TapSighashType, Tx, TxOut};
+use crate::encoding::util::{encode_hex, read_u32_le};
+use crate::encoding::varint::{encode_varint, read_varint, read_varint_len};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{hash160, sha256};
+use crate::math::ecc::{PrivateKey, SchnorrSignature};
+use crate::params::Network;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+/// "psbt" followed by 0xff
+pub const PSBT_MAGIC: [u8; 5] = *b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u64 = 0x00;
+const PSBT_GLOBAL_XPUB: u64 = 0x01;
+const PSBT_GLOBAL_VERSION: u64 = 0xfb;
+
+const PSBT_IN_NON_WITNESS_UTXO: u64 = 0x00;
+const PSBT_IN_WITNESS_UTXO: u64 = 0x01;
+const PSBT_IN_PARTIAL_SIG: u64 = 0x02;
+const PSBT_IN_SIGHASH_TYPE: u64 = 0x03;
+const PSBT_IN_REDEEM_SCRIPT: u64 = 0x04;
+const PSBT_IN_WITNESS_SCRIPT: u64 = 0x05;
+const PSBT_IN_BIP32_DERIVATION: u64 = 0x06;
+const PSBT_IN_FINAL_SCRIPTSIG: u64 = 0x07;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u64 = 0x08;
+const PSBT_IN_TAP_KEY_SIG: u64 = 0x13;
+const PSBT_IN_TAP_INTERNAL_KEY: u64 = 0x17;
+const PSBT_IN_TAP_MERKLE_ROOT: u64 = 0x18;
+
+const PSBT_OUT_REDEEM_SCRIPT: u64 = 0x00;
+const PSBT_OUT_WITNESS_SCRIPT: u64 = 0x01;
+const PSBT_OUT_BIP32_DERIVATION: u64 = 0x02;
+const PSBT_OUT_TAP_INTERNAL_KEY: u64 = 0x05;
+
+const OP_HASH160: u8 = 0xa9;
+const OP_EQUAL: u8 = 0x87;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+// OP_1 through OP_16
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+
+/// Where a key came from: the master key fingerprint and derivation path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeySource {
+    pub fingerprint: [u8; 4],
+    pub path: Vec<u32>,
+}
+
+impl KeySource {
+    fn parse(bytes: &[u8]) -> Result<KeySource> {
+        if bytes.len() < 4 || !bytes.len().is_multiple_of(4) {
+            return Err(psbt_error(format!("key origin of {} bytes", bytes.len())));
+        }
+        let mut fingerprint = [0u8; 4];
+        fingerprint.copy_from_slice(&bytes[..4]);
+        let path = bytes[4..].chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+        Ok(KeySource { fingerprint, path })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = self.fingerprint.to_vec();
+        for index in self.path.iter() {
+            out.extend_from_slice(&index.to_le_bytes());
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PsbtInput {
+    /// The whole transaction being spent
+    pub non_witness_utxo: Option<Tx>,
+    /// Just the output being spent, enough for segwit inputs
+    pub witness_utxo: Option<TxOut>,
+    /// `<DER signature || hash type>` by SEC public key
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sighash_type: Option<u32>,
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub final_script_sig: Option<Vec<u8>>,
+    pub final_script_witness: Option<Vec<Vec<u8>>>,
+    /// Key-path Schnorr signature, with the hash type unless DEFAULT
+    pub tap_key_sig: Option<Vec<u8>>,
+    pub tap_internal_key: Option<[u8; 32]>,
+    pub tap_merkle_root: Option<[u8; 32]>,
+    /// Raw key (type and key data) to value for everything else
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PsbtOutput {
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub tap_internal_key: Option<[u8; 32]>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Psbt {
+    /// Parsed as a mainnet transaction, PSBTs do not record the network
+    pub unsigned_tx: Tx,
+    /// Serialized extended public key to its origin
+    pub xpubs: BTreeMap<Vec<u8>, KeySource>,
+    pub version: u32,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub inputs: Vec<PsbtInput>,
+    pub outputs: Vec<PsbtOutput>,
+}
+
+fn psbt_error<S: Into<String>>(msg: S) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, msg)
+}
+
+// A map entry: the key type, the key data after it and the value
+type Pair = (u64, Vec<u8>, Vec<u8>);
+
+/// Reads key-value pairs up to the 0x00 separator, rejecting duplicates.
+fn read_map<R: Read>(reader: &mut R) -> Result<Vec<Pair>> {
+    let mut pairs: Vec<Pair> = Vec::new();
+    let mut seen = std::collections::BTreeSet::new();
+    loop {
+        let key_len = read_varint_len(reader)?;
+        if key_len == 0 {
+            return Ok(pairs);
+        }
+        let mut key = vec![0u8; key_len];
+        reader.read_exact(&mut key)?;
+        let mut value = vec![0u8; read_varint_len(reader)?];
+        reader.read_exact(&mut value)?;
+        if !seen.insert(key.clone()) {
+            return Err(psbt_error(format!("duplicate key {}", encode_hex(&key))));
+        }
+        let key_reader = &mut &key[..];
+        let key_type = read_varint(key_reader)?;
+        pairs.push((key_type, key_reader.to_vec(), value));
+    }
+}
+
+/// Writes `pairs` sorted by key, then the separator.
+fn write_map<W: Write>(writer: &mut W, mut pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+    pairs.sort();
+    for (key, value) in pairs.iter() {
+        encode_varint(writer, key.len() as u64)?;
+        writer.write_all(key)?;
+        encode_varint(writer, value.len() as u64)?;
+        writer.write_all(value)?;
+    }
+    writer.write_all(&[0x00])?;
+    Ok(())
+}
+
+fn key(key_type: u64, key_data: &[u8]) -> Vec<u8> {
+    let mut key = Vec::new();
+    encode_varint(&mut key, key_type).unwrap();
+    key.extend_from_slice(key_data);
+    key
+}
+
+/// Keeps `value` in `field` for a key type that takes no key data.
+fn set_once<T>(field: &mut Option<T>, key_data: &[u8], value: T) -> Result<()> {
+    if !key_data.is_empty() {
+        return Err(psbt_error("unexpected key data"));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+fn hash32(bytes: &[u8]) -> Result<[u8; 32]> {
+    if bytes.len() != 32 {
+        return Err(psbt_error(format!("expected 32 bytes, got {}", bytes.len())));
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(bytes);
+    Ok(hash)
+}
+
+fn sec_key(key_data: &[u8]) -> Result<Vec<u8>> {
+    match key_data.len() {
+        33 | 65 => Ok(key_data.to_vec()),
+        len => Err(psbt_error(format!("public key of {} bytes", len))),
+    }
+}
+
+/// Parses all of `bytes` with `parse`.
+fn parse_all<T, P>(bytes: &[u8], parse: P) -> Result<T>
+where
+    P: FnOnce(&mut &[u8]) -> Result<T>,
+{
+    let reader = &mut &bytes[..];
+    let parsed = parse(reader)?;
+    if !reader.is_empty() {
+        return Err(psbt_error(format!("{} trailing bytes in value", reader.len())));
+    }
+    Ok(parsed)
+}
+
+fn parse_witness(reader: &mut &[u8]) -> Result<Vec<Vec<u8>>> {
+    let count = read_varint_len(reader)?;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let mut item = vec![0u8; read_varint_len(reader)?];
+        reader.read_exact(&mut item)?;
+        items.push(item);
+    }
+    Ok(items)
+}
+
+fn serialize_witness(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_varint(&mut out, items.len() as u64).unwrap();
+    for item in items.iter() {
+        encode_varint(&mut out, item.len() as u64).unwrap();
+        out.extend_from_slice(item);
+    }
+    out
+}
+
+impl PsbtInput {
+    fn parse<R: Read>(reader: &mut R) -> Result<PsbtInput> {
+        let mut input = PsbtInput::default();
+        for (key_type, key_data, value) in read_map(reader)? {
+            match key_type {
+                PSBT_IN_NON_WITNESS_UTXO => {
+                    let tx = parse_all(&value, |r| Tx::parse(r, Network::Mainnet))?;
+                    set_once(&mut input.non_witness_utxo, &key_data, tx)?
+                }
+                PSBT_IN_WITNESS_UTXO => set_once(&mut input.witness_utxo, &key_data, parse_all(&value, |r| TxOut::parse(r))?)?,
+                PSBT_IN_PARTIAL_SIG => {
+                    input.partial_sigs.insert(sec_key(&key_data)?, value);
+                }
+                PSBT_IN_SIGHASH_TYPE => {
+                    let hash_type = parse_all(&value, |r| read_u32_le(r))?;
+                    set_once(&mut input.sighash_type, &key_data, hash_type)?
+                }
+                PSBT_IN_REDEEM_SCRIPT => set_once(&mut input.redeem_script, &key_data, value)?,
+                PSBT_IN_WITNESS_SCRIPT => set_once(&mut input.witness_script, &key_data, value)?,
+                PSBT_IN_BIP32_DERIVATION => {
+                    input.bip32_derivation.insert(sec_key(&key_data)?, KeySource::parse(&value)?);
+                }
+                PSBT_IN_FINAL_SCRIPTSIG => set_once(&mut input.final_script_sig, &key_data, value)?,
+                PSBT_IN_FINAL_SCRIPTWITNESS => {
+                    set_once(&mut input.final_script_witness, &key_data, parse_all(&value, parse_witness)?)?
+                }
+                PSBT_IN_TAP_KEY_SIG => {
+                    if value.len() != 64 && value.len() != 65 {
+                        return Err(psbt_error(format!("taproot key signature of {} bytes", value.len())));
+                    }
+                    set_once(&mut input.tap_key_sig, &key_data, value)?
+                }
+                PSBT_IN_TAP_INTERNAL_KEY => set_once(&mut input.tap_internal_key, &key_data, hash32(&value)?)?,
+                PSBT_IN_TAP_MERKLE_ROOT => set_once(&mut input.tap_merkle_root, &key_data, hash32(&value)?)?,
+                _ => {
+                    input.unknown.insert(key(key_type, &key_data), value);
+                }
+            }
+        }
+        Ok(input)
+    }
+
+    fn pairs(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = self.unknown.clone().into_iter().collect();
+        if let Some(ref tx) = self.non_witness_utxo {
+            pairs.push((key(PSBT_IN_NON_WITNESS_UTXO, &[]), tx.serialize()));
+        }
+        if let Some(ref tx_out) = self.witness_utxo {
+            let mut value = Vec::new();
+            tx_out.write(&mut value)?;
+            pairs.push((key(PSBT_IN_WITNESS_UTXO, &[]), value));
+        }
+        for (sec, sig) in self.partial_sigs.iter() {
+            pairs.push((key(PSBT_IN_PARTIAL_SIG, sec), sig.clone()));
+        }
+        if let Some(hash_type) = self.sighash_type {
+            pairs.push((key(PSBT_IN_SIGHASH_TYPE, &[]), hash_type.to_le_bytes().to_vec()));
+        }
+        if let Some(ref script) = self.redeem_script {
+            pairs.push((key(PSBT_IN_REDEEM_SCRIPT, &[]), script.clone()));
+        }
+        if let Some(ref script) = self.witness_script {
+            pairs.push((key(PSBT_IN_WITNESS_SCRIPT, &[]), script.clone()));
+        }
+        for (sec, source) in self.bip32_derivation.iter() {
+            pairs.push((key(PSBT_IN_BIP32_DERIVATION, sec), source.serialize()));
+        }
+        if let Some(ref script_sig) = self.final_script_sig {
+            pairs.push((key(PSBT_IN_FINAL_SCRIPTSIG, &[]), script_sig.clone()));
+        }
+        if let Some(ref witness) = self.final_script_witness {
+            pairs.push((key(PSBT_IN_FINAL_SCRIPTWITNESS, &[]), serialize_witness(witness)));
+        }
+        if let Some(ref sig) = self.tap_key_sig {
+            pairs.push((key(PSBT_IN_TAP_KEY_SIG, &[]), sig.clone()));
+        }
+        if let Some(internal_key) = self.tap_internal_key {
+            pairs.push((key(PSBT_IN_TAP_INTERNAL_KEY, &[]), internal_key.to_vec()));
+        }
+        if let Some(merkle_root) = self.tap_merkle_root {
+            pairs.push((key(PSBT_IN_TAP_MERKLE_ROOT, &[]), merkle_root.to_vec()));
+        }
+        Ok(pairs)
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.final_script_sig.is_some() || self.final_script_witness.is_some()
+    }
+}
+
+impl PsbtOutput {
+    fn parse<R: Read>(reader: &mut R) -> Result<PsbtOutput> {
+        let mut output = PsbtOutput::default();
+        for (key_type, key_data, value) in read_map(reader)? {
+            match key_type {
+                PSBT_OUT_REDEEM_SCRIPT => set_once(&mut output.redeem_script, &key_data, value)?,
+                PSBT_OUT_WITNESS_SCRIPT => set_once(&mut output.witness_script, &key_data, value)?,
+                PSBT_OUT_BIP32_DERIVATION => {
+                    output.bip32_derivation.insert(sec_key(&key_data)?, KeySource::parse(&value)?);
+                }
+                PSBT_OUT_TAP_INTERNAL_KEY => set_once(&mut output.tap_internal_key, &key_data, hash32(&value)?)?,
+                _ => {
+                    output.unknown.insert(key(key_type, &key_data), value);
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = self.unknown.clone().into_iter().collect();
+        if let Some(ref script) = self.redeem_script {
+            pairs.push((key(PSBT_OUT_REDEEM_SCRIPT, &[]), script.clone()));
+        }
+        if let Some(ref script) = self.witness_script {
+            pairs.push((key(PSBT_OUT_WITNESS_SCRIPT, &[]), script.clone()));
+        }
+        for (sec, source) in self.bip32_derivation.iter() {
+            pairs.push((key(PSBT_OUT_BIP32_DERIVATION, sec), source.serialize()));
+        }
+        if let Some(internal_key) = self.tap_internal_key {
+            pairs.push((key(PSBT_OUT_TAP_INTERNAL_KEY, &[]), internal_key.to_vec()));
+        }
+        pairs
+    }
+}
+
+/// `OP_HASH160 <20 bytes> OP_EQUAL`
+fn p2sh_hash(script_pubkey: &[u8]) -> Option<&[u8]> {
+    match script_pubkey {
+        [OP_HASH160, 20, hash @ .., OP_EQUAL] if hash.len() == 20 => Some(hash),
+        _ => None,
+    }
+}
+
+/// Whether `script` contains `sec` as a pushed key.
+fn mentions(script: &[u8], sec: &[u8]) -> bool {
+    script.windows(sec.len() + 1).any(|w| w[0] as usize == sec.len() && &w[1..] == sec)
+}
+
+/// The threshold and keys of `OP_m <keys> OP_n OP_CHECKMULTISIG`.
+fn multisig(script: &[u8]) -> Option<(usize, Vec<&[u8]>)> {
+    let (&first, mut rest) = script.split_first()?;
+    let (&last, body) = rest.split_last()?;
+    let (&n_op, body) = body.split_last()?;
+    if last != OP_CHECKMULTISIG || !(OP_1..=OP_16).contains(&first) || !(OP_1..=OP_16).contains(&n_op) {
+        return None;
+    }
+    rest = body;
+    let mut keys = Vec::new();
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = len as usize;
+        if (len != 33 && len != 65) || tail.len() < len {
+            return None;
+        }
+        keys.push(&tail[..len]);
+        rest = &tail[len..];
+    }
+    let (m, n) = ((first - OP_1 + 1) as usize, (n_op - OP_1 + 1) as usize);
+    if keys.len() != n || m > n {
+        return None;
+    }
+    Some((m, keys))
+}
+
+fn missing_sigs(input_index: usize) -> Error {
+    Error::new(ErrorKind::InvalidSignature, format!("input {} is missing signatures", input_index))
+}
+
+impl Psbt {
+    /// The creator role: wraps a transaction whose script_sigs and
+    /// witnesses are all empty.
+    pub fn new(unsigned_tx: Tx) -> Result<Psbt> {
+        if unsigned_tx.tx_ins.iter().any(|tx_in| !tx_in.script_sig.is_empty() || !tx_in.witness.is_empty()) {
+            return Err(psbt_error("unsigned transaction has script_sigs or witnesses"));
+        }
+        Ok(Psbt {
+            inputs: vec![PsbtInput::default(); unsigned_tx.tx_ins.len()],
+            outputs: vec![PsbtOutput::default(); unsigned_tx.tx_outs.len()],
+            unsigned_tx,
+            xpubs: BTreeMap::new(),
+            version: 0,
+            unknown: BTreeMap::new(),
+        })
+    }
+
+    pub fn parse<R: Read>(reader: &mut R) -> Result<Psbt> {
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if magic != PSBT_MAGIC {
+            return Err(psbt_error("missing PSBT magic"));
+        }
+        let mut unsigned_tx = None;
+        let mut xpubs = BTreeMap::new();
+        let mut version = None;
+        let mut unknown = BTreeMap::new();
+        for (key_type, key_data, value) in read_map(reader)? {
+            match key_type {
+                PSBT_GLOBAL_UNSIGNED_TX => {
+                    let tx = parse_all(&value, |r| Tx::parse(r, Network::Mainnet))?;
+                    set_once(&mut unsigned_tx, &key_data, tx)?
+                }
+                PSBT_GLOBAL_XPUB => {
+                    if key_data.len() != 78 {
+                        return Err(psbt_error(format!("extended key of {} bytes", key_data.len())));
+                    }
+                    xpubs.insert(key_data, KeySource::parse(&value)?);
+                }
+                PSBT_GLOBAL_VERSION => {
+                    set_once(&mut version, &key_data, parse_all(&value, |r| read_u32_le(r))?)?
+                }
+                _ => {
+                    unknown.insert(key(key_type, &key_data), value);
+                }
+            }
+        }
+        let unsigned_tx = unsigned_tx.ok_or_else(|| psbt_error("missing unsigned transaction"))?;
+        let version = version.unwrap_or(0);
+        if version != 0 {
+            return Err(psbt_error(format!("unsupported PSBT version {}", version)));
+        }
+        let mut psbt = Psbt::new(unsigned_tx)?;
+        psbt.xpubs = xpubs;
+        psbt.unknown = unknown;
+        for input in psbt.inputs.iter_mut() {
+            *input = PsbtInput::parse(reader)?;
+        }
+        for output in psbt.outputs.iter_mut() {
+            *output = PsbtOutput::parse(reader)?;
+        }
+        for (input, tx_in) in psbt.inputs.iter().zip(psbt.unsigned_tx.tx_ins.iter()) {
+            if let Some(ref prev) = input.non_witness_utxo {
+                if prev.hash() != tx_in.prev_tx {
+                    return Err(psbt_error(format!("non-witness UTXO {} is not the transaction spent", prev.id())));
+                }
+            }
+        }
+        Ok(psbt)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = PSBT_MAGIC.to_vec();
+        let mut global = vec![(key(PSBT_GLOBAL_UNSIGNED_TX, &[]), self.unsigned_tx.serialize_legacy())];
+        for (xpub, source) in self.xpubs.iter() {
+            global.push((key(PSBT_GLOBAL_XPUB, xpub), source.serialize()));
+        }
+        if self.version != 0 {
+            global.push((key(PSBT_GLOBAL_VERSION, &[]), self.version.to_le_bytes().to_vec()));
+        }
+        global.extend(self.unknown.clone());
+        // Writing to a Vec cannot fail
+        write_map(&mut out, global).unwrap();
+        for input in self.inputs.iter() {
+            write_map(&mut out, input.pairs().unwrap()).unwrap();
+        }
+        for output in self.outputs.iter() {
+            write_map(&mut out, output.pairs()).unwrap();
+        }
+        out
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Psbt> {
+        let raw = BASE64
+            .decode(encoded.trim())
+            .map_err(|e| psbt_error(format!("PSBT is not base64: {}", e)))?;
+        parse_all(&raw, |r| Psbt::parse(r))
+    }
+
+    /// The base64 form that Bitcoin Core and most wallets exchange.
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.serialize())
+    }
+
+    /// The updater role: looks up the transactions spent by inputs that do
+    /// not have a UTXO yet. Legacy inputs get the whole transaction, segwit
+    /// v0 inputs both it and the output, taproot inputs just the output.
+    pub fn update<F: TxFetcher + ?Sized>(&mut self, fetcher: &F) -> Result<()> {
+        let network = self.unsigned_tx.network;
+        for (input, tx_in) in self.inputs.iter_mut().zip(self.unsigned_tx.tx_ins.iter()) {
+            if input.non_witness_utxo.is_some() || input.witness_utxo.is_some() {
+                continue;
+            }
+            let prev = fetcher.fetch(&tx_in.prev_tx, network)?;
+            let prev_output = tx_in.prev_output(&SingleTx(&prev), network)?;
+            match classify(&prev_output.script_pubkey) {
+                Ok(Spend::P2tr(_)) => input.witness_utxo = Some(prev_output),
+                Ok(Spend::P2wpkh(_)) | Ok(Spend::P2wsh(_)) => {
+                    input.witness_utxo = Some(prev_output);
+                    input.non_witness_utxo = Some(prev);
+                }
+                _ => input.non_witness_utxo = Some(prev),
+            }
+        }
+        Ok(())
+    }
+
+    /// The output spent by input `input_index`, if the PSBT has it.
+    pub fn spent_output(&self, input_index: usize) -> Result<Option<TxOut>> {
+        let input = self.inputs.get(input_index).ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("input {} of {}", input_index, self.inputs.len()))
+        })?;
+        if let Some(ref tx_out) = input.witness_utxo {
+            return Ok(Some(tx_out.clone()));
+        }
+        match input.non_witness_utxo {
+            Some(ref prev) => self.unsigned_tx.tx_ins[input_index].prev_output(&SingleTx(prev), self.unsigned_tx.network).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The script that input `input_index` actually satisfies: the redeem
+    /// script for P2SH, the script_pubkey otherwise.
+    fn spent_script(&self, input_index: usize, script_pubkey: &[u8]) -> Result<Option<Vec<u8>>> {
+        let hash = match p2sh_hash(script_pubkey) {
+            Some(hash) => hash,
+            None => return Ok(Some(script_pubkey.to_vec())),
+        };
+        match self.inputs[input_index].redeem_script {
+            Some(ref redeem_script) if hash160(redeem_script)[..] == hash[..] => Ok(Some(redeem_script.clone())),
+            Some(_) => Err(psbt_error(format!("input {} redeem script does not match", input_index))),
+            None => Ok(None),
+        }
+    }
+
+    fn witness_script(&self, input_index: usize, hash: &[u8]) -> Result<Option<&Vec<u8>>> {
+        match self.inputs[input_index].witness_script {
+            Some(ref script) if sha256(script)[..] == hash[..] => Ok(Some(script)),
+            Some(_) => Err(psbt_error(format!("input {} witness script does not match", input_index))),
+            None => Ok(None),
+        }
+    }
+
+    /// The signer role: signs every input that `key` can sign and that is
+    /// not finalized yet, returning how many it signed.
+    ///
+    /// ECDSA signatures go to `partial_sigs` and use the input's sighash
+    /// type, ALL by default. Taproot inputs are signed on the key path when
+    /// `key`, tweaked with the input's merkle root, is the output key; that
+    /// needs the UTXO of every input.
+    pub fn sign(&mut self, key: &PrivateKey) -> Result<usize> {
+        let mut signed = 0;
+        for input_index in 0..self.inputs.len() {
+            if !self.inputs[input_index].is_finalized() && self.sign_input(input_index, key)? {
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+
+    fn sign_input(&mut self, input_index: usize, key: &PrivateKey) -> Result<bool> {
+        let prev_output = match self.spent_output(input_index)? {
+            Some(prev_output) => prev_output,
+            None => return Ok(false),
+        };
+        let script = match self.spent_script(input_index, &prev_output.script_pubkey)? {
+            Some(script) => script,
+            None => return Ok(false),
+        };
+        let point = key.public_key();
+        let hash_type = self.inputs[input_index].sighash_type;
+        let tx = &self.unsigned_tx;
+
+        let (sec, z) = match classify(&script) {
+            Ok(Spend::P2tr(output_key)) => {
+                let merkle_root = self.inputs[input_index].tap_merkle_root;
+                let tweaked = key.tap_tweak(merkle_root.as_ref())?;
+                if tweaked.xonly_public_key().serialize()[..] != output_key[..] {
+                    return Ok(false);
+                }
+                let sighash = TapSighashType::from_u32(hash_type.unwrap_or(0))?;
+                let prevouts = (0..self.inputs.len())
+                    .map(|i| self.spent_output(i)?.ok_or_else(|| psbt_error(format!("input {} has no UTXO", i))))
+                    .collect::<Result<Vec<TxOut>>>()?;
+                let msg = tx.taproot_sig_hash(input_index, &prevouts, sighash, None, None)?;
+                let mut sig = SchnorrSignature::sign(&tweaked, &msg, &[0; 32])?.serialize().to_vec();
+                if sighash != TapSighashType::Default {
+                    sig.push(sighash.to_u32() as u8);
+                }
+                self.inputs[input_index].tap_key_sig = Some(sig);
+                return Ok(true);
+            }
+            Ok(Spend::P2wpkh(hash)) => {
+                if hash != point.hash160(true) {
+                    return Ok(false);
+                }
+                let sighash = SighashType::from_u32(hash_type.unwrap_or(1))?;
+                let script_code = p2pkh_script(&point.hash160(true));
+                (point.sec(true), tx.bip143_sig_hash(input_index, &script_code, prev_output.amount, sighash)?)
+            }
+            Ok(Spend::P2wsh(hash)) => {
+                let witness_script = match self.witness_script(input_index, hash)? {
+                    Some(script) if mentions(script, &point.sec(true)) => script,
+                    _ => return Ok(false),
+                };
+                let sighash = SighashType::from_u32(hash_type.unwrap_or(1))?;
+                (point.sec(true), tx.bip143_sig_hash(input_index, witness_script, prev_output.amount, sighash)?)
+            }
+            Ok(Spend::P2pkh(_)) | Err(_) => {
+                let sec = match classify(&script) {
+                    Ok(Spend::P2pkh(hash)) if hash == point.hash160(true) => point.sec(true),
+                    Ok(Spend::P2pkh(hash)) if hash == point.hash160(false) => point.sec(false),
+                    Err(_) if mentions(&script, &point.sec(true)) => point.sec(true),
+                    Err(_) if mentions(&script, &point.sec(false)) => point.sec(false),
+                    _ => return Ok(false),
+                };
+                let sighash = SighashType::from_u32(hash_type.unwrap_or(1))?;
+                (sec, tx.legacy_sig_hash(input_index, &script, sighash)?)
+            }
+        };
+        let mut sig = key.sign(&z).der();
+        sig.push(hash_type.unwrap_or(1) as u8);
+        self.inputs[input_index].partial_sigs.insert(sec, sig);
+        Ok(true)
+    }
+
+    /// The items that satisfy `script` given the input's partial
+    /// signatures: a single-key `<sec> OP_CHECKSIG`, or m-of-n
+    /// OP_CHECKMULTISIG with signatures in key order.
+    fn satisfy(&self, input_index: usize, script: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let sigs = &self.inputs[input_index].partial_sigs;
+        if let Some((m, keys)) = multisig(script) {
+            // The extra item consumed by OP_CHECKMULTISIG
+            let mut items = vec![Vec::new()];
+            items.extend(keys.iter().filter_map(|sec| sigs.get(*sec).cloned()).take(m));
+            if items.len() != m + 1 {
+                return Err(missing_sigs(input_index));
+            }
+            return Ok(items);
+        }
+        match script.split_last() {
+            Some((&OP_CHECKSIG, pushed)) if !pushed.is_empty() && pushed[0] as usize == pushed.len() - 1 => {
+                let sig = sigs.get(&pushed[1..]).ok_or_else(|| missing_sigs(input_index))?;
+                Ok(vec![sig.clone()])
+            }
+            _ => Err(Error::new(ErrorKind::UnsupportedScript, "only single-key and multisig scripts can be finalized")),
+        }
+    }
+
+    /// The finalizer role for every input.
+    pub fn finalize(&mut self) -> Result<()> {
+        for input_index in 0..self.inputs.len() {
+            self.finalize_input(input_index)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the final script_sig and witness of input `input_index` from
+    /// its signatures and scripts, then drops everything else but the
+    /// UTXOs and unknown fields.
+    pub fn finalize_input(&mut self, input_index: usize) -> Result<()> {
+        let prev_output = self
+            .spent_output(input_index)?
+            .ok_or_else(|| psbt_error(format!("input {} has no UTXO", input_index)))?;
+        if self.inputs[input_index].is_finalized() {
+            return Ok(());
+        }
+        let script = self
+            .spent_script(input_index, &prev_output.script_pubkey)?
+            .ok_or_else(|| psbt_error(format!("input {} has no redeem script", input_index)))?;
+        let input = &self.inputs[input_index];
+
+        let (mut script_sig_items, witness) = match classify(&script) {
+            Ok(Spend::P2tr(_)) => {
+                let sig = input.tap_key_sig.clone().ok_or_else(|| missing_sigs(input_index))?;
+                (vec![], vec![sig])
+            }
+            Ok(Spend::P2wpkh(hash)) => {
+                let (sec, sig) = input
+                    .partial_sigs
+                    .iter()
+                    .find(|(sec, _)| hash160(sec)[..] == hash[..])
+                    .ok_or_else(|| missing_sigs(input_index))?;
+                (vec![], vec![sig.clone(), sec.clone()])
+            }
+            Ok(Spend::P2wsh(hash)) => {
+                let witness_script = self
+                    .witness_script(input_index, hash)?
+                    .ok_or_else(|| psbt_error(format!("input {} has no witness script", input_index)))?;
+                let mut witness = self.satisfy(input_index, witness_script)?;
+                witness.push(witness_script.clone());
+                (vec![], witness)
+            }
+            Ok(Spend::P2pkh(hash)) => {
+                let (sec, sig) = input
+                    .partial_sigs
+                    .iter()
+                    .find(|(sec, _)| hash160(sec)[..] == hash[..])
+                    .ok_or_else(|| missing_sigs(input_index))?;
+                (vec![sig.clone(), sec.clone()], vec![])
+            }
+            Err(_) => (self.satisfy(input_index, &script)?, vec![]),
+        };
+        if script != prev_output.script_pubkey {
+            script_sig_items.push(script);
+        }
+
+        let input = &mut self.inputs[input_index];
+        let mut script_sig = Vec::new();
+        for item in script_sig_items.iter() {
+            push(&mut script_sig, item);
+        }
+        input.final_script_sig = if script_sig.is_empty() { None } else { Some(script_sig) };
+        input.final_script_witness = if witness.is_empty() { None } else { Some(witness) };
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+        input.tap_key_sig = None;
+        input.tap_internal_key = None;
+        input.tap_merkle_root = None;
+        Ok(())
+    }
+
+    /// The extractor role: the signed transaction, once every input is
+    /// finalized.
+    pub fn extract(&self) -> Result<Tx> {
+        let mut tx = self.unsigned_tx.clone();
+        for (input_index, (input, tx_in)) in self.inputs.iter().zip(tx.tx_ins.iter_mut()).enumerate() {
+            if !input.is_finalized() {
+                return Err(Error::new(ErrorKind::InvalidSignature, format!("input {} is not finalized", input_index)));
+            }
+            tx_in.script_sig = input.final_script_sig.clone().unwrap_or_default();
+            tx_in.witness = input.final_script_witness.clone().unwrap_or_default();
+        }
+        Ok(tx)
+    }
+}
+
+// Serves the one transaction a PSBT input carries
+struct SingleTx<'a>(&'a Tx);
+
+impl TxFetcher for SingleTx<'_> {
+    fn fetch(&self, txid: &[u8; 32], _network: Network) -> Result<Tx> {
+        if self.0.hash() != *txid {
+            return Err(psbt_error(format!("non-witness UTXO {} is not the transaction spent", self.0.id())));
+        }
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+use super::{p2tr_script, p2wpkh_script, p2wsh_script, LockTime, MapFetcher, TxIn};
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn psbt_bip174_vectors() {
+    // Valid vectors from BIP174 and BIP371, which round trip byte for byte
+    let vectors = [
+        "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab300000000000000",
+        "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+        "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+        "70736274ff01003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000a0f0102030405060708090f0102030405060708090a0b0c0d0e0f0000",
+        "70736274ff010052020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff0148e6052a01000000160014768e1eeb4cf420866033f80aceff0f9720744969000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757011340bb53ec917bad9d906af1ba87181c48b86ace5aae2b53605a725ca74625631476fc6f5baedaf4f2ee0f477f36f58f3970d5b8273b7e497b97af2e3f125c97af342116fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000011720fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa232002202036b772a6db74d8753c98a827958de6c78ab3312109f37d3e0304484242ece73d818772b2da7540000800100008000000080000000000000000000",
+    ];
+    for vector in vectors.iter() {
+        let psbt = parse_all(&decode_hex(vector).unwrap(), |r| Psbt::parse(r)).unwrap();
+        assert_eq!(encode_hex(&psbt.serialize()), *vector);
+    }
+
+    let psbt = Psbt::from_base64("cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUAAAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAAAAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8USIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6efkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA").unwrap();
+    assert_eq!(encode_hex(&psbt.serialize()), vectors[0]);
+    assert_eq!(psbt.inputs[0].non_witness_utxo.as_ref().unwrap().hash(), psbt.unsigned_tx.tx_ins[0].prev_tx);
+    assert_eq!(psbt.to_base64(), "cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUAAAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAAAAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8USIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6efkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA");
+
+    let psbt = Psbt::from_base64(&BASE64.encode(decode_hex(vectors[1]).unwrap())).unwrap();
+    assert!(psbt.inputs[0].is_finalized());
+    let redeem_script = psbt.inputs[1].redeem_script.as_ref().unwrap();
+    assert_eq!(psbt.inputs[1].witness_utxo.as_ref().unwrap().script_pubkey, [&[OP_HASH160, 20][..], &hash160(redeem_script), &[OP_EQUAL]].concat());
+
+    let psbt = Psbt::parse(&mut &decode_hex(vectors[3]).unwrap()[..]).unwrap();
+    assert_eq!(psbt.unsigned_tx.id(), "75c5c9665a570569ad77dd1279e6fd4628a093c4dcbf8d41532614044c14c115");
+    assert_eq!(psbt.inputs[0].unknown[&decode_hex("0f010203040506070809").unwrap()], decode_hex("0102030405060708090a0b0c0d0e0f").unwrap());
+
+    let psbt = Psbt::parse(&mut &decode_hex(vectors[4]).unwrap()[..]).unwrap();
+    assert_eq!(psbt.inputs[0].tap_key_sig.as_ref().unwrap().len(), 64);
+    assert!(psbt.inputs[0].tap_internal_key.is_some());
+}
+
+#[test]
+fn psbt_rejects_invalid() {
+    let valid = decode_hex("70736274ff01003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000a0f0102030405060708090f0102030405060708090a0b0c0d0e0f0000").unwrap();
+    let parse = |bytes: &[u8]| Psbt::parse(&mut &bytes[..]).unwrap_err().kind();
+
+    let mut bad_magic = valid.clone();
+    bad_magic[4] = 0;
+    assert_eq!(parse(&bad_magic), ErrorKind::InvalidEncoding);
+    assert_eq!(parse(&[&PSBT_MAGIC[..], &[0x00]].concat()), ErrorKind::InvalidEncoding);
+    // The global unsigned transaction twice
+    let global_len = 3 + valid[7] as usize;
+    let duplicate = [&valid[..5 + global_len], &valid[5..]].concat();
+    assert_eq!(parse(&duplicate), ErrorKind::InvalidEncoding);
+
+    let mut psbt = Psbt::parse(&mut &valid[..]).unwrap();
+    psbt.unsigned_tx.tx_ins[0].script_sig = vec![0x51];
+    assert_eq!(parse(&psbt.serialize()), ErrorKind::InvalidEncoding);
+    assert!(Psbt::new(psbt.unsigned_tx).is_err());
+    assert!(Psbt::from_base64("not base64!").is_err());
+}
+
+#[test]
+fn psbt_sign_finalize_extract() {
+    let key = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    let point = key.public_key();
+    let output_key = key.tap_tweak(None).unwrap().xonly_public_key().serialize();
+    let parent = Tx::new(
+        2,
+        vec![TxIn::new([7; 32], 0)],
+        vec![
+            TxOut::new(50_000, p2pkh_script(&point.hash160(true))),
+            TxOut::new(60_000, p2wpkh_script(&point.hash160(true))),
+            TxOut::new(70_000, p2tr_script(&output_key)),
+        ],
+        LockTime::ZERO,
+        Network::Testnet,
+    );
+    let inputs = (0..3).map(|i| TxIn::new(parent.hash(), i)).collect();
+    let tx = Tx::new(2, inputs, vec![TxOut::new(170_000, p2wpkh_script(&[1; 20]))], LockTime::ZERO, Network::Testnet);
+    let fetcher = MapFetcher(vec![parent]);
+
+    let mut psbt = Psbt::new(tx).unwrap();
+    psbt.update(&fetcher).unwrap();
+    assert!(psbt.inputs[0].witness_utxo.is_none());
+    assert!(psbt.inputs[1].non_witness_utxo.is_some() && psbt.inputs[1].witness_utxo.is_some());
+    assert!(psbt.inputs[2].non_witness_utxo.is_none());
+    psbt.inputs[1].sighash_type = Some(SighashType::AllAnyoneCanPay.to_u32());
+
+    // Passed to the signer as base64 and back
+    let mut signer = Psbt::from_base64(&psbt.to_base64()).unwrap();
+    assert_eq!(signer.sign(&PrivateKey::new(&BigInt::from(5)).unwrap()).unwrap(), 0);
+    assert_eq!(signer.sign(&key).unwrap(), 3);
+    assert_eq!(*signer.inputs[1].partial_sigs[&point.sec(true)].last().unwrap(), 0x81);
+    assert_eq!(signer.extract().unwrap_err().kind(), ErrorKind::InvalidSignature);
+
+    let mut finalizer = Psbt::from_base64(&signer.to_base64()).unwrap();
+    finalizer.finalize().unwrap();
+    assert!(finalizer.inputs.iter().all(|input| input.partial_sigs.is_empty() && input.sighash_type.is_none()));
+    let signed = finalizer.extract().unwrap();
+    assert_eq!(signed.tx_ins[2].witness.len(), 1);
+    assert!(signed.verify(&fetcher).unwrap());
+
+    assert_eq!(psbt.finalize().unwrap_err().kind(), ErrorKind::InvalidSignature);
+}
+
+#[test]
+fn psbt_multisig() {
+    let keys: Vec<PrivateKey> = (1..=3).map(|i| PrivateKey::new(&BigInt::from(1000 + i)).unwrap()).collect();
+    let mut script = vec![OP_1 + 1];
+    for key in keys.iter() {
+        push(&mut script, &key.public_key().sec(true));
+    }
+    script.extend_from_slice(&[OP_1 + 2, OP_CHECKMULTISIG]);
+    assert_eq!(multisig(&script).unwrap().0, 2);
+
+    // 2-of-3 as P2SH-P2WSH and as bare P2SH, whose 105-byte redeem script
+    // needs OP_PUSHDATA1
+    let nested = p2wsh_script(&sha256(&script));
+    let p2sh = |redeem: &[u8]| [&[OP_HASH160, 20][..], &hash160(redeem), &[OP_EQUAL]].concat();
+    let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(1_000, p2sh(&nested)), TxOut::new(2_000, p2sh(&script))], LockTime::ZERO, Network::Mainnet);
+    let inputs = vec![TxIn::new(parent.hash(), 0), TxIn::new(parent.hash(), 1)];
+    let mut psbt = Psbt::new(Tx::new(1, inputs, vec![TxOut::new(2_500, p2wpkh_script(&[1; 20]))], LockTime::ZERO, Network::Mainnet)).unwrap();
+    psbt.update(&MapFetcher(vec![parent])).unwrap();
+    psbt.inputs[0].redeem_script = Some(nested.clone());
+    psbt.inputs[0].witness_script = Some(script.clone());
+    psbt.inputs[1].redeem_script = Some(script.clone());
+
+    assert_eq!(psbt.sign(&keys[2]).unwrap(), 2);
+    assert_eq!(psbt.clone().finalize().unwrap_err().kind(), ErrorKind::InvalidSignature);
+    assert_eq!(psbt.sign(&keys[0]).unwrap(), 2);
+    let unsigned = psbt.unsigned_tx.clone();
+    psbt.finalize().unwrap();
+    let tx = psbt.extract().unwrap();
+
+    let mut nested_script_sig = Vec::new();
+    push(&mut nested_script_sig, &nested);
+    assert_eq!(tx.tx_ins[0].script_sig, nested_script_sig);
+    let witness = &tx.tx_ins[0].witness;
+    assert_eq!((witness.len(), witness[0].len(), &witness[3]), (4, 0, &script));
+    for (sig, key) in witness[1..3].iter().zip([&keys[0], &keys[2]].iter()) {
+        let z = unsigned.bip143_sig_hash(0, &script, 1_000, SighashType::All).unwrap();
+        let sig = crate::math::ecc::Signature::parse_der(&sig[..sig.len() - 1]).unwrap();
+        assert!(key.public_key().verify(&z, &sig));
+    }
+    assert_eq!(&tx.tx_ins[1].script_sig[..1], &[0x00]);
+    assert!(tx.tx_ins[1].script_sig.ends_with(&[&[0x4c, 105][..], &script].concat()));
+}
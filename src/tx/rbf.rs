@@ -0,0 +1,168 @@
+//! Raising the fee of an unconfirmed transaction.
+//!
+//! A transaction signalling BIP125 can be replaced by one spending the same
+//! coins with a higher fee. Otherwise a child spending one of its outputs
+//! can pay enough for both (child pays for parent).
+
+use super::builder::{dust_threshold, estimate_weight, Utxo};
+use super::coin_selection::fee_for_weight;
+use super::fetcher::TxFetcher;
+use super::{LockTime, Sequence, Tx, TxIn, TxOut};
+use crate::error::{Error, ErrorKind, Result};
+
+/// Bitcoin Core's default incremental relay fee, in sat/vB: what a
+/// replacement pays for its own relay on top of the fee it replaces.
+pub const INCREMENTAL_RELAY_FEE: f64 = 1.0;
+/// Bitcoin Core's default minimum relay fee, in sat/vB.
+pub const MIN_RELAY_FEE: f64 = 1.0;
+
+impl Tx {
+    /// Lowers every input's sequence to at most
+    /// `Sequence::ENABLE_RBF_NO_LOCKTIME`, keeping lower ones and their
+    /// relative locktimes.
+    pub fn signal_rbf(&mut self) {
+        for tx_in in self.tx_ins.iter_mut() {
+            if !tx_in.sequence.signals_rbf() {
+                tx_in.sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+            }
+        }
+    }
+
+    /// Whether any input signals replaceability. Ancestors signalling for
+    /// it are not considered.
+    pub fn signals_rbf(&self) -> bool {
+        self.tx_ins.iter().any(|tx_in| tx_in.sequence.signals_rbf())
+    }
+}
+
+/// An unsigned replacement for `original` paying `new_rate` sat/vB. It
+/// spends the same coins and takes the extra fee from the last output,
+/// where `TxBuilder` puts change, dropping it if what is left is dust.
+/// The fee also covers BIP125's rules 3 and 4: more than the original's
+/// and at least the incremental relay fee for the replacement's size.
+pub fn bump_fee<F: TxFetcher + ?Sized>(original: &Tx, new_rate: f64, fetcher: &F) -> Result<Tx> {
+    if !original.signals_rbf() {
+        return Err(Error::new(ErrorKind::OutOfRange, format!("{} does not signal replaceability", original.id())));
+    }
+    let old_fee = original.fee(fetcher)?;
+    let utxos: Vec<Utxo> = original
+        .tx_ins
+        .iter()
+        .zip(original.spent_outputs(fetcher)?)
+        .map(|(tx_in, output)| Utxo::new(tx_in.prev_tx, tx_in.prev_index, output))
+        .collect();
+    let input_sum: u64 = utxos.iter().map(|utxo| utxo.output.amount).sum();
+    let required_fee = |outputs: &[TxOut]| -> Result<u64> {
+        let weight = estimate_weight(&utxos, outputs)?;
+        Ok(fee_for_weight(weight, new_rate).max(old_fee + fee_for_weight(weight, INCREMENTAL_RELAY_FEE)))
+    };
+
+    let mut tx_outs = original.tx_outs.clone();
+    let change = tx_outs.pop().ok_or_else(|| Error::new(ErrorKind::OutOfRange, "no outputs to take the fee from"))?;
+    let payments: u64 = tx_outs.iter().map(|o| o.amount).sum();
+    let mut with_change = tx_outs.clone();
+    with_change.push(change.clone());
+    let fee = required_fee(&with_change)?;
+    if input_sum >= payments + fee + dust_threshold(&change.script_pubkey) {
+        tx_outs.push(TxOut::new(input_sum - payments - fee, change.script_pubkey));
+    } else if tx_outs.is_empty() || input_sum < payments + required_fee(&tx_outs)? {
+        return Err(Error::new(ErrorKind::OutOfRange,
+            format!("insufficient funds: {} in inputs cannot pay {} at {} sat/vB", input_sum, payments, new_rate)));
+    }
+
+    let tx_ins = original
+        .tx_ins
+        .iter()
+        .map(|tx_in| {
+            let mut replacement = TxIn::new(tx_in.prev_tx, tx_in.prev_index);
+            replacement.sequence = tx_in.sequence;
+            replacement
+        })
+        .collect();
+    Ok(Tx::new(original.version, tx_ins, tx_outs, original.locktime, original.network))
+}
+
+/// An unsigned child spending output `output_index` of `parent` to
+/// `script_pubkey`, with a fee bringing the two to `package_rate` sat/vB
+/// together. The child signals replaceability so it can be bumped too.
+pub fn cpfp_child<F: TxFetcher + ?Sized>(
+    parent: &Tx,
+    output_index: u32,
+    script_pubkey: Vec<u8>,
+    package_rate: f64,
+    fetcher: &F,
+) -> Result<Tx> {
+    let output = parent.tx_outs.get(output_index as usize).ok_or_else(|| {
+        Error::new(ErrorKind::OutOfRange, format!("output {} of a transaction with {}", output_index, parent.tx_outs.len()))
+    })?;
+    let parent_fee = parent.fee(fetcher)?;
+    let utxo = Utxo::new(parent.hash(), output_index, output.clone());
+    let child_weight = estimate_weight(&[utxo], &[TxOut::new(0, script_pubkey.clone())])?;
+    let package_fee = fee_for_weight(parent.weight() + child_weight, package_rate);
+    let child_fee = package_fee.saturating_sub(parent_fee).max(fee_for_weight(child_weight, MIN_RELAY_FEE));
+    let amount = output.amount.saturating_sub(child_fee);
+    if amount < dust_threshold(&script_pubkey) {
+        return Err(Error::new(ErrorKind::OutOfRange,
+            format!("output of {} cannot pay a {} fee and leave more than dust", output.amount, child_fee)));
+    }
+    let mut tx_in = TxIn::new(parent.hash(), output_index);
+    tx_in.sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+    Ok(Tx::new(2, vec![tx_in], vec![TxOut::new(amount, script_pubkey)], LockTime::ZERO, parent.network))
+}
+
+#[cfg(test)]
+use super::builder::TxBuilder;
+#[cfg(test)]
+use super::{p2tr_script, p2wpkh_script, MapFetcher, SighashType};
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use crate::params::Network;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn bump_fee_and_cpfp() {
+    let key = PrivateKey::new(&BigInt::from(2024)).unwrap();
+    let change_script = p2wpkh_script(&key.public_key().hash160(true));
+    let funding = Tx::new(1, vec![TxIn::new([9; 32], 0)], vec![TxOut::new(100_000, change_script.clone())], LockTime::ZERO, Network::Mainnet);
+    let builder = TxBuilder::new()
+        .add_utxo(Utxo::new(funding.hash(), 0, funding.tx_outs[0].clone()))
+        .add_recipient(p2tr_script(&[3; 32]), 40_000)
+        .change_script(change_script.clone())
+        .fee_rate(2.0);
+    let mut original = builder.build().unwrap();
+    assert!(!original.signals_rbf());
+    let fetcher = MapFetcher(vec![funding.clone()]);
+    assert_eq!(bump_fee(&original, 10.0, &fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+    original.signal_rbf();
+    assert!(original.signals_rbf());
+    original.sign_input(0, &key, SighashType::All, &fetcher).unwrap();
+
+    let mut replacement = bump_fee(&original, 10.0, &fetcher).unwrap();
+    assert_eq!(replacement.tx_outs[0], original.tx_outs[0]);
+    assert_eq!(replacement.tx_ins[0].sequence, Sequence::ENABLE_RBF_NO_LOCKTIME);
+    replacement.sign_input(0, &key, SighashType::All, &fetcher).unwrap();
+    assert!(replacement.verify(&fetcher).unwrap());
+    let fee = replacement.fee(&fetcher).unwrap();
+    assert!(fee as f64 >= 10.0 * replacement.vsize() as f64);
+    assert!(fee >= original.fee(&fetcher).unwrap() + replacement.vsize() as u64);
+
+    // A rate barely above the original's still pays for the replacement's relay
+    let replacement = bump_fee(&original, 2.1, &fetcher).unwrap();
+    let old_fee = original.fee(&fetcher).unwrap();
+    assert!(old_fee + replacement.vsize() as u64 <= 100_000 - 40_000 - replacement.tx_outs[1].amount);
+    // Change too small to keep goes to the fee
+    assert_eq!(bump_fee(&original, 450.0, &fetcher).unwrap().tx_outs.len(), 1);
+    assert_eq!(bump_fee(&original, 500.0, &fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+
+    let fetcher = MapFetcher(vec![funding, original.clone()]);
+    let mut child = cpfp_child(&original, 1, change_script, 20.0, &fetcher).unwrap();
+    child.sign_input(0, &key, SighashType::All, &fetcher).unwrap();
+    assert!(child.verify(&fetcher).unwrap());
+    let package_fee = original.fee(&fetcher).unwrap() + child.fee(&fetcher).unwrap();
+    let package_vsize = (original.weight() + child.weight()) as f64 / 4.0;
+    assert!(package_fee as f64 >= 20.0 * package_vsize);
+    assert!((package_fee as f64) < 20.0 * (package_vsize + 2.0));
+    assert_eq!(cpfp_child(&original, 2, vec![], 20.0, &fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
@@ -0,0 +1,639 @@
+//! Signature hashes (chapter 7).
+//!
+//! The hash type appended to a signature picks which parts of the
+//! transaction it commits to: all outputs, none of them, or only the one
+//! at the same index as the input, optionally combined with
+//! ANYONECANPAY to commit to the signing input alone.
+//!
+//! Legacy inputs hash a modified copy of the whole transaction. Segwit v0
+//! inputs use BIP143, which also commits to the amount being spent and
+//! shares the hashes of all prevouts, sequences and outputs between inputs
+//! so signing stays linear in the transaction size. Taproot inputs use
+//! BIP341, which commits to every spent output's amount and script_pubkey
+//! and adds a DEFAULT hash type.
+
+use super::fetcher::TxFetcher;
+use super::{Sequence, Tx, TxIn, TxOut};
+use crate::encoding::varint::encode_varint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{hash256, sha256, tagged_hash};
+use crate::math::ecc::bytes_to_int;
+use num_bigint::BigInt;
+use std::fmt;
+
+const ANYONECANPAY: u32 = 0x80;
+/// First byte of a taproot annex
+pub const ANNEX_TAG: u8 = 0x50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SighashType {
+    All = 0x01,
+    None = 0x02,
+    Single = 0x03,
+    AllAnyoneCanPay = 0x81,
+    NoneAnyoneCanPay = 0x82,
+    SingleAnyoneCanPay = 0x83,
+}
+
+impl SighashType {
+    /// Parses one of the six standard hash types.
+    pub fn from_u32(hash_type: u32) -> Result<SighashType> {
+        match hash_type {
+            0x01 => Ok(SighashType::All),
+            0x02 => Ok(SighashType::None),
+            0x03 => Ok(SighashType::Single),
+            0x81 => Ok(SighashType::AllAnyoneCanPay),
+            0x82 => Ok(SighashType::NoneAnyoneCanPay),
+            0x83 => Ok(SighashType::SingleAnyoneCanPay),
+            _ => Err(Error::new(ErrorKind::InvalidEncoding, format!("non-standard sighash type {:#x}", hash_type))),
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    /// The hash type with the ANYONECANPAY flag cleared.
+    pub fn base(self) -> SighashType {
+        SighashType::from_u32(self.to_u32() & !ANYONECANPAY).unwrap()
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.to_u32() & ANYONECANPAY != 0
+    }
+}
+
+/// Uses Bitcoin Core's names, e.g. `SINGLE|ANYONECANPAY`.
+impl fmt::Display for SighashType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let base = match self.base() {
+            SighashType::None => "NONE",
+            SighashType::Single => "SINGLE",
+            _ => "ALL",
+        };
+        write!(f, "{}{}", base, if self.anyone_can_pay() { "|ANYONECANPAY" } else { "" })
+    }
+}
+
+/// BIP341 hash types: the legacy ones plus DEFAULT, which signs like ALL
+/// but is left out of the signature encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TapSighashType {
+    Default = 0x00,
+    All = 0x01,
+    None = 0x02,
+    Single = 0x03,
+    AllAnyoneCanPay = 0x81,
+    NoneAnyoneCanPay = 0x82,
+    SingleAnyoneCanPay = 0x83,
+}
+
+impl TapSighashType {
+    pub fn from_u32(hash_type: u32) -> Result<TapSighashType> {
+        if hash_type == 0 {
+            return Ok(TapSighashType::Default);
+        }
+        SighashType::from_u32(hash_type).map(TapSighashType::from)
+    }
+
+    pub fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.to_u32() & ANYONECANPAY != 0
+    }
+
+    fn base(self) -> TapSighashType {
+        TapSighashType::from_u32(self.to_u32() & !ANYONECANPAY).unwrap()
+    }
+}
+
+impl From<SighashType> for TapSighashType {
+    fn from(sighash: SighashType) -> TapSighashType {
+        match sighash {
+            SighashType::All => TapSighashType::All,
+            SighashType::None => TapSighashType::None,
+            SighashType::Single => TapSighashType::Single,
+            SighashType::AllAnyoneCanPay => TapSighashType::AllAnyoneCanPay,
+            SighashType::NoneAnyoneCanPay => TapSighashType::NoneAnyoneCanPay,
+            SighashType::SingleAnyoneCanPay => TapSighashType::SingleAnyoneCanPay,
+        }
+    }
+}
+
+impl fmt::Display for TapSighashType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match SighashType::from_u32(self.to_u32()) {
+            Ok(sighash) => sighash.fmt(f),
+            Err(_) => write!(f, "DEFAULT"),
+        }
+    }
+}
+
+impl Tx {
+    /// The legacy digest `z` that input `input_index` signs under
+    /// `sighash`, with `script_code` (normally the spent script_pubkey) in
+    /// place of its script_sig and every other script_sig left empty.
+    ///
+    /// SIGHASH_SINGLE without a matching output reproduces the consensus
+    /// "one hash" bug: the digest is 1 rather than an error, and a
+    /// signature over it can spend the input in any transaction.
+    pub fn legacy_sig_hash(&self, input_index: usize, script_code: &[u8], sighash: SighashType) -> Result<BigInt> {
+        self.input(input_index)?;
+        let base = sighash.base();
+        if base == SighashType::Single && input_index >= self.tx_outs.len() {
+            let mut one = [0u8; 32];
+            one[0] = 1;
+            return Ok(bytes_to_int(&one));
+        }
+
+        let mut result = self.version.to_le_bytes().to_vec();
+        let signed_inputs: Vec<usize> = if sighash.anyone_can_pay() {
+            vec![input_index]
+        } else {
+            (0..self.tx_ins.len()).collect()
+        };
+        encode_varint(&mut result, signed_inputs.len() as u64)?;
+        for i in signed_inputs {
+            let tx_in = &self.tx_ins[i];
+            let (script_sig, sequence) = if i == input_index {
+                (script_code.to_vec(), tx_in.sequence)
+            } else if base == SighashType::All {
+                (Vec::new(), tx_in.sequence)
+            } else {
+                // Other signers may still replace their inputs
+                (Vec::new(), Sequence::ZERO)
+            };
+            TxIn { script_sig, sequence, ..tx_in.clone() }.write(&mut result)?;
+        }
+
+        let signed_outputs: Vec<TxOut> = match base {
+            SighashType::None => Vec::new(),
+            SighashType::Single => {
+                let blank = TxOut::new(u64::MAX, Vec::new());
+                let mut outputs = vec![blank; input_index];
+                outputs.push(self.tx_outs[input_index].clone());
+                outputs
+            }
+            _ => self.tx_outs.clone(),
+        };
+        encode_varint(&mut result, signed_outputs.len() as u64)?;
+        for tx_out in signed_outputs.iter() {
+            tx_out.write(&mut result)?;
+        }
+        result.extend_from_slice(&self.locktime.to_consensus_u32().to_le_bytes());
+        result.extend_from_slice(&sighash.to_u32().to_le_bytes());
+        Ok(bytes_to_int(&hash256(&result)))
+    }
+
+    /// The BIP143 digest that segwit v0 input `input_index` signs. The
+    /// `script_code` is the P2PKH script of the key hash for P2WPKH and the
+    /// witness script for P2WSH, and `amount` is the value being spent.
+    pub fn bip143_sig_hash(&self, input_index: usize, script_code: &[u8], amount: u64, sighash: SighashType) -> Result<BigInt> {
+        self.bip143_sig_hash_with(&Bip143Hashes::new(self), input_index, script_code, amount, sighash)
+    }
+
+    /// `bip143_sig_hash` reusing `hashes` computed once for this transaction.
+    pub fn bip143_sig_hash_with(
+        &self,
+        hashes: &Bip143Hashes,
+        input_index: usize,
+        script_code: &[u8],
+        amount: u64,
+        sighash: SighashType,
+    ) -> Result<BigInt> {
+        let tx_in = self.input(input_index)?;
+        let base = sighash.base();
+        let zero = [0u8; 32];
+
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend_from_slice(if sighash.anyone_can_pay() { &zero } else { &hashes.prevouts });
+        let all_sequences = !sighash.anyone_can_pay() && base == SighashType::All;
+        result.extend_from_slice(if all_sequences { &hashes.sequences } else { &zero });
+        write_outpoint(&mut result, tx_in);
+        encode_varint(&mut result, script_code.len() as u64)?;
+        result.extend_from_slice(script_code);
+        result.extend_from_slice(&amount.to_le_bytes());
+        result.extend_from_slice(&tx_in.sequence.to_consensus_u32().to_le_bytes());
+        match base {
+            SighashType::All => result.extend_from_slice(&hashes.outputs),
+            SighashType::Single if input_index < self.tx_outs.len() => {
+                let mut output = Vec::new();
+                self.tx_outs[input_index].write(&mut output)?;
+                result.extend_from_slice(&hash256(&output));
+            }
+            _ => result.extend_from_slice(&zero),
+        }
+        result.extend_from_slice(&self.locktime.to_consensus_u32().to_le_bytes());
+        result.extend_from_slice(&sighash.to_u32().to_le_bytes());
+        Ok(bytes_to_int(&hash256(&result)))
+    }
+
+    /// The BIP341 message hash that taproot input `input_index` signs.
+    ///
+    /// `prevouts` are the outputs spent by every input, in input order.
+    /// `annex` is the last witness element if it starts with 0x50.
+    /// Script-path spends pass the tapleaf hash and the position of the
+    /// last executed OP_CODESEPARATOR (0xffffffff for none).
+    pub fn taproot_sig_hash(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        sighash: TapSighashType,
+        annex: Option<&[u8]>,
+        script_path: Option<(&[u8; 32], u32)>,
+    ) -> Result<[u8; 32]> {
+        let hashes = Bip341Hashes::new(self, prevouts)?;
+        self.taproot_sig_hash_with(&hashes, input_index, prevouts, sighash, annex, script_path)
+    }
+
+    /// `taproot_sig_hash` reusing `hashes` computed once for this
+    /// transaction and `prevouts`.
+    pub fn taproot_sig_hash_with(
+        &self,
+        hashes: &Bip341Hashes,
+        input_index: usize,
+        prevouts: &[TxOut],
+        sighash: TapSighashType,
+        annex: Option<&[u8]>,
+        script_path: Option<(&[u8; 32], u32)>,
+    ) -> Result<[u8; 32]> {
+        let tx_in = self.input(input_index)?;
+        if prevouts.len() != self.tx_ins.len() {
+            return Err(Error::new(ErrorKind::OutOfRange,
+                format!("{} prevouts for {} inputs", prevouts.len(), self.tx_ins.len())));
+        }
+        let base = sighash.base();
+        if base == TapSighashType::Single && input_index >= self.tx_outs.len() {
+            return Err(Error::new(ErrorKind::OutOfRange, format!("SIGHASH_SINGLE input {} has no output", input_index)));
+        }
+
+        // Epoch 0, then SigMsg
+        let mut msg = vec![0u8, sighash.to_u32() as u8];
+        msg.extend_from_slice(&self.version.to_le_bytes());
+        msg.extend_from_slice(&self.locktime.to_consensus_u32().to_le_bytes());
+        if !sighash.anyone_can_pay() {
+            msg.extend_from_slice(&hashes.prevouts);
+            msg.extend_from_slice(&hashes.amounts);
+            msg.extend_from_slice(&hashes.script_pubkeys);
+            msg.extend_from_slice(&hashes.sequences);
+        }
+        if base == TapSighashType::Default || base == TapSighashType::All {
+            msg.extend_from_slice(&hashes.outputs);
+        }
+        let ext_flag = if script_path.is_some() { 1 } else { 0 };
+        msg.push(ext_flag * 2 + annex.is_some() as u8);
+        if sighash.anyone_can_pay() {
+            write_outpoint(&mut msg, tx_in);
+            msg.extend_from_slice(&prevouts[input_index].amount.to_le_bytes());
+            encode_varint(&mut msg, prevouts[input_index].script_pubkey.len() as u64)?;
+            msg.extend_from_slice(&prevouts[input_index].script_pubkey);
+            msg.extend_from_slice(&tx_in.sequence.to_consensus_u32().to_le_bytes());
+        } else {
+            msg.extend_from_slice(&(input_index as u32).to_le_bytes());
+        }
+        if let Some(annex) = annex {
+            if annex.first() != Some(&ANNEX_TAG) {
+                return Err(Error::new(ErrorKind::InvalidEncoding, "annex must start with 0x50"));
+            }
+            let mut data = Vec::new();
+            encode_varint(&mut data, annex.len() as u64)?;
+            data.extend_from_slice(annex);
+            msg.extend_from_slice(&sha256(&data));
+        }
+        if base == TapSighashType::Single {
+            let mut output = Vec::new();
+            self.tx_outs[input_index].write(&mut output)?;
+            msg.extend_from_slice(&sha256(&output));
+        }
+        if let Some((leaf_hash, codesep_pos)) = script_path {
+            msg.extend_from_slice(leaf_hash);
+            msg.push(0x00); // key_version
+            msg.extend_from_slice(&codesep_pos.to_le_bytes());
+        }
+        Ok(tagged_hash("TapSighash", &msg))
+    }
+
+    /// `legacy_sig_hash` of input `input_index`, looking the spent
+    /// script_pubkey up with `fetcher`.
+    pub fn sig_hash<F: TxFetcher + ?Sized>(&self, input_index: usize, sighash: SighashType, fetcher: &F) -> Result<BigInt> {
+        let script_pubkey = self.input(input_index)?.script_pubkey(fetcher, self.network)?;
+        self.legacy_sig_hash(input_index, &script_pubkey, sighash)
+    }
+}
+
+/// The BIP143 hashPrevouts, hashSequence and hashOutputs of a transaction,
+/// shared by all of its inputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bip143Hashes {
+    prevouts: [u8; 32],
+    sequences: [u8; 32],
+    outputs: [u8; 32],
+}
+
+impl Bip143Hashes {
+    pub fn new(tx: &Tx) -> Bip143Hashes {
+        let mut prevouts = Vec::new();
+        let mut sequences = Vec::new();
+        for tx_in in tx.tx_ins.iter() {
+            write_outpoint(&mut prevouts, tx_in);
+            sequences.extend_from_slice(&tx_in.sequence.to_consensus_u32().to_le_bytes());
+        }
+        let mut outputs = Vec::new();
+        for tx_out in tx.tx_outs.iter() {
+            tx_out.write(&mut outputs).unwrap();
+        }
+        Bip143Hashes {
+            prevouts: hash256(&prevouts),
+            sequences: hash256(&sequences),
+            outputs: hash256(&outputs),
+        }
+    }
+}
+
+/// The BIP341 single-SHA256 hashes of a transaction's prevouts, spent
+/// amounts, spent script_pubkeys, sequences and outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bip341Hashes {
+    prevouts: [u8; 32],
+    amounts: [u8; 32],
+    script_pubkeys: [u8; 32],
+    sequences: [u8; 32],
+    outputs: [u8; 32],
+}
+
+impl Bip341Hashes {
+    /// `prevouts` are the outputs spent by every input of `tx`.
+    pub fn new(tx: &Tx, prevouts: &[TxOut]) -> Result<Bip341Hashes> {
+        if prevouts.len() != tx.tx_ins.len() {
+            return Err(Error::new(ErrorKind::OutOfRange,
+                format!("{} prevouts for {} inputs", prevouts.len(), tx.tx_ins.len())));
+        }
+        let mut outpoints = Vec::new();
+        let mut sequences = Vec::new();
+        for tx_in in tx.tx_ins.iter() {
+            write_outpoint(&mut outpoints, tx_in);
+            sequences.extend_from_slice(&tx_in.sequence.to_consensus_u32().to_le_bytes());
+        }
+        let mut amounts = Vec::new();
+        let mut script_pubkeys = Vec::new();
+        for prevout in prevouts.iter() {
+            amounts.extend_from_slice(&prevout.amount.to_le_bytes());
+            encode_varint(&mut script_pubkeys, prevout.script_pubkey.len() as u64)?;
+            script_pubkeys.extend_from_slice(&prevout.script_pubkey);
+        }
+        let mut outputs = Vec::new();
+        for tx_out in tx.tx_outs.iter() {
+            tx_out.write(&mut outputs)?;
+        }
+        Ok(Bip341Hashes {
+            prevouts: sha256(&outpoints),
+            amounts: sha256(&amounts),
+            script_pubkeys: sha256(&script_pubkeys),
+            sequences: sha256(&sequences),
+            outputs: sha256(&outputs),
+        })
+    }
+}
+
+fn write_outpoint(out: &mut Vec<u8>, tx_in: &TxIn) {
+    let mut prev_tx = tx_in.prev_tx;
+    prev_tx.reverse();
+    out.extend_from_slice(&prev_tx);
+    out.extend_from_slice(&tx_in.prev_index.to_le_bytes());
+}
+
+#[cfg(test)]
+use super::{LockTime, MapFetcher, CH5_TX};
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::encoding::util::encode_hex;
+#[cfg(test)]
+use crate::params::Network;
+
+#[cfg(test)]
+const CH5_SPENT_SCRIPT: &str = "76a914a802fc56c704ce87c42d7c92eb75e7896bdc41ae88ac";
+
+#[test]
+fn sighash_type_round_trip() {
+    for &hash_type in [0x01, 0x02, 0x03, 0x81, 0x82, 0x83].iter() {
+        assert_eq!(SighashType::from_u32(hash_type).unwrap().to_u32(), hash_type);
+    }
+    assert!(SighashType::from_u32(0).is_err());
+    assert!(SighashType::from_u32(0x84).is_err());
+    assert_eq!(SighashType::SingleAnyoneCanPay.base(), SighashType::Single);
+    assert_eq!(SighashType::SingleAnyoneCanPay.to_string(), "SINGLE|ANYONECANPAY");
+    assert_eq!(SighashType::All.to_string(), "ALL");
+}
+
+#[test]
+fn legacy_sig_hash_all() {
+    use crate::math::ecc::{S256Point, Signature};
+
+    let raw = decode_hex(CH5_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let script_pubkey = decode_hex(CH5_SPENT_SCRIPT).unwrap();
+    let want = bytes_to_int(&decode_hex("27e0c5994dec7824e56dec6b2fcb342eb7cdb0d0957c2fce9882f715e85d81a6").unwrap());
+    let z = tx.legacy_sig_hash(0, &script_pubkey, SighashType::All).unwrap();
+    assert_eq!(z, want);
+
+    // The signature and public key pushed by the script_sig commit to z
+    let script_sig = &tx.tx_ins[0].script_sig;
+    let der_len = script_sig[0] as usize;
+    let sig = Signature::parse_der(&script_sig[1..der_len]).unwrap();
+    assert_eq!(script_sig[der_len] as u32, SighashType::All.to_u32());
+    let point = S256Point::parse_sec(&script_sig[der_len + 2..]).unwrap();
+    assert!(point.verify(&z, &sig));
+
+    let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(1, script_pubkey.clone())], LockTime::ZERO, Network::Mainnet);
+    let mut child = tx.clone();
+    child.tx_ins[0].prev_tx = parent.hash();
+    let fetcher = MapFetcher(vec![parent]);
+    assert_eq!(
+        child.sig_hash(0, SighashType::All, &fetcher).unwrap(),
+        child.legacy_sig_hash(0, &script_pubkey, SighashType::All).unwrap()
+    );
+    assert_eq!(child.sig_hash(1, SighashType::All, &fetcher).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(tx.legacy_sig_hash(1, &[], SighashType::All).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
+
+#[test]
+fn legacy_sig_hash_types() {
+    // The chapter 5 transaction with two more inputs, checked against
+    // rust-bitcoin's SighashCache::legacy_signature_hash
+    let raw = decode_hex(CH5_TX).unwrap();
+    let mut tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    tx.tx_ins[0].script_sig.clear();
+    tx.tx_ins.push(TxIn::new([0xaa; 32], 3));
+    tx.tx_ins.push(TxIn { sequence: Sequence::from_consensus(5), ..TxIn::new([0xbb; 32], 1) });
+    let script_code = decode_hex(CH5_SPENT_SCRIPT).unwrap();
+
+    let cases = [
+        (0, SighashType::None, "1cfe96df1ed28a0cadbd897ad8bd50dc0706540b5423695f5ad05bd5193e2979"),
+        (0, SighashType::Single, "6cb7ce146f20d8c2224be6685346521b6959a934510eb6cd12828815ff610924"),
+        (0, SighashType::AllAnyoneCanPay, "d598d6849114bb8573a0f51184fa46fb3ff7516ef4ba7a27356d691ff579c20d"),
+        (1, SighashType::All, "533ed2235c4de77d82c3b5bb91c0fce645b9765c18f4b7af94d1456887c11518"),
+        (1, SighashType::Single, "762ba44e625437509da82c835fe1ff4b283c88f2446dca9582b69d48bfe7cedb"),
+        (1, SighashType::NoneAnyoneCanPay, "c5c912e236ea5ab5c614b62282f2fac3e5d4a0dc219ab3e64f90ed821f535fcc"),
+        (1, SighashType::SingleAnyoneCanPay, "d8dc8f4f51bfcf49490f35d3c97ba61f8c5e19bed4d26646acf57f3b4f7699a3"),
+        (2, SighashType::None, "b36047a17979bcea89d041aff3e4855f6dc14f47c3bf175c84405e248db6966e"),
+        (2, SighashType::AllAnyoneCanPay, "22c1241fcef5542e9c9b8083091e41708e1fb99b6be2fbae74e5812fb396909e"),
+        // SINGLE with no output at index 2
+        (2, SighashType::Single, "0100000000000000000000000000000000000000000000000000000000000000"),
+        (2, SighashType::SingleAnyoneCanPay, "0100000000000000000000000000000000000000000000000000000000000000"),
+    ];
+    for &(index, sighash, want) in cases.iter() {
+        let want = bytes_to_int(&decode_hex(want).unwrap());
+        assert_eq!(tx.legacy_sig_hash(index, &script_code, sighash).unwrap(), want, "input {} {}", index, sighash);
+    }
+}
+
+#[test]
+fn bip143_native_p2wpkh() {
+    // The native P2WPKH example from BIP143, signing its second input
+    let raw = decode_hex("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000").unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let hashes = Bip143Hashes::new(&tx);
+    assert_eq!(encode_hex(&hashes.prevouts), "96b827c8483d4e9b96712b6713a7b68d6e8003a781feba36c31143470b4efd37");
+    assert_eq!(encode_hex(&hashes.sequences), "52b0a642eea2fb7ae638c36f6252b6750293dbe574a806984b8e4d8548339a3b");
+    assert_eq!(encode_hex(&hashes.outputs), "863ef3e1a92afbfdb97f31ad0fc7683ee943e9abcf2501590ff8f6551f47e5e5");
+
+    let script_code = decode_hex("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap();
+    let z = tx.bip143_sig_hash(1, &script_code, 600_000_000, SighashType::All).unwrap();
+    assert_eq!(z, bytes_to_int(&decode_hex("c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670").unwrap()));
+    assert_eq!(tx.bip143_sig_hash(2, &script_code, 1, SighashType::All).unwrap_err().kind(), ErrorKind::OutOfRange);
+}
+
+#[test]
+fn bip143_sighash_types() {
+    // The P2SH-P2WSH 6-of-6 multisig example from BIP143
+    let raw = decode_hex("010000000136641869ca081e70f394c6948e8af409e18b619df2ed74aa106c1ca29787b96e0100000000ffffffff0200e9a435000000001976a914389ffce9cd9ae88dcc0631e88a821ffdbe9bfe2688acc0832f05000000001976a9147480a33f950689af511e6e84c138dbbd3c3ee41588ac00000000").unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let witness_script = decode_hex("56210307b8ae49ac90a048e9b53357a2354b3334e9c8bee813ecb98e99a7e07e8c3ba32103b28f0c28bfab54554ae8c658ac5c3e0ce6e79ad336331f78c428dd43eea8449b21034b8113d703413d57761b8b9781957b8c0ac1dfe69f492580ca4195f50376ba4a21033400f6afecb833092a9a21cfdf1ed1376e58c5d1f47de74683123987e967a8f42103a6d48b1131e94ba04d9737d61acdaa1322008af9602b3b14862c07a1789aac162102d8b661b0b3302ee2f162b09e07a55ad5dfbe673a9f01d9f0c19617681024306b56ae").unwrap();
+    let cases = [
+        (SighashType::All, "185c0be5263dce5b4bb50a047973c1b6272bfbd0103a89444597dc40b248ee7c"),
+        (SighashType::None, "e9733bc60ea13c95c6527066bb975a2ff29a925e80aa14c213f686cbae5d2f36"),
+        (SighashType::Single, "1e1f1c303dc025bd664acb72e583e933fae4cff9148bf78c157d1e8f78530aea"),
+        (SighashType::AllAnyoneCanPay, "2a67f03e63a6a422125878b40b82da593be8d4efaafe88ee528af6e5a9955c6e"),
+        (SighashType::NoneAnyoneCanPay, "781ba15f3779d5542ce8ecb5c18716733a5ee42a6f51488ec96154934e2c890a"),
+        (SighashType::SingleAnyoneCanPay, "511e8e52ed574121fc1b654970395502128263f62662e076dc6baf05c2e6a99b"),
+    ];
+    let hashes = Bip143Hashes::new(&tx);
+    for &(sighash, want) in cases.iter() {
+        let want = bytes_to_int(&decode_hex(want).unwrap());
+        assert_eq!(tx.bip143_sig_hash_with(&hashes, 0, &witness_script, 987_654_321, sighash).unwrap(), want, "{}", sighash);
+    }
+}
+
+#[cfg(test)]
+fn check_taproot_sig_hash(
+    tx_hex: &str,
+    prevouts_hex: &str,
+    input_index: usize,
+    sighash: TapSighashType,
+    annex: Option<&str>,
+    script_path: Option<&str>,
+    want: &str,
+) {
+    use crate::encoding::varint::read_varint_len;
+
+    let raw = decode_hex(tx_hex).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let raw_prevouts = decode_hex(prevouts_hex).unwrap();
+    let reader = &mut &raw_prevouts[..];
+    let count = read_varint_len(reader).unwrap();
+    let prevouts: Vec<TxOut> = (0..count).map(|_| TxOut::parse(reader).unwrap()).collect();
+    let annex = annex.map(|a| decode_hex(a).unwrap());
+    let leaf_hash = script_path.map(|l| {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&decode_hex(l).unwrap());
+        hash
+    });
+    let got = tx
+        .taproot_sig_hash(input_index, &prevouts, sighash, annex.as_deref(), leaf_hash.as_ref().map(|h| (h, 0xffff_ffff)))
+        .unwrap();
+    assert_eq!(encode_hex(&got), want, "{}", sighash);
+}
+
+#[test]
+fn taproot_sig_hash_key_path() {
+    // Bitcoin Core's functional test cases, as collected by rust-bitcoin
+    check_taproot_sig_hash(
+        "020000000164eb050a5e3da0c2a65e4786f26d753b7bc69691fabccafb11f7acef36641f1846010000003101b2b404392a22000000000017a9147f2bde86fe78bf68a0544a4f290e12f0b7e0a08c87580200000000000017a91425d11723074ecfb96a0a83c3956bfaf362ae0c908758020000000000001600147e20f938993641de67bb0cdd71682aa34c4d29ad5802000000000000160014c64984dc8761acfa99418bd6bedc79b9287d652d72000000",
+        "01365724000000000023542156b39dab4f8f3508e0432cfb41fab110170acaa2d4c42539cb90a4dc7c093bc500",
+        0, TapSighashType::Default, None, None,
+        "33ca0ebfb4a945eeee9569fc0f5040221275f88690b7f8592ada88ce3bdf6703",
+    );
+    check_taproot_sig_hash(
+        "0200000002fff49be59befe7566050737910f6ccdc5e749c7f8860ddc140386463d88c5ad0f3000000002cf68eb4a3d67f9d4c079249f7e4f27b8854815cb1ed13842d4fbf395f9e217fd605ee24090100000065235d9203f458520000000000160014b6d48333bb13b4c644e57c43a9a26df3a44b785e58020000000000001976a914eea9461a9e1e3f765d3af3e726162e0229fe3eb688ac58020000000000001976a9143a8869c9f2b5ea1d4ff3aeeb6a8fb2fffb1ad5fe88ac0ad7125c",
+        "02591f220000000000225120f25ad35583ea31998d968871d7de1abd2a52f6fe4178b54ea158274806ff4ece48fb310000000000225120f25ad35583ea31998d968871d7de1abd2a52f6fe4178b54ea158274806ff4ece",
+        1, TapSighashType::All, None, None,
+        "626ab955d58c9a8a600a0c580549d06dc7da4e802eb2a531f62a588e430967a8",
+    );
+    check_taproot_sig_hash(
+        "0200000001350005f65aa830ced2079df348e2d8c2bdb4f10e2dde6a161d8a07b40d1ad87dae000000001611d0d603d9dc0e000000000017a914459b6d7d6bbb4d8837b4bf7e9a4556f952da2f5c8758020000000000001976a9141dd70e1299ffc2d5b51f6f87de9dfe9398c33cbb88ac58020000000000001976a9141dd70e1299ffc2d5b51f6f87de9dfe9398c33cbb88aca71c1f4f",
+        "01c4811000000000002251201bf9297d0a2968ae6693aadd0fa514717afefd218087a239afb7418e2d22e65c",
+        0, TapSighashType::AllAnyoneCanPay, None, None,
+        "dfa9437f9c9a1d1f9af271f79f2f5482f287cdb0d2e03fa92c8a9b216cc6061c",
+    );
+    check_taproot_sig_hash(
+        "020000000185bed1a6da2bffbd60ec681a1bfb71c5111d6395b99b3f8b2bf90167111bcb18f5010000007c83ace802ded24a00000000001600142c4698f9f7a773866879755aa78c516fb332af8e5802000000000000160014d38639dfbac4259323b98a472405db0c461b31fa61073747",
+        "0144c84d0000000000225120e3f2107989c88e67296ab2faca930efa2e3a5bd3ff0904835a11c9e807458621",
+        0, TapSighashType::None, None, None,
+        "3129de36a5d05fff97ffca31eb75fcccbbbc27b3147a7a36a9e4b45d8b625067",
+    );
+    check_taproot_sig_hash(
+        "eb93dbb901028c8515589dac980b6e7f8e4088b77ed866ca0d6d210a7218b6fd0f6b22dd6d7300000000eb4740a9047efc0e0000000000160014913da2128d8fcf292b3691db0e187414aa1783825802000000000000160014913da2128d8fcf292b3691db0e187414aa178382580200000000000017a9143dd27f01c6f7ef9bb9159937b17f17065ed01a0c875802000000000000160014d7630e19df70ada9905ede1722b800c0005f246641000000",
+        "013fed110000000000225120eb536ae8c33580290630fc495046e998086a64f8f33b93b07967d9029b265c55",
+        0, TapSighashType::NoneAnyoneCanPay, None, None,
+        "2441e8b0e063a2083ee790f14f2045022f07258ddde5ee01de543c9e789d80ae",
+    );
+    check_taproot_sig_hash(
+        "02000000017836b409a5fed32211407e44b971591f2032053f14701fb5b3a30c0ff382f2cc9c0100000061ac55f60288fb5600000000001976a9144ea02f6f182b082fb6ce47e36bbde390b6a41b5088ac58020000000000001976a9144ea02f6f182b082fb6ce47e36bbde390b6a41b5088ace4000000",
+        "01efa558000000000022512007071ea3dc7e331b0687d0193d1e6d6ed10e645ef36f10ef8831d5e522ac9e80",
+        0, TapSighashType::Single, None, None,
+        "30239345177cadd0e3ea413d49803580abb6cb27971b481b7788a78d35117a88",
+    );
+    check_taproot_sig_hash(
+        "0100000001aa6deae89d5e0aaca58714fc76ef6f3c8284224888089232d4e663843ed3ab3eae010000008b6657a60450cb4c0000000000160014a3d42b5413ef0c0701c4702f3cd7d4df222c147058020000000000001976a91430b4ed8723a4ee8992aa2c8814cfe5c3ad0ab9d988ac5802000000000000160014365b1166a6ed0a5e8e9dff17a6d00bbb43454bc758020000000000001976a914bc98c51a84fe7fad5dc380eb8b39586eff47241688ac4f313247",
+        "0107af4e00000000002251202c36d243dfc06cb56a248e62df27ecba7417307511a81ae61aa41c597a929c69",
+        0, TapSighashType::SingleAnyoneCanPay, None, None,
+        "bf9c83f26c6dd16449e4921f813f551c4218e86f2ec906ca8611175b41b566df",
+    );
+}
+
+#[test]
+fn taproot_sig_hash_annex_and_script_path() {
+    check_taproot_sig_hash(
+        "0200000001df8123752e8f37d132c4e9f1ff7e4f9b986ade9211267e9ebd5fd22a5e718dec6d01000000ce4023b903cb7b23000000000017a914a18b36ea7a094db2f4940fc09edf154e86de7bd787580200000000000017a914afd0d512a2c5c2b40e25669e9cc460303c325b8b87580200000000000017a914a18b36ea7a094db2f4940fc09edf154e86de7bd787f6020000",
+        "01ea49260000000000225120ab5e9800806bf18cb246edcf5fe63441208fe955a4b5a35bbff65f5db622a010",
+        0, TapSighashType::SingleAnyoneCanPay,
+        Some("507b979802e62d397acb29f56743a791894b99372872fc5af06a4f6e8d242d0615cda53062bb20e6ec79756fe39183f0c128adfe85559a8fa042b042c018aa8010143799e44f0893c40e1e"),
+        None,
+        "3b003000add359a364a156e73e02846782a59d0d95ca8c4638aaad99f2ef915c",
+    );
+    check_taproot_sig_hash(
+        "020000000189fc651483f9296b906455dd939813bf086b1bbe7c77635e157c8e14ae29062195010000004445b5c7044561320000000000160014331414dbdada7fb578f700f38fb69995fc9b5ab958020000000000001976a914268db0a8104cc6d8afd91233cc8b3d1ace8ac3ef88ac580200000000000017a914ec00dcb368d6a693e11986d265f659d2f59e8be2875802000000000000160014c715799a49a0bae3956df9c17cb4440a673ac0df6f010000",
+        "011bec34000000000022512028055142ea437db73382e991861446040b61dd2185c4891d7daf6893d79f7182",
+        0, TapSighashType::All, None,
+        Some("15a2530514e399f8b5cf0b3d3112cf5b289eaa3e308ba2071b58392fdc6da68a"),
+        "d66de5274a60400c7b08c86ba6b7f198f40660079edf53aca89d2a9501317f2e",
+    );
+}
+
+#[test]
+fn taproot_sig_hash_errors() {
+    let raw = decode_hex(CH5_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let prevouts = vec![TxOut::new(1, vec![0x51, 0x20])];
+    assert_eq!(tx.taproot_sig_hash(0, &[], TapSighashType::Default, None, None).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(tx.taproot_sig_hash(0, &prevouts, TapSighashType::Default, Some(&[0x51]), None).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    let mut no_outputs = tx.clone();
+    no_outputs.tx_outs.clear();
+    assert_eq!(no_outputs.taproot_sig_hash(0, &prevouts, TapSighashType::Single, None, None).unwrap_err().kind(), ErrorKind::OutOfRange);
+    assert_eq!(TapSighashType::from_u32(0).unwrap().to_string(), "DEFAULT");
+    assert_eq!(TapSighashType::from(SighashType::NoneAnyoneCanPay).to_u32(), 0x82);
+    assert!(TapSighashType::from_u32(4).is_err());
+}
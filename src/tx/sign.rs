@@ -0,0 +1,654 @@
+//! Signing and verifying P2PKH, P2WPKH, P2WSH and P2TR inputs (chapters 7
+//! and 13, BIP341).
+//!
+//! Until the script interpreter exists, outputs are matched by template:
+//!
+//! * P2PKH, `OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG`,
+//!   spent by a script_sig pushing a signature and a SEC public key.
+//! * P2WPKH, `OP_0 <20 bytes>`, spent by the same two items in the witness.
+//! * P2WSH, `OP_0 <32 bytes>`, when the witness script is a single-key
+//!   `<SEC public key> OP_CHECKSIG`, spent by a signature and that script.
+//! * P2TR, `OP_1 <32 bytes>`, spent by a single Schnorr signature made
+//!   with the tweaked output key, optionally followed by an annex. Script
+//!   path spends are checked against their control block, and the leaf
+//!   script executed when it is the single-key `<x-only key> OP_CHECKSIG`.
+//!
+//! Other output types are reported as `ErrorKind::UnsupportedScript`.
+
+use super::fetcher::TxFetcher;
+use super::sighash::{Bip143Hashes, Bip341Hashes, ANNEX_TAG};
+use super::taproot::{tap_leaf_hash, ControlBlock, TAPSCRIPT_LEAF_VERSION};
+use super::{SighashType, TapSighashType, Tx, TxOut};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::{hash160, sha256};
+use crate::math::ecc::{PrivateKey, S256Point, SchnorrSignature, Signature, XOnlyPoint};
+use num_bigint::BigInt;
+
+const OP_0: u8 = 0x00;
+const OP_1: u8 = 0x51;
+const OP_DUP: u8 = 0x76;
+const OP_HASH160: u8 = 0xa9;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_CHECKSIG: u8 = 0xac;
+// Opcodes 0x01-0x4b push that many bytes
+const MAX_DIRECT_PUSH: usize = 0x4b;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+
+/// The P2PKH script_pubkey paying to `hash`.
+pub fn p2pkh_script(hash: &[u8; 20]) -> Vec<u8> {
+    let mut script = vec![OP_DUP, OP_HASH160, 20];
+    script.extend_from_slice(hash);
+    script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
+    script
+}
+
+/// The P2WPKH script_pubkey paying to the hash160 of a compressed key.
+pub fn p2wpkh_script(hash: &[u8; 20]) -> Vec<u8> {
+    [&[OP_0, 20][..], hash].concat()
+}
+
+/// The P2WSH script_pubkey paying to the sha256 of a witness script.
+pub fn p2wsh_script(hash: &[u8; 32]) -> Vec<u8> {
+    [&[OP_0, 32][..], hash].concat()
+}
+
+/// The P2TR script_pubkey paying to an x-only output key.
+pub fn p2tr_script(output_key: &[u8; 32]) -> Vec<u8> {
+    [&[OP_1, 32][..], output_key].concat()
+}
+
+/// `<sec> OP_CHECKSIG`, paying to a bare public key.
+pub fn p2pk_script(sec: &[u8]) -> Vec<u8> {
+    let mut script = Vec::new();
+    push(&mut script, sec);
+    script.push(OP_CHECKSIG);
+    script
+}
+
+pub(super) enum Spend<'a> {
+    P2pkh(&'a [u8]),
+    P2wpkh(&'a [u8]),
+    P2wsh(&'a [u8]),
+    P2tr(&'a [u8]),
+}
+
+pub(super) fn classify(script_pubkey: &[u8]) -> Result<Spend<'_>> {
+    match script_pubkey {
+        [OP_DUP, OP_HASH160, 20, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => Ok(Spend::P2pkh(hash)),
+        [OP_0, 20, hash @ ..] if hash.len() == 20 => Ok(Spend::P2wpkh(hash)),
+        [OP_0, 32, hash @ ..] if hash.len() == 32 => Ok(Spend::P2wsh(hash)),
+        [OP_1, 32, key @ ..] if key.len() == 32 => Ok(Spend::P2tr(key)),
+        _ => Err(Error::new(ErrorKind::UnsupportedScript, "only P2PKH, P2WPKH, P2WSH and P2TR outputs are supported")),
+    }
+}
+
+/// The key of a `<sec> OP_CHECKSIG` witness script.
+fn p2pk_key(witness_script: &[u8]) -> Result<&[u8]> {
+    match pushed_elements(&witness_script[..witness_script.len().saturating_sub(1)]).as_deref() {
+        Some(&[sec]) if witness_script.last() == Some(&OP_CHECKSIG) => Ok(sec),
+        _ => Err(Error::new(ErrorKind::UnsupportedScript, "only <pubkey> OP_CHECKSIG witness scripts are supported")),
+    }
+}
+
+/// Appends the shortest push of `data`.
+pub(super) fn push(script: &mut Vec<u8>, data: &[u8]) {
+    match data.len() {
+        0 => script.push(OP_0),
+        len if len <= MAX_DIRECT_PUSH => script.push(len as u8),
+        len if len <= 0xff => script.extend_from_slice(&[OP_PUSHDATA1, len as u8]),
+        len => {
+            assert!(len <= 0xffff);
+            script.push(OP_PUSHDATA2);
+            script.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+    }
+    script.extend_from_slice(data);
+}
+
+/// The key of a `<x-only key> OP_CHECKSIG` tapscript.
+fn tapscript_key(script: &[u8]) -> Result<&[u8]> {
+    match script {
+        [32, key @ .., OP_CHECKSIG] if key.len() == 32 => Ok(key),
+        _ => Err(Error::new(ErrorKind::UnsupportedScript, "only <x-only key> OP_CHECKSIG tapscripts are supported")),
+    }
+}
+
+/// Splits a script_sig into the elements it pushes, or `None` if it does
+/// anything other than direct pushes.
+fn pushed_elements(mut script: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut elements = Vec::new();
+    while let Some((&len, rest)) = script.split_first() {
+        let len = len as usize;
+        if len == 0 || len > MAX_DIRECT_PUSH || rest.len() < len {
+            return None;
+        }
+        elements.push(&rest[..len]);
+        script = &rest[len..];
+    }
+    Some(elements)
+}
+
+/// Checks a `<DER signature || hash type>` element against `sec`, with the
+/// digest computed by `sig_hash` for the hash type found.
+fn check_sig<H>(sig: &[u8], sec: &[u8], sig_hash: H) -> Result<bool>
+where
+    H: FnOnce(SighashType) -> Result<BigInt>,
+{
+    let (&hash_type, der) = match sig.split_last() {
+        Some(split) => split,
+        None => return Ok(false),
+    };
+    let parsed = (SighashType::from_u32(hash_type as u32), Signature::parse_der(der), S256Point::parse_sec(sec));
+    match parsed {
+        (Ok(sighash), Ok(sig), Ok(point)) => Ok(point.verify(&sig_hash(sighash)?, &sig)),
+        _ => Ok(false),
+    }
+}
+
+/// Checks a BIP341 signature: 64 bytes for DEFAULT, or 65 with an
+/// explicit, non-DEFAULT hash type.
+fn check_schnorr_sig<H>(sig: &[u8], output_key: &[u8], sig_hash: H) -> Result<bool>
+where
+    H: FnOnce(TapSighashType) -> Result<[u8; 32]>,
+{
+    let (sig, sighash) = match sig.len() {
+        64 => (sig, TapSighashType::Default),
+        65 => match TapSighashType::from_u32(sig[64] as u32) {
+            Ok(TapSighashType::Default) | Err(_) => return Ok(false),
+            Ok(sighash) => (&sig[..64], sighash),
+        },
+        _ => return Ok(false),
+    };
+    let (sig, point) = match (SchnorrSignature::parse(sig), XOnlyPoint::parse(output_key)) {
+        (Ok(sig), Ok(point)) => (sig, point),
+        _ => return Ok(false),
+    };
+    match sig_hash(sighash) {
+        Ok(msg) => Ok(sig.verify(&point, &msg)),
+        // SIGHASH_SINGLE without a matching output
+        Err(ref e) if e.kind() == ErrorKind::OutOfRange => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The spent outputs and BIP341 hashes shared by every taproot input.
+struct TaprootContext {
+    prevouts: Vec<TxOut>,
+    hashes: Bip341Hashes,
+}
+
+impl TaprootContext {
+    fn new(tx: &Tx, prevouts: Vec<TxOut>) -> Result<TaprootContext> {
+        let hashes = Bip341Hashes::new(tx, &prevouts)?;
+        Ok(TaprootContext { prevouts, hashes })
+    }
+}
+
+fn key_mismatch() -> Error {
+    Error::new(ErrorKind::InvalidSignature, "key does not match the output being spent")
+}
+
+impl Tx {
+    /// Signs input `input_index` with `key`.
+    ///
+    /// For P2PKH the script_sig becomes `<DER signature || hash type> <SEC
+    /// public key>`, with the SEC format (compressed or not) that the output
+    /// pays to. P2WPKH puts the same two items in the witness, always with
+    /// the compressed key. P2WSH is signed when the output commits to the
+    /// witness script `<compressed SEC> OP_CHECKSIG` of `key`. P2TR is
+    /// signed on the key path when the output key is `key` tweaked with no
+    /// script tree, see `sign_taproot_key_path`.
+    pub fn sign_input<F: TxFetcher + ?Sized>(
+        &mut self,
+        input_index: usize,
+        key: &PrivateKey,
+        sighash: SighashType,
+        fetcher: &F,
+    ) -> Result<()> {
+        let prev_output = self.input(input_index)?.prev_output(fetcher, self.network)?;
+        if let Spend::P2tr(_) = classify(&prev_output.script_pubkey)? {
+            return self.sign_taproot_key_path(input_index, key, None, sighash.into(), fetcher);
+        }
+        let point = key.public_key();
+        let sign = |z| {
+            let mut sig = key.sign(&z).der();
+            sig.push(sighash.to_u32() as u8);
+            sig
+        };
+
+        match classify(&prev_output.script_pubkey)? {
+            Spend::P2pkh(hash) => {
+                let compressed = if hash == point.hash160(true) {
+                    true
+                } else if hash == point.hash160(false) {
+                    false
+                } else {
+                    return Err(key_mismatch());
+                };
+                let z = self.legacy_sig_hash(input_index, &prev_output.script_pubkey, sighash)?;
+                let mut script_sig = Vec::new();
+                push(&mut script_sig, &sign(z));
+                push(&mut script_sig, &point.sec(compressed));
+                let tx_in = &mut self.tx_ins[input_index];
+                tx_in.script_sig = script_sig;
+                tx_in.witness.clear();
+            }
+            Spend::P2wpkh(hash) => {
+                let key_hash = point.hash160(true);
+                if hash != key_hash {
+                    return Err(key_mismatch());
+                }
+                let z = self.bip143_sig_hash(input_index, &p2pkh_script(&key_hash), prev_output.amount, sighash)?;
+                let tx_in = &mut self.tx_ins[input_index];
+                tx_in.script_sig.clear();
+                tx_in.witness = vec![sign(z), point.sec(true)];
+            }
+            Spend::P2wsh(hash) => {
+                let witness_script = p2pk_script(&point.sec(true));
+                if hash != sha256(&witness_script) {
+                    return Err(key_mismatch());
+                }
+                let z = self.bip143_sig_hash(input_index, &witness_script, prev_output.amount, sighash)?;
+                let tx_in = &mut self.tx_ins[input_index];
+                tx_in.script_sig.clear();
+                tx_in.witness = vec![sign(z), witness_script];
+            }
+            Spend::P2tr(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Signs taproot input `input_index` on the key path.
+    ///
+    /// `key` is the internal key; it is tweaked with `merkle_root` (`None`
+    /// for an output without a script tree) and must then match the output
+    /// key. The witness becomes the Schnorr signature, with the hash type
+    /// appended unless it is DEFAULT. Signing uses all-zero auxiliary
+    /// randomness, so signatures are deterministic.
+    pub fn sign_taproot_key_path<F: TxFetcher + ?Sized>(
+        &mut self,
+        input_index: usize,
+        key: &PrivateKey,
+        merkle_root: Option<&[u8; 32]>,
+        sighash: TapSighashType,
+        fetcher: &F,
+    ) -> Result<()> {
+        self.input(input_index)?;
+        let prevouts = self.spent_outputs(fetcher)?;
+        let output_key = match classify(&prevouts[input_index].script_pubkey)? {
+            Spend::P2tr(output_key) => output_key,
+            _ => return Err(Error::new(ErrorKind::UnsupportedScript, "not a P2TR output")),
+        };
+        let tweaked = key.tap_tweak(merkle_root)?;
+        if tweaked.xonly_public_key().serialize()[..] != output_key[..] {
+            return Err(key_mismatch());
+        }
+        let msg = self.taproot_sig_hash(input_index, &prevouts, sighash, None, None)?;
+        let mut sig = SchnorrSignature::sign(&tweaked, &msg, &[0; 32])?.serialize().to_vec();
+        if sighash != TapSighashType::Default {
+            sig.push(sighash.to_u32() as u8);
+        }
+        let tx_in = &mut self.tx_ins[input_index];
+        tx_in.script_sig.clear();
+        tx_in.witness = vec![sig];
+        Ok(())
+    }
+
+    /// Checks that input `input_index` is allowed to spend its output.
+    ///
+    /// Malformed script_sigs, witnesses, signatures and keys make the input
+    /// invalid (`Ok(false)`). Errors are left for lookups that fail and
+    /// spent outputs of unsupported types.
+    pub fn verify_input<F: TxFetcher + ?Sized>(&self, input_index: usize, fetcher: &F) -> Result<bool> {
+        self.verify_input_with(&Bip143Hashes::new(self), None, input_index, fetcher)
+    }
+
+    fn verify_input_with<F: TxFetcher + ?Sized>(
+        &self,
+        hashes: &Bip143Hashes,
+        taproot: Option<&TaprootContext>,
+        input_index: usize,
+        fetcher: &F,
+    ) -> Result<bool> {
+        let tx_in = self.input(input_index)?;
+        let prev_output = tx_in.prev_output(fetcher, self.network)?;
+        let spend = classify(&prev_output.script_pubkey)?;
+        // Native segwit spends must leave the script_sig empty
+        if !matches!(spend, Spend::P2pkh(_)) && !tx_in.script_sig.is_empty() {
+            return Ok(false);
+        }
+
+        match spend {
+            Spend::P2pkh(hash) => {
+                let (sig, sec) = match pushed_elements(&tx_in.script_sig).as_deref() {
+                    Some(&[sig, sec]) => (sig, sec),
+                    _ => return Ok(false),
+                };
+                if hash160(sec)[..] != hash[..] {
+                    return Ok(false);
+                }
+                check_sig(sig, sec, |sighash| self.legacy_sig_hash(input_index, &prev_output.script_pubkey, sighash))
+            }
+            Spend::P2wpkh(hash) => {
+                let (sig, sec) = match tx_in.witness.as_slice() {
+                    [sig, sec] => (sig, sec),
+                    _ => return Ok(false),
+                };
+                let key_hash = hash160(sec);
+                if key_hash[..] != hash[..] {
+                    return Ok(false);
+                }
+                let script_code = p2pkh_script(&key_hash);
+                check_sig(sig, sec, |sighash| {
+                    self.bip143_sig_hash_with(hashes, input_index, &script_code, prev_output.amount, sighash)
+                })
+            }
+            Spend::P2wsh(hash) => {
+                let (sig, witness_script) = match tx_in.witness.as_slice() {
+                    [sig, witness_script] => (sig, witness_script),
+                    _ => return Ok(false),
+                };
+                if sha256(witness_script)[..] != hash[..] {
+                    return Ok(false);
+                }
+                let sec = p2pk_key(witness_script)?;
+                check_sig(sig, sec, |sighash| {
+                    self.bip143_sig_hash_with(hashes, input_index, witness_script, prev_output.amount, sighash)
+                })
+            }
+            Spend::P2tr(output_key) => {
+                let mut witness = tx_in.witness.as_slice();
+                let annex = match witness {
+                    [.., last] if witness.len() >= 2 && last.first() == Some(&ANNEX_TAG) => {
+                        witness = &witness[..witness.len() - 1];
+                        Some(last.as_slice())
+                    }
+                    _ => None,
+                };
+                let fetched;
+                let taproot = match taproot {
+                    Some(taproot) => taproot,
+                    None => {
+                        fetched = TaprootContext::new(self, self.spent_outputs(fetcher)?)?;
+                        &fetched
+                    }
+                };
+                let sig_hash = |sighash, script_path| {
+                    self.taproot_sig_hash_with(&taproot.hashes, input_index, &taproot.prevouts, sighash, annex, script_path)
+                };
+                match witness {
+                    [] => Ok(false),
+                    [sig] => check_schnorr_sig(sig, output_key, |sighash| sig_hash(sighash, None)),
+                    [stack @ .., script, control_block] => {
+                        let control_block = match ControlBlock::parse(control_block) {
+                            Ok(control_block) => control_block,
+                            Err(_) => return Ok(false),
+                        };
+                        let committed = match XOnlyPoint::parse(output_key) {
+                            Ok(output_key) => control_block.verify_commitment(&output_key, script),
+                            Err(_) => false,
+                        };
+                        if !committed {
+                            return Ok(false);
+                        }
+                        if control_block.leaf_version != TAPSCRIPT_LEAF_VERSION {
+                            return Err(Error::new(ErrorKind::UnsupportedScript,
+                                format!("leaf version {:#04x}", control_block.leaf_version)));
+                        }
+                        let key = tapscript_key(script)?;
+                        let sig = match stack {
+                            [sig] => sig,
+                            _ => return Ok(false),
+                        };
+                        let leaf_hash = tap_leaf_hash(control_block.leaf_version, script);
+                        // No OP_CODESEPARATOR has been executed
+                        check_schnorr_sig(sig, key, |sighash| sig_hash(sighash, Some((&leaf_hash, 0xffff_ffff))))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that the transaction does not create money and that every
+    /// input is validly signed.
+    pub fn verify<F: TxFetcher + ?Sized>(&self, fetcher: &F) -> Result<bool> {
+        if self.is_coinbase() {
+            // Nothing is spent, so nothing is signed
+            return Ok(self.check().is_ok());
+        }
+        let prevouts = self.spent_outputs(fetcher)?;
+        let input_sum: u64 = prevouts.iter().map(|o| o.amount).sum();
+        let output_sum: u64 = self.tx_outs.iter().map(|o| o.amount).sum();
+        if output_sum > input_sum {
+            return Ok(false);
+        }
+        let hashes = Bip143Hashes::new(self);
+        let taproot = TaprootContext::new(self, prevouts)?;
+        for input_index in 0..self.tx_ins.len() {
+            if !self.verify_input_with(&hashes, Some(&taproot), input_index, fetcher)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+use super::{LockTime, MapFetcher, TxIn, CH5_TX};
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+#[cfg(test)]
+use crate::params::Network;
+
+// Returns the same transaction whatever id is asked for
+#[cfg(test)]
+struct AnyTx(Tx);
+
+#[cfg(test)]
+impl TxFetcher for AnyTx {
+    fn fetch(&self, _txid: &[u8; 32], _network: Network) -> Result<Tx> {
+        Ok(self.0.clone())
+    }
+}
+
+#[test]
+fn verify_ch5_tx() {
+    let raw = decode_hex(CH5_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let script_pubkey = decode_hex("76a914a802fc56c704ce87c42d7c92eb75e7896bdc41ae88ac").unwrap();
+    // Stands in for d1c789a9...3f81, whose output 0 paid 40000 more than
+    // the outputs here
+    let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(42_505_594, script_pubkey)], LockTime::ZERO, Network::Mainnet);
+    let fetcher = AnyTx(parent);
+    assert!(tx.verify(&fetcher).unwrap());
+
+    let mut tampered = tx.clone();
+    tampered.tx_outs[0].amount -= 1;
+    assert!(!tampered.verify(&fetcher).unwrap());
+    let mut truncated = tx.clone();
+    truncated.tx_ins[0].script_sig.pop();
+    assert!(!truncated.verify_input(0, &fetcher).unwrap());
+    let mut overspent = tx.clone();
+    overspent.tx_outs[0].amount = 50_000_000;
+    assert!(!overspent.verify(&fetcher).unwrap());
+}
+
+#[test]
+fn sign_input_round_trip() {
+    let key = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    for &compressed in [true, false].iter() {
+        let script_pubkey = p2pkh_script(&key.public_key().hash160(compressed));
+        let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(100_000, script_pubkey)], LockTime::ZERO, Network::Testnet);
+        let change = TxOut::new(90_000, p2pkh_script(&[1; 20]));
+        let mut tx = Tx::new(1, vec![TxIn::new(parent.hash(), 0)], vec![change], LockTime::ZERO, Network::Testnet);
+        let fetcher = MapFetcher(vec![parent]);
+
+        for &sighash in [SighashType::All, SighashType::NoneAnyoneCanPay].iter() {
+            tx.sign_input(0, &key, sighash, &fetcher).unwrap();
+            assert_eq!(tx.tx_ins[0].script_sig.len(), 1 + tx.tx_ins[0].script_sig[0] as usize + if compressed { 34 } else { 66 });
+            assert!(tx.verify(&fetcher).unwrap());
+        }
+
+        // NONE leaves the outputs unsigned, ALL does not
+        tx.tx_outs[0].amount = 80_000;
+        assert!(tx.verify(&fetcher).unwrap());
+        tx.sign_input(0, &key, SighashType::All, &fetcher).unwrap();
+        tx.tx_outs[0].amount = 90_000;
+        assert!(!tx.verify(&fetcher).unwrap());
+
+        let other = PrivateKey::new(&BigInt::from(5)).unwrap();
+        assert_eq!(tx.sign_input(0, &other, SighashType::All, &fetcher).unwrap_err().kind(), ErrorKind::InvalidSignature);
+    }
+}
+
+#[test]
+fn sign_input_rejects_other_scripts() {
+    let key = PrivateKey::new(&BigInt::from(5)).unwrap();
+    let p2sh = [&[OP_HASH160, 20][..], &[0; 20], &[0x87]].concat();
+    let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(1_000, p2sh)], LockTime::ZERO, Network::Mainnet);
+    let mut tx = Tx::new(1, vec![TxIn::new(parent.hash(), 0)], vec![], LockTime::ZERO, Network::Mainnet);
+    let fetcher = MapFetcher(vec![parent]);
+    assert_eq!(tx.sign_input(0, &key, SighashType::All, &fetcher).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+    assert_eq!(tx.verify(&fetcher).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+}
+
+#[test]
+fn sign_input_bip143_p2wpkh() {
+    // The native P2WPKH example from BIP143, whose second input spends 6 BTC
+    // from the key below. Deterministic nonces reproduce its signature.
+    let raw = decode_hex("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000").unwrap();
+    let mut tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let secret = decode_hex("619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9").unwrap();
+    let key = PrivateKey::new(&BigInt::from_bytes_be(num_bigint::Sign::Plus, &secret)).unwrap();
+    let script_pubkey = p2wpkh_script(&key.public_key().hash160(true));
+    assert_eq!(encode_hex(&script_pubkey), "00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1");
+    let parent = Tx::new(1, vec![], vec![TxOut::new(0, vec![]), TxOut::new(600_000_000, script_pubkey)], LockTime::ZERO, Network::Mainnet);
+    let fetcher = AnyTx(parent);
+
+    tx.sign_input(1, &key, SighashType::All, &fetcher).unwrap();
+    assert!(tx.tx_ins[1].script_sig.is_empty());
+    assert_eq!(
+        encode_hex(&tx.tx_ins[1].witness[0]),
+        "304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01"
+    );
+    assert_eq!(encode_hex(&tx.tx_ins[1].witness[1]), "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357");
+    assert!(tx.is_segwit());
+    assert!(tx.verify_input(1, &fetcher).unwrap());
+
+    // The amount is committed to
+    let mut cheaper = AnyTx(fetcher.0.clone());
+    cheaper.0.tx_outs[1].amount -= 1;
+    assert!(!tx.verify_input(1, &cheaper).unwrap());
+
+    let mut with_script_sig = tx.clone();
+    with_script_sig.tx_ins[1].script_sig = vec![0x51];
+    assert!(!with_script_sig.verify_input(1, &fetcher).unwrap());
+    let mut short_witness = tx.clone();
+    short_witness.tx_ins[1].witness.pop();
+    assert!(!short_witness.verify_input(1, &fetcher).unwrap());
+}
+
+#[test]
+fn sign_input_p2wsh_round_trip() {
+    let key = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    let witness_script = p2pk_script(&key.public_key().sec(true));
+    let parent = Tx::new(1, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(100_000, p2wsh_script(&sha256(&witness_script)))], LockTime::ZERO, Network::Mainnet);
+    let mut tx = Tx::new(2, vec![TxIn::new(parent.hash(), 0)], vec![TxOut::new(90_000, p2wpkh_script(&[1; 20]))], LockTime::ZERO, Network::Mainnet);
+    let fetcher = MapFetcher(vec![parent]);
+
+    tx.sign_input(0, &key, SighashType::Single, &fetcher).unwrap();
+    assert_eq!(tx.tx_ins[0].witness[1], witness_script);
+    assert!(tx.verify(&fetcher).unwrap());
+    tx.tx_outs[0].amount = 95_000;
+    assert!(!tx.verify(&fetcher).unwrap());
+
+    let other = PrivateKey::new(&BigInt::from(5)).unwrap();
+    assert_eq!(tx.sign_input(0, &other, SighashType::All, &fetcher).unwrap_err().kind(), ErrorKind::InvalidSignature);
+}
+
+#[test]
+fn sign_taproot_key_path_round_trip() {
+    let key = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    let merkle_root = [3; 32];
+    let p2tr = |root: Option<&[u8; 32]>| p2tr_script(&key.tap_tweak(root).unwrap().xonly_public_key().serialize());
+    let parent = Tx::new(
+        2,
+        vec![TxIn::new([7; 32], 0)],
+        vec![TxOut::new(100_000, p2tr(None)), TxOut::new(50_000, p2tr(Some(&merkle_root)))],
+        LockTime::ZERO,
+        Network::Testnet,
+    );
+    let inputs = vec![TxIn::new(parent.hash(), 0), TxIn::new(parent.hash(), 1)];
+    let mut tx = Tx::new(2, inputs, vec![TxOut::new(140_000, p2wpkh_script(&[1; 20]))], LockTime::ZERO, Network::Testnet);
+    let fetcher = MapFetcher(vec![parent]);
+
+    tx.sign_input(0, &key, SighashType::All, &fetcher).unwrap();
+    assert_eq!(tx.tx_ins[0].witness[0].len(), 65);
+    tx.sign_taproot_key_path(1, &key, Some(&merkle_root), TapSighashType::Default, &fetcher).unwrap();
+    assert_eq!(tx.tx_ins[1].witness[0].len(), 64);
+    assert!(tx.verify(&fetcher).unwrap());
+    assert!(tx.verify_input(1, &fetcher).unwrap());
+
+    // An annex is committed to, DEFAULT cannot be spelled out explicitly
+    let mut with_annex = tx.clone();
+    with_annex.tx_ins[0].witness.push(vec![ANNEX_TAG, 1]);
+    assert!(!with_annex.verify_input(0, &fetcher).unwrap());
+    let mut explicit_default = tx.clone();
+    explicit_default.tx_ins[1].witness[0].push(0x00);
+    assert!(!explicit_default.verify_input(1, &fetcher).unwrap());
+    let mut bad_control_block = tx.clone();
+    bad_control_block.tx_ins[1].witness.push(vec![OP_1]);
+    assert!(!bad_control_block.verify_input(1, &fetcher).unwrap());
+
+    tx.tx_outs[0].amount = 130_000;
+    assert!(!tx.verify(&fetcher).unwrap());
+    assert_eq!(
+        tx.sign_taproot_key_path(1, &key, None, TapSighashType::Default, &fetcher).unwrap_err().kind(),
+        ErrorKind::InvalidSignature
+    );
+}
+
+#[test]
+fn verify_taproot_script_path() {
+    use super::taproot::TapTree;
+
+    let internal = PrivateKey::new(&BigInt::from(8675309)).unwrap();
+    let leaf_key = PrivateKey::new(&BigInt::from(5)).unwrap();
+    let script = [&[32][..], &leaf_key.xonly_public_key().serialize(), &[OP_CHECKSIG]].concat();
+    let tree = TapTree::branch(TapTree::leaf(script.clone()), TapTree::leaf(vec![OP_1]));
+    let internal_key = internal.xonly_public_key();
+    let (output_key, _) = tree.output_key(&internal_key).unwrap();
+    let parent = Tx::new(2, vec![TxIn::new([7; 32], 0)], vec![TxOut::new(100_000, p2tr_script(&output_key.serialize()))], LockTime::ZERO, Network::Mainnet);
+    let mut tx = Tx::new(2, vec![TxIn::new(parent.hash(), 0)], vec![TxOut::new(90_000, p2wpkh_script(&[1; 20]))], LockTime::ZERO, Network::Mainnet);
+    let fetcher = MapFetcher(vec![parent]);
+
+    let control_block = tree.control_block(&internal_key, &script).unwrap().unwrap();
+    let leaf_hash = tap_leaf_hash(TAPSCRIPT_LEAF_VERSION, &script);
+    let prevouts = tx.spent_outputs(&fetcher).unwrap();
+    let msg = tx.taproot_sig_hash(0, &prevouts, TapSighashType::Default, None, Some((&leaf_hash, 0xffff_ffff))).unwrap();
+    let sig = SchnorrSignature::sign(&leaf_key, &msg, &[0; 32]).unwrap().serialize().to_vec();
+    tx.tx_ins[0].witness = vec![sig, script.clone(), control_block.serialize()];
+    assert!(tx.verify(&fetcher).unwrap());
+
+    // The internal key can still spend on the key path
+    let mut key_path = tx.clone();
+    key_path.sign_taproot_key_path(0, &internal, Some(&tree.merkle_root()), TapSighashType::Default, &fetcher).unwrap();
+    assert!(key_path.verify(&fetcher).unwrap());
+
+    let mut wrong_parity = tx.clone();
+    wrong_parity.tx_ins[0].witness[2][0] ^= 1;
+    assert!(!wrong_parity.verify(&fetcher).unwrap());
+    let mut wrong_path = tx.clone();
+    wrong_path.tx_ins[0].witness[2][40] ^= 1;
+    assert!(!wrong_path.verify(&fetcher).unwrap());
+    let mut wrong_sig = tx.clone();
+    wrong_sig.tx_ins[0].witness[0][10] ^= 1;
+    assert!(!wrong_sig.verify(&fetcher).unwrap());
+
+    // The other leaf commits correctly but cannot be executed yet
+    let mut other_leaf = tx.clone();
+    let control_block = tree.control_block(&internal_key, &[OP_1]).unwrap().unwrap();
+    other_leaf.tx_ins[0].witness = vec![vec![OP_1], control_block.serialize()];
+    assert_eq!(other_leaf.verify(&fetcher).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+}
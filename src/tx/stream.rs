@@ -0,0 +1,270 @@
+//! Parsing many transactions without holding them all.
+//!
+//! `TxStream` reads transactions one at a time from any `Read`, so a block
+//! can be processed as it arrives. `TxRef` parses from a byte slice and
+//! borrows scripts and witness items from it instead of copying them.
+
+use super::{LockTime, Sequence, Tx, TxIn, TxOut, SEGWIT_FLAG};
+use crate::encoding::util::{read_u32_le, read_u64_le};
+use crate::encoding::varint::read_varint_len;
+use crate::error::{Error, ErrorKind, Result};
+use crate::params::Network;
+use std::io::Read;
+
+/// Yields a known number of transactions from a reader, stopping at the
+/// first error.
+#[derive(Debug)]
+pub struct TxStream<R> {
+    reader: R,
+    remaining: usize,
+    network: Network,
+}
+
+impl<R: Read> TxStream<R> {
+    pub fn new(reader: R, count: usize, network: Network) -> TxStream<R> {
+        TxStream { reader, remaining: count, network }
+    }
+
+    /// Reads the count first, as blocks store it.
+    pub fn with_count_prefix(mut reader: R, network: Network) -> Result<TxStream<R>> {
+        let count = read_varint_len(&mut reader)?;
+        Ok(TxStream::new(reader, count, network))
+    }
+
+    /// Transactions not yet read.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// The reader, positioned after the transactions read so far.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for TxStream<R> {
+    type Item = Result<Tx>;
+
+    fn next(&mut self) -> Option<Result<Tx>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let tx = Tx::parse(&mut self.reader, self.network);
+        // The reader's position is unknown after an error
+        self.remaining = if tx.is_ok() { self.remaining - 1 } else { 0 };
+        Some(tx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+/// A transaction input borrowing its script_sig and witness.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TxInRef<'a> {
+    /// Id of the transaction being spent, in display order
+    pub prev_tx: [u8; 32],
+    pub prev_index: u32,
+    pub script_sig: &'a [u8],
+    pub sequence: Sequence,
+    pub witness: Vec<&'a [u8]>,
+}
+
+/// A transaction output borrowing its script_pubkey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TxOutRef<'a> {
+    pub amount: u64,
+    pub script_pubkey: &'a [u8],
+}
+
+/// A transaction parsed in place from a byte slice.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TxRef<'a> {
+    pub version: u32,
+    pub tx_ins: Vec<TxInRef<'a>>,
+    pub tx_outs: Vec<TxOutRef<'a>>,
+    pub locktime: LockTime,
+    raw: &'a [u8],
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} bytes left, {} needed", bytes.len(), len)));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_script<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = read_varint_len(bytes)?;
+    take(bytes, len)
+}
+
+impl<'a> TxRef<'a> {
+    /// Parses the transaction at the start of `bytes`, legacy or segwit,
+    /// and returns the bytes after it.
+    pub fn parse(bytes: &'a [u8]) -> Result<(TxRef<'a>, &'a [u8])> {
+        let mut rest = bytes;
+        let version = read_u32_le(&mut rest)?;
+        let mut num_inputs = read_varint_len(&mut rest)?;
+        let segwit = num_inputs == 0;
+        if segwit {
+            let flag = take(&mut rest, 1)?[0];
+            if flag != SEGWIT_FLAG {
+                return Err(Error::new(ErrorKind::InvalidEncoding, format!("unknown segwit flag {:#04x}", flag)));
+            }
+            num_inputs = read_varint_len(&mut rest)?;
+        }
+        // Counts are bounded by what is left, not trusted for allocation
+        let mut tx_ins = Vec::with_capacity(num_inputs.min(rest.len() / 41));
+        for _ in 0..num_inputs {
+            let mut prev_tx = [0u8; 32];
+            prev_tx.copy_from_slice(take(&mut rest, 32)?);
+            prev_tx.reverse();
+            let prev_index = read_u32_le(&mut rest)?;
+            let script_sig = take_script(&mut rest)?;
+            let sequence = Sequence::from_consensus(read_u32_le(&mut rest)?);
+            tx_ins.push(TxInRef { prev_tx, prev_index, script_sig, sequence, witness: Vec::new() });
+        }
+        let num_outputs = read_varint_len(&mut rest)?;
+        let mut tx_outs = Vec::with_capacity(num_outputs.min(rest.len() / 9));
+        for _ in 0..num_outputs {
+            let amount = read_u64_le(&mut rest)?;
+            tx_outs.push(TxOutRef { amount, script_pubkey: take_script(&mut rest)? });
+        }
+        if segwit {
+            for tx_in in tx_ins.iter_mut() {
+                let num_items = read_varint_len(&mut rest)?;
+                for _ in 0..num_items {
+                    tx_in.witness.push(take_script(&mut rest)?);
+                }
+            }
+            if tx_ins.iter().all(|tx_in| tx_in.witness.is_empty()) {
+                return Err(Error::new(ErrorKind::InvalidEncoding, "segwit marker without any witness data"));
+            }
+        }
+        let locktime = LockTime::from_consensus(read_u32_le(&mut rest)?);
+        let raw = &bytes[..bytes.len() - rest.len()];
+        Ok((TxRef { version, tx_ins, tx_outs, locktime, raw }, rest))
+    }
+
+    /// The bytes this transaction was parsed from, which are its
+    /// serialization.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Copies everything into an owned `Tx`.
+    pub fn to_tx(&self, network: Network) -> Tx {
+        let tx_ins = self
+            .tx_ins
+            .iter()
+            .map(|tx_in| TxIn {
+                prev_tx: tx_in.prev_tx,
+                prev_index: tx_in.prev_index,
+                script_sig: tx_in.script_sig.to_vec(),
+                sequence: tx_in.sequence,
+                witness: tx_in.witness.iter().map(|item| item.to_vec()).collect(),
+            })
+            .collect();
+        let tx_outs = self.tx_outs.iter().map(|out| TxOut::new(out.amount, out.script_pubkey.to_vec())).collect();
+        Tx::new(self.version, tx_ins, tx_outs, self.locktime, network)
+    }
+}
+
+/// Yields the transactions packed one after another in a byte slice.
+#[derive(Debug, Clone)]
+pub struct TxRefs<'a> {
+    bytes: &'a [u8],
+    remaining: usize,
+}
+
+impl<'a> TxRefs<'a> {
+    pub fn new(bytes: &'a [u8], count: usize) -> TxRefs<'a> {
+        TxRefs { bytes, remaining: count }
+    }
+
+    /// Reads the count first, as blocks store it.
+    pub fn with_count_prefix(mut bytes: &'a [u8]) -> Result<TxRefs<'a>> {
+        let count = read_varint_len(&mut bytes)?;
+        Ok(TxRefs::new(bytes, count))
+    }
+
+    /// The bytes after the transactions read so far.
+    pub fn rest(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> Iterator for TxRefs<'a> {
+    type Item = Result<TxRef<'a>>;
+
+    fn next(&mut self) -> Option<Result<TxRef<'a>>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match TxRef::parse(self.bytes) {
+            Ok((tx, rest)) => {
+                self.bytes = rest;
+                self.remaining -= 1;
+                Some(Ok(tx))
+            }
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+use super::{p2wpkh_script, CH5_TX};
+#[cfg(test)]
+use crate::encoding::util::decode_hex;
+#[cfg(test)]
+use crate::encoding::varint::encode_varint;
+
+#[test]
+fn stream_and_borrowed_parsing() {
+    let legacy = Tx::parse(&mut &decode_hex(CH5_TX).unwrap()[..], Network::Mainnet).unwrap();
+    let mut segwit = Tx::new(2, vec![TxIn::new([5; 32], 1)], vec![TxOut::new(1_000, p2wpkh_script(&[6; 20]))], LockTime::ZERO, Network::Mainnet);
+    segwit.tx_ins[0].witness = vec![vec![1, 2, 3], Vec::new()];
+    let txs = [legacy, segwit.clone(), segwit];
+    let mut raw = Vec::new();
+    encode_varint(&mut raw, txs.len() as u64).unwrap();
+    for tx in txs.iter() {
+        raw.extend_from_slice(&tx.serialize());
+    }
+    raw.extend_from_slice(b"trailer");
+
+    let mut stream = TxStream::with_count_prefix(&raw[..], Network::Mainnet).unwrap();
+    assert_eq!(stream.remaining(), 3);
+    assert_eq!(stream.next().unwrap().unwrap(), txs[0]);
+    assert_eq!(stream.by_ref().collect::<Result<Vec<_>>>().unwrap(), txs[1..].to_vec());
+    assert_eq!(stream.into_inner(), b"trailer");
+
+    let mut refs = TxRefs::with_count_prefix(&raw).unwrap();
+    for tx in txs.iter() {
+        let tx_ref = refs.next().unwrap().unwrap();
+        assert_eq!(tx_ref.as_bytes(), &tx.serialize()[..]);
+        assert_eq!(tx_ref.to_tx(Network::Mainnet), *tx);
+        // Scripts point into the original buffer
+        let script = tx_ref.tx_outs[0].script_pubkey;
+        assert!(raw.as_ptr_range().contains(&script.as_ptr()));
+    }
+    assert!(refs.next().is_none());
+    assert_eq!(refs.rest(), b"trailer");
+
+    // A truncated transaction is an error and ends iteration
+    let truncated = &raw[..raw.len() - 12];
+    assert_eq!(TxRefs::with_count_prefix(truncated).unwrap().filter(|tx| tx.is_err()).count(), 1);
+    let results: Vec<_> = TxStream::with_count_prefix(truncated, Network::Mainnet).unwrap().collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[2].is_err());
+}
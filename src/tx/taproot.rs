@@ -0,0 +1,200 @@
+//! Taproot script trees and control blocks (BIP341).
+//!
+//! A P2TR output key commits to an internal key and, optionally, the merkle
+//! root of a tree of scripts. Spending through a script reveals it together
+//! with a control block: the leaf version, the output key's parity, the
+//! internal key and the hashes along the path to the root.
+
+use crate::encoding::varint::encode_varint;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::tagged_hash;
+use crate::math::ecc::{Parity, XOnlyPoint};
+
+/// Leaf version of BIP342 tapscript.
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+// The low bit of the first control block byte holds the parity
+const LEAF_VERSION_MASK: u8 = 0xfe;
+const CONTROL_BLOCK_BASE_SIZE: usize = 33;
+const MAX_PATH_LEN: usize = 128;
+
+/// hash_TapLeaf(leaf_version || compact_size(script) || script)
+pub fn tap_leaf_hash(leaf_version: u8, script: &[u8]) -> [u8; 32] {
+    let mut data = vec![leaf_version];
+    encode_varint(&mut data, script.len() as u64).unwrap();
+    data.extend_from_slice(script);
+    tagged_hash("TapLeaf", &data)
+}
+
+/// hash_TapBranch of two child hashes, smallest first.
+pub fn tap_branch_hash(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    tagged_hash("TapBranch", &[&left[..], &right[..]].concat())
+}
+
+/// A binary tree of scripts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TapTree {
+    Leaf { leaf_version: u8, script: Vec<u8> },
+    Branch(Box<TapTree>, Box<TapTree>),
+}
+
+impl TapTree {
+    /// A tapscript leaf.
+    pub fn leaf(script: Vec<u8>) -> TapTree {
+        TapTree::Leaf { leaf_version: TAPSCRIPT_LEAF_VERSION, script }
+    }
+
+    pub fn branch(left: TapTree, right: TapTree) -> TapTree {
+        TapTree::Branch(Box::new(left), Box::new(right))
+    }
+
+    /// The leaf hash for a leaf, the branch hash of the children otherwise.
+    pub fn merkle_root(&self) -> [u8; 32] {
+        match self {
+            TapTree::Leaf { leaf_version, script } => tap_leaf_hash(*leaf_version, script),
+            TapTree::Branch(left, right) => tap_branch_hash(&left.merkle_root(), &right.merkle_root()),
+        }
+    }
+
+    /// The output key committing to `internal_key` and this tree.
+    pub fn output_key(&self, internal_key: &XOnlyPoint) -> Result<(XOnlyPoint, Parity)> {
+        internal_key.tweak_add(Some(&self.merkle_root()))
+    }
+
+    /// The control block spending through the first tapscript leaf holding
+    /// `script`, or `None` if the tree has no such leaf.
+    pub fn control_block(&self, internal_key: &XOnlyPoint, script: &[u8]) -> Result<Option<ControlBlock>> {
+        let merkle_branch = match self.path_to(TAPSCRIPT_LEAF_VERSION, script) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let (_, output_key_parity) = self.output_key(internal_key)?;
+        Ok(Some(ControlBlock {
+            leaf_version: TAPSCRIPT_LEAF_VERSION,
+            output_key_parity,
+            internal_key: internal_key.clone(),
+            merkle_branch,
+        }))
+    }
+
+    /// Sibling hashes from the leaf up to the root.
+    fn path_to(&self, version: u8, target: &[u8]) -> Option<Vec<[u8; 32]>> {
+        match self {
+            TapTree::Leaf { leaf_version, script } => {
+                if *leaf_version == version && script[..] == target[..] {
+                    Some(Vec::new())
+                } else {
+                    None
+                }
+            }
+            TapTree::Branch(left, right) => {
+                let (mut path, sibling) = match left.path_to(version, target) {
+                    Some(path) => (path, right),
+                    None => (right.path_to(version, target)?, left),
+                };
+                path.push(sibling.merkle_root());
+                Some(path)
+            }
+        }
+    }
+}
+
+/// The last witness element of a taproot script-path spend.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ControlBlock {
+    pub leaf_version: u8,
+    pub output_key_parity: Parity,
+    pub internal_key: XOnlyPoint,
+    /// Sibling hashes from the leaf up to the root
+    pub merkle_branch: Vec<[u8; 32]>,
+}
+
+impl ControlBlock {
+    /// Parses 33 + 32m bytes, m at most 128.
+    pub fn parse(bytes: &[u8]) -> Result<ControlBlock> {
+        let path_bytes = bytes.len().saturating_sub(CONTROL_BLOCK_BASE_SIZE);
+        if bytes.len() < CONTROL_BLOCK_BASE_SIZE || !path_bytes.is_multiple_of(32) || path_bytes / 32 > MAX_PATH_LEN {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("control block of {} bytes", bytes.len())));
+        }
+        let output_key_parity = if bytes[0] & 1 == 0 { Parity::Even } else { Parity::Odd };
+        let internal_key = XOnlyPoint::parse(&bytes[1..CONTROL_BLOCK_BASE_SIZE])?;
+        let merkle_branch = bytes[CONTROL_BLOCK_BASE_SIZE..]
+            .chunks(32)
+            .map(|chunk| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+        Ok(ControlBlock { leaf_version: bytes[0] & LEAF_VERSION_MASK, output_key_parity, internal_key, merkle_branch })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![self.leaf_version | self.output_key_parity.to_u8()];
+        out.extend_from_slice(&self.internal_key.serialize());
+        for hash in self.merkle_branch.iter() {
+            out.extend_from_slice(hash);
+        }
+        out
+    }
+
+    /// The merkle root reached from the leaf holding `script`.
+    pub fn merkle_root(&self, script: &[u8]) -> [u8; 32] {
+        self.merkle_branch
+            .iter()
+            .fold(tap_leaf_hash(self.leaf_version, script), |hash, sibling| tap_branch_hash(&hash, sibling))
+    }
+
+    /// Checks that `output_key` commits to `script` through this control
+    /// block: same x coordinate and same parity after tweaking.
+    pub fn verify_commitment(&self, output_key: &XOnlyPoint, script: &[u8]) -> bool {
+        match self.internal_key.tweak_add(Some(&self.merkle_root(script))) {
+            Ok((key, parity)) => &key == output_key && parity == self.output_key_parity,
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::encoding::util::{decode_hex, encode_hex};
+
+#[test]
+fn tap_tree_control_blocks() {
+    // Cross-checked with rust-bitcoin's TaprootBuilder
+    let internal_key =
+        XOnlyPoint::parse(&decode_hex("93c7378d96518a75448821c4f7c8f4bae7ce60f804d03d1f0628dd5dd0f5de51").unwrap())
+            .unwrap();
+    let scripts: Vec<Vec<u8>> = vec![vec![0x51], vec![0x52], vec![0x53]];
+    let control_blocks = [
+        "c193c7378d96518a75448821c4f7c8f4bae7ce60f804d03d1f0628dd5dd0f5de5196f5ef67a1641de7ef38da0361f157ad5b6874db8efd0ee0ef7cb84e4976ace6",
+        "c193c7378d96518a75448821c4f7c8f4bae7ce60f804d03d1f0628dd5dd0f5de51a8199db85e1f94b911a63ffece012bb8afc92131e59a614341db4ed2312a3c48a85b2107f791b26a84e7586c28cec7cb61202ed3d01944d832500f363782d675",
+        "c193c7378d96518a75448821c4f7c8f4bae7ce60f804d03d1f0628dd5dd0f5de51c276fef1386890619b80e10a4a328572d97493add269df1a15a7f89f8ae8ec09a85b2107f791b26a84e7586c28cec7cb61202ed3d01944d832500f363782d675",
+    ];
+    let tree = TapTree::branch(
+        TapTree::leaf(scripts[0].clone()),
+        TapTree::branch(TapTree::leaf(scripts[1].clone()), TapTree::leaf(scripts[2].clone())),
+    );
+    assert_eq!(encode_hex(&tree.merkle_root()), "49e49cd9cb39ef9dc8ddc4e55f918a2b53530516d1b9de4a0c3b646b8e7633cb");
+    let (output_key, parity) = tree.output_key(&internal_key).unwrap();
+    assert_eq!(encode_hex(&output_key.serialize()), "e26b89af8cf84187f5f4a103c97b3e74fbc85beac848dec12a0c0cf71df7c781");
+    assert_eq!(parity, Parity::Odd);
+
+    for (script, want) in scripts.iter().zip(control_blocks.iter()) {
+        let control_block = tree.control_block(&internal_key, script).unwrap().unwrap();
+        assert_eq!(encode_hex(&control_block.serialize()), *want);
+        let parsed = ControlBlock::parse(&control_block.serialize()).unwrap();
+        assert_eq!(parsed, control_block);
+        assert!(parsed.verify_commitment(&output_key, script));
+        assert!(!parsed.verify_commitment(&output_key, &[0x54]));
+    }
+    assert!(tree.control_block(&internal_key, &[0x54]).unwrap().is_none());
+
+    let mut flipped = tree.control_block(&internal_key, &scripts[0]).unwrap().unwrap();
+    flipped.output_key_parity = if flipped.output_key_parity == Parity::Even { Parity::Odd } else { Parity::Even };
+    assert!(!flipped.verify_commitment(&output_key, &scripts[0]));
+
+    assert!(ControlBlock::parse(&[0xc0; 32]).is_err());
+    assert!(ControlBlock::parse(&[0xc0; 34]).is_err());
+    assert!(ControlBlock::parse(&vec![0xc0; 33 + 32 * 129]).is_err());
+}
+
@@ -0,0 +1,212 @@
+//! A two-step vault, tying timelocked scripts, PSBTs and relative locks
+//! together.
+//!
+//! Coins are deposited to a P2WSH output that a vault key spends:
+//!
+//! `OP_IF <cold> OP_ELSE <vault> OP_ENDIF OP_CHECKSIG`
+//!
+//! Right after the deposit the vault key signs the unvault transaction,
+//! moving the coins to
+//!
+//! `OP_IF <cold> OP_ELSE <delay> OP_CHECKSEQUENCEVERIFY OP_DROP <hot>
+//! OP_ENDIF OP_CHECKSIG`
+//!
+//! and is then destroyed, so the unvault transaction is the only way out
+//! short of the cold key. Once it confirms, the hot key can withdraw after
+//! `delay` blocks; until then, a watcher seeing an unvault it didn't expect
+//! sends the coins to the cold key with `recover`, which also works on the
+//! deposit itself.
+//!
+//! Each step is a PSBT with the spent output and witness script filled in,
+//! for `Psbt::sign` with the key the step needs. `Psbt::finalize` only
+//! knows single-key and multisig scripts, so `Vault::finalize` picks the
+//! branch.
+
+use crate::address::Address;
+use crate::error::{Error, ErrorKind, Result};
+use crate::math::ecc::S256Point;
+use crate::params::Network;
+use crate::script::opcodes::OpCode;
+use crate::script::timelock::witness;
+use crate::script::{p2wpkh_script, Command, Script};
+use crate::tx::builder::{dust_threshold, Utxo};
+use crate::tx::psbt::Psbt;
+use crate::tx::{LockTime, Sequence, Tx, TxIn, TxOut};
+
+fn op(op: OpCode) -> Command {
+    Command::Op(op.to_u8())
+}
+
+fn key(pubkey: &S256Point) -> Command {
+    Command::Data(pubkey.sec(true))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vault {
+    /// Signs the unvault transaction, then is gone
+    pub vault_key: S256Point,
+    /// Withdraws once the delay has passed
+    pub hot_key: S256Point,
+    /// Recovers at any step, kept offline
+    pub cold_key: S256Point,
+    pub delay: Sequence,
+    pub network: Network,
+}
+
+impl Vault {
+    pub fn new(vault_key: S256Point, hot_key: S256Point, cold_key: S256Point, delay: Sequence, network: Network) -> Vault {
+        Vault { vault_key, hot_key, cold_key, delay, network }
+    }
+
+    /// The witness script of deposits.
+    pub fn deposit_script(&self) -> Script {
+        Script::new(vec![op(OpCode::If), key(&self.cold_key), op(OpCode::Else), key(&self.vault_key), op(OpCode::EndIf), op(OpCode::CheckSig)])
+    }
+
+    /// The witness script of the unvault transaction's output.
+    pub fn unvault_script(&self) -> Script {
+        Script::new(vec![
+            op(OpCode::If),
+            key(&self.cold_key),
+            op(OpCode::Else),
+            Command::num(self.delay.to_consensus_u32() as i64),
+            op(OpCode::CheckSequenceVerify),
+            op(OpCode::Drop),
+            key(&self.hot_key),
+            op(OpCode::EndIf),
+            op(OpCode::CheckSig),
+        ])
+    }
+
+    /// Where to deposit.
+    pub fn deposit_address(&self) -> Result<String> {
+        Ok(Address::from_script(&self.deposit_script().p2wsh_script_pubkey())?.encode(&self.network.params()))
+    }
+
+    /// The unvault transaction spending `deposit`, for the vault key.
+    pub fn unvault(&self, deposit: &Utxo, fee: u64) -> Result<Psbt> {
+        let unvault_script = self.unvault_script();
+        let mut psbt = self.spend(deposit, &self.deposit_script(), unvault_script.p2wsh_script_pubkey().raw_serialize(), fee, Sequence::MAX)?;
+        psbt.outputs[0].witness_script = Some(unvault_script.raw_serialize());
+        Ok(psbt)
+    }
+
+    /// A withdrawal of `unvaulted` to `script_pubkey`, for the hot key. It
+    /// can only confirm `delay` after the unvault transaction.
+    pub fn withdraw(&self, unvaulted: &Utxo, script_pubkey: Vec<u8>, fee: u64) -> Result<Psbt> {
+        self.spend(unvaulted, &self.unvault_script(), script_pubkey, fee, self.delay)
+    }
+
+    /// Sends a deposited or unvaulted `coin` to the cold key's P2WPKH, for
+    /// the cold key.
+    pub fn recover(&self, coin: &Utxo, fee: u64) -> Result<Psbt> {
+        let witness_script = vec![self.deposit_script(), self.unvault_script()]
+            .into_iter()
+            .find(|script| script.p2wsh_script_pubkey().raw_serialize() == coin.output.script_pubkey)
+            .ok_or_else(|| Error::new(ErrorKind::UnsupportedScript, "coin is not in the vault"))?;
+        let cold = p2wpkh_script(&self.cold_key.hash160(true)).raw_serialize();
+        self.spend(coin, &witness_script, cold, fee, Sequence::MAX)
+    }
+
+    // One input to one output, with what a signer needs
+    fn spend(&self, coin: &Utxo, witness_script: &Script, script_pubkey: Vec<u8>, fee: u64, sequence: Sequence) -> Result<Psbt> {
+        let amount = coin.output.amount.checked_sub(fee).filter(|amount| *amount >= dust_threshold(&script_pubkey));
+        let amount = amount.ok_or_else(|| {
+            Error::new(ErrorKind::OutOfRange, format!("{} sat coin leaves dust after the {} sat fee", coin.output.amount, fee))
+        })?;
+        let mut tx_in = TxIn::new(coin.prev_tx, coin.prev_index);
+        tx_in.sequence = sequence;
+        // Version 2 for OP_CHECKSEQUENCEVERIFY
+        let tx = Tx::new(2, vec![tx_in], vec![TxOut::new(amount, script_pubkey)], LockTime::ZERO, self.network);
+        let mut psbt = Psbt::new(tx)?;
+        psbt.inputs[0].witness_utxo = Some(coin.output.clone());
+        psbt.inputs[0].witness_script = Some(witness_script.raw_serialize());
+        Ok(psbt)
+    }
+
+    /// The signed transaction of a PSBT from this vault: the cold branch if
+    /// the cold key signed, the other one if the vault or hot key did.
+    pub fn finalize(&self, mut psbt: Psbt) -> Result<Tx> {
+        let input = &mut psbt.inputs[0];
+        let witness_script = input.witness_script.take().ok_or_else(|| Error::new(ErrorKind::InvalidSignature, "vault input has no witness script"))?;
+        let sigs = &input.partial_sigs;
+        let stack = match (sigs.get(&self.cold_key.sec(true)), sigs.get(&self.vault_key.sec(true)).or_else(|| sigs.get(&self.hot_key.sec(true)))) {
+            (Some(sig), _) => vec![sig.clone(), vec![1]],
+            (None, Some(sig)) => vec![sig.clone(), vec![]],
+            (None, None) => return Err(Error::new(ErrorKind::InvalidSignature, "vault input is not signed")),
+        };
+        input.final_script_witness = Some(witness(stack, &Script::from_bytes(&witness_script)?));
+        input.partial_sigs.clear();
+        psbt.extract()
+    }
+}
+
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use crate::script::interpreter::{verify_script_in, SpendContext, VerifyFlags};
+#[cfg(test)]
+use crate::tx::SighashType;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn vault_unvault_withdraw_and_recover() {
+    let keys: Vec<PrivateKey> = (1..=4).map(|n| PrivateKey::new(&BigInt::from(n * 7919)).unwrap()).collect();
+    let (vault_key, hot, cold, thief) = (&keys[0], &keys[1], &keys[2], &keys[3]);
+    let vault = Vault::new(vault_key.public_key().clone(), hot.public_key().clone(), cold.public_key().clone(), Sequence::from_height(144), Network::Regtest);
+    assert!(vault.deposit_address().unwrap().starts_with("bcrt1q"));
+
+    // Runs the input's witness against the coin it spends
+    let verify = |tx: &Tx, coin: &Utxo, witness_script: &Script| {
+        let z = tx.bip143_sig_hash(0, &witness_script.raw_serialize(), coin.output.amount, SighashType::All).unwrap();
+        let script_pubkey = Script::from_bytes(&coin.output.script_pubkey).unwrap();
+        let context = SpendContext::new(tx, 0).unwrap();
+        verify_script_in(&Script::default(), &script_pubkey, &tx.tx_ins[0].witness, &z, VerifyFlags::STANDARD, &context).unwrap().valid
+    };
+    let deposit = Utxo::new([5; 32], 1, TxOut::new(1_000_000, vault.deposit_script().p2wsh_script_pubkey().raw_serialize()));
+
+    // Pre-signed by the vault key, passed around as base64
+    let mut psbt = vault.unvault(&deposit, 1_000).unwrap();
+    assert_eq!(psbt.clone().sign(hot).unwrap(), 0);
+    assert_eq!(psbt.sign(vault_key).unwrap(), 1);
+    let psbt = Psbt::from_base64(&psbt.to_base64()).unwrap();
+    assert_eq!(psbt.outputs[0].witness_script, Some(vault.unvault_script().raw_serialize()));
+    let unvault_tx = vault.finalize(psbt).unwrap();
+    assert!(verify(&unvault_tx, &deposit, &vault.deposit_script()));
+    assert!(vault.finalize(vault.unvault(&deposit, 1_000).unwrap()).is_err());
+    assert!(vault.unvault(&deposit, 999_900).is_err());
+
+    let unvaulted = Utxo::new(unvault_tx.hash(), 0, unvault_tx.tx_outs[0].clone());
+    assert_eq!(unvaulted.output.amount, 999_000);
+    let destination = p2wpkh_script(&[9; 20]).raw_serialize();
+    let mut psbt = vault.withdraw(&unvaulted, destination.clone(), 1_000).unwrap();
+    assert_eq!(psbt.sign(hot).unwrap(), 1);
+    let withdrawal = vault.finalize(psbt).unwrap();
+    assert!(verify(&withdrawal, &unvaulted, &vault.unvault_script()));
+    assert_eq!(withdrawal.tx_outs, vec![TxOut::new(998_000, destination.clone())]);
+    // The unvault confirmed in block 200
+    assert!(!withdrawal.sequence_locks_satisfied(&[(200, 0)], 343, 0).unwrap());
+    assert!(withdrawal.sequence_locks_satisfied(&[(200, 0)], 344, 0).unwrap());
+
+    // A shorter delay fails OP_CHECKSEQUENCEVERIFY, even signed
+    let mut psbt = vault.withdraw(&unvaulted, destination.clone(), 1_000).unwrap();
+    psbt.unsigned_tx.tx_ins[0].sequence = Sequence::from_height(143);
+    psbt.sign(hot).unwrap();
+    assert!(!verify(&vault.finalize(psbt).unwrap(), &unvaulted, &vault.unvault_script()));
+    // Only the hot key withdraws
+    let mut psbt = vault.withdraw(&unvaulted, destination, 1_000).unwrap();
+    assert_eq!(psbt.sign(thief).unwrap(), 0);
+
+    // The cold key takes the coins back at either step, with no delay
+    for (coin, witness_script) in [(&deposit, vault.deposit_script()), (&unvaulted, vault.unvault_script())] {
+        let mut psbt = vault.recover(coin, 1_000).unwrap();
+        assert_eq!(psbt.unsigned_tx.tx_ins[0].sequence, Sequence::MAX);
+        assert_eq!(psbt.sign(cold).unwrap(), 1);
+        let recovery = vault.finalize(psbt).unwrap();
+        assert!(verify(&recovery, coin, &witness_script));
+        assert_eq!(recovery.tx_outs[0].script_pubkey, p2wpkh_script(&cold.public_key().hash160(true)).raw_serialize());
+    }
+    let elsewhere = Utxo::new([6; 32], 0, TxOut::new(50_000, p2wpkh_script(&[9; 20]).raw_serialize()));
+    assert_eq!(vault.recover(&elsewhere, 1_000).unwrap_err().kind(), ErrorKind::UnsupportedScript);
+}
@@ -0,0 +1,171 @@
+//! Wallet backups: a `Wallet` file, so the descriptors, labels and synced
+//! state, and the wallet's mnemonic if it has one.
+//!
+//! A backup is a text file starting with a "backup N" line and ending with
+//! a checksum line, the SHA256 of everything before it, so a damaged or
+//! truncated backup is refused rather than restored. In between are a
+//! "seed" line holding the mnemonic's words, if given, then the wallet
+//! file. Anyone reading a backup with a seed line can spend the wallet's
+//! coins.
+
+use crate::bip39::Mnemonic;
+use crate::encoding::util::encode_hex;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::sha256;
+use crate::network::sync::{HeaderStore, HeaderSync};
+use crate::wallet::descriptor::{Wallet, FILE_VERSION};
+use crate::wallet::spv::split_version;
+use std::fs;
+use std::path::Path;
+
+/// Version of the backups `Backup::to_text` writes.
+pub const BACKUP_VERSION: u32 = 1;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("bad backup: {}", msg))
+}
+
+pub struct Backup {
+    pub mnemonic: Option<Mnemonic>,
+    /// The wallet file, "wallet N" line and all
+    wallet: String,
+}
+
+impl Backup {
+    pub fn new<S: HeaderStore>(wallet: &Wallet<S>, mnemonic: Option<Mnemonic>) -> Backup {
+        Backup { mnemonic, wallet: wallet.to_file_text() }
+    }
+
+    /// A backup of the wallet file `Wallet::save` wrote to `path`.
+    pub fn from_wallet_file<P: AsRef<Path>>(path: P, mnemonic: Option<Mnemonic>) -> Result<Backup> {
+        let wallet = fs::read_to_string(path)?;
+        check_wallet_version(&wallet)?;
+        Ok(Backup { mnemonic, wallet })
+    }
+
+    /// The wallet file in the backup, for `Wallet::load`.
+    pub fn wallet_file(&self) -> &str {
+        &self.wallet
+    }
+
+    /// The wallet, scanning the headers `sync` holds.
+    pub fn restore<S: HeaderStore>(&self, sync: HeaderSync<S>) -> Result<Wallet<S>> {
+        let (version, rest) = check_wallet_version(&self.wallet)?;
+        Wallet::read_lines(version, rest, sync)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("backup {}\n", BACKUP_VERSION);
+        if let Some(mnemonic) = &self.mnemonic {
+            text.push_str(&format!("seed {}\n", mnemonic));
+        }
+        text.push_str(&self.wallet);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let checksum = encode_hex(&sha256(text.as_bytes()));
+        text + "checksum " + &checksum + "\n"
+    }
+
+    /// Reads what `to_text` wrote, checking the checksum.
+    pub fn parse(text: &str) -> Result<Backup> {
+        let (body, last) = text.trim_end_matches('\n').rsplit_once('\n').ok_or_else(|| invalid("no checksum"))?;
+        let body = format!("{}\n", body);
+        match last.strip_prefix("checksum ") {
+            Some(checksum) if checksum == encode_hex(&sha256(body.as_bytes())) => {}
+            Some(_) => return Err(invalid("checksum mismatch")),
+            None => return Err(invalid("no checksum")),
+        }
+        let (first, mut rest) = body.split_once('\n').unwrap();
+        match first.strip_prefix("backup ").and_then(|version| version.parse::<u32>().ok()) {
+            Some(BACKUP_VERSION) => {}
+            Some(version) => return Err(invalid(&format!("version {} is not supported", version))),
+            None => return Err(invalid("no version")),
+        }
+        let mut mnemonic = None;
+        if let Some(seed) = rest.strip_prefix("seed ") {
+            let (words, after) = seed.split_once('\n').unwrap();
+            mnemonic = Some(Mnemonic::parse(words)?);
+            rest = after;
+        }
+        check_wallet_version(rest)?;
+        Ok(Backup { mnemonic, wallet: rest.to_string() })
+    }
+
+    /// Writes the backup to `path`, replacing any old file atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        super::write_atomic(path.as_ref(), &self.to_text())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Backup> {
+        Backup::parse(&fs::read_to_string(path)?)
+    }
+}
+
+// The version of the wallet file `text` and the lines after its first
+fn check_wallet_version(text: &str) -> Result<(u32, &str)> {
+    match split_version(text) {
+        Some((version, rest)) if (1..=FILE_VERSION).contains(&version) => Ok((version, rest)),
+        Some((version, _)) => Err(invalid(&format!("a version {} wallet file", version))),
+        None => Err(invalid("no wallet file")),
+    }
+}
+
+#[cfg(test)]
+use crate::bip32::KeyScript;
+#[cfg(test)]
+use crate::block::BlockHeader;
+#[cfg(test)]
+use crate::descriptor::Descriptor;
+#[cfg(test)]
+use crate::network::sync::MemoryHeaderStore;
+#[cfg(test)]
+use crate::params::{Network, NetworkParams};
+#[cfg(test)]
+use crate::tx::{LockTime, Tx, TxIn, TxOut};
+#[cfg(test)]
+use crate::wallet::{Birthday, Keychain, SpvWallet, TrustPolicy};
+
+#[test]
+fn backup_round_trip() {
+    let genesis = BlockHeader::parse(&mut &crate::encoding::util::decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let mnemonic = Mnemonic::from_entropy(&[5; 16]).unwrap();
+    let account = mnemonic.to_master_key("", Network::Regtest).unwrap().derive_path(&[0x8000_0054, 0x8000_0001, 0x8000_0000]).unwrap().extended_pub_key();
+    let mut wallet = Wallet::new(SpvWallet::new(HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), NetworkParams::regtest()).unwrap(), 1), 5);
+    wallet.add_xpub(&account.to_slip132(KeyScript::P2wpkh), None, Birthday::Height(1)).unwrap();
+    let address = wallet.next_address(Keychain::Receive).unwrap();
+    wallet.set_label(&address, "savings").unwrap();
+
+    // Paid at the address handed out and at the one after, in a block
+    let receive = Descriptor::parse(&format!("wpkh({}/0/*)", account)).unwrap();
+    let script = |index: u32| receive.script_pubkey(index).unwrap().raw_serialize();
+    let pay = Tx::new(2, vec![TxIn::new([3; 32], 0)], vec![TxOut::new(40_000, script(0)), TxOut::new(2_000, script(1))], LockTime::ZERO, Network::Regtest);
+    let block = super::spv::mine_block(&genesis, vec![pay]);
+    wallet.spv.sync.add_headers(std::slice::from_ref(&block.header)).unwrap();
+    assert_eq!(wallet.scan_block(&block).unwrap(), 1);
+    let synced = || {
+        let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), NetworkParams::regtest()).unwrap();
+        sync.add_headers(std::slice::from_ref(&block.header)).unwrap();
+        sync
+    };
+
+    let text = Backup::new(&wallet, Some(mnemonic.clone())).to_text();
+    assert!(text.starts_with(&format!("backup 1\nseed {}\nwallet ", mnemonic)));
+    let backup = Backup::parse(&text).unwrap();
+    assert_eq!(backup.mnemonic, Some(mnemonic));
+    let mut restored = backup.restore(synced()).unwrap();
+    assert_eq!(restored.spv.balance(TrustPolicy::TrustAll), wallet.spv.balance(TrustPolicy::TrustAll));
+    assert_eq!(restored.spv.balance(TrustPolicy::TrustAll).confirmed, 42_000);
+    assert_eq!(restored.label(&address), Some("savings"));
+    for keychain in [Keychain::Receive, Keychain::Change].iter() {
+        for _ in 0..3 {
+            assert_eq!(restored.next_address(*keychain).unwrap(), wallet.next_address(*keychain).unwrap());
+        }
+    }
+
+    // Any change to a backup is caught
+    assert!(Backup::parse(&text.replacen("savings", "spending", 1)).is_err());
+    assert!(Backup::parse(&text[..text.len() - 10]).is_err());
+    let body = text.rsplit_once("checksum").unwrap().0.replacen("backup 1", "backup 2", 1);
+    assert!(Backup::parse(&format!("{}checksum {}\n", body, encode_hex(&sha256(body.as_bytes())))).is_err());
+}
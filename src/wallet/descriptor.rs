@@ -0,0 +1,394 @@
+//! A watch-only wallet over descriptors, deriving its scripts with a gap
+//! limit the way BIP44 wallets do.
+//!
+//! Each descriptor belongs to the receive or the change keychain. Scripts
+//! are derived and watched up to `gap_limit` past the last index used on
+//! the chain, and the lookahead grows as scanned transactions use them.
+//! The first descriptor added to a keychain hands out its fresh
+//! addresses; any others are only watched.
+//!
+//! `save` writes an `SpvWallet` file plus the gap limit, each chain's
+//! descriptor and indices, and labels. `load` also opens `SpvWallet`
+//! files as a wallet with no descriptors yet.
+
+use crate::block::{Block, MerkleBlock};
+use crate::bloom::BLOOM_UPDATE_ALL;
+use crate::descriptor::Descriptor;
+use crate::error::{Error, ErrorKind, Result};
+use crate::network::sync::{HeaderStore, HeaderSync};
+use crate::network::SimpleNode;
+use crate::tx::psbt::KeySource;
+use crate::tx::Tx;
+use crate::wallet::spv::{invalid_line, parse_number, read_wallet_file, unsupported_version, Birthday, SpvWallet};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Version of the files `Wallet::save` writes, numbered along with
+/// `SpvWallet` files, see `spv::FILE_VERSION`.
+pub const FILE_VERSION: u32 = 4;
+
+/// Unused addresses watched past the last used one, as in BIP44.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Keychain {
+    /// Addresses given out to be paid
+    Receive,
+    /// Addresses the wallet pays its change to
+    Change,
+}
+
+struct Chain {
+    keychain: Keychain,
+    descriptor: Descriptor,
+    /// Indices below this are derived and watched
+    derived: u32,
+    last_used: Option<u32>,
+    /// The next index to hand out
+    next: u32,
+}
+
+pub struct Wallet<S: HeaderStore> {
+    pub spv: SpvWallet<S>,
+    gap_limit: u32,
+    chains: Vec<Chain>,
+    /// Derived script_pubkeys, with their chain and index
+    scripts: HashMap<Vec<u8>, (usize, u32)>,
+    /// Labels by address or txid
+    labels: BTreeMap<String, String>,
+}
+
+impl<S: HeaderStore> Wallet<S> {
+    /// A wallet watching through `spv`, which needn't watch anything yet.
+    pub fn new(spv: SpvWallet<S>, gap_limit: u32) -> Wallet<S> {
+        Wallet { spv, gap_limit, chains: vec![], scripts: HashMap::new(), labels: BTreeMap::new() }
+    }
+
+    pub fn gap_limit(&self) -> u32 {
+        self.gap_limit
+    }
+
+    /// Watches `descriptor` as part of `keychain`. One that isn't ranged
+    /// has a single script, at index 0. Scanning for it starts at
+    /// `birthday`, see `SpvWallet::set_birthday`.
+    pub fn add_descriptor(&mut self, descriptor: Descriptor, keychain: Keychain, birthday: Birthday) -> Result<()> {
+        let height = birthday.height(&self.spv.sync);
+        self.spv.set_birthday(height);
+        self.chains.push(Chain { keychain, descriptor, derived: 0, last_used: None, next: 0 });
+        self.derive(self.chains.len() - 1).map(|_| ())
+    }
+
+    /// Watches an account key another wallet exported, an xpub, ypub or
+    /// zpub: its `/0/*` chain receives and its `/1/*` chain takes change.
+    pub fn add_xpub(&mut self, key: &str, origin: Option<KeySource>, birthday: Birthday) -> Result<()> {
+        let (receive, change) = Descriptor::from_slip132(key, origin)?;
+        self.add_descriptor(receive, Keychain::Receive, birthday)?;
+        self.add_descriptor(change, Keychain::Change, birthday)
+    }
+
+    // Derives and watches `chain` up to the gap limit past its last used
+    // and last handed out indices, returning whether it watched anything new
+    fn derive(&mut self, chain: usize) -> Result<bool> {
+        let Chain { descriptor, derived, last_used, next, .. } = &self.chains[chain];
+        let end = if descriptor.is_ranged() { last_used.map_or(0, |used| used + 1).max(*next).saturating_add(self.gap_limit) } else { 1 };
+        let start = *derived;
+        for index in start..end {
+            let script_pubkey = self.chains[chain].descriptor.script_pubkey(index)?.raw_serialize();
+            self.spv.watch_script(script_pubkey.clone());
+            self.scripts.insert(script_pubkey, (chain, index));
+        }
+        self.chains[chain].derived = end.max(start);
+        Ok(end > start)
+    }
+
+    // The chain that hands out `keychain`'s addresses
+    fn first_chain(&self, keychain: Keychain) -> Result<usize> {
+        self.chains.iter().position(|chain| chain.keychain == keychain).ok_or_else(|| Error::new(ErrorKind::OutOfRange, format!("no {:?} descriptor", keychain)))
+    }
+
+    /// The keychain and index that derive `script_pubkey`, if watched.
+    pub fn is_mine(&self, script_pubkey: &[u8]) -> Option<(Keychain, u32)> {
+        self.scripts.get(script_pubkey).map(|(chain, index)| (self.chains[*chain].keychain, *index))
+    }
+
+    /// The highest index of `keychain` a scanned transaction paid.
+    pub fn last_used(&self, keychain: Keychain) -> Option<u32> {
+        self.chains[self.first_chain(keychain).ok()?].last_used
+    }
+
+    /// An address of `keychain` that hasn't been used or handed out. A
+    /// descriptor that isn't ranged always gives its one address.
+    pub fn next_address(&mut self, keychain: Keychain) -> Result<String> {
+        let chain = self.first_chain(keychain)?;
+        let index = match &mut self.chains[chain] {
+            Chain { descriptor, .. } if !descriptor.is_ranged() => 0,
+            Chain { last_used, next, .. } => {
+                let index = last_used.map_or(0, |used| used + 1).max(*next);
+                *next = index + 1;
+                index
+            }
+        };
+        self.derive(chain)?;
+        self.chains[chain].descriptor.address(index, self.spv.sync.params())
+    }
+
+    /// Labels an address or txid; an empty label removes it. Labels are
+    /// one line, and what they label has no spaces.
+    pub fn set_label(&mut self, key: &str, label: &str) -> Result<()> {
+        if key.is_empty() || key.contains(char::is_whitespace) || label.contains(['\n', '\r']) {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("label {:?} for {:?}", label, key)));
+        }
+        if label.is_empty() {
+            self.labels.remove(key);
+        } else {
+            self.labels.insert(key.to_string(), label.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+
+    /// Marks the indices `txs` pay as used, growing the lookahead. Returns
+    /// whether new scripts are watched, so a bloom filter is out of date.
+    ///
+    /// Scanning calls this once the block checks out, so a rejected one
+    /// uses nothing up, and before the `SpvWallet` scans it, so it sees
+    /// outputs to the scripts the lookahead just reached.
+    pub fn mark_used(&mut self, txs: &[Tx]) -> Result<bool> {
+        let mut grew = false;
+        // An output can pay a script the lookahead only just reached
+        loop {
+            let mut used = vec![];
+            for tx_out in txs.iter().flat_map(|tx| tx.tx_outs.iter()) {
+                if let Some((chain, index)) = self.scripts.get(&tx_out.script_pubkey) {
+                    if self.chains[*chain].last_used.is_none_or(|used| used < *index) {
+                        used.push((*chain, *index));
+                    }
+                }
+            }
+            if used.is_empty() {
+                return Ok(grew);
+            }
+            for (chain, index) in used {
+                let last_used = &mut self.chains[chain].last_used;
+                *last_used = Some(last_used.map_or(index, |used| used.max(index)));
+                grew |= self.derive(chain)?;
+            }
+        }
+    }
+
+    /// Records a transaction the mempool relayed.
+    pub fn add_unconfirmed(&mut self, tx: &Tx) -> Result<bool> {
+        // Ignored until mined, see `SpvWallet::apply_tx`
+        if self.spv.replaced_by(&tx.hash()).is_some() {
+            return Ok(false);
+        }
+        self.mark_used(std::slice::from_ref(tx))?;
+        Ok(self.spv.add_unconfirmed(tx))
+    }
+
+    /// Scans the next block from its merkleblock, as `SpvWallet` does.
+    pub fn scan_merkle_block(&mut self, merkle_block: &MerkleBlock, txs: &[Tx]) -> Result<usize> {
+        self.spv.check_merkle_block(merkle_block, txs)?;
+        self.mark_used(txs)?;
+        self.spv.scan_merkle_block(merkle_block, txs)
+    }
+
+    /// Scans the next block in full, as `SpvWallet` does.
+    pub fn scan_block(&mut self, block: &Block) -> Result<usize> {
+        self.spv.check_block(block)?;
+        self.mark_used(&block.txs)?;
+        self.spv.scan_block(block)
+    }
+
+    /// Syncs headers from `node` and scans every block up to the tip as a
+    /// filtered block, loading a new filter whenever the lookahead grows.
+    /// Returns how many transactions touched the wallet.
+    pub fn scan(&mut self, node: &mut SimpleNode) -> Result<usize> {
+        self.spv.sync.sync(node)?;
+        self.spv.handle_chain_events();
+        node.load_filter(&self.spv.bloom_filter(0.0001, 0)?, BLOOM_UPDATE_ALL)?;
+        let mut found = 0;
+        while let Some(hash) = self.spv.next_block() {
+            let (merkle_block, txs) = node.get_filtered_block(&hash)?;
+            self.spv.check_merkle_block(&merkle_block, &txs)?;
+            let grew = self.mark_used(&txs)?;
+            found += self.spv.scan_merkle_block(&merkle_block, &txs)?;
+            if grew {
+                node.load_filter(&self.spv.bloom_filter(0.0001, 0)?, BLOOM_UPDATE_ALL)?;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Saves the wallet to `path`, replacing any old file atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        super::write_atomic(path.as_ref(), &self.to_file_text())
+    }
+
+    /// What `save` writes.
+    pub fn to_file_text(&self) -> String {
+        let mut text = format!("wallet {}\ngap_limit {}\n", FILE_VERSION, self.gap_limit);
+        for Chain { keychain, descriptor, last_used, next, .. } in self.chains.iter() {
+            let keychain = if *keychain == Keychain::Receive { "receive" } else { "change" };
+            let last_used = last_used.map_or_else(|| "-".to_string(), |used| used.to_string());
+            text.push_str(&format!("chain {} {} {} {}\n", keychain, next, last_used, descriptor.to_string_with_checksum()));
+        }
+        self.spv.write_state(&mut text);
+        text.extend(self.labels.iter().map(|(key, label)| format!("label {} {}\n", key, label)));
+        text
+    }
+
+    /// Loads a wallet `save` wrote, or migrates an `SpvWallet` file, which
+    /// gets the default gap limit. Scans the headers `sync` holds.
+    pub fn load<P: AsRef<Path>>(path: P, sync: HeaderSync<S>) -> Result<Wallet<S>> {
+        let (version, text) = read_wallet_file(path.as_ref())?;
+        if version == 0 || version > FILE_VERSION {
+            return Err(unsupported_version(path.as_ref(), version));
+        }
+        Wallet::read_lines(version, &text, sync)
+    }
+
+    // The lines after the "wallet N" one of a version `version` file
+    pub(super) fn read_lines(version: u32, text: &str, sync: HeaderSync<S>) -> Result<Wallet<S>> {
+        let mut wallet = Wallet::new(SpvWallet::new(sync, 0), DEFAULT_GAP_LIMIT);
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if wallet.spv.read_state(version, &fields, line)? {
+                continue;
+            }
+            // Version 1 is an SpvWallet file, without the lines below
+            if version == 1 {
+                return Err(Error::new(ErrorKind::InvalidEncoding, format!("{:?} in a version 1 wallet file", line)));
+            }
+            match (fields[0], fields.len()) {
+                ("gap_limit", 2) => wallet.gap_limit = parse_number(fields[1], line)?,
+                ("chain", 5) => {
+                    let keychain = match fields[1] {
+                        "receive" => Keychain::Receive,
+                        "change" => Keychain::Change,
+                        _ => return Err(invalid_line(line)),
+                    };
+                    let last_used = if fields[3] == "-" { None } else { Some(parse_number(fields[3], line)?) };
+                    let descriptor = Descriptor::parse(fields[4])?;
+                    wallet.chains.push(Chain { keychain, descriptor, derived: 0, last_used, next: parse_number(fields[2], line)? });
+                }
+                ("label", n) if n >= 2 => {
+                    // The label is the rest of the line, spaces and all
+                    let label = line.splitn(3, ' ').nth(2).unwrap_or("");
+                    wallet.labels.insert(fields[1].to_string(), label.to_string());
+                }
+                _ => return Err(invalid_line(line)),
+            }
+        }
+        for chain in 0..wallet.chains.len() {
+            wallet.derive(chain)?;
+        }
+        Ok(wallet)
+    }
+}
+
+#[cfg(test)]
+use crate::bip32::{ExtendedPrivKey, KeyScript};
+#[cfg(test)]
+use crate::block::BlockHeader;
+#[cfg(test)]
+use crate::encoding::util::encode_hex;
+#[cfg(test)]
+use crate::network::sync::MemoryHeaderStore;
+#[cfg(test)]
+use crate::params::{Network, NetworkParams};
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn, TxOut};
+#[cfg(test)]
+use crate::wallet::spv::{TrustPolicy, TIMESTAMP_WINDOW};
+
+#[test]
+fn gap_limit_wallet() {
+    let regtest = NetworkParams::regtest();
+    let genesis = BlockHeader::parse(&mut &crate::encoding::util::decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let account = ExtendedPrivKey::new_master(&[3; 32], Network::Regtest).unwrap().extended_pub_key();
+    let receive = Descriptor::parse(&format!("wpkh({}/0/*)", account)).unwrap();
+    let pay = |i: u32, amount: u64| {
+        let outs = vec![TxOut::new(amount, receive.script_pubkey(i).unwrap().raw_serialize())];
+        Tx::new(2, vec![TxIn::new([i as u8; 32], 0)], outs, LockTime::ZERO, Network::Regtest)
+    };
+    let mine = |prev: &BlockHeader, txs: Vec<Tx>| {
+        let mut block = Block { header: BlockHeader { prev_block: prev.hash(), timestamp: prev.timestamp + 600, ..prev.clone() }, txs };
+        block.header.merkle_root = block.merkle_root();
+        while !block.header.check_pow() {
+            block.header.nonce += 1;
+        }
+        block
+    };
+    // Index 5 is past the first lookahead, but not once index 2 is used
+    let block1 = mine(&genesis, vec![pay(2, 1_000)]);
+    let block2 = mine(&block1.header, vec![pay(5, 2_000), pay(9, 4_000)]);
+    let headers = [block1.header.clone(), block2.header.clone()];
+    let synced = || {
+        let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), regtest.clone()).unwrap();
+        sync.add_headers(&headers).unwrap();
+        sync
+    };
+
+    let mut wallet = Wallet::new(SpvWallet::new(synced(), 1), 3);
+    wallet.add_xpub(&account.to_slip132(KeyScript::P2wpkh), None, Birthday::Height(1)).unwrap();
+    assert_eq!(wallet.is_mine(&receive.script_pubkey(2).unwrap().raw_serialize()), Some((Keychain::Receive, 2)));
+    assert_eq!(wallet.is_mine(&receive.script_pubkey(5).unwrap().raw_serialize()), None);
+    // A block out of order uses nothing up
+    let early = mine(&block1.header, vec![pay(2, 1_000)]);
+    assert_eq!(wallet.scan_block(&early).unwrap_err().kind(), ErrorKind::Fetch);
+    assert_eq!(wallet.last_used(Keychain::Receive), None);
+    assert_eq!(wallet.is_mine(&receive.script_pubkey(3).unwrap().raw_serialize()), None);
+    wallet.scan_block(&block1).unwrap();
+    assert_eq!(wallet.last_used(Keychain::Receive), Some(2));
+    // Index 9 stays out of reach: 5 is the last used, watching up to 8
+    assert_eq!(wallet.scan_block(&block2).unwrap(), 1);
+    assert_eq!((wallet.last_used(Keychain::Receive), wallet.spv.balance(TrustPolicy::TrustAll).confirmed), (Some(5), 3_000));
+
+    let fresh = receive.address(6, &NetworkParams::regtest()).unwrap();
+    assert_eq!(wallet.next_address(Keychain::Receive).unwrap(), fresh);
+    assert_ne!(wallet.next_address(Keychain::Receive).unwrap(), fresh);
+    assert_eq!(wallet.next_address(Keychain::Change).unwrap(), Descriptor::parse(&format!("wpkh({}/1/*)", account)).unwrap().address(0, &NetworkParams::regtest()).unwrap());
+    assert_eq!(wallet.last_used(Keychain::Change), None);
+
+    // A fresh wallet skips the blocks before its keys' birthday, found
+    // from the header timestamps; an older key imported later rescans
+    let mut dated = Wallet::new(SpvWallet::new(synced(), 0), 3);
+    assert_eq!(Birthday::Time(genesis.timestamp + 2 * 600 + TIMESTAMP_WINDOW).height(&dated.spv.sync), 2);
+    assert_eq!(Birthday::Time(u32::MAX).height(&dated.spv.sync), 3);
+    dated.add_xpub(&account.to_slip132(KeyScript::P2wpkh), None, Birthday::Time(block1.header.timestamp + TIMESTAMP_WINDOW)).unwrap();
+    assert_eq!((dated.spv.birth_height(), dated.spv.next_block()), (1, Some(block1.header.hash())));
+    dated.scan_block(&block1).unwrap();
+    dated.scan_block(&block2).unwrap();
+    dated.add_descriptor(Descriptor::parse(&format!("wpkh({}/2/*)", account)).unwrap(), Keychain::Receive, Birthday::Height(2)).unwrap();
+    assert_eq!((dated.spv.birth_height(), dated.spv.scanned_height()), (1, Some(1)));
+
+    // Saved and loaded, then an SpvWallet file migrated, which can only
+    // hold SpvWallet lines
+    wallet.set_label(&fresh, "from  alice ").unwrap();
+    wallet.set_label(&encode_hex(&block2.txs[0].hash()), "rent").unwrap();
+    assert!(wallet.set_label("a b", "x").is_err());
+    let path = std::env::temp_dir().join(format!("prog_btc_book_wallet_{}", std::process::id()));
+    wallet.save(&path).unwrap();
+    let mut loaded = Wallet::load(&path, synced()).unwrap();
+    assert_eq!((loaded.gap_limit(), loaded.last_used(Keychain::Receive), loaded.spv.balance(TrustPolicy::TrustAll)), (3, Some(5), wallet.spv.balance(TrustPolicy::TrustAll)));
+    assert_eq!(loaded.label(&fresh), Some("from  alice "));
+    assert_eq!(loaded.next_address(Keychain::Receive).unwrap(), receive.address(8, &regtest).unwrap());
+    assert!(loaded.is_mine(&receive.script_pubkey(10).unwrap().raw_serialize()).is_some());
+
+    wallet.spv.save(&path).unwrap();
+    let migrated = Wallet::load(&path, synced()).unwrap();
+    assert_eq!((migrated.gap_limit(), migrated.last_used(Keychain::Receive), migrated.spv.scanned_height()), (DEFAULT_GAP_LIMIT, None, Some(2)));
+    assert!(migrated.spv.is_watched(&receive.script_pubkey(8).unwrap().raw_serialize()));
+    // An SpvWallet can't read what it doesn't know
+    loaded.save(&path).unwrap();
+    assert!(SpvWallet::load(&path, synced()).is_err());
+    std::fs::write(&path, "wallet 1\nbirth 1\nnext 1\ngap_limit 5\n").unwrap();
+    assert!(matches!(Wallet::load(&path, synced()), Err(err) if err.kind() == ErrorKind::InvalidEncoding));
+    std::fs::write(&path, format!("wallet 1\nlabel {} rent\n", fresh)).unwrap();
+    assert!(Wallet::load(&path, synced()).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,41 @@
+//! Wallets built from the crate's pieces (chapter 12 on).
+
+pub mod backup;
+pub mod descriptor;
+pub mod reserves;
+pub mod spv;
+pub mod utxo;
+
+pub use backup::Backup;
+pub use descriptor::{Keychain, Wallet, DEFAULT_GAP_LIMIT};
+pub use spv::{Balance, Birthday, SpvWallet, TrustPolicy, WalletOutput};
+pub use utxo::{BlockUndo, Coin, FileUtxoStore, MemoryUtxoStore, OutPoint, UtxoStore};
+
+use crate::error::Result;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Replaces `path` with `text` so that even after a power loss it holds
+/// the old contents or the new, never a mix. The text goes to a file
+/// beside it, which is synced to disk before being renamed over `path`,
+/// and the directory is synced so the rename lasts too.
+pub(crate) fn write_atomic(path: &Path, text: &str) -> Result<()> {
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, path)?;
+    // Windows can't open a directory to sync it
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
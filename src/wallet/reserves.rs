@@ -0,0 +1,186 @@
+//! Proof of reserves in the style of BIP127: a PSBT that could never be
+//! mined, spending the coins being proven.
+//!
+//! Its first input spends output 0 of a transaction that doesn't exist,
+//! whose id commits to a message, say the auditor's challenge. The others
+//! spend the reserves, and the one output pays their total to OP_RETURN.
+//! SIGHASH_ALL signatures on the reserves commit to the challenge, so the
+//! proof can't be replayed for another message, nor broadcast. The
+//! challenge input spends nothing and is left finalized with an empty
+//! script_sig.
+//!
+//! `build_proof` makes the unsigned proof over, say, `SpvWallet::utxos`,
+//! with the spent outputs for `Psbt::sign`; then `Psbt::finalize`. The
+//! verifier checks every reserve against a `UtxoStore` following the
+//! chain, so spent coins don't count, and checks the signatures as
+//! `Tx::verify_input` does: P2PKH, P2WPKH and taproot key path spends.
+
+use super::utxo::{OutPoint, UtxoStore};
+use crate::encoding::util::encode_hex;
+use crate::error::{Error, ErrorKind, Result};
+use crate::hash::hash256;
+use crate::params::Network;
+use crate::script::opcodes::OpCode;
+use crate::script::{Command, Script};
+use crate::tx::builder::Utxo;
+use crate::tx::fetcher::TxFetcher;
+use crate::tx::psbt::Psbt;
+use crate::tx::{LockTime, Tx, TxIn, TxOut};
+use std::collections::HashMap;
+
+/// The id of the transaction the challenge input spends, in display
+/// order: the hash256 of "Proof-of-Reserves: " and the message.
+pub fn challenge_txid(message: &str) -> [u8; 32] {
+    let mut txid = hash256(format!("Proof-of-Reserves: {}", message).as_bytes());
+    txid.reverse();
+    txid
+}
+
+/// The unsigned proof that `utxos` are held, for `message`.
+pub fn build_proof(message: &str, utxos: &[Utxo], network: Network) -> Result<Psbt> {
+    if utxos.is_empty() {
+        return Err(Error::new(ErrorKind::OutOfRange, "no coins to prove"));
+    }
+    let mut tx_ins = vec![TxIn::new(challenge_txid(message), 0)];
+    tx_ins.extend(utxos.iter().map(|utxo| TxIn::new(utxo.prev_tx, utxo.prev_index)));
+    let total = utxos.iter().map(|utxo| utxo.output.amount).sum();
+    let tx = Tx::new(2, tx_ins, vec![TxOut::new(total, vec![OpCode::Return.to_u8()])], LockTime::ZERO, network);
+    let mut psbt = Psbt::new(tx)?;
+    // Taproot signatures commit to every spent output, so the challenge
+    // gets an empty one
+    psbt.inputs[0].witness_utxo = Some(TxOut::new(0, vec![]));
+    psbt.inputs[0].final_script_sig = Some(vec![]);
+    for (input, utxo) in psbt.inputs[1..].iter_mut().zip(utxos) {
+        input.witness_utxo = Some(utxo.output.clone());
+    }
+    Ok(psbt)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidSignature, format!("bad proof of reserves: {}", msg))
+}
+
+// Whether the input's signature commits to every input and output, so to
+// the challenge: SIGHASH_ALL, or taproot's default
+fn signs_all(tx_in: &TxIn) -> bool {
+    let sig = match tx_in.witness.first() {
+        Some(sig) => sig.clone(),
+        None => match Script::from_bytes(&tx_in.script_sig).ok().and_then(|script| script.cmds.into_iter().next()) {
+            Some(Command::Data(sig)) => sig,
+            _ => return false,
+        },
+    };
+    (tx_in.witness.len() == 1 && sig.len() == 64) || sig.last() == Some(&1)
+}
+
+// Stands in for the transactions a proof spends: the coins at their
+// indices, and the challenge's empty output
+struct ProvenCoins {
+    challenge: [u8; 32],
+    coins: HashMap<OutPoint, TxOut>,
+}
+
+impl TxFetcher for ProvenCoins {
+    fn fetch(&self, txid: &[u8; 32], network: Network) -> Result<Tx> {
+        let tx_outs = if *txid == self.challenge {
+            vec![TxOut::new(0, vec![])]
+        } else {
+            let len = self.coins.keys().filter(|(id, _)| id == txid).map(|(_, index)| index + 1).max().unwrap_or(0);
+            (0..len).map(|index| self.coins.get(&(*txid, index)).cloned().unwrap_or_else(|| TxOut::new(0, vec![]))).collect()
+        };
+        Ok(Tx::new(1, vec![], tx_outs, LockTime::ZERO, network))
+    }
+}
+
+/// Checks a finalized proof for `message` against the unspent coins in
+/// `utxos`, returning the reserves it proves.
+pub fn verify_proof<U: UtxoStore>(proof: &Psbt, message: &str, utxos: &U) -> Result<u64> {
+    let tx = proof.extract()?;
+    let challenge = challenge_txid(message);
+    match tx.tx_ins.first() {
+        Some(tx_in) if tx_in.prev_tx == challenge && tx_in.prev_index == 0 => {}
+        _ => return Err(invalid("the first input is not the message's challenge".to_string())),
+    }
+    if tx.tx_ins.len() < 2 || tx.tx_outs.len() != 1 {
+        return Err(invalid(format!("{} inputs and {} outputs", tx.tx_ins.len(), tx.tx_outs.len())));
+    }
+
+    let mut coins = HashMap::new();
+    for tx_in in tx.tx_ins[1..].iter() {
+        let outpoint = (tx_in.prev_tx, tx_in.prev_index);
+        let coin = utxos.get(&outpoint).ok_or_else(|| invalid(format!("{}:{} is not unspent", encode_hex(&tx_in.prev_tx), tx_in.prev_index)))?;
+        if coins.insert(outpoint, coin.utxo.output).is_some() {
+            return Err(invalid(format!("{}:{} is spent twice", encode_hex(&tx_in.prev_tx), tx_in.prev_index)));
+        }
+    }
+    let total: u64 = coins.values().map(|output| output.amount).sum();
+    if tx.tx_outs[0].amount != total {
+        return Err(invalid(format!("the output pays {} of {}", tx.tx_outs[0].amount, total)));
+    }
+
+    let fetcher = ProvenCoins { challenge, coins };
+    for input_index in 1..tx.tx_ins.len() {
+        if !signs_all(&tx.tx_ins[input_index]) || !tx.verify_input(input_index, &fetcher)? {
+            return Err(invalid(format!("input {} is not signed for the challenge", input_index)));
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+use super::utxo::{BlockUndo, Coin, MemoryUtxoStore};
+#[cfg(test)]
+use crate::math::ecc::PrivateKey;
+#[cfg(test)]
+use crate::script::{p2pkh_script, p2tr_script, p2wpkh_script};
+#[cfg(test)]
+use crate::tx::SighashType;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn proof_of_reserves() {
+    let keys: Vec<PrivateKey> = (1..=3).map(|n| PrivateKey::new(&BigInt::from(n * 104_729)).unwrap()).collect();
+    let scripts = [
+        p2wpkh_script(&keys[0].public_key().hash160(true)).raw_serialize(),
+        p2pkh_script(&keys[1].public_key().hash160(true)).raw_serialize(),
+        p2tr_script(&keys[2].tap_tweak(None).unwrap().xonly_public_key().serialize()).raw_serialize(),
+    ];
+    let utxos: Vec<Utxo> = scripts.iter().enumerate().map(|(i, script)| Utxo::new([i as u8 + 1; 32], i as u32, TxOut::new(100_000 * (i as u64 + 1), script.clone()))).collect();
+    // The chain, where `unspent` are left
+    let store_of = |unspent: &[Utxo]| {
+        let mut store = MemoryUtxoStore::new();
+        let coins = unspent.iter().map(|utxo| Coin { utxo: utxo.clone(), height: 1, coinbase: false }).collect();
+        store.commit_connect(coins, BlockUndo { height: 1, block_hash: [1; 32], prev_tip: None, spent: vec![], created: vec![] }).unwrap();
+        store
+    };
+    let store = store_of(&utxos);
+
+    let message = "audit 2026-10-16";
+    let mut proof = build_proof(message, &utxos, Network::Regtest).unwrap();
+    assert_eq!(proof.unsigned_tx.tx_ins[0].prev_tx, challenge_txid(message));
+    for key in keys.iter() {
+        assert_eq!(proof.sign(key).unwrap(), 1);
+    }
+    proof.finalize().unwrap();
+    let proof = Psbt::from_base64(&proof.to_base64()).unwrap();
+    assert_eq!(verify_proof(&proof, message, &store).unwrap(), 600_000);
+    assert!(verify_proof(&proof, "another audit", &store).is_err());
+    let mut replayed = proof.clone();
+    replayed.unsigned_tx.tx_ins[0].prev_tx = challenge_txid("another audit");
+    assert!(verify_proof(&replayed, "another audit", &store).is_err());
+
+    // Only the coins still unspent count
+    assert!(verify_proof(&proof, message, &store_of(&utxos[..2])).is_err());
+
+    // Claiming more than is there, or signing only some of it, fails
+    let mut inflated = proof.clone();
+    inflated.unsigned_tx.tx_outs[0].amount += 1;
+    assert!(verify_proof(&inflated, message, &store).is_err());
+    let mut proof = build_proof(message, &utxos[..1], Network::Regtest).unwrap();
+    proof.inputs[1].sighash_type = Some(SighashType::AllAnyoneCanPay.to_u32());
+    proof.sign(&keys[0]).unwrap();
+    proof.finalize().unwrap();
+    assert!(verify_proof(&proof, message, &store).is_err());
+    assert!(build_proof(message, &[], Network::Regtest).is_err());
+}
@@ -0,0 +1,802 @@
+//! An SPV wallet: watch scripts, scan the chain from the wallet's birth
+//! height and keep the outputs paying them (chapter 12's goal).
+//!
+//! Headers come from a `HeaderSync`, and every scanned block must be the
+//! stored header at the next height. Blocks arrive either as merkleblocks
+//! a bloom filter matched, whose proofs are checked against their header,
+//! or as full blocks a BIP158 filter matched, whose merkle roots are
+//! checked. Transactions seen only in the mempool count as unconfirmed.
+//!
+//! An unconfirmed transaction stays pending until a block holds it. One
+//! spending the same coins, relayed or mined, replaces it: the replaced
+//! transaction and those spending its outputs drop out of the history, and
+//! the outputs they spent are unspent again. `balance` takes a
+//! `TrustPolicy` saying whether unconfirmed payments their senders could
+//! still replace count.
+//!
+//! When a reorganisation disconnects scanned blocks, their transactions are
+//! pending again, so others spending the same coins can replace them, and
+//! scanning restarts at the fork.
+//!
+//! A `Birthday`, a height or the time the keys were made, says where
+//! scanning starts, so the blocks and filters before it are skipped.
+//!
+//! `save` writes the watched scripts, the scan position, the outputs, the
+//! transaction heights, the pending and mined transactions and what
+//! replaced others to a text file; the headers are the `HeaderStore`'s to
+//! keep.
+
+use crate::address::Address;
+use crate::block::filter::BlockFilter;
+use crate::block::{Block, MerkleBlock};
+use crate::bloom::{BloomFilter, BLOOM_UPDATE_ALL};
+use crate::encoding::util::{decode_hex, encode_hex};
+use crate::error::{Error, ErrorKind, Result};
+use crate::network::sync::{ChainEvent, HeaderStore, HeaderSync};
+use crate::network::SimpleNode;
+use crate::params::Network;
+use crate::script::{Command, Script};
+use crate::tx::builder::Utxo;
+use crate::tx::{Tx, TxOut};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+/// Version of the files `SpvWallet::save` writes. `Wallet` files share the
+/// numbering: version 1 has the lines below but pending, mined and replaced
+/// transactions, 2 adds the `Wallet` lines, 3 the pending and replaced
+/// transaction lines and 4 the mined ones.
+pub const FILE_VERSION: u32 = 4;
+
+pub(super) fn invalid_line(line: &str) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("bad wallet entry {:?}", line))
+}
+
+fn height_field(height: Option<u32>) -> String {
+    height.map_or_else(|| "-".to_string(), |height| height.to_string())
+}
+
+pub(super) fn parse_number<T: std::str::FromStr>(field: &str, line: &str) -> Result<T> {
+    field.parse().map_err(|_| invalid_line(line))
+}
+
+fn parse_height(field: &str, line: &str) -> Result<Option<u32>> {
+    if field == "-" {
+        return Ok(None);
+    }
+    parse_number(field, line).map(Some)
+}
+
+fn parse_txid(field: &str, line: &str) -> Result<[u8; 32]> {
+    decode_hex(field).ok().and_then(|bytes| bytes.try_into().ok()).ok_or_else(|| invalid_line(line))
+}
+
+/// Splits the "wallet N" line off a wallet file, returning N and the rest.
+pub(super) fn split_version(text: &str) -> Option<(u32, &str)> {
+    let (first, rest) = text.split_once('\n').unwrap_or((text, ""));
+    let version = first.strip_prefix("wallet ")?.parse().ok()?;
+    Some((version, rest))
+}
+
+/// Reads the "wallet N" line of a wallet file, returning N and the rest.
+pub(super) fn read_wallet_file(path: &Path) -> Result<(u32, String)> {
+    let text = fs::read_to_string(path)?;
+    let (version, rest) = split_version(&text).ok_or_else(|| Error::new(ErrorKind::InvalidEncoding, format!("{} is not a wallet file", path.display())))?;
+    Ok((version, rest.to_string()))
+}
+
+pub(super) fn unsupported_version(path: &Path, version: u32) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("{} is a version {} wallet file, which this wallet can't read", path.display(), version))
+}
+
+/// How far behind the time it was mined a block's timestamp can be, as
+/// Bitcoin Core allows for when rescanning from a key's birth time.
+pub const TIMESTAMP_WINDOW: u32 = 2 * 60 * 60;
+
+/// When a wallet's keys were made: nothing before can pay them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Birthday {
+    Height(u32),
+    /// Unix time
+    Time(u32),
+}
+
+impl Birthday {
+    /// The first block to scan: for a time, the first stored header
+    /// within `TIMESTAMP_WINDOW` of it, or the block after the tip if the
+    /// headers don't reach it yet.
+    pub fn height<S: HeaderStore>(self, sync: &HeaderSync<S>) -> u32 {
+        let time = match self {
+            Birthday::Height(height) => return height,
+            Birthday::Time(time) => time.saturating_sub(TIMESTAMP_WINDOW),
+        };
+        let ((start, _), (tip, _)) = (sync.store.start(), sync.store.tip());
+        (start..=tip).find(|&height| sync.store.get(height).is_some_and(|header| header.timestamp >= time)).unwrap_or(tip + 1)
+    }
+}
+
+/// A watched output and the height of its block, `None` while it's only
+/// in the mempool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletOutput {
+    pub utxo: Utxo,
+    pub height: Option<u32>,
+}
+
+/// Which unconfirmed payments `SpvWallet::balance` counts. Change from the
+/// wallet's own transactions always counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustPolicy {
+    /// Every unconfirmed payment
+    TrustAll,
+    /// Not payments signalling BIP125, which the sender can replace with
+    /// one paying someone else
+    ExcludeRbf,
+}
+
+/// Unspent value in satoshis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balance {
+    /// In blocks
+    pub confirmed: u64,
+    /// Only in the mempool
+    pub unconfirmed: u64,
+}
+
+pub struct SpvWallet<S: HeaderStore> {
+    pub sync: HeaderSync<S>,
+    scripts: HashSet<Vec<u8>>,
+    birth_height: u32,
+    /// The next block to scan
+    next_height: u32,
+    outputs: HashMap<([u8; 32], u32), WalletOutput>,
+    /// Heights of the transactions that paid or spent watched outputs
+    txs: HashMap<[u8; 32], Option<u32>>,
+    /// Those relayed but not mined yet. Outputs they spend stay in
+    /// `outputs` until they are, in case they get replaced
+    pending: HashMap<[u8; 32], Tx>,
+    /// Those mined, for a reorganisation to make pending again
+    mined: HashMap<[u8; 32], Tx>,
+    /// Replaced transactions, with what replaced them
+    replaced: HashMap<[u8; 32], [u8; 32]>,
+}
+
+impl<S: HeaderStore> SpvWallet<S> {
+    /// A wallet with no history before `birth_height`, scanning the
+    /// headers `sync` holds. The wallet records `sync`'s chain events.
+    pub fn new(mut sync: HeaderSync<S>, birth_height: u32) -> SpvWallet<S> {
+        sync.record_events(true);
+        SpvWallet {
+            sync,
+            scripts: HashSet::new(),
+            birth_height,
+            next_height: birth_height,
+            outputs: HashMap::new(),
+            txs: HashMap::new(),
+            pending: HashMap::new(),
+            mined: HashMap::new(),
+            replaced: HashMap::new(),
+        }
+    }
+
+    pub fn birth_height(&self) -> u32 {
+        self.birth_height
+    }
+
+    /// Starts scanning at `height` for scripts about to be watched. A
+    /// wallet watching nothing yet is born there, skipping the blocks
+    /// before; otherwise the blocks from `height` on are scanned again.
+    pub fn set_birthday(&mut self, height: u32) {
+        if self.scripts.is_empty() {
+            self.birth_height = height;
+            self.next_height = height;
+        } else {
+            self.birth_height = self.birth_height.min(height);
+            self.next_height = self.next_height.min(height);
+        }
+    }
+
+    /// Height of the last block scanned, if any.
+    pub fn scanned_height(&self) -> Option<u32> {
+        self.next_height.checked_sub(1).filter(|height| *height >= self.birth_height)
+    }
+
+    /// Watches outputs paying `script_pubkey`, returning whether it's new.
+    /// Blocks already scanned aren't scanned again for it.
+    pub fn watch_script(&mut self, script_pubkey: Vec<u8>) -> bool {
+        self.scripts.insert(script_pubkey)
+    }
+
+    pub fn watch_address(&mut self, address: &Address) -> bool {
+        self.watch_script(address.script_pubkey().raw_serialize())
+    }
+
+    pub fn is_watched(&self, script_pubkey: &[u8]) -> bool {
+        self.scripts.contains(script_pubkey)
+    }
+
+    /// A bloom filter matching the watched scripts' data pushes and the
+    /// unspent outputs, so spends of them match too.
+    pub fn bloom_filter(&self, fp_rate: f64, tweak: u32) -> Result<BloomFilter> {
+        let mut items = vec![];
+        for script in self.scripts.iter() {
+            for cmd in Script::from_bytes(script)?.cmds {
+                if let Command::Data(data) = cmd {
+                    items.push(data);
+                }
+            }
+        }
+        for (txid, index) in self.outputs.keys() {
+            let mut outpoint = txid.to_vec();
+            outpoint.reverse();
+            outpoint.extend_from_slice(&index.to_le_bytes());
+            items.push(outpoint);
+        }
+        let mut filter = BloomFilter::with_false_positive_rate(items.len().max(1), fp_rate, tweak)?;
+        for item in items.iter() {
+            filter.add(item);
+        }
+        Ok(filter)
+    }
+
+    /// Records what `tx` spends from and pays to the wallet, at `height`
+    /// or in the mempool, replacing pending transactions spending the same
+    /// coins. Returns whether it touched the wallet. A transaction seen
+    /// before only gets its height updated, and one replaced is ignored
+    /// unless mined.
+    pub fn apply_tx(&mut self, tx: &Tx, height: Option<u32>) -> bool {
+        let txid = tx.hash();
+        if let Some(known) = self.txs.get_mut(&txid) {
+            if height.is_some() {
+                *known = height;
+                for output in self.outputs.values_mut().filter(|output| output.utxo.prev_tx == txid) {
+                    output.height = height;
+                }
+                self.pending.remove(&txid);
+                for tx_in in tx.tx_ins.iter() {
+                    self.outputs.remove(&(tx_in.prev_tx, tx_in.prev_index));
+                }
+                self.mined.insert(txid, tx.clone());
+            }
+            return true;
+        }
+        if self.replaced.contains_key(&txid) {
+            if height.is_none() {
+                return false;
+            }
+            // Mined after all, so whatever replaced it is replaced in turn
+            self.replaced.remove(&txid);
+        }
+        let mut conflicts: Vec<[u8; 32]> = tx.tx_ins.iter().filter_map(|tx_in| self.spender(&(tx_in.prev_tx, tx_in.prev_index))).collect();
+        conflicts.dedup();
+        let replacing = !conflicts.is_empty();
+        for conflict in conflicts {
+            self.replace(conflict, txid);
+        }
+        // Replacing a wallet transaction doesn't make it one
+        let spends = tx.tx_ins.iter().any(|tx_in| self.outputs.contains_key(&(tx_in.prev_tx, tx_in.prev_index)));
+        let pays = tx.tx_outs.iter().any(|tx_out| self.scripts.contains(&tx_out.script_pubkey));
+        if !spends && !pays {
+            return replacing;
+        }
+        if height.is_some() {
+            for tx_in in tx.tx_ins.iter() {
+                self.outputs.remove(&(tx_in.prev_tx, tx_in.prev_index));
+            }
+            self.mined.insert(txid, tx.clone());
+        } else {
+            self.pending.insert(txid, tx.clone());
+        }
+        for (index, tx_out) in tx.tx_outs.iter().enumerate() {
+            if self.scripts.contains(&tx_out.script_pubkey) {
+                let utxo = Utxo::new(txid, index as u32, tx_out.clone());
+                self.outputs.insert((txid, index as u32), WalletOutput { utxo, height });
+            }
+        }
+        self.txs.insert(txid, height);
+        true
+    }
+
+    // The pending transaction spending `outpoint`, if any
+    fn spender(&self, outpoint: &([u8; 32], u32)) -> Option<[u8; 32]> {
+        self.pending.iter().find(|(_, tx)| tx.tx_ins.iter().any(|tx_in| (tx_in.prev_tx, tx_in.prev_index) == *outpoint)).map(|(txid, _)| *txid)
+    }
+
+    // Drops pending `txid` and its pending descendants for `by`
+    fn replace(&mut self, txid: [u8; 32], by: [u8; 32]) {
+        if self.pending.remove(&txid).is_none() {
+            return;
+        }
+        self.txs.remove(&txid);
+        self.replaced.insert(txid, by);
+        self.outputs.retain(|(prev_tx, _), _| *prev_tx != txid);
+        let children: Vec<[u8; 32]> = self.pending.iter().filter(|(_, tx)| tx.tx_ins.iter().any(|tx_in| tx_in.prev_tx == txid)).map(|(child, _)| *child).collect();
+        for child in children {
+            self.replace(child, by);
+        }
+    }
+
+    /// What replaced `txid`, if a transaction spending the same coins did.
+    pub fn replaced_by(&self, txid: &[u8; 32]) -> Option<[u8; 32]> {
+        self.replaced.get(txid).copied()
+    }
+
+    /// Records a transaction the mempool relayed.
+    pub fn add_unconfirmed(&mut self, tx: &Tx) -> bool {
+        self.apply_tx(tx, None)
+    }
+
+    /// Rolls back the blocks `sync` disconnected since the last call,
+    /// returning how many there were. Their transactions are pending
+    /// again: outputs they spent stay spent, as the transactions are still
+    /// ours to rebroadcast, unless one spending the same coins replaces
+    /// them.
+    pub fn handle_chain_events(&mut self) -> usize {
+        let mut disconnected = 0;
+        for event in self.sync.take_events() {
+            if let ChainEvent::Disconnected(height, _) = event {
+                let mut unconfirmed = vec![];
+                for (txid, known) in self.txs.iter_mut().filter(|(_, known)| known.is_some_and(|at| at >= height)) {
+                    *known = None;
+                    unconfirmed.push(*txid);
+                }
+                for output in self.outputs.values_mut().filter(|output| output.height.is_some_and(|at| at >= height)) {
+                    output.height = None;
+                }
+                for txid in unconfirmed {
+                    self.unconfirm(txid);
+                }
+                self.next_height = self.next_height.min(height.max(self.birth_height));
+                disconnected += 1;
+            }
+        }
+        disconnected
+    }
+
+    // Makes mined `txid` pending, putting back the watched outputs it
+    // spent. Files before version 4 don't keep mined transactions, so
+    // theirs only lose their heights
+    fn unconfirm(&mut self, txid: [u8; 32]) {
+        let tx = match self.mined.remove(&txid) {
+            Some(tx) => tx,
+            None => return,
+        };
+        for tx_in in tx.tx_ins.iter() {
+            let outpoint = (tx_in.prev_tx, tx_in.prev_index);
+            let prev = self.mined.get(&tx_in.prev_tx).or_else(|| self.pending.get(&tx_in.prev_tx));
+            let output = match prev.and_then(|prev| prev.tx_outs.get(tx_in.prev_index as usize)) {
+                Some(output) if self.scripts.contains(&output.script_pubkey) => output.clone(),
+                _ => continue,
+            };
+            let height = self.txs.get(&tx_in.prev_tx).copied().flatten();
+            self.outputs.entry(outpoint).or_insert_with(|| WalletOutput { utxo: Utxo::new(outpoint.0, outpoint.1, output), height });
+        }
+        self.pending.insert(txid, tx);
+    }
+
+    /// Hash of the next block to scan, once its header is stored.
+    pub fn next_block(&self) -> Option<[u8; 32]> {
+        self.sync.store.get(self.next_height).map(|header| header.hash())
+    }
+
+    // The next height, whose stored header must hash to `hash`
+    fn check_next(&self, hash: &[u8; 32]) -> Result<u32> {
+        let height = self.next_height;
+        let expected = self.sync.store.get(height).ok_or_else(|| Error::new(ErrorKind::Fetch, format!("no header at height {} yet", height)))?;
+        if expected.hash() != *hash {
+            return Err(Error::new(ErrorKind::Fetch, format!("block {} is not the one at height {}", encode_hex(hash), height)));
+        }
+        Ok(height)
+    }
+
+    // The height of `merkle_block`, if it's the next block and proves
+    // every one of `txs`
+    pub(super) fn check_merkle_block(&self, merkle_block: &MerkleBlock, txs: &[Tx]) -> Result<u32> {
+        let height = self.check_next(&merkle_block.header.hash())?;
+        let proven = merkle_block.matched_txids()?;
+        if let Some(tx) = txs.iter().find(|tx| !proven.contains(&tx.hash())) {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} is not in merkleblock {}", tx.id(), merkle_block.header.id())));
+        }
+        Ok(height)
+    }
+
+    /// Scans the next block from its merkleblock and the transactions sent
+    /// with it, each of which it must prove. Returns how many touched the
+    /// wallet.
+    pub fn scan_merkle_block(&mut self, merkle_block: &MerkleBlock, txs: &[Tx]) -> Result<usize> {
+        let height = self.check_merkle_block(merkle_block, txs)?;
+        let found = txs.iter().filter(|tx| self.apply_tx(tx, Some(height))).count();
+        self.next_height += 1;
+        Ok(found)
+    }
+
+    /// Checks the next block's BIP158 filter against the watched scripts.
+    /// Returns whether the block must be downloaded and given to
+    /// `scan_block`; if not, the block counts as scanned.
+    pub fn scan_block_filter(&mut self, filter: &BlockFilter) -> Result<bool> {
+        self.check_next(&filter.block_hash)?;
+        let scripts: Vec<Vec<u8>> = self.scripts.iter().cloned().collect();
+        if filter.match_any(&scripts)? {
+            return Ok(true);
+        }
+        self.next_height += 1;
+        Ok(false)
+    }
+
+    // The height of `block`, if it's the next block and its merkle root
+    // holds
+    pub(super) fn check_block(&self, block: &Block) -> Result<u32> {
+        let height = self.check_next(&block.header.hash())?;
+        if !block.validate_merkle_root() {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("block {} has a bad merkle root", block.header.id())));
+        }
+        Ok(height)
+    }
+
+    /// Scans the next block in full. Returns how many transactions touched
+    /// the wallet.
+    pub fn scan_block(&mut self, block: &Block) -> Result<usize> {
+        let height = self.check_block(block)?;
+        let found = block.txs.iter().filter(|tx| self.apply_tx(tx, Some(height))).count();
+        self.next_height += 1;
+        Ok(found)
+    }
+
+    /// Syncs headers from `node`, then scans every block up to the tip as
+    /// a filtered block. Returns how many transactions touched the wallet.
+    pub fn scan(&mut self, node: &mut SimpleNode) -> Result<usize> {
+        self.sync.sync(node)?;
+        self.handle_chain_events();
+        node.load_filter(&self.bloom_filter(0.0001, 0)?, BLOOM_UPDATE_ALL)?;
+        let mut found = 0;
+        while let Some(hash) = self.next_block() {
+            let (merkle_block, txs) = node.get_filtered_block(&hash)?;
+            found += self.scan_merkle_block(&merkle_block, &txs)?;
+        }
+        Ok(found)
+    }
+
+    // Lines for the scan position, the watched scripts, the outputs and
+    // the transactions, sorted so a file only changes where the wallet did
+    pub(super) fn write_state(&self, text: &mut String) {
+        text.push_str(&format!("birth {}\nnext {}\n", self.birth_height, self.next_height));
+        let mut scripts: Vec<String> = self.scripts.iter().map(|script| format!("watch {}\n", encode_hex(script))).collect();
+        scripts.sort();
+        text.extend(scripts);
+        let mut outputs: Vec<&WalletOutput> = self.outputs.values().collect();
+        outputs.sort_by_key(|output| (output.utxo.prev_tx, output.utxo.prev_index));
+        for WalletOutput { utxo, height } in outputs {
+            let output = &utxo.output;
+            text.push_str(&format!("output {} {} {} {} {}\n", encode_hex(&utxo.prev_tx), utxo.prev_index, output.amount, encode_hex(&output.script_pubkey), height_field(*height)));
+        }
+        let mut txs: Vec<(&[u8; 32], &Option<u32>)> = self.txs.iter().collect();
+        txs.sort();
+        text.extend(txs.into_iter().map(|(txid, height)| format!("tx {} {}\n", encode_hex(txid), height_field(*height))));
+        for (kind, txs) in [("pending", &self.pending), ("mined", &self.mined)] {
+            let mut txs: Vec<(&[u8; 32], &Tx)> = txs.iter().collect();
+            txs.sort_by_key(|(txid, _)| **txid);
+            text.extend(txs.into_iter().map(|(_, tx)| format!("{} {}\n", kind, encode_hex(&tx.serialize()))));
+        }
+        let mut replaced: Vec<(&[u8; 32], &[u8; 32])> = self.replaced.iter().collect();
+        replaced.sort();
+        text.extend(replaced.into_iter().map(|(txid, by)| format!("replaced {} {}\n", encode_hex(txid), encode_hex(by))));
+    }
+
+    // Reads a line `write_state` wrote to a `version` file, returning
+    // false for any other
+    pub(super) fn read_state(&mut self, version: u32, fields: &[&str], line: &str) -> Result<bool> {
+        match (fields.first(), fields.len()) {
+            (Some(&"birth"), 2) => self.birth_height = parse_number(fields[1], line)?,
+            (Some(&"next"), 2) => self.next_height = parse_number(fields[1], line)?,
+            (Some(&"watch"), 2) => {
+                self.scripts.insert(decode_hex(fields[1]).map_err(|_| invalid_line(line))?);
+            }
+            (Some(&"output"), 6) => {
+                let txid = parse_txid(fields[1], line)?;
+                let index = parse_number(fields[2], line)?;
+                let script_pubkey = decode_hex(fields[4]).map_err(|_| invalid_line(line))?;
+                let utxo = Utxo::new(txid, index, TxOut::new(parse_number(fields[3], line)?, script_pubkey));
+                self.outputs.insert((txid, index), WalletOutput { utxo, height: parse_height(fields[5], line)? });
+            }
+            (Some(&"tx"), 3) => {
+                self.txs.insert(parse_txid(fields[1], line)?, parse_height(fields[2], line)?);
+            }
+            (Some(&"pending"), 2) if version >= 3 => {
+                let tx = self.parse_tx(fields[1], line)?;
+                self.pending.insert(tx.hash(), tx);
+            }
+            (Some(&"mined"), 2) if version >= 4 => {
+                let tx = self.parse_tx(fields[1], line)?;
+                self.mined.insert(tx.hash(), tx);
+            }
+            (Some(&"replaced"), 3) if version >= 3 => {
+                self.replaced.insert(parse_txid(fields[1], line)?, parse_txid(fields[2], line)?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn parse_tx(&self, field: &str, line: &str) -> Result<Tx> {
+        let raw = decode_hex(field).map_err(|_| invalid_line(line))?;
+        let network = Network::from_params(self.sync.params()).unwrap_or(Network::Mainnet);
+        Tx::parse(&mut &raw[..], network)
+    }
+
+    /// Saves the wallet to `path`, replacing any old file atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut text = format!("wallet {}\n", FILE_VERSION);
+        self.write_state(&mut text);
+        super::write_atomic(path.as_ref(), &text)
+    }
+
+    /// Loads a wallet `save` wrote, scanning the headers `sync` holds.
+    pub fn load<P: AsRef<Path>>(path: P, sync: HeaderSync<S>) -> Result<SpvWallet<S>> {
+        let (version, text) = read_wallet_file(path.as_ref())?;
+        // Version 2 is only ever a `Wallet` file
+        if version == 0 || version == 2 || version > FILE_VERSION {
+            return Err(unsupported_version(path.as_ref(), version));
+        }
+        let mut wallet = SpvWallet::new(sync, 0);
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if !wallet.read_state(version, &fields, line)? {
+                return Err(invalid_line(line));
+            }
+        }
+        Ok(wallet)
+    }
+
+    fn tip_height(&self) -> u32 {
+        self.sync.store.tip().0
+    }
+
+    /// Blocks including and after the one with `txid`, 0 while it's in the
+    /// mempool, `None` for transactions that didn't touch the wallet.
+    pub fn confirmations(&self, txid: &[u8; 32]) -> Option<u32> {
+        let height = *self.txs.get(txid)?;
+        Some(height.map_or(0, |height| self.tip_height().saturating_sub(height) + 1))
+    }
+
+    // Outputs pending transactions spend
+    fn pending_spends(&self) -> HashSet<([u8; 32], u32)> {
+        self.pending.values().flat_map(|tx| tx.tx_ins.iter().map(|tx_in| (tx_in.prev_tx, tx_in.prev_index))).collect()
+    }
+
+    /// The unspent watched outputs, leaving out those pending transactions
+    /// spend.
+    pub fn outputs(&self) -> impl Iterator<Item = &WalletOutput> {
+        let spent = self.pending_spends();
+        self.outputs.iter().filter(move |(outpoint, _)| !spent.contains(outpoint)).map(|(_, output)| output)
+    }
+
+    /// Unspent outputs with at least `min_confirmations`, for a `TxBuilder`.
+    pub fn utxos(&self, min_confirmations: u32) -> Vec<Utxo> {
+        let mut utxos: Vec<Utxo> = self
+            .outputs()
+            .filter(|output| self.confirmations(&output.utxo.prev_tx).unwrap_or(0) >= min_confirmations)
+            .map(|output| output.utxo.clone())
+            .collect();
+        utxos.sort_by_key(|utxo| (utxo.prev_tx, utxo.prev_index));
+        utxos
+    }
+
+    // Whether `trust` leaves out the unconfirmed outputs of `txid`: a
+    // pending payment signalling BIP125 that spends nothing of the wallet's
+    fn untrusted(&self, txid: &[u8; 32], trust: TrustPolicy) -> bool {
+        match (trust, self.pending.get(txid)) {
+            (TrustPolicy::ExcludeRbf, Some(tx)) => {
+                tx.signals_rbf() && !tx.tx_ins.iter().any(|tx_in| self.outputs.contains_key(&(tx_in.prev_tx, tx_in.prev_index)))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn balance(&self, trust: TrustPolicy) -> Balance {
+        let mut balance = Balance::default();
+        for output in self.outputs() {
+            match output.height {
+                Some(_) => balance.confirmed += output.utxo.output.amount,
+                None if self.untrusted(&output.utxo.prev_tx, trust) => {}
+                None => balance.unconfirmed += output.utxo.output.amount,
+            }
+        }
+        balance
+    }
+}
+
+#[cfg(test)]
+use crate::block::BlockHeader;
+#[cfg(test)]
+use crate::network::sync::MemoryHeaderStore;
+#[cfg(test)]
+use crate::params::NetworkParams;
+#[cfg(test)]
+use crate::script::p2wpkh_script;
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn};
+
+// A regtest block on `prev` holding `txs`
+#[cfg(test)]
+pub(super) fn mine_block(prev: &BlockHeader, txs: Vec<Tx>) -> Block {
+    let header = BlockHeader { prev_block: prev.hash(), timestamp: prev.timestamp + 600, ..prev.clone() };
+    let mut block = Block { header, txs };
+    block.header.merkle_root = block.merkle_root();
+    while !block.header.check_pow() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+#[test]
+fn spv_wallet() {
+    let regtest = NetworkParams::regtest();
+    let genesis = BlockHeader::parse(&mut &crate::encoding::util::decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let ours = p2wpkh_script(&[1; 20]).raw_serialize();
+    let theirs = p2wpkh_script(&[2; 20]).raw_serialize();
+    let tx = |prev: [u8; 32], outs: Vec<TxOut>| Tx::new(2, vec![TxIn::new(prev, 0)], outs, LockTime::ZERO, Network::Regtest);
+
+    let pay = tx([9; 32], vec![TxOut::new(50_000, ours.clone()), TxOut::new(10_000, theirs.clone())]);
+    let other = tx([8; 32], vec![TxOut::new(7_000, theirs.clone())]);
+    let block1 = mine_block(&genesis, vec![pay.clone(), other.clone()]);
+    let spend = tx(pay.hash(), vec![TxOut::new(20_000, theirs.clone()), TxOut::new(29_000, ours.clone())]);
+    let block2 = mine_block(&block1.header, vec![tx([5; 32], vec![TxOut::new(3_000, theirs.clone())]), spend.clone()]);
+    let block3 = mine_block(&block2.header, vec![tx([7; 32], vec![TxOut::new(1_000, theirs.clone())])]);
+    let single = tx([6; 32], vec![TxOut::new(5_000, ours.clone())]);
+    let block4 = mine_block(&block3.header, vec![single.clone()]);
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), regtest.clone()).unwrap();
+    sync.add_headers(&[block1.header.clone(), block2.header.clone(), block3.header.clone(), block4.header.clone()]).unwrap();
+
+    let mut wallet = SpvWallet::new(sync, 1);
+    assert!(wallet.watch_script(ours.clone()));
+    assert!(wallet.bloom_filter(0.0001, 0).unwrap().contains(&[1; 20]));
+    assert_eq!(wallet.scan_block(&block2).unwrap_err().kind(), ErrorKind::Fetch);
+    assert_eq!(wallet.scan_block(&block1).unwrap(), 1);
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 50_000, unconfirmed: 0 });
+
+    // The spend arrives through the mempool first, then in block 2, which
+    // its BIP158 filter flags
+    assert!(wallet.add_unconfirmed(&spend));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 0, unconfirmed: 29_000 });
+    assert_eq!(wallet.confirmations(&spend.hash()), Some(0));
+    let spent_scripts = vec![ours.clone(), theirs.clone()];
+    assert!(wallet.scan_block_filter(&BlockFilter::new_basic(&block2, &spent_scripts)).unwrap());
+    assert_eq!(wallet.scan_block(&block2).unwrap(), 1);
+    assert!(!wallet.scan_block_filter(&BlockFilter::new_basic(&block3, &[vec![0x51]])).unwrap());
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 29_000, unconfirmed: 0 });
+    assert!(wallet.bloom_filter(0.0001, 0).unwrap().contains(&[&spend.hash().iter().rev().copied().collect::<Vec<u8>>()[..], &1u32.to_le_bytes()].concat()));
+
+    // A one transaction block's merkleblock just holds the txid
+    let mut txid = single.hash();
+    txid.reverse();
+    let merkle_block = MerkleBlock { header: block4.header.clone(), total: 1, hashes: vec![txid], flags: vec![1] };
+    assert_eq!(wallet.scan_merkle_block(&merkle_block, &[other]).unwrap_err().kind(), ErrorKind::InvalidEncoding);
+    assert_eq!(wallet.scan_merkle_block(&merkle_block, std::slice::from_ref(&single)).unwrap(), 1);
+    assert_eq!(wallet.scanned_height(), Some(4));
+    assert_eq!((wallet.confirmations(&pay.hash()), wallet.confirmations(&spend.hash()), wallet.confirmations(&single.hash())), (Some(4), Some(3), Some(1)));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 34_000, unconfirmed: 0 });
+    assert_eq!(wallet.utxos(2).len(), 1);
+
+    // A longer branch off block 3 drops block 4
+    let fork4 = mine_block(&block3.header, vec![tx([4; 32], vec![TxOut::new(2_000, theirs.clone())])]);
+    let fork5 = mine_block(&fork4.header, vec![tx([3; 32], vec![TxOut::new(2_000, theirs)])]);
+    wallet.sync.add_headers(&[fork4.header.clone(), fork5.header]).unwrap();
+    assert_eq!(wallet.handle_chain_events(), 1);
+    assert_eq!((wallet.scanned_height(), wallet.confirmations(&single.hash())), (Some(3), Some(0)));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 29_000, unconfirmed: 5_000 });
+    assert_eq!(wallet.scan_block(&fork4).unwrap(), 0);
+
+    let path = std::env::temp_dir().join(format!("prog_btc_book_spv_wallet_{}", std::process::id()));
+    wallet.save(&path).unwrap();
+    let confirmations = wallet.confirmations(&spend.hash());
+    // The loaded wallet takes over the headers
+    let sync = std::mem::replace(&mut wallet.sync, HeaderSync::new(MemoryHeaderStore::new(0, genesis), regtest).unwrap());
+    let loaded = SpvWallet::load(&path, sync).unwrap();
+    assert_eq!((loaded.scanned_height(), loaded.balance(TrustPolicy::TrustAll), loaded.utxos(0)), (Some(4), wallet.balance(TrustPolicy::TrustAll), wallet.utxos(0)));
+    assert_eq!(loaded.confirmations(&spend.hash()), confirmations);
+    assert!(loaded.is_watched(&ours));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn spv_wallet_replacements() {
+    let genesis = BlockHeader::parse(&mut &crate::encoding::util::decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let sync = || HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), NetworkParams::regtest()).unwrap();
+    let ours = p2wpkh_script(&[1; 20]).raw_serialize();
+    let theirs = p2wpkh_script(&[2; 20]).raw_serialize();
+    let rbf = |prev: [u8; 32], index: u32, outs: Vec<TxOut>| {
+        let mut tx = Tx::new(2, vec![TxIn::new(prev, index)], outs, LockTime::ZERO, Network::Regtest);
+        tx.signal_rbf();
+        tx
+    };
+    let mut wallet = SpvWallet::new(sync(), 0);
+    wallet.watch_script(ours.clone());
+
+    // A payment the sender bumps counts once, and not at all if
+    // replaceable payments aren't trusted
+    let receipt = rbf([9; 32], 0, vec![TxOut::new(10_000, ours.clone())]);
+    let bumped = rbf([9; 32], 0, vec![TxOut::new(9_000, ours.clone())]);
+    assert!(wallet.add_unconfirmed(&receipt));
+    assert_eq!(wallet.balance(TrustPolicy::ExcludeRbf), Balance { confirmed: 0, unconfirmed: 0 });
+    assert!(wallet.add_unconfirmed(&bumped));
+    assert!(!wallet.add_unconfirmed(&receipt));
+    assert_eq!((wallet.replaced_by(&receipt.hash()), wallet.confirmations(&receipt.hash())), (Some(bumped.hash()), None));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 0, unconfirmed: 9_000 });
+
+    // Change from our own replaceable spend is trusted. Bumping the spend
+    // replaces the child spending its change too
+    let pay = Tx::new(2, vec![TxIn::new([8; 32], 0)], vec![TxOut::new(50_000, ours.clone())], LockTime::ZERO, Network::Regtest);
+    assert!(wallet.apply_tx(&pay, Some(0)));
+    let spend = rbf(pay.hash(), 0, vec![TxOut::new(20_000, theirs.clone()), TxOut::new(29_000, ours.clone())]);
+    let child = rbf(spend.hash(), 1, vec![TxOut::new(28_000, ours.clone())]);
+    assert!(wallet.add_unconfirmed(&spend) && wallet.add_unconfirmed(&child));
+    assert_eq!(wallet.balance(TrustPolicy::ExcludeRbf), Balance { confirmed: 0, unconfirmed: 28_000 });
+    let spend2 = rbf(pay.hash(), 0, vec![TxOut::new(20_000, theirs.clone()), TxOut::new(28_500, ours.clone())]);
+    assert!(wallet.add_unconfirmed(&spend2));
+    assert_eq!((wallet.replaced_by(&spend.hash()), wallet.replaced_by(&child.hash())), (Some(spend2.hash()), Some(spend2.hash())));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 0, unconfirmed: 37_500 });
+    assert_eq!(wallet.utxos(0).len(), 2);
+
+    // The sender mines a double spend of the payment instead
+    let theft = Tx::new(2, vec![TxIn::new([9; 32], 0)], vec![TxOut::new(9_500, theirs)], LockTime::ZERO, Network::Regtest);
+    assert!(wallet.apply_tx(&theft, Some(0)));
+    assert_eq!((wallet.replaced_by(&bumped.hash()), wallet.confirmations(&theft.hash())), (Some(theft.hash()), None));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 0, unconfirmed: 28_500 });
+    // and our spend confirms
+    assert!(wallet.apply_tx(&spend2, Some(0)));
+    assert_eq!(wallet.balance(TrustPolicy::ExcludeRbf), Balance { confirmed: 28_500, unconfirmed: 0 });
+    assert!(wallet.add_unconfirmed(&rbf([7; 32], 0, vec![TxOut::new(1_000, ours)])));
+
+    let path = std::env::temp_dir().join(format!("prog_btc_book_spv_replacements_{}", std::process::id()));
+    wallet.save(&path).unwrap();
+    let loaded = SpvWallet::load(&path, sync()).unwrap();
+    assert_eq!(loaded.replaced_by(&child.hash()), Some(spend2.hash()));
+    assert_eq!((loaded.balance(TrustPolicy::TrustAll), loaded.balance(TrustPolicy::ExcludeRbf)), (wallet.balance(TrustPolicy::TrustAll), wallet.balance(TrustPolicy::ExcludeRbf)));
+    // Older files can't have these lines
+    fs::write(&path, format!("wallet 1\nreplaced {} {}\n", encode_hex(&child.hash()), encode_hex(&spend2.hash()))).unwrap();
+    assert!(SpvWallet::load(&path, sync()).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn spv_wallet_reorg_then_replace() {
+    let genesis = BlockHeader::parse(&mut &crate::encoding::util::decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    let ours = p2wpkh_script(&[1; 20]).raw_serialize();
+    let theirs = p2wpkh_script(&[2; 20]).raw_serialize();
+    let tx = |prev: [u8; 32], outs: Vec<TxOut>| Tx::new(2, vec![TxIn::new(prev, 0)], outs, LockTime::ZERO, Network::Regtest);
+
+    let pay = tx([9; 32], vec![TxOut::new(50_000, ours.clone())]);
+    let block1 = mine_block(&genesis, vec![pay.clone()]);
+    let spend = tx(pay.hash(), vec![TxOut::new(20_000, theirs.clone()), TxOut::new(29_000, ours.clone())]);
+    let block2 = mine_block(&block1.header, vec![spend.clone()]);
+    let mut sync = HeaderSync::new(MemoryHeaderStore::new(0, genesis.clone()), NetworkParams::regtest()).unwrap();
+    sync.add_headers(&[block1.header.clone(), block2.header.clone()]).unwrap();
+    let mut wallet = SpvWallet::new(sync, 1);
+    wallet.watch_script(ours.clone());
+    assert_eq!(wallet.scan_block(&block1).unwrap() + wallet.scan_block(&block2).unwrap(), 2);
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 29_000, unconfirmed: 0 });
+
+    // The mined transactions survive a reload
+    let path = std::env::temp_dir().join(format!("prog_btc_book_spv_reorg_{}", std::process::id()));
+    wallet.save(&path).unwrap();
+    let sync = std::mem::replace(&mut wallet.sync, HeaderSync::new(MemoryHeaderStore::new(0, genesis), NetworkParams::regtest()).unwrap());
+    let mut wallet = SpvWallet::load(&path, sync).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // A longer branch off block 1 drops the spend back into the mempool
+    let fork2 = mine_block(&block1.header, vec![tx([4; 32], vec![TxOut::new(2_000, theirs.clone())])]);
+    let fork3 = mine_block(&fork2.header, vec![tx([3; 32], vec![TxOut::new(2_000, theirs.clone())])]);
+    wallet.sync.add_headers(&[fork2.header.clone(), fork3.header]).unwrap();
+    assert_eq!(wallet.handle_chain_events(), 1);
+    assert_eq!((wallet.confirmations(&spend.hash()), wallet.confirmations(&pay.hash())), (Some(0), Some(3)));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 0, unconfirmed: 29_000 });
+
+    // where one paying more fee replaces it and its change
+    let spend2 = tx(pay.hash(), vec![TxOut::new(20_000, theirs), TxOut::new(28_000, ours)]);
+    assert!(wallet.add_unconfirmed(&spend2));
+    assert_eq!((wallet.replaced_by(&spend.hash()), wallet.confirmations(&spend.hash())), (Some(spend2.hash()), None));
+    assert_eq!(wallet.balance(TrustPolicy::TrustAll), Balance { confirmed: 0, unconfirmed: 28_000 });
+    assert_eq!(wallet.scan_block(&fork2).unwrap(), 0);
+
+}
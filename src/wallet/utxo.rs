@@ -0,0 +1,394 @@
+//! Unspent transaction outputs, kept as blocks connect and rolled back
+//! with undo data when they disconnect.
+//!
+//! Connecting a block removes the coins it spends, adds the ones it
+//! creates and keeps what it spent as undo data. Disconnecting the tip
+//! puts the spent coins back and removes the created ones, in that order,
+//! so coins created and spent in the same block stay gone. An SPV wallet
+//! connects only the transactions its filter matched; spends of unknown
+//! coins are skipped.
+
+use crate::block::{Block, BlockHeader};
+use crate::encoding::util::{decode_hex, encode_hex};
+use crate::error::{Error, ErrorKind, Result};
+use crate::tx::builder::Utxo;
+use crate::tx::{Tx, TxOut};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Blocks that can be disconnected, as in Bitcoin Core's `MIN_BLOCKS_TO_KEEP`.
+pub const MAX_UNDO_DEPTH: usize = 288;
+
+const OP_RETURN: u8 = 0x6a;
+
+/// A transaction id, in display order, and output index.
+pub type OutPoint = ([u8; 32], u32);
+
+/// An unspent output and where it was created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub utxo: Utxo,
+    pub height: u32,
+    pub coinbase: bool,
+}
+
+impl Coin {
+    pub fn outpoint(&self) -> OutPoint {
+        (self.utxo.prev_tx, self.utxo.prev_index)
+    }
+}
+
+/// What connecting a block changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub height: u32,
+    pub block_hash: [u8; 32],
+    /// The tip before the block
+    pub prev_tip: Option<(u32, [u8; 32])>,
+    /// Coins the block spent
+    pub spent: Vec<Coin>,
+    /// Outputs the block created
+    pub created: Vec<OutPoint>,
+}
+
+pub trait UtxoStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<Coin>;
+
+    /// The coins paying `script_pubkey`, in outpoint order.
+    fn utxos_for_script(&self, script_pubkey: &[u8]) -> Vec<Coin>;
+
+    /// Height and hash of the last connected block.
+    fn tip(&self) -> Option<(u32, [u8; 32])>;
+
+    /// Removes `undo.spent`, adds `created` and keeps `undo`.
+    fn commit_connect(&mut self, created: Vec<Coin>, undo: BlockUndo) -> Result<()>;
+
+    /// Reverts and returns the last kept undo data, if any.
+    fn commit_disconnect(&mut self) -> Result<Option<BlockUndo>>;
+
+    /// Connects the block `header` heads at `height`, of which `txs` are
+    /// all the transactions or those a filter matched.
+    fn connect(&mut self, header: &BlockHeader, height: u32, txs: &[Tx]) -> Result<()> {
+        let block_hash = header.hash();
+        let prev_tip = self.tip();
+        if let Some((tip_height, tip_hash)) = prev_tip {
+            if header.prev_block != tip_hash || height != tip_height + 1 {
+                return Err(Error::new(ErrorKind::InvalidEncoding, format!("block {} does not extend the UTXO tip", header.id())));
+            }
+        }
+        // Coins created in this block, which later ones in it may spend
+        let mut created: HashMap<OutPoint, Coin> = HashMap::new();
+        let mut undo = BlockUndo { height, block_hash, prev_tip, spent: vec![], created: vec![] };
+        for tx in txs {
+            let coinbase = tx.is_coinbase();
+            if !coinbase {
+                for tx_in in tx.tx_ins.iter() {
+                    let outpoint = (tx_in.prev_tx, tx_in.prev_index);
+                    if created.remove(&outpoint).is_none() {
+                        if let Some(coin) = self.get(&outpoint) {
+                            undo.spent.push(coin);
+                        }
+                    }
+                }
+            }
+            let txid = tx.hash();
+            for (index, tx_out) in tx.tx_outs.iter().enumerate() {
+                if tx_out.script_pubkey.first() == Some(&OP_RETURN) {
+                    continue;
+                }
+                let utxo = Utxo::new(txid, index as u32, tx_out.clone());
+                undo.created.push((txid, index as u32));
+                created.insert((txid, index as u32), Coin { utxo, height, coinbase });
+            }
+        }
+        let mut created: Vec<Coin> = created.into_values().collect();
+        created.sort_by_key(|coin| coin.outpoint());
+        self.commit_connect(created, undo)
+    }
+
+    fn connect_block(&mut self, block: &Block, height: u32) -> Result<()> {
+        self.connect(&block.header, height, &block.txs)
+    }
+
+    /// Disconnects the tip, returning its hash.
+    fn disconnect_tip(&mut self) -> Result<[u8; 32]> {
+        match self.commit_disconnect()? {
+            Some(undo) => Ok(undo.block_hash),
+            None => Err(Error::new(ErrorKind::OutOfRange, "no undo data to disconnect the tip with")),
+        }
+    }
+}
+
+/// Coins in memory, with undo data for the last `MAX_UNDO_DEPTH` blocks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUtxoStore {
+    coins: HashMap<OutPoint, Coin>,
+    by_script: HashMap<Vec<u8>, BTreeSet<OutPoint>>,
+    undo: VecDeque<BlockUndo>,
+    tip: Option<(u32, [u8; 32])>,
+}
+
+impl MemoryUtxoStore {
+    pub fn new() -> MemoryUtxoStore {
+        MemoryUtxoStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+    }
+
+    fn add(&mut self, coin: Coin) {
+        let outpoint = coin.outpoint();
+        self.by_script.entry(coin.utxo.output.script_pubkey.clone()).or_default().insert(outpoint);
+        self.coins.insert(outpoint, coin);
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) {
+        if let Some(coin) = self.coins.remove(outpoint) {
+            let script = &coin.utxo.output.script_pubkey;
+            if let Some(outpoints) = self.by_script.get_mut(script) {
+                outpoints.remove(outpoint);
+                if outpoints.is_empty() {
+                    self.by_script.remove(script);
+                }
+            }
+        }
+    }
+}
+
+impl UtxoStore for MemoryUtxoStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.coins.get(outpoint).cloned()
+    }
+
+    fn utxos_for_script(&self, script_pubkey: &[u8]) -> Vec<Coin> {
+        match self.by_script.get(script_pubkey) {
+            Some(outpoints) => outpoints.iter().map(|outpoint| self.coins[outpoint].clone()).collect(),
+            None => vec![],
+        }
+    }
+
+    fn tip(&self) -> Option<(u32, [u8; 32])> {
+        self.tip
+    }
+
+    fn commit_connect(&mut self, created: Vec<Coin>, undo: BlockUndo) -> Result<()> {
+        for coin in undo.spent.iter() {
+            self.remove(&coin.outpoint());
+        }
+        for coin in created {
+            self.add(coin);
+        }
+        self.tip = Some((undo.height, undo.block_hash));
+        self.undo.push_back(undo);
+        if self.undo.len() > MAX_UNDO_DEPTH {
+            self.undo.pop_front();
+        }
+        Ok(())
+    }
+
+    fn commit_disconnect(&mut self) -> Result<Option<BlockUndo>> {
+        let undo = match self.undo.pop_back() {
+            Some(undo) => undo,
+            None => return Ok(None),
+        };
+        for coin in undo.spent.iter() {
+            self.add(coin.clone());
+        }
+        for outpoint in undo.created.iter() {
+            self.remove(outpoint);
+        }
+        self.tip = undo.prev_tip;
+        Ok(Some(undo))
+    }
+}
+
+/// A `MemoryUtxoStore` saved to a file after every change, replaced
+/// atomically.
+#[derive(Debug)]
+pub struct FileUtxoStore {
+    path: PathBuf,
+    memory: MemoryUtxoStore,
+}
+
+const FILE_VERSION: &str = "utxos 1";
+
+fn invalid_line(line: &str) -> Error {
+    Error::new(ErrorKind::InvalidEncoding, format!("bad UTXO entry {:?}", line))
+}
+
+fn coin_line(kind: &str, coin: &Coin) -> String {
+    let utxo = &coin.utxo;
+    format!(
+        "{} {} {} {} {} {} {}\n",
+        kind,
+        encode_hex(&utxo.prev_tx),
+        utxo.prev_index,
+        utxo.output.amount,
+        encode_hex(&utxo.output.script_pubkey),
+        coin.height,
+        coin.coinbase as u8
+    )
+}
+
+fn tip_fields(tip: &Option<(u32, [u8; 32])>) -> String {
+    match tip {
+        Some((height, hash)) => format!("{} {}", height, encode_hex(hash)),
+        None => "- -".to_string(),
+    }
+}
+
+fn parse_hash(field: &str, line: &str) -> Result<[u8; 32]> {
+    decode_hex(field).ok().and_then(|bytes| bytes.try_into().ok()).ok_or_else(|| invalid_line(line))
+}
+
+fn parse_tip(fields: &[&str], line: &str) -> Result<Option<(u32, [u8; 32])>> {
+    if fields == ["-", "-"] {
+        return Ok(None);
+    }
+    Ok(Some((fields[0].parse().map_err(|_| invalid_line(line))?, parse_hash(fields[1], line)?)))
+}
+
+fn parse_coin(fields: &[&str], line: &str) -> Result<Coin> {
+    let number = |i: usize| fields[i].parse::<u64>().map_err(|_| invalid_line(line));
+    let script_pubkey = decode_hex(fields[3]).map_err(|_| invalid_line(line))?;
+    let utxo = Utxo::new(parse_hash(fields[0], line)?, number(1)? as u32, TxOut::new(number(2)?, script_pubkey));
+    Ok(Coin { utxo, height: number(4)? as u32, coinbase: number(5)? == 1 })
+}
+
+impl FileUtxoStore {
+    /// Opens the store saved at `path`, or an empty one if there's no file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileUtxoStore> {
+        let path = path.as_ref().to_path_buf();
+        let memory = if path.exists() { FileUtxoStore::load(&path)? } else { MemoryUtxoStore::new() };
+        Ok(FileUtxoStore { path, memory })
+    }
+
+    pub fn memory(&self) -> &MemoryUtxoStore {
+        &self.memory
+    }
+
+    // A line for the tip, one per coin, then each kept block's undo data
+    // oldest first: its header line, its spent coins and its created outputs
+    fn save(&self) -> Result<()> {
+        let mut text = format!("{}\ntip {}\n", FILE_VERSION, tip_fields(&self.memory.tip));
+        let mut coins: Vec<&Coin> = self.memory.coins.values().collect();
+        coins.sort_by_key(|coin| coin.outpoint());
+        text.extend(coins.into_iter().map(|coin| coin_line("coin", coin)));
+        for undo in self.memory.undo.iter() {
+            text.push_str(&format!("undo {} {} {}\n", undo.height, encode_hex(&undo.block_hash), tip_fields(&undo.prev_tip)));
+            text.extend(undo.spent.iter().map(|coin| coin_line("spent", coin)));
+            text.extend(undo.created.iter().map(|(txid, index)| format!("created {} {}\n", encode_hex(txid), index)));
+        }
+        super::write_atomic(&self.path, &text)
+    }
+
+    fn load(path: &Path) -> Result<MemoryUtxoStore> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        if lines.next() != Some(FILE_VERSION) {
+            return Err(Error::new(ErrorKind::InvalidEncoding, format!("{} is not a version 1 UTXO file", path.display())));
+        }
+        let mut memory = MemoryUtxoStore::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match (fields.first(), fields.len()) {
+                (Some(&"tip"), 3) => memory.tip = parse_tip(&fields[1..], line)?,
+                (Some(&"coin"), 7) => memory.add(parse_coin(&fields[1..], line)?),
+                (Some(&"undo"), 5) => memory.undo.push_back(BlockUndo {
+                    height: fields[1].parse().map_err(|_| invalid_line(line))?,
+                    block_hash: parse_hash(fields[2], line)?,
+                    prev_tip: parse_tip(&fields[3..], line)?,
+                    spent: vec![],
+                    created: vec![],
+                }),
+                (Some(&"spent"), 7) => {
+                    let coin = parse_coin(&fields[1..], line)?;
+                    memory.undo.back_mut().ok_or_else(|| invalid_line(line))?.spent.push(coin);
+                }
+                (Some(&"created"), 3) => {
+                    let outpoint = (parse_hash(fields[1], line)?, fields[2].parse().map_err(|_| invalid_line(line))?);
+                    memory.undo.back_mut().ok_or_else(|| invalid_line(line))?.created.push(outpoint);
+                }
+                (None, _) => {}
+                _ => return Err(invalid_line(line)),
+            }
+        }
+        Ok(memory)
+    }
+}
+
+impl UtxoStore for FileUtxoStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.memory.get(outpoint)
+    }
+
+    fn utxos_for_script(&self, script_pubkey: &[u8]) -> Vec<Coin> {
+        self.memory.utxos_for_script(script_pubkey)
+    }
+
+    fn tip(&self) -> Option<(u32, [u8; 32])> {
+        self.memory.tip()
+    }
+
+    fn commit_connect(&mut self, created: Vec<Coin>, undo: BlockUndo) -> Result<()> {
+        self.memory.commit_connect(created, undo)?;
+        self.save()
+    }
+
+    fn commit_disconnect(&mut self) -> Result<Option<BlockUndo>> {
+        let undo = self.memory.commit_disconnect()?;
+        self.save()?;
+        Ok(undo)
+    }
+}
+
+#[cfg(test)]
+use crate::params::Network;
+#[cfg(test)]
+use crate::script::p2wpkh_script;
+#[cfg(test)]
+use crate::tx::{LockTime, TxIn};
+
+#[test]
+fn utxo_store() {
+    let ours = p2wpkh_script(&[1; 20]).raw_serialize();
+    let theirs = p2wpkh_script(&[2; 20]).raw_serialize();
+    let coinbase = Tx::new_coinbase(1, b"", vec![TxOut::new(50_000, ours.clone())], Network::Regtest);
+    let spend = Tx::new(2, vec![TxIn::new(coinbase.hash(), 0)], vec![TxOut::new(30_000, theirs.clone()), TxOut::new(19_000, ours.clone()), TxOut::new(0, vec![OP_RETURN])], LockTime::ZERO, Network::Regtest);
+    // Spends the change in the same block
+    let chained = Tx::new(2, vec![TxIn::new(spend.hash(), 1)], vec![TxOut::new(18_000, ours.clone())], LockTime::ZERO, Network::Regtest);
+    let header = |prev_block: [u8; 32], nonce: u32| BlockHeader { version: 1, prev_block, merkle_root: [0; 32], timestamp: 0, bits: 0x207f_ffff, nonce };
+    let first = header([0; 32], 1);
+    let second = header(first.hash(), 2);
+
+    let path = std::env::temp_dir().join(format!("prog_btc_book_utxos_{}", std::process::id()));
+    let mut store = FileUtxoStore::open(&path).unwrap();
+    store.connect(&first, 1, std::slice::from_ref(&coinbase)).unwrap();
+    assert!(store.get(&(coinbase.hash(), 0)).unwrap().coinbase);
+    assert!(store.connect(&first, 2, &[]).is_err());
+    store.connect(&second, 2, &[spend.clone(), chained.clone()]).unwrap();
+    let coins: Vec<OutPoint> = store.utxos_for_script(&ours).iter().map(Coin::outpoint).collect();
+    assert_eq!(coins, vec![(chained.hash(), 0)]);
+    assert_eq!(store.utxos_for_script(&theirs)[0].height, 2);
+    assert_eq!(store.memory().len(), 2);
+
+    let reopened = FileUtxoStore::open(&path).unwrap();
+    assert_eq!(reopened.memory(), store.memory());
+    let mut store = reopened;
+    assert_eq!(store.disconnect_tip().unwrap(), second.hash());
+    assert_eq!(store.tip(), Some((1, first.hash())));
+    let coins: Vec<OutPoint> = store.utxos_for_script(&ours).iter().map(Coin::outpoint).collect();
+    assert_eq!(coins, vec![(coinbase.hash(), 0)]);
+    assert!(store.utxos_for_script(&theirs).is_empty());
+    store.disconnect_tip().unwrap();
+    assert!(store.memory().is_empty());
+    assert_eq!(store.disconnect_tip().unwrap_err().kind(), ErrorKind::OutOfRange);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,65 @@
+//! The `backup` and `restore` commands, run as the binary.
+
+use prog_btc_book::bip39::Mnemonic;
+use prog_btc_book::bip32::KeyScript;
+use prog_btc_book::block::BlockHeader;
+use prog_btc_book::encoding::util::decode_hex;
+use prog_btc_book::network::sync::{HeaderSync, MemoryHeaderStore};
+use prog_btc_book::params::{Network, NetworkParams};
+use prog_btc_book::wallet::{Birthday, Keychain, SpvWallet, TrustPolicy, Wallet};
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_prog_btc_book"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn regtest_sync() -> HeaderSync<MemoryHeaderStore> {
+    let genesis = BlockHeader::parse(&mut &decode_hex("0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000").unwrap()[..]).unwrap();
+    HeaderSync::new(MemoryHeaderStore::new(0, genesis), NetworkParams::regtest()).unwrap()
+}
+
+#[test]
+fn backup_and_restore_commands() {
+    let dir = std::env::temp_dir().join(format!("prog_btc_book_backup_cli_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (wallet_path, backup_path, restored_path) = (dir.join("wallet"), dir.join("backup"), dir.join("restored"));
+    let mnemonic = Mnemonic::from_entropy(&[9; 16]).unwrap();
+    let account = mnemonic.to_master_key("", Network::Regtest).unwrap().derive_path(&[0x8000_0054, 0x8000_0001, 0x8000_0000]).unwrap().extended_pub_key();
+    let mut wallet = Wallet::new(SpvWallet::new(regtest_sync(), 0), 5);
+    wallet.add_xpub(&account.to_slip132(KeyScript::P2wpkh), None, Birthday::Height(0)).unwrap();
+    let address = wallet.next_address(Keychain::Receive).unwrap();
+    wallet.set_label(&address, "rent").unwrap();
+    wallet.save(&wallet_path).unwrap();
+
+    let output = run(&["backup", wallet_path.to_str().unwrap(), backup_path.to_str().unwrap(), "--seed"], &format!("{}\n", mnemonic));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = run(&["restore", backup_path.to_str().unwrap(), restored_path.to_str().unwrap(), "--seed"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8(output.stdout).unwrap().ends_with(&format!("{}\n", mnemonic)));
+
+    // The restored wallet carries on where the original left off
+    let mut restored = Wallet::load(&restored_path, regtest_sync()).unwrap();
+    assert_eq!(restored.label(&address), Some("rent"));
+    assert_eq!(restored.spv.balance(TrustPolicy::TrustAll), wallet.spv.balance(TrustPolicy::TrustAll));
+    assert_eq!(restored.next_address(Keychain::Receive).unwrap(), wallet.next_address(Keychain::Receive).unwrap());
+    assert_eq!(restored.next_address(Keychain::Change).unwrap(), wallet.next_address(Keychain::Change).unwrap());
+
+    // Restoring never overwrites, and a damaged backup is refused
+    assert!(!run(&["restore", backup_path.to_str().unwrap(), restored_path.to_str().unwrap()], "").status.success());
+    let text = fs::read_to_string(&backup_path).unwrap();
+    fs::write(&backup_path, text.replacen("rent", "food", 1)).unwrap();
+    fs::remove_file(&restored_path).unwrap();
+    assert!(!run(&["restore", backup_path.to_str().unwrap(), restored_path.to_str().unwrap()], "").status.success());
+    assert!(!restored_path.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
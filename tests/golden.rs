@@ -0,0 +1,151 @@
+//! Golden-file regression tests.
+//!
+//! Each test renders a fixed corpus as `label value` lines and compares it
+//! with `tests/golden/<name>.txt`. Any byte-level change to an encoding shows
+//! up as a line diff. After an intentional change, regenerate the files with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and review the git diff.
+
+use num_bigint::BigInt;
+use prog_btc_book::encoding::util::{decode_hex, encode_hex};
+use prog_btc_book::hash::hash256;
+use prog_btc_book::math::ecc::{PrivateKey, S256Point};
+use prog_btc_book::params::Network;
+use prog_btc_book::tx::{SighashType, Tx};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Secrets from the chapter 4 exercises.
+fn corpus_keys() -> Vec<(String, PrivateKey)> {
+    let secrets = vec![
+        ("5000", BigInt::from(5000)),
+        ("2018^5", BigInt::from(2018).pow(5)),
+        ("0xdeadbeef12345", BigInt::from(0xdeadbeef12345u64)),
+        ("5001", BigInt::from(5001)),
+        ("2019^5", BigInt::from(2019).pow(5)),
+        ("0xdeadbeef54321", BigInt::from(0xdeadbeef54321u64)),
+    ];
+    secrets.into_iter().map(|(label, secret)| (label.to_string(), PrivateKey::new(&secret).unwrap())).collect()
+}
+
+const CORPUS_MESSAGES: [&str; 3] = ["Programming Bitcoin!", "my message", ""];
+
+/// The chapter 5 example transaction, spending the output paying to
+/// `CORPUS_TX_SPENT_SCRIPT`.
+const CORPUS_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+/// A P2WPKH spend from rust-bitcoin's tests.
+const CORPUS_SEGWIT_TX: &str = "02000000000101595895ea20179de87052b4046dfe6fd515860505d6511a9004cf12a1f93cac7c0100000000ffffffff01deb807000000000017a9140f3444e271620c736808aa7b33e370bd87cb5a078702483045022100fb60dad8df4af2841adc0346638c16d0b8035f5e3f3753b88db122e70c79f9370220756e6633b17fd2710e626347d28d60b0a2d6cbb41de51740644b9fb3ba7751040121028fa937ca8cba2197a37c007176ed8941055d3bcb8627d085e94553e62f057dcc00000000";
+
+const CORPUS_TX_SPENT_SCRIPT: &str = "76a914a802fc56c704ce87c42d7c92eb75e7896bdc41ae88ac";
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.txt", name))
+}
+
+/// Lines that differ between `expected` and `actual`, prefixed with `-`/`+`
+/// and their line number.
+fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut diff = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e == a {
+            continue;
+        }
+        if let Some(e) = e {
+            writeln!(diff, "{:>4} - {}", i + 1, e).unwrap();
+        }
+        if let Some(a) = a {
+            writeln!(diff, "{:>4} + {}", i + 1, a).unwrap();
+        }
+    }
+    diff
+}
+
+fn check_golden(name: &str, actual: &str) {
+    let path = golden_path(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (run with UPDATE_GOLDEN=1 to create it)", path.display(), e));
+    if expected != actual {
+        panic!(
+            "{} does not match:\n{}\nrerun with UPDATE_GOLDEN=1 if the change is intended",
+            path.display(),
+            line_diff(&expected, actual)
+        );
+    }
+}
+
+#[test]
+fn golden_sec() {
+    let mut out = String::new();
+    for (label, key) in corpus_keys() {
+        let point = key.public_key();
+        writeln!(out, "{} uncompressed {}", label, encode_hex(&point.sec(false))).unwrap();
+        writeln!(out, "{} compressed {}", label, encode_hex(&point.sec(true))).unwrap();
+        assert_eq!(&S256Point::parse_sec(&point.sec(true)).unwrap(), point);
+    }
+    check_golden("sec", &out);
+}
+
+#[test]
+fn golden_addresses() {
+    let mut out = String::new();
+    for (label, key) in corpus_keys() {
+        let point = key.public_key();
+        for &(compressed, network) in [(false, Network::Mainnet), (true, Network::Mainnet), (false, Network::Testnet), (true, Network::Testnet)].iter() {
+            writeln!(
+                out,
+                "{} {} {} {}",
+                label,
+                if compressed { "compressed" } else { "uncompressed" },
+                if network == Network::Testnet { "testnet" } else { "mainnet" },
+                point.address(compressed, network)
+            )
+            .unwrap();
+        }
+    }
+    check_golden("addresses", &out);
+}
+
+#[test]
+fn golden_der_signatures() {
+    let mut out = String::new();
+    for (label, key) in corpus_keys() {
+        for message in CORPUS_MESSAGES.iter() {
+            let z = BigInt::from_bytes_be(num_bigint::Sign::Plus, &hash256(message.as_bytes()));
+            let der = key.sign(&z).der();
+            writeln!(out, "{} {:?} {}", label, message, encode_hex(&der)).unwrap();
+        }
+    }
+    check_golden("der", &out);
+}
+
+#[test]
+fn golden_transactions() {
+    let raw = decode_hex(CORPUS_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    let mut out = String::new();
+    writeln!(out, "ch5 txid {}", tx.id()).unwrap();
+    writeln!(out, "ch5 serialize {}", encode_hex(&tx.serialize())).unwrap();
+    let script_pubkey = decode_hex(CORPUS_TX_SPENT_SCRIPT).unwrap();
+    writeln!(out, "ch5 sighash 0 {:064x}", tx.legacy_sig_hash(0, &script_pubkey, SighashType::All).unwrap()).unwrap();
+
+    let raw = decode_hex(CORPUS_SEGWIT_TX).unwrap();
+    let tx = Tx::parse(&mut &raw[..], Network::Mainnet).unwrap();
+    writeln!(out, "segwit txid {}", tx.id()).unwrap();
+    writeln!(out, "segwit wtxid {}", tx.wtxid()).unwrap();
+    writeln!(out, "segwit serialize {}", encode_hex(&tx.serialize())).unwrap();
+    writeln!(out, "segwit serialize_legacy {}", encode_hex(&tx.serialize_legacy())).unwrap();
+    check_golden("transactions", &out);
+}
+
+#[test]
+fn golden_diff_is_readable() {
+    let diff = line_diff("a 1\nb 2\nc 3\n", "a 1\nb 5\nc 3\nd 4\n");
+    assert_eq!(diff, "   2 - b 2\n   2 + b 5\n   4 + d 4\n");
+}
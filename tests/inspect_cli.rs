@@ -0,0 +1,82 @@
+//! The `inspect` command, run as the binary on each kind of input.
+
+use num_bigint::BigInt;
+use prog_btc_book::address::Address;
+use prog_btc_book::bip32::ExtendedPrivKey;
+use prog_btc_book::block::BlockHeader;
+use prog_btc_book::encoding::util::encode_hex;
+use prog_btc_book::math::ecc::PrivateKey;
+use prog_btc_book::params::{Network, NetworkParams};
+use prog_btc_book::tx::psbt::Psbt;
+use prog_btc_book::tx::{p2wpkh_script, LockTime, Tx, TxIn, TxOut};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_prog_btc_book"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// The fields printed for `input`, passed as the argument
+fn inspect(input: &str) -> Vec<String> {
+    let output = run(&["inspect", input], b"");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap().lines().map(str::to_string).collect()
+}
+
+#[test]
+fn inspect_command() {
+    let key = PrivateKey::new(&BigInt::from(0x54321deadbeefu64)).unwrap();
+    let script_pubkey = p2wpkh_script(&key.public_key().hash160(true));
+    let tx = Tx::new(2, vec![TxIn::new([3; 32], 1)], vec![TxOut::new(90_000, script_pubkey.clone())], LockTime::ZERO, Network::Mainnet);
+    let address = Address::P2wpkh(key.public_key().hash160(true)).encode(&NetworkParams::mainnet());
+
+    let fields = inspect(&encode_hex(&tx.serialize()));
+    assert_eq!(fields[..2], [String::from("type: transaction"), format!("txid: {}", tx.id())]);
+    assert!(fields.contains(&format!("input 0: {}:1", encode_hex(&[3; 32]))));
+    assert!(fields.contains(&format!("output 0: 90000 {}", address)));
+    // Pasted with a trailing newline, or as raw bytes
+    assert_eq!(run(&["inspect"], format!("{}\n", encode_hex(&tx.serialize())).as_bytes()).stdout, run(&["inspect"], &tx.serialize()).stdout);
+
+    let mut psbt = Psbt::new(tx).unwrap();
+    psbt.inputs[0].witness_utxo = Some(TxOut::new(100_000, script_pubkey.clone()));
+    let fields = inspect(&psbt.to_base64());
+    assert_eq!(fields[0], "type: PSBT");
+    assert!(fields.contains(&"fee: 10000".to_string()) && fields.contains(&"finalized: false".to_string()));
+    psbt.sign(&key).unwrap();
+    let output = run(&["inspect"], &psbt.serialize());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("with 1 signatures"));
+
+    let fields = inspect(&address);
+    assert_eq!(fields, ["type: address", "network: mainnet", &format!("script_pubkey: {}", encode_hex(&script_pubkey))]);
+
+    let wif = key.wif(true, &NetworkParams::testnet3());
+    assert_eq!(inspect(&wif), ["type: WIF private key", "network: testnet3", "compressed: true", &format!("public key: {}", encode_hex(&key.public_key().sec(true)))]);
+
+    let descriptor = format!("wpkh({})", encode_hex(&key.public_key().sec(true)));
+    let fields = inspect(&descriptor);
+    assert_eq!(fields[0], "type: descriptor");
+    assert!(fields[1].starts_with(&format!("descriptor: {}#", descriptor)));
+    assert_eq!(fields[4], format!("address 0: {}", address));
+
+    let master = ExtendedPrivKey::new_master(&[1; 32], Network::Mainnet).unwrap().extended_pub_key();
+    let fields = inspect(&master.to_string());
+    assert_eq!(fields[..3], ["type: extended public key", "script: P2pkh", "depth: 0"]);
+
+    let header = BlockHeader { version: 4, prev_block: [1; 32], merkle_root: [2; 32], timestamp: 1_700_000_000, bits: 0x207fffff, nonce: 0 };
+    let fields = inspect(&encode_hex(&header.serialize()));
+    assert_eq!(fields[..2], [String::from("type: block header"), format!("hash: {}", header.id())]);
+    assert!(fields.contains(&format!("prev_block: {}", encode_hex(&[1; 32]))));
+
+    for bad in ["not bitcoin", "wpkh(nonsense)", "cHNidP8BAA=="].iter() {
+        let output = run(&["inspect", bad], b"");
+        assert!(!output.status.success() && output.stdout.is_empty(), "{}", bad);
+    }
+}
@@ -0,0 +1,76 @@
+//! The `reserves prove` and `reserves verify` commands, run as the binary.
+
+use num_bigint::BigInt;
+use prog_btc_book::block::BlockHeader;
+use prog_btc_book::encoding::util::encode_hex;
+use prog_btc_book::math::ecc::PrivateKey;
+use prog_btc_book::params::Network;
+use prog_btc_book::script::p2wpkh_script;
+use prog_btc_book::tx::psbt::Psbt;
+use prog_btc_book::tx::{LockTime, Tx, TxIn, TxOut};
+use prog_btc_book::wallet::{FileUtxoStore, UtxoStore};
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_prog_btc_book"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn header(prev_block: [u8; 32]) -> BlockHeader {
+    BlockHeader { version: 1, prev_block, merkle_root: [0; 32], timestamp: 0, bits: 0x207fffff, nonce: 0 }
+}
+
+#[test]
+fn prove_and_verify_reserves_commands() {
+    let dir = std::env::temp_dir().join(format!("prog_btc_book_reserves_cli_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let utxo_path = dir.join("utxos");
+    let utxo_file = utxo_path.to_str().unwrap();
+    let key = PrivateKey::new(&BigInt::from(271_828)).unwrap();
+    let descriptor = format!("wpkh({})", encode_hex(&key.public_key().sec(true)));
+    let script_pubkey = p2wpkh_script(&key.public_key().hash160(true)).raw_serialize();
+
+    // Block 1 pays the descriptor twice and someone else once
+    let mut store = FileUtxoStore::open(&utxo_path).unwrap();
+    let outputs = vec![TxOut::new(70_000, script_pubkey.clone()), TxOut::new(5_000, vec![0x51]), TxOut::new(30_000, script_pubkey)];
+    let funding = Tx::new(2, vec![TxIn::new([3; 32], 0)], outputs, LockTime::ZERO, Network::Regtest);
+    let first = header([0; 32]);
+    store.connect(&first, 1, std::slice::from_ref(&funding)).unwrap();
+
+    let message = "reserves at block 1";
+    let output = run(&["reserves", "prove", utxo_file, &descriptor, message], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let mut proof = Psbt::from_base64(String::from_utf8(output.stdout).unwrap().trim()).unwrap();
+    assert_eq!(proof.unsigned_tx.tx_ins.len(), 3);
+    assert_eq!(proof.sign(&key).unwrap(), 2);
+    proof.finalize().unwrap();
+    let signed = proof.to_base64();
+
+    let output = run(&["reserves", "verify", utxo_file, message], &signed);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "100000\n");
+    let output = run(&["reserves", "verify", utxo_file, message, "--btc", "--rate", "usd=65000.00"], &signed);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "0.00100000 BTC (65.00 USD)\n");
+    let output = run(&["reserves", "verify", utxo_file, message, "--rate", "EUR=60000"], &signed);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "100000 sat (60 EUR)\n");
+    assert!(!run(&["reserves", "verify", utxo_file, message, "--rate"], &signed).status.success());
+    assert!(!run(&["reserves", "verify", utxo_file, "another message"], &signed).status.success());
+
+    // Once a coin is spent the proof no longer holds
+    let spend = Tx::new(2, vec![TxIn::new(funding.hash(), 2)], vec![TxOut::new(29_000, vec![0x51])], LockTime::ZERO, Network::Regtest);
+    store.connect(&header(first.hash()), 2, &[spend]).unwrap();
+    let output = run(&["reserves", "verify", utxo_file, message], &signed);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not unspent"));
+    assert!(!run(&["reserves", "prove", utxo_file], "").status.success());
+    fs::remove_dir_all(&dir).unwrap();
+}